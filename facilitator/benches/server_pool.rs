@@ -0,0 +1,32 @@
+//! Compares the time taken to get a libprio Server for a batch from a
+//! ServerPool with that taken to construct a new one, as intake did before
+//! there was a pool. Run with `cargo bench --bench server_pool`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use facilitator::{server_pool::ServerPool, test_utils::DEFAULT_PHA_ECIES_PRIVATE_KEY};
+use prio::{encrypt::PrivateKey, server::Server};
+
+const DIMENSIONS: &[usize] = &[10, 1024];
+
+fn server_allocation(c: &mut Criterion) {
+    let pha_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+    let pool = ServerPool::new(true, pha_key.clone());
+
+    let mut group = c.benchmark_group("server-allocation");
+    for dimension in DIMENSIONS {
+        group.bench_with_input(
+            BenchmarkId::new("unpooled", dimension),
+            dimension,
+            |b, dimension| b.iter(|| Server::new(*dimension, true, pha_key.clone())),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("pooled", dimension),
+            dimension,
+            |b, dimension| b.iter(|| pool.acquire(*dimension)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, server_allocation);
+criterion_main!(benches);
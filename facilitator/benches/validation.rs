@@ -0,0 +1,151 @@
+//! Measures the throughput of BatchIntaker::generate_validation_share, in
+//! packets and in bytes of ingestion packet file per second, on sample batches
+//! of a few sizes. Run with `cargo bench --bench validation`. The packet counts
+//! may be overridden with a comma separated list in FACILITATOR_BENCH_PACKETS,
+//! e.g. FACILITATOR_BENCH_PACKETS=1000,100000.
+
+use chrono::NaiveDateTime;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use facilitator::{
+    batch::{
+        AggregationName, BatchDate, BatchFileKind, BatchIdentity, ServerIdentity,
+        DEFAULT_NAMING_SCHEME,
+    },
+    intake::BatchIntaker,
+    sample::generate_ingestion_sample,
+    test_utils::{
+        default_ingestor_private_key_raw, default_ingestor_public_key,
+        default_pha_signing_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+        DEFAULT_PHA_ECIES_PRIVATE_KEY,
+    },
+    transport::{MemoryTransport, Transport},
+};
+use prio::encrypt::PrivateKey;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::io::Read;
+use uuid::Uuid;
+
+const DEFAULT_PACKET_COUNTS: &[usize] = &[1_000, 10_000];
+const DIMENSION: i32 = 10;
+
+/// An ingestion batch for the PHA, and the size of its packet file.
+struct SampleBatch {
+    batch: BatchIdentity,
+    transport: MemoryTransport,
+    packet_file_size: u64,
+}
+
+/// Generates a batch of packet_count packets. The batch's identity, keys and
+/// size are fixed, so that runs are comparable, though the packets' data,
+/// like their encryption, differs from one run to the next.
+fn sample_batch(packet_count: usize) -> SampleBatch {
+    let batch = BatchIdentity::new(
+        AggregationName::new("bench-aggregation").unwrap(),
+        BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+        Uuid::from_u128(packet_count as u128),
+    );
+    let mut transport = MemoryTransport::new();
+    generate_ingestion_sample(
+        &mut transport,
+        &mut MemoryTransport::new(),
+        None,
+        &batch,
+        &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+        &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+        &default_ingestor_private_key_raw(),
+        DIMENSION,
+        packet_count,
+        0.11,
+        100,
+        100,
+    )
+    .expect("failed to generate sample");
+
+    let mut packet_file = Vec::new();
+    transport
+        .get(
+            batch
+                .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                .key(BatchFileKind::Packets),
+        )
+        .unwrap()
+        .read_to_end(&mut packet_file)
+        .unwrap();
+    SampleBatch {
+        batch,
+        transport,
+        packet_file_size: packet_file.len() as u64,
+    }
+}
+
+fn packet_counts() -> Vec<usize> {
+    match std::env::var("FACILITATOR_BENCH_PACKETS") {
+        Ok(counts) => counts
+            .split(',')
+            .map(|count| {
+                count
+                    .trim()
+                    .parse()
+                    .expect("FACILITATOR_BENCH_PACKETS is not a list of packet counts")
+            })
+            .collect(),
+        Err(_) => DEFAULT_PACKET_COUNTS.to_vec(),
+    }
+}
+
+fn validation_throughput(c: &mut Criterion) {
+    let ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+    let signing_key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        &default_pha_signing_private_key(),
+    )
+    .unwrap();
+    let ingestor_key = default_ingestor_public_key();
+    let samples: Vec<(usize, SampleBatch)> = packet_counts()
+        .into_iter()
+        .map(|packet_count| (packet_count, sample_batch(packet_count)))
+        .collect();
+
+    // Criterion reports one throughput per group, so the same runs are
+    // measured once in packets and once in bytes.
+    for (group_name, packets) in &[("validation-packets", true), ("validation-bytes", false)] {
+        let mut group = c.benchmark_group(*group_name);
+        group.sample_size(10);
+        for (packet_count, sample) in &samples {
+            group.throughput(if *packets {
+                Throughput::Elements(*packet_count as u64)
+            } else {
+                Throughput::Bytes(sample.packet_file_size)
+            });
+            group.bench_with_input(
+                BenchmarkId::from_parameter(packet_count),
+                sample,
+                |b, sample| {
+                    b.iter_batched(
+                        || (sample.transport.clone(), MemoryTransport::new()),
+                        |(mut ingestion_transport, mut validation_transport)| {
+                            BatchIntaker::new(
+                                None,
+                                &sample.batch,
+                                &mut ingestion_transport,
+                                &mut validation_transport,
+                                ServerIdentity::Pha,
+                                &ecies_key,
+                                &signing_key,
+                                &ingestor_key,
+                            )
+                            .unwrap()
+                            .generate_validation_share()
+                            .unwrap()
+                        },
+                        BatchSize::PerIteration,
+                    )
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, validation_throughput);
+criterion_main!(benches);
@@ -1,53 +1,88 @@
 use crate::{
-    batch::{Batch, BatchReader, BatchWriter},
+    batch::{
+        AggregationName, Batch, BatchDate, BatchFileKind, BatchIdentity, BatchKind,
+        BatchNamingScheme, BatchReader, BatchWriter, InstanceName, ServerIdentity,
+        DEFAULT_NAMING_SCHEME,
+    },
     idl::{
         IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
         ValidationHeader, ValidationPacket,
     },
-    transport::Transport,
+    transport::{Transport, TransportWriter},
     Error,
 };
 use anyhow::{anyhow, Context, Result};
-use chrono::NaiveDateTime;
-use prio::{encrypt::PrivateKey, server::VerificationMessage};
+use avro_rs::Reader;
+use prio::{
+    encrypt::PrivateKey,
+    server::{Server, VerificationMessage},
+};
+use rand::{thread_rng, Rng};
 use ring::signature::{EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 use uuid::Uuid;
 
+/// Number of batches BatchAggregator fetches and sums at once, unless told
+/// otherwise with BatchAggregator::set_max_concurrent_batches. Each batch in
+/// flight holds up to three packet files or packet file shards in memory, so
+/// this also bounds the aggregator's memory use.
+pub const DEFAULT_MAX_CONCURRENT_BATCHES: usize = 4;
+
+/// A transport on which sums are computed must be shareable between the
+/// threads that fetch batches concurrently.
+pub type AggregationTransport = dyn Transport + Send + Sync;
+
 pub struct BatchAggregator<'a> {
-    is_first: bool,
-    aggregation_name: &'a str,
-    aggregation_start: &'a NaiveDateTime,
-    aggregation_end: &'a NaiveDateTime,
-    own_validation_transport: &'a mut dyn Transport,
-    peer_validation_transport: &'a mut dyn Transport,
-    ingestion_transport: &'a mut dyn Transport,
+    server_identity: ServerIdentity,
+    instance_name: Option<InstanceName>,
+    ingestion_naming_scheme: &'a dyn BatchNamingScheme,
+    validation_naming_scheme: &'a dyn BatchNamingScheme,
+    aggregation_name: AggregationName,
+    aggregation_start: &'a BatchDate,
+    aggregation_end: &'a BatchDate,
+    own_validation_transport: &'a mut AggregationTransport,
+    peer_validation_transport: &'a mut AggregationTransport,
+    ingestion_transport: &'a mut AggregationTransport,
     aggregation_batch: BatchWriter<'a, SumPart, InvalidPacket>,
-    ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ingestor_keys: Vec<&'a UnparsedPublicKey<Vec<u8>>>,
     share_processor_signing_key: &'a EcdsaKeyPair,
     peer_share_processor_key: &'a UnparsedPublicKey<Vec<u8>>,
     share_processor_ecies_key: &'a PrivateKey,
+    max_concurrent_batches: usize,
+    batch_start_jitter: Duration,
 }
 
 impl<'a> BatchAggregator<'a> {
     #[allow(clippy::too_many_arguments)] // Grandfathered in
     pub fn new(
-        aggregation_name: &'a str,
-        aggregation_start: &'a NaiveDateTime,
-        aggregation_end: &'a NaiveDateTime,
-        is_first: bool,
-        ingestion_transport: &'a mut dyn Transport,
-        own_validation_transport: &'a mut dyn Transport,
-        peer_validation_transport: &'a mut dyn Transport,
+        instance_name: Option<&str>,
+        aggregation_name: &str,
+        aggregation_start: &'a BatchDate,
+        aggregation_end: &'a BatchDate,
+        server_identity: ServerIdentity,
+        ingestion_transport: &'a mut AggregationTransport,
+        own_validation_transport: &'a mut AggregationTransport,
+        peer_validation_transport: &'a mut AggregationTransport,
         aggregation_transport: &'a mut dyn Transport,
         ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
         share_processor_signing_key: &'a EcdsaKeyPair,
         peer_share_processor_key: &'a UnparsedPublicKey<Vec<u8>>,
         share_processor_ecies_key: &'a PrivateKey,
     ) -> Result<BatchAggregator<'a>> {
+        let instance_name = instance_name.map(InstanceName::new).transpose()?;
+        let aggregation_name = AggregationName::new(aggregation_name)?;
         Ok(BatchAggregator {
-            is_first,
-            aggregation_name,
+            server_identity,
+            ingestion_naming_scheme: &DEFAULT_NAMING_SCHEME,
+            validation_naming_scheme: &DEFAULT_NAMING_SCHEME,
             aggregation_start,
             aggregation_end,
             own_validation_transport,
@@ -55,81 +90,186 @@ impl<'a> BatchAggregator<'a> {
             ingestion_transport,
             aggregation_batch: BatchWriter::new(
                 Batch::new_sum(
-                    aggregation_name,
+                    instance_name.as_ref(),
+                    &aggregation_name,
                     aggregation_start,
                     aggregation_end,
-                    is_first,
-                ),
+                    server_identity,
+                )
+                .check_key_lengths(0)?,
                 aggregation_transport,
             ),
-            ingestor_key,
+            instance_name,
+            aggregation_name,
+            ingestor_keys: vec![ingestor_key],
             share_processor_signing_key,
             peer_share_processor_key,
             share_processor_ecies_key,
+            max_concurrent_batches: DEFAULT_MAX_CONCURRENT_BATCHES,
+            batch_start_jitter: Duration::from_secs(0),
         })
     }
 
+    /// Sets the naming scheme used to locate ingestion and validation
+    /// batches. Defaults to DEFAULT_NAMING_SCHEME. Both share processors must
+    /// use the same naming scheme.
+    pub fn set_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.ingestion_naming_scheme = naming_scheme;
+        self.validation_naming_scheme = naming_scheme;
+    }
+
+    /// Sets the naming scheme used to locate ingestion batches, overriding
+    /// the one provided to set_naming_scheme.
+    pub fn set_ingestion_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.ingestion_naming_scheme = naming_scheme;
+    }
+
+    /// Sets the naming scheme used for validation batches, overriding the one
+    /// provided to set_naming_scheme.
+    pub fn set_validation_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.validation_naming_scheme = naming_scheme;
+    }
+
+    /// Adds a key with which ingestion headers' signatures may also be
+    /// verified. See BatchIntaker::add_ingestor_key.
+    pub fn add_ingestor_key(&mut self, ingestor_key: &'a UnparsedPublicKey<Vec<u8>>) {
+        self.ingestor_keys.push(ingestor_key);
+    }
+
+    /// Sets how many batches are fetched and summed at once. Batches are
+    /// handed out in order to this many workers, each summing into its own
+    /// libprio Server, and a worker releases a batch's packet files as soon as
+    /// it has summed it. Values below 1 are treated as 1, which sums the
+    /// batches one after another on the calling thread. Defaults to
+    /// DEFAULT_MAX_CONCURRENT_BATCHES.
+    pub fn set_max_concurrent_batches(&mut self, max_concurrent_batches: usize) {
+        self.max_concurrent_batches = max_concurrent_batches;
+    }
+
+    /// Sets the longest a worker waits, for a random time up to this long,
+    /// before it starts fetching each batch, so that workers don't send their
+    /// requests to the transports in lockstep. Defaults to no wait.
+    pub fn set_batch_start_jitter(&mut self, batch_start_jitter: Duration) {
+        self.batch_start_jitter = batch_start_jitter;
+    }
+
     /// Compute the sum part for all the provided batch IDs and write it out to
     /// the aggregation transport.
-    pub fn generate_sum_part(&mut self, batch_ids: &[(Uuid, NaiveDateTime)]) -> Result<()> {
+    pub fn generate_sum_part(&mut self, batch_ids: &[(Uuid, BatchDate)]) -> Result<()> {
         let share_processor_public_key = UnparsedPublicKey::new(
             &ECDSA_P256_SHA256_FIXED,
             Vec::from(self.share_processor_signing_key.public_key().as_ref()),
         );
-        let mut invalid_uuids = Vec::new();
 
         let ingestion_header = self.ingestion_header(&batch_ids[0].0, &batch_ids[0].1)?;
-        let mut server = prio::server::Server::new(
-            ingestion_header.bins as usize,
-            self.is_first,
-            self.share_processor_ecies_key.clone(),
-        );
+        let new_accumulator = || {
+            Accumulator::new(
+                &ingestion_header,
+                self.server_identity,
+                self.share_processor_ecies_key,
+            )
+        };
 
-        for batch_id in batch_ids {
-            self.aggregate_share(
-                &batch_id.0,
-                &batch_id.1,
-                &share_processor_public_key,
-                &mut server,
-                &mut invalid_uuids,
-            )?;
-        }
+        // Workers take batches from a shared queue, so at most worker_count
+        // batches are being fetched and summed at any time, and each sums into
+        // its own Accumulator, which are merged once all are done.
+        let batches: Vec<AggregationInputs> = batch_ids
+            .iter()
+            .map(|(batch_id, batch_date)| self.aggregation_inputs(batch_id, batch_date))
+            .collect();
+        let worker_count = self.max_concurrent_batches.clamp(1, batches.len().max(1));
+        let context = AggregationContext {
+            ingestion_transport: &*self.ingestion_transport,
+            own_validation_transport: &*self.own_validation_transport,
+            peer_validation_transport: &*self.peer_validation_transport,
+            ingestor_keys: &self.ingestor_keys,
+            share_processor_public_key: &share_processor_public_key,
+            peer_share_processor_key: self.peer_share_processor_key,
+            batch_start_jitter: self.batch_start_jitter,
+            batches: Mutex::new(batches.into_iter().enumerate()),
+            failed: AtomicBool::new(false),
+        };
+        let results = if worker_count == 1 {
+            vec![context.run_worker(new_accumulator())]
+        } else {
+            let workers: Vec<Accumulator> = (0..worker_count).map(|_| new_accumulator()).collect();
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = workers
+                    .into_iter()
+                    .map(|accumulator| {
+                        let context = &context;
+                        scope.spawn(move || context.run_worker(accumulator))
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| {
+                        worker
+                            .join()
+                            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                    })
+                    .collect()
+            })
+        };
 
-        // TODO(timg) what exactly do we write out when there are no invalid
-        // packets? Right now we will write an empty file.
-        let invalid_packets_digest =
-            self.aggregation_batch
-                .packet_file_writer(|mut packet_file_writer| {
-                    for invalid_uuid in invalid_uuids {
-                        InvalidPacket { uuid: invalid_uuid }.write(&mut packet_file_writer)?
+        // Workers stop taking batches once any of them fails, so not every
+        // batch is attempted. Report the failure of the earliest batch among
+        // those the workers saw fail before stopping, and invalid packets in
+        // the order of the batches, regardless of which worker got to them
+        // first.
+        let mut accumulator = new_accumulator();
+        let mut invalid_uuids_by_batch = Vec::new();
+        let mut first_failure: Option<(usize, anyhow::Error)> = None;
+        for result in results {
+            match result {
+                Ok(output) => {
+                    accumulator.merge(&output.accumulator);
+                    invalid_uuids_by_batch.extend(output.invalid_uuids);
+                }
+                Err((index, e)) => {
+                    if first_failure
+                        .as_ref()
+                        .map_or(true, |(first, _)| index < *first)
+                    {
+                        first_failure = Some((index, e));
                     }
-                    Ok(())
-                })?;
+                }
+            }
+        }
+        if let Some((_, e)) = first_failure {
+            return Err(e);
+        }
+        invalid_uuids_by_batch.sort_by_key(|(index, _)| *index);
+        let invalid_uuids = invalid_uuids_by_batch
+            .into_iter()
+            .flat_map(|(_, invalid_uuids)| invalid_uuids);
 
-        let sum = server
-            .total_shares()
-            .iter()
-            .map(|f| u32::from(*f) as i64)
-            .collect();
+        // When there are no invalid packets, this writes a packet file
+        // containing no records.
+        let invalid_packets_digest = self
+            .aggregation_batch
+            .packet_file_writer(|mut packet_file_writer| {
+                for invalid_uuid in invalid_uuids {
+                    InvalidPacket { uuid: invalid_uuid }.write(&mut packet_file_writer)?
+                }
+                Ok(())
+            })
+            .map_err(|e| self.aggregation_batch.roll_back(e))?;
 
         let sum_signature = self.aggregation_batch.put_header(
-            &SumPart {
-                batch_uuids: batch_ids.iter().map(|pair| pair.0).collect(),
-                name: ingestion_header.name,
-                bins: ingestion_header.bins,
-                epsilon: ingestion_header.epsilon,
-                prime: ingestion_header.prime,
-                number_of_servers: ingestion_header.number_of_servers,
-                hamming_weight: ingestion_header.hamming_weight,
-                sum,
-                aggregation_start_time: self.aggregation_start.timestamp_millis(),
-                aggregation_end_time: self.aggregation_end.timestamp_millis(),
-                packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
-            },
+            &accumulator.finish(
+                batch_ids.iter().map(|pair| pair.0).collect(),
+                self.aggregation_start,
+                self.aggregation_end,
+                invalid_packets_digest.as_ref().to_vec(),
+            ),
             &self.share_processor_signing_key,
-        )?;
+        );
+        let sum_signature = sum_signature.map_err(|e| self.aggregation_batch.roll_back(e))?;
 
-        self.aggregation_batch.put_signature(&sum_signature)
+        self.aggregation_batch
+            .put_signature(&sum_signature)
+            .map_err(|e| self.aggregation_batch.roll_back(e))
     }
 
     /// Fetch the ingestion header from one of the batches so various parameters
@@ -137,47 +277,142 @@ impl<'a> BatchAggregator<'a> {
     fn ingestion_header(
         &mut self,
         batch_id: &Uuid,
-        batch_date: &NaiveDateTime,
+        batch_date: &BatchDate,
     ) -> Result<IngestionHeader> {
         let ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
-                Batch::new_ingestion(self.aggregation_name, batch_id, batch_date),
+                Batch::with_naming_scheme(
+                    self.ingestion_naming_scheme,
+                    self.instance_name.as_ref(),
+                    &self.aggregation_name,
+                    batch_id,
+                    batch_date,
+                    BatchKind::Ingestion,
+                ),
                 self.ingestion_transport,
             );
-        let ingestion_header = ingestion_batch.header(&self.ingestor_key)?;
-        Ok(ingestion_header)
+        let (_, verified_header) =
+            ingestion_batch.verified_header_with_keys(&self.ingestor_keys)?;
+        Ok(verified_header.header)
+    }
+
+    /// Locates the ingestion and validation batches for the provided batch.
+    fn aggregation_inputs(&self, batch_id: &Uuid, batch_date: &BatchDate) -> AggregationInputs {
+        let batch = BatchIdentity::new(self.aggregation_name.clone(), *batch_date, *batch_id);
+        AggregationInputs {
+            ingestion: Batch::with_naming_scheme(
+                self.ingestion_naming_scheme,
+                self.instance_name.as_ref(),
+                &self.aggregation_name,
+                batch_id,
+                batch_date,
+                BatchKind::Ingestion,
+            ),
+            own_validation: batch
+                .own_validation_batch(
+                    self.validation_naming_scheme,
+                    self.instance_name.as_ref(),
+                    self.server_identity,
+                )
+                .into_batch(),
+            peer_validation: batch
+                .peer_validation_batch(
+                    self.validation_naming_scheme,
+                    self.instance_name.as_ref(),
+                    self.server_identity,
+                )
+                .into_batch(),
+        }
     }
+}
+
+/// The batches that are read to sum one batch's packets.
+struct AggregationInputs {
+    ingestion: Batch,
+    own_validation: Batch,
+    peer_validation: Batch,
+}
+
+/// What a worker in BatchAggregator::generate_sum_part hands back: the
+/// accumulator it summed into and the invalid packets of each of the batches it
+/// summed, by the batch's index.
+struct WorkerOutput {
+    accumulator: Accumulator,
+    invalid_uuids: Vec<(usize, Vec<Uuid>)>,
+}
+
+/// Everything the workers in BatchAggregator::generate_sum_part share: the
+/// transports they read through, the keys they verify with and the queue of
+/// batches still to be summed.
+struct AggregationContext<'a> {
+    ingestion_transport: &'a AggregationTransport,
+    own_validation_transport: &'a AggregationTransport,
+    peer_validation_transport: &'a AggregationTransport,
+    ingestor_keys: &'a [&'a UnparsedPublicKey<Vec<u8>>],
+    share_processor_public_key: &'a UnparsedPublicKey<Vec<u8>>,
+    peer_share_processor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    batch_start_jitter: Duration,
+    batches: Mutex<std::iter::Enumerate<std::vec::IntoIter<AggregationInputs>>>,
+    failed: AtomicBool,
+}
 
-    /// Aggregate the batch for the provided batch_id into the provided server.
-    /// The UUIDs of packets for which aggregation fails are recorded in the
-    /// provided invalid_uuids vector.
+impl AggregationContext<'_> {
+    /// Sums batches from the queue into the provided accumulator until the
+    /// queue is empty or some worker fails. If this worker's batch fails, the
+    /// index of that batch is returned with the error. Other workers finish
+    /// the batch they are summing but take no more, so later batches may
+    /// never be attempted.
+    fn run_worker(
+        &self,
+        mut accumulator: Accumulator,
+    ) -> Result<WorkerOutput, (usize, anyhow::Error)> {
+        let mut invalid_uuids = Vec::new();
+        while !self.failed.load(Ordering::Relaxed) {
+            let (index, inputs) = match self.batches.lock().unwrap().next() {
+                Some(batch) => batch,
+                None => break,
+            };
+            if self.batch_start_jitter > Duration::from_secs(0) {
+                std::thread::sleep(self.batch_start_jitter.mul_f64(thread_rng().gen()));
+            }
+            match self.aggregate_share(inputs, &mut accumulator) {
+                Ok(batch_invalid_uuids) => invalid_uuids.push((index, batch_invalid_uuids)),
+                Err(e) => {
+                    self.failed.store(true, Ordering::Relaxed);
+                    return Err((index, e));
+                }
+            }
+        }
+        Ok(WorkerOutput {
+            accumulator,
+            invalid_uuids,
+        })
+    }
+
+    /// Aggregate the provided batch into the provided accumulator. Returns the
+    /// UUIDs of packets for which aggregation fails.
     fn aggregate_share(
-        &mut self,
-        batch_id: &Uuid,
-        batch_date: &NaiveDateTime,
-        share_processor_public_key: &UnparsedPublicKey<Vec<u8>>,
-        server: &mut prio::server::Server,
-        invalid_uuids: &mut Vec<Uuid>,
-    ) -> Result<()> {
+        &self,
+        inputs: AggregationInputs,
+        accumulator: &mut Accumulator,
+    ) -> Result<Vec<Uuid>> {
+        let mut ingestion_transport = SharedTransport(self.ingestion_transport);
+        let mut own_validation_transport = SharedTransport(self.own_validation_transport);
+        let mut peer_validation_transport = SharedTransport(self.peer_validation_transport);
         let ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
-            BatchReader::new(
-                Batch::new_ingestion(self.aggregation_name, batch_id, batch_date),
-                self.ingestion_transport,
-            );
+            BatchReader::new(inputs.ingestion, &mut ingestion_transport);
         let own_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
-            BatchReader::new(
-                Batch::new_validation(self.aggregation_name, batch_id, batch_date, self.is_first),
-                self.own_validation_transport,
-            );
-        let peer_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
-            BatchReader::new(
-                Batch::new_validation(self.aggregation_name, batch_id, batch_date, !self.is_first),
-                self.peer_validation_transport,
-            );
-        let peer_validation_header =
-            peer_validation_batch.header(&self.peer_share_processor_key)?;
-        let own_validation_header = own_validation_batch.header(share_processor_public_key)?;
-        let ingestion_header = ingestion_batch.header(&self.ingestor_key)?;
+            BatchReader::new(inputs.own_validation, &mut own_validation_transport);
+        let peer_validation_batch = ValidationBatchReader::new(
+            inputs.peer_validation,
+            &mut peer_validation_transport,
+            self.peer_share_processor_key,
+        );
+        let (_, ingestion_header) =
+            ingestion_batch.verified_header_with_keys(self.ingestor_keys)?;
+        let ingestion_header = ingestion_header.header;
+        let peer_validation_header = peer_validation_batch.header(&ingestion_header)?;
+        let own_validation_header = own_validation_batch.header(self.share_processor_public_key)?;
 
         // Make sure all the parameters in the headers line up
         if !peer_validation_header.check_parameters(&own_validation_header) {
@@ -187,92 +422,866 @@ impl<'a> BatchAggregator<'a> {
                 own_validation_header
             ));
         }
-        if !ingestion_header.check_parameters(&peer_validation_header) {
+
+        let peer_validation_packets = peer_validation_batch.packets(&peer_validation_header)?;
+        let mut own_validation_packet_reader =
+            own_validation_batch.packet_file_reader(&own_validation_header)?;
+        let mut ingestion_packet_reader =
+            ingestion_batch.sharded_packet_reader(&ingestion_header)?;
+
+        accumulator.accumulate_batch(
+            std::iter::from_fn(|| match ingestion_packet_reader.read_packet() {
+                Ok(packet) => Some(Ok(packet)),
+                Err(Error::EofError) => None,
+                Err(e) => Some(Err(e.into())),
+            }),
+            peer_validation_packets,
+            std::iter::from_fn(|| {
+                next_validation_packet(&mut own_validation_packet_reader).transpose()
+            }),
+        )
+    }
+}
+
+/// Sums one share processor's shares of validated packets, for callers that
+/// feed it packets from sources of their own rather than have BatchAggregator
+/// fetch whole batches. All the packets summed must come from batches with the
+/// parameters of the ingestion header the Accumulator was created with.
+pub struct Accumulator {
+    server: Server,
+    name: String,
+    bins: i32,
+    epsilon: f64,
+    prime: i64,
+    number_of_servers: i32,
+    hamming_weight: Option<i32>,
+}
+
+impl Accumulator {
+    /// Creates an Accumulator for batches with the parameters in the provided
+    /// ingestion header, which sums the shares of the share processor with the
+    /// provided identity, decrypting them with the provided key.
+    pub fn new(
+        ingestion_header: &IngestionHeader,
+        server_identity: ServerIdentity,
+        ecies_key: &PrivateKey,
+    ) -> Accumulator {
+        Accumulator {
+            server: Server::new(
+                ingestion_header.bins as usize,
+                server_identity.is_first(),
+                ecies_key.clone(),
+            ),
+            name: ingestion_header.name.clone(),
+            bins: ingestion_header.bins,
+            epsilon: ingestion_header.epsilon,
+            prime: ingestion_header.prime,
+            number_of_servers: ingestion_header.number_of_servers,
+            hamming_weight: ingestion_header.hamming_weight,
+        }
+    }
+
+    /// Adds the share in the provided ingestion packet to the sum if the peer's
+    /// and this share processor's validation packets for it show the packet to
+    /// be valid, and returns whether it did. Fails if either validation packet
+    /// is for another packet or the share can't be decrypted.
+    pub fn accumulate(
+        &mut self,
+        ingestion_packet: &IngestionDataSharePacket,
+        peer_validation_packet: &ValidationPacket,
+        own_validation_packet: &ValidationPacket,
+    ) -> Result<bool> {
+        for validation_packet in &[peer_validation_packet, own_validation_packet] {
+            if validation_packet.uuid != ingestion_packet.uuid {
+                return Err(anyhow!(
+                    "validation packet {} does not match ingestion packet {}",
+                    validation_packet.uuid,
+                    ingestion_packet.uuid
+                ));
+            }
+        }
+        self.server
+            .aggregate(
+                &ingestion_packet.encrypted_payload,
+                &VerificationMessage::try_from(peer_validation_packet)?,
+                &VerificationMessage::try_from(own_validation_packet)?,
+            )
+            .context("failed to validate packets")
+    }
+
+    /// Sums the packets of one batch, provided in the order of the batch, and
+    /// returns the UUIDs of the ingestion packets that were not summed. A share
+    /// processor may leave packets it could not validate out of its validation
+    /// batch (see PacketFailurePolicy::Record), so the validation packets are
+    /// matched up with the ingestion packets in order, and any ingestion packet
+    /// lacking either validation is invalid. Validation packets that match no
+    /// ingestion packet, e.g. because they are out of order, fail the batch, as
+    /// does an error from any of the iterators.
+    pub fn accumulate_batch(
+        &mut self,
+        ingestion_packets: impl Iterator<Item = Result<IngestionDataSharePacket>>,
+        mut peer_validation_packets: impl Iterator<Item = Result<ValidationPacket>>,
+        mut own_validation_packets: impl Iterator<Item = Result<ValidationPacket>>,
+    ) -> Result<Vec<Uuid>> {
+        let mut invalid_uuids = Vec::new();
+        let mut peer_validation_packet = peer_validation_packets.next().transpose()?;
+        let mut own_validation_packet = own_validation_packets.next().transpose()?;
+        for ingestion_packet in ingestion_packets {
+            let ingestion_packet = ingestion_packet?;
+            let peer_matches = peer_validation_packet
+                .as_ref()
+                .is_some_and(|p| p.uuid == ingestion_packet.uuid);
+            let own_matches = own_validation_packet
+                .as_ref()
+                .is_some_and(|p| p.uuid == ingestion_packet.uuid);
+            if peer_matches && own_matches {
+                // Both validation packets are present, so unwrapping is safe.
+                if !self.accumulate(
+                    &ingestion_packet,
+                    peer_validation_packet.as_ref().unwrap(),
+                    own_validation_packet.as_ref().unwrap(),
+                )? {
+                    invalid_uuids.push(ingestion_packet.uuid);
+                }
+            } else {
+                invalid_uuids.push(ingestion_packet.uuid);
+            }
+            if peer_matches {
+                peer_validation_packet = peer_validation_packets.next().transpose()?;
+            }
+            if own_matches {
+                own_validation_packet = own_validation_packets.next().transpose()?;
+            }
+        }
+
+        if let Some(packet) = peer_validation_packet.or(own_validation_packet) {
             return Err(anyhow!(
-                "ingestion header does not match peer validation header. Ingestion: {:?}\nPeer:{:?}",
-                ingestion_header,
-                peer_validation_header
+                "validation packet {} does not match any ingestion packet",
+                packet.uuid
             ));
         }
+        Ok(invalid_uuids)
+    }
 
-        let mut peer_validation_packet_reader =
-            peer_validation_batch.packet_file_reader(&peer_validation_header)?;
-        let mut own_validation_packet_reader =
-            own_validation_batch.packet_file_reader(&own_validation_header)?;
-        let mut ingestion_packet_reader = ingestion_batch.packet_file_reader(&ingestion_header)?;
+    /// Adds the sum in another Accumulator, e.g. one that summed other batches
+    /// on another thread, into this one.
+    pub fn merge(&mut self, other: &Accumulator) {
+        self.server.merge_total_shares(other.server.total_shares());
+    }
 
-        loop {
-            let peer_validation_packet =
-                match ValidationPacket::read(&mut peer_validation_packet_reader) {
-                    Ok(p) => Some(p),
-                    Err(Error::EofError) => None,
-                    Err(e) => return Err(e.into()),
-                };
-            let own_validation_packet =
-                match ValidationPacket::read(&mut own_validation_packet_reader) {
-                    Ok(p) => Some(p),
-                    Err(Error::EofError) => None,
-                    Err(e) => return Err(e.into()),
-                };
-            let ingestion_packet =
-                match IngestionDataSharePacket::read(&mut ingestion_packet_reader) {
-                    Ok(p) => Some(p),
-                    Err(Error::EofError) => None,
-                    Err(e) => return Err(e.into()),
-                };
-
-            // All three packet files should contain the same number of packets,
-            // so if any of the readers hit EOF before the others, something is
-            // fishy.
-            let (peer_validation_packet, own_validation_packet, ingestion_packet) = match (
-                &peer_validation_packet,
-                &own_validation_packet,
-                &ingestion_packet,
-            ) {
-                (Some(a), Some(b), Some(c)) => (a, b, c),
-                (None, None, None) => break,
-                (_, _, _) => {
-                    return Err(anyhow!(
-                        "unexpected early EOF when checking peer validations"
-                    ));
+    /// Returns the sum as a SumPart over the provided batches and aggregation
+    /// window, naming the provided digest of the packet file listing the
+    /// invalid packets, which the caller writes.
+    pub fn finish(
+        self,
+        batch_uuids: Vec<Uuid>,
+        aggregation_start: &BatchDate,
+        aggregation_end: &BatchDate,
+        packet_file_digest: Vec<u8>,
+    ) -> SumPart {
+        SumPart {
+            batch_uuids,
+            name: self.name,
+            bins: self.bins,
+            epsilon: self.epsilon,
+            prime: self.prime,
+            number_of_servers: self.number_of_servers,
+            hamming_weight: self.hamming_weight,
+            sum: self
+                .server
+                .total_shares()
+                .iter()
+                .map(|f| u32::from(*f) as i64)
+                .collect(),
+            aggregation_start_time: aggregation_start.as_naive_date_time().timestamp_millis(),
+            aggregation_end_time: aggregation_end.as_naive_date_time().timestamp_millis(),
+            packet_file_digest,
+        }
+    }
+}
+
+/// Lets the workers in BatchAggregator::generate_sum_part read through one
+/// transport at once: reading only needs a shared reference, but BatchReader
+/// wants a mutable one. Writing through a SharedTransport fails.
+struct SharedTransport<'a>(&'a AggregationTransport);
+
+impl Transport for SharedTransport<'_> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        self.0.get(key)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Err(anyhow!("cannot write {} through a shared transport", key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.list(prefix)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.0.size(key)
+    }
+}
+
+/// Reads a peer share processor's validation batch so that it can be summed
+/// with the ingestion batch it validates. The header is only returned once its
+/// signature verifies with the peer's key and it describes the expected
+/// ingestion batch, and packets only once the packet file's digest matches the
+/// header. Errors name the file and the check that failed.
+pub struct ValidationBatchReader<'a> {
+    batch_reader: BatchReader<'a, ValidationHeader, ValidationPacket>,
+    peer_key: &'a UnparsedPublicKey<Vec<u8>>,
+}
+
+impl<'a> ValidationBatchReader<'a> {
+    pub fn new(
+        batch: Batch,
+        transport: &'a mut dyn Transport,
+        peer_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ) -> ValidationBatchReader<'a> {
+        ValidationBatchReader {
+            batch_reader: BatchReader::new(batch, transport),
+            peer_key,
+        }
+    }
+
+    /// Fetches the header and its signature, and returns the header if the
+    /// signature verifies with the peer's key and the header's parameters
+    /// match those of the provided ingestion header, which is assumed to be
+    /// trusted. Mismatched parameters are reported as
+    /// Error::ValidationBatchMismatch.
+    pub fn header(&self, ingestion_header: &IngestionHeader) -> Result<ValidationHeader> {
+        let header_key = self.batch_reader.batch().key(BatchFileKind::Header);
+        let header = self
+            .batch_reader
+            .header(self.peer_key)
+            .with_context(|| format!("failed to verify {}", header_key))?;
+        let expected = HeaderParameters::from_ingestion_header(ingestion_header);
+        if let Some((parameter, expected, actual)) =
+            expected.mismatch(&HeaderParameters::from_validation_header(&header))
+        {
+            return Err(Error::ValidationBatchMismatch(format!(
+                "{} has {} {} but the ingestion header has {}",
+                header_key, parameter, actual, expected
+            ))
+            .into());
+        }
+        Ok(header)
+    }
+
+    /// Returns an iterator over the packets in the packet file, if its digest
+    /// matches the provided header, as obtained from header().
+    pub fn packets(&self, header: &ValidationHeader) -> Result<ValidationPackets<'_>> {
+        let key = self.batch_reader.batch().key(BatchFileKind::Packets);
+        Ok(ValidationPackets {
+            reader: self
+                .batch_reader
+                .packet_file_reader(header)
+                .with_context(|| format!("failed to verify {}", key))?,
+            key: key.to_owned(),
+            declared_packet_count: header.packet_count,
+            packets_read: 0,
+            done: false,
+        })
+    }
+}
+
+/// Iterates over the packets of a validation packet file. See
+/// ValidationBatchReader::packets. If a packet can't be read, or the file
+/// holds a different number of packets than its header declares, an error is
+/// yielded and then nothing more.
+pub struct ValidationPackets<'b> {
+    reader: Reader<'b, Box<dyn Read>>,
+    key: String,
+    declared_packet_count: Option<u64>,
+    packets_read: u64,
+    done: bool,
+}
+
+impl Iterator for ValidationPackets<'_> {
+    type Item = Result<ValidationPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let error = match ValidationPacket::read(&mut self.reader) {
+            Ok(packet) => {
+                self.packets_read += 1;
+                return Some(Ok(packet));
+            }
+            Err(Error::EofError) => match self.declared_packet_count {
+                Some(declared) if declared != self.packets_read => {
+                    Error::PacketCountMismatch(declared, self.packets_read)
                 }
-            };
+                _ => {
+                    self.done = true;
+                    return None;
+                }
+            },
+            Err(e) => e,
+        };
+        self.done = true;
+        Some(Err(anyhow::Error::from(error).context(format!(
+            "failed to read packet {} of {}",
+            self.packets_read, self.key
+        ))))
+    }
+}
 
-            // TODO(timg) we need to make sure we are evaluating a valid triple
-            // of (peer validation, own validation, ingestion), i.e., they must
-            // have the same UUID. Can we assume that the peer validations will
-            // be in the same order as ours, or do we need to do an O(n) search
-            // of the peer validation packets to find the right UUID? Further,
-            // if aggregation fails, then we record the invalid UUID and send
-            // that along to the aggregator. But if some UUIDs are missing from
-            // the peer validation packet file (the EOF case handled above),
-            // should that UUID be marked as invalid or do we abort handling of
-            // the whole batch?
-            // For now I am assuming that all batches maintain the same order
-            // and that they are required to contain the same set of UUIDs.
-            if peer_validation_packet.uuid != own_validation_packet.uuid
-                || peer_validation_packet.uuid != ingestion_packet.uuid
-                || own_validation_packet.uuid != ingestion_packet.uuid
-            {
-                return Err(anyhow!(
-                    "mismatch between peer validation, own validation and ingestion packet UUIDs: {} {} {}",
-                    peer_validation_packet.uuid,
-                    own_validation_packet.uuid,
-                    ingestion_packet.uuid));
+/// The parameters that an ingestion header and the validation headers for it
+/// must agree on, by name, formatted for comparison and error messages.
+struct HeaderParameters([(&'static str, String); 7]);
+
+impl HeaderParameters {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        batch_uuid: &Uuid,
+        name: &str,
+        bins: i32,
+        epsilon: f64,
+        prime: i64,
+        number_of_servers: i32,
+        hamming_weight: Option<i32>,
+    ) -> HeaderParameters {
+        HeaderParameters([
+            ("batch UUID", batch_uuid.to_string()),
+            ("name", name.to_owned()),
+            ("bins", bins.to_string()),
+            ("epsilon", epsilon.to_string()),
+            ("prime", prime.to_string()),
+            ("number of servers", number_of_servers.to_string()),
+            ("hamming weight", format!("{:?}", hamming_weight)),
+        ])
+    }
+
+    fn from_ingestion_header(header: &IngestionHeader) -> HeaderParameters {
+        HeaderParameters::new(
+            &header.batch_uuid,
+            &header.name,
+            header.bins,
+            header.epsilon,
+            header.prime,
+            header.number_of_servers,
+            header.hamming_weight,
+        )
+    }
+
+    fn from_validation_header(header: &ValidationHeader) -> HeaderParameters {
+        HeaderParameters::new(
+            &header.batch_uuid,
+            &header.name,
+            header.bins,
+            header.epsilon,
+            header.prime,
+            header.number_of_servers,
+            header.hamming_weight,
+        )
+    }
+
+    /// Returns the name of the first parameter that differs between self and
+    /// other, and its values in each.
+    fn mismatch<'p>(
+        &'p self,
+        other: &'p HeaderParameters,
+    ) -> Option<(&'static str, &'p str, &'p str)> {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .find(|((_, a), (_, b))| a != b)
+            .map(|((parameter, a), (_, b))| (*parameter, a.as_str(), b.as_str()))
+    }
+}
+
+/// Checks that two validation batches for the same ingestion batch, e.g. the
+/// PHA's and the facilitator's, can be aggregated together: that their headers
+/// verify with the provided keys and agree on the parameters they share and on
+/// the number of packets, if both declare it, and that their packet files list
+/// the same packet UUIDs in the same order. Validation shares themselves
+/// differ between share processors and are not compared. Fails with
+/// Error::ValidationBatchMismatch describing the first divergence found, or
+/// with Error::AnyhowError if either batch can't be read or doesn't verify.
+pub fn compare_validation_batches(
+    a_reader: &BatchReader<'_, ValidationHeader, ValidationPacket>,
+    a_key: &UnparsedPublicKey<Vec<u8>>,
+    b_reader: &BatchReader<'_, ValidationHeader, ValidationPacket>,
+    b_key: &UnparsedPublicKey<Vec<u8>>,
+) -> Result<(), Error> {
+    let a_name = a_reader.batch().key(BatchFileKind::Header);
+    let b_name = b_reader.batch().key(BatchFileKind::Header);
+    let a_header = a_reader.header(a_key).map_err(Error::AnyhowError)?;
+    let b_header = b_reader.header(b_key).map_err(Error::AnyhowError)?;
+
+    if let Some((parameter, a_value, b_value)) = HeaderParameters::from_validation_header(&a_header)
+        .mismatch(&HeaderParameters::from_validation_header(&b_header))
+    {
+        return Err(Error::ValidationBatchMismatch(format!(
+            "{} has {} {} but {} has {}",
+            a_name, parameter, a_value, b_name, b_value
+        )));
+    }
+    if let (Some(a_count), Some(b_count)) = (a_header.packet_count, b_header.packet_count) {
+        if a_count != b_count {
+            return Err(Error::ValidationBatchMismatch(format!(
+                "{} declares {} packets but {} declares {}",
+                a_name, a_count, b_name, b_count
+            )));
+        }
+    }
+
+    let mut a_packets = a_reader
+        .packet_file_reader(&a_header)
+        .map_err(Error::AnyhowError)?;
+    let mut b_packets = b_reader
+        .packet_file_reader(&b_header)
+        .map_err(Error::AnyhowError)?;
+    let mut index = 0;
+    loop {
+        let a_packet = next_validation_packet(&mut a_packets).map_err(Error::AnyhowError)?;
+        let b_packet = next_validation_packet(&mut b_packets).map_err(Error::AnyhowError)?;
+        let divergence = match (a_packet, b_packet) {
+            (None, None) => return Ok(()),
+            (Some(a), Some(b)) if a.uuid == b.uuid => {
+                index += 1;
+                continue;
             }
+            (Some(a), Some(b)) => format!(
+                "packet {} is {} in {} but {} in {}",
+                index, a.uuid, a_name, b.uuid, b_name
+            ),
+            (Some(a), None) => format!(
+                "{} has packet {} at {} but {} ends after {} packets",
+                a_name, a.uuid, index, b_name, index
+            ),
+            (None, Some(b)) => format!(
+                "{} has packet {} at {} but {} ends after {} packets",
+                b_name, b.uuid, index, a_name, index
+            ),
+        };
+        return Err(Error::ValidationBatchMismatch(divergence));
+    }
+}
 
-            if !server
-                .aggregate(
-                    &ingestion_packet.encrypted_payload,
-                    &VerificationMessage::try_from(peer_validation_packet)?,
-                    &VerificationMessage::try_from(own_validation_packet)?,
-                )
-                .context("failed to validate packets")?
-            {
-                invalid_uuids.push(peer_validation_packet.uuid);
+/// Reads the next packet from a validation packet file, returning None at the
+/// end of the file.
+fn next_validation_packet<R: Read>(reader: &mut Reader<R>) -> Result<Option<ValidationPacket>> {
+    match ValidationPacket::read(reader) {
+        Ok(p) => Ok(Some(p)),
+        Err(Error::EofError) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        intake::BatchIntaker,
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_facilitator_signing_private_key, default_facilitator_signing_public_key,
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            default_pha_signing_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::MemoryTransport,
+    };
+    use chrono::NaiveDateTime;
+    use ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+
+    /// Writes a validation batch containing packets with the provided UUIDs.
+    fn write_validation_batch(
+        transport: &mut MemoryTransport,
+        batch: Batch,
+        mut header: ValidationHeader,
+        uuids: &[Uuid],
+        key: &EcdsaKeyPair,
+    ) {
+        let mut writer: BatchWriter<'_, ValidationHeader, ValidationPacket> =
+            BatchWriter::new(batch, transport);
+        let digest = writer
+            .packet_file_writer(|packet_writer| {
+                for (i, uuid) in uuids.iter().enumerate() {
+                    ValidationPacket {
+                        uuid: *uuid,
+                        f_r: i as i64,
+                        g_r: i as i64,
+                        h_r: i as i64,
+                    }
+                    .write(packet_writer)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        header.packet_file_digest = digest.as_ref().to_vec();
+        let signature = writer.put_header(&header, key).unwrap();
+        writer.put_signature(&signature).unwrap();
+    }
+
+    #[test]
+    fn compare_validation_batch_divergence() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let validation_batch = |server_identity| {
+            batch
+                .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, server_identity)
+                .into_batch()
+        };
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let pha_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+        let facilitator_signing_key = default_facilitator_signing_private_key();
+        let facilitator_public_key = default_facilitator_signing_public_key();
+        let header = || ValidationHeader {
+            batch_uuid: batch.batch_id,
+            name: "fake-aggregation-1".to_owned(),
+            bins: 10,
+            epsilon: 0.11,
+            prime: 4293918721,
+            number_of_servers: 2,
+            hamming_weight: None,
+            packet_file_digest: Vec::new(),
+            packet_count: Some(3),
+            signature_algorithm: None,
+        };
+        let uuids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let pha_header_key = validation_batch(ServerIdentity::Pha)
+            .key(BatchFileKind::Header)
+            .to_owned();
+        let facilitator_header_key = validation_batch(ServerIdentity::Facilitator)
+            .key(BatchFileKind::Header)
+            .to_owned();
+        let mut pha_transport = MemoryTransport::new();
+        write_validation_batch(
+            &mut pha_transport,
+            validation_batch(ServerIdentity::Pha),
+            header(),
+            &uuids,
+            &pha_signing_key,
+        );
+
+        type Divergence = fn(&mut ValidationHeader, &mut Vec<Uuid>);
+        let divergences: &[(Divergence, Option<String>)] = &[
+            (|_, _| (), None),
+            (
+                |header, _| header.bins = 11,
+                Some(format!(
+                    "{} has bins 10 but {} has 11",
+                    pha_header_key, facilitator_header_key
+                )),
+            ),
+            (
+                |header, _| header.hamming_weight = Some(2),
+                Some(format!(
+                    "{} has hamming weight None but {} has Some(2)",
+                    pha_header_key, facilitator_header_key
+                )),
+            ),
+            (
+                |header, _| header.packet_count = Some(4),
+                Some(format!(
+                    "{} declares 3 packets but {} declares 4",
+                    pha_header_key, facilitator_header_key
+                )),
+            ),
+            // Older share processors don't declare a packet count
+            (|header, _| header.packet_count = None, None),
+            (
+                |header, uuids| {
+                    header.packet_count = None;
+                    uuids.swap(1, 2);
+                },
+                Some(format!(
+                    "packet 1 is {} in {} but {} in {}",
+                    uuids[1], pha_header_key, uuids[2], facilitator_header_key
+                )),
+            ),
+            (
+                |header, uuids| {
+                    header.packet_count = None;
+                    uuids.pop();
+                },
+                Some(format!(
+                    "{} has packet {} at 2 but {} ends after 2 packets",
+                    pha_header_key, uuids[2], facilitator_header_key
+                )),
+            ),
+            (
+                |header, uuids| {
+                    header.packet_count = None;
+                    uuids.push(Uuid::nil());
+                },
+                Some(format!(
+                    "{} has packet {} at 3 but {} ends after 3 packets",
+                    facilitator_header_key,
+                    Uuid::nil(),
+                    pha_header_key
+                )),
+            ),
+        ];
+        for (diverge, expected_divergence) in divergences {
+            let mut facilitator_header = header();
+            let mut facilitator_uuids = uuids.to_vec();
+            diverge(&mut facilitator_header, &mut facilitator_uuids);
+            let mut facilitator_transport = MemoryTransport::new();
+            write_validation_batch(
+                &mut facilitator_transport,
+                validation_batch(ServerIdentity::Facilitator),
+                facilitator_header,
+                &facilitator_uuids,
+                &facilitator_signing_key,
+            );
+
+            let mut pha_reader_transport = pha_transport.clone();
+            let pha_batch: BatchReader<'_, ValidationHeader, ValidationPacket> = BatchReader::new(
+                validation_batch(ServerIdentity::Pha),
+                &mut pha_reader_transport,
+            );
+            let facilitator_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    validation_batch(ServerIdentity::Facilitator),
+                    &mut facilitator_transport,
+                );
+            let result = compare_validation_batches(
+                &pha_batch,
+                &pha_public_key,
+                &facilitator_batch,
+                &facilitator_public_key,
+            );
+            match (result, expected_divergence) {
+                (Ok(()), None) => (),
+                (Err(Error::ValidationBatchMismatch(divergence)), Some(expected)) => {
+                    assert_eq!(&divergence, expected)
+                }
+                (result, expected) => panic!(
+                    "unexpected result {:?}, expected divergence {:?}",
+                    result, expected
+                ),
+            }
+
+            // Either batch failing to verify is not a divergence
+            match compare_validation_batches(
+                &pha_batch,
+                &facilitator_public_key,
+                &facilitator_batch,
+                &facilitator_public_key,
+            ) {
+                Err(Error::AnyhowError(_)) => (),
+                v => panic!("unexpected result {:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn read_peer_validation_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut facilitator_validate_transport = MemoryTransport::new();
+        let facilitator_ecies_key =
+            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            20,
+            0.11,
+            100,
+            100,
+        )
+        .unwrap();
+        let ingestor_public_key = default_ingestor_public_key();
+        let facilitator_signing_key = default_facilitator_signing_private_key();
+        BatchIntaker::new(
+            None,
+            &batch,
+            &mut facilitator_ingest_transport,
+            &mut facilitator_validate_transport,
+            ServerIdentity::Facilitator,
+            &facilitator_ecies_key,
+            &facilitator_signing_key,
+            &ingestor_public_key,
+        )
+        .unwrap()
+        .generate_validation_share()
+        .unwrap();
+
+        let ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion(&batch.aggregation_name, &batch.batch_id, &batch.date),
+                &mut pha_ingest_transport,
+            );
+        let ingestion_header = ingestion_batch.header(&ingestor_public_key).unwrap();
+        let mut ingestion_packets = ingestion_batch
+            .packet_file_reader(&ingestion_header)
+            .unwrap();
+        let mut ingestion_uuids = Vec::new();
+        loop {
+            match IngestionDataSharePacket::read(&mut ingestion_packets) {
+                Ok(packet) => ingestion_uuids.push(packet.uuid),
+                Err(Error::EofError) => break,
+                Err(e) => panic!("failed to read ingestion packet: {:?}", e),
             }
         }
 
-        Ok(())
+        // The PHA reads the facilitator's validation batch as its peer's
+        let peer_batch = || {
+            batch
+                .peer_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+                .into_batch()
+        };
+        let header_key = peer_batch().key(BatchFileKind::Header).to_owned();
+        let packets_key = peer_batch().key(BatchFileKind::Packets).to_owned();
+        let facilitator_public_key = default_facilitator_signing_public_key();
+        let mut read_transport = facilitator_validate_transport.clone();
+        let reader =
+            ValidationBatchReader::new(peer_batch(), &mut read_transport, &facilitator_public_key);
+        let header = reader.header(&ingestion_header).unwrap();
+        assert_eq!(header.packet_count, Some(20));
+        let uuids: Vec<Uuid> = reader
+            .packets(&header)
+            .unwrap()
+            .map(|packet| packet.unwrap().uuid)
+            .collect();
+        assert_eq!(uuids, ingestion_uuids);
+
+        let mut mismatched_ingestion_header = ingestion_batch.header(&ingestor_public_key).unwrap();
+        mismatched_ingestion_header.bins = 11;
+        match reader
+            .header(&mismatched_ingestion_header)
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::ValidationBatchMismatch(message)) => assert_eq!(
+                message,
+                &format!("{} has bins 10 but the ingestion header has 11", header_key)
+            ),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let pha_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_FIXED_SIGNING,
+                &default_pha_signing_private_key(),
+            )
+            .unwrap()
+            .public_key()
+            .as_ref()
+            .to_vec(),
+        );
+        let mut wrong_key_transport = facilitator_validate_transport.clone();
+        let wrong_key_reader =
+            ValidationBatchReader::new(peer_batch(), &mut wrong_key_transport, &pha_public_key);
+        let error = wrong_key_reader.header(&ingestion_header).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "failed to verify {}: invalid signature on header: cryptography error: signature does not verify",
+                header_key
+            )
+        );
+
+        // Flip a bit in some validation packet
+        let mut packet_file = Vec::new();
+        facilitator_validate_transport
+            .get(&packets_key)
+            .unwrap()
+            .read_to_end(&mut packet_file)
+            .unwrap();
+        let middle = packet_file.len() / 2;
+        packet_file[middle] ^= 1;
+        let mut writer = facilitator_validate_transport.put(&packets_key).unwrap();
+        writer.write_all(&packet_file).unwrap();
+        writer.complete_upload().unwrap();
+        let mut tampered_transport = facilitator_validate_transport.clone();
+        let tampered_reader = ValidationBatchReader::new(
+            peer_batch(),
+            &mut tampered_transport,
+            &facilitator_public_key,
+        );
+        let header = tampered_reader.header(&ingestion_header).unwrap();
+        let error = tampered_reader.packets(&header).err().unwrap();
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "failed to verify {}: digest of packet file {} does not match header",
+                packets_key, packets_key
+            )
+        );
+    }
+
+    #[test]
+    fn validation_packet_count_mismatch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let validation_batch = || {
+            batch
+                .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Facilitator)
+                .into_batch()
+        };
+        let packets_key = validation_batch().key(BatchFileKind::Packets).to_owned();
+        let header = ValidationHeader {
+            batch_uuid: batch.batch_id,
+            name: "fake-aggregation-1".to_owned(),
+            bins: 10,
+            epsilon: 0.11,
+            prime: 4293918721,
+            number_of_servers: 2,
+            hamming_weight: None,
+            packet_file_digest: Vec::new(),
+            packet_count: Some(3),
+            signature_algorithm: None,
+        };
+        let mut transport = MemoryTransport::new();
+        write_validation_batch(
+            &mut transport,
+            validation_batch(),
+            header,
+            &[Uuid::new_v4(), Uuid::new_v4()],
+            &default_facilitator_signing_private_key(),
+        );
+
+        let facilitator_public_key = default_facilitator_signing_public_key();
+        let reader =
+            ValidationBatchReader::new(validation_batch(), &mut transport, &facilitator_public_key);
+        let header = reader.batch_reader.header(&facilitator_public_key).unwrap();
+        let mut packets = reader.packets(&header).unwrap();
+        assert!(packets.next().unwrap().is_ok());
+        assert!(packets.next().unwrap().is_ok());
+        let error = packets.next().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::PacketCountMismatch(3, 2))
+        ));
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "failed to read packet 2 of {}: header declares 3 packets but 2 were decoded",
+                packets_key
+            )
+        );
+        assert!(packets.next().is_none());
     }
 }
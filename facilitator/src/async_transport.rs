@@ -0,0 +1,570 @@
+use crate::{
+    transport::{
+        basic_runtime, LocalFileTransport, S3Transport, Transport, TransportWriter,
+        MINIMUM_UPLOAD_PART_SIZE,
+    },
+    Error,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, S3Client, UploadPartRequest, S3,
+};
+use std::{
+    future::Future,
+    io::{Read, Write},
+    mem,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{
+    fs::{create_dir_all, File},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    runtime::Runtime,
+};
+
+/// An AsyncTransportWriter is the asynchronous counterpart of TransportWriter:
+/// it extends tokio::io::AsyncWrite with methods that explicitly allow callers
+/// to complete or cancel an upload.
+#[async_trait]
+pub trait AsyncTransportWriter: AsyncWrite + Send + Unpin {
+    /// Complete an upload operation, flushing any buffered writes and cleaning
+    /// up any related resources. Callers must call this method or cancel_upload
+    /// when they are done with the AsyncTransportWriter.
+    async fn complete_upload(&mut self) -> Result<()>;
+
+    /// Cancel an upload operation, cleaning up any related resources. Callers
+    /// must call this method or complete_upload when they are done with the
+    /// AsyncTransportWriter.
+    async fn cancel_upload(&mut self) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: AsyncTransportWriter + ?Sized> AsyncTransportWriter for Box<T> {
+    async fn complete_upload(&mut self) -> Result<()> {
+        (**self).complete_upload().await
+    }
+
+    async fn cancel_upload(&mut self) -> Result<()> {
+        (**self).cancel_upload().await
+    }
+}
+
+/// AsyncTransport is the asynchronous counterpart of Transport, for use by
+/// code running on a tokio runtime that should not block it on I/O.
+#[async_trait]
+pub trait AsyncTransport: Send + Sync {
+    /// Returns a tokio::io::AsyncRead instance from which the contents of the
+    /// value of the provided key may be read.
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Send + Unpin>>;
+    /// Returns an AsyncTransportWriter into which the contents of the value
+    /// may be written.
+    async fn put(&mut self, key: &str) -> Result<Box<dyn AsyncTransportWriter>>;
+}
+
+#[async_trait]
+impl AsyncTransport for LocalFileTransport {
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let path = self.path(key);
+        let f = File::open(&path)
+            .await
+            .with_context(|| format!("opening {}", path.display()))?;
+        Ok(Box::new(f))
+    }
+
+    async fn put(&mut self, key: &str) -> Result<Box<dyn AsyncTransportWriter>> {
+        // This lists directories synchronously, but only those along the key's
+        // path, which is cheap next to the upload itself.
+        self.check_case_collisions(key)?;
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)
+                .await
+                .with_context(|| format!("creating parent directories {}", parent.display()))?;
+        }
+        let f = File::create(&path)
+            .await
+            .with_context(|| format!("creating {}", path.display()))?;
+        Ok(Box::new(f))
+    }
+}
+
+#[async_trait]
+impl AsyncTransportWriter for File {
+    async fn complete_upload(&mut self) -> Result<()> {
+        // tokio::fs::File performs writes in the background, so they are only
+        // guaranteed to have happened once it has been flushed.
+        self.flush().await.context("failed to flush file")
+    }
+
+    async fn cancel_upload(&mut self) -> Result<()> {
+        // This method is a no-op for local files
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsyncTransport for S3Transport {
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let get_output = self
+            .client()
+            .get_object(GetObjectRequest {
+                bucket: self.bucket().to_owned(),
+                key: key.to_string(),
+                ..Default::default()
+            })
+            .await
+            .context("error getting S3 object")?;
+
+        let body = get_output.body.context("no body in GetObjectResponse")?;
+
+        Ok(Box::new(Box::pin(body.into_async_read())))
+    }
+
+    async fn put(&mut self, key: &str) -> Result<Box<dyn AsyncTransportWriter>> {
+        Ok(Box::new(
+            AsyncMultipartUploadWriter::new(
+                self.client(),
+                self.bucket().to_owned(),
+                key.to_string(),
+                MINIMUM_UPLOAD_PART_SIZE,
+            )
+            .await?,
+        ))
+    }
+}
+
+type UploadPartFuture = Pin<Box<dyn Future<Output = Result<CompletedPart>> + Send>>;
+
+/// AsyncMultipartUploadWriter is the asynchronous counterpart of
+/// MultipartUploadWriter. It accumulates written content in a memory buffer
+/// and uploads it in an UploadPart call whenever more than
+/// minimum_upload_part_size bytes are buffered. At most one part is uploaded
+/// at a time, and writes wait for the previous part to be uploaded, which
+/// bounds memory use to about two parts. Unlike MultipartUploadWriter, it does
+/// not abort the upload by itself if a part fails to upload, since that can't
+/// be done from AsyncWrite::poll_write. Callers are required to call
+/// cancel_upload in that case anyway.
+struct AsyncMultipartUploadWriter {
+    client: S3Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    completed_parts: Vec<CompletedPart>,
+    minimum_upload_part_size: usize,
+    buffer: Vec<u8>,
+    in_flight_part: Option<UploadPartFuture>,
+}
+
+impl AsyncMultipartUploadWriter {
+    async fn new(
+        client: S3Client,
+        bucket: String,
+        key: String,
+        minimum_upload_part_size: usize,
+    ) -> Result<AsyncMultipartUploadWriter> {
+        let create_output = client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                ..Default::default()
+            })
+            .await
+            .context("error creating multipart upload")?;
+
+        Ok(AsyncMultipartUploadWriter {
+            client,
+            bucket,
+            key,
+            upload_id: create_output
+                .upload_id
+                .context("no upload ID in CreateMultipartUploadResponse")?,
+            completed_parts: Vec::new(),
+            minimum_upload_part_size,
+            buffer: Vec::with_capacity(minimum_upload_part_size * 2),
+            in_flight_part: None,
+        })
+    }
+
+    /// Moves the content of the internal buffer into an UploadPart request and
+    /// returns a future that resolves once it has been uploaded.
+    fn upload_part(&mut self) -> UploadPartFuture {
+        let part_number = (self.completed_parts.len() + 1) as i64;
+        let client = self.client.clone();
+        let request = UploadPartRequest {
+            bucket: self.bucket.to_string(),
+            key: self.key.to_string(),
+            upload_id: self.upload_id.clone(),
+            part_number,
+            body: Some(
+                mem::replace(
+                    &mut self.buffer,
+                    Vec::with_capacity(self.minimum_upload_part_size * 2),
+                )
+                .into(),
+            ),
+            ..Default::default()
+        };
+
+        Box::pin(async move {
+            let upload_output = client
+                .upload_part(request)
+                .await
+                .context("failed to upload_part")?;
+            let e_tag = upload_output.e_tag.context("no ETag in UploadPartOutput")?;
+            Ok(CompletedPart {
+                e_tag: Some(e_tag),
+                part_number: Some(part_number),
+            })
+        })
+    }
+
+    /// Drives the part currently being uploaded, if any, to completion.
+    fn poll_in_flight_part(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(part) = self.in_flight_part.as_mut() {
+            let result = match part.as_mut().poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.in_flight_part = None;
+            match result {
+                Ok(completed_part) => self.completed_parts.push(completed_part),
+                Err(e) => return Poll::Ready(Err(std::io::Error::other(Error::AnyhowError(e)))),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncMultipartUploadWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_in_flight_part(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other.map_ok(|_| 0),
+        }
+
+        this.buffer.extend_from_slice(buf);
+        if this.buffer.len() >= this.minimum_upload_part_size {
+            this.in_flight_part = Some(this.upload_part());
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_in_flight_part(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_in_flight_part(cx)
+    }
+}
+
+#[async_trait]
+impl AsyncTransportWriter for AsyncMultipartUploadWriter {
+    async fn complete_upload(&mut self) -> Result<()> {
+        if let Some(part) = self.in_flight_part.take() {
+            self.completed_parts.push(part.await?);
+        }
+        // Write last part, if any
+        if !self.buffer.is_empty() {
+            let part = self.upload_part().await?;
+            self.completed_parts.push(part);
+        }
+
+        // Ignore output for now, but we might want the e_tag to check the
+        // digest
+        self.client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket.to_string(),
+                key: self.key.to_string(),
+                upload_id: self.upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload {
+                    parts: Some(mem::take(&mut self.completed_parts)),
+                }),
+                ..Default::default()
+            })
+            .await
+            .context("error completing upload")?;
+        Ok(())
+    }
+
+    async fn cancel_upload(&mut self) -> Result<()> {
+        self.in_flight_part = None;
+        // There's nothing useful in the output so discard it
+        self.client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket.to_string(),
+                key: self.key.to_string(),
+                upload_id: self.upload_id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// BlockingTransport adapts an AsyncTransport into a Transport, so that it may
+/// be used with synchronous code like BatchIntaker or BatchAggregator. Each
+/// reader or writer it returns drives the underlying I/O on its own single
+/// threaded runtime, so like the other Transport implementations, a
+/// BlockingTransport must not be used from a thread that is running a tokio
+/// runtime: from async code, run the synchronous code on a blocking pool, e.g.
+/// with tokio::task::spawn_blocking.
+pub struct BlockingTransport<T> {
+    transport: T,
+}
+
+impl<T: AsyncTransport> BlockingTransport<T> {
+    pub fn new(transport: T) -> BlockingTransport<T> {
+        BlockingTransport { transport }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: AsyncTransport> Transport for BlockingTransport<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        let mut runtime = basic_runtime()?;
+        let reader = runtime.block_on(self.transport.get(key))?;
+        Ok(Box::new(BlockingReader { reader, runtime }))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let mut runtime = basic_runtime()?;
+        let writer = runtime.block_on(self.transport.put(key))?;
+        Ok(Box::new(BlockingWriter { writer, runtime }))
+    }
+}
+
+/// An std::io::Read that reads from a tokio::io::AsyncRead by blocking on it.
+struct BlockingReader {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    runtime: Runtime,
+}
+
+impl Read for BlockingReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.runtime.block_on(self.reader.read(buf))
+    }
+}
+
+/// A TransportWriter that writes into an AsyncTransportWriter by blocking on
+/// it.
+struct BlockingWriter {
+    writer: Box<dyn AsyncTransportWriter>,
+    runtime: Runtime,
+}
+
+impl Write for BlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.runtime.block_on(self.writer.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.runtime.block_on(self.writer.flush())
+    }
+}
+
+impl TransportWriter for BlockingWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.runtime.block_on(self.writer.complete_upload())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.runtime.block_on(self.writer.cancel_upload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::{signature::SignedRequest, Region};
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+
+    const TEST_BUCKET: &str = "fake-bucket";
+    const TEST_KEY: &str = "fake-key";
+
+    #[test]
+    fn roundtrip_async_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+        basic_runtime().unwrap().block_on(async {
+            AsyncTransport::get(&file_transport, "path2")
+                .await
+                .err()
+                .expect("get of missing key should fail");
+
+            for path in &["path", "path3/with/separators"] {
+                let mut writer = AsyncTransport::put(&mut file_transport, path)
+                    .await
+                    .expect("failed to create writer");
+                writer.write_all(&content).await.expect("failed to write");
+                writer.complete_upload().await.expect("failed to complete");
+
+                let mut reader = AsyncTransport::get(&file_transport, path)
+                    .await
+                    .expect("failed to create reader");
+                let mut content_again = Vec::new();
+                reader
+                    .read_to_end(&mut content_again)
+                    .await
+                    .expect("failed to read");
+                assert_eq!(content_again, content);
+            }
+        });
+    }
+
+    #[test]
+    fn roundtrip_blocking_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport =
+            BlockingTransport::new(LocalFileTransport::new(tempdir.path().to_path_buf()));
+        let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+        let mut writer = transport.put("some/path").expect("failed to create writer");
+        writer.write_all(&content).expect("failed to write");
+        writer.complete_upload().expect("failed to complete");
+
+        let mut content_again = Vec::new();
+        transport
+            .get("some/path")
+            .expect("failed to create reader")
+            .read_to_end(&mut content_again)
+            .expect("failed to read");
+        assert_eq!(content_again, content);
+
+        // The content should be visible to the synchronous implementation,
+        // too.
+        let mut content_again = Vec::new();
+        Transport::get(&transport.into_inner(), "some/path")
+            .expect("failed to create reader")
+            .read_to_end(&mut content_again)
+            .expect("failed to read");
+        assert_eq!(content_again, content);
+    }
+
+    fn expect_request(method: &'static str, param: &'static str) -> MockRequestDispatcher {
+        MockRequestDispatcher::with_status(200).with_request_checker(
+            move |request: &SignedRequest| {
+                assert_eq!(request.method, method, "unexpected request {:?}", request);
+                assert!(
+                    request.params.contains_key(param),
+                    "unexpected request {:?}",
+                    request
+                );
+            },
+        )
+    }
+
+    #[test]
+    fn async_multipart_upload() {
+        let requests = vec![
+            // Response to CreateMultipartUpload
+            expect_request("POST", "uploads").with_body(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+            ),
+            // Response to UploadPart for the first 50 bytes
+            expect_request("PUT", "partNumber").with_header("ETag", "fake-etag-1"),
+            // Response to UploadPart for the remainder
+            expect_request("PUT", "partNumber").with_header("ETag", "fake-etag-2"),
+            // Response to CompleteMultipartUpload
+            expect_request("POST", "uploadId").with_body(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<CompleteMultipartUploadResult>
+   <Location>string</Location>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <ETag>fake-etag</ETag>
+</CompleteMultipartUploadResult>"#,
+            ),
+        ];
+        let client = S3Client::new_with(
+            MultipleMockRequestDispatcher::new(requests),
+            MockCredentialsProvider,
+            Region::UsWest2,
+        );
+
+        basic_runtime().unwrap().block_on(async {
+            let mut writer = AsyncMultipartUploadWriter::new(
+                client,
+                TEST_BUCKET.to_owned(),
+                TEST_KEY.to_owned(),
+                50,
+            )
+            .await
+            .expect("failed to create multipart upload writer");
+
+            // This write fills the buffer and starts uploading a part
+            writer.write_all(&[0; 51]).await.unwrap();
+            // This write waits for the part, then buffers its content
+            writer.write_all(&[0; 25]).await.unwrap();
+            assert_eq!(writer.completed_parts.len(), 1);
+            // Completing uploads the last part, then completes the upload
+            writer.complete_upload().await.unwrap();
+            assert!(writer.completed_parts.is_empty());
+        });
+    }
+
+    #[test]
+    fn async_multipart_upload_part_fails() {
+        let requests = vec![
+            // Response to CreateMultipartUpload
+            expect_request("POST", "uploads").with_body(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+            ),
+            // Response to UploadPart. HTTP 200 but no ETag header will cause
+            // failure.
+            expect_request("PUT", "partNumber"),
+            // Response to AbortMultipartUpload, expected because of
+            // cancel_upload call
+            MockRequestDispatcher::with_status(204).with_request_checker(
+                |request: &SignedRequest| {
+                    assert_eq!(request.method, "DELETE", "unexpected request {:?}", request);
+                },
+            ),
+        ];
+        let client = S3Client::new_with(
+            MultipleMockRequestDispatcher::new(requests),
+            MockCredentialsProvider,
+            Region::UsWest2,
+        );
+
+        basic_runtime().unwrap().block_on(async {
+            let mut writer = AsyncMultipartUploadWriter::new(
+                client,
+                TEST_BUCKET.to_owned(),
+                TEST_KEY.to_owned(),
+                50,
+            )
+            .await
+            .expect("failed to create multipart upload writer");
+
+            writer.write_all(&[0; 51]).await.unwrap();
+            // The failure of the part upload surfaces on the next write
+            writer.write_all(&[0; 1]).await.unwrap_err();
+            writer.cancel_upload().await.unwrap();
+        });
+    }
+}
@@ -1,364 +1,2609 @@
 use crate::{
-    idl::{Header, Packet},
-    transport::{Transport, TransportWriter},
-    DigestWriter, SidecarWriter, DATE_FORMAT,
+    copy_with_buffer,
+    hash::{self, Sha256Digest},
+    idl::{can_read_schema, resolvable_container, Header, Packet, SCHEMA_VERSION},
+    transport::{RetryPolicy, Transport, TransportWriter},
+    DigestWriter, Error, SidecarWriter, SpooledBuffer, DATE_FORMAT,
 };
 use anyhow::{anyhow, Context, Result};
-use avro_rs::{Reader, Schema, Writer};
-use chrono::NaiveDateTime;
+use avro_rs::{to_avro_datum, types::Value, Reader, Schema, Writer};
+use chrono::{
+    naive::{MAX_DATETIME, MIN_DATETIME},
+    Duration, NaiveDateTime, Timelike, Utc,
+};
 use ring::{
-    digest::Digest,
-    rand::SystemRandom,
+    rand::{SecureRandom, SystemRandom},
     signature::{EcdsaKeyPair, Signature, UnparsedPublicKey},
 };
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    io::{Cursor, Read, Write},
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{BufWriter, Cursor, Read, Write},
     marker::PhantomData,
+    rc::Rc,
+    str::FromStr,
 };
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 use uuid::Uuid;
 
-/// Manages the paths to the different files in a batch
-pub struct Batch {
-    header_path: String,
-    signature_path: String,
-    packet_file_path: String,
+/// Implements Serialize and Deserialize for a type in terms of its Display and
+/// FromStr implementations, so that values are validated exactly as they are
+/// when parsed from any other source.
+macro_rules! serde_via_str {
+    ($type:ty) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(de::Error::custom)
+            }
+        }
+    };
 }
 
-impl Batch {
-    /// Creates a Batch representing an ingestion batch
-    pub fn new_ingestion(aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime) -> Batch {
-        Batch::new(aggregation_name, batch_id, date, "batch")
+/// The name of an aggregation. Aggregation names are used as a component of
+/// the keys under which batches are stored, so they are restricted to letters,
+/// digits, '-', '_' and '.', may not begin with '.' and may not contain "..".
+/// This guarantees that a name can neither escape the directory of a
+/// LocalFileTransport nor introduce extra path segments into object store
+/// keys.
+///
+/// Letters and digits may be any Unicode alphabetic or numeric characters, so
+/// names are kept in Unicode Normalization Form C (NFC): AggregationName::new
+/// normalizes its input, and the canonical form of a name, which is what
+/// appears in keys, is always the NFC form. Names that look the same but were
+/// entered in different forms therefore map to the same keys. Control
+/// characters and unassigned code points are rejected, the latter because
+/// they have no alphabetic or numeric property.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AggregationName(String);
+
+impl AggregationName {
+    /// Normalizes the provided name to NFC and validates it, returning
+    /// Error::IllegalNameError if it is not a legal aggregation name.
+    pub fn new(name: &str) -> Result<AggregationName, Error> {
+        let name: String = name.nfc().collect();
+        if let Some(c) = name.chars().find(|c| c.is_control()) {
+            return Err(Error::IllegalNameError(format!(
+                "{:?} contains control character {:?}",
+                name, c
+            )));
+        }
+        validate_name_chars(&name, char::is_alphanumeric)?;
+        Ok(AggregationName(name))
     }
 
-    /// Creates a Batch representing a validation batch
-    pub fn new_validation(
-        aggregation_name: &str,
-        batch_id: &Uuid,
-        date: &NaiveDateTime,
-        is_first: bool,
-    ) -> Batch {
-        Batch::new(
-            aggregation_name,
-            batch_id,
-            date,
-            &format!("validity_{}", if is_first { 0 } else { 1 }),
-        )
+    /// Like AggregationName::new, but also rejects names containing uppercase
+    /// letters, for deployments that want to rule out aggregations whose
+    /// names differ only by case, which would collide on case-insensitive
+    /// filesystems.
+    pub fn new_lowercase(name: &str) -> Result<AggregationName, Error> {
+        let aggregation_name = AggregationName::new(name)?;
+        if let Some(c) = aggregation_name.0.chars().find(|c| c.is_uppercase()) {
+            return Err(Error::IllegalNameError(format!(
+                "{:?} contains uppercase character {:?}",
+                name, c
+            )));
+        }
+        Ok(aggregation_name)
     }
 
-    // Creates a batch representing a sum part batch
-    pub fn new_sum(
-        aggregation_name: &str,
-        aggregation_start: &NaiveDateTime,
-        aggregation_end: &NaiveDateTime,
-        is_first: bool,
-    ) -> Batch {
-        let batch_path = format!(
-            "{}/{}-{}",
-            aggregation_name,
-            aggregation_start.format(DATE_FORMAT),
-            aggregation_end.format(DATE_FORMAT)
-        );
-        let filename = format!("sum_{}", if is_first { 0 } else { 1 });
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
-        Batch {
-            header_path: format!("{}.{}", batch_path, filename),
-            signature_path: format!("{}.{}.sig", batch_path, filename),
-            packet_file_path: format!(
-                "{}.invalid_uuid_{}.avro",
-                batch_path,
-                if is_first { 0 } else { 1 }
-            ),
-        }
+impl FromStr for AggregationName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AggregationName::new(s)
     }
+}
 
-    fn new(aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime, filename: &str) -> Batch {
-        let batch_path = format!(
-            "{}/{}/{}",
-            aggregation_name,
-            date.format(DATE_FORMAT),
-            batch_id.to_hyphenated()
-        );
-        Batch {
-            header_path: format!("{}.{}", batch_path, filename),
-            signature_path: format!("{}.{}.sig", batch_path, filename),
-            packet_file_path: format!("{}.{}.avro", batch_path, filename),
-        }
+impl AsRef<str> for AggregationName {
+    fn as_ref(&self) -> &str {
+        &self.0
     }
+}
 
-    fn header_key(&self) -> &str {
-        self.header_path.as_ref()
+impl fmt::Display for AggregationName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
+}
 
-    fn signature_key(&self) -> &str {
-        self.signature_path.as_ref()
+serde_via_str!(AggregationName);
+
+/// The name of a facilitator instance. When several instances, e.g. for
+/// different localities, share buckets, the instance name is used as the
+/// leading segment of the keys under which batches are stored. Instance names
+/// are subject to the same restrictions as AggregationName, except that
+/// letters and digits must be ASCII.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceName(String);
+
+impl InstanceName {
+    /// Validates the provided name, returning Error::IllegalNameError if it is
+    /// not a legal instance name.
+    pub fn new(name: &str) -> Result<InstanceName, Error> {
+        validate_name_component(name)?;
+        Ok(InstanceName(name.to_owned()))
     }
 
-    fn packet_file_key(&self) -> &str {
-        self.packet_file_path.as_ref()
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 }
 
-/// Allows reading files, including signature validation, from an ingestion or
-/// validation batch containing a header, a packet file and a signature.
-pub struct BatchReader<'a, H, P> {
-    batch: Batch,
-    transport: &'a mut dyn Transport,
-    packet_schema: Schema,
-    // These next two fields are not real and are used because not using H and P
-    // in the struct definition is an error.
-    phantom_header: PhantomData<*const H>,
-    phantom_packet: PhantomData<*const P>,
+impl FromStr for InstanceName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InstanceName::new(s)
+    }
 }
 
-impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
-    pub fn new(batch: Batch, transport: &'a mut dyn Transport) -> Self {
-        BatchReader {
-            batch,
-            transport,
-            packet_schema: P::schema(),
-            phantom_header: PhantomData,
-            phantom_packet: PhantomData,
-        }
+impl AsRef<str> for InstanceName {
+    fn as_ref(&self) -> &str {
+        &self.0
     }
+}
 
-    /// Return the parsed header from this batch, but only if its signature is
-    /// valid.
-    pub fn header(&self, key: &UnparsedPublicKey<Vec<u8>>) -> Result<H> {
-        let mut signature = Vec::new();
-        self.transport
-            .get(self.batch.signature_key())?
-            .read_to_end(&mut signature)
-            .context("failed to read signature")?;
+impl fmt::Display for InstanceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-        let mut header_buf = Vec::new();
-        self.transport
-            .get(self.batch.header_key())?
-            .read_to_end(&mut header_buf)
-            .context("failed to read header from transport")?;
+serde_via_str!(InstanceName);
 
-        key.verify(&header_buf, &signature)
-            .context("invalid signature on header")?;
+/// The date of a batch. Dates appear in batch keys with a precision of minutes,
+/// so a BatchDate discards any seconds and fractions of a second. BatchDates
+/// are interpreted as UTC and their canonical representation, used both in
+/// keys and when parsing, is DATE_FORMAT, i.e., YYYY/mm/dd/HH/MM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BatchDate(NaiveDateTime);
 
-        Ok(H::read(Cursor::new(header_buf))?)
+impl BatchDate {
+    /// Creates a BatchDate from the provided UTC date, truncated to the minute.
+    pub fn new(date: &NaiveDateTime) -> BatchDate {
+        BatchDate(date.date().and_hms(date.hour(), date.minute(), 0))
     }
 
-    /// Return an avro_rs::Reader that yields the packets in the packet file,
-    /// but only if the whole file's digest matches the packet_file_digest field
-    /// in the provided header. The header is assumed to be trusted.
-    pub fn packet_file_reader(&self, header: &H) -> Result<Reader<Cursor<Vec<u8>>>> {
-        // Fetch packet file to validate its digest. It could be quite large so
-        // so our intuition would be to stream the packets from the transport
-        // and into a hasher and into the validation step, so that we wouldn't
-        // need the whole file in memory at once. We can't do this because:
-        //   (1) we don't want to do anything with any of the data in the packet
-        //       file until we've verified integrity+authenticity
-        //   (2) we need to copy the entire file into storage we control before
-        //       validating its digest to avoid TOCTOU vulnerabilities.
-        // We are assured by our friends writing ingestion servers that batches
-        // will be no more than 300-400 MB, which fits quite reasonably into the
-        // memory of anything we're going to run the facilitator on, so we load
-        // the entire packet file into memory ...
-        let mut packet_file_reader = self.transport.get(self.batch.packet_file_key())?;
-        let entire_packet_file = Vec::new();
-        let digest_writer = DigestWriter::new();
-        let mut sidecar_writer = SidecarWriter::new(entire_packet_file, digest_writer);
-
-        std::io::copy(&mut packet_file_reader, &mut sidecar_writer)
-            .context("failed to load packet file")?;
-
-        // ... then verify the digest over it ...
-        if header.packet_file_digest().as_slice() != sidecar_writer.sidecar.finish().as_ref() {
-            return Err(anyhow!("packet file digest does not match header"));
-        }
+    /// Parses a BatchDate from the provided string, which must be in exactly
+    /// the canonical form. Kept for callers that passed dates around as
+    /// strings; see BatchDate::from_str.
+    #[deprecated(note = "parse dates with str::parse::<BatchDate>() instead")]
+    pub fn from_date_string(date: &str) -> Result<BatchDate, Error> {
+        BatchDate::from_str(date)
+    }
 
-        // ... then return a packet reader.
-        Reader::with_schema(&self.packet_schema, Cursor::new(sidecar_writer.writer))
-            .context("failed to create Avro reader for packets")
+    pub fn as_naive_date_time(&self) -> &NaiveDateTime {
+        &self.0
     }
 }
 
-/// Allows writing files, including signature file construction, from an
-/// ingestion or validation batch containing a header, a packet file and a
-/// signature.
-pub struct BatchWriter<'a, H, P> {
-    batch: Batch,
-    transport: &'a mut dyn Transport,
-    packet_schema: Schema,
-    phantom_header: PhantomData<*const H>,
-    phantom_packet: PhantomData<*const P>,
+impl From<NaiveDateTime> for BatchDate {
+    fn from(date: NaiveDateTime) -> Self {
+        BatchDate::new(&date)
+    }
 }
 
-impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
-    pub fn new(batch: Batch, transport: &'a mut dyn Transport) -> Self {
-        BatchWriter {
-            batch,
-            transport,
-            packet_schema: P::schema(),
-            phantom_header: PhantomData,
-            phantom_packet: PhantomData,
+impl FromStr for BatchDate {
+    type Err = Error;
+
+    /// Parses a date in DATE_FORMAT. Unlike chrono's parser, this rejects
+    /// anything that is not in exactly the canonical form (e.g., "2020/1/2/3/4"
+    /// or trailing garbage), so that two share processors can never derive
+    /// different keys from what they believe to be the same date.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let date = NaiveDateTime::parse_from_str(s, DATE_FORMAT)
+            .map_err(|e| Error::MalformedDateError(format!("{:?}: {}", s, e)))?;
+        let date = BatchDate(date);
+        if date.to_string() != s {
+            return Err(Error::MalformedDateError(format!(
+                "{:?} is not in canonical form {}",
+                s, date
+            )));
         }
+        Ok(date)
     }
+}
 
-    /// Encode the provided header into Avro, sign that representation with the
-    /// provided key and write the header into the batch. Returns the signature
-    /// on success.
-    pub fn put_header(&mut self, header: &H, key: &EcdsaKeyPair) -> Result<Signature> {
-        let mut sidecar_writer =
-            SidecarWriter::new(self.transport.put(self.batch.header_key())?, Vec::new());
-        header.write(&mut sidecar_writer)?;
-        sidecar_writer
-            .writer
-            .complete_upload()
-            .context("failed to complete batch header upload")?;
-
-        let header_signature = key
-            .sign(&SystemRandom::new(), &sidecar_writer.sidecar)
-            .context("failed to sign header file")?;
-        Ok(header_signature)
+impl fmt::Display for BatchDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format(DATE_FORMAT))
     }
+}
 
-    /// Creates an avro_rs::Writer and provides it to the caller-provided
-    /// function, which may then write arbitrarily many packets into it. The
-    /// Avro encoding of the packet will be digested while they are written.
-    /// The operation should return Ok(()) when it has finished successfully or
-    /// some Err() otherwise. packet_file_writer returns the digest of all the
-    /// content written by the operation.
-    pub fn packet_file_writer<F>(&mut self, operation: F) -> Result<Digest>
-    where
-        F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
-    {
-        let mut writer = Writer::new(
-            &self.packet_schema,
-            SidecarWriter::new(
-                self.transport.put(self.batch.packet_file_key())?,
-                DigestWriter::new(),
-            ),
-        );
+serde_via_str!(BatchDate);
 
-        let result = operation(&mut writer);
-        let mut sidecar_writer = writer
-            .into_inner()
-            .with_context(|| format!("failed to flush Avro writer ({:?})", result))?;
+/// A source of the current time, so that checks against the present can be
+/// tested with a fixed time.
+pub trait Clock: Sync {
+    /// Returns the current time in UTC.
+    fn now(&self) -> NaiveDateTime;
 
-        if let Err(e) = result {
-            sidecar_writer
-                .writer
-                .cancel_upload()
-                .with_context(|| format!("Encountered while handling: {}", e))?;
-            return Err(e);
+    /// Returns true if less than ttl has passed since the provided time. A
+    /// time after the present, e.g. because the clock was set back, counts as
+    /// no time having passed.
+    fn is_within(&self, since: &NaiveDateTime, ttl: std::time::Duration) -> bool {
+        match Duration::from_std(ttl) {
+            Ok(ttl) => self.now().signed_duration_since(*since) < ttl,
+            // Too long to represent, so it can't have passed
+            Err(_) => true,
         }
-
-        sidecar_writer
-            .writer
-            .complete_upload()
-            .context("failed to complete packet file upload")?;
-        Ok(sidecar_writer.sidecar.finish())
     }
+}
 
-    /// Constructs a signature structure from the provided buffers and writes it
-    /// to the batch's signature file
-    pub fn put_signature(&mut self, signature: &Signature) -> Result<()> {
-        let mut writer = self.transport.put(self.batch.signature_key())?;
-        writer
-            .write_all(signature.as_ref())
-            .context("failed to write signature")?;
-        writer
-            .complete_upload()
-            .context("failed to complete signature upload")
+/// A Clock that reads the system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        idl::{IngestionDataSharePacket, IngestionHeader},
-        test_utils::{
-            default_facilitator_signing_public_key, default_ingestor_private_key,
-            default_ingestor_public_key,
-        },
-        transport::LocalFileTransport,
-        Error,
-    };
+/// The range of batch dates, relative to the present, in which batches are
+/// accepted: a batch may be dated at most max_future_skew after the present,
+/// to allow for clock skew between us and the ingestor, and at most max_age
+/// before it. Both boundaries are inclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchDateWindow {
+    max_future_skew: Duration,
+    max_age: Duration,
+}
 
-    fn roundtrip_batch<'a>(
-        aggregation_name: String,
-        batch_id: Uuid,
-        base_path: String,
-        filenames: &[String],
-        batch_writer: &mut BatchWriter<'a, IngestionHeader, IngestionDataSharePacket>,
-        batch_reader: &BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
-        transport: &mut LocalFileTransport,
-        write_key: &EcdsaKeyPair,
-        read_key: &UnparsedPublicKey<Vec<u8>>,
-        keys_match: bool,
-    ) {
-        // These tests cheat by using IngestionHeader and
-        // IngestionDataSharePacket regardless of what kind of batch it is, but
-        // since BatchReader and BatchWriter are generic in H and P, this
-        // exercises what we care about in this module while saving us a bit of
-        // typing.
-        let packets = &[
-            IngestionDataSharePacket {
-                uuid: Uuid::new_v4(),
-                encrypted_payload: vec![0u8, 1u8, 2u8, 3u8],
-                encryption_key_id: "fake-key-1".to_owned(),
-                r_pit: 1,
-                version_configuration: Some("config-1".to_owned()),
-                device_nonce: None,
-            },
-            IngestionDataSharePacket {
-                uuid: Uuid::new_v4(),
-                encrypted_payload: vec![4u8, 5u8, 6u8, 7u8],
-                encryption_key_id: "fake-key-2".to_owned(),
-                r_pit: 2,
-                version_configuration: None,
-                device_nonce: Some(vec![8u8, 9u8, 10u8, 11u8]),
-            },
-            IngestionDataSharePacket {
-                uuid: Uuid::new_v4(),
-                encrypted_payload: vec![8u8, 9u8, 10u8, 11u8],
-                encryption_key_id: "fake-key-3".to_owned(),
-                r_pit: 3,
-                version_configuration: None,
-                device_nonce: None,
-            },
-        ];
+impl BatchDateWindow {
+    pub fn new(max_future_skew: Duration, max_age: Duration) -> BatchDateWindow {
+        BatchDateWindow {
+            max_future_skew,
+            max_age,
+        }
+    }
 
-        let packet_file_digest = batch_writer
-            .packet_file_writer(|mut packet_writer| {
-                packets[0].write(&mut packet_writer)?;
-                packets[1].write(&mut packet_writer)?;
-                packets[2].write(&mut packet_writer)?;
-                Ok(())
-            })
-            .expect("failed to write packets");
+    /// Returns Error::BatchDateOutsideWindow if the provided date is outside
+    /// the window around the provided present time.
+    pub fn check(&self, date: &BatchDate, now: &NaiveDateTime) -> Result<(), Error> {
+        let earliest = now.checked_sub_signed(self.max_age).unwrap_or(MIN_DATETIME);
+        let latest = now
+            .checked_add_signed(self.max_future_skew)
+            .unwrap_or(MAX_DATETIME);
+        if *date.as_naive_date_time() < earliest || *date.as_naive_date_time() > latest {
+            return Err(Error::BatchDateOutsideWindow(*date, earliest, latest));
+        }
+        Ok(())
+    }
+}
 
-        let header = IngestionHeader {
-            batch_uuid: batch_id,
-            name: aggregation_name.to_owned(),
-            bins: 2,
-            epsilon: 1.601,
-            prime: 17,
-            number_of_servers: 2,
-            hamming_weight: None,
-            batch_start_time: 789456123,
-            batch_end_time: 789456321,
-            packet_file_digest: packet_file_digest.as_ref().to_vec(),
-        };
+/// Identifies one of the two share processors participating in an
+/// aggregation. Which share processor is "first" determines how libprio
+/// evaluates polynomials, so both the PHA and the facilitator must agree on
+/// the identity of each server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServerIdentity {
+    /// The public health authority, i.e., the "first" server
+    Pha,
+    /// The facilitator, i.e., the "second" server
+    Facilitator,
+}
 
-        let header_signature = batch_writer
-            .put_header(&header, write_key)
-            .expect("failed to write header");
+impl ServerIdentity {
+    /// Returns the identity of the server for which the provided is_first
+    /// value would have been passed to libprio.
+    pub fn from_is_first(is_first: bool) -> ServerIdentity {
+        if is_first {
+            ServerIdentity::Pha
+        } else {
+            ServerIdentity::Facilitator
+        }
+    }
 
-        let res = batch_writer.put_signature(&header_signature);
-        assert!(res.is_ok(), "failed to put signature: {:?}", res.err());
+    /// Returns true if this is the "first" server, in libprio's terms.
+    pub fn is_first(self) -> bool {
+        self == ServerIdentity::Pha
+    }
 
-        // Verify file layout is as expected
-        for extension in filenames {
-            transport
-                .get(&format!("{}.{}", base_path, extension))
-                .expect(&format!("could not get batch file {}", extension));
+    /// Returns the identity of the other share processor.
+    pub fn peer(self) -> ServerIdentity {
+        match self {
+            ServerIdentity::Pha => ServerIdentity::Facilitator,
+            ServerIdentity::Facilitator => ServerIdentity::Pha,
+        }
+    }
+
+    /// Returns the label used for this server in batch keys.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServerIdentity::Pha => "pha",
+            ServerIdentity::Facilitator => "facilitator",
+        }
+    }
+
+    /// Returns the numeric index historically used for this server in batch
+    /// keys.
+    fn index(self) -> u8 {
+        if self.is_first() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl FromStr for ServerIdentity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pha" => Ok(ServerIdentity::Pha),
+            "facilitator" => Ok(ServerIdentity::Facilitator),
+            _ => Err(Error::IllegalNameError(format!(
+                "{:?} is not a server identity",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ServerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+serde_via_str!(ServerIdentity);
+
+/// Determines how the files in a validation batch are named.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationNaming {
+    /// Validation batches are labeled with the server identity, e.g.
+    /// "<uuid>.validity_pha".
+    ServerIdentity,
+    /// Validation batches are labeled with the server's index, e.g.
+    /// "<uuid>.validity_0" for the PHA. This is what earlier versions of the
+    /// facilitator did and is kept for compatibility with peers that still
+    /// expect it.
+    Legacy,
+}
+
+/// Determines how the components of the keys of ingestion and validation
+/// batches are arranged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathLayout {
+    /// Batches are stored under "<aggregation name>/<date>/<batch UUID>". This
+    /// is what earlier versions of the facilitator did.
+    Flat,
+    /// Batches are stored under "<date>/<aggregation name>/<batch UUID>", so
+    /// that the batches of all aggregations for a given date share a prefix.
+    DatePartitioned,
+}
+
+impl PathLayout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PathLayout::Flat => "flat",
+            PathLayout::DatePartitioned => "date-partitioned",
+        }
+    }
+}
+
+impl FromStr for PathLayout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(PathLayout::Flat),
+            "date-partitioned" => Ok(PathLayout::DatePartitioned),
+            _ => Err(Error::IllegalNameError(format!(
+                "{:?} is not a path layout",
+                s
+            ))),
+        }
+    }
+}
+
+/// Determines how the keys of the parts of a packet file that is split across
+/// several objects (see Batch::packet_file_shard_key) are derived from the key
+/// of the packet file. Shard is the default because it is how sharded batches
+/// were already named; ingestors that number their parts opt in to Numbered
+/// through their BatchNaming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketPartNaming {
+    /// Parts are stored under "<packet file key>.shard_<index>", e.g.
+    /// "<uuid>.batch.avro.shard_0".
+    Shard,
+    /// Parts are stored under "<packet file key>-<index>", with the index
+    /// padded to three digits, e.g. "<uuid>.batch.avro-000".
+    Numbered,
+}
+
+impl PacketPartNaming {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PacketPartNaming::Shard => "shard",
+            PacketPartNaming::Numbered => "numbered",
+        }
+    }
+
+    /// Returns the key of the part with the provided index of the packet file
+    /// stored under the provided key.
+    fn part_key(self, packet_file_key: &str, index: usize) -> String {
+        match self {
+            PacketPartNaming::Shard => format!("{}.shard_{}", packet_file_key, index),
+            PacketPartNaming::Numbered => format!("{}-{:03}", packet_file_key, index),
+        }
+    }
+}
+
+impl FromStr for PacketPartNaming {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shard" => Ok(PacketPartNaming::Shard),
+            "numbered" => Ok(PacketPartNaming::Numbered),
+            _ => Err(Error::IllegalNameError(format!(
+                "{:?} is not a packet part naming",
+                s
+            ))),
+        }
+    }
+}
+
+/// Checks that the provided string is safe to use as a single segment of a
+/// transport key, allowing only ASCII letters and digits. See AggregationName
+/// for the rules.
+fn validate_name_component(name: &str) -> Result<(), Error> {
+    validate_name_chars(name, |c| c.is_ascii_alphanumeric())
+}
+
+/// Checks that the provided string is safe to use as a single segment of a
+/// transport key, allowing '-', '_', '.' and any character for which
+/// is_letter_or_digit returns true.
+fn validate_name_chars(name: &str, is_letter_or_digit: fn(char) -> bool) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::IllegalNameError("name is empty".to_owned()));
+    }
+    if name.starts_with('.') {
+        return Err(Error::IllegalNameError(format!(
+            "{:?} begins with '.'",
+            name
+        )));
+    }
+    if name.contains("..") {
+        return Err(Error::IllegalNameError(format!(
+            "{:?} contains \"..\"",
+            name
+        )));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(is_letter_or_digit(*c) || *c == '-' || *c == '_' || *c == '.'))
+    {
+        return Err(Error::IllegalNameError(format!(
+            "{:?} contains illegal character {:?}",
+            name, c
+        )));
+    }
+    Ok(())
+}
+
+/// The kinds of batches whose keys are determined by a BatchNamingScheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchKind {
+    /// A batch of data shares sent by the ingestion server
+    Ingestion,
+    /// A batch of validation shares produced by the specified server
+    Validation(ServerIdentity),
+}
+
+impl BatchKind {
+    /// All the kinds of batches
+    pub const ALL: [BatchKind; 3] = [
+        BatchKind::Ingestion,
+        BatchKind::Validation(ServerIdentity::Pha),
+        BatchKind::Validation(ServerIdentity::Facilitator),
+    ];
+}
+
+/// A BatchNamingScheme determines the keys under which the header, signature
+/// and packet file of a batch are stored. Operators can implement this to
+/// match whatever layout a partner expects.
+pub trait BatchNamingScheme: Sync {
+    /// Returns the Batch for the specified batch.
+    fn batch(
+        &self,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+        date: &BatchDate,
+        kind: BatchKind,
+    ) -> Batch;
+}
+
+/// The suffixes that are appended to "<...>/<batch UUID>" to obtain the keys of
+/// the files in an ingestion batch, and how the keys of the parts of a packet
+/// file split across several objects are derived. Some ingestion servers do
+/// not use our defaults of ".batch", ".batch.avro", ".batch.sig" and
+/// PacketPartNaming::Shard.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchNaming {
+    header_suffix: Cow<'static, str>,
+    packet_suffix: Cow<'static, str>,
+    signature_suffix: Cow<'static, str>,
+    packet_part_naming: PacketPartNaming,
+}
+
+impl BatchNaming {
+    /// Validates the provided suffixes, returning Error::IllegalNameError if
+    /// any of them is neither empty nor begins with '.', contains characters
+    /// not allowed in an InstanceName, or if any two of them are equal.
+    pub fn new(
+        header_suffix: &str,
+        packet_suffix: &str,
+        signature_suffix: &str,
+    ) -> Result<BatchNaming, Error> {
+        for suffix in &[header_suffix, packet_suffix, signature_suffix] {
+            if !suffix.is_empty() {
+                if !suffix.starts_with('.') {
+                    return Err(Error::IllegalNameError(format!(
+                        "suffix {:?} does not begin with '.'",
+                        suffix
+                    )));
+                }
+                validate_name_component(&suffix[1..])?;
+            }
+        }
+        if header_suffix == packet_suffix
+            || header_suffix == signature_suffix
+            || packet_suffix == signature_suffix
+        {
+            return Err(Error::IllegalNameError(
+                "header, packet and signature suffixes must differ".to_owned(),
+            ));
+        }
+        Ok(BatchNaming {
+            header_suffix: Cow::Owned(header_suffix.to_owned()),
+            packet_suffix: Cow::Owned(packet_suffix.to_owned()),
+            signature_suffix: Cow::Owned(signature_suffix.to_owned()),
+            packet_part_naming: PacketPartNaming::Shard,
+        })
+    }
+
+    /// Returns this BatchNaming, changed to name the parts of packet files
+    /// according to the provided PacketPartNaming.
+    pub fn with_packet_part_naming(mut self, packet_part_naming: PacketPartNaming) -> BatchNaming {
+        self.packet_part_naming = packet_part_naming;
+        self
+    }
+
+    const fn default_ingestion() -> BatchNaming {
+        BatchNaming {
+            header_suffix: Cow::Borrowed(".batch"),
+            packet_suffix: Cow::Borrowed(".batch.avro"),
+            signature_suffix: Cow::Borrowed(".batch.sig"),
+            packet_part_naming: PacketPartNaming::Shard,
+        }
+    }
+
+    pub fn header_suffix(&self) -> &str {
+        &self.header_suffix
+    }
+
+    pub fn packet_suffix(&self) -> &str {
+        &self.packet_suffix
+    }
+
+    pub fn signature_suffix(&self) -> &str {
+        &self.signature_suffix
+    }
+
+    pub fn packet_part_naming(&self) -> PacketPartNaming {
+        self.packet_part_naming
+    }
+}
+
+impl Default for BatchNaming {
+    fn default() -> Self {
+        BatchNaming::default_ingestion()
+    }
+}
+
+/// The default BatchNamingScheme, which stores batches under
+/// "<aggregation name>/<date>/<batch UUID>" (or with the date first, depending
+/// on the PathLayout). Ingestion batch files get the suffixes from the
+/// BatchNaming. Validation batch headers get the suffix ".<kind>", e.g.
+/// ".validity_pha", their signatures ".<kind>.sig" and their packet files
+/// ".<kind>.avro".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefaultBatchNamingScheme {
+    pub validation_naming: ValidationNaming,
+    pub path_layout: PathLayout,
+    pub ingestion_naming: BatchNaming,
+}
+
+impl DefaultBatchNamingScheme {
+    /// Creates a DefaultBatchNamingScheme using PathLayout::Flat and the
+    /// default BatchNaming.
+    pub const fn new(validation_naming: ValidationNaming) -> DefaultBatchNamingScheme {
+        DefaultBatchNamingScheme {
+            validation_naming,
+            path_layout: PathLayout::Flat,
+            ingestion_naming: BatchNaming::default_ingestion(),
+        }
+    }
+
+    /// Returns this naming scheme, changed to use the provided PathLayout.
+    pub fn with_path_layout(mut self, path_layout: PathLayout) -> DefaultBatchNamingScheme {
+        self.path_layout = path_layout;
+        self
+    }
+
+    /// Returns this naming scheme, changed to name ingestion batch files
+    /// according to the provided BatchNaming.
+    pub fn with_ingestion_naming(
+        mut self,
+        ingestion_naming: BatchNaming,
+    ) -> DefaultBatchNamingScheme {
+        self.ingestion_naming = ingestion_naming;
+        self
+    }
+
+    /// Recovers the descriptor of the batch whose header is stored at the
+    /// provided key. The key must be exactly what this naming scheme, with the
+    /// provided instance name, produces for some ingestion or validation
+    /// batch, or some attempt at one (see Batch::with_attempt); otherwise
+    /// Error::MalformedKeyError is returned, or Error::IllegalNameError or
+    /// Error::MalformedDateError if the aggregation name or date in the key
+    /// are invalid.
+    pub fn parse_header_key(
+        &self,
+        instance_name: Option<&InstanceName>,
+        key: &str,
+    ) -> Result<BatchDescriptor, Error> {
+        let malformed = |reason: &str| Error::MalformedKeyError(format!("{:?}: {}", key, reason));
+        // We only ever construct keys from NFC aggregation names, so a key in
+        // any other form can't map back to itself.
+        if !is_nfc(key) {
+            return Err(malformed("not in Unicode Normalization Form C"));
+        }
+
+        // Header keys of later attempts are those of the first attempt with a
+        // ".retry-<attempt>" suffix. Only the canonical form of the attempt
+        // number is accepted, so that the key maps back to itself.
+        let (first_attempt_key, attempt) = match key
+            .rsplit_once(".retry-")
+            .filter(|(_, attempt)| !attempt.contains('/'))
+        {
+            Some((first_attempt_key, attempt_str)) => match attempt_str.parse::<u32>() {
+                Ok(attempt) if attempt > 0 && attempt.to_string() == attempt_str => {
+                    (first_attempt_key, attempt)
+                }
+                _ => return Err(malformed("invalid attempt number")),
+            },
+            None => (key, 0),
+        };
+
+        let relative_key = match instance_name {
+            Some(instance_name) => first_attempt_key
+                .strip_prefix(instance_name.as_str())
+                .and_then(|k| k.strip_prefix('/'))
+                .ok_or_else(|| malformed(&format!("not a key in instance {}", instance_name)))?,
+            None => first_attempt_key,
+        };
+
+        // The aggregation name, the five components of the date and the file
+        // name.
+        let components: Vec<&str> = relative_key.split('/').collect();
+        if components.len() != 7 {
+            return Err(malformed("wrong number of components"));
+        }
+        let (aggregation_name, date) = match self.path_layout {
+            PathLayout::Flat => (components[0], &components[1..6]),
+            PathLayout::DatePartitioned => (components[5], &components[0..5]),
+        };
+        let aggregation_name = AggregationName::new(aggregation_name)?;
+        let date = BatchDate::from_str(&date.join("/"))?;
+        let batch_id = components[6]
+            .split('.')
+            .next()
+            .map(Uuid::parse_str)
+            .transpose()
+            .map_err(|e| malformed(&format!("invalid batch UUID: {}", e)))?
+            .ok_or_else(|| malformed("missing batch UUID"))?;
+
+        // Rather than parsing the rest of the file name, find the kind of batch
+        // whose header this is. This guarantees that the descriptor we return
+        // maps back to the same key.
+        BatchKind::ALL
+            .iter()
+            .map(|kind| BatchDescriptor::new(aggregation_name.clone(), date, batch_id, *kind))
+            .find(|descriptor| {
+                descriptor.batch(self, instance_name).header_key() == first_attempt_key
+            })
+            .map(|descriptor| descriptor.with_attempt(attempt))
+            .ok_or_else(|| malformed("not the header of an ingestion or validation batch"))
+    }
+
+    /// Returns the identities of the ingestion batches in the provided
+    /// aggregation whose dates fall within [start, end), sorted by date and
+    /// then batch ID. Only the partitions for the days in the range are listed,
+    /// and only complete batches, i.e. those whose header, signature and packet
+    /// file (or first packet file shard) are all present, are returned. Keys
+    /// that do not belong to an ingestion batch, including keys that are not
+    /// in NFC (see AggregationName), are ignored.
+    pub fn enumerate_range(
+        &self,
+        transport: &dyn Transport,
+        instance_name: Option<&InstanceName>,
+        aggregation_name: &AggregationName,
+        start: &BatchDate,
+        end: &BatchDate,
+    ) -> Result<Vec<BatchIdentity>, Error> {
+        let mut identities = Vec::new();
+        let mut day = start.as_naive_date_time().date();
+        while day <= end.as_naive_date_time().date() && start < end {
+            let day_path = day.format("%Y/%m/%d");
+            let partition = match self.path_layout {
+                PathLayout::Flat => format!("{}/{}/", aggregation_name, day_path),
+                PathLayout::DatePartitioned => format!("{}/", day_path),
+            };
+            let partition = match instance_name {
+                Some(instance_name) => format!("{}/{}", instance_name, partition),
+                None => partition,
+            };
+            let keys: HashSet<String> = transport
+                .list(&partition)
+                .map_err(|e| {
+                    Error::AnyhowError(e.context(format!("failed to list {}", partition)))
+                })?
+                .into_iter()
+                .collect();
+
+            for key in &keys {
+                let descriptor = match self.parse_header_key(instance_name, key) {
+                    Ok(descriptor) => descriptor,
+                    Err(_) => continue,
+                };
+                if descriptor.kind != BatchKind::Ingestion
+                    || descriptor.aggregation_name != *aggregation_name
+                    || descriptor.date < *start
+                    || descriptor.date >= *end
+                {
+                    continue;
+                }
+                if descriptor.batch(self, instance_name).is_complete(&keys) {
+                    identities.push(descriptor.identity());
+                }
+            }
+
+            day = match day.succ_opt() {
+                Some(day) => day,
+                None => break,
+            };
+        }
+
+        identities.sort_by_key(|identity| (identity.date, identity.batch_id));
+        Ok(identities)
+    }
+
+    /// Returns the attempts at producing the described batch (see
+    /// Batch::with_attempt) that are complete, in increasing order. The
+    /// attempt in the provided descriptor is ignored. Only the directory
+    /// containing the batch's header is listed.
+    pub fn enumerate_attempts(
+        &self,
+        transport: &dyn Transport,
+        instance_name: Option<&InstanceName>,
+        descriptor: &BatchDescriptor,
+    ) -> Result<Vec<u32>, Error> {
+        let first_attempt = descriptor.clone().with_attempt(0);
+        let header_key = first_attempt
+            .batch(self, instance_name)
+            .header_key()
+            .to_owned();
+        let directory = &header_key[..header_key.rfind('/').map_or(0, |i| i + 1)];
+        let keys: HashSet<String> = transport
+            .list(directory)
+            .map_err(|e| Error::AnyhowError(e.context(format!("failed to list {}", directory))))?
+            .into_iter()
+            .collect();
+
+        let mut attempts = Vec::new();
+        for key in &keys {
+            let found = match self.parse_header_key(instance_name, key) {
+                Ok(found) => found,
+                Err(_) => continue,
+            };
+            if found.clone().with_attempt(0) == first_attempt
+                && found.batch(self, instance_name).is_complete(&keys)
+            {
+                attempts.push(found.attempt);
+            }
+        }
+        attempts.sort_unstable();
+        Ok(attempts)
+    }
+}
+
+impl Default for DefaultBatchNamingScheme {
+    fn default() -> Self {
+        DefaultBatchNamingScheme::new(ValidationNaming::ServerIdentity)
+    }
+}
+
+/// The naming scheme used when none is provided.
+pub static DEFAULT_NAMING_SCHEME: DefaultBatchNamingScheme =
+    DefaultBatchNamingScheme::new(ValidationNaming::ServerIdentity);
+
+impl BatchNamingScheme for DefaultBatchNamingScheme {
+    fn batch(
+        &self,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+        date: &BatchDate,
+        kind: BatchKind,
+    ) -> Batch {
+        let label = match (kind, self.validation_naming) {
+            (BatchKind::Ingestion, _) => None,
+            (BatchKind::Validation(server), ValidationNaming::ServerIdentity) => {
+                Some(format!("validity_{}", server))
+            }
+            (BatchKind::Validation(server), ValidationNaming::Legacy) => {
+                Some(format!("validity_{}", server.index()))
+            }
+        };
+        let batch_path = match self.path_layout {
+            PathLayout::Flat => {
+                format!("{}/{}/{}", aggregation_name, date, batch_id.to_hyphenated())
+            }
+            PathLayout::DatePartitioned => {
+                format!("{}/{}/{}", date, aggregation_name, batch_id.to_hyphenated())
+            }
+        };
+        match label {
+            None => Batch::from_keys(
+                format!("{}{}", batch_path, self.ingestion_naming.header_suffix()),
+                format!("{}{}", batch_path, self.ingestion_naming.signature_suffix()),
+                format!("{}{}", batch_path, self.ingestion_naming.packet_suffix()),
+            )
+            .with_packet_part_naming(self.ingestion_naming.packet_part_naming()),
+            Some(label) => Batch::from_keys(
+                format!("{}.{}", batch_path, label),
+                format!("{}.{}.sig", batch_path, label),
+                format!("{}.{}.avro", batch_path, label),
+            ),
+        }
+    }
+}
+
+/// Identifies the batch with some UUID from some date in some aggregation.
+/// The ingestion batch and both validation batches for it share a
+/// BatchIdentity.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BatchIdentity {
+    pub aggregation_name: AggregationName,
+    pub date: BatchDate,
+    pub batch_id: Uuid,
+}
+
+impl BatchIdentity {
+    pub fn new(
+        aggregation_name: AggregationName,
+        date: BatchDate,
+        batch_id: Uuid,
+    ) -> BatchIdentity {
+        BatchIdentity {
+            aggregation_name,
+            date,
+            batch_id,
+        }
+    }
+
+    /// Returns the descriptor of the batch of the provided kind with this
+    /// identity.
+    pub fn descriptor(&self, kind: BatchKind) -> BatchDescriptor {
+        BatchDescriptor::new(
+            self.aggregation_name.clone(),
+            self.date,
+            self.batch_id,
+            kind,
+        )
+    }
+
+    /// Returns the ingestion Batch with this identity, named according to the
+    /// provided naming scheme.
+    pub fn ingestion_batch(
+        &self,
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+    ) -> Batch {
+        self.descriptor(BatchKind::Ingestion)
+            .batch(naming_scheme, instance_name)
+    }
+
+    /// Returns the validation Batch produced by the specified server for the
+    /// ingestion batch with this identity, named according to the provided
+    /// naming scheme.
+    pub fn validation_batch(
+        &self,
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+        server: ServerIdentity,
+    ) -> Batch {
+        self.descriptor(BatchKind::Validation(server))
+            .batch(naming_scheme, instance_name)
+    }
+
+    /// Returns the validation Batch that the share processor with identity
+    /// own writes for the ingestion batch with this identity.
+    pub fn own_validation_batch(
+        &self,
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+        own: ServerIdentity,
+    ) -> OwnValidationBatch {
+        OwnValidationBatch {
+            batch: self.validation_batch(naming_scheme, instance_name, own),
+            owner: own,
+        }
+    }
+
+    /// Returns the validation Batch that the peer of the share processor with
+    /// identity own writes for the ingestion batch with this identity. This
+    /// takes our own identity rather than the peer's so that both sides of a
+    /// batch are constructed from the same arguments.
+    pub fn peer_validation_batch(
+        &self,
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+        own: ServerIdentity,
+    ) -> PeerValidationBatch {
+        PeerValidationBatch {
+            batch: self.validation_batch(naming_scheme, instance_name, own.peer()),
+            owner: own.peer(),
+        }
+    }
+}
+
+/// A validation batch written by this share processor. This and
+/// PeerValidationBatch are distinct types so that the two sides of a batch
+/// can't be mixed up, e.g. by reading our own validation batch from the peer's
+/// transport. Obtain one from BatchIdentity::own_validation_batch.
+pub struct OwnValidationBatch {
+    batch: Batch,
+    owner: ServerIdentity,
+}
+
+impl OwnValidationBatch {
+    /// Returns the identity of the share processor that writes this batch,
+    /// i.e., our own.
+    pub fn owner(&self) -> ServerIdentity {
+        self.owner
+    }
+
+    pub fn batch(&self) -> &Batch {
+        &self.batch
+    }
+
+    pub fn into_batch(self) -> Batch {
+        self.batch
+    }
+}
+
+/// A validation batch written by the peer share processor. See
+/// OwnValidationBatch. Obtain one from BatchIdentity::peer_validation_batch.
+pub struct PeerValidationBatch {
+    batch: Batch,
+    owner: ServerIdentity,
+}
+
+impl PeerValidationBatch {
+    /// Returns the identity of the share processor that writes this batch,
+    /// i.e., the peer.
+    pub fn owner(&self) -> ServerIdentity {
+        self.owner
+    }
+
+    pub fn batch(&self) -> &Batch {
+        &self.batch
+    }
+
+    pub fn into_batch(self) -> Batch {
+        self.batch
+    }
+}
+
+impl fmt::Display for BatchIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            self.aggregation_name,
+            self.date,
+            self.batch_id.to_hyphenated()
+        )
+    }
+}
+
+/// Everything needed to identify an ingestion or validation batch, e.g. in a
+/// message telling a worker which batch to process. Deserializing a
+/// BatchDescriptor validates the aggregation name and date exactly like
+/// constructing them directly does, and rejects unknown fields.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchDescriptor {
+    pub aggregation_name: AggregationName,
+    pub date: BatchDate,
+    pub batch_id: Uuid,
+    pub kind: BatchKind,
+    /// The attempt at producing the batch. See Batch::with_attempt.
+    #[serde(default, skip_serializing_if = "is_first_attempt")]
+    pub attempt: u32,
+}
+
+fn is_first_attempt(attempt: &u32) -> bool {
+    *attempt == 0
+}
+
+impl BatchDescriptor {
+    pub fn new(
+        aggregation_name: AggregationName,
+        date: BatchDate,
+        batch_id: Uuid,
+        kind: BatchKind,
+    ) -> BatchDescriptor {
+        BatchDescriptor {
+            aggregation_name,
+            date,
+            batch_id,
+            kind,
+            attempt: 0,
+        }
+    }
+
+    /// Returns this descriptor, changed to describe the provided attempt at
+    /// producing the batch.
+    pub fn with_attempt(self, attempt: u32) -> BatchDescriptor {
+        BatchDescriptor { attempt, ..self }
+    }
+
+    /// Returns the identity of the described batch.
+    pub fn identity(&self) -> BatchIdentity {
+        BatchIdentity::new(self.aggregation_name.clone(), self.date, self.batch_id)
+    }
+
+    /// Parses a BatchDescriptor from JSON, returning
+    /// Error::MalformedBatchDescriptorError if it is not a valid descriptor.
+    pub fn from_json(json: &[u8]) -> Result<BatchDescriptor, Error> {
+        serde_json::from_slice(json)
+            .map_err(|e| Error::MalformedBatchDescriptorError(e.to_string()))
+    }
+
+    /// Serializes this BatchDescriptor into JSON.
+    pub fn to_json(&self) -> String {
+        // None of the fields can fail to serialize, so it is OK to unwrap here.
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Returns the Batch described by this descriptor, named according to the
+    /// provided naming scheme. If an instance name is provided, it is
+    /// prepended to the keys.
+    pub fn batch(
+        &self,
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+    ) -> Batch {
+        let mut batch = naming_scheme
+            .batch(
+                &self.aggregation_name,
+                &self.batch_id,
+                &self.date,
+                self.kind,
+            )
+            .with_attempt(self.attempt)
+            .with_instance_name(instance_name);
+        batch.descriptor = Some(self.clone());
+        batch
+    }
+}
+
+impl From<&BatchDescriptor> for Batch {
+    /// Returns the described Batch, named according to the default naming
+    /// scheme.
+    fn from(descriptor: &BatchDescriptor) -> Batch {
+        descriptor.batch(&DEFAULT_NAMING_SCHEME, None)
+    }
+}
+
+/// The files that make up a batch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BatchFileKind {
+    /// The batch header
+    Header,
+    /// The Avro packet file
+    Packets,
+    /// The signature over the header
+    Signature,
+}
+
+impl BatchFileKind {
+    /// All the kinds of files in a batch
+    pub const ALL: [BatchFileKind; 3] = [
+        BatchFileKind::Header,
+        BatchFileKind::Packets,
+        BatchFileKind::Signature,
+    ];
+}
+
+/// The longest key, in bytes of UTF-8, that we store objects under. Both S3
+/// and GCS limit keys to 1024 bytes.
+pub const MAX_KEY_LENGTH: usize = 1024;
+
+/// Manages the paths to the different files in a batch
+pub struct Batch {
+    header_path: String,
+    signature_path: String,
+    packet_file_path: String,
+    packet_file_shard_paths: Vec<String>,
+    packet_part_naming: PacketPartNaming,
+    descriptor: Option<BatchDescriptor>,
+}
+
+impl Batch {
+    /// Creates a Batch representing an ingestion batch, named according to
+    /// the default naming scheme
+    pub fn new_ingestion(
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+        date: &BatchDate,
+    ) -> Batch {
+        Batch::with_naming_scheme(
+            &DEFAULT_NAMING_SCHEME,
+            None,
+            aggregation_name,
+            batch_id,
+            date,
+            BatchKind::Ingestion,
+        )
+    }
+
+    /// Creates a Batch representing a validation batch produced by the
+    /// specified server, named according to the default naming scheme
+    pub fn new_validation(
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+        date: &BatchDate,
+        server: ServerIdentity,
+    ) -> Batch {
+        Batch::with_naming_scheme(
+            &DEFAULT_NAMING_SCHEME,
+            None,
+            aggregation_name,
+            batch_id,
+            date,
+            BatchKind::Validation(server),
+        )
+    }
+
+    /// Creates a Batch named according to the provided naming scheme. If an
+    /// instance name is provided, it is prepended to the keys.
+    pub fn with_naming_scheme(
+        naming_scheme: &dyn BatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+        date: &BatchDate,
+        kind: BatchKind,
+    ) -> Batch {
+        BatchDescriptor::new(aggregation_name.clone(), *date, *batch_id, kind)
+            .batch(naming_scheme, instance_name)
+    }
+
+    /// Creates a Batch representing the sum part produced by the specified
+    /// server over the batches in the aggregation window from
+    /// aggregation_start to aggregation_end. Since a sum covers many batches,
+    /// it is keyed by the window rather than a batch UUID, e.g.
+    /// "<aggregation name>/<start>-<end>.sum_0". The packet file lists the
+    /// UUIDs of the invalid packets encountered during aggregation.
+    pub fn new_sum(
+        instance_name: Option<&InstanceName>,
+        aggregation_name: &AggregationName,
+        aggregation_start: &BatchDate,
+        aggregation_end: &BatchDate,
+        server: ServerIdentity,
+    ) -> Batch {
+        let batch_path = format!(
+            "{}/{}-{}",
+            aggregation_name, aggregation_start, aggregation_end
+        );
+        let filename = format!("sum_{}", server.index());
+
+        Batch::from_keys(
+            format!("{}.{}", batch_path, filename),
+            format!("{}.{}.sig", batch_path, filename),
+            format!("{}.invalid_uuid_{}.avro", batch_path, server.index()),
+        )
+        .with_instance_name(instance_name)
+    }
+
+    /// Recovers the descriptor of the batch whose header is stored at the
+    /// provided key under the default naming scheme. See
+    /// DefaultBatchNamingScheme::parse_header_key.
+    pub fn from_header_key(key: &str) -> Result<BatchDescriptor, Error> {
+        DEFAULT_NAMING_SCHEME.parse_header_key(None, key)
+    }
+
+    /// Returns the identities of the complete ingestion batches in the
+    /// provided aggregation whose dates fall within [start, end), named
+    /// according to the default naming scheme. See
+    /// DefaultBatchNamingScheme::enumerate_range.
+    pub fn enumerate_range(
+        transport: &dyn Transport,
+        aggregation_name: &AggregationName,
+        start: &BatchDate,
+        end: &BatchDate,
+    ) -> Result<Vec<BatchIdentity>, Error> {
+        DEFAULT_NAMING_SCHEME.enumerate_range(transport, None, aggregation_name, start, end)
+    }
+
+    /// Creates a Batch from the keys of its header, signature and packet
+    /// file. This is intended for implementations of BatchNamingScheme.
+    pub fn from_keys(header_key: String, signature_key: String, packet_file_key: String) -> Batch {
+        Batch {
+            header_path: header_key,
+            signature_path: signature_key,
+            packet_file_path: packet_file_key,
+            packet_file_shard_paths: Vec::new(),
+            packet_part_naming: PacketPartNaming::Shard,
+            descriptor: None,
+        }
+    }
+
+    /// Returns a Batch whose packet file shards are named according to the
+    /// provided PacketPartNaming. The keys of any shards the batch already has
+    /// are derived again, so this may be applied before or after
+    /// with_packet_file_shards.
+    pub fn with_packet_part_naming(self, packet_part_naming: PacketPartNaming) -> Batch {
+        let shard_count = self.packet_file_shard_paths.len();
+        Batch {
+            packet_part_naming,
+            ..self
+        }
+        .with_packet_file_shards(shard_count)
+    }
+
+    /// Returns a Batch whose packets are split across shard_count packet file
+    /// shards rather than a single packet file. The shard keys are derived
+    /// from the packet file key according to the batch's PacketPartNaming,
+    /// e.g. "<packet file key>.shard_0".
+    pub fn with_packet_file_shards(self, shard_count: usize) -> Batch {
+        let packet_file_shard_paths = (0..shard_count)
+            .map(|index| self.packet_file_shard_key(index))
+            .collect();
+        Batch {
+            packet_file_shard_paths,
+            ..self
+        }
+    }
+
+    /// Returns the key of the packet file shard with the provided index.
+    pub fn packet_file_shard_key(&self, index: usize) -> String {
+        self.packet_part_naming
+            .part_key(&self.packet_file_path, index)
+    }
+
+    /// Returns the keys of the packet file shards in the batch, in order. This
+    /// is empty unless the batch was created with with_packet_file_shards.
+    pub fn packet_file_shard_keys(&self) -> &[String] {
+        &self.packet_file_shard_paths
+    }
+
+    /// Returns a Batch for the provided attempt at producing this batch, so
+    /// that reprocessing a batch need not overwrite the output of an earlier
+    /// attempt. Attempt 0 is the first attempt and leaves the keys unchanged.
+    /// For later attempts, ".retry-<attempt>" is inserted into every key right
+    /// after the header key, which all keys produced by the default naming
+    /// scheme for validation batches begin with, e.g.
+    /// "<uuid>.validity_pha.retry-1.avro"; keys that don't begin with the
+    /// header key get it appended. This must be applied to the keys of a first
+    /// attempt.
+    pub fn with_attempt(self, attempt: u32) -> Batch {
+        if attempt == 0 {
+            return self;
+        }
+        let suffix = format!(".retry-{}", attempt);
+        let stem = self.header_path.clone();
+        let rename = |path: &str| match path.strip_prefix(stem.as_str()) {
+            Some(rest) => format!("{}{}{}", stem, suffix, rest),
+            None => format!("{}{}", path, suffix),
+        };
+        Batch {
+            header_path: rename(&self.header_path),
+            signature_path: rename(&self.signature_path),
+            packet_file_path: rename(&self.packet_file_path),
+            packet_file_shard_paths: self
+                .packet_file_shard_paths
+                .iter()
+                .map(|path| rename(path))
+                .collect(),
+            packet_part_naming: self.packet_part_naming,
+            descriptor: self
+                .descriptor
+                .map(|descriptor| descriptor.with_attempt(attempt)),
+        }
+    }
+
+    /// Checks that no key in this batch, including any packet file shards, is
+    /// longer than MAX_KEY_LENGTH once reserved_prefix_length bytes are
+    /// prepended to it, e.g. by a transport that stores everything under a
+    /// prefix. Otherwise, Error::KeyTooLong is returned for the longest key,
+    /// so that the problem is caught before any request is made to a store
+    /// that would reject the key. This should be applied once a Batch has all
+    /// its keys, i.e. after with_attempt and with_packet_file_shards.
+    pub fn check_key_lengths(self, reserved_prefix_length: usize) -> Result<Batch, Error> {
+        if let Some((_, key)) = self.keys().max_by_key(|(_, key)| key.len()) {
+            let length = reserved_prefix_length.saturating_add(key.len());
+            if length > MAX_KEY_LENGTH {
+                return Err(Error::KeyTooLong(key.to_owned(), length, MAX_KEY_LENGTH));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Returns true if the header, signature and packet file (or first packet
+    /// file shard) of this batch are all among the provided keys.
+    fn is_complete(&self, keys: &HashSet<String>) -> bool {
+        keys.contains(self.header_key())
+            && keys.contains(self.signature_key())
+            && (keys.contains(self.packet_file_key())
+                || keys.contains(&self.packet_file_shard_key(0)))
+    }
+
+    /// Prepends the instance name, if any, to all the keys in the batch.
+    fn with_instance_name(self, instance_name: Option<&InstanceName>) -> Batch {
+        match instance_name {
+            Some(instance_name) => Batch {
+                header_path: format!("{}/{}", instance_name, self.header_path),
+                signature_path: format!("{}/{}", instance_name, self.signature_path),
+                packet_file_path: format!("{}/{}", instance_name, self.packet_file_path),
+                packet_file_shard_paths: self
+                    .packet_file_shard_paths
+                    .iter()
+                    .map(|path| format!("{}/{}", instance_name, path))
+                    .collect(),
+                packet_part_naming: self.packet_part_naming,
+                descriptor: self.descriptor,
+            },
+            None => self,
+        }
+    }
+
+    /// Returns the descriptor of this batch, if it is an ingestion or
+    /// validation batch.
+    pub fn descriptor(&self) -> Option<&BatchDescriptor> {
+        self.descriptor.as_ref()
+    }
+
+    /// Returns the key of the specified file in the batch
+    pub fn key(&self, kind: BatchFileKind) -> &str {
+        match kind {
+            BatchFileKind::Header => self.header_path.as_ref(),
+            BatchFileKind::Packets => self.packet_file_path.as_ref(),
+            BatchFileKind::Signature => self.signature_path.as_ref(),
+        }
+    }
+
+    /// Returns the keys of all the files in the batch, in the order of
+    /// BatchFileKind::ALL, followed by any packet file shards. Anything that
+    /// needs to operate on a batch as a whole (e.g., copying or deleting it)
+    /// should use this rather than enumerating the files itself.
+    pub fn keys(&self) -> impl Iterator<Item = (BatchFileKind, &str)> {
+        BatchFileKind::ALL
+            .iter()
+            .map(move |kind| (*kind, self.key(*kind)))
+            .chain(
+                self.packet_file_shard_paths
+                    .iter()
+                    .map(|path| (BatchFileKind::Packets, path.as_str())),
+            )
+    }
+
+    /// Returns the key of the batch's manifest (see BatchWriter::put_manifest),
+    /// e.g. "<header key>.manifest.json". The manifest is optional, so it is
+    /// not among the keys returned by Batch::keys.
+    pub fn manifest_key(&self) -> String {
+        format!("{}.manifest.json", self.header_path)
+    }
+
+    /// Returns the key of the batch's record of packets that failed validation
+    /// (see BatchWriter::put_packet_failures), e.g. "<header key>.failures.json".
+    /// The record is optional, so it is not among the keys returned by
+    /// Batch::keys.
+    pub fn packet_failures_key(&self) -> String {
+        format!("{}.failures.json", self.header_path)
+    }
+
+    /// Returns the key of the signature over the batch's manifest.
+    pub fn manifest_signature_key(&self) -> String {
+        format!("{}.sig", self.manifest_key())
+    }
+
+    /// Returns the key of the batch's done marker (see DoneMarker), e.g.
+    /// "<header key>.done". The marker is optional, so it is not among the
+    /// keys returned by Batch::keys.
+    pub fn done_marker_key(&self) -> String {
+        format!("{}.done", self.header_path)
+    }
+
+    fn header_key(&self) -> &str {
+        self.key(BatchFileKind::Header)
+    }
+
+    fn signature_key(&self) -> &str {
+        self.key(BatchFileKind::Signature)
+    }
+
+    fn packet_file_key(&self) -> &str {
+        self.key(BatchFileKind::Packets)
+    }
+}
+
+/// Size in bytes of the buffer through which BatchReader decodes packet files
+/// that were spooled to disk. avro_rs reads whole blocks at a time, so larger
+/// buffers made no measurable difference to decoding a 120 MB packet file.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8_192;
+
+/// Size in bytes of the buffer through which BatchReader downloads packet
+/// files. std::io::copy's 8 KiB buffer means a great many small reads from
+/// network transports.
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 1_048_576;
+
+/// Checks that signature is a valid signature over message by the holder of
+/// the private key corresponding to public_key, failing with
+/// Error::CryptographyError otherwise, whatever the reason: a malformed
+/// signature, a signature by another key or over another message.
+///
+/// ring's verification is not constant time with respect to the signature: it
+/// rejects malformed signatures early and compares the final values with an
+/// early exit. That is safe because all of its inputs are public: anyone who
+/// can fetch a batch can run the same check, so its timing reveals nothing
+/// they couldn't learn themselves. It must not be used to compare secrets.
+pub fn verify_signature<B: AsRef<[u8]>>(
+    public_key: &UnparsedPublicKey<B>,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    public_key
+        .verify(message, signature)
+        .map_err(|_| Error::CryptographyError("signature does not verify".to_owned()))
+}
+
+/// Allows reading files, including signature validation, from an ingestion or
+/// validation batch containing a header, a packet file and a signature.
+pub struct BatchReader<'a, H, P> {
+    batch: Batch,
+    transport: &'a mut dyn Transport,
+    packet_schema: Schema,
+    spool_threshold: usize,
+    read_buffer_size: usize,
+    copy_buffer_size: usize,
+    max_packet_file_size: Option<u64>,
+    retry_policy: RetryPolicy,
+    // These next two fields are not real and are used because not using H and P
+    // in the struct definition is an error.
+    phantom_header: PhantomData<*const H>,
+    phantom_packet: PhantomData<*const P>,
+}
+
+impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
+    pub fn new(batch: Batch, transport: &'a mut dyn Transport) -> Self {
+        BatchReader {
+            batch,
+            transport,
+            packet_schema: P::reader_schema(),
+            spool_threshold: DEFAULT_SPOOL_THRESHOLD,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            copy_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            max_packet_file_size: None,
+            retry_policy: RetryPolicy::NONE,
+            phantom_header: PhantomData,
+            phantom_packet: PhantomData,
+        }
+    }
+
+    /// Returns the batch this reader reads.
+    pub fn batch(&self) -> &Batch {
+        &self.batch
+    }
+
+    /// Sets the number of bytes of a packet file that will be kept in memory
+    /// while it is downloaded before it is spooled to a temporary file. Use
+    /// usize::MAX to always hold packet files in memory.
+    pub fn set_spool_threshold(&mut self, spool_threshold: usize) {
+        self.spool_threshold = spool_threshold;
+    }
+
+    /// Sets the size in bytes of the buffer through which verified packet
+    /// files that were spooled to disk are read back and decoded. Defaults to
+    /// DEFAULT_READ_BUFFER_SIZE.
+    pub fn set_read_buffer_size(&mut self, read_buffer_size: usize) {
+        self.read_buffer_size = read_buffer_size;
+    }
+
+    /// Sets the size in bytes of the buffer through which packet files are
+    /// downloaded from the transport. Defaults to DEFAULT_COPY_BUFFER_SIZE.
+    pub fn set_copy_buffer_size(&mut self, copy_buffer_size: usize) {
+        self.copy_buffer_size = copy_buffer_size;
+    }
+
+    /// Sets the largest packet file or packet file shard, in bytes, that will
+    /// be read. Larger ones are refused with Error::PacketFileTooLarge, before
+    /// they are fetched if the transport reports their size and otherwise once
+    /// more than that many bytes have been read. Defaults to None, meaning no
+    /// limit.
+    pub fn set_max_packet_file_size(&mut self, max_packet_file_size: Option<u64>) {
+        self.max_packet_file_size = max_packet_file_size;
+    }
+
+    /// Sets how fetching the header, its signature and packet files is retried
+    /// when it fails with a transient error. A retried fetch starts over from
+    /// the beginning of the file, discarding whatever was read of it before.
+    /// Defaults to RetryPolicy::NONE.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Return the parsed header from this batch, but only if its signature is
+    /// valid. The signature covers only the header, but the header in turn
+    /// names the batch UUID and the digest of the packet file (or shards), so
+    /// a header or packet file from one batch can't be passed off as part of
+    /// another once callers check both.
+    pub fn header(&self, key: &UnparsedPublicKey<Vec<u8>>) -> Result<H> {
+        Ok(self.verified_header(key)?.header)
+    }
+
+    /// Like header, but also returns the exact content of the header and
+    /// signature files that were verified.
+    pub fn verified_header(&self, key: &UnparsedPublicKey<Vec<u8>>) -> Result<VerifiedHeader<H>> {
+        Ok(self.verified_header_with_keys(&[key])?.1)
+    }
+
+    /// Like verified_header, but accepts a signature made with any one of the
+    /// provided keys, e.g. while the signer is rotating its key. Signatures
+    /// don't identify their key, so each is tried in turn. Returns the index of
+    /// the first key that verified, along with the header.
+    pub fn verified_header_with_keys(
+        &self,
+        keys: &[&UnparsedPublicKey<Vec<u8>>],
+    ) -> Result<(usize, VerifiedHeader<H>)> {
+        let signature = self.retry_policy.retry(|| {
+            let mut signature = Vec::new();
+            self.transport
+                .get(self.batch.signature_key())?
+                .read_to_end(&mut signature)
+                .context("failed to read signature")?;
+            Ok(signature)
+        })?;
+
+        let header_buf = self.retry_policy.retry(|| {
+            let mut header_buf = Vec::new();
+            self.transport
+                .get(self.batch.header_key())?
+                .read_to_end(&mut header_buf)
+                .context("failed to read header from transport")?;
+            Ok(header_buf)
+        })?;
+
+        let key_index = keys
+            .iter()
+            .position(|key| verify_signature(key, &header_buf, &signature).is_ok())
+            .ok_or_else(|| match keys.len() {
+                1 => Error::CryptographyError("signature does not verify".to_owned()),
+                n => Error::CryptographyError(format!(
+                    "signature does not verify with any of {} keys",
+                    n
+                )),
+            })
+            .context("invalid signature on header")?;
+
+        Ok((
+            key_index,
+            VerifiedHeader {
+                header: H::read(Cursor::new(&header_buf))?,
+                header_bytes: header_buf,
+                signature,
+            },
+        ))
+    }
+
+    /// Fetches the batch's manifest (see BatchWriter::put_manifest) and
+    /// returns it if its signature verifies with the provided key.
+    pub fn verified_manifest(&self, key: &UnparsedPublicKey<Vec<u8>>) -> Result<BatchManifest> {
+        let mut signature = Vec::new();
+        self.transport
+            .get(&self.batch.manifest_signature_key())?
+            .read_to_end(&mut signature)
+            .context("failed to read manifest signature")?;
+
+        let mut manifest_buf = Vec::new();
+        self.transport
+            .get(&self.batch.manifest_key())?
+            .read_to_end(&mut manifest_buf)
+            .context("failed to read manifest from transport")?;
+
+        verify_signature(key, &manifest_buf, &signature)
+            .context("invalid signature on manifest")?;
+        serde_json::from_slice(&manifest_buf).context("malformed manifest")
+    }
+
+    /// Return an avro_rs::Reader that yields the packets in the packet file,
+    /// but only if the whole file's digest matches the packet_file_digest field
+    /// in the provided header. The header is assumed to be trusted. Fails if
+    /// the header describes a sharded packet file; see sharded_packet_reader.
+    pub fn packet_file_reader(&self, header: &H) -> Result<Reader<'_, Box<dyn Read>>> {
+        if !header.packet_file_shard_digests().is_empty() {
+            return Err(anyhow!(
+                "header describes {} packet file shards rather than one packet file",
+                header.packet_file_shard_digests().len()
+            ));
+        }
+        self.verified_packet_file_reader(self.batch.packet_file_key(), header.packet_file_digest())
+    }
+
+    /// Returns a ShardedPacketReader that yields the packets in all the packet
+    /// file shards described by the provided header, in order, or in the
+    /// single packet file if the header describes no shards. The header is
+    /// assumed to be trusted.
+    pub fn sharded_packet_reader<'b>(
+        &'b self,
+        header: &H,
+    ) -> Result<ShardedPacketReader<'b, 'a, H, P>> {
+        let shards = if header.packet_file_shard_digests().is_empty() {
+            vec![(
+                self.batch.packet_file_key().to_owned(),
+                header.packet_file_digest().clone(),
+            )]
+        } else {
+            if !header.packet_file_digest().is_empty() {
+                return Err(Error::MalformedHeaderError(
+                    "header has both a packet file digest and packet file shard digests".to_owned(),
+                )
+                .into());
+            }
+            header
+                .packet_file_shard_digests()
+                .iter()
+                .enumerate()
+                .map(|(index, digest)| (self.batch.packet_file_shard_key(index), digest.clone()))
+                .collect()
+        };
+
+        Ok(ShardedPacketReader {
+            batch_reader: self,
+            shards: shards.into_iter(),
+            current_shard: None,
+            verified_shard_callback: None,
+        })
+    }
+
+    /// Fetches the packet file at the provided key, checks that its digest
+    /// matches the provided one and returns an avro_rs::Reader over it.
+    fn verified_packet_file_reader(
+        &self,
+        key: &str,
+        digest: &[u8],
+    ) -> Result<Reader<'_, Box<dyn Read>>> {
+        let packet_file = self.verified_packet_file(key, digest)?;
+        self.packet_reader(key, packet_file, &Rc::default())
+    }
+
+    /// Fetches the packet file at the provided key and returns its content if
+    /// its digest matches the provided one.
+    fn verified_packet_file(&self, key: &str, digest: &[u8]) -> Result<SpooledBuffer> {
+        // Fetch packet file to validate its digest. It could be quite large so
+        // so our intuition would be to stream the packets from the transport
+        // and into a hasher and into the validation step, so that we wouldn't
+        // need the whole file in memory at once. We can't do this because:
+        //   (1) we don't want to do anything with any of the data in the packet
+        //       file until we've verified integrity+authenticity
+        //   (2) we need to copy the entire file into storage we control before
+        //       validating its digest to avoid TOCTOU vulnerabilities.
+        // Batches may be 300-400 MB, so rather than holding the entire packet
+        // file in memory we copy it into a SpooledBuffer, which moves it into
+        // an anonymous temporary file once it outgrows spool_threshold,
+        // computing its digest as it goes by ...
+        if let Some(max_packet_file_size) = self.max_packet_file_size {
+            if let Some(size) = self.retry_policy.retry(|| self.transport.size(key))? {
+                if size > max_packet_file_size {
+                    return Err(Error::PacketFileTooLarge(
+                        key.to_owned(),
+                        size,
+                        max_packet_file_size,
+                    )
+                    .into());
+                }
+            }
+        }
+
+        // The transport's idea of the size may be wrong or out of date, so we
+        // also stop reading one byte past the limit. A retried fetch starts
+        // over with an empty spool and digest.
+        let sidecar_writer = self.retry_policy.retry(|| {
+            let packet_file_reader = self.transport.get(key)?;
+            let mut packet_file_reader = match self.max_packet_file_size {
+                Some(max_packet_file_size) => {
+                    packet_file_reader.take(max_packet_file_size.saturating_add(1))
+                }
+                None => packet_file_reader.take(u64::MAX),
+            };
+            let mut sidecar_writer = SidecarWriter::new(
+                SpooledBuffer::new(self.spool_threshold),
+                DigestWriter::new(),
+            );
+
+            copy_with_buffer(
+                &mut packet_file_reader,
+                &mut sidecar_writer,
+                self.copy_buffer_size,
+            )
+            .context("failed to load packet file")?;
+            Ok(sidecar_writer)
+        })?;
+        if let Some(max_packet_file_size) = self.max_packet_file_size {
+            if sidecar_writer.bytes_written() > max_packet_file_size {
+                return Err(Error::PacketFileTooLarge(
+                    key.to_owned(),
+                    sidecar_writer.bytes_written(),
+                    max_packet_file_size,
+                )
+                .into());
+            }
+        }
+
+        // ... then verify the digest over it ...
+        if digest != sidecar_writer.sidecar.finish().as_ref() {
+            return Err(Error::PacketFileDigestMismatch(key.to_owned()).into());
+        }
+        Ok(sidecar_writer.writer)
+    }
+
+    /// Returns an avro_rs::Reader over the provided verified content of the
+    /// packet file at the provided key. How much of the packet file the
+    /// returned reader has consumed is kept in progress.
+    fn packet_reader(
+        &self,
+        key: &str,
+        packet_file: SpooledBuffer,
+        progress: &Rc<ReadProgress>,
+    ) -> Result<Reader<'_, Box<dyn Read>>> {
+        // ... then return a packet reader, provided that the schema the
+        // packet file was written with is one we can read. avro_rs resolves
+        // the writer's schema against ours as it reads, but checking up front
+        // means a renamed or retyped field fails loudly rather than being
+        // misread.
+        let packet_file = CountingReader {
+            reader: packet_file
+                .into_buffered_reader(self.read_buffer_size)
+                .context("failed to read back spooled packet file")?,
+            progress: Rc::clone(progress),
+        };
+        let reader = resolvable_container(packet_file)
+            .map_err(anyhow::Error::from)
+            .and_then(|packet_file| {
+                let packet_file: Box<dyn Read> = Box::new(packet_file);
+                Reader::with_schema(&self.packet_schema, packet_file)
+                    .context("failed to create Avro reader for packets")
+            });
+        let reader = match reader {
+            Ok(reader) => reader,
+            // A batch with no packets still has a packet file holding the
+            // schema, so an empty file is malformed rather than empty.
+            Err(_) if progress.exhausted.get() && progress.bytes_read.get() == 0 => {
+                return Err(Error::MalformedDataPacketError(format!(
+                    "packet file {} is empty rather than an Avro object container",
+                    key
+                ))
+                .into())
+            }
+            Err(e) => return Err(e),
+        };
+        if !can_read_schema(reader.writer_schema(), &self.packet_schema) {
+            return Err(Error::AvroError(
+                format!(
+                    "schema mismatch: packet file {} was written with schema {}, which is \
+                    incompatible with {}",
+                    key,
+                    reader.writer_schema().canonical_form(),
+                    self.packet_schema.canonical_form()
+                ),
+                avro_rs::Error::Validation,
+            )
+            .into());
+        }
+        Ok(reader)
+    }
+}
+
+/// How far an avro_rs::Reader has got through a packet file: the number of
+/// bytes it has consumed, and whether it has reached the end of the file.
+/// avro_rs reports the end of the records in the same way whether the file
+/// ends there or not, e.g. at a block claiming to hold no records, so this
+/// tells the two apart.
+#[derive(Default)]
+struct ReadProgress {
+    bytes_read: Cell<u64>,
+    exhausted: Cell<bool>,
+}
+
+/// Records the progress of reads through it, so that it can be followed while
+/// an avro_rs::Reader owns it.
+struct CountingReader<R> {
+    reader: R,
+    progress: Rc<ReadProgress>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        let progress = &self.progress;
+        progress
+            .bytes_read
+            .set(progress.bytes_read.get() + n as u64);
+        if n == 0 && !buf.is_empty() {
+            progress.exhausted.set(true);
+        }
+        Ok(n)
+    }
+}
+
+/// A header whose signature has been verified, along with the exact content of
+/// the header and signature files.
+pub struct VerifiedHeader<H> {
+    pub header: H,
+    pub header_bytes: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Yields the packets in the packet file shards of a batch, in order. Each
+/// shard is fetched and its digest checked against the header only once all the
+/// packets in the preceding shard have been read, so at most one shard is held
+/// in memory or spooled to a temporary file at a time.
+pub struct ShardedPacketReader<'b, 'a, H, P> {
+    batch_reader: &'b BatchReader<'a, H, P>,
+    shards: std::vec::IntoIter<(String, Vec<u8>)>,
+    current_shard: Option<CurrentShard<'b>>,
+    #[allow(clippy::type_complexity)]
+    verified_shard_callback: Option<Box<dyn FnMut(&str, &mut dyn Read) -> Result<()> + 'b>>,
+}
+
+impl<'b, 'a, H: Header, P: Packet> ShardedPacketReader<'b, 'a, H, P> {
+    /// Sets a function that is called with the key of each shard and a reader
+    /// over its content once its digest has been verified, before any packets
+    /// are read from it. If it fails, read_packet fails with its error.
+    pub fn set_verified_shard_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&str, &mut dyn Read) -> Result<()> + 'b,
+    {
+        self.verified_shard_callback = Some(Box::new(callback));
+    }
+
+    /// Reads the next packet, moving on to the next shard when the current one
+    /// is exhausted. Returns Error::EofError once all the shards have been
+    /// read. A shard with no packets is fine, but one whose packets end before
+    /// the shard does fails with Error::MalformedDataPacketError.
+    pub fn read_packet(&mut self) -> Result<P, Error> {
+        loop {
+            if let Some(shard) = &mut self.current_shard {
+                match P::read(&mut shard.reader) {
+                    Ok(packet) => {
+                        shard.packets_read += 1;
+                        return Ok(packet);
+                    }
+                    Err(Error::EofError) if shard.progress.exhausted.get() => {
+                        self.current_shard = None
+                    }
+                    Err(Error::EofError) => {
+                        return Err(shard.locate(Error::MalformedDataPacketError(
+                            "packet file continues after the end of its records".to_owned(),
+                        )))
+                    }
+                    Err(e) => return Err(shard.locate(e)),
+                }
+            }
+
+            let (key, digest) = self.shards.next().ok_or(Error::EofError)?;
+            let mut packet_file = self
+                .batch_reader
+                .verified_packet_file(&key, &digest)
+                .map_err(Error::AnyhowError)?;
+            if let Some(callback) = &mut self.verified_shard_callback {
+                let mut content = packet_file
+                    .reader()
+                    .context("failed to read back spooled packet file")
+                    .map_err(Error::AnyhowError)?;
+                callback(&key, &mut content).map_err(Error::AnyhowError)?;
+            }
+            let progress = Rc::default();
+            let reader = self
+                .batch_reader
+                .packet_reader(&key, packet_file, &progress)
+                .map_err(Error::AnyhowError)?;
+            self.current_shard = Some(CurrentShard {
+                key,
+                reader,
+                progress,
+                packets_read: 0,
+            });
+        }
+    }
+}
+
+/// The packet file shard that a ShardedPacketReader is reading from, and how
+/// far into it the reader is.
+struct CurrentShard<'b> {
+    key: String,
+    reader: Reader<'b, Box<dyn Read>>,
+    progress: Rc<ReadProgress>,
+    packets_read: u64,
+}
+
+impl CurrentShard<'_> {
+    /// Adds the index of the packet being read, and roughly where in the shard
+    /// it is, to an error decoding it. avro_rs reads a whole block of packets
+    /// before decoding any of them, so the offset is that of the end of the
+    /// block containing the packet, or of as much of it as could be read.
+    fn locate(&self, error: Error) -> Error {
+        let location = format!(
+            "packet {} of {}, near byte {}",
+            self.packets_read,
+            self.key,
+            self.progress.bytes_read.get()
+        );
+        match error {
+            Error::MalformedDataPacketError(message) => {
+                Error::MalformedDataPacketError(format!("{}: {}", location, message))
+            }
+            Error::AvroError(message, e) => {
+                Error::AvroError(format!("{}: {}", location, message), e)
+            }
+            e => e,
+        }
+    }
+}
+
+/// Returns an Avro object container file with the provided schema and no data
+/// blocks, i.e., the header that avro_rs::Writer would emit before appending
+/// the first record.
+fn empty_object_container(schema: &Schema) -> Vec<u8> {
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "avro.schema".to_owned(),
+        Value::Bytes(schema.canonical_form().into_bytes()),
+    );
+    metadata.insert("avro.codec".to_owned(), Value::Bytes(b"null".to_vec()));
+
+    let mut container = b"Obj\x01".to_vec();
+    // Encoding a map of bytes against a matching schema cannot fail.
+    container.extend(
+        to_avro_datum(&Schema::Map(Box::new(Schema::Bytes)), Value::Map(metadata)).unwrap(),
+    );
+    // The sync marker only has to match between data blocks, of which there
+    // are none.
+    container.extend_from_slice(&[0u8; 16]);
+    container
+}
+
+/// A TransportWriter into a SpooledBuffer shared with its creator, through which
+/// BatchWriter spools packet files that it uploads with retries.
+struct SpoolWriter(Rc<RefCell<SpooledBuffer>>);
+
+impl Write for SpoolWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl TransportWriter for SpoolWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Allows writing files, including signature file construction, from an
+/// ingestion or validation batch containing a header, a packet file and a
+/// signature.
+pub struct BatchWriter<'a, H, P> {
+    batch: Batch,
+    transport: &'a mut dyn Transport,
+    packet_schema: Schema,
+    spool_threshold: usize,
+    rng: Option<&'a dyn SecureRandom>,
+    retry_policy: RetryPolicy,
+    written_files: Vec<ManifestFile>,
+    written_keys: Vec<String>,
+    phantom_header: PhantomData<*const H>,
+    phantom_packet: PhantomData<*const P>,
+}
+
+/// An index of the files in a batch, for consumers that want to check what a
+/// share processor produced without parsing the batch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchManifest {
+    /// The version of the Avro schemas the batch's files conform to. See
+    /// idl::SCHEMA_VERSION.
+    pub schema_version: String,
+    /// The number of packets in the batch
+    pub packet_count: u64,
+    /// The files in the batch, in the order they were written
+    pub files: Vec<ManifestFile>,
+}
+
+/// An ingestion packet that was left out of a validation batch because it could
+/// not be validated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketFailure {
+    /// The UUID of the ingestion packet
+    pub uuid: Uuid,
+    /// Why the packet could not be validated
+    pub reason: String,
+}
+
+/// A file listed in a BatchManifest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// The key under which the file is stored
+    pub key: String,
+    /// The lowercase hex encoding of the SHA-256 digest of the file
+    pub sha256: String,
+    /// The size of the file in bytes
+    pub size: u64,
+}
+
+impl ManifestFile {
+    fn new(key: &str, digest: &Sha256Digest, size: u64) -> ManifestFile {
+        ManifestFile {
+            key: key.to_owned(),
+            sha256: lowercase_hex(digest.as_ref()),
+            size,
+        }
+    }
+}
+
+/// A record that a batch was completely written, stored as JSON under
+/// Batch::done_marker_key once nothing more remains to be done for it, so that
+/// a run handed the same batch again can recognize it as done from one small
+/// read. See BatchIntaker::set_write_done_marker.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DoneMarker {
+    /// The UUID of the batch
+    pub batch_uuid: Uuid,
+    /// The number of packets in the validation batch that was written
+    pub packet_count: u64,
+    /// The lowercase hex encoding of the SHA-256 digest of the validation
+    /// packet file that was written, which a later run can compare with the
+    /// packet file it finds
+    pub validation_packet_file_sha256: String,
+}
+
+impl DoneMarker {
+    /// Creates a marker for the batch with the provided UUID, whose validation
+    /// packet file holds packet_count packets and has the provided digest.
+    pub fn new(
+        batch_uuid: Uuid,
+        packet_count: u64,
+        validation_packet_file_digest: &[u8],
+    ) -> DoneMarker {
+        DoneMarker {
+            batch_uuid,
+            packet_count,
+            validation_packet_file_sha256: lowercase_hex(validation_packet_file_digest),
+        }
+    }
+
+    /// Returns the done marker of the provided batch, or None if there is no
+    /// marker. A marker that can't be read or parsed for any reason is taken
+    /// not to exist, so that the batch will be processed again.
+    pub fn get(transport: &dyn Transport, batch: &Batch) -> Option<DoneMarker> {
+        let mut marker = Vec::new();
+        transport
+            .get(&batch.done_marker_key())
+            .ok()?
+            .read_to_end(&mut marker)
+            .ok()?;
+        serde_json::from_slice(&marker).ok()
+    }
+
+    /// Writes this marker to the provided batch's done marker key, retrying
+    /// with a fresh upload according to the provided policy.
+    pub fn put(
+        &self,
+        transport: &mut dyn Transport,
+        batch: &Batch,
+        retry_policy: RetryPolicy,
+    ) -> Result<()> {
+        let key = batch.done_marker_key();
+        let marker = serde_json::to_vec_pretty(self).context("failed to encode done marker")?;
+        retry_policy.retry(|| {
+            let mut writer = transport.put(&key)?;
+            if let Err(e) = writer.write_all(&marker) {
+                // The upload is abandoned whether or not cancelling it succeeds
+                let _ = writer.cancel_upload();
+                return Err(e).with_context(|| format!("failed to write {}", key));
+            }
+            writer
+                .complete_upload()
+                .with_context(|| format!("failed to complete upload of {}", key))
+        })
+    }
+}
+
+fn lowercase_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Number of bytes of signed content BatchWriter will hold in memory before
+/// spooling it to a temporary file.
+pub const DEFAULT_SPOOL_THRESHOLD: usize = 1_048_576;
+
+impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
+    pub fn new(batch: Batch, transport: &'a mut dyn Transport) -> Self {
+        BatchWriter {
+            batch,
+            transport,
+            packet_schema: P::schema(),
+            spool_threshold: DEFAULT_SPOOL_THRESHOLD,
+            rng: None,
+            retry_policy: RetryPolicy::NONE,
+            written_files: Vec::new(),
+            written_keys: Vec::new(),
+            phantom_header: PhantomData,
+            phantom_packet: PhantomData,
+        }
+    }
+
+    /// Sets the number of bytes of content that will be kept in memory while
+    /// writing the header before it is spooled to a temporary file.
+    pub fn set_spool_threshold(&mut self, spool_threshold: usize) {
+        self.spool_threshold = spool_threshold;
+    }
+
+    /// Sets the source of randomness used to sign the header. Defaults to
+    /// ring::rand::SystemRandom. This exists so that tests can produce
+    /// reproducible signatures and must not be used otherwise. Note that the
+    /// Avro encoding of the header still contains a random sync marker.
+    pub fn set_rng(&mut self, rng: &'a dyn SecureRandom) {
+        self.rng = Some(rng);
+    }
+
+    /// Sets how uploading each file is retried when it fails with a transient
+    /// error. Each attempt puts the file anew after cancelling the upload that
+    /// failed, so that a retried file never contains anything an earlier
+    /// attempt wrote. Since the operation provided to packet_file_writer can
+    /// only be run once, packet files are then spooled (see set_spool_threshold)
+    /// and uploaded from the spool, rather than streamed into the transport.
+    /// Defaults to RetryPolicy::NONE.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Puts the value of the provided key and writes it with the provided
+    /// function, retrying with a fresh upload according to the retry policy.
+    fn upload(
+        &mut self,
+        key: &str,
+        mut content: impl FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        let retry_policy = self.retry_policy;
+        retry_policy.retry(|| {
+            let mut writer = self.transport.put(key)?;
+            if let Err(e) = content(&mut writer) {
+                return Err(match writer.cancel_upload() {
+                    Ok(()) => e,
+                    Err(cancel_error) => e.context(format!(
+                        "failed to cancel upload of {}: {:#}",
+                        key, cancel_error
+                    )),
+                });
+            }
+            writer
+                .complete_upload()
+                .with_context(|| format!("failed to complete upload of {}", key))
+        })
+    }
+
+    /// Encode the provided header into Avro, sign that representation with the
+    /// provided key and write the header into the batch. Returns the signature
+    /// on success.
+    pub fn put_header(&mut self, header: &H, key: &EcdsaKeyPair) -> Result<Signature> {
+        // The header is encoded into a SpooledBuffer before it is uploaded, so
+        // that a retried upload sends exactly the bytes that get signed. It is
+        // digested for the manifest as it is encoded and uploaded from the
+        // spool.
+        let mut sidecar_writer = SidecarWriter::new(
+            SpooledBuffer::new(self.spool_threshold),
+            DigestWriter::new(),
+        );
+        header.write(&mut sidecar_writer)?;
+        let header_size = sidecar_writer.bytes_written();
+        let header_digest = sidecar_writer.sidecar.finish();
+        let mut spool = sidecar_writer.writer;
+        let header_key = self.batch.header_key().to_owned();
+        self.upload(&header_key, |writer| {
+            std::io::copy(&mut spool.reader()?, writer).context("failed to write batch header")?;
+            Ok(())
+        })?;
+        self.record_written_file(ManifestFile::new(&header_key, &header_digest, header_size));
+
+        // ring only signs messages it is given whole. Headers are small enough
+        // that they stay in memory unless the spool threshold is tiny, so the
+        // spool is only read back into memory to sign it in that case.
+        let spooled_header;
+        let header_bytes = match spool.as_slice() {
+            Some(header_bytes) => header_bytes,
+            None => {
+                let mut header_bytes = Vec::new();
+                spool
+                    .reader()?
+                    .read_to_end(&mut header_bytes)
+                    .context("failed to read back spooled header")?;
+                spooled_header = header_bytes;
+                &spooled_header
+            }
+        };
+        let system_random = SystemRandom::new();
+        let header_signature = key
+            .sign(self.rng.unwrap_or(&system_random), header_bytes)
+            .context("failed to sign header file")?;
+        Ok(header_signature)
+    }
+
+    /// Creates an avro_rs::Writer and provides it to the caller-provided
+    /// function, which may then write arbitrarily many packets into it. The
+    /// Avro encoding of the packet will be digested while they are written, so
+    /// the packet file is never held in memory, no matter its size.
+    /// The operation should return Ok(()) when it has finished successfully or
+    /// some Err() otherwise. packet_file_writer returns the digest of all the
+    /// content written by the operation.
+    pub fn packet_file_writer<F>(&mut self, operation: F) -> Result<Sha256Digest>
+    where
+        F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
+    {
+        let key = self.batch.packet_file_key().to_owned();
+        self.write_packet_file(&key, operation)
+    }
+
+    /// Like packet_file_writer, but writes the packet file shard with the
+    /// provided index. The digests of all the shards must be listed in order
+    /// in the header's packet_file_shard_digests.
+    pub fn packet_file_shard_writer<F>(
+        &mut self,
+        index: usize,
+        operation: F,
+    ) -> Result<Sha256Digest>
+    where
+        F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
+    {
+        let key = self.batch.packet_file_shard_key(index);
+        self.write_packet_file(&key, operation)
+    }
+
+    fn write_packet_file<F>(&mut self, key: &str, operation: F) -> Result<Sha256Digest>
+    where
+        F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
+    {
+        // Without retries the packet file is streamed straight into the
+        // transport. With them it is spooled, and uploaded from the spool once
+        // the operation has written all of it.
+        let spool = if self.retry_policy.retries() {
+            Some(Rc::new(RefCell::new(SpooledBuffer::new(
+                self.spool_threshold,
+            ))))
+        } else {
+            None
+        };
+        let file_writer: Box<dyn TransportWriter> = match &spool {
+            Some(spool) => Box::new(SpoolWriter(Rc::clone(spool))),
+            None => self.transport.put(key)?,
+        };
+        let mut writer = Writer::new(
+            &self.packet_schema,
+            SidecarWriter::new(file_writer, DigestWriter::new()),
+        );
+
+        let result = operation(&mut writer);
+        let mut sidecar_writer = writer
+            .into_inner()
+            .with_context(|| format!("failed to flush Avro writer ({:?})", result))?;
+
+        // avro_rs only writes the object container header along with the
+        // first record, so if the operation wrote no packets we would be left
+        // with an empty file that Avro readers reject. Write a header with no
+        // data blocks so that the packet file is always well formed.
+        if result.is_ok() && sidecar_writer.bytes_written() == 0 {
+            sidecar_writer
+                .write_all(&empty_object_container(&self.packet_schema))
+                .context("failed to write empty packet file")?;
+        }
+
+        if let Err(e) = result {
+            sidecar_writer
+                .writer
+                .cancel_upload()
+                .with_context(|| format!("Encountered while handling: {}", e))?;
+            return Err(e);
+        }
+
+        sidecar_writer
+            .writer
+            .complete_upload()
+            .context("failed to complete packet file upload")?;
+        let size = sidecar_writer.bytes_written();
+        let digest = sidecar_writer.sidecar.finish();
+        if let Some(spool) = spool {
+            let mut spool = spool.borrow_mut();
+            self.upload(key, |writer| {
+                std::io::copy(&mut spool.reader()?, writer)
+                    .context("failed to write packet file")?;
+                Ok(())
+            })?;
+        }
+        self.record_written_file(ManifestFile::new(key, &digest, size));
+        Ok(digest)
+    }
+
+    /// Constructs a signature structure from the provided buffers and writes it
+    /// to the batch's signature file
+    pub fn put_signature(&mut self, signature: &Signature) -> Result<()> {
+        let signature_key = self.batch.signature_key().to_owned();
+        self.upload(&signature_key, |writer| {
+            writer
+                .write_all(signature.as_ref())
+                .context("failed to write signature")
+        })?;
+        self.record_written_file(ManifestFile::new(
+            &signature_key,
+            &hash::sha256(signature.as_ref()),
+            signature.as_ref().len() as u64,
+        ));
+        Ok(())
+    }
+
+    /// Writes a manifest of the files this BatchWriter has written, which must
+    /// include the header and signature, to the batch's manifest key as JSON,
+    /// along with a signature over it made with the provided key. Returns the
+    /// manifest. packet_count is the number of packets the caller wrote to
+    /// the packet file or shards.
+    pub fn put_manifest(&mut self, packet_count: u64, key: &EcdsaKeyPair) -> Result<BatchManifest> {
+        for required in &[self.batch.header_key(), self.batch.signature_key()] {
+            if !self.written_files.iter().any(|file| file.key == *required) {
+                return Err(anyhow!(
+                    "cannot write manifest before {} has been written",
+                    required
+                ));
+            }
+        }
+        let manifest = BatchManifest {
+            schema_version: SCHEMA_VERSION.to_owned(),
+            packet_count,
+            files: self.written_files.clone(),
+        };
+        let manifest_bytes =
+            serde_json::to_vec_pretty(&manifest).context("failed to encode manifest")?;
+        let system_random = SystemRandom::new();
+        let manifest_signature = key
+            .sign(self.rng.unwrap_or(&system_random), &manifest_bytes)
+            .context("failed to sign manifest")?;
+
+        for (key, content) in &[
+            (self.batch.manifest_key(), manifest_bytes.as_slice()),
+            (
+                self.batch.manifest_signature_key(),
+                manifest_signature.as_ref(),
+            ),
+        ] {
+            self.upload(key, |writer| {
+                writer
+                    .write_all(content)
+                    .with_context(|| format!("failed to write {}", key))
+            })?;
+            self.record_written_key(key);
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the provided record of packets that failed validation to the
+    /// batch's packet failures key as JSON. The file is listed in any manifest
+    /// written afterward.
+    pub fn put_packet_failures(&mut self, failures: &[PacketFailure]) -> Result<()> {
+        // Like packet files, the failures are digested as they are streamed
+        // into the transport rather than encoded in memory first. A retried
+        // upload encodes them again.
+        let key = self.batch.packet_failures_key();
+        let mut written = None;
+        self.upload(&key, |writer| {
+            let mut sidecar_writer = SidecarWriter::new(writer, DigestWriter::new());
+            let mut buffered_writer = BufWriter::new(&mut sidecar_writer);
+            serde_json::to_writer_pretty(&mut buffered_writer, failures)
+                .context("failed to encode packet failures")?;
+            buffered_writer
+                .flush()
+                .with_context(|| format!("failed to write {}", key))?;
+            drop(buffered_writer);
+            let size = sidecar_writer.bytes_written();
+            written = Some((sidecar_writer.sidecar.finish(), size));
+            Ok(())
+        })?;
+        let (digest, size) = written.unwrap();
+        self.record_written_file(ManifestFile::new(&key, &digest, size));
+        Ok(())
+    }
+
+    /// Records a file written to the batch for the manifest, replacing any
+    /// earlier record of the same key.
+    fn record_written_file(&mut self, file: ManifestFile) {
+        self.record_written_key(&file.key);
+        self.written_files.retain(|written| written.key != file.key);
+        self.written_files.push(file);
+    }
+
+    fn record_written_key(&mut self, key: &str) {
+        if !self.written_keys.iter().any(|written| written == key) {
+            self.written_keys.push(key.to_owned());
+        }
+    }
+
+    /// Attempts to delete every file this BatchWriter has written, so that a
+    /// failure partway through writing a batch does not leave behind, say, a
+    /// header without its signature. Returns the provided error, with context
+    /// listing the files that were deleted and any that could not be. The
+    /// error is returned unchanged if nothing had been written.
+    pub fn roll_back(&mut self, error: anyhow::Error) -> anyhow::Error {
+        if self.written_keys.is_empty() {
+            return error;
+        }
+        // Delete in the reverse of the order the files were written, so that
+        // a signature or manifest never outlives the files it covers.
+        let mut deleted = Vec::new();
+        let mut undeleted = Vec::new();
+        for key in self.written_keys.drain(..).rev() {
+            match self.transport.delete(&key) {
+                Ok(()) => deleted.push(key),
+                Err(e) => undeleted.push(format!("{} ({:#})", key, e)),
+            }
+        }
+        self.written_files.clear();
+        if undeleted.is_empty() {
+            error.context(format!("deleted partially written {}", deleted.join(", ")))
+        } else {
+            error.context(format!(
+                "deleted partially written {}, but failed to delete {}",
+                deleted.join(", "),
+                undeleted.join(", ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        idl::{IngestionDataSharePacket, IngestionHeader},
+        test_utils::{
+            default_facilitator_signing_public_key, default_ingestor_private_key,
+            default_ingestor_public_key,
+        },
+        transport::{EncryptingTransport, LocalFileTransport, MemoryTransport},
+        Error,
+    };
+    use avro_rs::{types::Record, Codec};
+    use chrono::NaiveDate;
+    use ring::{digest, test::rand::FixedByteRandom};
+
+    fn roundtrip_batch<'a>(
+        aggregation_name: String,
+        batch_id: Uuid,
+        base_path: String,
+        filenames: &[String],
+        batch_writer: &mut BatchWriter<'a, IngestionHeader, IngestionDataSharePacket>,
+        batch_reader: &BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
+        transport: &mut LocalFileTransport,
+        write_key: &EcdsaKeyPair,
+        read_key: &UnparsedPublicKey<Vec<u8>>,
+        keys_match: bool,
+    ) {
+        // These tests cheat by using IngestionHeader and
+        // IngestionDataSharePacket regardless of what kind of batch it is, but
+        // since BatchReader and BatchWriter are generic in H and P, this
+        // exercises what we care about in this module while saving us a bit of
+        // typing.
+        let packets = &[
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![0u8, 1u8, 2u8, 3u8],
+                encryption_key_id: "fake-key-1".to_owned(),
+                r_pit: 1,
+                version_configuration: Some("config-1".to_owned()),
+                device_nonce: None,
+                sequence_number: None,
+            },
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![4u8, 5u8, 6u8, 7u8],
+                encryption_key_id: "fake-key-2".to_owned(),
+                r_pit: 2,
+                version_configuration: None,
+                device_nonce: Some(vec![8u8, 9u8, 10u8, 11u8]),
+                sequence_number: None,
+            },
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![8u8, 9u8, 10u8, 11u8],
+                encryption_key_id: "fake-key-3".to_owned(),
+                r_pit: 3,
+                version_configuration: None,
+                device_nonce: None,
+                sequence_number: None,
+            },
+        ];
+
+        let packet_file_digest = batch_writer
+            .packet_file_writer(|mut packet_writer| {
+                packets[0].write(&mut packet_writer)?;
+                packets[1].write(&mut packet_writer)?;
+                packets[2].write(&mut packet_writer)?;
+                Ok(())
+            })
+            .expect("failed to write packets");
+
+        let header = IngestionHeader {
+            batch_uuid: batch_id,
+            name: aggregation_name.to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+
+        let header_signature = batch_writer
+            .put_header(&header, write_key)
+            .expect("failed to write header");
+
+        let res = batch_writer.put_signature(&header_signature);
+        assert!(res.is_ok(), "failed to put signature: {:?}", res.err());
+
+        // Verify file layout is as expected
+        for extension in filenames {
+            transport
+                .get(&format!("{}.{}", base_path, extension))
+                .expect(&format!("could not get batch file {}", extension));
         }
 
         let header_again = batch_reader.header(read_key);
@@ -367,70 +2612,1407 @@ mod tests {
                 header_again.is_err(),
                 "read should fail with mismatched keys"
             );
-            return;
+            return;
+        }
+        assert!(
+            header_again.is_ok(),
+            "failed to read header from batch {:?}",
+            header_again.err()
+        );
+        let header_again = header_again.unwrap();
+
+        assert_eq!(header, header_again, "header does not match");
+
+        let mut packet_file_reader = batch_reader
+            .packet_file_reader(&header_again)
+            .expect("failed to get packet file reader");
+
+        for packet in packets {
+            let packet_again = IngestionDataSharePacket::read(&mut packet_file_reader)
+                .expect("failed to read packet");
+            assert_eq!(packet, &packet_again, "packet does not match");
+        }
+
+        // One more read should get EOF
+        match IngestionDataSharePacket::read(&mut packet_file_reader) {
+            Err(Error::EofError) => (),
+            v => assert!(false, "wrong error {:?}", v),
+        }
+    }
+
+    #[test]
+    fn packet_file_schema_mismatch() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+
+        // Each case rewrites the r_pit field of the packet schema.
+        let cases: &[(&str, Value, bool)] = &[
+            // Widening int to long is a legal schema evolution.
+            (r#"{"name": "r_pit", "type": "int"}"#, Value::Int(1), true),
+            (
+                r#"{"name": "r_pit", "type": "string"}"#,
+                Value::String("1".to_owned()),
+                false,
+            ),
+            (
+                r#"{"name": "random_pit", "type": "long"}"#,
+                Value::Long(1),
+                false,
+            ),
+        ];
+        for (r_pit_field, r_pit, compatible) in cases {
+            let mut schema_json: serde_json::Value =
+                serde_json::from_str(IngestionDataSharePacket::schema_raw()).unwrap();
+            let r_pit_field: serde_json::Value = serde_json::from_str(r_pit_field).unwrap();
+            let r_pit_field_name = r_pit_field["name"].as_str().unwrap().to_owned();
+            for field in schema_json["fields"].as_array_mut().unwrap() {
+                if field["name"] == "r_pit" {
+                    *field = r_pit_field.clone();
+                }
+            }
+            let writer_schema = Schema::parse_str(&schema_json.to_string()).unwrap();
+
+            let mut record = Record::new(&writer_schema).unwrap();
+            record.put("uuid", Value::Uuid(Uuid::new_v4()));
+            record.put("encrypted_payload", Value::Bytes(vec![0u8, 1u8]));
+            record.put("encryption_key_id", Value::String("fake-key-1".to_owned()));
+            record.put(&r_pit_field_name, r_pit.clone());
+            record.put("version_configuration", Value::Union(Box::new(Value::Null)));
+            record.put("device_nonce", Value::Union(Box::new(Value::Null)));
+            record.put("sequence_number", Value::Union(Box::new(Value::Null)));
+            let mut writer = Writer::new(&writer_schema, Vec::new());
+            writer.append(record).unwrap();
+            let packet_file = writer.into_inner().unwrap();
+
+            let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+            let mut packet_file_writer = transport.put(batch.packet_file_key()).unwrap();
+            packet_file_writer.write_all(&packet_file).unwrap();
+            packet_file_writer.complete_upload().unwrap();
+
+            let header = IngestionHeader {
+                batch_uuid: Uuid::new_v4(),
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: digest::digest(&digest::SHA256, &packet_file)
+                    .as_ref()
+                    .to_vec(),
+                packet_file_shard_digests: vec![],
+                packet_count: None,
+            };
+            let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch, &mut transport);
+            let result = batch_reader.packet_file_reader(&header);
+            if *compatible {
+                let packet = IngestionDataSharePacket::read(&mut result.unwrap()).unwrap();
+                assert_eq!(packet.r_pit, 1);
+                continue;
+            }
+            let err = result.err().unwrap_or_else(|| {
+                panic!(
+                    "incompatible schema {} accepted",
+                    writer_schema.canonical_form()
+                )
+            });
+            match err.downcast_ref::<Error>() {
+                Some(Error::AvroError(message, _)) => {
+                    assert!(message.contains("schema mismatch"), "{}", message)
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn packet_file_schema_evolution() {
+        let mut transport = MemoryTransport::new();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+
+        // Older readers skip an optional field that a newer writer added, and
+        // newer readers default an optional field that an older writer lacks.
+        let extra_field = r#"{"name": "client_hint", "type": ["null", "string"], "default": null}"#;
+        let cases = [
+            (Some(extra_field), "", Some(vec![3u8])),
+            (None, "device_nonce", None),
+            (None, "version_configuration", Some(vec![3u8])),
+            (None, "sequence_number", Some(vec![3u8])),
+        ];
+        for (added_field, removed_field, device_nonce) in &cases {
+            let mut schema_json: serde_json::Value =
+                serde_json::from_str(IngestionDataSharePacket::schema_raw()).unwrap();
+            let fields = schema_json["fields"].as_array_mut().unwrap();
+            fields.retain(|field| field["name"] != *removed_field);
+            fields.extend(added_field.map(|field| serde_json::from_str(field).unwrap()));
+            let writer_schema = Schema::parse_str(&schema_json.to_string()).unwrap();
+
+            let uuid = Uuid::new_v4();
+            let mut record = Record::new(&writer_schema).unwrap();
+            record.put("uuid", Value::Uuid(uuid));
+            record.put("encrypted_payload", Value::Bytes(vec![0u8, 1u8]));
+            record.put("encryption_key_id", Value::String("fake-key-1".to_owned()));
+            record.put("r_pit", Value::Long(1));
+            record.put(
+                "version_configuration",
+                Value::Union(Box::new(Value::String("config-1".to_owned()))),
+            );
+            record.put(
+                "device_nonce",
+                Value::Union(Box::new(
+                    device_nonce.clone().map_or(Value::Null, Value::Bytes),
+                )),
+            );
+            record.put("sequence_number", Value::Union(Box::new(Value::Null)));
+            record.put(
+                "client_hint",
+                Value::Union(Box::new(Value::String("hint".to_owned()))),
+            );
+            let mut writer = Writer::new(&writer_schema, Vec::new());
+            writer.append(record).unwrap();
+            let packet_file = writer.into_inner().unwrap();
+
+            let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+            let mut packet_file_writer = transport.put(batch.packet_file_key()).unwrap();
+            packet_file_writer.write_all(&packet_file).unwrap();
+            packet_file_writer.complete_upload().unwrap();
+
+            let header = IngestionHeader {
+                batch_uuid: Uuid::new_v4(),
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: digest::digest(&digest::SHA256, &packet_file)
+                    .as_ref()
+                    .to_vec(),
+                packet_file_shard_digests: vec![],
+                packet_count: None,
+            };
+            let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch, &mut transport);
+            let mut packet_file_reader = batch_reader.packet_file_reader(&header).unwrap();
+            let packet = IngestionDataSharePacket::read(&mut packet_file_reader).unwrap();
+            assert_eq!(
+                packet,
+                IngestionDataSharePacket {
+                    uuid,
+                    encrypted_payload: vec![0u8, 1u8],
+                    encryption_key_id: "fake-key-1".to_owned(),
+                    r_pit: 1,
+                    version_configuration: if *removed_field == "version_configuration" {
+                        None
+                    } else {
+                        Some("config-1".to_owned())
+                    },
+                    device_nonce: device_nonce.clone(),
+                    sequence_number: None,
+                },
+                "schema {}",
+                writer_schema.canonical_form()
+            );
+            assert!(matches!(
+                IngestionDataSharePacket::read(&mut packet_file_reader),
+                Err(Error::EofError)
+            ));
+        }
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let key = default_ingestor_private_key();
+        let rng = FixedByteRandom { byte: 42 };
+        let signature = key.sign(&rng, b"message").unwrap();
+        verify_signature(
+            &default_ingestor_public_key(),
+            b"message",
+            signature.as_ref(),
+        )
+        .unwrap();
+
+        let mut flipped = signature.as_ref().to_vec();
+        flipped[0] ^= 1;
+        type Case<'c> = (&'c str, &'c UnparsedPublicKey<Vec<u8>>, &'c [u8], &'c [u8]);
+        let cases: &[Case<'_>] = &[
+            (
+                "other message",
+                &default_ingestor_public_key(),
+                b"other message",
+                signature.as_ref(),
+            ),
+            (
+                "flipped bit",
+                &default_ingestor_public_key(),
+                b"message",
+                &flipped,
+            ),
+            (
+                "truncated",
+                &default_ingestor_public_key(),
+                b"message",
+                &signature.as_ref()[1..],
+            ),
+            ("empty", &default_ingestor_public_key(), b"message", b""),
+            (
+                "other key",
+                &default_facilitator_signing_public_key(),
+                b"message",
+                signature.as_ref(),
+            ),
+        ];
+        for (name, public_key, message, signature) in cases {
+            match verify_signature(public_key, message, signature) {
+                Err(Error::CryptographyError(message)) => {
+                    assert_eq!(message, "signature does not verify", "{}", name)
+                }
+                v => panic!("unexpected result for {}: {:?}", name, v),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_packet_file() {
+        let mut transport = MemoryTransport::new();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let schema = IngestionDataSharePacket::schema();
+
+        // Write each packet in its own Avro block, so that a packet in the
+        // middle of the file can be cut short.
+        let mut writer = Writer::new(&schema, Vec::new());
+        let mut last_block_length = 0;
+        for r_pit in 0..5 {
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![r_pit as u8; 64],
+                encryption_key_id: "fake-key-1".to_owned(),
+                r_pit,
+                version_configuration: None,
+                device_nonce: None,
+                sequence_number: None,
+            }
+            .write(&mut writer)
+            .unwrap();
+            last_block_length = writer.flush().unwrap();
+        }
+        let mut packet_file = writer.into_inner().unwrap();
+        // Drop the last block and the end of the one before it
+        let truncated_length = packet_file.len() - last_block_length - 20;
+        packet_file.truncate(truncated_length);
+
+        let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+        let packet_file_key = batch.packet_file_key().to_owned();
+        let mut packet_file_writer = transport.put(&packet_file_key).unwrap();
+        packet_file_writer.write_all(&packet_file).unwrap();
+        packet_file_writer.complete_upload().unwrap();
+        let header = IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: digest::digest(&digest::SHA256, &packet_file)
+                .as_ref()
+                .to_vec(),
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+
+        let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch, &mut transport);
+        let mut packet_reader = batch_reader.sharded_packet_reader(&header).unwrap();
+        for r_pit in 0..3 {
+            assert_eq!(packet_reader.read_packet().unwrap().r_pit, r_pit);
+        }
+        match packet_reader.read_packet() {
+            Err(Error::AvroError(message, _)) => {
+                assert_eq!(
+                    message,
+                    format!(
+                        "packet 3 of {}, near byte {}: failed to read record from Avro reader",
+                        packet_file_key, truncated_length
+                    )
+                );
+            }
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+
+    #[test]
+    fn compressed_packet_files() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let schema = IngestionDataSharePacket::schema();
+
+        for codec in &[Codec::Deflate, Codec::Snappy, Codec::Null] {
+            let mut writer = Writer::with_codec(&schema, Vec::new(), *codec);
+            for r_pit in 0..100 {
+                IngestionDataSharePacket {
+                    uuid: Uuid::new_v4(),
+                    encrypted_payload: vec![r_pit as u8; 64],
+                    encryption_key_id: "fake-key-1".to_owned(),
+                    r_pit,
+                    version_configuration: None,
+                    device_nonce: None,
+                    sequence_number: Some(r_pit),
+                }
+                .write(&mut writer)
+                .unwrap();
+                // Several blocks, each compressed on its own
+                if r_pit % 30 == 29 {
+                    writer.flush().unwrap();
+                }
+            }
+            let packet_file = writer.into_inner().unwrap();
+
+            let mut transport = MemoryTransport::new();
+            let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+            let mut packet_file_writer = transport.put(batch.packet_file_key()).unwrap();
+            packet_file_writer.write_all(&packet_file).unwrap();
+            packet_file_writer.complete_upload().unwrap();
+            let header = IngestionHeader {
+                batch_uuid: Uuid::new_v4(),
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: digest::digest(&digest::SHA256, &packet_file)
+                    .as_ref()
+                    .to_vec(),
+                packet_file_shard_digests: vec![],
+                packet_count: None,
+            };
+
+            let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch, &mut transport);
+            let mut packet_reader = batch_reader.sharded_packet_reader(&header).unwrap();
+            for r_pit in 0..100 {
+                let packet = packet_reader.read_packet().unwrap();
+                assert_eq!(packet.r_pit, r_pit, "codec {:?}", codec);
+                assert_eq!(packet.encrypted_payload, vec![r_pit as u8; 64]);
+            }
+            assert!(matches!(packet_reader.read_packet(), Err(Error::EofError)));
+        }
+
+        // A codec we can't decompress is named rather than misread.
+        let mut writer = Writer::with_codec(&schema, Vec::new(), Codec::Deflate);
+        IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![0u8; 64],
+            encryption_key_id: "fake-key-1".to_owned(),
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            sequence_number: None,
+        }
+        .write(&mut writer)
+        .unwrap();
+        let mut packet_file = writer.into_inner().unwrap();
+        let codec_position = packet_file
+            .windows(7)
+            .position(|window| window == b"deflate")
+            .unwrap();
+        packet_file[codec_position..codec_position + 7].copy_from_slice(b"brotli7");
+        match resolvable_container(packet_file.as_slice()) {
+            Err(Error::MalformedDataPacketError(message)) => {
+                assert_eq!(message, "unsupported Avro codec brotli7")
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("unsupported codec accepted"),
+        }
+    }
+
+    #[test]
+    fn empty_packet_files() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let schema = IngestionDataSharePacket::schema();
+        let packet = |r_pit| IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![r_pit as u8; 64],
+            encryption_key_id: "fake-key-1".to_owned(),
+            r_pit,
+            version_configuration: None,
+            device_nonce: None,
+            sequence_number: None,
+        };
+
+        // Two packets, each in its own block, with a block claiming to hold no
+        // records between them. The sync marker ends every block.
+        let mut writer = Writer::new(&schema, Vec::new());
+        packet(0).write(&mut writer).unwrap();
+        let second_block_length = writer.flush().unwrap();
+        packet(1).write(&mut writer).unwrap();
+        writer.flush().unwrap();
+        let complete = writer.into_inner().unwrap();
+        let marker = complete[complete.len() - 16..].to_vec();
+        let first_block_end = complete.len() - second_block_length;
+        let mut with_empty_block = complete[..first_block_end].to_vec();
+        with_empty_block.extend_from_slice(&[0, 0]);
+        with_empty_block.extend_from_slice(&marker);
+        with_empty_block.extend_from_slice(&complete[first_block_end..]);
+
+        let read_packets = |packet_file: &[u8]| {
+            let mut transport = MemoryTransport::new();
+            let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+            let mut packet_file_writer = transport.put(batch.packet_file_key()).unwrap();
+            packet_file_writer.write_all(packet_file).unwrap();
+            packet_file_writer.complete_upload().unwrap();
+            let header = IngestionHeader {
+                batch_uuid: Uuid::new_v4(),
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: digest::digest(&digest::SHA256, packet_file)
+                    .as_ref()
+                    .to_vec(),
+                packet_file_shard_digests: vec![],
+                packet_count: None,
+            };
+            let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch, &mut transport);
+            let mut packet_reader = batch_reader.sharded_packet_reader(&header).unwrap();
+            let mut r_pits = Vec::new();
+            loop {
+                match packet_reader.read_packet() {
+                    Ok(packet) => r_pits.push(packet.r_pit),
+                    Err(Error::EofError) => return Ok(r_pits),
+                    Err(e) => return Err((r_pits, e)),
+                }
+            }
+        };
+
+        assert_eq!(read_packets(&complete).unwrap(), vec![0, 1]);
+        // A container with no blocks holds no packets.
+        assert_eq!(
+            read_packets(&empty_object_container(&schema)).unwrap(),
+            Vec::<i64>::new()
+        );
+        // An empty file is not a container at all.
+        match read_packets(b"") {
+            Err((r_pits, Error::AnyhowError(e))) => {
+                assert!(r_pits.is_empty());
+                assert!(
+                    matches!(e.downcast_ref(), Some(Error::MalformedDataPacketError(_))),
+                    "{:?}",
+                    e
+                );
+            }
+            v => panic!("unexpected result {:?}", v),
+        }
+        // avro_rs stops at the empty block, without reading the rest.
+        match read_packets(&with_empty_block) {
+            Err((r_pits, Error::MalformedDataPacketError(message))) => {
+                assert_eq!(r_pits, vec![0]);
+                assert!(
+                    message.ends_with("packet file continues after the end of its records"),
+                    "{}",
+                    message
+                );
+            }
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+
+    #[test]
+    fn encrypted_batch() {
+        let memory_transport = MemoryTransport::new();
+        let mut write_transport =
+            EncryptingTransport::new(memory_transport.clone(), &[7; 32]).unwrap();
+        let mut read_transport =
+            EncryptingTransport::new(memory_transport.clone(), &[7; 32]).unwrap();
+        let mut unencrypted_transport = memory_transport.clone();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let batch_id = Uuid::new_v4();
+        let packets: Vec<_> = (0..3)
+            .map(|r_pit| IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![r_pit as u8; 64],
+                encryption_key_id: "fake-key-1".to_owned(),
+                r_pit,
+                version_configuration: None,
+                device_nonce: None,
+                sequence_number: None,
+            })
+            .collect();
+
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+                &mut write_transport,
+            );
+        let packet_file_digest = batch_writer
+            .packet_file_writer(|packet_writer| {
+                for packet in &packets {
+                    packet.write(packet_writer)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let header = IngestionHeader {
+            batch_uuid: batch_id,
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            packet_file_shard_digests: vec![],
+            packet_count: Some(3),
+        };
+        let signature = batch_writer
+            .put_header(&header, &default_ingestor_private_key())
+            .unwrap();
+        batch_writer.put_signature(&signature).unwrap();
+
+        // The digest and signature are over the plaintext, so the batch reads
+        // back through an EncryptingTransport with the same key...
+        let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+                &mut read_transport,
+            );
+        let read_header = batch_reader.header(&default_ingestor_public_key()).unwrap();
+        assert_eq!(read_header, header);
+        let mut packet_reader = batch_reader.sharded_packet_reader(&read_header).unwrap();
+        for packet in &packets {
+            assert_eq!(&packet_reader.read_packet().unwrap(), packet);
+        }
+        assert!(matches!(packet_reader.read_packet(), Err(Error::EofError)));
+
+        // ...but not without it
+        let unencrypted_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+                &mut unencrypted_transport,
+            );
+        assert!(unencrypted_reader
+            .header(&default_ingestor_public_key())
+            .is_err());
+    }
+
+    /// Writes placeholder content for the files of the provided batch that
+    /// are listed in kinds.
+    fn put_batch_files(transport: &mut dyn Transport, batch: &Batch, kinds: &[BatchFileKind]) {
+        for kind in kinds {
+            let mut writer = transport.put(batch.key(*kind)).unwrap();
+            writer.write_all(b"fake-content").unwrap();
+            writer.complete_upload().unwrap();
+        }
+    }
+
+    fn check_enumerate_range(
+        naming_scheme: &DefaultBatchNamingScheme,
+        instance_name: Option<&InstanceName>,
+    ) {
+        let mut transport = MemoryTransport::new();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let other_aggregation_name = AggregationName::new("other-aggregation").unwrap();
+        let identity = |date: &str, batch_id: &str| {
+            BatchIdentity::new(
+                aggregation_name.clone(),
+                BatchDate::from_str(date).unwrap(),
+                Uuid::parse_str(batch_id).unwrap(),
+            )
+        };
+
+        // Batches spread over a month boundary, with no batches at all on
+        // 2020/02/02.
+        let expected = vec![
+            identity("2020/01/31/23/59", "7ca2e51a-6b86-4144-9d86-2f2d69a0e0d2"),
+            identity("2020/02/01/00/00", "1c0ae329-f2b4-4d2e-8be5-6f3cb32ec8a0"),
+            identity("2020/02/01/00/00", "e3cbd97b-1e5b-43b8-a87d-5a2fbd1c7e87"),
+            identity("2020/02/03/11/59", "04f9e2c8-4a4e-4d63-99a5-466d2a3b1cf8"),
+        ];
+        // Write them out of order to check sorting.
+        for identity in expected.iter().rev() {
+            put_batch_files(
+                &mut transport,
+                &identity.ingestion_batch(naming_scheme, instance_name),
+                &BatchFileKind::ALL,
+            );
+        }
+
+        // A sharded batch is complete without a packet file.
+        let sharded = identity("2020/02/01/12/00", "5b9a3b1e-63c1-4cc6-b0a7-8d4bcaf6a5a9");
+        let sharded_batch = sharded
+            .ingestion_batch(naming_scheme, instance_name)
+            .with_packet_file_shards(1);
+        put_batch_files(
+            &mut transport,
+            &sharded_batch,
+            &[BatchFileKind::Header, BatchFileKind::Signature],
+        );
+        let mut writer = transport
+            .put(&sharded_batch.packet_file_shard_keys()[0])
+            .unwrap();
+        writer.complete_upload().unwrap();
+
+        // None of these should be returned.
+        for (identity, kinds) in &[
+            // Just before the start of the range
+            (
+                identity("2020/01/31/23/58", "2d2ad1c5-5c53-4d8d-a1d2-2e4ef5ab66f4"),
+                &BatchFileKind::ALL[..],
+            ),
+            // At the end of the range, which is exclusive
+            (
+                identity("2020/02/03/12/00", "9e5ad9d1-0a5b-4b9b-9d0e-0d1c2d33e3c1"),
+                &BatchFileKind::ALL[..],
+            ),
+            // Incomplete batches
+            (
+                identity("2020/02/01/06/00", "a8c3e1de-2bd1-4e4e-8c63-1c1b0a3f1e5f"),
+                &[BatchFileKind::Header, BatchFileKind::Packets][..],
+            ),
+            (
+                identity("2020/02/01/06/00", "c3f1b6d8-86a9-4c0e-b5b5-3b0a6c0e9bf2"),
+                &[BatchFileKind::Packets, BatchFileKind::Signature][..],
+            ),
+        ] {
+            put_batch_files(
+                &mut transport,
+                &identity.ingestion_batch(naming_scheme, instance_name),
+                kinds,
+            );
+        }
+        // A validation batch, and an ingestion batch in another aggregation
+        let in_range = identity("2020/02/01/06/00", "f4e5f2a1-3a5d-47d6-b8f4-2f8d1d6b6f6e");
+        put_batch_files(
+            &mut transport,
+            &in_range.validation_batch(naming_scheme, instance_name, ServerIdentity::Pha),
+            &BatchFileKind::ALL,
+        );
+        put_batch_files(
+            &mut transport,
+            &BatchIdentity::new(other_aggregation_name, in_range.date, in_range.batch_id)
+                .ingestion_batch(naming_scheme, instance_name),
+            &BatchFileKind::ALL,
+        );
+
+        let mut expected = expected;
+        expected.insert(3, sharded);
+        let start = BatchDate::from_str("2020/01/31/23/59").unwrap();
+        let end = BatchDate::from_str("2020/02/03/12/00").unwrap();
+        assert_eq!(
+            naming_scheme
+                .enumerate_range(&transport, instance_name, &aggregation_name, &start, &end)
+                .unwrap(),
+            expected
+        );
+
+        // Empty ranges and ranges over empty partitions yield nothing.
+        assert!(naming_scheme
+            .enumerate_range(&transport, instance_name, &aggregation_name, &end, &start)
+            .unwrap()
+            .is_empty());
+        assert!(naming_scheme
+            .enumerate_range(
+                &transport,
+                instance_name,
+                &aggregation_name,
+                &BatchDate::from_str("2020/02/02/00/00").unwrap(),
+                &BatchDate::from_str("2020/02/03/00/00").unwrap(),
+            )
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn enumerate_range() {
+        check_enumerate_range(&DefaultBatchNamingScheme::default(), None);
+    }
+
+    #[test]
+    fn enumerate_range_date_partitioned() {
+        check_enumerate_range(
+            &DefaultBatchNamingScheme::default().with_path_layout(PathLayout::DatePartitioned),
+            Some(&InstanceName::new("fake-instance").unwrap()),
+        );
+    }
+
+    #[test]
+    fn enumerate_range_default_naming_scheme() {
+        let mut transport = MemoryTransport::new();
+        let identity = BatchIdentity::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::from_str("2020/12/31/23/59").unwrap(),
+            Uuid::new_v4(),
+        );
+        put_batch_files(
+            &mut transport,
+            &identity.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+            &BatchFileKind::ALL,
+        );
+        assert_eq!(
+            Batch::enumerate_range(
+                &transport,
+                &identity.aggregation_name,
+                &BatchDate::from_str("2020/12/31/00/00").unwrap(),
+                &BatchDate::from_str("2021/01/01/00/00").unwrap(),
+            )
+            .unwrap(),
+            vec![identity]
+        );
+    }
+
+    #[test]
+    fn batch_attempts() {
+        let descriptor = BatchDescriptor::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::from_str("2020/10/31/20/29").unwrap(),
+            Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            BatchKind::Validation(ServerIdentity::Pha),
+        );
+        let first = Batch::from(&descriptor);
+        assert!(first
+            .keys()
+            .eq(Batch::from(&descriptor).with_attempt(0).keys()));
+
+        let retry = Batch::from(&descriptor.clone().with_attempt(2));
+        let expected_keys = &[
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.validity_pha.retry-2",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.validity_pha.retry-2.avro",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.validity_pha.retry-2.sig",
+        ];
+        assert!(retry
+            .keys()
+            .map(|(_, key)| key)
+            .eq(expected_keys.iter().copied()));
+        assert!(Batch::from(&descriptor)
+            .with_attempt(2)
+            .keys()
+            .eq(retry.keys()));
+        assert_eq!(retry.descriptor().unwrap().attempt, 2);
+
+        // Keys of different attempts parse to descriptors that differ only in
+        // the attempt.
+        let parsed = Batch::from_header_key(retry.header_key()).unwrap();
+        assert_eq!(parsed, descriptor.clone().with_attempt(2));
+        assert_eq!(
+            Batch::from_header_key(first.header_key()).unwrap(),
+            descriptor
+        );
+
+        // The attempt survives serialization, and is omitted for first
+        // attempts so that existing descriptors are unchanged.
+        assert!(!descriptor.to_json().contains("attempt"));
+        assert_eq!(
+            BatchDescriptor::from_json(parsed.to_json().as_bytes()).unwrap(),
+            parsed
+        );
+
+        for suffix in &[".retry-0", ".retry-02", ".retry-+2", ".retry-x", ".retry-"] {
+            let key = format!("{}{}", first.header_key(), suffix);
+            match Batch::from_header_key(&key) {
+                Err(Error::MalformedKeyError(_)) => (),
+                r => panic!("unexpected result {:?} for {:?}", r, key),
+            }
+        }
+        // ".retry-" elsewhere in the key is not an attempt.
+        let aggregation_name = AggregationName::new("fake.retry-1").unwrap();
+        let other = BatchDescriptor {
+            aggregation_name,
+            ..descriptor
+        };
+        assert_eq!(
+            Batch::from_header_key(Batch::from(&other).header_key()).unwrap(),
+            other
+        );
+    }
+
+    #[test]
+    fn enumerate_attempts() {
+        let mut transport = MemoryTransport::new();
+        let instance_name = InstanceName::new("narnia").unwrap();
+        let descriptor = BatchDescriptor::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::from_str("2020/10/31/20/29").unwrap(),
+            Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            BatchKind::Validation(ServerIdentity::Pha),
+        );
+        let batch = |attempt| {
+            descriptor
+                .clone()
+                .with_attempt(attempt)
+                .batch(&DEFAULT_NAMING_SCHEME, Some(&instance_name))
+        };
+
+        for attempt in &[3, 0, 12] {
+            put_batch_files(&mut transport, &batch(*attempt), &BatchFileKind::ALL);
+        }
+        // An incomplete attempt, and the other server's validation batch
+        put_batch_files(
+            &mut transport,
+            &batch(4),
+            &[BatchFileKind::Header, BatchFileKind::Packets],
+        );
+        put_batch_files(
+            &mut transport,
+            &BatchDescriptor {
+                kind: BatchKind::Validation(ServerIdentity::Facilitator),
+                ..descriptor.clone()
+            }
+            .with_attempt(5)
+            .batch(&DEFAULT_NAMING_SCHEME, Some(&instance_name)),
+            &BatchFileKind::ALL,
+        );
+
+        assert_eq!(
+            DEFAULT_NAMING_SCHEME
+                .enumerate_attempts(
+                    &transport,
+                    Some(&instance_name),
+                    &descriptor.with_attempt(4)
+                )
+                .unwrap(),
+            vec![0, 3, 12]
+        );
+    }
+
+    #[test]
+    fn aggregation_name_validation() {
+        for name in &[
+            "fake-aggregation",
+            "kittens_seen.v2",
+            "A1",
+            "caf\u{e9}",
+            "\u{3c0}-\u{661}\u{662}",
+        ] {
+            let parsed = AggregationName::new(name).expect("legal name rejected");
+            assert_eq!(parsed.as_str(), *name);
+        }
+
+        for name in &[
+            "",
+            "../../etc",
+            "..",
+            ".hidden",
+            "a/b",
+            "a\\b",
+            "a..b",
+            "tab\tname",
+            "nul\0",
+            "spaces are bad",
+            "bell\u{7}",
+            "next\u{85}line",
+            "unassigned\u{378}",
+            "private\u{e000}use",
+            "rtl\u{202e}override",
+            "zero\u{200b}width",
+            // A combining mark with no precomposed form remains a separate
+            // character after normalization.
+            "q\u{301}",
+        ] {
+            match AggregationName::new(name) {
+                Err(Error::IllegalNameError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", name, v),
+            }
+        }
+    }
+
+    #[test]
+    fn key_length_limit() {
+        let key = |length: usize, suffix: &str| "k".repeat(length - suffix.len()) + suffix;
+        let batch = || {
+            Batch::from_keys(
+                key(MAX_KEY_LENGTH, ".header"),
+                key(100, ".sig"),
+                key(MAX_KEY_LENGTH - 8, ".avro"),
+            )
+        };
+
+        assert!(batch().check_key_lengths(0).is_ok());
+        match batch().check_key_lengths(1) {
+            Err(Error::KeyTooLong(k, length, limit)) => {
+                assert_eq!(k, key(MAX_KEY_LENGTH, ".header"));
+                assert_eq!(length, MAX_KEY_LENGTH + 1);
+                assert_eq!(limit, MAX_KEY_LENGTH);
+            }
+            v => panic!("unexpected result {:?}", v.map(|b| b.header_path)),
+        }
+
+        // Shard keys are 8 bytes longer than the packet file key, so they too
+        // are right at the limit.
+        let sharded = || {
+            Batch::from_keys(
+                key(100, ".header"),
+                key(100, ".sig"),
+                key(MAX_KEY_LENGTH - 8, ".avro"),
+            )
+            .with_packet_file_shards(2)
+        };
+        assert!(sharded().check_key_lengths(0).is_ok());
+        match sharded().check_key_lengths(1) {
+            Err(Error::KeyTooLong(k, length, _)) => {
+                assert!(k.ends_with(".avro.shard_0") || k.ends_with(".avro.shard_1"));
+                assert_eq!(length, MAX_KEY_LENGTH + 1);
+            }
+            v => panic!("unexpected result {:?}", v.map(|b| b.header_path)),
+        }
+
+        // A prefix too long to add to any key mustn't overflow.
+        assert!(matches!(
+            batch().check_key_lengths(usize::MAX),
+            Err(Error::KeyTooLong(_, usize::MAX, MAX_KEY_LENGTH))
+        ));
+    }
+
+    #[test]
+    fn packet_part_naming() {
+        let batch = || {
+            Batch::from_keys(
+                "a.header".to_owned(),
+                "a.sig".to_owned(),
+                "a.avro".to_owned(),
+            )
+        };
+        assert_eq!(
+            batch().with_packet_file_shards(2).packet_file_shard_keys(),
+            ["a.avro.shard_0", "a.avro.shard_1"]
+        );
+
+        // The naming applies to shards whichever order the two are applied in
+        let expected = ["a.avro-000", "a.avro-001", "a.avro-002"];
+        for numbered in &[
+            batch()
+                .with_packet_part_naming(PacketPartNaming::Numbered)
+                .with_packet_file_shards(3),
+            batch()
+                .with_packet_file_shards(3)
+                .with_packet_part_naming(PacketPartNaming::Numbered),
+        ] {
+            assert_eq!(numbered.packet_file_shard_keys(), expected);
+            assert_eq!(numbered.packet_file_shard_key(1), "a.avro-001");
+        }
+
+        assert_eq!(
+            "numbered".parse::<PacketPartNaming>().unwrap(),
+            PacketPartNaming::Numbered
+        );
+        assert_eq!(PacketPartNaming::Shard.as_str(), "shard");
+        assert!("-000".parse::<PacketPartNaming>().is_err());
+    }
+
+    #[test]
+    fn aggregation_name_nfc() {
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        let aggregation_name = AggregationName::new(nfd).unwrap();
+        assert_eq!(aggregation_name.as_str(), nfc);
+        assert_eq!(aggregation_name, AggregationName::new(nfc).unwrap());
+        assert_eq!(AggregationName::from_str(nfd).unwrap(), aggregation_name);
+        assert_eq!(
+            serde_json::from_str::<AggregationName>(&format!("\"{}\"", nfd)).unwrap(),
+            aggregation_name
+        );
+        // Normalization happens before the uppercase check, which sees the
+        // precomposed character.
+        assert!(matches!(
+            AggregationName::new_lowercase("CAFE\u{301}"),
+            Err(Error::IllegalNameError(_))
+        ));
+
+        let descriptor = BatchDescriptor::new(
+            aggregation_name.clone(),
+            BatchDate::from_str("2020/10/31/20/29").unwrap(),
+            Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            BatchKind::Ingestion,
+        );
+        for layout in &[PathLayout::Flat, PathLayout::DatePartitioned] {
+            let naming_scheme = DefaultBatchNamingScheme::default().with_path_layout(*layout);
+            let batch = descriptor.batch(&naming_scheme, None);
+            for kind in &BatchFileKind::ALL {
+                let key = batch.key(*kind);
+                assert!(key.contains(nfc), "{:?} does not contain {:?}", key, nfc);
+                assert!(!key.contains(nfd), "{:?} contains {:?}", key, nfd);
+                assert!(is_nfc(key), "{:?} is not NFC", key);
+            }
+
+            assert_eq!(
+                naming_scheme
+                    .parse_header_key(None, batch.header_key())
+                    .unwrap(),
+                descriptor
+            );
+            let nfd_key: String = batch.header_key().nfd().collect();
+            match naming_scheme.parse_header_key(None, &nfd_key) {
+                Err(Error::MalformedKeyError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", nfd_key, v),
+            }
+
+            // Discovery finds batches written under the NFC name, whichever
+            // form the name we search for was given in, and ignores keys in
+            // any other form.
+            let mut transport = MemoryTransport::new();
+            put_batch_files(&mut transport, &batch, &BatchFileKind::ALL);
+            let nfd_batch = Batch::from_keys(
+                batch.header_key().nfd().collect(),
+                batch.signature_key().nfd().collect(),
+                batch.packet_file_key().nfd().collect(),
+            );
+            put_batch_files(&mut transport, &nfd_batch, &BatchFileKind::ALL);
+            assert_eq!(
+                naming_scheme
+                    .enumerate_range(
+                        &transport,
+                        None,
+                        &AggregationName::new(nfd).unwrap(),
+                        &BatchDate::from_str("2020/10/31/00/00").unwrap(),
+                        &BatchDate::from_str("2020/11/01/00/00").unwrap(),
+                    )
+                    .unwrap(),
+                vec![descriptor.identity()]
+            );
+        }
+    }
+
+    #[test]
+    fn lowercase_aggregation_name_validation() {
+        for name in &["fake-aggregation", "kittens_seen.v2", "a1"] {
+            let parsed = AggregationName::new_lowercase(name).expect("legal name rejected");
+            assert_eq!(parsed.as_str(), *name);
+        }
+
+        for name in &["A1", "fake-Aggregation", "../etc"] {
+            match AggregationName::new_lowercase(name) {
+                Err(Error::IllegalNameError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", name, v),
+            }
+        }
+    }
+
+    #[test]
+    fn batch_date_parse() {
+        let date: BatchDate = "2020/10/14/16/05".parse().expect("failed to parse date");
+        assert_eq!(date.to_string(), "2020/10/14/16/05");
+        assert_eq!(
+            *date.as_naive_date_time(),
+            NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 5, 0)
+        );
+
+        for bad in &[
+            "fake-date",
+            "",
+            "2020/10/14",
+            "2020/10/14/16/05/00",
+            "2020/1/14/16/05",
+            "2020/13/14/16/05",
+            "2020/10/14/25/05",
+            "2020-10-14-16-05",
+            " 2020/10/14/16/05",
+        ] {
+            match bad.parse::<BatchDate>() {
+                Err(Error::MalformedDateError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", bad, v),
+            }
+            #[allow(deprecated)]
+            let result = BatchDate::from_date_string(bad);
+            match result {
+                Err(Error::MalformedDateError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", bad, v),
+            }
+        }
+
+        #[allow(deprecated)]
+        let date_from_string = BatchDate::from_date_string("2020/10/14/16/05").unwrap();
+        assert_eq!(date_from_string, date);
+    }
+
+    #[test]
+    fn batch_date_truncates_to_minute() {
+        let date = BatchDate::new(&NaiveDate::from_ymd(2020, 10, 14).and_hms_milli(16, 5, 7, 9));
+        assert_eq!(date, "2020/10/14/16/05".parse().unwrap());
+        assert_eq!(date.to_string().parse::<BatchDate>().unwrap(), date);
+    }
+
+    #[test]
+    fn batch_date_ordering() {
+        let dates: Vec<BatchDate> = [
+            "2020/10/14/16/05",
+            "2019/12/31/23/59",
+            "2020/10/14/16/04",
+            "2020/01/01/00/00",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let mut sorted = dates.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![dates[1], dates[3], dates[2], dates[0]]);
+        assert!(dates[2] < dates[0]);
+    }
+
+    #[test]
+    fn batch_date_window() {
+        let now = NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 5, 0);
+        let window = BatchDateWindow::new(Duration::minutes(10), Duration::days(7));
+
+        for accepted in &[
+            "2020/10/14/16/05",
+            "2020/10/14/16/15",
+            "2020/10/07/16/05",
+            "2020/10/10/00/00",
+        ] {
+            let date: BatchDate = accepted.parse().unwrap();
+            window
+                .check(&date, &now)
+                .unwrap_or_else(|e| panic!("{} rejected: {}", accepted, e));
+        }
+
+        for rejected in &["2020/10/14/16/16", "2020/10/07/16/04", "2023/10/14/16/05"] {
+            let date: BatchDate = rejected.parse().unwrap();
+            match window.check(&date, &now) {
+                Err(Error::BatchDateOutsideWindow(d, earliest, latest)) => {
+                    assert_eq!(d, date);
+                    assert_eq!(earliest, NaiveDate::from_ymd(2020, 10, 7).and_hms(16, 5, 0));
+                    assert_eq!(latest, NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 15, 0));
+                }
+                r => panic!("{} not rejected: {:?}", rejected, r),
+            }
+        }
+
+        // Windows too large to represent are unbounded.
+        let window = BatchDateWindow::new(Duration::max_value(), Duration::max_value());
+        window
+            .check(&"1970/01/01/00/00".parse().unwrap(), &now)
+            .unwrap();
+    }
+
+    #[test]
+    fn roundtrip_ingestion_batch_ok() {
+        roundtrip_ingestion_batch(true, DEFAULT_SPOOL_THRESHOLD, DEFAULT_READ_BUFFER_SIZE)
+    }
+
+    #[test]
+    fn roundtrip_ingestion_batch_in_memory() {
+        roundtrip_ingestion_batch(true, usize::MAX, DEFAULT_READ_BUFFER_SIZE)
+    }
+
+    /// A transport that understates the size of its values.
+    struct UnderstatingTransport(MemoryTransport);
+
+    impl Transport for UnderstatingTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            self.0.get(key)
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            self.0.put(key)
+        }
+
+        fn size(&self, _key: &str) -> Result<Option<u64>> {
+            Ok(Some(1))
+        }
+    }
+
+    #[test]
+    fn max_packet_file_size_understated() {
+        let mut transport = UnderstatingTransport(MemoryTransport::new());
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+        let batch = || Batch::new_ingestion(&aggregation_name, &batch_id, &date);
+        let packet = IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![0u8; 64],
+            encryption_key_id: "fake-key-1".to_owned(),
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            sequence_number: None,
+        };
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch(), &mut transport);
+        let packet_file_digest = batch_writer
+            .packet_file_writer(|packet_writer| Ok(packet.write(packet_writer)?))
+            .unwrap();
+        let header = IngestionHeader {
+            batch_uuid: batch_id,
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+        let packet_file_size = transport
+            .0
+            .size(batch().packet_file_key())
+            .unwrap()
+            .unwrap();
+
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch(), &mut transport);
+        batch_reader.set_max_packet_file_size(Some(packet_file_size - 1));
+        let err = batch_reader.packet_file_reader(&header).err().unwrap();
+        match err.downcast_ref::<Error>() {
+            // Reading stops one byte past the limit
+            Some(Error::PacketFileTooLarge(_, size, max)) => {
+                assert_eq!(*size, packet_file_size);
+                assert_eq!(*max, packet_file_size - 1);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        batch_reader.set_max_packet_file_size(Some(packet_file_size));
+        let mut packet_reader = batch_reader.packet_file_reader(&header).unwrap();
+        assert_eq!(
+            IngestionDataSharePacket::read(&mut packet_reader).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn packet_file_digest_mismatch() {
+        let mut transport = MemoryTransport::new();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+        let batch = || Batch::new_ingestion(&aggregation_name, &batch_id, &date);
+        let packet = IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![0u8; 64],
+            encryption_key_id: "fake-key-1".to_owned(),
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            sequence_number: None,
+        };
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch(), &mut transport);
+        let packet_file_digest = batch_writer
+            .packet_file_writer(|packet_writer| Ok(packet.write(packet_writer)?))
+            .unwrap();
+        let header = IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+
+        for spool_threshold in &[usize::MAX, 8] {
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch(), &mut transport);
+            batch_reader.set_spool_threshold(*spool_threshold);
+            let mut packet_reader = batch_reader.packet_file_reader(&header).unwrap();
+            assert_eq!(
+                IngestionDataSharePacket::read(&mut packet_reader).unwrap(),
+                packet
+            );
         }
-        assert!(
-            header_again.is_ok(),
-            "failed to read header from batch {:?}",
-            header_again.err()
-        );
-        let header_again = header_again.unwrap();
-
-        assert_eq!(header, header_again, "header does not match");
 
-        let mut packet_file_reader = batch_reader
-            .packet_file_reader(&header_again)
-            .expect("failed to get packet file reader");
+        // Flip a byte near the end of the packet file
+        let mut packet_file = Vec::new();
+        transport
+            .get(batch().packet_file_key())
+            .unwrap()
+            .read_to_end(&mut packet_file)
+            .unwrap();
+        let index = packet_file.len() - 40;
+        packet_file[index] ^= 1;
+        let mut writer = transport.put(batch().packet_file_key()).unwrap();
+        writer.write_all(&packet_file).unwrap();
+        writer.complete_upload().unwrap();
 
-        for packet in packets {
-            let packet_again = IngestionDataSharePacket::read(&mut packet_file_reader)
-                .expect("failed to read packet");
-            assert_eq!(packet, &packet_again, "packet does not match");
+        for spool_threshold in &[usize::MAX, 8] {
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch(), &mut transport);
+            batch_reader.set_spool_threshold(*spool_threshold);
+            let err = batch_reader.packet_file_reader(&header).err().unwrap();
+            assert!(
+                err.to_string().contains("does not match header"),
+                "unexpected error {:?}",
+                err
+            );
         }
+    }
 
-        // One more read should get EOF
-        match IngestionDataSharePacket::read(&mut packet_file_reader) {
-            Err(Error::EofError) => (),
-            v => assert!(false, "wrong error {:?}", v),
-        }
+    #[test]
+    fn roundtrip_ingestion_batch_bad_read_key() {
+        roundtrip_ingestion_batch(false, DEFAULT_SPOOL_THRESHOLD, DEFAULT_READ_BUFFER_SIZE)
     }
 
     #[test]
-    fn roundtrip_ingestion_batch_ok() {
-        roundtrip_ingestion_batch(true)
+    fn roundtrip_ingestion_batch_spooled() {
+        // A threshold this small forces the header and packet file to be
+        // spooled to files
+        roundtrip_ingestion_batch(true, 8, DEFAULT_READ_BUFFER_SIZE)
     }
 
     #[test]
-    fn roundtrip_ingestion_batch_bad_read_key() {
-        roundtrip_ingestion_batch(false)
+    fn roundtrip_ingestion_batch_spooled_small_read_buffer() {
+        roundtrip_ingestion_batch(true, 8, 3)
     }
 
-    fn roundtrip_ingestion_batch(keys_match: bool) {
+    fn roundtrip_ingestion_batch(
+        keys_match: bool,
+        spool_threshold: usize,
+        read_buffer_size: usize,
+    ) {
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
 
-        let aggregation_name = "fake-aggregation";
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
         let batch_id = Uuid::new_v4();
-        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
 
         let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
                 Batch::new_ingestion(&aggregation_name, &batch_id, &date),
                 &mut write_transport,
             );
-        let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+        batch_writer.set_spool_threshold(spool_threshold);
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
                 Batch::new_ingestion(&aggregation_name, &batch_id, &date),
                 &mut read_transport,
             );
-        let base_path = format!(
-            "{}/{}/{}",
-            aggregation_name,
-            date.format(DATE_FORMAT),
-            batch_id.to_hyphenated()
-        );
+        batch_reader.set_spool_threshold(spool_threshold);
+        batch_reader.set_read_buffer_size(read_buffer_size);
+        let base_path = format!("{}/{}/{}", aggregation_name, date, batch_id.to_hyphenated());
         let read_key = if keys_match {
             default_ingestor_public_key()
         } else {
@@ -456,59 +4038,94 @@ mod tests {
 
     #[test]
     fn roundtrip_validation_batch_first_ok() {
-        roundtrip_validation_batch(true, true)
+        roundtrip_validation_batch(ServerIdentity::Pha, ValidationNaming::ServerIdentity, true)
     }
 
     #[test]
     fn roundtrip_validation_batch_first_bad_read_key() {
-        roundtrip_validation_batch(true, false)
+        roundtrip_validation_batch(ServerIdentity::Pha, ValidationNaming::ServerIdentity, false)
     }
 
     #[test]
     fn roundtrip_validation_batch_second_ok() {
-        roundtrip_validation_batch(false, true)
+        roundtrip_validation_batch(
+            ServerIdentity::Facilitator,
+            ValidationNaming::ServerIdentity,
+            true,
+        )
     }
 
     #[test]
     fn roundtrip_validation_batch_second_bad_read_key() {
-        roundtrip_validation_batch(false, false)
+        roundtrip_validation_batch(
+            ServerIdentity::Facilitator,
+            ValidationNaming::ServerIdentity,
+            false,
+        )
+    }
+
+    #[test]
+    fn roundtrip_validation_batch_first_legacy_ok() {
+        roundtrip_validation_batch(ServerIdentity::Pha, ValidationNaming::Legacy, true)
+    }
+
+    #[test]
+    fn roundtrip_validation_batch_second_legacy_ok() {
+        roundtrip_validation_batch(ServerIdentity::Facilitator, ValidationNaming::Legacy, true)
     }
 
-    fn roundtrip_validation_batch(is_first: bool, keys_match: bool) {
+    fn roundtrip_validation_batch(
+        server: ServerIdentity,
+        naming: ValidationNaming,
+        keys_match: bool,
+    ) {
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
 
-        let aggregation_name = "fake-aggregation";
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
         let batch_id = Uuid::new_v4();
-        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
 
+        let naming_scheme = DefaultBatchNamingScheme::new(naming);
         let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
-                Batch::new_validation(&aggregation_name, &batch_id, &date, is_first),
+                Batch::with_naming_scheme(
+                    &naming_scheme,
+                    None,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Validation(server),
+                ),
                 &mut write_transport,
             );
         let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
-                Batch::new_validation(&aggregation_name, &batch_id, &date, is_first),
+                Batch::with_naming_scheme(
+                    &naming_scheme,
+                    None,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Validation(server),
+                ),
                 &mut read_transport,
             );
-        let base_path = format!(
-            "{}/{}/{}",
-            aggregation_name,
-            date.format(DATE_FORMAT),
-            batch_id.to_hyphenated()
-        );
-        let first_filenames = &[
-            "validity_0".to_owned(),
-            "validity_0.avro".to_owned(),
-            "validity_0.sig".to_owned(),
-        ];
-        let second_filenames = &[
-            "validity_1".to_owned(),
-            "validity_1.avro".to_owned(),
-            "validity_1.sig".to_owned(),
+        let base_path = format!("{}/{}/{}", aggregation_name, date, batch_id.to_hyphenated());
+        let label = match (server, naming) {
+            (ServerIdentity::Pha, ValidationNaming::ServerIdentity) => "validity_pha",
+            (ServerIdentity::Facilitator, ValidationNaming::ServerIdentity) => {
+                "validity_facilitator"
+            }
+            (ServerIdentity::Pha, ValidationNaming::Legacy) => "validity_0",
+            (ServerIdentity::Facilitator, ValidationNaming::Legacy) => "validity_1",
+        };
+        let filenames = &[
+            label.to_owned(),
+            format!("{}.avro", label),
+            format!("{}.sig", label),
         ];
         let read_key = if keys_match {
             default_ingestor_public_key()
@@ -519,11 +4136,7 @@ mod tests {
             aggregation_name.to_string(),
             batch_id,
             base_path,
-            if is_first {
-                first_filenames
-            } else {
-                second_filenames
-            },
+            filenames,
             &mut batch_writer,
             &batch_reader,
             &mut verify_transport,
@@ -533,6 +4146,747 @@ mod tests {
         )
     }
 
+    #[test]
+    fn validation_batch_keys_distinct_per_server() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+
+        for naming in &[ValidationNaming::ServerIdentity, ValidationNaming::Legacy] {
+            let naming_scheme = DefaultBatchNamingScheme::new(*naming);
+            let pha = naming_scheme.batch(
+                &aggregation_name,
+                &batch_id,
+                &date,
+                BatchKind::Validation(ServerIdentity::Pha),
+            );
+            let facilitator = naming_scheme.batch(
+                &aggregation_name,
+                &batch_id,
+                &date,
+                BatchKind::Validation(ServerIdentity::Facilitator),
+            );
+            assert_ne!(pha.header_key(), facilitator.header_key());
+            assert_ne!(pha.signature_key(), facilitator.signature_key());
+            assert_ne!(pha.packet_file_key(), facilitator.packet_file_key());
+        }
+    }
+
+    #[test]
+    fn own_and_peer_validation_batches() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::from_str("2020/10/31/20/29").unwrap(),
+            Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+        );
+        let instance_name = InstanceName::new("narnia").unwrap();
+
+        for naming in &[ValidationNaming::ServerIdentity, ValidationNaming::Legacy] {
+            let naming_scheme = DefaultBatchNamingScheme::new(*naming);
+            let label = |server: ServerIdentity| match naming {
+                ValidationNaming::ServerIdentity => format!(".validity_{}", server),
+                ValidationNaming::Legacy => format!(".validity_{}", server.index()),
+            };
+            for own in &[ServerIdentity::Pha, ServerIdentity::Facilitator] {
+                let own_batch =
+                    batch.own_validation_batch(&naming_scheme, Some(&instance_name), *own);
+                let peer_batch =
+                    batch.peer_validation_batch(&naming_scheme, Some(&instance_name), *own);
+                assert_eq!(own_batch.owner(), *own);
+                assert_eq!(peer_batch.owner(), own.peer());
+
+                // Each side is what the respective share processor writes.
+                let peer_own_batch =
+                    batch.own_validation_batch(&naming_scheme, Some(&instance_name), own.peer());
+                assert!(peer_batch.batch().keys().eq(peer_own_batch.batch().keys()));
+
+                for ((_, own_key), (_, peer_key)) in
+                    own_batch.batch().keys().zip(peer_batch.batch().keys())
+                {
+                    assert_ne!(own_key, peer_key);
+                    assert_eq!(own_key.replace(&label(*own), &label(own.peer())), peer_key);
+                }
+                assert_eq!(
+                    own_batch.into_batch().descriptor().unwrap().kind,
+                    BatchKind::Validation(*own)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn instance_name_validation() {
+        assert_eq!(InstanceName::new("narnia").unwrap().as_str(), "narnia");
+        for name in &["", "..", "narnia/", "a/b", ".hidden"] {
+            match InstanceName::new(name) {
+                Err(Error::IllegalNameError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", name, v),
+            }
+        }
+    }
+
+    #[test]
+    fn instance_name_prefix() {
+        let instance_name = InstanceName::new("narnia").unwrap();
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+
+        let batches = |instance_name| {
+            vec![
+                Batch::with_naming_scheme(
+                    &DEFAULT_NAMING_SCHEME,
+                    instance_name,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Ingestion,
+                ),
+                Batch::with_naming_scheme(
+                    &DEFAULT_NAMING_SCHEME,
+                    instance_name,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Validation(ServerIdentity::Pha),
+                ),
+                Batch::new_sum(
+                    instance_name,
+                    &aggregation_name,
+                    &date,
+                    &date,
+                    ServerIdentity::Pha,
+                ),
+            ]
+        };
+
+        for (unprefixed, prefixed) in batches(None)
+            .iter()
+            .zip(batches(Some(&instance_name)).iter())
+        {
+            for ((kind, key), (prefixed_kind, prefixed_key)) in
+                unprefixed.keys().zip(prefixed.keys())
+            {
+                assert_eq!(kind, prefixed_kind);
+                assert!(key.starts_with("fake-aggregation/"), "{}", key);
+                assert_eq!(prefixed_key, format!("narnia/{}", key));
+            }
+        }
+    }
+
+    #[test]
+    fn batch_keys() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+        let batches = &[
+            Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date),
+            Batch::new_validation(
+                &aggregation_name,
+                &Uuid::new_v4(),
+                &date,
+                ServerIdentity::Facilitator,
+            ),
+            Batch::new_sum(None, &aggregation_name, &date, &date, ServerIdentity::Pha),
+        ];
+        for batch in batches {
+            let keys: Vec<_> = batch.keys().collect();
+            assert_eq!(
+                keys,
+                vec![
+                    (BatchFileKind::Header, batch.header_key()),
+                    (BatchFileKind::Packets, batch.packet_file_key()),
+                    (BatchFileKind::Signature, batch.signature_key()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn deterministic_signature() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let header = IngestionHeader {
+            batch_uuid: Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            name: "fake-batch".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: vec![1u8, 2u8, 3u8, 4u8],
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+        let key = default_ingestor_private_key();
+        let batch = Batch::new_ingestion(&aggregation_name, &Uuid::new_v4(), &date);
+        let header_key = batch.header_key().to_owned();
+        let rng = FixedByteRandom { byte: 42 };
+
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch, &mut transport);
+        batch_writer.set_rng(&rng);
+        let signature = batch_writer.put_header(&header, &key).unwrap();
+
+        let mut header_bytes = Vec::new();
+        transport
+            .get(&header_key)
+            .unwrap()
+            .read_to_end(&mut header_bytes)
+            .unwrap();
+        default_ingestor_public_key()
+            .verify(&header_bytes, signature.as_ref())
+            .unwrap();
+
+        // ECDSA signatures over the same message with the same randomness are
+        // identical, but differ if the randomness differs.
+        let signature_again = key.sign(&rng, &header_bytes).unwrap();
+        assert_eq!(signature.as_ref(), signature_again.as_ref());
+        let other_signature = key
+            .sign(&FixedByteRandom { byte: 43 }, &header_bytes)
+            .unwrap();
+        assert_ne!(signature.as_ref(), other_signature.as_ref());
+    }
+
+    #[test]
+    fn manifest_keys_and_order() {
+        let mut transport = MemoryTransport::new();
+        let batch = Batch::new_ingestion(
+            &AggregationName::new("fake-aggregation").unwrap(),
+            &Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            &BatchDate::from_str("2020/10/31/20/29").unwrap(),
+        );
+        assert_eq!(
+            batch.manifest_key(),
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch.manifest.json"
+        );
+        assert_eq!(
+            batch.manifest_signature_key(),
+            format!("{}.sig", batch.manifest_key())
+        );
+        let key = default_ingestor_private_key();
+        let header_key = batch.header_key().to_owned();
+
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch, &mut transport);
+        let header = IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-batch".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: vec![1u8, 2u8, 3u8, 4u8],
+            packet_file_shard_digests: vec![],
+            packet_count: None,
+        };
+        // The header and its signature must be written first.
+        assert!(batch_writer.put_manifest(0, &key).is_err());
+        let signature = batch_writer.put_header(&header, &key).unwrap();
+        assert!(batch_writer.put_manifest(0, &key).is_err());
+        batch_writer.put_signature(&signature).unwrap();
+        // Rewriting the header replaces its entry.
+        let signature = batch_writer.put_header(&header, &key).unwrap();
+        batch_writer.put_signature(&signature).unwrap();
+
+        let manifest = batch_writer.put_manifest(0, &key).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(manifest.files[0].key, header_key);
+        assert_eq!(manifest.files[1].size, signature.as_ref().len() as u64);
+    }
+
+    #[test]
+    fn sum_batch_keys() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let start = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let end = BatchDate::from_str("2020/11/01/08/29").unwrap();
+
+        let batch = Batch::new_sum(None, &aggregation_name, &start, &end, ServerIdentity::Pha);
+        assert_eq!(
+            batch.header_key(),
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.sum_0"
+        );
+        assert_eq!(
+            batch.signature_key(),
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.sum_0.sig"
+        );
+        assert_eq!(
+            batch.packet_file_key(),
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.invalid_uuid_0.avro"
+        );
+
+        let batch = Batch::new_sum(
+            None,
+            &aggregation_name,
+            &start,
+            &end,
+            ServerIdentity::Facilitator,
+        );
+        assert_eq!(
+            batch.header_key(),
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.sum_1"
+        );
+        assert_eq!(
+            batch.packet_file_key(),
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.invalid_uuid_1.avro"
+        );
+
+        // Sums over different windows must not collide.
+        let other_batch = Batch::new_sum(
+            None,
+            &aggregation_name,
+            &start,
+            &start,
+            ServerIdentity::Facilitator,
+        );
+        for ((_, key), (_, other_key)) in batch.keys().zip(other_batch.keys()) {
+            assert_ne!(key, other_key);
+        }
+    }
+
+    #[test]
+    fn batch_descriptor_roundtrip() {
+        let descriptor = BatchDescriptor::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::from_str("2020/10/31/20/29").unwrap(),
+            Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap(),
+            BatchKind::Validation(ServerIdentity::Facilitator),
+        );
+        let json = descriptor.to_json();
+        assert_eq!(
+            json,
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":{"validation":"facilitator"}}"#
+        );
+        assert_eq!(
+            BatchDescriptor::from_json(json.as_bytes()).unwrap(),
+            descriptor
+        );
+
+        let ingestion_descriptor = BatchDescriptor {
+            kind: BatchKind::Ingestion,
+            ..descriptor.clone()
+        };
+        assert_eq!(
+            BatchDescriptor::from_json(ingestion_descriptor.to_json().as_bytes()).unwrap(),
+            ingestion_descriptor
+        );
+
+        let batch = Batch::from(&descriptor);
+        assert_eq!(batch.descriptor(), Some(&descriptor));
+        let expected = Batch::new_validation(
+            &descriptor.aggregation_name,
+            &descriptor.batch_id,
+            &descriptor.date,
+            ServerIdentity::Facilitator,
+        );
+        assert!(batch.keys().eq(expected.keys()));
+
+        let instance_name = InstanceName::new("narnia").unwrap();
+        let batch = descriptor.batch(&DEFAULT_NAMING_SCHEME, Some(&instance_name));
+        assert_eq!(batch.descriptor(), Some(&descriptor));
+        assert!(batch.header_key().starts_with("narnia/"));
+
+        let sum = Batch::new_sum(
+            None,
+            &descriptor.aggregation_name,
+            &descriptor.date,
+            &descriptor.date,
+            ServerIdentity::Pha,
+        );
+        assert_eq!(sum.descriptor(), None);
+    }
+
+    #[test]
+    fn batch_identity() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let batch_id = Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap();
+        let identity = BatchIdentity::new(aggregation_name.clone(), date, batch_id);
+
+        assert_eq!(
+            identity.to_string(),
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8"
+        );
+        assert_eq!(
+            identity,
+            BatchIdentity::new(
+                AggregationName::new("fake-aggregation").unwrap(),
+                BatchDate::new(&NaiveDate::from_ymd(2020, 10, 31).and_hms(20, 29, 59)),
+                batch_id
+            )
+        );
+        assert_ne!(
+            identity,
+            BatchIdentity::new(
+                AggregationName::new("other-aggregation").unwrap(),
+                date,
+                batch_id
+            )
+        );
+        assert_ne!(
+            identity,
+            BatchIdentity::new(
+                aggregation_name.clone(),
+                BatchDate::from_str("2020/10/31/20/30").unwrap(),
+                batch_id
+            )
+        );
+        assert_ne!(
+            identity,
+            BatchIdentity::new(aggregation_name.clone(), date, Uuid::new_v4())
+        );
+
+        assert!(identity
+            .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+            .keys()
+            .eq(Batch::new_ingestion(&aggregation_name, &batch_id, &date).keys()));
+        for server in &[ServerIdentity::Pha, ServerIdentity::Facilitator] {
+            assert!(identity
+                .validation_batch(&DEFAULT_NAMING_SCHEME, None, *server)
+                .keys()
+                .eq(Batch::new_validation(&aggregation_name, &batch_id, &date, *server).keys()));
+        }
+        for kind in &BatchKind::ALL {
+            assert_eq!(identity.descriptor(*kind).identity(), identity);
+        }
+    }
+
+    #[test]
+    fn batch_identity_dedup() {
+        // Both validation batches and the ingestion batch for the same batch
+        // have the same identity, so discovering all of them should yield the
+        // batch once.
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let batch_ids = &[Uuid::new_v4(), Uuid::new_v4()];
+
+        let mut counts: HashMap<BatchIdentity, usize> = HashMap::new();
+        for batch_id in batch_ids {
+            for kind in &BatchKind::ALL {
+                let key = BatchDescriptor::new(aggregation_name.clone(), date, *batch_id, *kind)
+                    .batch(&DEFAULT_NAMING_SCHEME, None)
+                    .header_key()
+                    .to_owned();
+                let identity = Batch::from_header_key(&key).unwrap().identity();
+                *counts.entry(identity).or_default() += 1;
+            }
+        }
+
+        assert_eq!(counts.len(), batch_ids.len());
+        for batch_id in batch_ids {
+            assert_eq!(
+                counts[&BatchIdentity::new(aggregation_name.clone(), date, *batch_id)],
+                BatchKind::ALL.len()
+            );
+        }
+    }
+
+    #[test]
+    fn batch_descriptor_malformed() {
+        let malformed = &[
+            // Not JSON
+            "",
+            "not json",
+            // Missing fields
+            r#"{"date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"ingestion"}"#,
+            r#"{"aggregation_name":"fake-aggregation","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"ingestion"}"#,
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","kind":"ingestion"}"#,
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8"}"#,
+            // Unknown field
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"ingestion","extra":1}"#,
+            // Illegal aggregation name
+            r#"{"aggregation_name":"../escaped","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"ingestion"}"#,
+            // Non-canonical date
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29/00","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"ingestion"}"#,
+            // Bad UUID
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"not-a-uuid","kind":"ingestion"}"#,
+            // Unknown kind or server
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":"sum"}"#,
+            r#"{"aggregation_name":"fake-aggregation","date":"2020/10/31/20/29","batch_id":"a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8","kind":{"validation":"ingestor"}}"#,
+        ];
+        for json in malformed {
+            match BatchDescriptor::from_json(json.as_bytes()) {
+                Err(Error::MalformedBatchDescriptorError(_)) => (),
+                r => panic!("unexpected result {:?} for {}", r, json),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_header_key() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let instance_name = InstanceName::new("narnia").unwrap();
+        let legacy_naming_scheme = DefaultBatchNamingScheme::new(ValidationNaming::Legacy);
+
+        for naming_scheme in &[DefaultBatchNamingScheme::default(), legacy_naming_scheme] {
+            for instance_name in &[None, Some(&instance_name)] {
+                for kind in &BatchKind::ALL {
+                    let descriptor =
+                        BatchDescriptor::new(aggregation_name.clone(), date, batch_id, *kind);
+                    let batch = descriptor.batch(naming_scheme, *instance_name);
+                    assert_eq!(
+                        naming_scheme
+                            .parse_header_key(*instance_name, batch.header_key())
+                            .unwrap(),
+                        descriptor
+                    );
+                }
+            }
+        }
+
+        assert_eq!(
+            Batch::from_header_key(
+                "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch"
+            )
+            .unwrap(),
+            BatchDescriptor::new(aggregation_name, date, batch_id, BatchKind::Ingestion)
+        );
+    }
+
+    #[test]
+    fn path_layout() {
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::parse_str("a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8").unwrap();
+        let date = BatchDate::from_str("2020/10/31/20/29").unwrap();
+        let date_partitioned =
+            DefaultBatchNamingScheme::default().with_path_layout(PathLayout::DatePartitioned);
+
+        let batch = Batch::with_naming_scheme(
+            &date_partitioned,
+            None,
+            &aggregation_name,
+            &batch_id,
+            &date,
+            BatchKind::Validation(ServerIdentity::Pha),
+        );
+        assert_eq!(
+            batch.header_key(),
+            "2020/10/31/20/29/fake-aggregation/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.validity_pha"
+        );
+
+        for naming_scheme in &[
+            DefaultBatchNamingScheme::default(),
+            date_partitioned.clone(),
+        ] {
+            for kind in &BatchKind::ALL {
+                let descriptor =
+                    BatchDescriptor::new(aggregation_name.clone(), date, batch_id, *kind);
+                let batch = descriptor.batch(naming_scheme, None);
+                assert_eq!(
+                    naming_scheme
+                        .parse_header_key(None, batch.header_key())
+                        .unwrap(),
+                    descriptor
+                );
+            }
+        }
+
+        // Keys in one layout are not mistaken for keys in the other.
+        let flat_key = Batch::new_ingestion(&aggregation_name, &batch_id, &date)
+            .header_key()
+            .to_owned();
+        assert!(date_partitioned.parse_header_key(None, &flat_key).is_err());
+        let date_partitioned_key = Batch::with_naming_scheme(
+            &date_partitioned,
+            None,
+            &aggregation_name,
+            &batch_id,
+            &date,
+            BatchKind::Ingestion,
+        )
+        .header_key()
+        .to_owned();
+        assert!(Batch::from_header_key(&date_partitioned_key).is_err());
+
+        assert_eq!(
+            PathLayout::from_str("date-partitioned").unwrap(),
+            PathLayout::DatePartitioned
+        );
+        assert_eq!(PathLayout::from_str("flat").unwrap(), PathLayout::Flat);
+        assert!(PathLayout::from_str("nested").is_err());
+    }
+
+    #[test]
+    fn batch_naming() {
+        assert_eq!(
+            BatchNaming::default(),
+            BatchNaming::new(".batch", ".batch.avro", ".batch.sig").unwrap()
+        );
+        assert!(BatchNaming::new("", ".data", ".sig").is_ok());
+        for (header, packet, signature) in &[
+            ("batch", ".avro", ".sig"),
+            (".batch", "/avro", ".sig"),
+            (".batch", ".avro", "./../sig"),
+            (".batch", ".avro", ".s/g"),
+            (".batch", ".batch", ".sig"),
+            ("", ".avro", ""),
+        ] {
+            match BatchNaming::new(header, packet, signature) {
+                Err(Error::IllegalNameError(_)) => (),
+                r => panic!(
+                    "unexpected result {:?} for {:?}",
+                    r,
+                    (header, packet, signature)
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_header_key_malformed() {
+        let malformed_keys = &[
+            // Missing components
+            "",
+            "a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+            "fake-aggregation/2020/10/31/20/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+            "fake-aggregation/2020/10/31/20/29/.batch",
+            // Extra components
+            "narnia/fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+            // Bad UUIDs
+            "fake-aggregation/2020/10/31/20/29/not-a-uuid.batch",
+            "fake-aggregation/2020/10/31/20/29/A1D2FA7B-FA60-4A3B-8CD5-9BB1C8D0E1A8.batch",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7bfa604a3b8cd59bb1c8d0e1a8.batch",
+            // Not a header
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch.sig",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch.avro",
+            "fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.validity_0",
+            "fake-aggregation/2020/10/31/20/29-2020/11/01/08/29.sum_0",
+        ];
+        for key in malformed_keys {
+            match Batch::from_header_key(key) {
+                Err(Error::MalformedKeyError(_)) => (),
+                r => panic!("unexpected result {:?} for {:?}", r, key),
+            }
+        }
+
+        match Batch::from_header_key(
+            ".hidden/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+        ) {
+            Err(Error::IllegalNameError(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match Batch::from_header_key(
+            "fake-aggregation/2020/10/31/25/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+        ) {
+            Err(Error::MalformedDateError(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match DEFAULT_NAMING_SCHEME.parse_header_key(
+            Some(&InstanceName::new("narnia").unwrap()),
+            "other/fake-aggregation/2020/10/31/20/29/a1d2fa7b-fa60-4a3b-8cd5-9bb1c8d0e1a8.batch",
+        ) {
+            Err(Error::MalformedKeyError(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    /// A naming scheme that puts the date first and gives each kind of batch
+    /// its own directory.
+    struct PartnerNamingScheme;
+
+    impl BatchNamingScheme for PartnerNamingScheme {
+        fn batch(
+            &self,
+            aggregation_name: &AggregationName,
+            batch_id: &Uuid,
+            date: &BatchDate,
+            kind: BatchKind,
+        ) -> Batch {
+            let kind = match kind {
+                BatchKind::Ingestion => "ingestion".to_owned(),
+                BatchKind::Validation(server) => format!("validation-{}", server),
+            };
+            let prefix = format!("{}/{}/{}/{}", date, aggregation_name, kind, batch_id);
+            Batch::from_keys(
+                format!("{}.header", prefix),
+                format!("{}.signature", prefix),
+                format!("{}.packets", prefix),
+            )
+        }
+    }
+
+    #[test]
+    fn roundtrip_custom_naming_scheme() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
+        let batch_id = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::with_naming_scheme(
+                    &PartnerNamingScheme,
+                    None,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Ingestion,
+                ),
+                &mut write_transport,
+            );
+        let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::with_naming_scheme(
+                    &PartnerNamingScheme,
+                    None,
+                    &aggregation_name,
+                    &batch_id,
+                    &date,
+                    BatchKind::Ingestion,
+                ),
+                &mut read_transport,
+            );
+        let base_path = format!("{}/{}/ingestion/{}", date, aggregation_name, batch_id);
+        let filenames = &[
+            "header".to_owned(),
+            "packets".to_owned(),
+            "signature".to_owned(),
+        ];
+        roundtrip_batch(
+            aggregation_name.to_string(),
+            batch_id,
+            base_path,
+            filenames,
+            &mut batch_writer,
+            &batch_reader,
+            &mut verify_transport,
+            &default_ingestor_private_key(),
+            &default_ingestor_public_key(),
+            true,
+        )
+    }
+
+    #[test]
+    fn server_identity() {
+        assert_eq!(ServerIdentity::from_is_first(true), ServerIdentity::Pha);
+        assert_eq!(
+            ServerIdentity::from_is_first(false),
+            ServerIdentity::Facilitator
+        );
+        assert_eq!(ServerIdentity::Pha.peer(), ServerIdentity::Facilitator);
+        assert_eq!(ServerIdentity::Facilitator.peer(), ServerIdentity::Pha);
+        for server in &[ServerIdentity::Pha, ServerIdentity::Facilitator] {
+            assert_eq!(
+                ServerIdentity::from_str(&server.to_string()).unwrap(),
+                *server
+            );
+        }
+        ServerIdentity::from_str("first").unwrap_err();
+    }
+
     #[test]
     fn roundtrip_sum_batch_first_ok() {
         roundtrip_sum_batch(true, true)
@@ -554,32 +4908,28 @@ mod tests {
     }
 
     fn roundtrip_sum_batch(is_first: bool, keys_match: bool) {
+        let server = ServerIdentity::from_is_first(is_first);
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
 
-        let aggregation_name = "fake-aggregation";
+        let aggregation_name = AggregationName::new("fake-aggregation").unwrap();
         let batch_id = Uuid::new_v4();
-        let start = NaiveDateTime::from_timestamp(1234567890, 654321);
-        let end = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let start = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let end = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
 
         let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
-                Batch::new_sum(&aggregation_name, &start, &end, is_first),
+                Batch::new_sum(None, &aggregation_name, &start, &end, server),
                 &mut write_transport,
             );
         let batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
-                Batch::new_sum(&aggregation_name, &start, &end, is_first),
+                Batch::new_sum(None, &aggregation_name, &start, &end, server),
                 &mut read_transport,
             );
-        let batch_path = format!(
-            "{}/{}-{}",
-            aggregation_name,
-            start.format(DATE_FORMAT),
-            end.format(DATE_FORMAT)
-        );
+        let batch_path = format!("{}/{}-{}", aggregation_name, start, end);
         let first_filenames = &[
             "sum_0".to_owned(),
             "invalid_uuid_0.avro".to_owned(),
@@ -1,26 +1,45 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::{prelude::Utc, NaiveDateTime};
+use chrono::Duration;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use prio::encrypt::PrivateKey;
-use ring::signature::{
-    EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
-    ECDSA_P256_SHA256_FIXED_SIGNING,
-};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
 use rusoto_core::Region;
-use std::{path::Path, str::FromStr};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter},
+    num::{NonZeroU32, NonZeroUsize},
+    path::Path,
+    str::FromStr,
+};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 use facilitator::{
-    aggregation::BatchAggregator,
-    intake::BatchIntaker,
-    sample::generate_ingestion_sample,
+    aggregation::{AggregationTransport, BatchAggregator},
+    batch::{
+        AggregationName, Batch, BatchDate, BatchDateWindow, BatchFileKind, BatchIdentity,
+        BatchNaming, Clock, DefaultBatchNamingScheme, InstanceName, PacketPartNaming, PathLayout,
+        ServerIdentity, SystemClock, ValidationNaming, DEFAULT_NAMING_SCHEME,
+    },
+    config::{FacilitatorConfig, KeyConfig, LimitConfig, ToggleConfig, TransportConfig},
+    export::export_validation_csv,
+    intake::{
+        BatchIntaker, BatchIntakerBuilder, IntakeProgress, OutputOrdering, PacketFailurePolicy,
+    },
+    keygen::{signing_key_pair_from_base64, signing_public_key_from_base64, write_key_files},
+    preflight::Preflight,
+    resign::resign_validation_batch,
+    sample::{generate_ingestion_sample, generate_multipart_ingestion_sample},
     test_utils::{
         DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY,
         DEFAULT_INGESTOR_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
         DEFAULT_PHA_SIGNING_PRIVATE_KEY,
     },
-    transport::{LocalFileTransport, S3Transport, Transport},
-    DATE_FORMAT,
+    transport::{
+        FanoutTransport, HttpTransport, LocalFileTransport, RetryPolicy, S3Transport, Stream,
+        StreamTransport, Transport, DEFAULT_RETRY_POLICY,
+    },
+    Error,
 };
 
 fn num_validator<F: FromStr>(s: String) -> Result<(), String> {
@@ -29,10 +48,43 @@ fn num_validator<F: FromStr>(s: String) -> Result<(), String> {
         .map_err(|_| "could not parse value as number".to_owned())
 }
 
+fn fraction_validator(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+        _ => Err("value must be a number between 0 and 1".to_owned()),
+    }
+}
+
+fn epsilon_validator(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(f) if f.is_finite() && f > 0.0 => Ok(()),
+        _ => Err("value must be a finite number greater than 0".to_owned()),
+    }
+}
+
+/// Returns the batch date window given by the max-batch-date-future-skew and
+/// max-batch-age settings, if they are present.
+fn batch_date_window(limits: &LimitConfig) -> Option<BatchDateWindow> {
+    Some(BatchDateWindow::new(
+        Duration::seconds(limits.max_batch_date_future_skew?.into()),
+        Duration::seconds(limits.max_batch_age?.into()),
+    ))
+}
+
 fn date_validator(s: String) -> Result<(), String> {
-    NaiveDateTime::parse_from_str(&s, DATE_FORMAT)
+    BatchDate::from_str(&s)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn aggregation_name_validator(s: String) -> Result<(), String> {
+    AggregationName::new(&s)
         .map(|_| ())
-        .map_err(|e| format!("{} {}", s, e.to_string()))
+        .map_err(|e| e.to_string())
+}
+
+fn instance_name_validator(s: String) -> Result<(), String> {
+    InstanceName::new(&s).map(|_| ()).map_err(|e| e.to_string())
 }
 
 fn b64_validator(s: String) -> Result<(), String> {
@@ -45,6 +97,7 @@ fn uuid_validator(s: String) -> Result<(), String> {
 
 enum StoragePath<'a> {
     S3Path { region: &'a str, bucket: &'a str },
+    HttpUrl(&'a str),
     LocalPath(&'a str),
 }
 
@@ -66,6 +119,9 @@ fn parse_path(s: &str) -> Result<StoragePath> {
             assert!(components.next().is_none());
             Ok(StoragePath::S3Path { region, bucket })
         }
+        None if s.starts_with("http://") || s.starts_with("https://") => {
+            Ok(StoragePath::HttpUrl(s))
+        }
         None => Ok(StoragePath::LocalPath(s)),
     }
 }
@@ -121,11 +177,24 @@ fn main() -> Result<(), anyhow::Error> {
                             formatted as \"s3://{region}/{bucket-name}\"",
                         ),
                 )
+                .arg(
+                    Arg::with_name("instance-name")
+                        .long("instance-name")
+                        .value_name("NAME")
+                        .validator(instance_name_validator)
+                        .help("Name of this facilitator instance")
+                        .long_help(
+                            "Name of this facilitator instance. If specified, \
+                            it is used as the leading segment of all batch \
+                            keys, allowing several instances to share buckets.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("aggregation-id")
                         .long("aggregation-id")
                         .value_name("ID")
                         .default_value("fake-aggregation")
+                        .validator(aggregation_name_validator)
                         .help("Name of the aggregation"),
                 )
                 .arg(
@@ -248,16 +317,67 @@ fn main() -> Result<(), anyhow::Error> {
                         .help("End of timespan covered by the batch, in milliseconds since epoch")
                         .default_value("1000000100")
                         .validator(num_validator::<i64>),
+                )
+                .arg(
+                    Arg::with_name("packet-file-parts")
+                        .long("packet-file-parts")
+                        .value_name("INT")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Number of parts to split each packet file into")
+                        .long_help(
+                            "Number of parts to split each packet file into, \
+                            listing the digest of each part in the header \
+                            instead of that of a single packet file. If \
+                            omitted, a single packet file is written.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("packet-part-naming")
+                        .long("packet-part-naming")
+                        .value_name("NAMING")
+                        .possible_values(&["shard", "numbered"])
+                        .default_value("shard")
+                        .help("Naming of the packet file parts")
+                        .long_help(
+                            "Naming of the packet file parts, if \
+                            --packet-file-parts is given. See \
+                            --ingestion-packet-part-naming.",
+                        ),
                 ),
         )
         .subcommand(
             SubCommand::with_name("batch-intake")
                 .about("Validate an ingestion share and emit a validation share.")
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("TOML or YAML file of settings")
+                        .long_help(
+                            "TOML (\".toml\") or YAML (\".yaml\", \".yml\") \
+                            file of settings, named after the flags they \
+                            correspond to. Flags given on the command line take \
+                            precedence over the file.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("instance-name")
+                        .long("instance-name")
+                        .value_name("NAME")
+                        .validator(instance_name_validator)
+                        .help("Name of this facilitator instance")
+                        .long_help(
+                            "Name of this facilitator instance. If specified, \
+                            it is used as the leading segment of all batch \
+                            keys, allowing several instances to share buckets.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("aggregation-id")
                         .long("aggregation-id")
                         .value_name("ID")
                         .default_value("fake-aggregation")
+                        .validator(aggregation_name_validator)
                         .help("Name of the aggregation"),
                 )
                 .arg(
@@ -308,6 +428,22 @@ fn main() -> Result<(), anyhow::Error> {
                         .hide_default_value(true)
                         .validator(b64_validator),
                 )
+                .arg(
+                    Arg::with_name("additional-ingestor-public-key")
+                        .long("additional-ingestor-public-key")
+                        .value_name("B64")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Further base64 encoded public key for the ingestor")
+                        .long_help(
+                            "Base64 encoded ECDSA P256 public key with which \
+                            ingestion headers may also be signed, e.g. while \
+                            the ingestor rotates its key. May be given more \
+                            than once. Keys are tried in order, after \
+                            ingestor-public-key.",
+                        )
+                        .validator(b64_validator),
+                )
                 .arg(
                     Arg::with_name("share-processor-private-key")
                         .long("share-processor-private-key")
@@ -326,6 +462,309 @@ fn main() -> Result<(), anyhow::Error> {
                     "Whether this is the \"first\" server receiving a share, \
                     i.e., the PHA.",
                 ))
+                .arg(
+                    Arg::with_name("legacy-validation-naming")
+                        .long("legacy-validation-naming")
+                        .help(
+                            "Name validation batches \"validity_0\" and \
+                            \"validity_1\" rather than after the server \
+                            identity, for compatibility with older peers.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("ingestion-path-layout")
+                        .long("ingestion-path-layout")
+                        .value_name("LAYOUT")
+                        .possible_values(&["flat", "date-partitioned"])
+                        .default_value("flat")
+                        .help("Layout of the keys of ingestion batches")
+                        .long_help(
+                            "Layout of the keys of ingestion batches. \"flat\" \
+                            stores batches under \
+                            \"{aggregation}/{date}/{uuid}\", \
+                            \"date-partitioned\" under \
+                            \"{date}/{aggregation}/{uuid}\".",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("validation-path-layout")
+                        .long("validation-path-layout")
+                        .value_name("LAYOUT")
+                        .possible_values(&["flat", "date-partitioned"])
+                        .default_value("flat")
+                        .help("Layout of the keys of validation batches")
+                        .long_help(
+                            "Layout of the keys of validation batches. See \
+                            --ingestion-path-layout.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("ingestion-header-suffix")
+                        .long("ingestion-header-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch")
+                        .empty_values(true)
+                        .help("Suffix of the keys of ingestion batch headers"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-packet-suffix")
+                        .long("ingestion-packet-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch.avro")
+                        .help("Suffix of the keys of ingestion batch packet files"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-signature-suffix")
+                        .long("ingestion-signature-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch.sig")
+                        .help("Suffix of the keys of ingestion batch signatures"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-packet-part-naming")
+                        .long("ingestion-packet-part-naming")
+                        .value_name("NAMING")
+                        .possible_values(&["shard", "numbered"])
+                        .default_value("shard")
+                        .help("Naming of the parts of split ingestion packet files")
+                        .long_help(
+                            "Naming of the parts of ingestion packet files that \
+                            are split across several objects, whose digests the \
+                            ingestion header lists. \"shard\" parts are stored \
+                            under \"<packet file key>.shard_<index>\", \
+                            \"numbered\" ones under \"<packet file key>-000\", \
+                            \"<packet file key>-001\" and so on.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("allow-empty-batches")
+                        .long("allow-empty-batches")
+                        .help(
+                            "Emit an empty validation batch for an ingestion \
+                            batch containing no packets, instead of failing.",
+                        ),
+                )
+                .arg(Arg::with_name("verify-only").long("verify-only").help(
+                    "Check the ingestion batch, including every packet, \
+                            without writing a validation batch or archiving \
+                            anything.",
+                ))
+                .arg(
+                    Arg::with_name("overwrite")
+                        .long("overwrite")
+                        .alias("force")
+                        .help(
+                            "Validate the ingestion batch even if a validation \
+                            batch or done marker for it has already been \
+                            written, replacing it. --force is an alias.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("worker-threads")
+                        .long("worker-threads")
+                        .value_name("INT")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Number of threads used to validate packets")
+                        .long_help(
+                            "Number of threads used to validate packets. If \
+                            not specified, one thread per logical CPU is used.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("validation-attempt")
+                        .long("validation-attempt")
+                        .value_name("INT")
+                        .default_value("0")
+                        .validator(num_validator::<u32>)
+                        .help("Attempt number of the validation batch to write")
+                        .long_help(
+                            "Attempt number of the validation batch to write. \
+                            Attempts other than 0 are written to keys with a \
+                            \".retry-<attempt>\" suffix, leaving the output of \
+                            earlier attempts in place.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("write-manifest")
+                        .long("write-manifest")
+                        .help(
+                            "Also write a signed manifest listing the validation \
+                            batch's files, their SHA-256 digests and sizes and \
+                            the number of packets.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("write-done-marker")
+                        .long("write-done-marker")
+                        .help("Mark the batch done once its validation batch is written")
+                        .long_help(
+                            "Once the validation batch has been written, write \
+                            a marker recording the digest of its packet file, \
+                            and skip any later run over a batch that has one \
+                            unless --overwrite is given, so that a batch handed \
+                            over twice is only validated once.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("sort-validation-packets")
+                        .long("sort-validation-packets")
+                        .help("Write validation packets in order of UUID")
+                        .long_help(
+                            "Write validation packets in order of UUID rather \
+                            than in the order of the ingestion packets, so that \
+                            the packet file can be compared byte for byte with \
+                            that of a peer that sorts. The whole batch's \
+                            validation packets are held in memory until it is \
+                            validated.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("verify-after-write")
+                        .long("verify-after-write")
+                        .help("Read back and verify the validation batch after writing it")
+                        .long_help(
+                            "Read back the validation batch after writing it, \
+                            verify its signature and count its packets, failing \
+                            if it differs from what was written. This doubles \
+                            reads from the validation bucket.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-packet-file-size")
+                        .long("max-packet-file-size")
+                        .value_name("BYTES")
+                        .validator(num_validator::<u64>)
+                        .help("Largest ingestion packet file that will be downloaded")
+                        .long_help(
+                            "Largest ingestion packet file, or packet file \
+                            shard, in bytes, that will be downloaded. Larger \
+                            batches are refused. If not specified, there is \
+                            no limit.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("read-buffer-size")
+                        .long("read-buffer-size")
+                        .value_name("BYTES")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Size of the buffer through which packet files are decoded")
+                        .long_help(
+                            "Size in bytes of the buffer through which ingestion \
+                            packet files that were spooled to disk are read \
+                            back and decoded.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("copy-buffer-size")
+                        .long("copy-buffer-size")
+                        .value_name("BYTES")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Size of the buffer through which packet files are downloaded")
+                        .long_help(
+                            "Size in bytes of the buffer through which ingestion \
+                            packet files are downloaded. Larger buffers move \
+                            data faster over network transports; smaller ones \
+                            use less memory. Defaults to 1 MiB.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("spool-threshold")
+                        .long("spool-threshold")
+                        .value_name("BYTES")
+                        .validator(num_validator::<usize>)
+                        .help("Size above which packet files are spooled to disk")
+                        .long_help(
+                            "Number of bytes of a downloaded packet file that are \
+                            held in memory before the rest is spooled to a \
+                            temporary file, which bounds the memory a batch \
+                            takes however large it is. Defaults to 1 MiB.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("transport-retry-attempts")
+                        .long("transport-retry-attempts")
+                        .value_name("ATTEMPTS")
+                        .validator(num_validator::<NonZeroU32>)
+                        .help("Attempts at each transiently failing transport operation")
+                        .long_help(
+                            "Number of times each fetch of an ingestion file and \
+                            each upload of a validation file is attempted when it \
+                            fails with a transient error, such as a timeout, a \
+                            reset connection or an S3 server error. A retried \
+                            upload starts the file over. 1 disables retries. \
+                            Defaults to 3.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-packet-failure-fraction")
+                        .long("max-packet-failure-fraction")
+                        .value_name("FRACTION")
+                        .validator(fraction_validator)
+                        .help("Skip packets that fail validation rather than the whole batch")
+                        .long_help(
+                            "Leave packets that fail validation out of the \
+                            validation batch and record them next to it, rather \
+                            than failing the whole batch, unless more than this \
+                            fraction, between 0 and 1, of the batch's packets \
+                            fail. If not specified, any failure fails the batch.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-epsilon")
+                        .long("max-epsilon")
+                        .value_name("EPSILON")
+                        .validator(epsilon_validator)
+                        .help("Largest epsilon ingestion headers may declare")
+                        .long_help(
+                            "Reject batches whose ingestion header declares an \
+                            epsilon greater than this. Defaults to 100. Entries \
+                            for particular aggregations in the \
+                            max-epsilon-by-aggregation table of the limits in a \
+                            configuration file take precedence.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("min-epsilon")
+                        .long("min-epsilon")
+                        .value_name("EPSILON")
+                        .validator(epsilon_validator)
+                        .help("Smallest epsilon ingestion headers may declare")
+                        .long_help(
+                            "Reject batches whose ingestion header declares an \
+                            epsilon less than this. If not specified, any \
+                            epsilon greater than 0 and no greater than \
+                            max-epsilon is accepted. Entries for particular \
+                            aggregations in the min-epsilon-by-aggregation table \
+                            of the limits in a configuration file take \
+                            precedence.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-batch-date-future-skew")
+                        .long("max-batch-date-future-skew")
+                        .value_name("SECONDS")
+                        .validator(num_validator::<u32>)
+                        .requires("max-batch-age")
+                        .help("How far in the future batches may be dated")
+                        .long_help(
+                            "How far in the future, in seconds, batches may be \
+                            dated. Batches dated outside the window given by \
+                            this and --max-batch-age are rejected. If neither \
+                            is specified, batches of any date are accepted.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-batch-age")
+                        .long("max-batch-age")
+                        .value_name("SECONDS")
+                        .validator(num_validator::<u32>)
+                        .requires("max-batch-date-future-skew")
+                        .help("How far in the past batches may be dated")
+                        .long_help(
+                            "How far in the past, in seconds, batches may be \
+                            dated. See --max-batch-date-future-skew.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("ingestion-bucket")
                         .long("ingestion-bucket")
@@ -350,16 +789,210 @@ fn main() -> Result<(), anyhow::Error> {
                             filesystem path or an S3 bucket, formatted as \
                             \"s3://{region}/{bucket-name}\"",
                         ),
+                )
+                .arg(
+                    Arg::with_name("validation-audit-bucket")
+                        .long("validation-audit-bucket")
+                        .value_name("DIR")
+                        .validator(path_validator)
+                        .help(
+                            "Bucket into which to also write validation shares, \
+                            e.g. for auditing. Failing to write to it fails \
+                            the batch. May be either a local filesystem path \
+                            or an S3 bucket, formatted as \
+                            \"s3://{region}/{bucket-name}\"",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate-stream")
+                .about("Validate a single ingestion share read from files or standard input.")
+                .long_about(
+                    "Validate a single ingestion share read from files or \
+                    standard input and write the validation share to files \
+                    or standard output, without a bucket or directory to hold \
+                    them. An argument of \"-\" means standard input or \
+                    output, which at most one input and one output may use.",
+                )
+                .arg(
+                    Arg::with_name("aggregation-id")
+                        .long("aggregation-id")
+                        .value_name("ID")
+                        .default_value("fake-aggregation")
+                        .validator(aggregation_name_validator)
+                        .help("Name of the aggregation"),
+                )
+                .arg(
+                    Arg::with_name("batch-id")
+                        .long("batch-id")
+                        .value_name("UUID")
+                        .required(true)
+                        .help("UUID of the batch")
+                        .validator(uuid_validator),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .long("date")
+                        .value_name("DATE")
+                        .help("Date for the batch in YYYY/mm/dd/HH/MM format")
+                        .long_help(
+                            "Date for the batch in YYYY/mm/dd/HH/MM format. If \
+                            omitted, the current date is used.",
+                        )
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("ecies-private-key")
+                        .long("ecies-private-key")
+                        .value_name("B64")
+                        .help("Base64 encoded ECIES private key")
+                        .long_help(
+                            "Base64 encoded ECIES private key. If not \
+                            specified, a fixed private key will be used.",
+                        )
+                        .default_value(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY)
+                        .hide_default_value(true)
+                        .validator(b64_validator),
+                )
+                .arg(
+                    Arg::with_name("ingestor-public-key")
+                        .long("ingestor-public-key")
+                        .value_name("B64")
+                        .help("Base64 encoded public key for the ingestor")
+                        .long_help(
+                            "Base64 encoded ECDSA P256 public key for the \
+                            ingestor. If not specified, a default key will be \
+                            used.",
+                        )
+                        .default_value(DEFAULT_INGESTOR_PRIVATE_KEY)
+                        .hide_default_value(true)
+                        .validator(b64_validator),
+                )
+                .arg(
+                    Arg::with_name("share-processor-private-key")
+                        .long("share-processor-private-key")
+                        .value_name("B64")
+                        .help("Base64 encoded share processor private key for the server")
+                        .long_help(
+                            "Base64 encoded ECDSA P256 share processor private \
+                            key. If not specified, a fixed private key will be \
+                            used.",
+                        )
+                        .default_value(DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY)
+                        .hide_default_value(true)
+                        .validator(b64_validator),
+                )
+                .arg(Arg::with_name("is-first").long("is-first").help(
+                    "Whether this is the \"first\" server receiving a share, \
+                    i.e., the PHA.",
+                ))
+                .arg(
+                    Arg::with_name("allow-empty-batches")
+                        .long("allow-empty-batches")
+                        .help(
+                            "Emit an empty validation batch for an ingestion \
+                            batch containing no packets, instead of failing.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("worker-threads")
+                        .long("worker-threads")
+                        .value_name("INT")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Number of threads used to validate packets")
+                        .long_help(
+                            "Number of threads used to validate packets. If \
+                            not specified, one thread per logical CPU is used.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-packet-file-size")
+                        .long("max-packet-file-size")
+                        .value_name("BYTES")
+                        .validator(num_validator::<u64>)
+                        .help("Largest ingestion packet file that will be read")
+                        .long_help(
+                            "Largest ingestion packet file, in bytes, that \
+                            will be read. Larger batches are refused. If not \
+                            specified, there is no limit.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("ingestion-header")
+                        .long("ingestion-header")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File from which to read the ingestion batch header"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-packets")
+                        .long("ingestion-packets")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File from which to read the ingestion batch packet file"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-signature")
+                        .long("ingestion-signature")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File from which to read the ingestion batch signature"),
+                )
+                .arg(
+                    Arg::with_name("validation-header")
+                        .long("validation-header")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File into which to write the validation batch header"),
+                )
+                .arg(
+                    Arg::with_name("validation-packets")
+                        .long("validation-packets")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File into which to write the validation batch packet file"),
+                )
+                .arg(
+                    Arg::with_name("validation-signature")
+                        .long("validation-signature")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("File into which to write the validation batch signature"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("aggregate")
                 .about("Verify peer validation share and emit sum part.")
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("TOML or YAML file of settings")
+                        .long_help(
+                            "TOML (\".toml\") or YAML (\".yaml\", \".yml\") \
+                            file of settings, named after the flags they \
+                            correspond to. Flags given on the command line take \
+                            precedence over the file.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("instance-name")
+                        .long("instance-name")
+                        .value_name("NAME")
+                        .validator(instance_name_validator)
+                        .help("Name of this facilitator instance")
+                        .long_help(
+                            "Name of this facilitator instance. If specified, \
+                            it is used as the leading segment of all batch \
+                            keys, allowing several instances to share buckets.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("aggregation-id")
                         .long("aggregation-id")
                         .value_name("ID")
                         .default_value("fake-aggregation")
+                        .validator(aggregation_name_validator)
                         .help("Name of the aggregation"),
                 )
                 .arg(
@@ -465,6 +1098,31 @@ fn main() -> Result<(), anyhow::Error> {
                             as \"s3://{region}/{bucket-name}\"",
                         ),
                 )
+                .arg(
+                    Arg::with_name("max-concurrent-batches")
+                        .long("max-concurrent-batches")
+                        .value_name("INT")
+                        .validator(num_validator::<NonZeroUsize>)
+                        .help("Number of batches to fetch and sum at once")
+                        .long_help(
+                            "Number of batches whose files are fetched and \
+                            summed at once. Each batch in flight holds up to \
+                            three packet files in memory. Defaults to 4.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("batch-start-jitter")
+                        .long("batch-start-jitter")
+                        .value_name("MILLIS")
+                        .validator(num_validator::<u64>)
+                        .help("Longest random wait before fetching each batch")
+                        .long_help(
+                            "Longest time, in milliseconds, that each fetcher \
+                            waits for a random time up to before it fetches a \
+                            batch, to spread out requests to the buckets. \
+                            Defaults to 0.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("ecies-private-key")
                         .long("ecies-private-key")
@@ -492,6 +1150,22 @@ fn main() -> Result<(), anyhow::Error> {
                         .hide_default_value(true)
                         .validator(b64_validator),
                 )
+                .arg(
+                    Arg::with_name("additional-ingestor-public-key")
+                        .long("additional-ingestor-public-key")
+                        .value_name("B64")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Further base64 encoded public key for the ingestor")
+                        .long_help(
+                            "Base64 encoded ECDSA P256 public key with which \
+                            ingestion headers may also be signed, e.g. while \
+                            the ingestor rotates its key. May be given more \
+                            than once. Keys are tried in order, after \
+                            ingestor-public-key.",
+                        )
+                        .validator(b64_validator),
+                )
                 .arg(
                     Arg::with_name("share-processor-private-key")
                         .long("share-processor-private-key")
@@ -523,13 +1197,258 @@ fn main() -> Result<(), anyhow::Error> {
                         .hide_default_value(true)
                         .validator(b64_validator),
                 )
-                .arg(Arg::with_name("is-first").long("is-first").help(
-                    "Whether this is the \"first\" server receiving a share, i.e., the PHA.",
-                )),
+                .arg(
+                    Arg::with_name("is-first").long("is-first").help(
+                        "Whether this is the \"first\" server receiving a share, i.e., the PHA.",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("legacy-validation-naming")
+                        .long("legacy-validation-naming")
+                        .help(
+                            "Name validation batches \"validity_0\" and \
+                            \"validity_1\" rather than after the server \
+                            identity, for compatibility with older peers.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("ingestion-path-layout")
+                        .long("ingestion-path-layout")
+                        .value_name("LAYOUT")
+                        .possible_values(&["flat", "date-partitioned"])
+                        .default_value("flat")
+                        .help("Layout of the keys of ingestion batches")
+                        .long_help(
+                            "Layout of the keys of ingestion batches. \"flat\" \
+                            stores batches under \
+                            \"{aggregation}/{date}/{uuid}\", \
+                            \"date-partitioned\" under \
+                            \"{date}/{aggregation}/{uuid}\".",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("validation-path-layout")
+                        .long("validation-path-layout")
+                        .value_name("LAYOUT")
+                        .possible_values(&["flat", "date-partitioned"])
+                        .default_value("flat")
+                        .help("Layout of the keys of validation batches")
+                        .long_help(
+                            "Layout of the keys of validation batches. See \
+                            --ingestion-path-layout.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("ingestion-header-suffix")
+                        .long("ingestion-header-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch")
+                        .empty_values(true)
+                        .help("Suffix of the keys of ingestion batch headers"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-packet-suffix")
+                        .long("ingestion-packet-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch.avro")
+                        .help("Suffix of the keys of ingestion batch packet files"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-signature-suffix")
+                        .long("ingestion-signature-suffix")
+                        .value_name("SUFFIX")
+                        .default_value(".batch.sig")
+                        .help("Suffix of the keys of ingestion batch signatures"),
+                )
+                .arg(
+                    Arg::with_name("ingestion-packet-part-naming")
+                        .long("ingestion-packet-part-naming")
+                        .value_name("NAMING")
+                        .possible_values(&["shard", "numbered"])
+                        .default_value("shard")
+                        .help("Naming of the parts of split ingestion packet files")
+                        .long_help(
+                            "Naming of the parts of ingestion packet files that \
+                            are split across several objects, whose digests the \
+                            ingestion header lists. \"shard\" parts are stored \
+                            under \"<packet file key>.shard_<index>\", \
+                            \"numbered\" ones under \"<packet file key>-000\", \
+                            \"<packet file key>-001\" and so on.",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("preflight")
+                .about("Check that keys and transports are usable before processing batches.")
+                .long_about(
+                    "Check that keys and transports are usable before processing batches. \
+                    Each key provided is parsed and checked for consistency, and a small value \
+                    is written to, read back from and deleted from each bucket provided. All \
+                    checks are run and reported, and the command fails if any of them fail.",
+                )
+                .arg(
+                    Arg::with_name("ecies-private-key")
+                        .long("ecies-private-key")
+                        .value_name("B64")
+                        .help("Base64 encoded ECIES private key"),
+                )
+                .arg(
+                    Arg::with_name("share-processor-private-key")
+                        .long("share-processor-private-key")
+                        .value_name("B64")
+                        .help("Base64 encoded share processor private key for the server"),
+                )
+                .arg(
+                    Arg::with_name("ingestor-public-key")
+                        .long("ingestor-public-key")
+                        .value_name("B64")
+                        .help("Base64 encoded public key for the ingestor"),
+                )
+                .arg(
+                    Arg::with_name("peer-share-processor-public-key")
+                        .long("peer-share-processor-public-key")
+                        .value_name("B64")
+                        .help("Base64 encoded public key for the peer share processor"),
+                )
+                .args(
+                    &[
+                        "ingestion-bucket",
+                        "validation-bucket",
+                        "peer-validation-bucket",
+                        "aggregation-bucket",
+                    ]
+                    .iter()
+                    .map(|name| {
+                        Arg::with_name(name)
+                            .long(name)
+                            .value_name("DIR")
+                            .validator(path_validator)
+                            .help(
+                                "Bucket to check. May be either a local filesystem path or an \
+                                S3 bucket, formatted as \"s3://{region}/{bucket-name}\"",
+                            )
+                    })
+                    .collect::<Vec<_>>(),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generate signing and ECIES key pairs")
+                .arg(
+                    Arg::with_name("output-dir")
+                        .long("output-dir")
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Local directory into which to write key files"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .default_value("facilitator")
+                        .help("Prefix for the names of the key files")
+                        .long_help(
+                            "Prefix for the names of the key files. Key files \
+                            are named e.g. \"{prefix}-ecies-private-key\". \
+                            Existing files are never overwritten.",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-validation-csv")
+                .about("Write the packets in a validation packet file as CSV")
+                .long_about(
+                    "Write the packets in a validation packet file as CSV, one \
+                    uuid,f_r,g_r,h_r row per packet after a header row. The \
+                    packet file is only read, and its signature is not \
+                    checked.",
+                )
+                .arg(
+                    Arg::with_name("packet-file")
+                        .long("packet-file")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("Local path of the validation packet file"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("PATH")
+                        .help("Local path to write the CSV to")
+                        .long_help(
+                            "Local path to write the CSV to. If not specified, \
+                            it is written to stdout. Existing files are never \
+                            overwritten.",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resign-batch")
+                .about("Re-sign an existing validation batch with a new key")
+                .long_about(
+                    "Re-sign an existing validation batch with a new key, e.g. \
+                    while rotating signing keys. The header's signature must \
+                    verify with the old public key and the packet file must \
+                    match the header. Neither is rewritten: only a new \
+                    signature over the same header is written.",
+                )
+                .arg(
+                    Arg::with_name("storage")
+                        .long("storage")
+                        .value_name("PATH")
+                        .required(true)
+                        .validator(path_validator)
+                        .help(
+                            "Storage containing the validation batch. May be \
+                            either a local filesystem path or an S3 bucket, \
+                            formatted as \"s3://{region}/{bucket-name}\"",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("header-key")
+                        .long("header-key")
+                        .value_name("KEY")
+                        .required(true)
+                        .help(
+                            "Key of the validation batch's header in the \
+                            storage, named according to the default naming scheme",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("old-public-key")
+                        .long("old-public-key")
+                        .value_name("B64")
+                        .required(true)
+                        .validator(b64_validator)
+                        .help(
+                            "Base64 encoded ECDSA P256 public key with which the \
+                            batch is currently signed",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("new-private-key")
+                        .long("new-private-key")
+                        .value_name("B64")
+                        .required(true)
+                        .validator(b64_validator)
+                        .help(
+                            "Base64 encoded ECDSA P256 private key with which to \
+                            re-sign the batch",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output-signature-key")
+                        .long("output-signature-key")
+                        .value_name("KEY")
+                        .help(
+                            "Key to write the new signature to. If not \
+                            specified, the batch's signature is replaced.",
+                        ),
+                ),
         )
         .get_matches();
 
-    let _verbose = matches.is_present("verbose");
+    let verbose = matches.is_present("verbose");
 
     match matches.subcommand() {
         // The configuration of the Args above should guarantee that the
@@ -540,128 +1459,334 @@ fn main() -> Result<(), anyhow::Error> {
             let mut facilitator_transport =
                 transport_for_output_path("facilitator-output", sub_matches)?;
 
-            generate_ingestion_sample(
-                &mut *pha_transport,
-                &mut *facilitator_transport,
-                &sub_matches
-                    .value_of("batch-id")
-                    .map_or_else(Uuid::new_v4, |v| Uuid::parse_str(v).unwrap()),
-                &sub_matches.value_of("aggregation-id").unwrap(),
-                &sub_matches.value_of("date").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
-                ),
-                &PrivateKey::from_base64(sub_matches.value_of("pha-ecies-private-key").unwrap())
-                    .unwrap(),
-                &PrivateKey::from_base64(
-                    sub_matches
-                        .value_of("facilitator-ecies-private-key")
-                        .unwrap(),
-                )
-                .unwrap(),
-                &base64::decode(sub_matches.value_of("ingestor-private-key").unwrap()).unwrap(),
-                sub_matches
-                    .value_of("dimension")
-                    .unwrap()
-                    .parse::<i32>()
-                    .unwrap(),
-                sub_matches
-                    .value_of("packet-count")
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap(),
-                sub_matches
-                    .value_of("epsilon")
-                    .unwrap()
-                    .parse::<f64>()
-                    .unwrap(),
-                sub_matches
-                    .value_of("batch-start-time")
-                    .unwrap()
-                    .parse::<i64>()
-                    .unwrap(),
+            let instance_name = sub_matches.value_of("instance-name");
+            let batch = batch_identity(sub_matches);
+            let pha_key =
+                PrivateKey::from_base64(sub_matches.value_of("pha-ecies-private-key").unwrap())
+                    .unwrap();
+            let facilitator_key = PrivateKey::from_base64(
                 sub_matches
-                    .value_of("batch-end-time")
-                    .unwrap()
-                    .parse::<i64>()
+                    .value_of("facilitator-ecies-private-key")
                     .unwrap(),
-            )?;
+            )
+            .unwrap();
+            let ingestor_key = Zeroizing::new(
+                base64::decode(sub_matches.value_of("ingestor-private-key").unwrap()).unwrap(),
+            );
+            let dim = sub_matches
+                .value_of("dimension")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap();
+            let packet_count = sub_matches
+                .value_of("packet-count")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            let epsilon = sub_matches
+                .value_of("epsilon")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap();
+            let batch_start_time = sub_matches
+                .value_of("batch-start-time")
+                .unwrap()
+                .parse::<i64>()
+                .unwrap();
+            let batch_end_time = sub_matches
+                .value_of("batch-end-time")
+                .unwrap()
+                .parse::<i64>()
+                .unwrap();
+
+            match sub_matches.value_of("packet-file-parts") {
+                Some(part_count) => generate_multipart_ingestion_sample(
+                    &mut *pha_transport,
+                    &mut *facilitator_transport,
+                    instance_name,
+                    &batch,
+                    &pha_key,
+                    &facilitator_key,
+                    &ingestor_key,
+                    dim,
+                    packet_count,
+                    epsilon,
+                    batch_start_time,
+                    batch_end_time,
+                    part_count.parse().unwrap(),
+                    PacketPartNaming::from_str(sub_matches.value_of("packet-part-naming").unwrap())
+                        .unwrap(),
+                )?,
+                None => generate_ingestion_sample(
+                    &mut *pha_transport,
+                    &mut *facilitator_transport,
+                    instance_name,
+                    &batch,
+                    &pha_key,
+                    &facilitator_key,
+                    &ingestor_key,
+                    dim,
+                    packet_count,
+                    epsilon,
+                    batch_start_time,
+                    batch_end_time,
+                )?,
+            };
             Ok(())
         }
         ("batch-intake", Some(sub_matches)) => {
+            let config = facilitator_config(sub_matches)?;
+            config.validate_for_intake()?;
+            // validate_for_intake checks that everything unwrapped below is
+            // present.
             let mut ingestion_transport =
-                transport_for_output_path("ingestion-bucket", sub_matches)?;
+                transport_for_path(config.transports.ingestion_bucket.as_ref().unwrap())?;
             let mut validation_transport =
-                transport_for_output_path("validation-bucket", sub_matches)?;
+                transport_for_path(config.transports.validation_bucket.as_ref().unwrap())?;
+            if let Some(audit_bucket) = &config.transports.validation_audit_bucket {
+                validation_transport = Box::new(FanoutTransport::new(vec![
+                    validation_transport,
+                    transport_for_path(audit_bucket)?,
+                ])?);
+            }
 
             let share_processor_ecies_key =
-                PrivateKey::from_base64(sub_matches.value_of("ecies-private-key").unwrap())
-                    .unwrap();
+                PrivateKey::from_base64(&config.keys.ecies_private_key()?.unwrap())
+                    .context("failed to parse value for ecies-private-key")?;
 
-            let ingestor_pub_key = public_key_from_arg("ingestor-public-key", sub_matches);
+            let ingestor_pub_key = public_key_from_setting(
+                "ingestor-public-key",
+                config.keys.ingestor_public_key.as_ref().unwrap(),
+            )?;
+            let additional_ingestor_pub_keys = additional_ingestor_public_keys(&config.keys)?;
+
+            let share_processor_key =
+                signing_key_pair_from_base64(&config.keys.share_processor_private_key()?.unwrap())
+                    .context("failed to parse value for share-processor-private-key")?;
 
-            let share_processor_key_bytes =
-                base64::decode(sub_matches.value_of("share-processor-private-key").unwrap())
+            let legacy_validation_naming = config.toggles.legacy_validation_naming.unwrap_or(false);
+            let ingestion_naming_scheme = naming_scheme(
+                sub_matches,
+                "ingestion-path-layout",
+                legacy_validation_naming,
+            )
+            .with_ingestion_naming(ingestion_naming(sub_matches)?);
+            let validation_naming_scheme = naming_scheme(
+                sub_matches,
+                "validation-path-layout",
+                legacy_validation_naming,
+            );
+            let mut print_progress = |progress: &IntakeProgress| eprintln!("{}", progress);
+            let mut builder = BatchIntakerBuilder::new(
+                &batch_identity(sub_matches),
+                ServerIdentity::from_is_first(config.is_first.unwrap_or(false)),
+                &share_processor_ecies_key,
+                &share_processor_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut *ingestion_transport)
+            .validation_transport(&mut *validation_transport)
+            .ingestion_naming_scheme(&ingestion_naming_scheme)
+            .validation_naming_scheme(&validation_naming_scheme)
+            .allow_empty_batches(config.toggles.allow_empty_batches.unwrap_or(false))
+            .worker_threads(config.limits.worker_threads)
+            .batch_date_window(batch_date_window(&config.limits))
+            .validation_attempt(
+                sub_matches
+                    .value_of("validation-attempt")
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            )
+            .write_manifest(config.toggles.write_manifest.unwrap_or(false))
+            .write_done_marker(config.toggles.write_done_marker.unwrap_or(false))
+            .output_ordering(if config.toggles.sort_validation_packets.unwrap_or(false) {
+                OutputOrdering::SortByUuid
+            } else {
+                OutputOrdering::PreserveInput
+            })
+            .verify_after_write(config.toggles.verify_after_write.unwrap_or(false))
+            .max_packet_file_size(config.limits.max_packet_file_size)
+            .overwrite(sub_matches.is_present("overwrite"));
+            if let Some(instance_name) = &config.instance_name {
+                builder = builder.instance_name(instance_name);
+            }
+            for key in &additional_ingestor_pub_keys {
+                builder = builder.additional_ingestor_key(key);
+            }
+            if verbose {
+                builder = builder.progress_callback(&mut print_progress);
+            }
+            if let Some(max_failure_fraction) = config.limits.max_packet_failure_fraction {
+                builder = builder.packet_failure_policy(PacketFailurePolicy::Record {
+                    max_failure_fraction,
+                });
+            }
+            if let Some(max_epsilon) = config
+                .limits
+                .max_epsilon_for(sub_matches.value_of("aggregation-id").unwrap())
+            {
+                builder = builder.max_epsilon(max_epsilon);
+            }
+            builder = builder.min_epsilon(
+                config
+                    .limits
+                    .min_epsilon_for(sub_matches.value_of("aggregation-id").unwrap()),
+            );
+            if let Some(size) = config.limits.read_buffer_size {
+                builder = builder.read_buffer_size(size);
+            }
+            if let Some(size) = config.limits.copy_buffer_size {
+                builder = builder.copy_buffer_size(size);
+            }
+            if let Some(threshold) = config.limits.spool_threshold {
+                builder = builder.spool_threshold(threshold);
+            }
+            if let Some(attempts) = config.limits.transport_retry_attempts {
+                builder = builder.transport_retry_policy(RetryPolicy {
+                    max_attempts: attempts,
+                    ..DEFAULT_RETRY_POLICY
+                });
+            }
+            let mut batch_intaker = builder.build()?;
+            let result = if sub_matches.is_present("verify-only") {
+                batch_intaker.verify_batch()
+            } else {
+                batch_intaker.generate_validation_share()
+            };
+            // Timings are logged whatever the outcome, so that slow batches
+            // can be diagnosed even if they eventually fail
+            let stats = match result {
+                Ok(stats) => {
+                    eprintln!("{}", stats.timings());
+                    stats
+                }
+                // A rerun of a batch that was already validated has nothing
+                // left to do
+                Err(e) if matches!(e.downcast_ref(), Some(Error::AlreadyProcessed(_))) => {
+                    if verbose {
+                        eprintln!("{}", e);
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("{}", batch_intaker.last_timings());
+                    return Err(e);
+                }
+            };
+            if verbose {
+                eprintln!("{}", stats);
+            }
+            Ok(())
+        }
+        ("validate-stream", Some(sub_matches)) => {
+            let batch = batch_identity(sub_matches);
+            let server_identity = ServerIdentity::from_is_first(sub_matches.is_present("is-first"));
+            let mut ingestion_transport = stream_transport(
+                &batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                &[
+                    "ingestion-header",
+                    "ingestion-packets",
+                    "ingestion-signature",
+                ],
+                Stream::input,
+                sub_matches,
+            )?;
+            let mut validation_transport = stream_transport(
+                &batch
+                    .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, server_identity)
+                    .into_batch(),
+                &[
+                    "validation-header",
+                    "validation-packets",
+                    "validation-signature",
+                ],
+                Stream::output,
+                sub_matches,
+            )?;
+
+            let share_processor_ecies_key =
+                PrivateKey::from_base64(sub_matches.value_of("ecies-private-key").unwrap())
                     .unwrap();
-            let share_processor_key = EcdsaKeyPair::from_pkcs8(
-                &ECDSA_P256_SHA256_FIXED_SIGNING,
-                &share_processor_key_bytes,
+            let ingestor_pub_key = public_key_from_arg("ingestor-public-key", sub_matches);
+            let share_processor_key = signing_key_pair_from_base64(
+                sub_matches.value_of("share-processor-private-key").unwrap(),
             )
             .context("failed to parse value for share-processor-private-key")?;
 
             let mut batch_intaker = BatchIntaker::new(
-                &sub_matches.value_of("aggregation-id").unwrap(),
-                &sub_matches
-                    .value_of("batch-id")
-                    .map_or_else(Uuid::new_v4, |v| Uuid::parse_str(v).unwrap()),
-                &sub_matches.value_of("date").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
-                ),
-                &mut *ingestion_transport,
-                &mut *validation_transport,
-                sub_matches.is_present("is-first"),
+                None,
+                &batch,
+                &mut ingestion_transport,
+                &mut validation_transport,
+                server_identity,
                 &share_processor_ecies_key,
                 &share_processor_key,
                 &ingestor_pub_key,
             )?;
-            batch_intaker.generate_validation_share()?;
+            batch_intaker.set_allow_empty_batches(sub_matches.is_present("allow-empty-batches"));
+            batch_intaker.set_worker_threads(
+                sub_matches
+                    .value_of("worker-threads")
+                    .map(|v| v.parse().unwrap()),
+            );
+            batch_intaker.set_max_packet_file_size(
+                sub_matches
+                    .value_of("max-packet-file-size")
+                    .map(|v| v.parse().unwrap()),
+            );
+            let result = batch_intaker.generate_validation_share();
+            eprintln!("{}", batch_intaker.last_timings());
+            let stats = result?;
+            if verbose {
+                eprintln!("{}", stats);
+            }
             Ok(())
         }
         ("aggregate", Some(sub_matches)) => {
+            let config = facilitator_config(sub_matches)?;
+            config.validate_for_aggregation()?;
+            // validate_for_aggregation checks that everything unwrapped below
+            // is present.
+            let transports = &config.transports;
             let mut ingestion_transport =
-                transport_for_output_path("ingestion-bucket", sub_matches)?;
+                transport_for_path(transports.ingestion_bucket.as_ref().unwrap())?;
             let mut own_validation_transport =
-                transport_for_output_path("own-validation-bucket", sub_matches)?;
+                transport_for_path(transports.own_validation_bucket.as_ref().unwrap())?;
             let mut peer_validation_transport =
-                transport_for_output_path("peer-validation-bucket", sub_matches)?;
+                transport_for_path(transports.peer_validation_bucket.as_ref().unwrap())?;
             let mut aggregation_transport =
-                transport_for_output_path("aggregation-bucket", sub_matches)?;
+                transport_for_path(transports.aggregation_bucket.as_ref().unwrap())?;
 
-            let ingestor_pub_key = public_key_from_arg("ingestor-public-key", sub_matches);
-            let peer_share_processor_pub_key =
-                public_key_from_arg("peer-share-processor-public-key", sub_matches);
-            let share_processor_key_bytes =
-                base64::decode(sub_matches.value_of("share-processor-private-key").unwrap())
-                    .unwrap();
-            let share_processor_key = EcdsaKeyPair::from_pkcs8(
-                &ECDSA_P256_SHA256_FIXED_SIGNING,
-                &share_processor_key_bytes,
-            )
-            .context("failed to parse value for share-processor-private-key")?;
+            let ingestor_pub_key = public_key_from_setting(
+                "ingestor-public-key",
+                config.keys.ingestor_public_key.as_ref().unwrap(),
+            )?;
+            let additional_ingestor_pub_keys = additional_ingestor_public_keys(&config.keys)?;
+            let peer_share_processor_pub_key = public_key_from_setting(
+                "peer-share-processor-public-key",
+                config
+                    .keys
+                    .peer_share_processor_public_key
+                    .as_ref()
+                    .unwrap(),
+            )?;
+            let share_processor_key =
+                signing_key_pair_from_base64(&config.keys.share_processor_private_key()?.unwrap())
+                    .context("failed to parse value for share-processor-private-key")?;
             let share_processor_ecies_key =
-                PrivateKey::from_base64(sub_matches.value_of("ecies-private-key").unwrap())
-                    .unwrap();
+                PrivateKey::from_base64(&config.keys.ecies_private_key()?.unwrap())
+                    .context("failed to parse value for ecies-private-key")?;
 
             let batch_ids: Vec<Uuid> = sub_matches
                 .values_of("batch-id")
                 .unwrap()
                 .map(|v| Uuid::parse_str(v).unwrap())
                 .collect();
-            let batch_dates: Vec<NaiveDateTime> = sub_matches
+            let batch_dates: Vec<BatchDate> = sub_matches
                 .values_of("batch-date")
                 .unwrap()
-                .map(|s| NaiveDateTime::parse_from_str(&s, DATE_FORMAT).unwrap())
+                .map(|s| BatchDate::from_str(s).unwrap())
                 .collect();
             if batch_ids.len() != batch_dates.len() {
                 return Err(anyhow!(
@@ -670,17 +1795,32 @@ fn main() -> Result<(), anyhow::Error> {
             }
 
             let batch_info: Vec<_> = batch_ids.into_iter().zip(batch_dates).collect();
-            BatchAggregator::new(
+            let aggregation_start = sub_matches.value_of("aggregation-start").map_or_else(
+                || BatchDate::new(&SystemClock.now()),
+                |v| BatchDate::from_str(v).unwrap(),
+            );
+            let aggregation_end = sub_matches.value_of("aggregation-end").map_or_else(
+                || BatchDate::new(&SystemClock.now()),
+                |v| BatchDate::from_str(v).unwrap(),
+            );
+            let legacy_validation_naming = config.toggles.legacy_validation_naming.unwrap_or(false);
+            let ingestion_naming_scheme = naming_scheme(
+                sub_matches,
+                "ingestion-path-layout",
+                legacy_validation_naming,
+            )
+            .with_ingestion_naming(ingestion_naming(sub_matches)?);
+            let validation_naming_scheme = naming_scheme(
+                sub_matches,
+                "validation-path-layout",
+                legacy_validation_naming,
+            );
+            let mut batch_aggregator = BatchAggregator::new(
+                config.instance_name.as_deref(),
                 &sub_matches.value_of("aggregation-id").unwrap(),
-                &sub_matches.value_of("aggregation-start").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
-                ),
-                &sub_matches.value_of("aggregation-end").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
-                ),
-                sub_matches.is_present("is-first"),
+                &aggregation_start,
+                &aggregation_end,
+                ServerIdentity::from_is_first(config.is_first.unwrap_or(false)),
                 &mut *ingestion_transport,
                 &mut *own_validation_transport,
                 &mut *peer_validation_transport,
@@ -689,34 +1829,304 @@ fn main() -> Result<(), anyhow::Error> {
                 &share_processor_key,
                 &peer_share_processor_pub_key,
                 &share_processor_ecies_key,
-            )?
-            .generate_sum_part(&batch_info)?;
+            )?;
+            for key in &additional_ingestor_pub_keys {
+                batch_aggregator.add_ingestor_key(key);
+            }
+            if let Some(max_concurrent_batches) = config.limits.max_concurrent_batches {
+                batch_aggregator.set_max_concurrent_batches(max_concurrent_batches);
+            }
+            if let Some(jitter) = config.limits.batch_start_jitter {
+                batch_aggregator.set_batch_start_jitter(std::time::Duration::from_millis(jitter));
+            }
+            batch_aggregator.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+            batch_aggregator.set_validation_naming_scheme(&validation_naming_scheme);
+            batch_aggregator.generate_sum_part(&batch_info)?;
+            Ok(())
+        }
+        ("preflight", Some(sub_matches)) => {
+            let mut preflight = Preflight::new();
+            if let Some(key) = sub_matches.value_of("ecies-private-key") {
+                preflight.check_ecies_private_key("ecies-private-key", key);
+            }
+            if let Some(key) = sub_matches.value_of("share-processor-private-key") {
+                preflight.check_signing_private_key("share-processor-private-key", key);
+            }
+            for arg in &["ingestor-public-key", "peer-share-processor-public-key"] {
+                if let Some(key) = sub_matches.value_of(arg) {
+                    preflight.check_signing_public_key(arg, key);
+                }
+            }
+            for arg in &[
+                "ingestion-bucket",
+                "validation-bucket",
+                "peer-validation-bucket",
+                "aggregation-bucket",
+            ] {
+                if !sub_matches.is_present(arg) {
+                    continue;
+                }
+                match transport_for_output_path(arg, sub_matches) {
+                    Ok(mut transport) => preflight.check_transport(arg, &mut *transport),
+                    Err(e) => preflight.record(format!("transport {}", arg), Err(e)),
+                }
+            }
+
+            print!("{}", preflight);
+            if !preflight.passed() {
+                let failures = preflight
+                    .results()
+                    .iter()
+                    .filter(|result| result.error.is_some())
+                    .count();
+                return Err(anyhow!(
+                    "{} of {} preflight checks failed",
+                    failures,
+                    preflight.results().len()
+                ));
+            }
+            Ok(())
+        }
+        ("keygen", Some(sub_matches)) => {
+            let paths = write_key_files(
+                Path::new(sub_matches.value_of("output-dir").unwrap()),
+                sub_matches.value_of("prefix").unwrap(),
+            )?;
+            for path in paths {
+                println!("wrote {}", path.display());
+            }
+            Ok(())
+        }
+        ("export-validation-csv", Some(sub_matches)) => {
+            let packet_file_path = sub_matches.value_of("packet-file").unwrap();
+            let packet_file = File::open(packet_file_path)
+                .with_context(|| format!("failed to open {}", packet_file_path))?;
+            let packet_file = BufReader::new(packet_file);
+            let rows = match sub_matches.value_of("output") {
+                Some(output_path) => {
+                    let output = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(output_path)
+                        .with_context(|| format!("failed to create {}", output_path))?;
+                    export_validation_csv(packet_file, &mut BufWriter::new(output))?
+                }
+                None => export_validation_csv(packet_file, &mut std::io::stdout().lock())?,
+            };
+            if verbose {
+                eprintln!("exported {} validation packets", rows);
+            }
+            Ok(())
+        }
+        ("resign-batch", Some(sub_matches)) => {
+            let mut transport = transport_for_path(sub_matches.value_of("storage").unwrap())?;
+            let batch = Batch::from_header_key(sub_matches.value_of("header-key").unwrap())?
+                .batch(&DEFAULT_NAMING_SCHEME, None);
+            let new_key =
+                signing_key_pair_from_base64(sub_matches.value_of("new-private-key").unwrap())
+                    .context("failed to parse value for new-private-key")?;
+            resign_validation_batch(
+                batch,
+                &mut *transport,
+                &public_key_from_arg("old-public-key", sub_matches),
+                &new_key,
+                sub_matches.value_of("output-signature-key"),
+            )?;
             Ok(())
         }
         (_, _) => Ok(()),
     }
 }
 
-fn public_key_from_arg(arg: &str, matches: &ArgMatches) -> UnparsedPublicKey<Vec<u8>> {
-    // UnparsedPublicKey::new doesn't return an error, so try parsing the
-    // argument as a private key first.
-    let key_bytes = base64::decode(matches.value_of(arg).unwrap()).unwrap();
-    match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_bytes) {
-        Ok(priv_key) => UnparsedPublicKey::new(
-            &ECDSA_P256_SHA256_FIXED,
-            Vec::from(priv_key.public_key().as_ref()),
+fn naming_scheme(
+    matches: &ArgMatches,
+    path_layout_arg: &str,
+    legacy_validation_naming: bool,
+) -> DefaultBatchNamingScheme {
+    DefaultBatchNamingScheme::new(if legacy_validation_naming {
+        ValidationNaming::Legacy
+    } else {
+        ValidationNaming::ServerIdentity
+    })
+    .with_path_layout(PathLayout::from_str(matches.value_of(path_layout_arg).unwrap()).unwrap())
+}
+
+/// Returns the identity of the batch specified by the aggregation-id, date and
+/// batch-id arguments, defaulting to the current date and a random batch ID.
+fn batch_identity(matches: &ArgMatches) -> BatchIdentity {
+    BatchIdentity::new(
+        AggregationName::new(matches.value_of("aggregation-id").unwrap()).unwrap(),
+        matches.value_of("date").map_or_else(
+            || BatchDate::new(&SystemClock.now()),
+            |v| BatchDate::from_str(v).unwrap(),
         ),
-        Err(_) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key_bytes),
+        matches
+            .value_of("batch-id")
+            .map_or_else(Uuid::new_v4, |v| Uuid::parse_str(v).unwrap()),
+    )
+}
+
+fn ingestion_naming(matches: &ArgMatches) -> Result<BatchNaming> {
+    Ok(BatchNaming::new(
+        matches.value_of("ingestion-header-suffix").unwrap(),
+        matches.value_of("ingestion-packet-suffix").unwrap(),
+        matches.value_of("ingestion-signature-suffix").unwrap(),
+    )
+    .context("invalid ingestion batch suffixes")?
+    .with_packet_part_naming(
+        PacketPartNaming::from_str(matches.value_of("ingestion-packet-part-naming").unwrap())
+            .unwrap(),
+    ))
+}
+
+fn public_key_from_arg(arg: &str, matches: &ArgMatches) -> UnparsedPublicKey<Vec<u8>> {
+    UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        signing_public_key_from_base64(matches.value_of(arg).unwrap()).unwrap(),
+    )
+}
+
+fn public_key_from_setting(name: &str, key: &str) -> Result<UnparsedPublicKey<Vec<u8>>> {
+    Ok(UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        signing_public_key_from_base64(key)
+            .with_context(|| format!("failed to parse value for {}", name))?,
+    ))
+}
+
+/// Parses the additional ingestor public keys in the configuration, if any.
+fn additional_ingestor_public_keys(keys: &KeyConfig) -> Result<Vec<UnparsedPublicKey<Vec<u8>>>> {
+    keys.additional_ingestor_public_keys
+        .iter()
+        .flatten()
+        .map(|key| public_key_from_setting("additional-ingestor-public-key", key))
+        .collect()
+}
+
+/// Returns the configuration given by the arguments and the --config file, if
+/// any. Arguments given on the command line take precedence over the file,
+/// which takes precedence over the arguments' defaults.
+fn facilitator_config(matches: &ArgMatches) -> Result<FacilitatorConfig> {
+    let mut config = config_from_args(matches, false);
+    if let Some(path) = matches.value_of("config") {
+        config.merge(FacilitatorConfig::from_file(Path::new(path))?);
+    }
+    config.merge(config_from_args(matches, true));
+    Ok(config)
+}
+
+/// Returns the configuration given by the arguments, optionally disregarding
+/// the ones that were not explicitly given and so have their default value.
+fn config_from_args(matches: &ArgMatches, explicit_only: bool) -> FacilitatorConfig {
+    let value = |name| {
+        if explicit_only && matches.occurrences_of(name) == 0 {
+            None
+        } else {
+            matches.value_of(name).map(str::to_owned)
+        }
+    };
+    let flag = |name| {
+        if matches.is_present(name) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    FacilitatorConfig {
+        instance_name: value("instance-name"),
+        is_first: flag("is-first"),
+        transports: TransportConfig {
+            ingestion_bucket: value("ingestion-bucket"),
+            validation_bucket: value("validation-bucket"),
+            validation_audit_bucket: value("validation-audit-bucket"),
+            own_validation_bucket: value("own-validation-bucket"),
+            peer_validation_bucket: value("peer-validation-bucket"),
+            aggregation_bucket: value("aggregation-bucket"),
+        },
+        keys: KeyConfig {
+            ecies_private_key: value("ecies-private-key"),
+            ecies_private_key_file: None,
+            ecies_private_key_secret: None,
+            share_processor_private_key: value("share-processor-private-key"),
+            share_processor_private_key_file: None,
+            share_processor_private_key_secret: None,
+            ingestor_public_key: value("ingestor-public-key"),
+            additional_ingestor_public_keys: matches
+                .values_of("additional-ingestor-public-key")
+                .map(|values| values.map(str::to_owned).collect()),
+            peer_share_processor_public_key: value("peer-share-processor-public-key"),
+        },
+        limits: LimitConfig {
+            worker_threads: value("worker-threads").map(|v| v.parse().unwrap()),
+            max_packet_file_size: value("max-packet-file-size").map(|v| v.parse().unwrap()),
+            max_packet_failure_fraction: value("max-packet-failure-fraction")
+                .map(|v| v.parse().unwrap()),
+            read_buffer_size: value("read-buffer-size").map(|v| v.parse().unwrap()),
+            copy_buffer_size: value("copy-buffer-size").map(|v| v.parse().unwrap()),
+            spool_threshold: value("spool-threshold").map(|v| v.parse().unwrap()),
+            transport_retry_attempts: value("transport-retry-attempts").map(|v| v.parse().unwrap()),
+            max_concurrent_batches: value("max-concurrent-batches").map(|v| v.parse().unwrap()),
+            batch_start_jitter: value("batch-start-jitter").map(|v| v.parse().unwrap()),
+            max_batch_age: value("max-batch-age").map(|v| v.parse().unwrap()),
+            max_batch_date_future_skew: value("max-batch-date-future-skew")
+                .map(|v| v.parse().unwrap()),
+            max_epsilon: value("max-epsilon").map(|v| v.parse().unwrap()),
+            max_epsilon_by_aggregation: None,
+            min_epsilon: value("min-epsilon").map(|v| v.parse().unwrap()),
+            min_epsilon_by_aggregation: None,
+        },
+        toggles: ToggleConfig {
+            allow_empty_batches: flag("allow-empty-batches"),
+            write_manifest: flag("write-manifest"),
+            write_done_marker: flag("write-done-marker"),
+            verify_after_write: flag("verify-after-write"),
+            legacy_validation_naming: flag("legacy-validation-naming"),
+            sort_validation_packets: flag("sort-validation-packets"),
+        },
     }
 }
 
+/// Returns a StreamTransport mapping the keys of the header, packet file and
+/// signature of the batch onto the streams named by the corresponding
+/// arguments, each interpreted by the provided function. At most one of the
+/// arguments may be "-", since standard input or output cannot be shared.
+fn stream_transport(
+    batch: &Batch,
+    args: &[&str; 3],
+    stream: fn(&str) -> Stream,
+    matches: &ArgMatches,
+) -> Result<StreamTransport> {
+    let stdio_args: Vec<_> = args
+        .iter()
+        .filter(|arg| matches.value_of(arg) == Some("-"))
+        .collect();
+    if stdio_args.len() > 1 {
+        return Err(anyhow!(
+            "at most one of {} may be \"-\", but {:?} are",
+            args.join(", "),
+            stdio_args
+        ));
+    }
+
+    let mut transport = StreamTransport::new();
+    for (kind, arg) in BatchFileKind::ALL.iter().zip(args) {
+        transport.map(batch.key(*kind), stream(matches.value_of(arg).unwrap()));
+    }
+    Ok(transport)
+}
+
 fn transport_for_output_path(arg: &str, matches: &ArgMatches) -> Result<Box<dyn Transport>> {
-    let path = parse_path(matches.value_of(arg).unwrap())?;
-    match path {
+    Ok(transport_for_path(matches.value_of(arg).unwrap())?)
+}
+
+fn transport_for_path(path: &str) -> Result<Box<AggregationTransport>> {
+    match parse_path(path)? {
         StoragePath::S3Path { region, bucket } => Ok(Box::new(S3Transport::new(
             Region::from_str(region)?,
             bucket.to_string(),
         ))),
+        // Read-only, for debugging against batches served over HTTP
+        StoragePath::HttpUrl(url) => Ok(Box::new(HttpTransport::new(url))),
         StoragePath::LocalPath(path) => Ok(Box::new(LocalFileTransport::new(
             Path::new(path).to_path_buf(),
         ))),
@@ -0,0 +1,908 @@
+use crate::{
+    batch::InstanceName,
+    secrets::{secret_source, FileSecretSource, SecretSource},
+    Error,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+/// Configuration for the share processor subcommands, which may be loaded from
+/// a TOML or YAML file. Every field is optional so that configurations can be
+/// layered with FacilitatorConfig::merge, e.g. command line flags over a
+/// configuration file over defaults. Field names match the command line flags
+/// they correspond to, e.g.:
+///
+/// ```toml
+/// instance-name = "narnia"
+/// is-first = true
+///
+/// [transports]
+/// ingestion-bucket = "s3://us-west-2/ingestion"
+///
+/// [keys]
+/// share-processor-private-key-file = "/secrets/signing-key"
+///
+/// [limits]
+/// max-packet-file-size = 419430400
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FacilitatorConfig {
+    pub instance_name: Option<String>,
+    pub is_first: Option<bool>,
+    pub transports: TransportConfig,
+    pub keys: KeyConfig,
+    pub limits: LimitConfig,
+    pub toggles: ToggleConfig,
+}
+
+/// Where batches are read from and written to. Each value is a local
+/// directory or an S3 bucket, formatted as "s3://{region}/{bucket-name}".
+/// Validation batches are also written to validation_audit_bucket, if set.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TransportConfig {
+    pub ingestion_bucket: Option<String>,
+    pub validation_bucket: Option<String>,
+    pub validation_audit_bucket: Option<String>,
+    pub own_validation_bucket: Option<String>,
+    pub peer_validation_bucket: Option<String>,
+    pub aggregation_bucket: Option<String>,
+}
+
+/// The keys a share processor uses. Private keys may be given inline, base64
+/// encoded, as the path of a file containing the base64 encoding, or as a
+/// reference to a secret containing it (see secrets::secret_source), but only
+/// one of these.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct KeyConfig {
+    pub ecies_private_key: Option<String>,
+    pub ecies_private_key_file: Option<PathBuf>,
+    pub ecies_private_key_secret: Option<String>,
+    pub share_processor_private_key: Option<String>,
+    pub share_processor_private_key_file: Option<PathBuf>,
+    pub share_processor_private_key_secret: Option<String>,
+    pub ingestor_public_key: Option<String>,
+    pub additional_ingestor_public_keys: Option<Vec<String>>,
+    pub peer_share_processor_public_key: Option<String>,
+}
+
+/// Limits on the work a share processor does. See the BatchIntaker setters of
+/// the same names.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitConfig {
+    pub worker_threads: Option<usize>,
+    pub max_packet_file_size: Option<u64>,
+    pub max_packet_failure_fraction: Option<f64>,
+    pub read_buffer_size: Option<usize>,
+    pub copy_buffer_size: Option<usize>,
+    pub spool_threshold: Option<usize>,
+    /// How many times each transport operation of a validation run is
+    /// attempted when it fails transiently. 1 disables retries.
+    pub transport_retry_attempts: Option<u32>,
+    pub max_concurrent_batches: Option<usize>,
+    /// In milliseconds
+    pub batch_start_jitter: Option<u64>,
+    /// In seconds
+    pub max_batch_age: Option<u32>,
+    /// In seconds
+    pub max_batch_date_future_skew: Option<u32>,
+    pub max_epsilon: Option<f64>,
+    pub min_epsilon: Option<f64>,
+    /// Replaces max-epsilon for batches of the aggregations named, e.g.:
+    ///
+    /// ```toml
+    /// [limits.max-epsilon-by-aggregation]
+    /// kittens-seen = 20.0
+    /// ```
+    pub max_epsilon_by_aggregation: Option<BTreeMap<String, f64>>,
+    /// Replaces min-epsilon for batches of the aggregations named, like
+    /// max-epsilon-by-aggregation
+    pub min_epsilon_by_aggregation: Option<BTreeMap<String, f64>>,
+}
+
+impl LimitConfig {
+    /// Returns the largest epsilon to accept in batches of the named
+    /// aggregation, if one is configured for it or for all aggregations.
+    pub fn max_epsilon_for(&self, aggregation_name: &str) -> Option<f64> {
+        self.max_epsilon_by_aggregation
+            .as_ref()
+            .and_then(|by_aggregation| by_aggregation.get(aggregation_name))
+            .copied()
+            .or(self.max_epsilon)
+    }
+
+    /// Returns the smallest epsilon to accept in batches of the named
+    /// aggregation, if one is configured for it or for all aggregations.
+    pub fn min_epsilon_for(&self, aggregation_name: &str) -> Option<f64> {
+        self.min_epsilon_by_aggregation
+            .as_ref()
+            .and_then(|by_aggregation| by_aggregation.get(aggregation_name))
+            .copied()
+            .or(self.min_epsilon)
+    }
+}
+
+/// Optional behaviors of a share processor, all of which default to off.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ToggleConfig {
+    pub allow_empty_batches: Option<bool>,
+    pub write_manifest: Option<bool>,
+    pub write_done_marker: Option<bool>,
+    pub verify_after_write: Option<bool>,
+    pub legacy_validation_naming: Option<bool>,
+    pub sort_validation_packets: Option<bool>,
+}
+
+/// Replaces the value in into with the one in from, if there is one.
+fn merge_option<T>(into: &mut Option<T>, from: Option<T>) {
+    if from.is_some() {
+        *into = from;
+    }
+}
+
+/// Like merge_option, but for a key that may be given inline, as a file or as
+/// a secret: if from gives the key any way, it replaces every way of giving it
+/// in into.
+fn merge_key_source(
+    into: (
+        &mut Option<String>,
+        &mut Option<PathBuf>,
+        &mut Option<String>,
+    ),
+    from: (Option<String>, Option<PathBuf>, Option<String>),
+) {
+    if from.0.is_some() || from.1.is_some() || from.2.is_some() {
+        *into.0 = from.0;
+        *into.1 = from.1;
+        *into.2 = from.2;
+    }
+}
+
+/// A key given inline in the configuration.
+#[derive(Debug)]
+struct InlineKey(Zeroizing<String>);
+
+impl SecretSource for InlineKey {
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(self.0.as_bytes().to_vec()))
+    }
+}
+
+/// Returns the source of the key given inline, in the file or as a secret, if
+/// any, without reading it. Fails if the key is given more than one way or
+/// the secret reference is malformed.
+fn key_source(
+    name: &str,
+    inline: &Option<String>,
+    file: &Option<PathBuf>,
+    secret: &Option<String>,
+) -> Result<Option<Box<dyn SecretSource>>> {
+    match (inline, file, secret) {
+        (None, None, None) => Ok(None),
+        (Some(key), None, None) => Ok(Some(Box::new(InlineKey(Zeroizing::new(key.clone()))))),
+        (None, Some(path), None) => Ok(Some(Box::new(FileSecretSource::new(path)))),
+        (None, None, Some(reference)) => Ok(Some(
+            secret_source(reference).with_context(|| format!("invalid {}-secret", name))?,
+        )),
+        _ => Err(Error::MalformedConfigError(format!(
+            "more than one of {}, {}-file and {}-secret are set",
+            name, name, name
+        ))
+        .into()),
+    }
+}
+
+/// Returns the content of the key given inline, in the file or as a secret,
+/// if any, with surrounding whitespace removed.
+fn resolve_key_source(
+    name: &str,
+    inline: &Option<String>,
+    file: &Option<PathBuf>,
+    secret: &Option<String>,
+) -> Result<Option<Zeroizing<String>>> {
+    let source = match key_source(name, inline, file, secret)? {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let key = source
+        .secret()
+        .with_context(|| format!("failed to read {}", name))?;
+    let key = std::str::from_utf8(&key).with_context(|| format!("{} is not UTF-8", name))?;
+    Ok(Some(Zeroizing::new(key.trim().to_owned())))
+}
+
+impl FacilitatorConfig {
+    /// Parses a configuration from TOML.
+    pub fn from_toml(toml: &str) -> Result<FacilitatorConfig> {
+        toml::from_str(toml).context("failed to parse TOML configuration")
+    }
+
+    /// Parses a configuration from YAML.
+    pub fn from_yaml(yaml: &str) -> Result<FacilitatorConfig> {
+        serde_yaml::from_str(yaml).context("failed to parse YAML configuration")
+    }
+
+    /// Loads a configuration from the file at the provided path, which is
+    /// parsed as TOML or YAML according to whether its extension is ".toml" or
+    /// ".yaml" or ".yml".
+    pub fn from_file(path: &Path) -> Result<FacilitatorConfig> {
+        let content = read_to_string(path)
+            .with_context(|| format!("failed to read configuration {}", path.display()))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => FacilitatorConfig::from_toml(&content),
+            Some("yaml") | Some("yml") => FacilitatorConfig::from_yaml(&content),
+            _ => Err(Error::MalformedConfigError(format!(
+                "cannot tell the format of configuration {} from its extension",
+                path.display()
+            ))
+            .into()),
+        }
+        .with_context(|| format!("in configuration {}", path.display()))
+    }
+
+    /// Encodes the configuration as TOML.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).context("failed to encode configuration as TOML")
+    }
+
+    /// Encodes the configuration as YAML.
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).context("failed to encode configuration as YAML")
+    }
+
+    /// Overrides the values in this configuration with those set in the
+    /// provided one.
+    pub fn merge(&mut self, overrides: FacilitatorConfig) {
+        merge_option(&mut self.instance_name, overrides.instance_name);
+        merge_option(&mut self.is_first, overrides.is_first);
+
+        let transports = overrides.transports;
+        merge_option(
+            &mut self.transports.ingestion_bucket,
+            transports.ingestion_bucket,
+        );
+        merge_option(
+            &mut self.transports.validation_bucket,
+            transports.validation_bucket,
+        );
+        merge_option(
+            &mut self.transports.validation_audit_bucket,
+            transports.validation_audit_bucket,
+        );
+        merge_option(
+            &mut self.transports.own_validation_bucket,
+            transports.own_validation_bucket,
+        );
+        merge_option(
+            &mut self.transports.peer_validation_bucket,
+            transports.peer_validation_bucket,
+        );
+        merge_option(
+            &mut self.transports.aggregation_bucket,
+            transports.aggregation_bucket,
+        );
+
+        let keys = overrides.keys;
+        merge_key_source(
+            (
+                &mut self.keys.ecies_private_key,
+                &mut self.keys.ecies_private_key_file,
+                &mut self.keys.ecies_private_key_secret,
+            ),
+            (
+                keys.ecies_private_key,
+                keys.ecies_private_key_file,
+                keys.ecies_private_key_secret,
+            ),
+        );
+        merge_key_source(
+            (
+                &mut self.keys.share_processor_private_key,
+                &mut self.keys.share_processor_private_key_file,
+                &mut self.keys.share_processor_private_key_secret,
+            ),
+            (
+                keys.share_processor_private_key,
+                keys.share_processor_private_key_file,
+                keys.share_processor_private_key_secret,
+            ),
+        );
+        merge_option(&mut self.keys.ingestor_public_key, keys.ingestor_public_key);
+        merge_option(
+            &mut self.keys.additional_ingestor_public_keys,
+            keys.additional_ingestor_public_keys,
+        );
+        merge_option(
+            &mut self.keys.peer_share_processor_public_key,
+            keys.peer_share_processor_public_key,
+        );
+
+        let limits = overrides.limits;
+        merge_option(&mut self.limits.worker_threads, limits.worker_threads);
+        merge_option(
+            &mut self.limits.max_packet_file_size,
+            limits.max_packet_file_size,
+        );
+        merge_option(
+            &mut self.limits.max_packet_failure_fraction,
+            limits.max_packet_failure_fraction,
+        );
+        merge_option(&mut self.limits.read_buffer_size, limits.read_buffer_size);
+        merge_option(&mut self.limits.copy_buffer_size, limits.copy_buffer_size);
+        merge_option(&mut self.limits.spool_threshold, limits.spool_threshold);
+        merge_option(
+            &mut self.limits.transport_retry_attempts,
+            limits.transport_retry_attempts,
+        );
+        merge_option(
+            &mut self.limits.max_concurrent_batches,
+            limits.max_concurrent_batches,
+        );
+        merge_option(
+            &mut self.limits.batch_start_jitter,
+            limits.batch_start_jitter,
+        );
+        merge_option(&mut self.limits.max_batch_age, limits.max_batch_age);
+        merge_option(
+            &mut self.limits.max_batch_date_future_skew,
+            limits.max_batch_date_future_skew,
+        );
+        merge_option(&mut self.limits.max_epsilon, limits.max_epsilon);
+        merge_option(
+            &mut self.limits.max_epsilon_by_aggregation,
+            limits.max_epsilon_by_aggregation,
+        );
+        merge_option(&mut self.limits.min_epsilon, limits.min_epsilon);
+        merge_option(
+            &mut self.limits.min_epsilon_by_aggregation,
+            limits.min_epsilon_by_aggregation,
+        );
+
+        let toggles = overrides.toggles;
+        merge_option(
+            &mut self.toggles.allow_empty_batches,
+            toggles.allow_empty_batches,
+        );
+        merge_option(&mut self.toggles.write_manifest, toggles.write_manifest);
+        merge_option(
+            &mut self.toggles.write_done_marker,
+            toggles.write_done_marker,
+        );
+        merge_option(
+            &mut self.toggles.verify_after_write,
+            toggles.verify_after_write,
+        );
+        merge_option(
+            &mut self.toggles.legacy_validation_naming,
+            toggles.legacy_validation_naming,
+        );
+        merge_option(
+            &mut self.toggles.sort_validation_packets,
+            toggles.sort_validation_packets,
+        );
+    }
+
+    /// Checks that the values that are set are consistent with each other and
+    /// within range. Keys are not read, so a missing key file or secret is
+    /// only found when the key is.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(instance_name) = &self.instance_name {
+            InstanceName::new(instance_name)?;
+        }
+        self.keys.ecies_private_key_source()?;
+        self.keys.share_processor_private_key_source()?;
+
+        let limits = &self.limits;
+        for (name, value) in &[
+            ("worker-threads", limits.worker_threads),
+            ("read-buffer-size", limits.read_buffer_size),
+            ("copy-buffer-size", limits.copy_buffer_size),
+            ("max-concurrent-batches", limits.max_concurrent_batches),
+            (
+                "transport-retry-attempts",
+                limits
+                    .transport_retry_attempts
+                    .map(|attempts| attempts as usize),
+            ),
+        ] {
+            if *value == Some(0) {
+                return Err(
+                    Error::MalformedConfigError(format!("{} must not be zero", name)).into(),
+                );
+            }
+        }
+        if let Some(fraction) = limits.max_packet_failure_fraction {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(Error::MalformedConfigError(format!(
+                    "max-packet-failure-fraction is {} but must be between 0 and 1",
+                    fraction
+                ))
+                .into());
+            }
+        }
+        let epsilons = [
+            (
+                "max-epsilon",
+                limits.max_epsilon,
+                &limits.max_epsilon_by_aggregation,
+            ),
+            (
+                "min-epsilon",
+                limits.min_epsilon,
+                &limits.min_epsilon_by_aggregation,
+            ),
+        ];
+        for (setting, epsilon, by_aggregation) in &epsilons {
+            let by_aggregation = by_aggregation.iter().flat_map(|by_aggregation| {
+                by_aggregation
+                    .iter()
+                    .map(move |(aggregation_name, epsilon)| {
+                        (
+                            format!("{}-by-aggregation.{}", setting, aggregation_name),
+                            *epsilon,
+                        )
+                    })
+            });
+            for (name, epsilon) in epsilon
+                .map(|epsilon| (setting.to_string(), epsilon))
+                .into_iter()
+                .chain(by_aggregation)
+            {
+                if !(epsilon.is_finite() && epsilon > 0.0) {
+                    return Err(Error::MalformedConfigError(format!(
+                        "{} is {} but must be finite and greater than zero",
+                        name, epsilon
+                    ))
+                    .into());
+                }
+            }
+        }
+        // Where both bounds apply to an aggregation, or to all of them, the
+        // range they make must not be empty.
+        let aggregation_names = limits
+            .min_epsilon_by_aggregation
+            .iter()
+            .chain(&limits.max_epsilon_by_aggregation)
+            .flat_map(BTreeMap::keys)
+            .map(Some)
+            .chain(std::iter::once(None));
+        for aggregation_name in aggregation_names {
+            let (min_epsilon, max_epsilon) = match aggregation_name {
+                Some(aggregation_name) => (
+                    limits.min_epsilon_for(aggregation_name),
+                    limits.max_epsilon_for(aggregation_name),
+                ),
+                None => (limits.min_epsilon, limits.max_epsilon),
+            };
+            if let (Some(min_epsilon), Some(max_epsilon)) = (min_epsilon, max_epsilon) {
+                if min_epsilon > max_epsilon {
+                    return Err(Error::MalformedConfigError(format!(
+                        "minimum epsilon {} is greater than maximum epsilon {}{}",
+                        min_epsilon,
+                        max_epsilon,
+                        aggregation_name
+                            .map(|name| format!(" for aggregation {}", name))
+                            .unwrap_or_default()
+                    ))
+                    .into());
+                }
+            }
+        }
+        if limits.max_batch_age.is_some() != limits.max_batch_date_future_skew.is_some() {
+            return Err(Error::MalformedConfigError(
+                "max-batch-age and max-batch-date-future-skew must be set together".to_owned(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration and checks that it has everything batch
+    /// intake needs.
+    pub fn validate_for_intake(&self) -> Result<()> {
+        self.validate()?;
+        require(&[
+            (
+                "transports.ingestion-bucket",
+                self.transports.ingestion_bucket.is_some(),
+            ),
+            (
+                "transports.validation-bucket",
+                self.transports.validation_bucket.is_some(),
+            ),
+            ("keys.ecies-private-key", self.has_ecies_private_key()),
+            (
+                "keys.share-processor-private-key",
+                self.has_share_processor_private_key(),
+            ),
+            (
+                "keys.ingestor-public-key",
+                self.keys.ingestor_public_key.is_some(),
+            ),
+        ])
+    }
+
+    /// Validates the configuration and checks that it has everything
+    /// aggregation needs.
+    pub fn validate_for_aggregation(&self) -> Result<()> {
+        self.validate()?;
+        require(&[
+            (
+                "transports.ingestion-bucket",
+                self.transports.ingestion_bucket.is_some(),
+            ),
+            (
+                "transports.own-validation-bucket",
+                self.transports.own_validation_bucket.is_some(),
+            ),
+            (
+                "transports.peer-validation-bucket",
+                self.transports.peer_validation_bucket.is_some(),
+            ),
+            (
+                "transports.aggregation-bucket",
+                self.transports.aggregation_bucket.is_some(),
+            ),
+            ("keys.ecies-private-key", self.has_ecies_private_key()),
+            (
+                "keys.share-processor-private-key",
+                self.has_share_processor_private_key(),
+            ),
+            (
+                "keys.ingestor-public-key",
+                self.keys.ingestor_public_key.is_some(),
+            ),
+            (
+                "keys.peer-share-processor-public-key",
+                self.keys.peer_share_processor_public_key.is_some(),
+            ),
+        ])
+    }
+
+    fn has_ecies_private_key(&self) -> bool {
+        self.keys.ecies_private_key.is_some()
+            || self.keys.ecies_private_key_file.is_some()
+            || self.keys.ecies_private_key_secret.is_some()
+    }
+
+    fn has_share_processor_private_key(&self) -> bool {
+        self.keys.share_processor_private_key.is_some()
+            || self.keys.share_processor_private_key_file.is_some()
+            || self.keys.share_processor_private_key_secret.is_some()
+    }
+}
+
+/// Fails naming the first of the provided fields that is not present.
+fn require(fields: &[(&str, bool)]) -> Result<()> {
+    match fields.iter().find(|(_, present)| !present) {
+        Some((name, _)) => Err(Error::MalformedConfigError(format!("{} is required", name)).into()),
+        None => Ok(()),
+    }
+}
+
+impl KeyConfig {
+    /// Returns the base64 encoded ECIES private key, reading it from
+    /// ecies-private-key-file or ecies-private-key-secret if it is not given
+    /// inline.
+    pub fn ecies_private_key(&self) -> Result<Option<Zeroizing<String>>> {
+        resolve_key_source(
+            "ecies-private-key",
+            &self.ecies_private_key,
+            &self.ecies_private_key_file,
+            &self.ecies_private_key_secret,
+        )
+    }
+
+    /// Returns where the ECIES private key is to be read from, without reading
+    /// it.
+    pub fn ecies_private_key_source(&self) -> Result<Option<Box<dyn SecretSource>>> {
+        key_source(
+            "ecies-private-key",
+            &self.ecies_private_key,
+            &self.ecies_private_key_file,
+            &self.ecies_private_key_secret,
+        )
+    }
+
+    /// Returns the base64 encoded share processor signing key, reading it from
+    /// share-processor-private-key-file or share-processor-private-key-secret
+    /// if it is not given inline.
+    pub fn share_processor_private_key(&self) -> Result<Option<Zeroizing<String>>> {
+        resolve_key_source(
+            "share-processor-private-key",
+            &self.share_processor_private_key,
+            &self.share_processor_private_key_file,
+            &self.share_processor_private_key_secret,
+        )
+    }
+
+    /// Returns where the share processor signing key is to be read from,
+    /// without reading it.
+    pub fn share_processor_private_key_source(&self) -> Result<Option<Box<dyn SecretSource>>> {
+        key_source(
+            "share-processor-private-key",
+            &self.share_processor_private_key,
+            &self.share_processor_private_key_file,
+            &self.share_processor_private_key_secret,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_INGESTOR_PRIVATE_KEY};
+
+    fn full_config(tempdir: &Path) -> FacilitatorConfig {
+        let key_path = tempdir.join("signing-key");
+        std::fs::write(&key_path, "c2lnbmluZyBrZXk=\n").unwrap();
+        FacilitatorConfig {
+            instance_name: Some("narnia".to_owned()),
+            is_first: Some(true),
+            transports: TransportConfig {
+                ingestion_bucket: Some("s3://us-west-2/ingestion".to_owned()),
+                validation_bucket: Some("/var/validation".to_owned()),
+                validation_audit_bucket: Some("s3://us-west-2/audit".to_owned()),
+                own_validation_bucket: None,
+                peer_validation_bucket: None,
+                aggregation_bucket: None,
+            },
+            keys: KeyConfig {
+                ecies_private_key: Some(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY.to_owned()),
+                ecies_private_key_file: None,
+                ecies_private_key_secret: None,
+                share_processor_private_key: None,
+                share_processor_private_key_file: Some(key_path),
+                share_processor_private_key_secret: None,
+                ingestor_public_key: Some(DEFAULT_INGESTOR_PRIVATE_KEY.to_owned()),
+                additional_ingestor_public_keys: Some(vec![
+                    DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY.to_owned()
+                ]),
+                peer_share_processor_public_key: None,
+            },
+            limits: LimitConfig {
+                worker_threads: Some(4),
+                max_packet_file_size: Some(419_430_400),
+                max_packet_failure_fraction: Some(0.25),
+                read_buffer_size: None,
+                copy_buffer_size: Some(262_144),
+                spool_threshold: None,
+                transport_retry_attempts: Some(5),
+                max_concurrent_batches: Some(8),
+                batch_start_jitter: Some(250),
+                max_batch_age: Some(86400),
+                max_batch_date_future_skew: Some(300),
+                max_epsilon: Some(10.0),
+                max_epsilon_by_aggregation: Some(
+                    vec![("kittens-seen".to_owned(), 20.0)]
+                        .into_iter()
+                        .collect(),
+                ),
+                min_epsilon: Some(0.01),
+                min_epsilon_by_aggregation: Some(
+                    vec![("kittens-seen".to_owned(), 1.0)].into_iter().collect(),
+                ),
+            },
+            toggles: ToggleConfig {
+                allow_empty_batches: Some(false),
+                write_manifest: Some(true),
+                write_done_marker: Some(true),
+                verify_after_write: Some(true),
+                legacy_validation_naming: None,
+                sort_validation_packets: Some(false),
+            },
+        }
+    }
+
+    #[test]
+    fn roundtrip_config_file() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let config = full_config(tempdir.path());
+        config.validate_for_intake().unwrap();
+
+        for (file_name, content) in &[
+            ("config.toml", config.to_toml().unwrap()),
+            ("config.yaml", config.to_yaml().unwrap()),
+            ("config.yml", config.to_yaml().unwrap()),
+        ] {
+            let path = tempdir.path().join(file_name);
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(FacilitatorConfig::from_file(&path).unwrap(), config);
+        }
+
+        let path = tempdir.path().join("config.json");
+        std::fs::write(&path, "{}").unwrap();
+        assert!(FacilitatorConfig::from_file(&path).is_err());
+
+        assert_eq!(
+            *config.keys.share_processor_private_key().unwrap().unwrap(),
+            "c2lnbmluZyBrZXk="
+        );
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = FacilitatorConfig::from_toml(
+            r#"
+            is-first = true
+
+            [transports]
+            ingestion-bucket = "s3://us-west-2/ingestion"
+
+            [limits]
+            max-packet-file-size = 1000
+            max-epsilon = 10.0
+
+            min-epsilon = 0.5
+
+            [limits.max-epsilon-by-aggregation]
+            kittens-seen = 20.0
+
+            [limits.min-epsilon-by-aggregation]
+            kittens-seen = 2.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.is_first, Some(true));
+        assert_eq!(
+            config.transports.ingestion_bucket.as_deref(),
+            Some("s3://us-west-2/ingestion")
+        );
+        assert_eq!(config.limits.max_packet_file_size, Some(1000));
+        assert_eq!(config.limits.max_epsilon_for("kittens-seen"), Some(20.0));
+        assert_eq!(config.limits.max_epsilon_for("puppies-seen"), Some(10.0));
+        assert_eq!(LimitConfig::default().max_epsilon_for("kittens-seen"), None);
+        assert_eq!(config.limits.min_epsilon_for("kittens-seen"), Some(2.0));
+        assert_eq!(config.limits.min_epsilon_for("puppies-seen"), Some(0.5));
+        assert_eq!(config.keys, KeyConfig::default());
+
+        // Misspelled fields are an error rather than silently ignored
+        assert!(FacilitatorConfig::from_toml("[limits]\nmax-packet-size = 1000").is_err());
+        assert!(FacilitatorConfig::from_yaml("is_first: true").is_err());
+    }
+
+    #[test]
+    fn merge_config() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut config = full_config(tempdir.path());
+        config.merge(FacilitatorConfig {
+            is_first: Some(false),
+            keys: KeyConfig {
+                share_processor_private_key: Some("aW5saW5l".to_owned()),
+                ..Default::default()
+            },
+            limits: LimitConfig {
+                worker_threads: Some(8),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(config.is_first, Some(false));
+        assert_eq!(config.instance_name.as_deref(), Some("narnia"));
+        assert_eq!(config.limits.worker_threads, Some(8));
+        assert_eq!(config.limits.max_packet_file_size, Some(419_430_400));
+        // Giving the key inline replaces the key file
+        assert_eq!(config.keys.share_processor_private_key_file, None);
+        assert_eq!(
+            *config.keys.share_processor_private_key().unwrap().unwrap(),
+            "aW5saW5l"
+        );
+        config.validate_for_intake().unwrap();
+    }
+
+    #[test]
+    fn key_secrets() {
+        let variable = "FACILITATOR_TEST_CONFIG_SECRET";
+        std::env::set_var(variable, "c2VjcmV0IGtleQ==\n");
+        let mut config = FacilitatorConfig::from_toml(&format!(
+            "[keys]\necies-private-key-secret = \"env:{}\"",
+            variable
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        assert!(config.keys.ecies_private_key_source().unwrap().is_some());
+        assert!(config
+            .keys
+            .share_processor_private_key_source()
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            *config.keys.ecies_private_key().unwrap().unwrap(),
+            "c2VjcmV0IGtleQ=="
+        );
+
+        // An unset variable is found when the key is read, not when the
+        // configuration is validated.
+        std::env::remove_var(variable);
+        config.validate().unwrap();
+        assert!(config.keys.ecies_private_key().is_err());
+
+        // Giving the key inline replaces the secret
+        config.merge(FacilitatorConfig {
+            keys: KeyConfig {
+                ecies_private_key: Some("aW5saW5l".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(config.keys.ecies_private_key_secret, None);
+        assert_eq!(
+            *config.keys.ecies_private_key().unwrap().unwrap(),
+            "aW5saW5l"
+        );
+
+        config.keys.ecies_private_key_secret = Some(format!("env:{}", variable));
+        assert!(config.validate().is_err());
+        config.keys.ecies_private_key = None;
+        config.keys.ecies_private_key_secret = Some("vault://ecies-key".to_owned());
+        match config.validate().unwrap_err().downcast_ref::<Error>() {
+            Some(Error::MalformedConfigError(_)) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let corruptions: &[fn(&mut FacilitatorConfig)] = &[
+            |config| config.keys.share_processor_private_key = Some("aW5saW5l".to_owned()),
+            |config| config.limits.worker_threads = Some(0),
+            |config| config.limits.max_packet_failure_fraction = Some(1.5),
+            |config| config.limits.max_batch_age = None,
+            |config| config.limits.max_epsilon = Some(0.0),
+            |config| config.limits.max_epsilon = Some(f64::NAN),
+            |config| config.limits.min_epsilon = Some(-1.0),
+            |config| config.limits.min_epsilon = Some(20.0),
+            |config| {
+                config.limits.min_epsilon_by_aggregation = Some(
+                    vec![("kittens-seen".to_owned(), 30.0)]
+                        .into_iter()
+                        .collect(),
+                )
+            },
+            |config| {
+                config.limits.max_epsilon_by_aggregation = Some(
+                    vec![("kittens-seen".to_owned(), -1.0)]
+                        .into_iter()
+                        .collect(),
+                )
+            },
+            |config| config.instance_name = Some("../escaped".to_owned()),
+            |config| config.transports.validation_bucket = None,
+            |config| config.keys.ingestor_public_key = None,
+        ];
+        for corrupt in corruptions {
+            let mut config = full_config(tempdir.path());
+            corrupt(&mut config);
+            assert!(
+                config.validate_for_intake().is_err(),
+                "{:?} was accepted",
+                config
+            );
+        }
+
+        // Intake does not need the aggregation-only fields
+        let config = full_config(tempdir.path());
+        config.validate_for_intake().unwrap();
+        match config
+            .validate_for_aggregation()
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::MalformedConfigError(message)) => {
+                assert_eq!(message, "transports.own-validation-bucket is required")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,1055 @@
+use crate::{
+    batch::{
+        AggregationName, BatchDate, BatchIdentity, BatchNamingScheme, Clock,
+        DefaultBatchNamingScheme, InstanceName, ServerIdentity, SystemClock, DEFAULT_NAMING_SCHEME,
+    },
+    intake::{BatchIntaker, BatchIntakerBuilder, EciesKeys, ValidationStats},
+    server_pool::ServerPool,
+    transport::Transport,
+    Error,
+};
+use anyhow::Result;
+use chrono::{Duration, NaiveDate};
+use prio::encrypt::PrivateKey;
+use ring::signature::{EcdsaKeyPair, UnparsedPublicKey};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// Whether a run over several batches stops at the first that fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Stop at the first batch that fails, leaving the rest unattempted.
+    FailFast,
+    /// Attempt every batch however many fail.
+    #[default]
+    ContinueOnError,
+}
+
+/// What became of one batch in a BatchDriver run.
+#[derive(Debug)]
+pub enum BatchOutcome {
+    /// A validation batch was written.
+    Succeeded(ValidationStats),
+    /// A validation batch had already been written, so nothing was done. See
+    /// BatchIntaker::set_overwrite.
+    Skipped,
+    /// Validating the batch failed.
+    Failed(anyhow::Error),
+}
+
+/// The outcome of each batch that BatchDriver::run attempted, in the order
+/// they were attempted, and the batches it didn't get to.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub outcomes: Vec<(BatchIdentity, BatchOutcome)>,
+    /// Batches left unattempted because an earlier one failed and the driver
+    /// is set to fail fast, or because the run was cancelled, in which case
+    /// this includes the batch that was in progress
+    pub not_attempted: Vec<BatchIdentity>,
+    /// The failure mode of the run, which decides what into_result reports
+    pub failure_mode: FailureMode,
+}
+
+impl RunSummary {
+    pub fn succeeded(&self) -> usize {
+        self.count(|outcome| matches!(outcome, BatchOutcome::Succeeded(_)))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|outcome| matches!(outcome, BatchOutcome::Skipped))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, BatchOutcome::Failed(_)))
+    }
+
+    /// Returns true if every batch was attempted and none failed.
+    pub fn is_success(&self) -> bool {
+        self.failed() == 0 && self.not_attempted.is_empty()
+    }
+
+    /// Returns the summary if no batch failed. Otherwise fails with
+    /// RunError::FirstFailure carrying the first batch that failed if the
+    /// run failed fast, or RunError::Failures carrying every batch that
+    /// failed if it continued on error. Batches left unattempted because the
+    /// run was cancelled are not failures.
+    pub fn into_result(self) -> Result<RunSummary, RunError> {
+        if self.failed() == 0 {
+            return Ok(self);
+        }
+        let mut failures = self
+            .outcomes
+            .into_iter()
+            .filter_map(|(batch, outcome)| match outcome {
+                BatchOutcome::Failed(e) => Some((batch, e)),
+                _ => None,
+            });
+        Err(match self.failure_mode {
+            FailureMode::FailFast => {
+                let (batch, e) = failures.next().unwrap();
+                RunError::FirstFailure(batch, e)
+            }
+            FailureMode::ContinueOnError => RunError::Failures(failures.collect()),
+        })
+    }
+
+    fn count(&self, predicate: impl Fn(&BatchOutcome) -> bool) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| predicate(outcome))
+            .count()
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} batches: {} succeeded, {} skipped, {} failed, {} not attempted",
+            self.outcomes.len() + self.not_attempted.len(),
+            self.succeeded(),
+            self.skipped(),
+            self.failed(),
+            self.not_attempted.len()
+        )
+    }
+}
+
+/// Why a run over several batches failed. See RunSummary::into_result.
+#[derive(Debug, thiserror::Error)]
+pub enum RunError {
+    #[error("batch {0} failed: {1:#}")]
+    FirstFailure(BatchIdentity, anyhow::Error),
+    #[error("{}", describe_failures(.0))]
+    Failures(Vec<(BatchIdentity, anyhow::Error)>),
+}
+
+fn describe_failures(failures: &[(BatchIdentity, anyhow::Error)]) -> String {
+    let mut description = format!("{} batches failed", failures.len());
+    for (batch, e) in failures {
+        description.push_str(&format!("\n{}: {:#}", batch, e));
+    }
+    description
+}
+
+/// Runs BatchIntaker::generate_validation_share on each of a list of ingestion
+/// batches in one aggregation, either provided or discovered in the ingestion
+/// transport, and collects the outcome for each into a RunSummary, by default
+/// without stopping at the first failure. The libprio Servers used to validate packets
+/// are reused from one batch to the next.
+pub struct BatchDriver<'a> {
+    instance_name: Option<String>,
+    aggregation_name: AggregationName,
+    batches: Vec<BatchIdentity>,
+    ingestion_transport: &'a mut dyn Transport,
+    validation_transport: &'a mut dyn Transport,
+    server_identity: ServerIdentity,
+    ingestion_naming_scheme: &'a DefaultBatchNamingScheme,
+    validation_naming_scheme: &'a dyn BatchNamingScheme,
+    failure_mode: FailureMode,
+    configure: Option<&'a dyn Fn(&mut BatchIntaker<'_>)>,
+    cancellation: Option<&'a AtomicBool>,
+    clock: &'a dyn Clock,
+    server_pool: ServerPool,
+    ecies_keys: Option<&'a EciesKeys>,
+    share_processor_ecies_key: &'a PrivateKey,
+    share_processor_signing_key: &'a EcdsaKeyPair,
+    ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+}
+
+impl<'a> BatchDriver<'a> {
+    /// Creates a BatchDriver for the aggregation with the provided name, with
+    /// no batches to process yet. See set_batches and discover_batches.
+    #[allow(clippy::too_many_arguments)] // Grandfathered in
+    pub fn new(
+        instance_name: Option<&str>,
+        aggregation_name: &AggregationName,
+        ingestion_transport: &'a mut dyn Transport,
+        validation_transport: &'a mut dyn Transport,
+        server_identity: ServerIdentity,
+        share_processor_ecies_key: &'a PrivateKey,
+        share_processor_signing_key: &'a EcdsaKeyPair,
+        ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ) -> BatchDriver<'a> {
+        BatchDriver {
+            instance_name: instance_name.map(str::to_owned),
+            aggregation_name: aggregation_name.clone(),
+            batches: Vec::new(),
+            ingestion_transport,
+            validation_transport,
+            server_identity,
+            ingestion_naming_scheme: &DEFAULT_NAMING_SCHEME,
+            validation_naming_scheme: &DEFAULT_NAMING_SCHEME,
+            failure_mode: FailureMode::default(),
+            configure: None,
+            cancellation: None,
+            clock: &SystemClock,
+            server_pool: ServerPool::new(
+                server_identity.is_first(),
+                share_processor_ecies_key.clone(),
+            ),
+            ecies_keys: None,
+            share_processor_ecies_key,
+            share_processor_signing_key,
+            ingestor_key,
+        }
+    }
+
+    /// Sets the naming scheme used to locate and discover ingestion batches.
+    /// Defaults to DEFAULT_NAMING_SCHEME.
+    pub fn set_ingestion_naming_scheme(&mut self, naming_scheme: &'a DefaultBatchNamingScheme) {
+        self.ingestion_naming_scheme = naming_scheme;
+    }
+
+    /// Sets the naming scheme used for validation batches. Defaults to
+    /// DEFAULT_NAMING_SCHEME.
+    pub fn set_validation_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.validation_naming_scheme = naming_scheme;
+    }
+
+    /// Sets whether run stops at the first batch that fails. Batches that
+    /// are skipped because they were already processed don't count as
+    /// failures. Defaults to FailureMode::ContinueOnError.
+    pub fn set_failure_mode(&mut self, failure_mode: FailureMode) {
+        self.failure_mode = failure_mode;
+    }
+
+    /// Sets a function that is called with each BatchIntaker before it runs,
+    /// to apply settings beyond those BatchDriver provides, e.g.
+    /// BatchIntaker::set_allow_empty_batches.
+    pub fn set_configure(&mut self, configure: &'a dyn Fn(&mut BatchIntaker<'_>)) {
+        self.configure = Some(configure);
+    }
+
+    /// Sets a flag that, once set, stops run before the next batch. A batch in
+    /// progress is stopped too, as described in BatchIntaker::set_cancellation,
+    /// and is counted as not attempted rather than failed.
+    pub fn set_cancellation(&mut self, cancellation: &'a AtomicBool) {
+        self.cancellation = Some(cancellation);
+    }
+
+    /// Sets the packet decryption keys from which the key for this driver's
+    /// aggregation is selected, in place of the key provided to new, so that
+    /// drivers for several aggregations can share one set of keys. The
+    /// identifier of the key is reported in each batch's
+    /// ValidationStats::ecies_key_id. Fails with Error::MissingEciesKey if
+    /// keys has no key for the aggregation and no default.
+    pub fn set_ecies_keys(&mut self, keys: &'a EciesKeys) -> Result<(), Error> {
+        let (key_id, key) = keys.key_for(&self.aggregation_name)?;
+        self.server_pool =
+            ServerPool::with_key_id(self.server_identity.is_first(), key_id, key.clone());
+        self.ecies_keys = Some(keys);
+        Ok(())
+    }
+
+    /// Sets the clock each BatchIntaker uses wherever the current time is
+    /// needed. See BatchIntaker::set_clock. Defaults to SystemClock.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    /// Sets the batches to process, in order, replacing any set or discovered
+    /// earlier. Fails with Error::MalformedConfigError if any of them belongs
+    /// to another aggregation.
+    pub fn set_batches(&mut self, batches: Vec<BatchIdentity>) -> Result<(), Error> {
+        if let Some(batch) = batches
+            .iter()
+            .find(|batch| batch.aggregation_name != self.aggregation_name)
+        {
+            return Err(Error::MalformedConfigError(format!(
+                "batch {} is not in aggregation {}",
+                batch, self.aggregation_name
+            )));
+        }
+        self.batches = batches;
+        Ok(())
+    }
+
+    /// Sets the batches to process to the complete ingestion batches in the
+    /// ingestion transport dated within [start, end), in order of date and
+    /// then batch ID, and returns how many there are. See
+    /// DefaultBatchNamingScheme::enumerate_range.
+    pub fn discover_batches(&mut self, start: &BatchDate, end: &BatchDate) -> Result<usize> {
+        let instance_name = self
+            .instance_name
+            .as_deref()
+            .map(InstanceName::new)
+            .transpose()?;
+        self.batches = self.ingestion_naming_scheme.enumerate_range(
+            self.ingestion_transport,
+            instance_name.as_ref(),
+            &self.aggregation_name,
+            start,
+            end,
+        )?;
+        Ok(self.batches.len())
+    }
+
+    /// Returns the batches that run will process.
+    pub fn batches(&self) -> &[BatchIdentity] {
+        &self.batches
+    }
+
+    /// Validates each batch in turn and returns what became of each.
+    pub fn run(&mut self) -> RunSummary {
+        let mut summary = RunSummary {
+            failure_mode: self.failure_mode,
+            ..RunSummary::default()
+        };
+        let mut batches = self.batches.clone().into_iter();
+        for batch in &mut batches {
+            if self
+                .cancellation
+                .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+            {
+                summary.not_attempted.push(batch);
+                break;
+            }
+            let outcome = match self.generate_validation_share(&batch) {
+                Ok(stats) => BatchOutcome::Succeeded(stats),
+                Err(e) if matches!(e.downcast_ref(), Some(Error::AlreadyProcessed(_))) => {
+                    BatchOutcome::Skipped
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(Error::Cancelled)) => {
+                    summary.not_attempted.push(batch);
+                    break;
+                }
+                Err(e) => BatchOutcome::Failed(e),
+            };
+            let failed = matches!(outcome, BatchOutcome::Failed(_));
+            summary.outcomes.push((batch, outcome));
+            if failed && self.failure_mode == FailureMode::FailFast {
+                break;
+            }
+        }
+        summary.not_attempted.extend(batches);
+        summary
+    }
+
+    fn generate_validation_share(&mut self, batch: &BatchIdentity) -> Result<ValidationStats> {
+        let mut builder = BatchIntakerBuilder::new(
+            batch,
+            self.server_identity,
+            self.share_processor_ecies_key,
+            self.share_processor_signing_key,
+            self.ingestor_key,
+        )
+        .ingestion_transport(&mut *self.ingestion_transport)
+        .validation_transport(&mut *self.validation_transport)
+        .ingestion_naming_scheme(self.ingestion_naming_scheme)
+        .validation_naming_scheme(self.validation_naming_scheme)
+        .server_pool(&self.server_pool)
+        .clock(self.clock);
+        if let Some(cancellation) = self.cancellation {
+            builder = builder.cancellation(cancellation);
+        }
+        if let Some(instance_name) = &self.instance_name {
+            builder = builder.instance_name(instance_name);
+        }
+        if let Some(keys) = self.ecies_keys {
+            builder = builder.ecies_keys(keys);
+        }
+        let mut intaker = builder.build()?;
+        if let Some(configure) = self.configure {
+            configure(&mut intaker);
+        }
+        intaker.generate_validation_share()
+    }
+}
+
+/// Creates the ingestion and validation transports, in that order, for one
+/// worker of validate_aggregation_day.
+pub type WorkerTransports<'a> =
+    dyn Fn() -> Result<(Box<dyn Transport>, Box<dyn Transport>)> + Sync + 'a;
+
+/// Validates every complete ingestion batch in the aggregation dated on the
+/// provided day (UTC), as found by BatchDriver::discover_batches, with up to
+/// max_concurrent_batches of them in progress at once. Each worker thread gets
+/// its own transports from transports, and runs its batches through its own
+/// BatchDriver, with the first pair of transports also used to list the
+/// batches. Values of max_concurrent_batches below 1 are treated as 1, which
+/// validates the batches one after another on the calling thread. Under
+/// FailureMode::FailFast, no batch is started once one has failed, though
+/// batches already in progress on other workers are finished, and the rest
+/// are reported as not attempted. Under FailureMode::ContinueOnError, a batch
+/// that fails doesn't stop the others. The outcomes are reported in the order
+/// of the batches whichever worker got to them first. Fails only if the
+/// batches can't be listed; see RunSummary::into_result to fail if any batch
+/// did.
+#[allow(clippy::too_many_arguments)]
+pub fn validate_aggregation_day(
+    instance_name: Option<&str>,
+    aggregation_name: &AggregationName,
+    day: NaiveDate,
+    transports: &WorkerTransports<'_>,
+    server_identity: ServerIdentity,
+    share_processor_ecies_key: &PrivateKey,
+    share_processor_signing_key: &EcdsaKeyPair,
+    ingestor_key: &UnparsedPublicKey<Vec<u8>>,
+    max_concurrent_batches: usize,
+    failure_mode: FailureMode,
+) -> Result<RunSummary> {
+    let (mut ingestion_transport, mut validation_transport) = transports()?;
+    let mut driver = BatchDriver::new(
+        instance_name,
+        aggregation_name,
+        &mut *ingestion_transport,
+        &mut *validation_transport,
+        server_identity,
+        share_processor_ecies_key,
+        share_processor_signing_key,
+        ingestor_key,
+    );
+    driver.set_failure_mode(failure_mode);
+    driver.discover_batches(
+        &BatchDate::new(&day.and_hms(0, 0, 0)),
+        &BatchDate::new(&(day + Duration::days(1)).and_hms(0, 0, 0)),
+    )?;
+    let batches = driver.batches().to_vec();
+    let worker_count = max_concurrent_batches.clamp(1, batches.len().max(1));
+    if worker_count == 1 {
+        return Ok(driver.run());
+    }
+    drop(driver);
+
+    // Workers take batches from a shared queue, so at most worker_count
+    // batches are in progress at any time. Failing fast stops them taking
+    // more.
+    let queue = Mutex::new(batches.into_iter().enumerate());
+    let outcomes = Mutex::new(Vec::new());
+    let stopped = AtomicBool::new(false);
+    let run_worker = || -> Result<()> {
+        let (mut ingestion_transport, mut validation_transport) = transports()?;
+        let mut driver = BatchDriver::new(
+            instance_name,
+            aggregation_name,
+            &mut *ingestion_transport,
+            &mut *validation_transport,
+            server_identity,
+            share_processor_ecies_key,
+            share_processor_signing_key,
+            ingestor_key,
+        );
+        loop {
+            if stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let next = queue.lock().unwrap().next();
+            let (index, batch) = match next {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            driver.set_batches(vec![batch])?;
+            let summary = driver.run();
+            if failure_mode == FailureMode::FailFast && summary.failed() > 0 {
+                stopped.store(true, Ordering::Relaxed);
+            }
+            outcomes
+                .lock()
+                .unwrap()
+                .extend(summary.outcomes.into_iter().map(|outcome| (index, outcome)));
+        }
+    };
+    let worker_results: Vec<Result<()>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..worker_count).map(|_| scope.spawn(run_worker)).collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    });
+
+    // A worker that couldn't get its transports leaves its share of the
+    // batches to the others, so the run only fails if none could.
+    let mut worker_errors: Vec<anyhow::Error> =
+        worker_results.into_iter().filter_map(Result::err).collect();
+    if worker_errors.len() == worker_count {
+        return Err(worker_errors.swap_remove(0));
+    }
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+    Ok(RunSummary {
+        outcomes: outcomes.into_iter().map(|(_, outcome)| outcome).collect(),
+        not_attempted: queue
+            .into_inner()
+            .unwrap()
+            .map(|(_, batch)| batch)
+            .collect(),
+        failure_mode,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{BatchDateWindow, BatchFileKind},
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            default_pha_signing_private_key, MockClock, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::MemoryTransport,
+    };
+    use chrono::NaiveDateTime;
+    use ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+    use std::io::Write;
+    use uuid::Uuid;
+
+    #[test]
+    fn run_batches() {
+        let aggregation_name = AggregationName::new("fake-aggregation-1").unwrap();
+        let batches: Vec<BatchIdentity> = (0..3)
+            .map(|minute| {
+                BatchIdentity::new(
+                    aggregation_name.clone(),
+                    BatchDate::new(&NaiveDateTime::from_timestamp(1234567890 + 60 * minute, 0)),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+        for batch in &batches {
+            generate_ingestion_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                None,
+                batch,
+                &pha_ecies_key,
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                &default_ingestor_private_key_raw(),
+                10,
+                10,
+                0.11,
+                100,
+                100,
+            )
+            .expect("failed to generate sample");
+        }
+
+        // Corrupt the middle batch's signature
+        let mut writer = pha_ingest_transport
+            .put(
+                batches[1]
+                    .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                    .key(BatchFileKind::Signature),
+            )
+            .unwrap();
+        writer.write_all(b"not a signature").unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut run = |failure_mode| {
+            let mut driver = BatchDriver::new(
+                None,
+                &aggregation_name,
+                &mut pha_ingest_transport,
+                &mut pha_validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            );
+            driver.set_failure_mode(failure_mode);
+            driver.set_batches(batches.clone()).unwrap();
+            driver.run()
+        };
+
+        // Failing fast leaves the last batch unattempted
+        let summary = run(FailureMode::FailFast);
+        assert_eq!(
+            (summary.succeeded(), summary.skipped(), summary.failed()),
+            (1, 0, 1)
+        );
+        assert_eq!(summary.not_attempted, batches[2..]);
+        assert!(!summary.is_success());
+        match summary.into_result() {
+            Err(RunError::FirstFailure(batch, e)) => {
+                assert_eq!(batch, batches[1]);
+                assert!(
+                    format!("{:#}", e).contains("invalid signature on header"),
+                    "{:#}",
+                    e
+                );
+            }
+            v => panic!("unexpected result {:?}", v),
+        }
+
+        let summary = run(FailureMode::ContinueOnError);
+        assert_eq!(
+            summary.to_string(),
+            "3 batches: 1 succeeded, 1 skipped, 1 failed, 0 not attempted"
+        );
+        let attempted: Vec<&BatchIdentity> = summary.outcomes.iter().map(|(b, _)| b).collect();
+        assert_eq!(attempted, batches.iter().collect::<Vec<_>>());
+        match &summary.outcomes[..] {
+            [(_, BatchOutcome::Skipped), (_, BatchOutcome::Failed(e)), (_, BatchOutcome::Succeeded(stats))] =>
+            {
+                assert!(
+                    format!("{:#}", e).contains("invalid signature on header"),
+                    "{:#}",
+                    e
+                );
+                assert_eq!(stats.packets, 10);
+            }
+            outcomes => panic!("unexpected outcomes {:?}", outcomes),
+        }
+        let e = summary.into_result().unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with(&format!("1 batches failed\n{}: ", batches[1])),
+            "{}",
+            e
+        );
+        match e {
+            RunError::Failures(failures) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, batches[1]);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // With fresh output, two batches succeed and the corrupt one fails
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut driver = BatchDriver::new(
+            None,
+            &aggregation_name,
+            &mut pha_ingest_transport,
+            &mut pha_validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        );
+        let configure = |intaker: &mut BatchIntaker<'_>| intaker.set_worker_threads(Some(1));
+        driver.set_configure(&configure);
+        assert_eq!(
+            driver
+                .discover_batches(
+                    &batches[0].date,
+                    &BatchDate::new(&NaiveDateTime::from_timestamp(1234567890 + 3600, 0)),
+                )
+                .unwrap(),
+            3
+        );
+        assert_eq!(driver.batches(), &batches[..]);
+        let summary = driver.run();
+        assert_eq!(
+            (summary.succeeded(), summary.skipped(), summary.failed()),
+            (2, 0, 1)
+        );
+        assert!(matches!(summary.outcomes[1].1, BatchOutcome::Failed(_)));
+
+        // A cancelled run attempts nothing and fails nothing
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut driver = BatchDriver::new(
+            None,
+            &aggregation_name,
+            &mut pha_ingest_transport,
+            &mut pha_validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        );
+        let cancelled = AtomicBool::new(true);
+        driver.set_cancellation(&cancelled);
+        driver.set_batches(batches.clone()).unwrap();
+        let summary = driver.run();
+        assert!(summary.outcomes.is_empty());
+        assert_eq!(summary.not_attempted, batches);
+        assert!(!summary.is_success());
+        assert!(summary.into_result().is_ok());
+        assert!(pha_validate_transport.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn batch_date_window_with_mock_clock() {
+        let aggregation_name = AggregationName::new("fake-aggregation-1").unwrap();
+        let start = NaiveDateTime::from_timestamp(1234567890, 0);
+        let batches: Vec<BatchIdentity> = (0..3)
+            .map(|minute| {
+                BatchIdentity::new(
+                    aggregation_name.clone(),
+                    BatchDate::new(&(start + Duration::minutes(minute))),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+        for batch in &batches {
+            generate_ingestion_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                None,
+                batch,
+                &pha_ecies_key,
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                &default_ingestor_private_key_raw(),
+                10,
+                10,
+                0.11,
+                100,
+                100,
+            )
+            .expect("failed to generate sample");
+        }
+
+        // Half a minute after the first batch, the last is too far in the
+        // future.
+        let clock = MockClock::new(start + Duration::seconds(30));
+        let configure = |intaker: &mut BatchIntaker<'_>| {
+            intaker.set_batch_date_window(Some(BatchDateWindow::new(
+                Duration::seconds(30),
+                Duration::hours(1),
+            )))
+        };
+        let mut run = |pha_validate_transport: &mut MemoryTransport| {
+            let mut driver = BatchDriver::new(
+                None,
+                &aggregation_name,
+                &mut pha_ingest_transport,
+                pha_validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            );
+            driver.set_clock(&clock);
+            driver.set_configure(&configure);
+            driver.set_batches(batches.clone()).unwrap();
+            driver.run()
+        };
+        let outside_window = |outcome: &BatchOutcome| {
+            matches!(outcome, BatchOutcome::Failed(e)
+                if matches!(e.downcast_ref(), Some(Error::BatchDateOutsideWindow(..))))
+        };
+
+        let summary = run(&mut MemoryTransport::new());
+        assert_eq!((summary.succeeded(), summary.failed()), (2, 1));
+        assert!(outside_window(&summary.outcomes[2].1));
+
+        // Two hours later, all of them are too old.
+        clock.advance(Duration::hours(2));
+        let summary = run(&mut MemoryTransport::new());
+        assert_eq!(summary.failed(), 3);
+        assert!(summary
+            .outcomes
+            .iter()
+            .all(|(_, outcome)| outside_window(outcome)));
+    }
+
+    #[test]
+    fn validate_day() {
+        let aggregation_name = AggregationName::new("fake-aggregation-1").unwrap();
+        let day = NaiveDate::from_ymd(2009, 2, 13);
+        // Five batches through the day, and one just after it
+        let batches: Vec<BatchIdentity> = [0, 1, 7, 12, 23, 24]
+            .iter()
+            .map(|hour| {
+                BatchIdentity::new(
+                    aggregation_name.clone(),
+                    BatchDate::new(&(day.and_hms(0, 30, 0) + Duration::hours(*hour))),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+        for batch in &batches {
+            generate_ingestion_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                None,
+                batch,
+                &pha_ecies_key,
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                &default_ingestor_private_key_raw(),
+                10,
+                10,
+                0.11,
+                100,
+                100,
+            )
+            .expect("failed to generate sample");
+        }
+
+        // Corrupt the third batch's signature
+        let mut writer = pha_ingest_transport
+            .put(
+                batches[2]
+                    .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                    .key(BatchFileKind::Signature),
+            )
+            .unwrap();
+        writer.write_all(b"not a signature").unwrap();
+        writer.complete_upload().unwrap();
+
+        for max_concurrent_batches in &[1, 3] {
+            let pha_validate_transport = MemoryTransport::new();
+            let transports = || -> Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+                Ok((
+                    Box::new(pha_ingest_transport.clone()),
+                    Box::new(pha_validate_transport.clone()),
+                ))
+            };
+            let summary = validate_aggregation_day(
+                None,
+                &aggregation_name,
+                day,
+                &transports,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+                *max_concurrent_batches,
+                FailureMode::ContinueOnError,
+            )
+            .unwrap();
+
+            assert_eq!(
+                summary.to_string(),
+                "5 batches: 4 succeeded, 0 skipped, 1 failed, 0 not attempted"
+            );
+            let attempted: Vec<&BatchIdentity> = summary.outcomes.iter().map(|(b, _)| b).collect();
+            assert_eq!(attempted, batches[..5].iter().collect::<Vec<_>>());
+            assert!(matches!(summary.outcomes[2].1, BatchOutcome::Failed(_)));
+            // Each successful batch wrote a header, packet file and signature
+            let written: Vec<String> = pha_validate_transport.list("").unwrap();
+            assert!(written
+                .iter()
+                .all(|key| !key.contains(&batches[2].batch_id.to_string())
+                    && !key.contains(&batches[5].batch_id.to_string())));
+            assert_eq!(written.len(), 4 * 3);
+            match summary.into_result() {
+                Err(RunError::Failures(failures)) => {
+                    assert_eq!(failures.len(), 1);
+                    assert_eq!(failures[0].0, batches[2]);
+                }
+                v => panic!("unexpected result {:?}", v),
+            }
+
+            // Failing fast, no batch is started after the broken one fails,
+            // though with several workers others may already be in progress.
+            let pha_validate_transport = MemoryTransport::new();
+            let transports = || -> Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+                Ok((
+                    Box::new(pha_ingest_transport.clone()),
+                    Box::new(pha_validate_transport.clone()),
+                ))
+            };
+            let summary = validate_aggregation_day(
+                None,
+                &aggregation_name,
+                day,
+                &transports,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+                *max_concurrent_batches,
+                FailureMode::FailFast,
+            )
+            .unwrap();
+            assert_eq!(summary.failed(), 1);
+            assert_eq!(summary.outcomes.len() + summary.not_attempted.len(), 5);
+            if *max_concurrent_batches == 1 {
+                assert_eq!(summary.succeeded(), 2);
+                assert_eq!(summary.not_attempted, batches[3..5]);
+            }
+            match summary.into_result() {
+                Err(RunError::FirstFailure(batch, _)) => assert_eq!(batch, batches[2]),
+                v => panic!("unexpected result {:?}", v),
+            }
+        }
+
+        // If no worker can get transports, nothing is attempted
+        let transports = || -> Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+            Err(anyhow::anyhow!("no transports"))
+        };
+        assert!(validate_aggregation_day(
+            None,
+            &aggregation_name,
+            day,
+            &transports,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+            3,
+            FailureMode::ContinueOnError,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ecies_keys_by_aggregation() {
+        let aggregation_names: Vec<AggregationName> = (1..=3)
+            .map(|n| AggregationName::new(&format!("fake-aggregation-{}", n)).unwrap())
+            .collect();
+        let pha_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let other_key = PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        // The first aggregation's packets are encrypted to the PHA's usual
+        // key, and the others' to another key.
+        let batches: Vec<BatchIdentity> = aggregation_names
+            .iter()
+            .map(|aggregation_name| {
+                BatchIdentity::new(
+                    aggregation_name.clone(),
+                    BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        for (batch, (pha_batch_key, facilitator_batch_key)) in batches.iter().zip(&[
+            (&pha_key, &other_key),
+            (&other_key, &pha_key),
+            (&other_key, &pha_key),
+        ]) {
+            generate_ingestion_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                None,
+                batch,
+                pha_batch_key,
+                facilitator_batch_key,
+                &default_ingestor_private_key_raw(),
+                10,
+                10,
+                0.11,
+                100,
+                100,
+            )
+            .expect("failed to generate sample");
+        }
+
+        let mut keys = EciesKeys::new();
+        keys.insert(&aggregation_names[0], "pha-key", pha_key.clone());
+        keys.insert(&aggregation_names[1], "other-key", other_key.clone());
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut run = |aggregation_name: &AggregationName, keys: &EciesKeys| {
+            // The key provided to new only decrypts the first aggregation's
+            // packets, so the others succeed only with the key from keys.
+            let mut driver = BatchDriver::new(
+                None,
+                aggregation_name,
+                &mut pha_ingest_transport,
+                &mut pha_validate_transport,
+                ServerIdentity::Pha,
+                &pha_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            );
+            driver.set_ecies_keys(keys)?;
+            driver
+                .set_batches(
+                    batches
+                        .iter()
+                        .filter(|batch| &batch.aggregation_name == aggregation_name)
+                        .cloned()
+                        .collect(),
+                )
+                .unwrap();
+            Ok::<_, Error>(driver.run())
+        };
+
+        for (aggregation_name, key_id) in aggregation_names.iter().zip(&["pha-key", "other-key"]) {
+            let summary = run(aggregation_name, &keys).unwrap();
+            match &summary.outcomes[..] {
+                [(_, BatchOutcome::Succeeded(stats))] => {
+                    assert_eq!(stats.packets, 10);
+                    assert_eq!(stats.ecies_key_id.as_deref(), Some(*key_id));
+                }
+                outcomes => panic!("unexpected outcomes {:?}", outcomes),
+            }
+        }
+
+        // The third aggregation has no key until there is a default.
+        match run(&aggregation_names[2], &keys) {
+            Err(Error::MissingEciesKey(name)) => assert_eq!(name, "fake-aggregation-3"),
+            v => panic!("unexpected result {:?}", v),
+        }
+        keys.set_default("default-key", other_key.clone());
+        let summary = run(&aggregation_names[2], &keys).unwrap();
+        match &summary.outcomes[..] {
+            [(_, BatchOutcome::Succeeded(stats))] => {
+                assert_eq!(stats.ecies_key_id.as_deref(), Some("default-key"))
+            }
+            outcomes => panic!("unexpected outcomes {:?}", outcomes),
+        }
+    }
+
+    #[test]
+    fn batches_in_other_aggregations() {
+        let mut ingestion_transport = MemoryTransport::new();
+        let mut validation_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+        let mut driver = BatchDriver::new(
+            None,
+            &AggregationName::new("fake-aggregation-1").unwrap(),
+            &mut ingestion_transport,
+            &mut validation_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        );
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-2").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        match driver.set_batches(vec![batch]) {
+            Err(Error::MalformedConfigError(_)) => (),
+            v => panic!("unexpected result {:?}", v),
+        }
+        assert!(driver.batches().is_empty());
+    }
+}
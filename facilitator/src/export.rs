@@ -0,0 +1,159 @@
+use crate::idl::ValidationPacketReader;
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+
+/// Header row written by export_validation_csv.
+pub const VALIDATION_CSV_HEADER: &str = "uuid,f_r,g_r,h_r";
+
+/// Writes the validation packets in the provided packet file to writer as CSV,
+/// a header row followed by one row per packet, and returns the number of
+/// packets written. This is meant for eyeballing validation shares without an
+/// Avro toolchain: the packet file is only read, and its authenticity is not
+/// checked. UUIDs and integers never need quoting, so no CSV library is needed.
+pub fn export_validation_csv<R: Read, W: Write>(packet_file: R, writer: &mut W) -> Result<u64> {
+    let packets =
+        ValidationPacketReader::new(packet_file).context("failed to read validation packets")?;
+    writeln!(writer, "{}", VALIDATION_CSV_HEADER).context("failed to write CSV header")?;
+    let mut rows = 0;
+    for packet in packets {
+        let packet = packet.context("failed to read validation packet")?;
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            packet.uuid, packet.f_r, packet.g_r, packet.h_r
+        )
+        .context("failed to write CSV row")?;
+        rows += 1;
+    }
+    writer.flush().context("failed to flush CSV")?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{
+            AggregationName, BatchDate, BatchFileKind, BatchIdentity, ServerIdentity,
+            DEFAULT_NAMING_SCHEME,
+        },
+        intake::BatchIntaker,
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            default_pha_signing_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::{MemoryTransport, Transport},
+    };
+    use chrono::NaiveDateTime;
+    use prio::encrypt::PrivateKey;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use uuid::Uuid;
+
+    fn read_all(transport: &dyn Transport, key: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        transport
+            .get(key)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn export_validation_packets() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let packet_count = 17;
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut pha_validate_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let ingestor_pub_key = default_ingestor_public_key();
+
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &pha_ecies_key,
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            packet_count,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut pha_validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.generate_validation_share().unwrap();
+        drop(pha_ingestor);
+
+        let validation_batch = batch
+            .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+            .into_batch();
+        let keys = pha_validate_transport.list("").unwrap();
+        let contents: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|key| read_all(&pha_validate_transport, key))
+            .collect();
+
+        let mut csv = Vec::new();
+        let rows = export_validation_csv(
+            pha_validate_transport
+                .get(validation_batch.key(BatchFileKind::Packets))
+                .unwrap(),
+            &mut csv,
+        )
+        .unwrap();
+        assert_eq!(rows, packet_count as u64);
+
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(VALIDATION_CSV_HEADER));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), packet_count);
+        for row in &rows {
+            assert_eq!(row.len(), 4, "unexpected row {:?}", row);
+            Uuid::parse_str(row[0]).unwrap();
+            for value in &row[1..] {
+                value.parse::<i64>().unwrap();
+            }
+        }
+
+        // Exporting leaves the signed batch untouched
+        assert_eq!(pha_validate_transport.list("").unwrap(), keys);
+        for (key, content) in keys.iter().zip(&contents) {
+            assert_eq!(&read_all(&pha_validate_transport, key), content);
+        }
+
+        // Packet files of other kinds are refused
+        let ingestion_packets = read_all(
+            &pha_ingest_transport,
+            batch
+                .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                .key(BatchFileKind::Packets),
+        );
+        export_validation_csv(ingestion_packets.as_slice(), &mut Vec::new()).unwrap_err();
+    }
+}
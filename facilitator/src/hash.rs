@@ -0,0 +1,184 @@
+//! The SHA-256 implementation used for the digests of the files in a batch:
+//! packet file and shard digests, which ingestion headers carry and which
+//! signatures over them therefore cover, and the digests listed in batch
+//! manifests. By default digests are computed with ring. Building with the
+//! openssl-digest feature computes them with OpenSSL instead, which may be
+//! configured to use a FIPS-validated provider. Either way the digests are the
+//! same. ECDSA signatures are still made and verified with ring, which hashes
+//! the signed message itself.
+
+use ring::digest;
+use std::fmt;
+
+/// The length in bytes of a SHA-256 digest.
+pub const SHA256_OUTPUT_LEN: usize = 32;
+
+/// A SHA-256 digest computed by a Digest.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Sha256Digest([u8; SHA256_OUTPUT_LEN]);
+
+impl AsRef<[u8]> for Sha256Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sha256Digest(")?;
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// An incremental SHA-256 computation.
+pub trait Digest: Sized {
+    fn new() -> Self;
+
+    fn update(&mut self, data: &[u8]);
+
+    /// Consumes the Digest and returns the digest of all the data it was
+    /// provided.
+    fn finish(self) -> Sha256Digest;
+
+    /// Returns the digest of the provided data.
+    fn digest(data: &[u8]) -> Sha256Digest {
+        let mut digest = Self::new();
+        digest.update(data);
+        digest.finish()
+    }
+}
+
+/// A Digest computed with ring.
+pub struct RingDigest {
+    context: digest::Context,
+}
+
+impl Digest for RingDigest {
+    fn new() -> RingDigest {
+        RingDigest {
+            context: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.context.update(data);
+    }
+
+    fn finish(self) -> Sha256Digest {
+        let mut output = [0u8; SHA256_OUTPUT_LEN];
+        output.copy_from_slice(self.context.finish().as_ref());
+        Sha256Digest(output)
+    }
+}
+
+/// A Digest computed with OpenSSL's EVP interface, so that it is provided by
+/// whichever provider OpenSSL is configured with. OpenSSL only fails to hash
+/// if it is misconfigured, e.g. if its FIPS provider failed its self tests, in
+/// which case this panics rather than produce a digest that can't be trusted.
+#[cfg(feature = "openssl-digest")]
+pub struct OpensslDigest {
+    hasher: openssl::hash::Hasher,
+}
+
+#[cfg(feature = "openssl-digest")]
+impl Digest for OpensslDigest {
+    fn new() -> OpensslDigest {
+        OpensslDigest {
+            hasher: openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())
+                .expect("failed to create OpenSSL SHA-256 hasher"),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.hasher
+            .update(data)
+            .expect("failed to update OpenSSL SHA-256 hasher");
+    }
+
+    fn finish(mut self) -> Sha256Digest {
+        let mut output = [0u8; SHA256_OUTPUT_LEN];
+        output.copy_from_slice(
+            &self
+                .hasher
+                .finish()
+                .expect("failed to finish OpenSSL SHA-256 hasher"),
+        );
+        Sha256Digest(output)
+    }
+}
+
+/// The Digest the facilitator computes batch file digests with, as chosen by
+/// the openssl-digest feature.
+#[cfg(not(feature = "openssl-digest"))]
+pub type DefaultDigest = RingDigest;
+#[cfg(feature = "openssl-digest")]
+pub type DefaultDigest = OpensslDigest;
+
+/// Returns the digest of the provided data, computed with DefaultDigest.
+pub fn sha256(data: &[u8]) -> Sha256Digest {
+    DefaultDigest::digest(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_digest<D: Digest>() {
+        // FIPS 180-2 test vectors
+        let cases: &[(&[u8], &str)] = &[
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(
+                format!("{:?}", D::digest(data)),
+                format!("Sha256Digest({})", expected)
+            );
+
+            // Digesting in pieces is the same as digesting all at once.
+            let mut digest = D::new();
+            for piece in data.chunks(5) {
+                digest.update(piece);
+            }
+            assert_eq!(digest.finish(), D::digest(data));
+        }
+    }
+
+    #[test]
+    fn ring_digest() {
+        check_digest::<RingDigest>();
+    }
+
+    #[test]
+    fn default_digest() {
+        check_digest::<DefaultDigest>();
+        let data = vec![7u8; 100_000];
+        assert_eq!(
+            sha256(&data).as_ref(),
+            digest::digest(&digest::SHA256, &data).as_ref()
+        );
+    }
+
+    #[cfg(feature = "openssl-digest")]
+    #[test]
+    fn openssl_digest_matches_ring() {
+        check_digest::<OpensslDigest>();
+        for length in &[0, 1, 55, 56, 64, 1000, 1_048_577] {
+            let data: Vec<u8> = (0..*length).map(|i| (i * 31 % 251) as u8).collect();
+            assert_eq!(OpensslDigest::digest(&data), RingDigest::digest(&data));
+        }
+    }
+}
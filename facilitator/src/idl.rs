@@ -4,12 +4,17 @@ use avro_rs::{
     types::{Record, Value},
     Reader, Schema, Writer,
 };
-use prio::{finite_field::Field, server::VerificationMessage};
+use prio::{
+    finite_field::{Field, MODULUS},
+    server::VerificationMessage,
+};
+use ring::signature::{VerificationAlgorithm, ECDSA_P256_SHA256_FIXED};
 use serde::{Deserialize, Serialize};
 use std::{
     convert::TryFrom,
-    io::{Read, Write},
+    io::{Cursor, Read, Write},
     num::TryFromIntError,
+    sync::OnceLock,
 };
 use uuid::Uuid;
 
@@ -21,9 +26,133 @@ const VALIDATION_PACKET_SCHEMA: &str = include_str!("../../avro-schema/validatio
 const SUM_PART_SCHEMA: &str = include_str!("../../avro-schema/sum-part.avsc");
 const INVALID_PACKET_SCHEMA: &str = include_str!("../../avro-schema/invalid-packet.avsc");
 
+/// The version of the Avro schemas above, which is the last component of their
+/// namespace, "org.abetterinternet.prio.<version>".
+pub const SCHEMA_VERSION: &str = "v1";
+
+/// Returns true if data written with writer_schema can be read with
+/// reader_schema according to the Avro schema resolution rules: records must
+/// have the same name and each of the reader's fields must either be present
+/// in the writer's record with a readable schema or have a default, numeric
+/// types may be widened and unions are resolved branch by branch. Logical types
+/// are treated as their underlying type. This exists because
+/// avro_rs::schema_compatibility rejects logical types, which our schemas use.
+pub fn can_read_schema(writer_schema: &Schema, reader_schema: &Schema) -> bool {
+    match (writer_schema, reader_schema) {
+        (Schema::Union(writer), _) => writer
+            .variants()
+            .iter()
+            .all(|writer| can_read_schema(writer, reader_schema)),
+        (_, Schema::Union(reader)) => reader
+            .variants()
+            .iter()
+            .any(|reader| can_read_schema(writer_schema, reader)),
+        (
+            Schema::Record {
+                name: writer_name,
+                fields: writer_fields,
+                lookup: writer_lookup,
+                ..
+            },
+            Schema::Record {
+                name: reader_name,
+                fields: reader_fields,
+                ..
+            },
+        ) => {
+            same_name(&writer_name.name, &reader_name.name)
+                && reader_fields
+                    .iter()
+                    .all(|field| match writer_lookup.get(&field.name) {
+                        Some(index) => {
+                            can_read_schema(&writer_fields[*index].schema, &field.schema)
+                        }
+                        None => field.default.is_some(),
+                    })
+        }
+        (Schema::Array(writer), Schema::Array(reader))
+        | (Schema::Map(writer), Schema::Map(reader)) => can_read_schema(writer, reader),
+        (
+            Schema::Enum {
+                name: writer_name,
+                symbols: writer_symbols,
+                ..
+            },
+            Schema::Enum {
+                name: reader_name,
+                symbols: reader_symbols,
+                ..
+            },
+        ) => {
+            same_name(&writer_name.name, &reader_name.name)
+                && writer_symbols
+                    .iter()
+                    .all(|symbol| reader_symbols.contains(symbol))
+        }
+        (
+            Schema::Fixed {
+                name: writer_name,
+                size: writer_size,
+            },
+            Schema::Fixed {
+                name: reader_name,
+                size: reader_size,
+            },
+        ) => same_name(&writer_name.name, &reader_name.name) && writer_size == reader_size,
+        (Schema::Decimal { .. }, Schema::Decimal { .. }) => writer_schema == reader_schema,
+        _ => match (primitive_type(writer_schema), primitive_type(reader_schema)) {
+            (Some(writer), Some(reader)) => {
+                matches!(
+                    (writer, reader),
+                    ("int", "long")
+                        | ("int", "float")
+                        | ("int", "double")
+                        | ("long", "float")
+                        | ("long", "double")
+                        | ("float", "double")
+                        | ("string", "bytes")
+                        | ("bytes", "string")
+                ) || writer == reader
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Avro allows names to be fully qualified, e.g. in the canonical form of a
+/// schema, even when a namespace is also provided, so compare only the
+/// unqualified part of names.
+fn same_name(a: &str, b: &str) -> bool {
+    a.rsplit('.').next() == b.rsplit('.').next()
+}
+
+/// Returns the name of the primitive type of the provided schema, which for
+/// logical types is the type they annotate, or None for complex types.
+fn primitive_type(schema: &Schema) -> Option<&'static str> {
+    match schema {
+        Schema::Null => Some("null"),
+        Schema::Boolean => Some("boolean"),
+        Schema::Int | Schema::Date | Schema::TimeMillis => Some("int"),
+        Schema::Long | Schema::TimeMicros | Schema::TimestampMillis | Schema::TimestampMicros => {
+            Some("long")
+        }
+        Schema::Float => Some("float"),
+        Schema::Double => Some("double"),
+        Schema::Bytes => Some("bytes"),
+        Schema::String | Schema::Uuid => Some("string"),
+        _ => None,
+    }
+}
+
 pub trait Header: Sized {
     /// Returns the SHA256 digest of the packet file this header describes.
     fn packet_file_digest(&self) -> &Vec<u8>;
+    /// Returns the SHA256 digests of the packet file shards this header
+    /// describes, in order. If empty, the batch has a single packet file whose
+    /// digest is packet_file_digest.
+    fn packet_file_shard_digests(&self) -> &[Vec<u8>] {
+        &[]
+    }
     /// Reads and parses one Header from the provided std::io::Read instance.
     fn read<R: Read>(reader: R) -> Result<Self, Error>;
     /// Serializes this message into Avro format and writes it to the provided
@@ -55,6 +184,177 @@ pub trait Packet: Sized {
     fn schema() -> Schema {
         Schema::parse_str(Self::schema_raw()).unwrap()
     }
+
+    /// Like Packet::schema, but for reading packet files only: an
+    /// avro_rs::Reader created with it over a file passed through
+    /// resolvable_container resolves the schema the file was written with
+    /// against it, skipping fields that the writer added and defaulting
+    /// optional fields that the writer lacks to null.
+    fn reader_schema() -> Schema {
+        let mut schema_json = serde_json::from_str(Self::schema_raw()).unwrap();
+        placeholder_null_defaults(&mut schema_json);
+        Schema::parse(&schema_json).unwrap()
+    }
+}
+
+/// Gives every record field in the JSON schema that defaults to null, or that
+/// is a union whose first branch is null and lacks a default, a placeholder
+/// default instead, so that avro_rs resolves such a field to null when the
+/// writer's schema lacks it. Our schemas can't declare "default": null, and a
+/// writer's schema can't be compared with ours if it does, since avro_rs 0.11
+/// panics on a null default when computing a schema's canonical form. The
+/// placeholder is the string "null", which avro_rs ignores in favor of null
+/// for a union whose first branch is null, and which is never written out.
+fn placeholder_null_defaults(schema: &mut serde_json::Value) {
+    match schema {
+        serde_json::Value::Array(variants) => {
+            variants.iter_mut().for_each(placeholder_null_defaults)
+        }
+        serde_json::Value::Object(object) => {
+            if let Some(serde_json::Value::Array(fields)) = object.get_mut("fields") {
+                for field in fields.iter_mut() {
+                    let optional = field["type"]
+                        .as_array()
+                        .and_then(|variants| variants.first())
+                        .is_some_and(|first| *first == "null");
+                    match field.get("default") {
+                        Some(serde_json::Value::Null) => (),
+                        None if optional => (),
+                        _ => continue,
+                    }
+                    field["default"] = serde_json::Value::String("null".to_owned());
+                }
+                fields
+                    .iter_mut()
+                    .filter_map(|field| field.get_mut("type"))
+                    .for_each(placeholder_null_defaults);
+            }
+            for key in &["items", "values"] {
+                if let Some(schema) = object.get_mut(*key) {
+                    placeholder_null_defaults(schema);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// The bytes with which an Avro object container file begins.
+const CONTAINER_MAGIC: &[u8; 4] = b"Obj\x01";
+
+/// The codecs an Avro object container file may be compressed with that
+/// avro_rs can decompress. A file whose header names none of them is written
+/// uncompressed.
+const SUPPORTED_CODECS: &[&str] = &["null", "deflate", "snappy"];
+
+/// Reads the header of the Avro object container file from the provided
+/// reader and returns a reader over the whole file in which the schema in the
+/// header has been passed through placeholder_null_defaults, so that an
+/// avro_rs::Reader created over it with a Packet::reader_schema can compare
+/// and resolve the two schemas. The rest of the file is passed through as is,
+/// to be decompressed by the avro_rs::Reader with the codec the header names,
+/// which must be one of SUPPORTED_CODECS.
+pub fn resolvable_container<R: Read>(
+    mut file: R,
+) -> Result<std::io::Chain<Cursor<Vec<u8>>, R>, Error> {
+    let malformed = |what: &str| {
+        Error::MalformedDataPacketError(format!("malformed Avro container header: {}", what))
+    };
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)
+        .map_err(|_| malformed("too short"))?;
+    if &magic != CONTAINER_MAGIC {
+        return Err(malformed("not an Avro object container"));
+    }
+    let mut header = magic.to_vec();
+
+    // The header's metadata is a map of names to bytes, in blocks each
+    // preceded by a count of entries, which is negated if followed by the size
+    // of the block. Rewrite it as a single block.
+    let mut metadata = Vec::new();
+    loop {
+        let count = read_long(&mut file).ok_or_else(|| malformed("truncated metadata"))?;
+        if count == 0 {
+            break;
+        }
+        if count < 0 {
+            read_long(&mut file).ok_or_else(|| malformed("truncated metadata"))?;
+        }
+        for _ in 0..count.unsigned_abs() {
+            let key = read_bytes(&mut file).ok_or_else(|| malformed("truncated metadata"))?;
+            let mut value = read_bytes(&mut file).ok_or_else(|| malformed("truncated metadata"))?;
+            if key == b"avro.schema" {
+                let mut schema: serde_json::Value =
+                    serde_json::from_slice(&value).map_err(|_| malformed("schema is not JSON"))?;
+                placeholder_null_defaults(&mut schema);
+                value = serde_json::to_vec(&schema).unwrap();
+            }
+            if key == b"avro.codec"
+                && !SUPPORTED_CODECS
+                    .iter()
+                    .any(|codec| codec.as_bytes() == value.as_slice())
+            {
+                return Err(Error::MalformedDataPacketError(format!(
+                    "unsupported Avro codec {}",
+                    String::from_utf8_lossy(&value)
+                )));
+            }
+            metadata.push((key, value));
+        }
+    }
+    if !metadata.is_empty() {
+        write_long(&mut header, metadata.len() as i64);
+    }
+    for (key, value) in &metadata {
+        write_long(&mut header, key.len() as i64);
+        header.extend_from_slice(key);
+        write_long(&mut header, value.len() as i64);
+        header.extend_from_slice(value);
+    }
+    write_long(&mut header, 0);
+
+    let mut sync_marker = [0u8; 16];
+    file.read_exact(&mut sync_marker)
+        .map_err(|_| malformed("truncated sync marker"))?;
+    header.extend_from_slice(&sync_marker);
+    Ok(Cursor::new(header).chain(file))
+}
+
+/// Reads an Avro long, i.e. a zig-zag encoded variable length integer, or
+/// returns None if the reader ends first or the integer is too long.
+fn read_long<R: Read>(reader: &mut R) -> Option<i64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte).ok()?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Some((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    None
+}
+
+/// Appends the Avro encoding of the long to the buffer.
+fn write_long(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Reads Avro bytes, i.e. a long length followed by that many bytes, or
+/// returns None if the reader ends first.
+fn read_bytes<R: Read>(reader: &mut R) -> Option<Vec<u8>> {
+    let length = u64::try_from(read_long(reader)?).ok()?;
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes).ok()?;
+    if bytes.len() as u64 != length {
+        return None;
+    }
+    Some(bytes)
 }
 
 /// The header on a Prio ingestion batch.
@@ -70,6 +370,11 @@ pub struct IngestionHeader {
     pub batch_start_time: i64,
     pub batch_end_time: i64,
     pub packet_file_digest: Vec<u8>,
+    #[serde(default)]
+    pub packet_file_shard_digests: Vec<Vec<u8>>,
+    /// Number of packets the ingestor says it put into the batch, if it says.
+    #[serde(default)]
+    pub packet_count: Option<u64>,
 }
 
 impl IngestionHeader {
@@ -83,6 +388,36 @@ impl IngestionHeader {
             && self.number_of_servers == validation_header.number_of_servers
             && self.hamming_weight == validation_header.hamming_weight
     }
+
+    /// Checks that the epsilon the header declares is finite, greater than
+    /// zero and no greater than max_epsilon, and, if min_epsilon is provided,
+    /// no less than min_epsilon, failing with Error::MalformedHeaderError
+    /// otherwise. A value outside the range suggests a misconfigured ingestor
+    /// whose batches would not get the privacy the aggregation is meant to
+    /// provide.
+    pub fn validate(&self, min_epsilon: Option<f64>, max_epsilon: f64) -> Result<(), Error> {
+        if !(self.epsilon.is_finite() && self.epsilon > 0.0) {
+            return Err(Error::MalformedHeaderError(format!(
+                "epsilon is {} but must be finite and greater than zero",
+                self.epsilon
+            )));
+        }
+        if let Some(min_epsilon) = min_epsilon {
+            if self.epsilon < min_epsilon {
+                return Err(Error::MalformedHeaderError(format!(
+                    "epsilon is {} but must be at least {}",
+                    self.epsilon, min_epsilon
+                )));
+            }
+        }
+        if self.epsilon > max_epsilon {
+            return Err(Error::MalformedHeaderError(format!(
+                "epsilon is {} but must be at most {}",
+                self.epsilon, max_epsilon
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl Header for IngestionHeader {
@@ -90,6 +425,10 @@ impl Header for IngestionHeader {
         &self.packet_file_digest
     }
 
+    fn packet_file_shard_digests(&self) -> &[Vec<u8>] {
+        &self.packet_file_shard_digests
+    }
+
     fn read<R: Read>(reader: R) -> Result<IngestionHeader, Error> {
         let schema = Schema::parse_str(INGESTION_HEADER_SCHEMA).map_err(|e| {
             Error::AvroError("failed to parse ingestion header schema".to_owned(), e)
@@ -134,6 +473,8 @@ impl Header for IngestionHeader {
         let mut batch_start_time = None;
         let mut batch_end_time = None;
         let mut packet_file_digest = None;
+        let mut packet_file_shard_digests = Vec::new();
+        let mut packet_count = None;
 
         for tuple in record {
             match (tuple.0.as_str(), tuple.1) {
@@ -158,6 +499,20 @@ impl Header for IngestionHeader {
                 ("batch_start_time", Value::TimestampMillis(v)) => batch_start_time = Some(v),
                 ("batch_end_time", Value::TimestampMillis(v)) => batch_end_time = Some(v),
                 ("packet_file_digest", Value::Bytes(v)) => packet_file_digest = Some(v),
+                ("packet_file_shard_digests", Value::Array(values)) => {
+                    for v in values {
+                        match v {
+                            Value::Bytes(v) => packet_file_shard_digests.push(v),
+                            v => {
+                                return Err(Error::MalformedHeaderError(format!(
+                                    "unexpected value {:?} for packet file shard digest",
+                                    v
+                                )));
+                            }
+                        }
+                    }
+                }
+                ("packet_count", Value::Long(v)) => packet_count = read_packet_count(v)?,
                 (f, v) => {
                     return Err(Error::MalformedHeaderError(format!(
                         "unexpected field {} -> {:?} in record",
@@ -193,6 +548,8 @@ impl Header for IngestionHeader {
             batch_start_time: batch_start_time.unwrap(),
             batch_end_time: batch_end_time.unwrap(),
             packet_file_digest: packet_file_digest.unwrap(),
+            packet_file_shard_digests,
+            packet_count,
         })
     }
 
@@ -237,6 +594,19 @@ impl Header for IngestionHeader {
             "packet_file_digest",
             Value::Bytes(self.packet_file_digest.clone()),
         );
+        record.put(
+            "packet_file_shard_digests",
+            Value::Array(
+                self.packet_file_shard_digests
+                    .iter()
+                    .map(|v| Value::Bytes(v.clone()))
+                    .collect(),
+            ),
+        );
+        record.put(
+            "packet_count",
+            Value::Long(self.packet_count.map_or(-1, |v| v as i64)),
+        );
 
         writer.append(record).map_err(|e| {
             Error::AvroError("failed to append record to Avro writer".to_owned(), e)
@@ -261,6 +631,9 @@ pub struct IngestionDataSharePacket {
     pub r_pit: i64,
     pub version_configuration: Option<String>,
     pub device_nonce: Option<Vec<u8>>,
+    /// The position of the packet in the packet file, counting from zero, if
+    /// the ingestor numbers its packets.
+    pub sequence_number: Option<i64>,
 }
 
 impl Packet for IngestionDataSharePacket {
@@ -293,6 +666,7 @@ impl Packet for IngestionDataSharePacket {
         let mut r_pit = None;
         let mut version_configuration = None;
         let mut device_nonce = None;
+        let mut sequence_number = None;
 
         for tuple in record {
             match (tuple.0.as_str(), tuple.1) {
@@ -320,6 +694,16 @@ impl Packet for IngestionDataSharePacket {
                         )))
                     }
                 },
+                ("sequence_number", Value::Union(boxed)) => match *boxed {
+                    Value::Long(v) => sequence_number = Some(v),
+                    Value::Null => sequence_number = None,
+                    v => {
+                        return Err(Error::MalformedDataPacketError(format!(
+                            "unexpected boxed value {:?} in sequence_number",
+                            v
+                        )))
+                    }
+                },
                 (f, _) => {
                     return Err(Error::MalformedDataPacketError(format!(
                         "unexpected field {} in record",
@@ -346,6 +730,7 @@ impl Packet for IngestionDataSharePacket {
             r_pit: r_pit.unwrap(),
             version_configuration,
             device_nonce,
+            sequence_number,
         })
     }
 
@@ -384,6 +769,10 @@ impl Packet for IngestionDataSharePacket {
             ),
             None => record.put("device_nonce", Value::Union(Box::new(Value::Null))),
         }
+        match self.sequence_number {
+            Some(v) => record.put("sequence_number", Value::Union(Box::new(Value::Long(v)))),
+            None => record.put("sequence_number", Value::Union(Box::new(Value::Null))),
+        }
 
         writer.append(record).map_err(|e| {
             Error::AvroError("failed to append record to Avro writer".to_owned(), e)
@@ -405,6 +794,14 @@ pub struct ValidationHeader {
     pub number_of_servers: i32,
     pub hamming_weight: Option<i32>,
     pub packet_file_digest: Vec<u8>,
+    /// Number of packets in the packet file, written so that peers and
+    /// aggregators may cross-check it. Older share processors don't write it.
+    #[serde(default)]
+    pub packet_count: Option<u64>,
+    /// The algorithm with which the header was signed, so that verifiers
+    /// needn't guess. Older share processors don't write it.
+    #[serde(default)]
+    pub signature_algorithm: Option<SignatureAlgorithm>,
 }
 
 impl ValidationHeader {
@@ -470,6 +867,8 @@ impl Header for ValidationHeader {
         let mut number_of_servers = None;
         let mut hamming_weight = None;
         let mut packet_file_digest = None;
+        let mut packet_count = None;
+        let mut signature_algorithm = None;
 
         for tuple in record {
             match (tuple.0.as_str(), tuple.1) {
@@ -492,6 +891,17 @@ impl Header for ValidationHeader {
                     }
                 }
                 ("packet_file_digest", Value::Bytes(v)) => packet_file_digest = Some(v),
+                ("packet_count", Value::Long(v)) => packet_count = read_packet_count(v)?,
+                ("signature_algorithm", Value::String(v)) if v.is_empty() => (),
+                ("signature_algorithm", Value::String(v)) => {
+                    signature_algorithm =
+                        Some(SignatureAlgorithm::from_name(&v).ok_or_else(|| {
+                            Error::MalformedHeaderError(format!(
+                                "unsupported signature algorithm {}",
+                                v
+                            ))
+                        })?)
+                }
                 (f, v) => {
                     return Err(Error::MalformedHeaderError(format!(
                         "unexpected field {} -> {:?} in record",
@@ -523,6 +933,8 @@ impl Header for ValidationHeader {
             number_of_servers: number_of_servers.unwrap(),
             hamming_weight,
             packet_file_digest: packet_file_digest.unwrap(),
+            packet_count,
+            signature_algorithm,
         })
     }
 
@@ -555,6 +967,18 @@ impl Header for ValidationHeader {
             "packet_file_digest",
             Value::Bytes(self.packet_file_digest.clone()),
         );
+        record.put(
+            "packet_count",
+            Value::Long(self.packet_count.map_or(-1, |v| v as i64)),
+        );
+        record.put(
+            "signature_algorithm",
+            Value::String(
+                self.signature_algorithm
+                    .map_or("", |algorithm| algorithm.name())
+                    .to_owned(),
+            ),
+        );
 
         writer.append(record).map_err(|e| {
             Error::AvroError("failed to append record to Avro writer".to_owned(), e)
@@ -568,6 +992,61 @@ impl Header for ValidationHeader {
     }
 }
 
+/// An algorithm with which headers may be signed, as recorded in validation
+/// headers' signature_algorithm field. Absence is encoded as an empty string,
+/// for the same reason as packet_count's -1.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// ECDSA over P-256 with SHA-256, with signatures in the fixed-length
+    /// encoding. All keys in this deployment use it.
+    #[serde(rename = "ECDSA_P256_SHA256_FIXED")]
+    EcdsaP256Sha256Fixed,
+}
+
+impl SignatureAlgorithm {
+    /// The name recorded in headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            SignatureAlgorithm::EcdsaP256Sha256Fixed => "ECDSA_P256_SHA256_FIXED",
+        }
+    }
+
+    /// Parses a name recorded in a header, returning None for algorithms we
+    /// don't support.
+    pub fn from_name(name: &str) -> Option<SignatureAlgorithm> {
+        match name {
+            "ECDSA_P256_SHA256_FIXED" => Some(SignatureAlgorithm::EcdsaP256Sha256Fixed),
+            _ => None,
+        }
+    }
+
+    /// The ring algorithm with which to verify signatures made with this one.
+    pub fn verification_algorithm(self) -> &'static dyn VerificationAlgorithm {
+        match self {
+            SignatureAlgorithm::EcdsaP256Sha256Fixed => &ECDSA_P256_SHA256_FIXED,
+        }
+    }
+}
+
+/// Interprets the packet_count field in ingestion and validation headers.
+/// Rather than a union with null, which is what hamming_weight uses, absence is
+/// encoded as -1: avro_rs can't handle schemas with null defaults, and without
+/// a default, headers written before the field existed could not be read.
+fn read_packet_count(value: i64) -> Result<Option<u64>, Error> {
+    match value {
+        -1 => Ok(None),
+        v if v >= 0 => Ok(Some(v as u64)),
+        v => Err(Error::MalformedHeaderError(format!(
+            "packet count is {}",
+            v
+        ))),
+    }
+}
+
+/// The libprio verification message for one data share packet. f_r, g_r and
+/// h_r are elements of finite_field::Field, whose modulus is below 2^32, so
+/// they always fit in an Avro long. See ValidationPacket::new and the
+/// conversion to VerificationMessage.
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct ValidationPacket {
     pub uuid: Uuid,
@@ -576,6 +1055,19 @@ pub struct ValidationPacket {
     pub h_r: i64,
 }
 
+impl ValidationPacket {
+    /// Creates the ValidationPacket for the packet with the provided UUID from
+    /// its verification message. The conversion is lossless.
+    pub fn new(uuid: Uuid, message: &VerificationMessage) -> ValidationPacket {
+        ValidationPacket {
+            uuid,
+            f_r: i64::from(u32::from(message.f_r)),
+            g_r: i64::from(u32::from(message.g_r)),
+            h_r: i64::from(u32::from(message.h_r)),
+        }
+    }
+}
+
 impl Packet for ValidationPacket {
     fn schema_raw() -> &'static str {
         VALIDATION_PACKET_SCHEMA
@@ -624,18 +1116,78 @@ impl Packet for ValidationPacket {
     }
 }
 
+/// Fails with Error::LibPrioError if any of the packet's values is not an
+/// element of finite_field::Field, rather than reducing it modulo MODULUS as
+/// Field::from would. Unlike an ingestion packet's r_pit, which share
+/// processors have always reduced (see intake::PrimeField::element), these
+/// values are computed by a share processor and are always field elements, so
+/// one that isn't means the packet is corrupt.
 impl TryFrom<&ValidationPacket> for VerificationMessage {
-    type Error = TryFromIntError;
+    type Error = Error;
 
     fn try_from(p: &ValidationPacket) -> Result<Self, Self::Error> {
+        let element = |name: &str, value: i64| {
+            u32::try_from(value)
+                .ok()
+                .filter(|value| *value < MODULUS)
+                .map(Field::from)
+                .ok_or_else(|| {
+                    Error::LibPrioError(format!(
+                        "{} value {} in packet {} is not an element of the field",
+                        name, value, p.uuid
+                    ))
+                })
+        };
         Ok(VerificationMessage {
-            f_r: Field::from(u32::try_from(p.f_r)?),
-            g_r: Field::from(u32::try_from(p.g_r)?),
-            h_r: Field::from(u32::try_from(p.h_r)?),
+            f_r: element("f_r", p.f_r)?,
+            g_r: element("g_r", p.g_r)?,
+            h_r: element("h_r", p.h_r)?,
         })
     }
 }
 
+/// Iterates over the ValidationPackets in a validation packet file. This does
+/// nothing to authenticate the packet file: callers that need to trust its
+/// content should read it through batch::BatchReader instead, which checks it
+/// against a signed header.
+pub struct ValidationPacketReader<R: Read> {
+    reader: Reader<'static, std::io::Chain<Cursor<Vec<u8>>, R>>,
+}
+
+impl<R: Read> ValidationPacketReader<R> {
+    /// Creates a ValidationPacketReader over the provided packet file, failing
+    /// if it was not written with a schema that ValidationPacket can be read
+    /// from.
+    pub fn new(packet_file: R) -> Result<ValidationPacketReader<R>, Error> {
+        // Reading with our schema rather than just the writer's resolves the
+        // one against the other, so that e.g. fields added by newer writers
+        // are skipped. The reader borrows the schema for as long as it lives.
+        static SCHEMA: OnceLock<Schema> = OnceLock::new();
+        let schema = SCHEMA.get_or_init(ValidationPacket::reader_schema);
+        let reader =
+            Reader::with_schema(schema, resolvable_container(packet_file)?).map_err(|e| {
+                Error::AvroError("failed to create Avro reader for packets".to_owned(), e)
+            })?;
+        if !can_read_schema(reader.writer_schema(), schema) {
+            return Err(Error::MalformedDataPacketError(
+                "packet file was not written with the validation packet schema".to_owned(),
+            ));
+        }
+        Ok(ValidationPacketReader { reader })
+    }
+}
+
+impl<R: Read> Iterator for ValidationPacketReader<R> {
+    type Item = Result<ValidationPacket, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match ValidationPacket::read(&mut self.reader) {
+            Err(Error::EofError) => None,
+            result => Some(result),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct SumPart {
     pub batch_uuids: Vec<Uuid>,
@@ -920,6 +1472,83 @@ impl Packet for InvalidPacket {
 mod tests {
     use super::*;
 
+    #[test]
+    fn validate_ingestion_header_epsilon() {
+        let header = |epsilon| IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-batch".to_owned(),
+            bins: 2,
+            epsilon,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: Vec::new(),
+            packet_file_shard_digests: Vec::new(),
+            packet_count: None,
+        };
+        let cases: &[(f64, Option<f64>, f64, Option<&str>)] = &[
+            (0.5, Some(0.5), 2.0, None),
+            (2.0, Some(0.5), 2.0, None),
+            (1e-9, None, 2.0, None),
+            (
+                0.49,
+                Some(0.5),
+                2.0,
+                Some("epsilon is 0.49 but must be at least 0.5"),
+            ),
+            (
+                2.01,
+                Some(0.5),
+                2.0,
+                Some("epsilon is 2.01 but must be at most 2"),
+            ),
+            (
+                0.0,
+                None,
+                2.0,
+                Some("epsilon is 0 but must be finite and greater than zero"),
+            ),
+            (
+                -1.0,
+                Some(-2.0),
+                2.0,
+                Some("epsilon is -1 but must be finite and greater than zero"),
+            ),
+        ];
+        for (epsilon, min_epsilon, max_epsilon, expected_error) in cases {
+            match (
+                header(*epsilon).validate(*min_epsilon, *max_epsilon),
+                expected_error,
+            ) {
+                (Ok(()), None) => (),
+                (Err(Error::MalformedHeaderError(message)), Some(expected_error)) => {
+                    assert_eq!(message, *expected_error)
+                }
+                (result, _) => panic!("unexpected result {:?} for epsilon {}", result, epsilon),
+            }
+        }
+    }
+
+    #[test]
+    fn schema_version() {
+        for schema in &[
+            INGESTION_HEADER_SCHEMA,
+            INGESTION_DATA_SHARE_PACKET_SCHEMA,
+            VALIDATION_HEADER_SCHEMA,
+            VALIDATION_PACKET_SCHEMA,
+            SUM_PART_SCHEMA,
+            INVALID_PACKET_SCHEMA,
+        ] {
+            let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+            assert_eq!(
+                schema["namespace"],
+                format!("org.abetterinternet.prio.{}", SCHEMA_VERSION)
+            );
+        }
+    }
+
     #[test]
     fn roundtrip_ingestion_header() {
         let headers = &[
@@ -934,6 +1563,8 @@ mod tests {
                 batch_start_time: 789456123,
                 batch_end_time: 789456321,
                 packet_file_digest: vec![1u8],
+                packet_file_shard_digests: vec![],
+                packet_count: None,
             },
             IngestionHeader {
                 batch_uuid: Uuid::new_v4(),
@@ -946,6 +1577,22 @@ mod tests {
                 batch_start_time: 789456123,
                 batch_end_time: 789456321,
                 packet_file_digest: vec![2u8],
+                packet_file_shard_digests: vec![],
+                packet_count: Some(1000),
+            },
+            IngestionHeader {
+                batch_uuid: Uuid::new_v4(),
+                name: "fake-batch".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: vec![],
+                packet_file_shard_digests: vec![vec![3u8], vec![4u8, 5u8]],
+                packet_count: None,
             },
         ];
 
@@ -958,6 +1605,165 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_unsharded_ingestion_header() {
+        // Ingestors that predate packet file shards write headers without the
+        // packet_file_shard_digests field, which must still be readable.
+        let mut schema_json: serde_json::Value =
+            serde_json::from_str(INGESTION_HEADER_SCHEMA).unwrap();
+        schema_json["fields"]
+            .as_array_mut()
+            .unwrap()
+            .retain(|field| {
+                field["name"] != "packet_file_shard_digests" && field["name"] != "packet_count"
+            });
+        let schema = Schema::parse_str(&schema_json.to_string()).unwrap();
+
+        let batch_uuid = Uuid::new_v4();
+        let mut record = Record::new(&schema).unwrap();
+        record.put("batch_uuid", Value::Uuid(batch_uuid));
+        record.put("name", Value::String("fake-batch".to_owned()));
+        record.put("bins", Value::Int(2));
+        record.put("epsilon", Value::Double(1.601));
+        record.put("prime", Value::Long(17));
+        record.put("number_of_servers", Value::Int(2));
+        record.put("hamming_weight", Value::Union(Box::new(Value::Null)));
+        record.put("batch_start_time", Value::TimestampMillis(789456123));
+        record.put("batch_end_time", Value::TimestampMillis(789456321));
+        record.put("packet_file_digest", Value::Bytes(vec![1u8]));
+
+        let mut writer = Writer::new(&schema, Vec::new());
+        writer.append(record).unwrap();
+        let header = IngestionHeader::read(&writer.into_inner().unwrap()[..]).unwrap();
+        assert_eq!(header.batch_uuid, batch_uuid);
+        assert_eq!(header.packet_file_digest, vec![1u8]);
+        assert!(header.packet_file_shard_digests.is_empty());
+    }
+
+    #[test]
+    fn validation_packet_reader_skips_added_fields() {
+        let mut schema_json: serde_json::Value =
+            serde_json::from_str(VALIDATION_PACKET_SCHEMA).unwrap();
+        schema_json["fields"].as_array_mut().unwrap().push(
+            serde_json::from_str(
+                r#"{"name": "note", "type": ["null", "string"], "default": null}"#,
+            )
+            .unwrap(),
+        );
+        let schema = Schema::parse_str(&schema_json.to_string()).unwrap();
+
+        let uuid = Uuid::new_v4();
+        let mut record = Record::new(&schema).unwrap();
+        record.put("uuid", Value::Uuid(uuid));
+        record.put("f_r", Value::Long(1));
+        record.put("g_r", Value::Long(2));
+        record.put("h_r", Value::Long(3));
+        record.put(
+            "note",
+            Value::Union(Box::new(Value::String("hi".to_owned()))),
+        );
+        let mut writer = Writer::new(&schema, Vec::new());
+        writer.append(record).unwrap();
+        let packets = ValidationPacketReader::new(&writer.into_inner().unwrap()[..])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            packets,
+            vec![ValidationPacket {
+                uuid,
+                f_r: 1,
+                g_r: 2,
+                h_r: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn read_headers_without_packet_count() {
+        // Ingestors and share processors that predate packet counts write
+        // headers without the packet_count field (nor, for validation headers,
+        // signature_algorithm), which must still be readable.
+        let schema_without_packet_count = |schema: &str| {
+            let mut schema_json: serde_json::Value = serde_json::from_str(schema).unwrap();
+            schema_json["fields"]
+                .as_array_mut()
+                .unwrap()
+                .retain(|field| {
+                    field["name"] != "packet_count" && field["name"] != "signature_algorithm"
+                });
+            Schema::parse_str(&schema_json.to_string()).unwrap()
+        };
+
+        let schema = schema_without_packet_count(INGESTION_HEADER_SCHEMA);
+        let mut record = Record::new(&schema).unwrap();
+        record.put("batch_uuid", Value::Uuid(Uuid::new_v4()));
+        record.put("name", Value::String("fake-batch".to_owned()));
+        record.put("bins", Value::Int(2));
+        record.put("epsilon", Value::Double(1.601));
+        record.put("prime", Value::Long(17));
+        record.put("number_of_servers", Value::Int(2));
+        record.put("hamming_weight", Value::Union(Box::new(Value::Null)));
+        record.put("batch_start_time", Value::TimestampMillis(789456123));
+        record.put("batch_end_time", Value::TimestampMillis(789456321));
+        record.put("packet_file_digest", Value::Bytes(vec![1u8]));
+        record.put("packet_file_shard_digests", Value::Array(vec![]));
+        let mut writer = Writer::new(&schema, Vec::new());
+        writer.append(record).unwrap();
+        let header = IngestionHeader::read(&writer.into_inner().unwrap()[..]).unwrap();
+        assert_eq!(header.packet_count, None);
+
+        let schema = schema_without_packet_count(VALIDATION_HEADER_SCHEMA);
+        let mut record = Record::new(&schema).unwrap();
+        record.put("batch_uuid", Value::Uuid(Uuid::new_v4()));
+        record.put("name", Value::String("fake-batch".to_owned()));
+        record.put("bins", Value::Int(2));
+        record.put("epsilon", Value::Double(1.601));
+        record.put("prime", Value::Long(17));
+        record.put("number_of_servers", Value::Int(2));
+        record.put("hamming_weight", Value::Union(Box::new(Value::Null)));
+        record.put("packet_file_digest", Value::Bytes(vec![1u8]));
+        let mut writer = Writer::new(&schema, Vec::new());
+        writer.append(record).unwrap();
+        let header = ValidationHeader::read(&writer.into_inner().unwrap()[..]).unwrap();
+        assert_eq!(header.packet_count, None);
+        assert_eq!(header.signature_algorithm, None);
+    }
+
+    #[test]
+    fn unsupported_signature_algorithm() {
+        let header = ValidationHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-batch".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            packet_file_digest: vec![4u8],
+            packet_count: None,
+            signature_algorithm: Some(SignatureAlgorithm::EcdsaP256Sha256Fixed),
+        };
+        let mut record_vec = Vec::new();
+        header.write(&mut record_vec).unwrap();
+        // Rewrite the algorithm's name in place, keeping its length so the
+        // Avro string encoding stays valid.
+        let name = SignatureAlgorithm::EcdsaP256Sha256Fixed.name().as_bytes();
+        let position = record_vec
+            .windows(name.len())
+            .rposition(|window| window == name)
+            .unwrap();
+        record_vec[position..position + 5].copy_from_slice(b"ecdsa");
+
+        match ValidationHeader::read(&record_vec[..]) {
+            Err(Error::MalformedHeaderError(message)) => assert_eq!(
+                message,
+                "unsupported signature algorithm ecdsa_P256_SHA256_FIXED"
+            ),
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+
     #[test]
     fn roundtrip_data_share_packet() {
         let packets = &[
@@ -968,6 +1774,7 @@ mod tests {
                 r_pit: 1,
                 version_configuration: Some("config-1".to_owned()),
                 device_nonce: None,
+                sequence_number: None,
             },
             IngestionDataSharePacket {
                 uuid: Uuid::new_v4(),
@@ -976,6 +1783,7 @@ mod tests {
                 r_pit: 2,
                 version_configuration: None,
                 device_nonce: Some(vec![8u8, 9u8, 10u8, 11u8]),
+                sequence_number: Some(1),
             },
             IngestionDataSharePacket {
                 uuid: Uuid::new_v4(),
@@ -984,6 +1792,7 @@ mod tests {
                 r_pit: 3,
                 version_configuration: None,
                 device_nonce: None,
+                sequence_number: None,
             },
         ];
 
@@ -1022,6 +1831,8 @@ mod tests {
                 number_of_servers: 2,
                 hamming_weight: None,
                 packet_file_digest: vec![4u8],
+                packet_count: None,
+                signature_algorithm: None,
             },
             ValidationHeader {
                 batch_uuid: Uuid::new_v4(),
@@ -1032,6 +1843,8 @@ mod tests {
                 number_of_servers: 2,
                 hamming_weight: Some(12),
                 packet_file_digest: vec![6u8],
+                packet_count: Some(77),
+                signature_algorithm: Some(SignatureAlgorithm::EcdsaP256Sha256Fixed),
             },
         ];
 
@@ -1090,6 +1903,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn validation_packet_field_bounds() {
+        let uuid = Uuid::new_v4();
+        for value in &[0, 1, MODULUS - 1] {
+            let message = VerificationMessage {
+                f_r: Field::from(*value),
+                g_r: Field::from(MODULUS - 1 - *value),
+                h_r: Field::from(*value),
+            };
+            let packet = ValidationPacket::new(uuid, &message);
+            assert_eq!(packet.f_r, i64::from(*value));
+            assert_eq!(packet.g_r, i64::from(MODULUS - 1 - *value));
+
+            let mut record_vec = Vec::new();
+            let schema = ValidationPacket::schema();
+            let mut writer = Writer::new(&schema, &mut record_vec);
+            packet.write(&mut writer).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            let mut reader = Reader::with_schema(&schema, &record_vec[..]).unwrap();
+            let packet_again = ValidationPacket::read(&mut reader).unwrap();
+            assert_eq!(packet_again, packet);
+            let message_again = VerificationMessage::try_from(&packet_again).unwrap();
+            assert_eq!(
+                (message_again.f_r, message_again.g_r, message_again.h_r),
+                (message.f_r, message.g_r, message.h_r)
+            );
+        }
+
+        for value in &[
+            -1,
+            i64::from(MODULUS),
+            i64::from(u32::MAX),
+            i64::from(u32::MAX) + 1,
+            i64::MAX,
+            i64::MIN,
+        ] {
+            let packets = [
+                ValidationPacket {
+                    uuid,
+                    f_r: *value,
+                    g_r: 0,
+                    h_r: 0,
+                },
+                ValidationPacket {
+                    uuid,
+                    f_r: 0,
+                    g_r: 0,
+                    h_r: *value,
+                },
+            ];
+            for packet in &packets {
+                match VerificationMessage::try_from(packet) {
+                    Err(Error::LibPrioError(message)) => {
+                        assert!(message.contains(&value.to_string()), "{}", message)
+                    }
+                    v => panic!("unexpected result {:?}", v.map(|_| ())),
+                }
+            }
+        }
+    }
+
     #[test]
     fn roundtrip_sum_part() {
         let headers = &[
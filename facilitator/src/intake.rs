@@ -1,118 +1,1309 @@
 use crate::{
-    batch::{Batch, BatchReader, BatchWriter},
-    idl::{IngestionDataSharePacket, IngestionHeader, Packet, ValidationHeader, ValidationPacket},
-    transport::Transport,
+    batch::{
+        AggregationName, Batch, BatchDate, BatchDateWindow, BatchFileKind, BatchIdentity,
+        BatchManifest, BatchNamingScheme, BatchReader, BatchWriter, Clock, DoneMarker,
+        InstanceName, PacketFailure, ServerIdentity, ShardedPacketReader, SystemClock,
+        DEFAULT_COPY_BUFFER_SIZE, DEFAULT_NAMING_SCHEME, DEFAULT_READ_BUFFER_SIZE,
+        DEFAULT_SPOOL_THRESHOLD,
+    },
+    idl::{
+        IngestionDataSharePacket, IngestionHeader, Packet, SignatureAlgorithm, ValidationHeader,
+        ValidationPacket,
+    },
+    replay::ProcessedBatchStore,
+    server_pool::{PooledServer, ServerPool},
+    transport::{
+        MeteredTransport, NullTransport, RetryPolicy, Transport, TransportMeter,
+        DEFAULT_RETRY_POLICY,
+    },
     Error,
 };
 use anyhow::{anyhow, Context, Result};
-use chrono::NaiveDateTime;
-use prio::{encrypt::PrivateKey, finite_field::Field, server::Server};
-use ring::signature::{EcdsaKeyPair, UnparsedPublicKey};
-use std::convert::TryFrom;
+use avro_rs::Writer;
+use prio::{
+    encrypt::{decrypt_share, PrivateKey},
+    finite_field::{Field, MODULUS},
+    server::Server,
+};
+use ring::{
+    rand::SecureRandom,
+    signature::{EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    io::{Read, Write},
+    ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 
+/// The number of servers participating in the MPC protocol between the PHA and
+/// the facilitator.
+pub const DEFAULT_NUMBER_OF_SERVERS: i32 = 2;
+
+/// The default largest epsilon that ingestion headers may declare. Far above
+/// any privacy budget in use, so that only nonsensical values are refused.
+/// See BatchIntaker::set_max_epsilon.
+pub const DEFAULT_MAX_EPSILON: f64 = 100.0;
+
+/// The finite fields, each identified by its prime modulus, that an ingestion
+/// header may declare its packets to be in. libprio's Server only validates
+/// packets in its own Field, modulo finite_field::MODULUS, so that is the only
+/// one for now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrimeField {
+    /// libprio's Field, modulo 2^32 - 2^20 + 1
+    Default,
+}
+
+impl PrimeField {
+    pub const ALL: [PrimeField; 1] = [PrimeField::Default];
+
+    /// Returns the field with the provided prime modulus, failing with
+    /// Error::MalformedHeaderError if libprio doesn't support it.
+    pub fn from_prime(prime: i64) -> Result<PrimeField, Error> {
+        PrimeField::ALL
+            .iter()
+            .find(|field| field.prime() == prime)
+            .copied()
+            .ok_or_else(|| {
+                Error::MalformedHeaderError(format!(
+                    "prime is {} but libprio only supports {:?}",
+                    prime,
+                    PrimeField::ALL
+                        .iter()
+                        .map(PrimeField::prime)
+                        .collect::<Vec<_>>()
+                ))
+            })
+    }
+
+    /// Returns the field's prime modulus, as it appears in batch headers.
+    pub fn prime(&self) -> i64 {
+        match self {
+            PrimeField::Default => MODULUS as i64,
+        }
+    }
+
+    /// Returns the provided value, e.g. a packet's r_pit, as an element of
+    /// this field, reduced modulo the prime. Fails if the value is negative or
+    /// doesn't fit in the field's underlying integer type.
+    ///
+    /// Values at or above the prime are reduced rather than rejected because
+    /// r_pit comes from the ingestor, and both share processors have always
+    /// reduced it with Field::from. Rejecting such a packet here while the
+    /// peer accepts it would make the two share processors' validation
+    /// batches disagree.
+    /// Validation packets' values, in contrast, are computed by share
+    /// processors, so TryFrom<&ValidationPacket> rejects any that are not
+    /// field elements.
+    fn element(&self, value: i64) -> Result<Field> {
+        match self {
+            PrimeField::Default => u32::try_from(value)
+                .map(Field::from)
+                .map_err(|_| anyhow!("{} is not representable in the field", value)),
+        }
+    }
+}
+
+/// The number of ingestion packets handed to a validation worker thread at a
+/// time. Bounds the number of packets held in memory while validating.
+const PACKETS_PER_WORKER: usize = 256;
+
+/// The largest number of packet UUIDs for which room is made up front when
+/// checking for duplicates, however many packets the ingestion header claims,
+/// so that a header can't make us allocate arbitrarily much. Larger batches
+/// grow the set as they are read.
+const MAX_PREALLOCATED_UUIDS: usize = 1 << 20;
+
+/// The default number of packets between reports of validation progress. See
+/// BatchIntaker::set_progress_interval.
+pub const DEFAULT_PROGRESS_INTERVAL: u64 = 10_000;
+
+/// What BatchIntaker does with an ingestion packet that it cannot validate,
+/// e.g. because its r_pit is out of range or its payload can't be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketFailurePolicy {
+    /// Fail the whole batch.
+    Abort,
+    /// Leave the packet out of the validation batch and record its UUID and
+    /// the reason in ValidationStats and in a sidecar next to the validation
+    /// batch (see BatchWriter::put_packet_failures). The batch still fails if
+    /// more than max_failure_fraction of its packets fail.
+    Record { max_failure_fraction: f64 },
+}
+
+/// The order in which BatchIntaker writes validation packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputOrdering {
+    /// Write each validation packet as soon as it is validated, in the order
+    /// of the ingestion packets.
+    PreserveInput,
+    /// Hold the validation packets in memory until the whole batch is
+    /// validated and write them in order of UUID, so that the packet file can
+    /// be compared byte for byte with that of an implementation that sorts.
+    /// The memory held is reported in ValidationStats::sort_buffer_bytes.
+    SortByUuid,
+}
+
+/// Fails a batch early with Error::ProbableKeyMismatch if too many of its
+/// packets fail to decrypt, which suggests that the ingestor encrypted them to
+/// a key other than this share processor's, rather than that some clients sent
+/// bad packets. Packets that decrypt but fail validation don't count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecryptionFailureLimit {
+    /// The number of packets to validate before the limit applies, so that a
+    /// few early failures don't fail the batch. Smaller batches are checked
+    /// once all their packets are validated.
+    pub min_packets: u64,
+    /// The fraction of the packets validated so far, between 0 and 1, that may
+    /// fail to decrypt
+    pub max_fraction: f64,
+}
+
+/// Packet decryption keys for a share processor that serves several
+/// aggregations with a key each, and optionally a default key for the
+/// aggregations that have none of their own. Each key has an identifier, such
+/// as the name under which it is configured, which is reported in
+/// ValidationStats::ecies_key_id. See BatchIntakerBuilder::ecies_keys.
+#[derive(Clone, Default)]
+pub struct EciesKeys {
+    by_aggregation: HashMap<AggregationName, (String, PrivateKey)>,
+    default: Option<(String, PrivateKey)>,
+}
+
+impl EciesKeys {
+    /// Creates an EciesKeys with no keys.
+    pub fn new() -> EciesKeys {
+        EciesKeys::default()
+    }
+
+    /// Sets the key for the provided aggregation, replacing any set before.
+    pub fn insert(&mut self, aggregation_name: &AggregationName, key_id: &str, key: PrivateKey) {
+        self.by_aggregation
+            .insert(aggregation_name.clone(), (key_id.to_owned(), key));
+    }
+
+    /// Sets the key for aggregations that have none of their own.
+    pub fn set_default(&mut self, key_id: &str, key: PrivateKey) {
+        self.default = Some((key_id.to_owned(), key));
+    }
+
+    /// Returns the identifier of the key for the provided aggregation, falling
+    /// back to the default, and the key itself. Fails with
+    /// Error::MissingEciesKey if there is neither.
+    pub fn key_for(
+        &self,
+        aggregation_name: &AggregationName,
+    ) -> Result<(&str, &PrivateKey), Error> {
+        self.by_aggregation
+            .get(aggregation_name)
+            .or(self.default.as_ref())
+            .map(|(key_id, key)| (key_id.as_str(), key))
+            .ok_or_else(|| Error::MissingEciesKey(aggregation_name.to_string()))
+    }
+}
+
+/// The DecryptionFailureLimit that BatchIntaker applies unless told otherwise.
+pub const DEFAULT_DECRYPTION_FAILURE_LIMIT: DecryptionFailureLimit = DecryptionFailureLimit {
+    min_packets: 100,
+    max_fraction: 0.5,
+};
+
+/// Summarizes the work done by BatchIntaker::generate_validation_share on one
+/// batch, so that it may be logged or exported.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationStats {
+    /// Number of ingestion packets decoded, including any that failed
+    /// validation. If the ingestion header declares a packet count, this
+    /// matches it.
+    pub packets: u64,
+    /// Packets left out of the validation batch under
+    /// PacketFailurePolicy::Record
+    pub packet_failures: Vec<PacketFailure>,
+    /// Number of packet_failures whose payload could not be decrypted
+    pub decryption_failures: u64,
+    /// Number of packet_failures whose payload decrypted but whose r_pit or
+    /// proof is malformed. Duplicate packets count as neither kind of failure.
+    pub structural_failures: u64,
+    /// Index of the ingestor key that verified the ingestion header: 0 for the
+    /// key provided to BatchIntaker::new, 1 for the first one added with
+    /// BatchIntaker::add_ingestor_key and so on
+    pub ingestor_key_index: usize,
+    /// Identifier of the packet decryption key, if it was selected from
+    /// EciesKeys by the batch's aggregation
+    pub ecies_key_id: Option<String>,
+    /// The epsilon the ingestion header declares, which the validation header
+    /// carries over
+    pub epsilon: f64,
+    /// Approximate bytes held by the set of packet UUIDs used to detect
+    /// duplicate packets, which grows by one UUID per distinct packet
+    pub duplicate_check_bytes: u64,
+    /// Bytes held by the validation packets buffered for sorting under
+    /// OutputOrdering::SortByUuid, or 0 under OutputOrdering::PreserveInput
+    pub sort_buffer_bytes: u64,
+    /// Bytes read from the ingestion transport
+    pub bytes_read: u64,
+    /// Bytes written to the validation transport
+    pub bytes_written: u64,
+    /// Wall-clock time spent fetching the ingestion header, signature and
+    /// packet files
+    pub download_duration: Duration,
+    /// Wall-clock time spent verifying and parsing the ingestion header, not
+    /// counting its download. Packet file digests are computed as the files
+    /// are downloaded and are counted in download_duration.
+    pub verification_duration: Duration,
+    /// Wall-clock time spent validating packets and writing validations, not
+    /// counting the download of packet files or the upload of validations
+    pub packet_loop_duration: Duration,
+    /// Wall-clock time spent signing and writing the validation header,
+    /// signature, manifest and any packet failures, not counting their upload
+    pub sign_duration: Duration,
+    /// Wall-clock time spent writing to the validation transport
+    pub upload_duration: Duration,
+}
+
+impl ValidationStats {
+    /// Returns the time spent in each phase of intake.
+    pub fn timings(&self) -> IntakeTimings {
+        IntakeTimings {
+            download_duration: self.download_duration,
+            verification_duration: self.verification_duration,
+            packet_loop_duration: self.packet_loop_duration,
+            sign_duration: self.sign_duration,
+            upload_duration: self.upload_duration,
+        }
+    }
+}
+
+impl fmt::Display for ValidationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "validated {} packets ({} failed, {} to decrypt, {} structurally) verified with \
+            ingestor key {}, epsilon {}, duplicate check {} bytes, read {} bytes, wrote {} bytes, \
+            download {:?}, verification {:?}, packet loop {:?}, sign {:?}, upload {:?}",
+            self.packets,
+            self.packet_failures.len(),
+            self.decryption_failures,
+            self.structural_failures,
+            self.ingestor_key_index,
+            self.epsilon,
+            self.duplicate_check_bytes,
+            self.bytes_read,
+            self.bytes_written,
+            self.download_duration,
+            self.verification_duration,
+            self.packet_loop_duration,
+            self.sign_duration,
+            self.upload_duration
+        )?;
+        if let Some(ecies_key_id) = &self.ecies_key_id {
+            write!(f, ", decryption key {}", ecies_key_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The time BatchIntaker spent in each phase of its last run, as also found in
+/// ValidationStats, and available from BatchIntaker::last_timings even if the
+/// run failed, in which case phases after the failure took no time. Download
+/// and upload time is counted apart from the phase during which it happened.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IntakeTimings {
+    pub download_duration: Duration,
+    pub verification_duration: Duration,
+    pub packet_loop_duration: Duration,
+    pub sign_duration: Duration,
+    pub upload_duration: Duration,
+}
+
+/// Formats the timings as a single line of key=value pairs, in milliseconds,
+/// for logs.
+impl fmt::Display for IntakeTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "intake_timings download_ms={} verification_ms={} packet_loop_ms={} sign_ms={} \
+            upload_ms={}",
+            self.download_duration.as_millis(),
+            self.verification_duration.as_millis(),
+            self.packet_loop_duration.as_millis(),
+            self.sign_duration.as_millis(),
+            self.upload_duration.as_millis()
+        )
+    }
+}
+
+/// The phases of intake timed by IntakeClock, other than download and upload.
+#[derive(Clone, Copy)]
+enum TimedPhase {
+    Verification,
+    PacketLoop,
+    Sign,
+}
+
+/// Accumulates IntakeTimings over a run of BatchIntaker::intake. Only one phase
+/// is under way at a time, and it ends when the next one begins or the clock
+/// is finished, so a failed run still accounts for the phase it failed in.
+/// Time spent reading from or writing to the metered transports during a
+/// phase is taken out of it and counted as download or upload instead.
+#[derive(Default)]
+struct IntakeClock {
+    timings: IntakeTimings,
+    ingestion_meter: Option<TransportMeter>,
+    validation_meter: Option<TransportMeter>,
+    current: Option<(TimedPhase, Instant, Duration)>,
+}
+
+impl IntakeClock {
+    /// Time spent so far in the metered transports
+    fn transfer_duration(&self) -> Duration {
+        let ingestion = self
+            .ingestion_meter
+            .as_ref()
+            .map_or(Duration::default(), |meter| meter.metrics().read_duration);
+        let validation = self
+            .validation_meter
+            .as_ref()
+            .map_or(Duration::default(), |meter| meter.metrics().write_duration);
+        ingestion + validation
+    }
+
+    fn begin(&mut self, phase: TimedPhase) {
+        self.end();
+        self.current = Some((phase, Instant::now(), self.transfer_duration()));
+    }
+
+    fn end(&mut self) {
+        if let Some((phase, start, transfer_start)) = self.current.take() {
+            let duration = start
+                .elapsed()
+                .saturating_sub(self.transfer_duration().saturating_sub(transfer_start));
+            *match phase {
+                TimedPhase::Verification => &mut self.timings.verification_duration,
+                TimedPhase::PacketLoop => &mut self.timings.packet_loop_duration,
+                TimedPhase::Sign => &mut self.timings.sign_duration,
+            } += duration;
+        }
+    }
+
+    fn finish(mut self) -> IntakeTimings {
+        self.end();
+        if let Some(meter) = &self.ingestion_meter {
+            self.timings.download_duration = meter.metrics().read_duration;
+        }
+        if let Some(meter) = &self.validation_meter {
+            self.timings.upload_duration = meter.metrics().write_duration;
+        }
+        self.timings
+    }
+}
+
+/// What BatchIntaker::self_verify_validation_batch found in a validation batch
+/// written earlier by this share processor.
+#[derive(Debug, PartialEq)]
+pub struct SelfVerificationSummary {
+    /// The validation batch's header, whose signature verified
+    pub header: ValidationHeader,
+    /// Number of packets in the validation batch's packet file, whose digest
+    /// matched the header
+    pub packets: u64,
+    /// The batch's manifest, if BatchIntaker is configured to write one, whose
+    /// signature verified and whose packet count matched
+    pub manifest: Option<BatchManifest>,
+}
+
+/// The stages of BatchIntaker::generate_validation_share reported to a progress
+/// callback. See BatchIntaker::set_progress_callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntakePhase {
+    /// The ingestion header and its signature were downloaded and the
+    /// signature verified.
+    HeaderVerified,
+    /// Another progress interval's worth of packets was validated.
+    ValidatingPackets,
+    /// Every ingestion packet file was downloaded and its digest verified.
+    /// Packets decoded from the last of them may still be being validated.
+    PacketFilesDownloaded,
+    /// The validation batch's header was signed and the batch written.
+    Signed,
+}
+
+impl IntakePhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntakePhase::HeaderVerified => "header verified",
+            IntakePhase::ValidatingPackets => "validating packets",
+            IntakePhase::PacketFilesDownloaded => "packet files downloaded",
+            IntakePhase::Signed => "signed",
+        }
+    }
+}
+
+/// A report of how far BatchIntaker::generate_validation_share has got with a
+/// batch, passed to a progress callback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntakeProgress {
+    pub phase: IntakePhase,
+    /// Wall-clock time since generate_validation_share was called
+    pub elapsed: Duration,
+    /// Number of ingestion packets validated so far, including any that
+    /// failed validation
+    pub packets: u64,
+    /// Number of packets the ingestion header declares, if it does
+    pub declared_packets: Option<u64>,
+}
+
+impl fmt::Display for IntakeProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.phase.as_str(), self.packets)?;
+        if let Some(declared_packets) = self.declared_packets {
+            write!(f, "/{}", declared_packets)?;
+        }
+        write!(f, " packets validated after {:?}", self.elapsed)
+    }
+}
+
 /// BatchIntaker is responsible for validating a batch of data packet shares
 /// sent by the ingestion server and emitting validation shares to the other
 /// share processor.
+///
+/// BatchIntaker only borrows key material, so dropping it wipes nothing. Load
+/// signing keys with keygen::signing_key_pair_from_base64, which wipes the
+/// decoded PKCS#8 document once it is parsed. The parsed keys themselves are
+/// owned by libprio's PrivateKey and ring's EcdsaKeyPair, neither of which
+/// wipes its copy of the key when dropped, and each libprio Server holds a
+/// further copy of the ECIES key.
+///
+/// BatchIntaker performs blocking I/O through its transports. To use it from
+/// async code, construct and run it inside tokio::task::spawn_blocking so that
+/// it doesn't stall the runtime's worker threads, and wrap any AsyncTransport
+/// in an async_transport::BlockingTransport.
+///
+/// BatchIntaker is Send, so a BatchIntaker built with owned transports (see
+/// BatchIntakerBuilder::owned_ingestion_transport) can be moved onto a worker
+/// thread, provided the keys and any other borrowed settings outlive it.
 pub struct BatchIntaker<'a> {
-    ingestion_batch: BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
-    validation_batch: BatchWriter<'a, ValidationHeader, ValidationPacket>,
-    is_first: bool,
+    instance_name: Option<InstanceName>,
+    batch: BatchIdentity,
+    ingestion_transport: IntakeTransport<'a>,
+    validation_transport: IntakeTransport<'a>,
+    server_identity: ServerIdentity,
+    ingestion_naming_scheme: &'a dyn BatchNamingScheme,
+    validation_naming_scheme: &'a dyn BatchNamingScheme,
+    allow_empty_batches: bool,
+    expected_number_of_servers: i32,
+    expected_hamming_weight: Option<i32>,
+    min_epsilon: Option<f64>,
+    max_epsilon: f64,
+    rng: Option<&'a (dyn SecureRandom + Sync)>,
+    worker_threads: Option<usize>,
+    archive_transport: Option<&'a mut dyn Transport>,
+    archive_failures_fatal: bool,
+    archive_errors: Vec<anyhow::Error>,
+    last_timings: IntakeTimings,
+    batch_date_window: Option<BatchDateWindow>,
+    clock: &'a dyn Clock,
+    server_pool: Option<&'a ServerPool>,
+    validation_attempt: u32,
+    reserved_key_prefix_length: usize,
+    write_manifest: bool,
+    write_done_marker: bool,
+    verify_after_write: bool,
+    overwrite: bool,
+    processed_batch_store: Option<&'a dyn ProcessedBatchStore>,
+    cancellation: Option<&'a AtomicBool>,
+    max_packet_file_size: Option<u64>,
+    read_buffer_size: usize,
+    copy_buffer_size: usize,
+    spool_threshold: usize,
+    transport_retry_policy: RetryPolicy,
+    packet_failure_policy: PacketFailurePolicy,
+    output_ordering: OutputOrdering,
+    decryption_failure_limit: Option<DecryptionFailureLimit>,
+    progress_callback: Option<&'a mut (dyn FnMut(&IntakeProgress) + Send)>,
+    progress_interval: u64,
     share_processor_ecies_key: &'a PrivateKey,
+    share_processor_ecies_key_id: Option<String>,
     share_processor_signing_key: &'a EcdsaKeyPair,
-    ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ingestor_keys: Vec<&'a UnparsedPublicKey<Vec<u8>>>,
 }
 
 impl<'a> BatchIntaker<'a> {
+    /// Creates a BatchIntaker with default settings, which may then be changed
+    /// with the set_* methods. See BatchIntakerBuilder for an alternative.
     #[allow(clippy::too_many_arguments)] // Grandfathered in
     pub fn new(
+        instance_name: Option<&str>,
+        batch: &BatchIdentity,
+        ingestion_transport: &'a mut dyn Transport,
+        validation_transport: &'a mut dyn Transport,
+        server_identity: ServerIdentity,
+        share_processor_ecies_key: &'a PrivateKey,
+        share_processor_signing_key: &'a EcdsaKeyPair,
+        ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ) -> Result<BatchIntaker<'a>> {
+        let mut builder = BatchIntakerBuilder::new(
+            batch,
+            server_identity,
+            share_processor_ecies_key,
+            share_processor_signing_key,
+            ingestor_key,
+        )
+        .ingestion_transport(ingestion_transport)
+        .validation_transport(validation_transport);
+        if let Some(instance_name) = instance_name {
+            builder = builder.instance_name(instance_name);
+        }
+        builder.build()
+    }
+
+    /// Creates a BatchIntaker for the batch identified by the provided
+    /// aggregation name, batch ID and date. See BatchIntaker::new.
+    #[allow(clippy::too_many_arguments)] // Grandfathered in
+    pub fn from_parts(
+        instance_name: Option<&str>,
         aggregation_name: &str,
         batch_id: &Uuid,
-        date: &NaiveDateTime,
+        date: &BatchDate,
         ingestion_transport: &'a mut dyn Transport,
         validation_transport: &'a mut dyn Transport,
-        is_first: bool,
+        server_identity: ServerIdentity,
         share_processor_ecies_key: &'a PrivateKey,
         share_processor_signing_key: &'a EcdsaKeyPair,
         ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
     ) -> Result<BatchIntaker<'a>> {
-        Ok(BatchIntaker {
-            ingestion_batch: BatchReader::new(
-                Batch::new_ingestion(aggregation_name, batch_id, date),
-                ingestion_transport,
-            ),
-            validation_batch: BatchWriter::new(
-                Batch::new_validation(aggregation_name, batch_id, date, is_first),
-                validation_transport,
-            ),
-            is_first,
+        BatchIntaker::new(
+            instance_name,
+            &BatchIdentity::new(AggregationName::new(aggregation_name)?, *date, *batch_id),
+            ingestion_transport,
+            validation_transport,
+            server_identity,
             share_processor_ecies_key,
             share_processor_signing_key,
             ingestor_key,
-        })
+        )
+    }
+
+    /// Sets the naming scheme used to locate the ingestion batch and to name
+    /// the validation batch. Defaults to DEFAULT_NAMING_SCHEME.
+    pub fn set_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.ingestion_naming_scheme = naming_scheme;
+        self.validation_naming_scheme = naming_scheme;
+    }
+
+    /// Sets the naming scheme used to locate ingestion batches, overriding
+    /// the one provided to set_naming_scheme.
+    pub fn set_ingestion_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.ingestion_naming_scheme = naming_scheme;
+    }
+
+    /// Sets the naming scheme used for validation batches, overriding the one
+    /// provided to set_naming_scheme.
+    pub fn set_validation_naming_scheme(&mut self, naming_scheme: &'a dyn BatchNamingScheme) {
+        self.validation_naming_scheme = naming_scheme;
+    }
+
+    /// Sets whether an ingestion batch containing no packets is accepted. Its
+    /// packet file must still be an Avro object container holding the schema;
+    /// an empty file is malformed. If true, a validation batch containing no
+    /// packets is emitted for it, with a signed header declaring a packet
+    /// count of zero and a packet file holding only the schema. Otherwise, generate_validation_share fails with Error::EmptyBatchError
+    /// without writing anything. Defaults to false.
+    pub fn set_allow_empty_batches(&mut self, allow_empty_batches: bool) {
+        self.allow_empty_batches = allow_empty_batches;
+    }
+
+    /// Sets the number_of_servers value that ingestion headers must contain.
+    /// Batches with any other value are rejected with
+    /// Error::MalformedHeaderError, since their shares could not be combined
+    /// with those of the peer. Defaults to DEFAULT_NUMBER_OF_SERVERS.
+    pub fn set_expected_number_of_servers(&mut self, expected_number_of_servers: i32) {
+        self.expected_number_of_servers = expected_number_of_servers;
+    }
+
+    /// Sets the hamming_weight that ingestion headers must declare, for
+    /// deployments whose clients encode each input with a fixed number of
+    /// ones. Batches declaring any other weight, or none, are rejected with
+    /// Error::MalformedHeaderError. libprio only proves that each entry of an
+    /// input is 0 or 1, and a share processor only sees shares, so the weight
+    /// of individual packets can't be checked. Defaults to None, meaning any
+    /// weight between 1 and bins, or none, is accepted.
+    pub fn set_expected_hamming_weight(&mut self, expected_hamming_weight: Option<i32>) {
+        self.expected_hamming_weight = expected_hamming_weight;
+    }
+
+    /// Sets the largest epsilon that ingestion headers may declare. Batches
+    /// with a greater epsilon, or one that isn't finite and positive, are
+    /// rejected with Error::MalformedHeaderError rather than passed on to the
+    /// PHA's privacy accounting. Defaults to DEFAULT_MAX_EPSILON.
+    pub fn set_max_epsilon(&mut self, max_epsilon: f64) {
+        self.max_epsilon = max_epsilon;
+    }
+
+    /// Sets the smallest epsilon that ingestion headers may declare, for
+    /// aggregations whose privacy budget calls for a floor as well as a
+    /// ceiling. Batches with a smaller epsilon are rejected with
+    /// Error::MalformedHeaderError. See IngestionHeader::validate. Defaults to
+    /// None, meaning any epsilon greater than zero and no greater than the
+    /// maximum is accepted.
+    pub fn set_min_epsilon(&mut self, min_epsilon: Option<f64>) {
+        self.min_epsilon = min_epsilon;
+    }
+
+    /// Sets the source of randomness used to sign the validation batch. See
+    /// BatchWriter::set_rng.
+    pub fn set_rng(&mut self, rng: &'a (dyn SecureRandom + Sync)) {
+        self.rng = Some(rng);
+    }
+
+    /// Sets the number of threads used to validate packets. If None, one
+    /// thread per logical CPU is used. With Some(1), packets are validated
+    /// serially on the calling thread. Otherwise, the calling thread decodes
+    /// packets while the workers validate them, and writes the validation
+    /// packets in the order of the ingestion packets. Some(0) is treated as
+    /// Some(1). Defaults to None.
+    pub fn set_worker_threads(&mut self, worker_threads: Option<usize>) {
+        self.worker_threads = worker_threads;
+    }
+
+    /// Sets a transport to which the header, packet files and signature of the
+    /// ingestion batch are copied once they have been verified. Each file is
+    /// archived under its ingestion key, prefixed with the time, according to
+    /// the clock (see set_clock), at which generate_validation_share was
+    /// called (e.g. "20201014T153000Z/"). The
+    /// copies are made from the content already fetched for verification, so
+    /// nothing is downloaded twice.
+    pub fn set_archive_transport(&mut self, archive_transport: &'a mut dyn Transport) {
+        self.archive_transport = Some(archive_transport);
+    }
+
+    /// Sets whether failing to archive a file fails generate_validation_share.
+    /// If false, archiving is best-effort and failures are collected into
+    /// archive_errors instead. Defaults to true.
+    pub fn set_archive_failures_fatal(&mut self, archive_failures_fatal: bool) {
+        self.archive_failures_fatal = archive_failures_fatal;
+    }
+
+    /// Returns the errors encountered while archiving files if archive
+    /// failures are not fatal. See set_archive_failures_fatal.
+    pub fn archive_errors(&self) -> &[anyhow::Error] {
+        &self.archive_errors
+    }
+
+    /// Returns the time spent in each phase of the last run of
+    /// generate_validation_share or verify_batch, up to the point of failure
+    /// if it failed.
+    pub fn last_timings(&self) -> IntakeTimings {
+        self.last_timings
+    }
+
+    /// Sets the window of dates, relative to the present, in which ingestion
+    /// batches are accepted. generate_validation_share fails with
+    /// Error::BatchDateOutsideWindow, before fetching anything, for batches
+    /// dated outside it. If None, batches of any date are accepted, as when
+    /// backfilling. Defaults to None.
+    pub fn set_batch_date_window(&mut self, batch_date_window: Option<BatchDateWindow>) {
+        self.batch_date_window = batch_date_window;
+    }
+
+    /// Sets the clock used wherever the current time is needed: to check the
+    /// batch date window and to timestamp archived files. Defaults to
+    /// SystemClock.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    /// Sets the pool from which the libprio Servers used to validate packets
+    /// are acquired, so that they can be reused across batches. The pool must
+    /// have been created with this share processor's ECIES key. If not set, a
+    /// pool is created for each call to generate_validation_share.
+    pub fn set_server_pool(&mut self, server_pool: &'a ServerPool) {
+        self.server_pool = Some(server_pool);
+    }
+
+    /// Sets the attempt at producing the validation batch, so that
+    /// reprocessing an ingestion batch leaves the output of earlier attempts in
+    /// place. See Batch::with_attempt. Defaults to 0, the first attempt.
+    pub fn set_validation_attempt(&mut self, validation_attempt: u32) {
+        self.validation_attempt = validation_attempt;
+    }
+
+    /// Sets the number of bytes that the validation transport prepends to the
+    /// keys it is given, so that generate_validation_share can fail with
+    /// Error::KeyTooLong, before fetching anything, if the validation batch
+    /// would have keys longer than the store allows. See
+    /// Batch::check_key_lengths. Defaults to 0.
+    pub fn set_reserved_key_prefix_length(&mut self, reserved_key_prefix_length: usize) {
+        self.reserved_key_prefix_length = reserved_key_prefix_length;
+    }
+
+    /// Sets whether a manifest listing the validation batch's files, their
+    /// digests and sizes and the number of packets is written alongside it,
+    /// signed with the share processor's signing key. See
+    /// BatchWriter::put_manifest. Defaults to false.
+    pub fn set_write_manifest(&mut self, write_manifest: bool) {
+        self.write_manifest = write_manifest;
+    }
+
+    /// Sets whether generate_validation_share records that it is done with a
+    /// batch by writing a DoneMarker, naming the validation packet file's
+    /// digest and packet count, once the validation batch has been written
+    /// and, with set_verify_after_write, verified, and the batch recorded in
+    /// any processed batch store. A later run over the same batch then finds
+    /// the marker and, unless set_overwrite is set, fails with
+    /// Error::AlreadyProcessed before reading anything else, so that a batch
+    /// handed over twice by at-least-once orchestration is a no-op the second
+    /// time. Defaults to false.
+    pub fn set_write_done_marker(&mut self, write_done_marker: bool) {
+        self.write_done_marker = write_done_marker;
+    }
+
+    /// Sets whether generate_validation_share reads back the validation batch
+    /// it has just written, through the validation transport and so through
+    /// any compression, encryption or mirroring it applies, and checks it as
+    /// self_verify_validation_batch does, also checking that the packet file
+    /// and packet count are the ones that were written. Any discrepancy fails
+    /// generate_validation_share with Error::PostWriteVerificationFailed. This
+    /// doubles the reads from the validation transport. Defaults to false.
+    pub fn set_verify_after_write(&mut self, verify_after_write: bool) {
+        self.verify_after_write = verify_after_write;
+    }
+
+    /// Sets a flag that, once set, e.g. by another thread or a signal handler,
+    /// stops generate_validation_share at the next check: before and after
+    /// verifying the ingestion header, before each group of a few hundred
+    /// packets and before signing. Anything written to the validation
+    /// transport is then cancelled or deleted, and Error::Cancelled returned.
+    /// Once signing has begun the batch is completed.
+    pub fn set_cancellation(&mut self, cancellation: &'a AtomicBool) {
+        self.cancellation = Some(cancellation);
+    }
+
+    /// Sets whether generate_validation_share redoes a batch whose validation
+    /// batch already exists. If false, generate_validation_share fails with
+    /// Error::AlreadyProcessed, without fetching the ingestion batch, if the
+    /// validation batch's header, packet file and signature all exist and the
+    /// signature verifies with this share processor's own public key, or if
+    /// done markers are written and the batch has one (see
+    /// set_write_done_marker), e.g. because an earlier run's success was never
+    /// acknowledged. Defaults to false.
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        self.overwrite = overwrite;
+    }
+
+    /// Sets a store of the batches already processed. Before fetching the
+    /// ingestion batch, generate_validation_share and verify_batch fail with
+    /// Error::ReplayDetected if the store has a record of a batch with the
+    /// same aggregation and ID under another date, and once
+    /// generate_validation_share has written the validation batch, it records
+    /// the batch. The batch is refused too if another writer records it under
+    /// another date in the meantime, though its validation batch has been
+    /// written by then. Processing a batch again under the same date, e.g.
+    /// with set_overwrite, is not a replay.
+    pub fn set_processed_batch_store(&mut self, store: &'a dyn ProcessedBatchStore) {
+        self.processed_batch_store = Some(store);
+    }
+
+    /// Sets the largest ingestion packet file or packet file shard, in bytes,
+    /// that will be downloaded, so that an oversized batch is refused with
+    /// Error::PacketFileTooLarge rather than exhausting memory or disk. See
+    /// BatchReader::set_max_packet_file_size. Defaults to None, meaning no
+    /// limit.
+    pub fn set_max_packet_file_size(&mut self, max_packet_file_size: Option<u64>) {
+        self.max_packet_file_size = max_packet_file_size;
+    }
+
+    /// Sets the size in bytes of the buffer through which the ingestion packet
+    /// file is decoded once it has been verified. See
+    /// BatchReader::set_read_buffer_size.
+    pub fn set_read_buffer_size(&mut self, read_buffer_size: usize) {
+        self.read_buffer_size = read_buffer_size;
+    }
+
+    /// Sets the size in bytes of the buffer through which the ingestion packet
+    /// file is downloaded. See BatchReader::set_copy_buffer_size.
+    pub fn set_copy_buffer_size(&mut self, copy_buffer_size: usize) {
+        self.copy_buffer_size = copy_buffer_size;
+    }
+
+    /// Sets the number of bytes of a downloaded packet file, or of the
+    /// validation batch's header, that are held in memory before the rest is
+    /// spooled to a temporary file, which bounds the memory a batch takes
+    /// however large it is. See BatchReader::set_spool_threshold. Defaults to
+    /// batch::DEFAULT_SPOOL_THRESHOLD.
+    pub fn set_spool_threshold(&mut self, spool_threshold: usize) {
+        self.spool_threshold = spool_threshold;
+    }
+
+    /// Sets how the individual transport operations of
+    /// generate_validation_share and self_verify_validation_batch (fetching
+    /// the ingestion signature, header and packet file, and putting each file
+    /// of the validation batch) are retried when they fail with a transient
+    /// error; see transport::is_transient. A retried put restarts the object,
+    /// so validation files never hold content from an attempt that failed.
+    /// Packet files are spooled while retries are enabled; see
+    /// BatchWriter::set_retry_policy. Defaults to DEFAULT_RETRY_POLICY.
+    pub fn set_transport_retry_policy(&mut self, transport_retry_policy: RetryPolicy) {
+        self.transport_retry_policy = transport_retry_policy;
+    }
+
+    /// Sets what happens to ingestion packets that cannot be validated.
+    /// Defaults to PacketFailurePolicy::Abort.
+    pub fn set_packet_failure_policy(&mut self, packet_failure_policy: PacketFailurePolicy) {
+        self.packet_failure_policy = packet_failure_policy;
+    }
+
+    /// Sets the order in which validation packets are written. Either way, the
+    /// same packets are written. Defaults to OutputOrdering::PreserveInput.
+    pub fn set_output_ordering(&mut self, output_ordering: OutputOrdering) {
+        self.output_ordering = output_ordering;
+    }
+
+    /// Sets the limit on packets that fail to decrypt, or None to treat them
+    /// like any other failed packet. Only matters under
+    /// PacketFailurePolicy::Record, since under PacketFailurePolicy::Abort the
+    /// first failure fails the batch. Defaults to
+    /// DEFAULT_DECRYPTION_FAILURE_LIMIT.
+    pub fn set_decryption_failure_limit(
+        &mut self,
+        decryption_failure_limit: Option<DecryptionFailureLimit>,
+    ) {
+        self.decryption_failure_limit = decryption_failure_limit;
+    }
+
+    /// Sets a callback to which generate_validation_share reports its progress:
+    /// once at each IntakePhase other than ValidatingPackets, and with
+    /// ValidatingPackets each time another progress interval's worth of
+    /// packets has been validated (see set_progress_interval). The callback
+    /// runs on the calling thread between batches of packets, so a slow
+    /// callback slows validation down but can't interfere with it. If the
+    /// callback panics, generate_validation_share fails without writing the
+    /// validation batch, or, if the panic is in the report of
+    /// IntakePhase::Signed, fails after writing it. Defaults to no callback.
+    pub fn set_progress_callback(
+        &mut self,
+        progress_callback: &'a mut (dyn FnMut(&IntakeProgress) + Send),
+    ) {
+        self.progress_callback = Some(progress_callback);
+    }
+
+    /// Sets the number of packets validated between reports to the progress
+    /// callback. Packets are validated in groups of a few hundred per worker
+    /// thread, so reports come no more often than once per group. 0 is
+    /// treated as 1. Defaults to DEFAULT_PROGRESS_INTERVAL.
+    pub fn set_progress_interval(&mut self, progress_interval: u64) {
+        self.progress_interval = progress_interval;
+    }
+
+    /// Adds a key with which the ingestion header's signature may also be
+    /// verified, e.g. while the ingestor rotates its key. Signatures don't
+    /// identify their key, so keys are tried in the order they were provided,
+    /// starting with the one provided to BatchIntaker::new. See
+    /// ValidationStats::ingestor_key_index.
+    pub fn add_ingestor_key(&mut self, ingestor_key: &'a UnparsedPublicKey<Vec<u8>>) {
+        self.ingestor_keys.push(ingestor_key);
+    }
+
+    /// Returns the validation batch that generate_validation_share writes.
+    fn output_batch(&self) -> Result<Batch> {
+        Ok(self
+            .batch
+            .own_validation_batch(
+                self.validation_naming_scheme,
+                self.instance_name.as_ref(),
+                self.server_identity,
+            )
+            .into_batch()
+            .with_attempt(self.validation_attempt)
+            .check_key_lengths(self.reserved_key_prefix_length)?)
     }
 
     /// Fetches the ingestion batch, validates the signatures over its header
-    /// and packet file, then computes validation shares and sends them to the
-    /// peer share processor.
-    pub fn generate_validation_share(&mut self) -> Result<()> {
-        let ingestion_header = self.ingestion_batch.header(&self.ingestor_key)?;
-        if ingestion_header.bins <= 0 {
-            return Err(anyhow!(
-                "invalid bins/dimension value {}",
-                ingestion_header.bins
-            ));
+    /// and packet files, then computes validation shares and sends them to the
+    /// peer share processor. Returns statistics about the work done.
+    pub fn generate_validation_share(&mut self) -> Result<ValidationStats> {
+        self.intake(false)
+    }
+
+    /// Checks the ingestion batch exactly as generate_validation_share does,
+    /// including decoding every packet and computing its validation share, but
+    /// discards the validation batch instead of writing it and archives
+    /// nothing. The returned ValidationStats describe the packets as
+    /// generate_validation_share would, packet failures included, except that
+    /// bytes_written is 0.
+    pub fn verify_batch(&mut self) -> Result<ValidationStats> {
+        self.intake(true)
+    }
+
+    /// Implements generate_validation_share and, if verify_only, verify_batch.
+    fn intake(&mut self, verify_only: bool) -> Result<ValidationStats> {
+        let mut clock = IntakeClock::default();
+        let result = self.timed_intake(verify_only, &mut clock);
+        self.last_timings = clock.finish();
+        let timings = self.last_timings;
+        result.map(|stats| ValidationStats {
+            download_duration: timings.download_duration,
+            verification_duration: timings.verification_duration,
+            packet_loop_duration: timings.packet_loop_duration,
+            sign_duration: timings.sign_duration,
+            upload_duration: timings.upload_duration,
+            ..stats
+        })
+    }
+
+    /// Implements intake, keeping time in clock. The durations in the returned
+    /// ValidationStats are left for intake to fill in.
+    fn timed_intake(
+        &mut self,
+        verify_only: bool,
+        clock: &mut IntakeClock,
+    ) -> Result<ValidationStats> {
+        let start = Instant::now();
+        let cancellation = self.cancellation;
+        check_cancellation(cancellation)?;
+        if let Some(batch_date_window) = &self.batch_date_window {
+            batch_date_window.check(&self.batch.date, &self.clock.now())?;
+        }
+        let output_batch = self.output_batch()?;
+        if !verify_only && !self.overwrite && (self.is_done()? || self.validation_batch_exists()?) {
+            return Err(Error::AlreadyProcessed(self.batch.to_string()).into());
+        }
+        let processed_batch_store = self.processed_batch_store;
+        if let Some(store) = processed_batch_store {
+            let recorded_date = store
+                .lookup(&self.batch.aggregation_name, &self.batch.batch_id)
+                .context("failed to look up processed batch")?;
+            check_replay(&self.batch, recorded_date)?;
         }
 
-        let mut server = Server::new(
-            ingestion_header.bins as usize,
-            self.is_first,
-            self.share_processor_ecies_key.clone(),
-        );
+        let batch = self
+            .batch
+            .ingestion_batch(self.ingestion_naming_scheme, self.instance_name.as_ref());
+        let header_key = batch.key(BatchFileKind::Header).to_owned();
+        let signature_key = batch.key(BatchFileKind::Signature).to_owned();
+        let mut ingestion_transport = MeteredTransport::new(&mut *self.ingestion_transport);
+        let ingestion_meter = ingestion_transport.meter();
+        clock.ingestion_meter = Some(ingestion_meter.clone());
+        let mut ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch, &mut ingestion_transport);
+        ingestion_batch.set_max_packet_file_size(self.max_packet_file_size);
+        ingestion_batch.set_read_buffer_size(self.read_buffer_size);
+        ingestion_batch.set_copy_buffer_size(self.copy_buffer_size);
+        ingestion_batch.set_spool_threshold(self.spool_threshold);
+        ingestion_batch.set_retry_policy(self.transport_retry_policy);
+        clock.begin(TimedPhase::Verification);
+        let (ingestor_key_index, verified_header) =
+            ingestion_batch.verified_header_with_keys(&self.ingestor_keys)?;
+        clock.end();
+        check_cancellation(cancellation)?;
+        let field = check_ingestion_header(
+            &verified_header.header,
+            &self.batch.batch_id,
+            self.expected_number_of_servers,
+            self.expected_hamming_weight,
+            self.min_epsilon,
+            self.max_epsilon,
+        )?;
+        let mut progress = ProgressReporter {
+            callback: self.progress_callback.as_deref_mut(),
+            start,
+            interval: self.progress_interval.max(1),
+            next_report: self.progress_interval.max(1),
+            declared_packets: verified_header.header.packet_count,
+        };
+        progress.report(IntakePhase::HeaderVerified, 0)?;
+
+        let archive_prefix = self.clock.now().format("%Y%m%dT%H%M%SZ").to_string();
+        let fatal = self.archive_failures_fatal;
+        let errors = &mut self.archive_errors;
+        let mut archiver = self
+            .archive_transport
+            .as_deref_mut()
+            .filter(|_| !verify_only)
+            .map(|transport| Archiver {
+                transport,
+                prefix: archive_prefix,
+                fatal,
+                errors,
+            });
+        if let Some(archiver) = &mut archiver {
+            archiver.archive(&header_key, &mut verified_header.header_bytes.as_slice())?;
+            archiver.archive(&signature_key, &mut verified_header.signature.as_slice())?;
+        }
+
+        let ingestion_header = verified_header.header;
+
+        // Each worker thread needs its own libprio Server, since generating a
+        // verification message mutates the server's scratch memory.
+        let worker_threads = self
+            .worker_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1);
+        let is_first = self.server_identity.is_first();
+        let local_server_pool;
+        let server_pool = match self.server_pool {
+            Some(server_pool) if server_pool.is_first() != is_first => {
+                return Err(anyhow!(
+                    "server pool does not belong to the {} server",
+                    self.server_identity.as_str()
+                ));
+            }
+            Some(server_pool)
+                if server_pool.key_id() != self.share_processor_ecies_key_id.as_deref() =>
+            {
+                return Err(anyhow!(
+                    "server pool decrypts with key {:?} rather than {:?}",
+                    server_pool.key_id(),
+                    self.share_processor_ecies_key_id
+                ));
+            }
+            Some(server_pool) => server_pool,
+            None => {
+                local_server_pool =
+                    ServerPool::new(is_first, self.share_processor_ecies_key.clone());
+                &local_server_pool
+            }
+        };
+        let mut servers: Vec<PooledServer<'_>> = (0..worker_threads)
+            .map(|_| server_pool.acquire(ingestion_header.bins as usize))
+            .collect();
+        let ecies_key = server_pool.private_key();
 
         // Read all the ingestion packets, generate a verification message for
         // each, and write them to the validation batch.
+        clock.begin(TimedPhase::PacketLoop);
         let mut ingestion_packet_reader =
-            self.ingestion_batch.packet_file_reader(&ingestion_header)?;
-
-        let packet_file_digest =
-            self.validation_batch
-                .packet_file_writer(|mut packet_writer| loop {
-                    let packet = match IngestionDataSharePacket::read(&mut ingestion_packet_reader)
-                    {
-                        Ok(p) => p,
-                        Err(Error::EofError) => return Ok(()),
-                        Err(e) => return Err(e.into()),
-                    };
-
-                    let r_pit = u32::try_from(packet.r_pit)
-                        .with_context(|| format!("illegal r_pit value {}", packet.r_pit))?;
-
-                    // TODO(timg): if this fails for a non-empty subset of the
-                    // ingestion packets, do we abort handling of the entire
-                    // batch (as implemented currently) or should we record it
-                    // as an invalid UUID and emit a validation batch for the
-                    //  other packets?
-                    let validation_message = server
-                        .generate_verification_message(
-                            Field::from(r_pit),
-                            &packet.encrypted_payload,
-                        )
-                        .context("failed to construct validation message")?;
-
-                    let packet = ValidationPacket {
-                        uuid: packet.uuid,
-                        f_r: u32::from(validation_message.f_r) as i64,
-                        g_r: u32::from(validation_message.g_r) as i64,
-                        h_r: u32::from(validation_message.h_r) as i64,
-                    };
-                    packet.write(&mut packet_writer)?;
+            ingestion_batch.sharded_packet_reader(&ingestion_header)?;
+        if let Some(mut archiver) = archiver {
+            ingestion_packet_reader
+                .set_verified_shard_callback(move |key, content| archiver.archive(key, content));
+        }
+
+        // Read the first packet before writing anything so that we can refuse
+        // empty batches without leaving a partial validation batch behind.
+        // Errors fetching packet files are wrapped by read_packet, so unwrap
+        // them to let callers downcast e.g. Error::PacketFileTooLarge.
+        let mut first_packet = match ingestion_packet_reader.read_packet() {
+            Ok(p) => Some(p),
+            Err(Error::EofError) => None,
+            Err(Error::AnyhowError(e)) => return Err(e),
+            Err(e) => return Err(e.into()),
+        };
+        if first_packet.is_none() && !self.allow_empty_batches {
+            return Err(Error::EmptyBatchError(format!(
+                "ingestion batch {} contains no packets",
+                self.batch
+            ))
+            .into());
+        }
+
+        let mut null_transport = NullTransport;
+        let validation_transport: &mut dyn Transport = if verify_only {
+            &mut null_transport
+        } else {
+            &mut *self.validation_transport
+        };
+        let mut validation_transport = MeteredTransport::new(validation_transport);
+        let validation_meter = validation_transport.meter();
+        clock.validation_meter = Some(validation_meter.clone());
+        let mut validation_batch: BatchWriter<'_, ValidationHeader, ValidationPacket> =
+            BatchWriter::new(output_batch, &mut validation_transport);
+        validation_batch.set_spool_threshold(self.spool_threshold);
+        validation_batch.set_retry_policy(self.transport_retry_policy);
+        if let Some(rng) = self.rng {
+            validation_batch.set_rng(rng);
+        }
+        let packet_failure_policy = self.packet_failure_policy;
+        let mut sort_buffer = match self.output_ordering {
+            OutputOrdering::PreserveInput => None,
+            OutputOrdering::SortByUuid => Some(Vec::new()),
+        };
+        let decryption_failure_limit = self.decryption_failure_limit;
+        let declared_packet_count = ingestion_header.packet_count;
+        let mut packet_count = 0;
+        let mut packet_failures = Vec::new();
+        let mut failure_counts = FailureCounts::default();
+        let mut admission = PacketAdmission::new(declared_packet_count);
+        let packet_file_digest = validation_batch.packet_file_writer(|packet_writer| {
+            let mut chunk = PacketChunk::new(0);
+            if let Some(packet) = first_packet.take() {
+                admission.admit(packet, packet_failure_policy, &mut chunk)?;
+            }
+            if servers.len() == 1 {
+                let server = &mut servers[0];
+                loop {
+                    check_cancellation(cancellation)?;
+                    let eof = read_packet_chunk(
+                        &mut ingestion_packet_reader,
+                        &mut admission,
+                        packet_failure_policy,
+                        &mut chunk,
+                    )?;
+                    if eof {
+                        progress.report(IntakePhase::PacketFilesDownloaded, packet_count)?;
+                    }
+                    let validated = chunk.validate(server, field, ecies_key);
+                    packet_count += write_validated_chunk(
+                        packet_writer,
+                        packet_failure_policy,
+                        validated,
+                        sort_buffer.as_mut(),
+                        &mut packet_failures,
+                        &mut failure_counts,
+                    )?;
+                    failure_counts.check(decryption_failure_limit, packet_count, false)?;
+                    progress.packets_validated(packet_count)?;
+                    if eof {
+                        break;
+                    }
+                    chunk = PacketChunk::new(0);
+                }
+            } else {
+                // The calling thread decodes chunks of packets into a bounded
+                // queue that the workers validate from, and writes the
+                // validated chunks back out in the order they were read. At
+                // most two chunks per worker are held in memory at a time.
+                let max_chunks_in_flight = 2 * servers.len();
+                let (chunk_sender, chunk_receiver) = mpsc::sync_channel(servers.len());
+                let chunk_receiver = Mutex::new(chunk_receiver);
+                let (validated_sender, validated_receiver) = mpsc::channel();
+                std::thread::scope(|scope| -> Result<()> {
+                    for server in servers.iter_mut() {
+                        let chunk_receiver = &chunk_receiver;
+                        let validated_sender = validated_sender.clone();
+                        scope.spawn(move || {
+                            validation_worker(
+                                server,
+                                field,
+                                ecies_key,
+                                chunk_receiver,
+                                validated_sender,
+                            )
+                        });
+                    }
+                    // Workers exit once both ends are dropped, which happens
+                    // when this closure returns, including on error.
+                    let chunk_sender = chunk_sender;
+                    let validated_receiver = validated_receiver;
+                    drop(validated_sender);
+
+                    let mut eof = false;
+                    let mut chunks_read = 0;
+                    let mut chunks_written = 0;
+                    let mut validated_chunks = BTreeMap::new();
+                    loop {
+                        while let Some(validated) = validated_chunks.remove(&chunks_written) {
+                            packet_count += write_validated_chunk(
+                                packet_writer,
+                                packet_failure_policy,
+                                validated,
+                                sort_buffer.as_mut(),
+                                &mut packet_failures,
+                                &mut failure_counts,
+                            )?;
+                            failure_counts.check(decryption_failure_limit, packet_count, false)?;
+                            progress.packets_validated(packet_count)?;
+                            chunks_written += 1;
+                        }
+                        if eof && chunks_written == chunks_read {
+                            break;
+                        }
+
+                        if !eof && chunks_read - chunks_written < max_chunks_in_flight {
+                            check_cancellation(cancellation)?;
+                            eof = read_packet_chunk(
+                                &mut ingestion_packet_reader,
+                                &mut admission,
+                                packet_failure_policy,
+                                &mut chunk,
+                            )?;
+                            if eof {
+                                progress
+                                    .report(IntakePhase::PacketFilesDownloaded, packet_count)?;
+                            }
+                            if !chunk.packets.is_empty() || !chunk.rejected.is_empty() {
+                                chunks_read += 1;
+                                let next_chunk = PacketChunk::new(chunks_read);
+                                chunk_sender
+                                    .send(std::mem::replace(&mut chunk, next_chunk))
+                                    .map_err(|_| anyhow!("validation workers exited early"))?;
+                            }
+                            for validated in validated_receiver.try_iter() {
+                                let validated = validated
+                                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                                validated_chunks.insert(validated.index, validated);
+                            }
+                            continue;
+                        }
+
+                        let validated = validated_receiver
+                            .recv()
+                            .map_err(|_| anyhow!("validation workers exited early"))?
+                            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                        validated_chunks.insert(validated.index, validated);
+                    }
+                    Ok(())
                 })?;
+            }
+
+            if let Some(declared_packet_count) = declared_packet_count {
+                if declared_packet_count != packet_count {
+                    return Err(
+                        Error::PacketCountMismatch(declared_packet_count, packet_count).into(),
+                    );
+                }
+            }
+
+            // Cancel the packet file if too many packets failed, rather than
+            // emit a validation batch that is mostly holes.
+            failure_counts.check(decryption_failure_limit, packet_count, true)?;
+            if let PacketFailurePolicy::Record {
+                max_failure_fraction,
+            } = packet_failure_policy
+            {
+                let failure_count = packet_failures.len() as u64;
+                if failure_count as f64 > max_failure_fraction * packet_count as f64 {
+                    return Err(Error::TooManyPacketFailures(
+                        failure_count,
+                        packet_count,
+                        max_failure_fraction,
+                    )
+                    .into());
+                }
+            }
+            // Sorting by UUID alone is a total order, since duplicate UUIDs
+            // were left out above.
+            if let Some(sort_buffer) = &mut sort_buffer {
+                sort_buffer.sort_unstable_by_key(|packet: &ValidationPacket| packet.uuid);
+                for validation_packet in sort_buffer.iter() {
+                    validation_packet.write(packet_writer)?;
+                }
+            }
+            Ok(())
+        });
+        let packet_file_digest = packet_file_digest
+            .and_then(|digest| {
+                check_cancellation(cancellation)?;
+                Ok(digest)
+            })
+            .map_err(|e| validation_batch.roll_back(e))?;
+        let ingestion_metrics = ingestion_meter.metrics();
 
         // Construct validation header and write it out
-        let header_signature = self.validation_batch.put_header(
+        clock.begin(TimedPhase::Sign);
+        let header_signature = validation_batch.put_header(
             &ValidationHeader {
-                batch_uuid: ingestion_header.batch_uuid,
+                batch_uuid: self.batch.batch_id,
                 name: ingestion_header.name,
                 bins: ingestion_header.bins,
                 epsilon: ingestion_header.epsilon,
@@ -120,110 +1311,5744 @@ impl<'a> BatchIntaker<'a> {
                 number_of_servers: ingestion_header.number_of_servers,
                 hamming_weight: ingestion_header.hamming_weight,
                 packet_file_digest: packet_file_digest.as_ref().to_vec(),
+                packet_count: Some(packet_count - packet_failures.len() as u64),
+                signature_algorithm: Some(SignatureAlgorithm::EcdsaP256Sha256Fixed),
             },
             &self.share_processor_signing_key,
-        )?;
+        );
+        let header_signature = header_signature.map_err(|e| validation_batch.roll_back(e))?;
 
         // Construct and write out signature
-        self.validation_batch.put_signature(&header_signature)
+        validation_batch
+            .put_signature(&header_signature)
+            .map_err(|e| validation_batch.roll_back(e))?;
+
+        if let PacketFailurePolicy::Record { .. } = self.packet_failure_policy {
+            validation_batch
+                .put_packet_failures(&packet_failures)
+                .map_err(|e| validation_batch.roll_back(e))?;
+        }
+        if self.write_manifest {
+            validation_batch
+                .put_manifest(
+                    packet_count - packet_failures.len() as u64,
+                    self.share_processor_signing_key,
+                )
+                .map_err(|e| validation_batch.roll_back(e))?;
+        }
+        clock.end();
+        progress.report(IntakePhase::Signed, packet_count)?;
+        // Release the ingestion transport, which is borrowed from self
+        drop(ingestion_packet_reader);
+        if self.verify_after_write && !verify_only {
+            self.verify_written_batch(
+                packet_file_digest.as_ref(),
+                packet_count - packet_failures.len() as u64,
+            )?;
+        }
+        if let Some(store) = processed_batch_store.filter(|_| !verify_only) {
+            let recorded_date = store
+                .record_if_absent(&self.batch)
+                .context("failed to record processed batch")?;
+            check_replay(&self.batch, recorded_date)?;
+        }
+        if self.write_done_marker && !verify_only {
+            // The marker goes last, so that it is only ever found beside a
+            // complete validation batch.
+            let output_batch = self.output_batch()?;
+            DoneMarker::new(
+                self.batch.batch_id,
+                packet_count - packet_failures.len() as u64,
+                packet_file_digest.as_ref(),
+            )
+            .put(
+                &mut MeteredTransport::with_meter(
+                    &mut *self.validation_transport,
+                    validation_meter.clone(),
+                ),
+                &output_batch,
+                self.transport_retry_policy,
+            )
+            .context("failed to write done marker")?;
+        }
+        Ok(ValidationStats {
+            packets: packet_count,
+            packet_failures,
+            decryption_failures: failure_counts.decryption,
+            structural_failures: failure_counts.structural,
+            ingestor_key_index,
+            ecies_key_id: self.share_processor_ecies_key_id.clone(),
+            epsilon: ingestion_header.epsilon,
+            duplicate_check_bytes: (admission.seen_uuids.capacity() * std::mem::size_of::<Uuid>())
+                as u64,
+            sort_buffer_bytes: sort_buffer.map_or(0, |sort_buffer| {
+                (sort_buffer.capacity() * std::mem::size_of::<ValidationPacket>()) as u64
+            }),
+            bytes_read: ingestion_metrics.bytes_read,
+            bytes_written: if verify_only {
+                0
+            } else {
+                validation_meter.metrics().bytes_written
+            },
+            ..Default::default()
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        sample::generate_ingestion_sample,
-        test_utils::{
-            default_facilitator_signing_private_key, default_ingestor_private_key,
-            default_ingestor_private_key_raw, default_pha_signing_private_key,
-            DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
-        },
-        transport::LocalFileTransport,
-    };
-    use ring::signature::{KeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
+    /// Implements set_verify_after_write, given the digest of the packet file
+    /// and the number of packets that were written.
+    fn verify_written_batch(&mut self, packet_file_digest: &[u8], packets: u64) -> Result<()> {
+        let summary = self
+            .self_verify_validation_batch()
+            .map_err(|e| Error::PostWriteVerificationFailed(format!("{:#}", e)))?;
+        if summary.header.packet_file_digest != packet_file_digest {
+            return Err(Error::PostWriteVerificationFailed(
+                "header read back names a different packet file digest than was written".to_owned(),
+            )
+            .into());
+        }
+        if summary.packets != packets {
+            return Err(Error::PostWriteVerificationFailed(format!(
+                "read back {} packets but {} were written",
+                summary.packets, packets
+            ))
+            .into());
+        }
+        Ok(())
+    }
 
-    #[test]
-    fn share_validator() {
-        let pha_tempdir = tempfile::TempDir::new().unwrap();
-        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+    /// Returns true if done markers are written and there is one for this
+    /// batch's validation batch that names this batch.
+    fn is_done(&self) -> Result<bool> {
+        if !self.write_done_marker {
+            return Ok(false);
+        }
+        Ok(
+            DoneMarker::get(&*self.validation_transport, &self.output_batch()?)
+                .is_some_and(|marker| marker.batch_uuid == self.batch.batch_id),
+        )
+    }
 
-        let aggregation_name = "fake-aggregation-1".to_owned();
-        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
-        let batch_uuid = Uuid::new_v4();
-        let mut pha_ingest_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
-        let mut facilitator_ingest_transport =
-            LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
-        let mut pha_validate_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
-        let mut facilitator_validate_transport =
-            LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+    /// Returns true if the header, packet file and signature of the validation
+    /// batch for this batch all exist, the signature over the header verifies
+    /// with this share processor's own public key and the header describes
+    /// this batch. A batch that can't be read for any reason is taken not to
+    /// exist, so that it will be written again.
+    fn validation_batch_exists(&mut self) -> Result<bool> {
+        let share_processor_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            Vec::from(self.share_processor_signing_key.public_key().as_ref()),
+        );
+        let batch = self.output_batch()?;
+        if self
+            .validation_transport
+            .get(batch.key(BatchFileKind::Packets))
+            .is_err()
+        {
+            return Ok(false);
+        }
+        let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(batch, &mut *self.validation_transport);
+        Ok(validation_batch
+            .header(&share_processor_public_key)
+            .is_ok_and(|header| header.batch_uuid == self.batch.batch_id))
+    }
 
-        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
-        let facilitator_ecies_key =
-            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
-        let ingestor_pub_key = UnparsedPublicKey::new(
+    /// Re-reads the validation batch that generate_validation_share wrote for
+    /// this batch and checks it as a consumer would: that its header verifies
+    /// with this share processor's own public key and describes this batch,
+    /// that its packet file matches the header and holds as many packets as the
+    /// header declares, that it contains packets unless empty batches are
+    /// allowed and, if manifests are written, that the manifest verifies and
+    /// agrees on the number of packets.
+    pub fn self_verify_validation_batch(&mut self) -> Result<SelfVerificationSummary> {
+        let share_processor_public_key = UnparsedPublicKey::new(
             &ECDSA_P256_SHA256_FIXED,
-            default_ingestor_private_key()
-                .public_key()
-                .as_ref()
-                .to_vec(),
+            Vec::from(self.share_processor_signing_key.public_key().as_ref()),
         );
-        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
-            &ECDSA_P256_SHA256_FIXED_SIGNING,
+        let batch = self.output_batch()?;
+        let mut validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(batch, &mut *self.validation_transport);
+        validation_batch.set_spool_threshold(self.spool_threshold);
+        validation_batch.set_retry_policy(self.transport_retry_policy);
+
+        let header = validation_batch.header(&share_processor_public_key)?;
+        if header.batch_uuid != self.batch.batch_id {
+            return Err(
+                Error::BatchIdentityMismatch(self.batch.batch_id, header.batch_uuid).into(),
+            );
+        }
+
+        let mut packet_reader = validation_batch.packet_file_reader(&header)?;
+        let mut packets = 0;
+        loop {
+            match ValidationPacket::read(&mut packet_reader) {
+                Ok(_) => packets += 1,
+                Err(Error::EofError) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let Some(declared_packet_count) = header.packet_count {
+            if declared_packet_count != packets {
+                return Err(Error::PacketCountMismatch(declared_packet_count, packets).into());
+            }
+        }
+        if packets == 0 && !self.allow_empty_batches {
+            return Err(Error::EmptyBatchError(format!(
+                "validation batch for {} contains no packets",
+                self.batch
+            ))
+            .into());
+        }
+
+        let manifest = if self.write_manifest {
+            let manifest = validation_batch.verified_manifest(&share_processor_public_key)?;
+            if manifest.packet_count != packets {
+                return Err(anyhow!(
+                    "manifest lists {} packets but packet file contains {}",
+                    manifest.packet_count,
+                    packets
+                ));
+            }
+            Some(manifest)
+        } else {
+            None
+        };
+
+        Ok(SelfVerificationSummary {
+            header,
+            packets,
+            manifest,
+        })
+    }
+}
+
+/// Builds a BatchIntaker from the settings that every batch needs, provided to
+/// BatchIntakerBuilder::new, and any number of optional ones, each of which
+/// defaults as described on the corresponding BatchIntaker::set_* method.
+/// Unlike the setters, build checks the settings against each other.
+pub struct BatchIntakerBuilder<'a> {
+    instance_name: Option<String>,
+    batch: BatchIdentity,
+    ingestion_transport: Option<IntakeTransport<'a>>,
+    validation_transport: Option<IntakeTransport<'a>>,
+    server_identity: ServerIdentity,
+    ingestion_naming_scheme: &'a dyn BatchNamingScheme,
+    validation_naming_scheme: &'a dyn BatchNamingScheme,
+    allow_empty_batches: bool,
+    expected_number_of_servers: i32,
+    expected_hamming_weight: Option<i32>,
+    min_epsilon: Option<f64>,
+    max_epsilon: f64,
+    rng: Option<&'a (dyn SecureRandom + Sync)>,
+    worker_threads: Option<usize>,
+    archive_transport: Option<&'a mut dyn Transport>,
+    archive_failures_fatal: Option<bool>,
+    batch_date_window: Option<BatchDateWindow>,
+    clock: &'a dyn Clock,
+    server_pool: Option<&'a ServerPool>,
+    validation_attempt: u32,
+    reserved_key_prefix_length: usize,
+    write_manifest: bool,
+    write_done_marker: bool,
+    verify_after_write: bool,
+    overwrite: bool,
+    processed_batch_store: Option<&'a dyn ProcessedBatchStore>,
+    cancellation: Option<&'a AtomicBool>,
+    max_packet_file_size: Option<u64>,
+    read_buffer_size: usize,
+    copy_buffer_size: usize,
+    spool_threshold: usize,
+    transport_retry_policy: RetryPolicy,
+    packet_failure_policy: PacketFailurePolicy,
+    output_ordering: OutputOrdering,
+    decryption_failure_limit: Option<DecryptionFailureLimit>,
+    progress_callback: Option<&'a mut (dyn FnMut(&IntakeProgress) + Send)>,
+    progress_interval: u64,
+    share_processor_ecies_key: &'a PrivateKey,
+    ecies_keys: Option<&'a EciesKeys>,
+    share_processor_signing_key: &'a EcdsaKeyPair,
+    ingestor_keys: Vec<&'a UnparsedPublicKey<Vec<u8>>>,
+}
+
+impl<'a> BatchIntakerBuilder<'a> {
+    /// Creates a builder for a BatchIntaker that validates the provided batch
+    /// as the provided share processor, using the provided keys. The ingestion
+    /// and validation transports must also be provided before calling build.
+    pub fn new(
+        batch: &BatchIdentity,
+        server_identity: ServerIdentity,
+        share_processor_ecies_key: &'a PrivateKey,
+        share_processor_signing_key: &'a EcdsaKeyPair,
+        ingestor_key: &'a UnparsedPublicKey<Vec<u8>>,
+    ) -> BatchIntakerBuilder<'a> {
+        BatchIntakerBuilder {
+            instance_name: None,
+            batch: batch.clone(),
+            ingestion_transport: None,
+            validation_transport: None,
+            server_identity,
+            ingestion_naming_scheme: &DEFAULT_NAMING_SCHEME,
+            validation_naming_scheme: &DEFAULT_NAMING_SCHEME,
+            allow_empty_batches: false,
+            expected_number_of_servers: DEFAULT_NUMBER_OF_SERVERS,
+            expected_hamming_weight: None,
+            min_epsilon: None,
+            max_epsilon: DEFAULT_MAX_EPSILON,
+            rng: None,
+            worker_threads: None,
+            archive_transport: None,
+            archive_failures_fatal: None,
+            batch_date_window: None,
+            clock: &SystemClock,
+            server_pool: None,
+            validation_attempt: 0,
+            reserved_key_prefix_length: 0,
+            write_manifest: false,
+            write_done_marker: false,
+            verify_after_write: false,
+            overwrite: false,
+            processed_batch_store: None,
+            cancellation: None,
+            max_packet_file_size: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            copy_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            spool_threshold: DEFAULT_SPOOL_THRESHOLD,
+            transport_retry_policy: DEFAULT_RETRY_POLICY,
+            packet_failure_policy: PacketFailurePolicy::Abort,
+            output_ordering: OutputOrdering::PreserveInput,
+            decryption_failure_limit: Some(DEFAULT_DECRYPTION_FAILURE_LIMIT),
+            progress_callback: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            share_processor_ecies_key,
+            ecies_keys: None,
+            share_processor_signing_key,
+            ingestor_keys: vec![ingestor_key],
+        }
+    }
+
+    /// Sets the name of the instance, if any, whose batches are keyed under it.
+    pub fn instance_name(mut self, instance_name: &str) -> Self {
+        self.instance_name = Some(instance_name.to_owned());
+        self
+    }
+
+    /// Sets the transport from which the ingestion batch is read. Required.
+    pub fn ingestion_transport(mut self, transport: &'a mut dyn Transport) -> Self {
+        self.ingestion_transport = Some(IntakeTransport::Borrowed(transport));
+        self
+    }
+
+    /// Sets the transport to which the validation batch is written. Required.
+    pub fn validation_transport(mut self, transport: &'a mut dyn Transport) -> Self {
+        self.validation_transport = Some(IntakeTransport::Borrowed(transport));
+        self
+    }
+
+    /// Like ingestion_transport, but the BatchIntaker takes ownership of the
+    /// transport, so that it needn't outlive a borrowed one.
+    pub fn owned_ingestion_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.ingestion_transport = Some(IntakeTransport::Owned(transport));
+        self
+    }
+
+    /// Like validation_transport, but the BatchIntaker takes ownership of the
+    /// transport, so that it needn't outlive a borrowed one.
+    pub fn owned_validation_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.validation_transport = Some(IntakeTransport::Owned(transport));
+        self
+    }
+
+    /// See BatchIntaker::set_naming_scheme.
+    pub fn naming_scheme(mut self, naming_scheme: &'a dyn BatchNamingScheme) -> Self {
+        self.ingestion_naming_scheme = naming_scheme;
+        self.validation_naming_scheme = naming_scheme;
+        self
+    }
+
+    /// See BatchIntaker::set_ingestion_naming_scheme.
+    pub fn ingestion_naming_scheme(mut self, naming_scheme: &'a dyn BatchNamingScheme) -> Self {
+        self.ingestion_naming_scheme = naming_scheme;
+        self
+    }
+
+    /// See BatchIntaker::set_validation_naming_scheme.
+    pub fn validation_naming_scheme(mut self, naming_scheme: &'a dyn BatchNamingScheme) -> Self {
+        self.validation_naming_scheme = naming_scheme;
+        self
+    }
+
+    /// See BatchIntaker::set_allow_empty_batches.
+    pub fn allow_empty_batches(mut self, allow_empty_batches: bool) -> Self {
+        self.allow_empty_batches = allow_empty_batches;
+        self
+    }
+
+    /// See BatchIntaker::set_expected_number_of_servers. Must be positive.
+    pub fn expected_number_of_servers(mut self, expected_number_of_servers: i32) -> Self {
+        self.expected_number_of_servers = expected_number_of_servers;
+        self
+    }
+
+    /// See BatchIntaker::set_expected_hamming_weight. Must be positive if set.
+    pub fn expected_hamming_weight(mut self, expected_hamming_weight: Option<i32>) -> Self {
+        self.expected_hamming_weight = expected_hamming_weight;
+        self
+    }
+
+    /// See BatchIntaker::set_max_epsilon. Must be finite and positive.
+    pub fn max_epsilon(mut self, max_epsilon: f64) -> Self {
+        self.max_epsilon = max_epsilon;
+        self
+    }
+
+    /// See BatchIntaker::set_min_epsilon.
+    pub fn min_epsilon(mut self, min_epsilon: Option<f64>) -> Self {
+        self.min_epsilon = min_epsilon;
+        self
+    }
+
+    /// See BatchIntaker::set_rng.
+    pub fn rng(mut self, rng: &'a (dyn SecureRandom + Sync)) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// See BatchIntaker::set_worker_threads.
+    pub fn worker_threads(mut self, worker_threads: Option<usize>) -> Self {
+        self.worker_threads = worker_threads;
+        self
+    }
+
+    /// See BatchIntaker::set_archive_transport.
+    pub fn archive_transport(mut self, archive_transport: &'a mut dyn Transport) -> Self {
+        self.archive_transport = Some(archive_transport);
+        self
+    }
+
+    /// See BatchIntaker::set_archive_failures_fatal. Requires an archive
+    /// transport.
+    pub fn archive_failures_fatal(mut self, archive_failures_fatal: bool) -> Self {
+        self.archive_failures_fatal = Some(archive_failures_fatal);
+        self
+    }
+
+    /// See BatchIntaker::set_batch_date_window.
+    pub fn batch_date_window(mut self, batch_date_window: Option<BatchDateWindow>) -> Self {
+        self.batch_date_window = batch_date_window;
+        self
+    }
+
+    /// See BatchIntaker::set_clock.
+    pub fn clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Selects the packet decryption key from keys by the batch's aggregation,
+    /// in place of the key provided to new, and reports its identifier in
+    /// ValidationStats::ecies_key_id. build fails with Error::MissingEciesKey
+    /// if keys has no key for the aggregation and no default. A server pool,
+    /// if provided, must have been created with ServerPool::with_key_id for
+    /// the selected key.
+    pub fn ecies_keys(mut self, keys: &'a EciesKeys) -> Self {
+        self.ecies_keys = Some(keys);
+        self
+    }
+
+    /// See BatchIntaker::set_server_pool.
+    pub fn server_pool(mut self, server_pool: &'a ServerPool) -> Self {
+        self.server_pool = Some(server_pool);
+        self
+    }
+
+    /// See BatchIntaker::set_validation_attempt.
+    pub fn validation_attempt(mut self, validation_attempt: u32) -> Self {
+        self.validation_attempt = validation_attempt;
+        self
+    }
+
+    /// See BatchIntaker::set_reserved_key_prefix_length.
+    pub fn reserved_key_prefix_length(mut self, reserved_key_prefix_length: usize) -> Self {
+        self.reserved_key_prefix_length = reserved_key_prefix_length;
+        self
+    }
+
+    /// See BatchIntaker::set_write_manifest.
+    pub fn write_manifest(mut self, write_manifest: bool) -> Self {
+        self.write_manifest = write_manifest;
+        self
+    }
+
+    /// See BatchIntaker::set_write_done_marker.
+    pub fn write_done_marker(mut self, write_done_marker: bool) -> Self {
+        self.write_done_marker = write_done_marker;
+        self
+    }
+
+    /// See BatchIntaker::set_verify_after_write.
+    pub fn verify_after_write(mut self, verify_after_write: bool) -> Self {
+        self.verify_after_write = verify_after_write;
+        self
+    }
+
+    /// See BatchIntaker::set_overwrite.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// See BatchIntaker::set_processed_batch_store.
+    pub fn processed_batch_store(mut self, store: &'a dyn ProcessedBatchStore) -> Self {
+        self.processed_batch_store = Some(store);
+        self
+    }
+
+    /// See BatchIntaker::set_cancellation.
+    pub fn cancellation(mut self, cancellation: &'a AtomicBool) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// See BatchIntaker::set_max_packet_file_size.
+    pub fn max_packet_file_size(mut self, max_packet_file_size: Option<u64>) -> Self {
+        self.max_packet_file_size = max_packet_file_size;
+        self
+    }
+
+    /// See BatchIntaker::set_read_buffer_size. Must be positive.
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// See BatchIntaker::set_copy_buffer_size. Must be positive.
+    pub fn copy_buffer_size(mut self, copy_buffer_size: usize) -> Self {
+        self.copy_buffer_size = copy_buffer_size;
+        self
+    }
+
+    /// See BatchIntaker::set_spool_threshold.
+    pub fn spool_threshold(mut self, spool_threshold: usize) -> Self {
+        self.spool_threshold = spool_threshold;
+        self
+    }
+
+    /// See BatchIntaker::set_transport_retry_policy. The policy must allow at
+    /// least one attempt.
+    pub fn transport_retry_policy(mut self, transport_retry_policy: RetryPolicy) -> Self {
+        self.transport_retry_policy = transport_retry_policy;
+        self
+    }
+
+    /// See BatchIntaker::set_packet_failure_policy. A max_failure_fraction
+    /// must be between 0 and 1.
+    pub fn packet_failure_policy(mut self, packet_failure_policy: PacketFailurePolicy) -> Self {
+        self.packet_failure_policy = packet_failure_policy;
+        self
+    }
+
+    /// See BatchIntaker::set_output_ordering.
+    pub fn output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+        self.output_ordering = output_ordering;
+        self
+    }
+
+    /// See BatchIntaker::set_decryption_failure_limit. A max_fraction must be
+    /// between 0 and 1.
+    pub fn decryption_failure_limit(
+        mut self,
+        decryption_failure_limit: Option<DecryptionFailureLimit>,
+    ) -> Self {
+        self.decryption_failure_limit = decryption_failure_limit;
+        self
+    }
+
+    /// See BatchIntaker::set_progress_callback.
+    pub fn progress_callback(
+        mut self,
+        progress_callback: &'a mut (dyn FnMut(&IntakeProgress) + Send),
+    ) -> Self {
+        self.progress_callback = Some(progress_callback);
+        self
+    }
+
+    /// See BatchIntaker::set_progress_interval. Must be positive.
+    pub fn progress_interval(mut self, progress_interval: u64) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    /// See BatchIntaker::add_ingestor_key.
+    pub fn additional_ingestor_key(mut self, ingestor_key: &'a UnparsedPublicKey<Vec<u8>>) -> Self {
+        self.ingestor_keys.push(ingestor_key);
+        self
+    }
+
+    /// Creates the BatchIntaker, failing with Error::MalformedConfigError if a
+    /// required transport is missing or the settings are out of range or
+    /// inconsistent, or with Error::IllegalNameError if the instance name is
+    /// not valid.
+    pub fn build(self) -> Result<BatchIntaker<'a>> {
+        let ingestion_transport = self.ingestion_transport.ok_or_else(|| {
+            Error::MalformedConfigError("no ingestion transport provided".to_owned())
+        })?;
+        let validation_transport = self.validation_transport.ok_or_else(|| {
+            Error::MalformedConfigError("no validation transport provided".to_owned())
+        })?;
+        if self.archive_failures_fatal.is_some() && self.archive_transport.is_none() {
+            return Err(Error::MalformedConfigError(
+                "archive failure handling set without an archive transport".to_owned(),
+            )
+            .into());
+        }
+        if self.expected_number_of_servers < 1 {
+            return Err(Error::MalformedConfigError(format!(
+                "expected number of servers is {}",
+                self.expected_number_of_servers
+            ))
+            .into());
+        }
+        if let Some(expected_hamming_weight) = self.expected_hamming_weight {
+            if expected_hamming_weight < 1 {
+                return Err(Error::MalformedConfigError(format!(
+                    "expected hamming weight is {}",
+                    expected_hamming_weight
+                ))
+                .into());
+            }
+        }
+        if !(self.max_epsilon.is_finite() && self.max_epsilon > 0.0) {
+            return Err(Error::MalformedConfigError(format!(
+                "maximum epsilon is {}",
+                self.max_epsilon
+            ))
+            .into());
+        }
+        if let Some(min_epsilon) = self.min_epsilon {
+            if !(min_epsilon.is_finite() && min_epsilon > 0.0 && min_epsilon <= self.max_epsilon) {
+                return Err(Error::MalformedConfigError(format!(
+                    "minimum epsilon is {} but must be greater than zero and at most the \
+                    maximum epsilon {}",
+                    min_epsilon, self.max_epsilon
+                ))
+                .into());
+            }
+        }
+        if self.read_buffer_size == 0 {
+            return Err(Error::MalformedConfigError("read buffer size is 0".to_owned()).into());
+        }
+        if self.copy_buffer_size == 0 {
+            return Err(Error::MalformedConfigError("copy buffer size is 0".to_owned()).into());
+        }
+        if self.progress_interval == 0 {
+            return Err(Error::MalformedConfigError("progress interval is 0".to_owned()).into());
+        }
+        if self.transport_retry_policy.max_attempts == 0 {
+            return Err(Error::MalformedConfigError(
+                "transport retry policy allows no attempts".to_owned(),
+            )
+            .into());
+        }
+        if let PacketFailurePolicy::Record {
+            max_failure_fraction,
+        } = self.packet_failure_policy
+        {
+            if !(0.0..=1.0).contains(&max_failure_fraction) {
+                return Err(Error::MalformedConfigError(format!(
+                    "max packet failure fraction {} is not between 0 and 1",
+                    max_failure_fraction
+                ))
+                .into());
+            }
+        }
+        if let Some(limit) = self.decryption_failure_limit {
+            if !(0.0..=1.0).contains(&limit.max_fraction) {
+                return Err(Error::MalformedConfigError(format!(
+                    "max decryption failure fraction {} is not between 0 and 1",
+                    limit.max_fraction
+                ))
+                .into());
+            }
+        }
+        let instance_name = self
+            .instance_name
+            .as_deref()
+            .map(InstanceName::new)
+            .transpose()?;
+        let (share_processor_ecies_key_id, share_processor_ecies_key) = match self.ecies_keys {
+            Some(keys) => {
+                let (key_id, key) = keys.key_for(&self.batch.aggregation_name)?;
+                (Some(key_id.to_owned()), key)
+            }
+            None => (None, self.share_processor_ecies_key),
+        };
+
+        Ok(BatchIntaker {
+            instance_name,
+            batch: self.batch,
+            ingestion_transport,
+            validation_transport,
+            server_identity: self.server_identity,
+            ingestion_naming_scheme: self.ingestion_naming_scheme,
+            validation_naming_scheme: self.validation_naming_scheme,
+            allow_empty_batches: self.allow_empty_batches,
+            expected_number_of_servers: self.expected_number_of_servers,
+            expected_hamming_weight: self.expected_hamming_weight,
+            min_epsilon: self.min_epsilon,
+            max_epsilon: self.max_epsilon,
+            rng: self.rng,
+            worker_threads: self.worker_threads,
+            archive_transport: self.archive_transport,
+            archive_failures_fatal: self.archive_failures_fatal.unwrap_or(true),
+            archive_errors: Vec::new(),
+            last_timings: IntakeTimings::default(),
+            batch_date_window: self.batch_date_window,
+            clock: self.clock,
+            server_pool: self.server_pool,
+            validation_attempt: self.validation_attempt,
+            reserved_key_prefix_length: self.reserved_key_prefix_length,
+            write_manifest: self.write_manifest,
+            write_done_marker: self.write_done_marker,
+            verify_after_write: self.verify_after_write,
+            overwrite: self.overwrite,
+            processed_batch_store: self.processed_batch_store,
+            cancellation: self.cancellation,
+            max_packet_file_size: self.max_packet_file_size,
+            read_buffer_size: self.read_buffer_size,
+            copy_buffer_size: self.copy_buffer_size,
+            spool_threshold: self.spool_threshold,
+            transport_retry_policy: self.transport_retry_policy,
+            packet_failure_policy: self.packet_failure_policy,
+            output_ordering: self.output_ordering,
+            decryption_failure_limit: self.decryption_failure_limit,
+            progress_callback: self.progress_callback,
+            progress_interval: self.progress_interval,
+            share_processor_ecies_key,
+            share_processor_ecies_key_id,
+            share_processor_signing_key: self.share_processor_signing_key,
+            ingestor_keys: self.ingestor_keys,
+        })
+    }
+}
+
+/// A transport that a BatchIntaker either borrows or owns.
+enum IntakeTransport<'a> {
+    Borrowed(&'a mut dyn Transport),
+    Owned(Box<dyn Transport>),
+}
+
+impl<'a> Deref for IntakeTransport<'a> {
+    type Target = dyn Transport + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            IntakeTransport::Borrowed(transport) => *transport,
+            IntakeTransport::Owned(transport) => transport.as_ref(),
+        }
+    }
+}
+
+impl<'a> DerefMut for IntakeTransport<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            IntakeTransport::Borrowed(transport) => *transport,
+            IntakeTransport::Owned(transport) => transport.as_mut(),
+        }
+    }
+}
+
+/// Passes IntakeProgress reports to a progress callback, if there is one.
+struct ProgressReporter<'c, 'f> {
+    callback: Option<&'c mut (dyn FnMut(&IntakeProgress) + Send + 'f)>,
+    start: Instant,
+    interval: u64,
+    next_report: u64,
+    declared_packets: Option<u64>,
+}
+
+impl<'c, 'f> ProgressReporter<'c, 'f> {
+    /// Reports the provided phase, failing if the callback panics.
+    fn report(&mut self, phase: IntakePhase, packets: u64) -> Result<()> {
+        let callback = match &mut self.callback {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        let progress = IntakeProgress {
+            phase,
+            elapsed: self.start.elapsed(),
+            packets,
+            declared_packets: self.declared_packets,
+        };
+        catch_unwind(AssertUnwindSafe(|| callback(&progress)))
+            .map_err(|_| anyhow!("progress callback panicked"))
+    }
+
+    /// Reports IntakePhase::ValidatingPackets if at least another interval's
+    /// worth of packets has been validated since the last such report.
+    fn packets_validated(&mut self, packets: u64) -> Result<()> {
+        if packets < self.next_report {
+            return Ok(());
+        }
+        self.next_report = (packets / self.interval + 1) * self.interval;
+        self.report(IntakePhase::ValidatingPackets, packets)
+    }
+}
+
+/// Copies verified ingestion batch files to an archive transport.
+struct Archiver<'t> {
+    transport: &'t mut dyn Transport,
+    prefix: String,
+    fatal: bool,
+    errors: &'t mut Vec<anyhow::Error>,
+}
+
+impl<'t> Archiver<'t> {
+    /// Writes the provided content to the archive transport under the provided
+    /// key, prefixed with the archive timestamp. Failures are only returned if
+    /// they are fatal.
+    fn archive(&mut self, key: &str, content: &mut dyn Read) -> Result<()> {
+        let archive_key = format!("{}/{}", self.prefix, key);
+        let result = self.put(&archive_key, content);
+        match result {
+            Err(e) if !self.fatal => {
+                self.errors.push(e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn put(&mut self, archive_key: &str, content: &mut dyn Read) -> Result<()> {
+        let mut writer = self.transport.put(archive_key)?;
+        std::io::copy(content, &mut writer)
+            .with_context(|| format!("failed to write archive copy {}", archive_key))?;
+        writer
+            .complete_upload()
+            .with_context(|| format!("failed to archive {}", archive_key))
+    }
+}
+
+/// A run of consecutive ingestion packets, numbered in the order it was read.
+/// Packets rejected as they are read, for a duplicate UUID or a sequence
+/// number out of order, are left out of the run to be validated but travel
+/// with it so that their failures are recorded in input order.
+struct PacketChunk {
+    index: usize,
+    packets: Vec<IngestionDataSharePacket>,
+    rejected: Vec<PacketFailure>,
+}
+
+impl PacketChunk {
+    fn new(index: usize) -> PacketChunk {
+        PacketChunk {
+            index,
+            packets: Vec::with_capacity(PACKETS_PER_WORKER),
+            rejected: Vec::new(),
+        }
+    }
+
+    fn validate(
+        self,
+        server: &mut Server,
+        field: PrimeField,
+        ecies_key: &PrivateKey,
+    ) -> ValidatedChunk {
+        let results = self
+            .packets
+            .iter()
+            .map(|packet| validate_packet(server, field, ecies_key, packet))
+            .collect();
+        ValidatedChunk {
+            index: self.index,
+            packets: self.packets,
+            rejected: self.rejected,
+            results,
+        }
+    }
+}
+
+/// A PacketChunk along with the validation result for each of its packets.
+struct ValidatedChunk {
+    index: usize,
+    packets: Vec<IngestionDataSharePacket>,
+    rejected: Vec<PacketFailure>,
+    results: Vec<Result<ValidationPacket>>,
+}
+
+/// Counts the packets recorded as failures by kind, other than those rejected
+/// as they were read.
+#[derive(Default)]
+struct FailureCounts {
+    decryption: u64,
+    structural: u64,
+}
+
+impl FailureCounts {
+    /// Returns Error::ProbableKeyMismatch if, out of packet_count packets, too
+    /// many failed to decrypt for the limit, if any. The limit only applies
+    /// once it has seen enough packets, or at the end of the batch.
+    fn check(
+        &self,
+        limit: Option<DecryptionFailureLimit>,
+        packet_count: u64,
+        end_of_batch: bool,
+    ) -> Result<(), Error> {
+        match limit {
+            Some(limit)
+                if (end_of_batch || packet_count >= limit.min_packets)
+                    && self.decryption as f64 > limit.max_fraction * packet_count as f64 =>
+            {
+                Err(Error::ProbableKeyMismatch(
+                    self.decryption,
+                    packet_count,
+                    limit.max_fraction,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns Error::Cancelled if the cancellation flag has been set.
+fn check_cancellation(cancellation: Option<&AtomicBool>) -> Result<(), Error> {
+    match cancellation {
+        Some(cancelled) if cancelled.load(Ordering::Relaxed) => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Tracks the packets read from an ingestion batch so far, to reject those
+/// that repeat a UUID or whose sequence number is out of order.
+struct PacketAdmission {
+    // A packet UUID that appears twice in a batch would be counted twice by
+    // the aggregation, so only its first occurrence is validated. The set is
+    // sized from the ingestion header's packet count, if it has one, up to
+    // MAX_PREALLOCATED_UUIDS. It holds one UUID per packet and so is bounded
+    // by the packet file size limit (see set_max_packet_file_size).
+    seen_uuids: HashSet<Uuid>,
+    // The sequence number the next packet should have, if it has one.
+    next_sequence_number: i64,
+}
+
+impl PacketAdmission {
+    /// Creates a PacketAdmission for a batch whose header declares the
+    /// provided number of packets, if it declares any.
+    fn new(declared_packet_count: Option<u64>) -> PacketAdmission {
+        PacketAdmission {
+            seen_uuids: HashSet::with_capacity(preallocated_uuids(declared_packet_count)),
+            next_sequence_number: 0,
+        }
+    }
+
+    /// Adds the packet to the chunk to be validated, or rejects it, recording
+    /// or returning its failure as the policy dictates. A packet whose
+    /// sequence number is not one more than that of the packet before it,
+    /// counting from zero, is rejected. The count then resumes from its
+    /// number, so that a record lost from the middle of the packet file fails
+    /// only the packet after it. Packets without a sequence number are
+    /// counted but not checked.
+    fn admit(
+        &mut self,
+        packet: IngestionDataSharePacket,
+        packet_failure_policy: PacketFailurePolicy,
+        chunk: &mut PacketChunk,
+    ) -> Result<()> {
+        let expected_sequence_number = self.next_sequence_number;
+        let sequence_number = packet.sequence_number.unwrap_or(expected_sequence_number);
+        self.next_sequence_number = sequence_number.saturating_add(1);
+        let (error, reason) = if sequence_number != expected_sequence_number {
+            (
+                Error::SequenceNumberMismatch(
+                    packet.uuid,
+                    expected_sequence_number,
+                    sequence_number,
+                ),
+                format!(
+                    "sequence number {} where {} was expected",
+                    sequence_number, expected_sequence_number
+                ),
+            )
+        } else if !self.seen_uuids.insert(packet.uuid) {
+            (
+                Error::DuplicatePacketError(packet.uuid),
+                "duplicate packet UUID".to_owned(),
+            )
+        } else {
+            chunk.packets.push(packet);
+            return Ok(());
+        };
+        match packet_failure_policy {
+            PacketFailurePolicy::Abort => Err(error.into()),
+            PacketFailurePolicy::Record { .. } => {
+                chunk.rejected.push(PacketFailure {
+                    uuid: packet.uuid,
+                    reason,
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reads packets into the chunk until it holds PACKETS_PER_WORKER of them or
+/// the batch is exhausted, returning true in the latter case.
+fn read_packet_chunk(
+    reader: &mut ShardedPacketReader<'_, '_, IngestionHeader, IngestionDataSharePacket>,
+    admission: &mut PacketAdmission,
+    packet_failure_policy: PacketFailurePolicy,
+    chunk: &mut PacketChunk,
+) -> Result<bool> {
+    while chunk.packets.len() < PACKETS_PER_WORKER {
+        match reader.read_packet() {
+            Ok(p) => admission.admit(p, packet_failure_policy, chunk)?,
+            Err(Error::EofError) => return Ok(true),
+            Err(Error::AnyhowError(e)) => return Err(e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(false)
+}
+
+/// Writes out the validation packets of the chunk, or adds them to the sort
+/// buffer if there is one, and records and counts its failures or returns the
+/// first of them, as the policy dictates. Returns the
+/// number of ingestion packets the chunk accounts for.
+fn write_validated_chunk<W: Write>(
+    packet_writer: &mut Writer<W>,
+    packet_failure_policy: PacketFailurePolicy,
+    chunk: ValidatedChunk,
+    mut sort_buffer: Option<&mut Vec<ValidationPacket>>,
+    packet_failures: &mut Vec<PacketFailure>,
+    failure_counts: &mut FailureCounts,
+) -> Result<u64> {
+    let packet_count = (chunk.rejected.len() + chunk.packets.len()) as u64;
+    packet_failures.extend(chunk.rejected);
+    for (packet, result) in chunk.packets.iter().zip(chunk.results) {
+        match (result, packet_failure_policy) {
+            (Ok(validation_packet), _) => match sort_buffer.as_deref_mut() {
+                Some(sort_buffer) => sort_buffer.push(validation_packet),
+                None => validation_packet.write(packet_writer)?,
+            },
+            (Err(e), PacketFailurePolicy::Abort) => {
+                return Err(e.context(format!("in packet {}", packet.uuid)))
+            }
+            (Err(e), PacketFailurePolicy::Record { .. }) => {
+                match e.downcast_ref() {
+                    Some(Error::PacketDecryptionError(_)) => failure_counts.decryption += 1,
+                    _ => failure_counts.structural += 1,
+                }
+                packet_failures.push(PacketFailure {
+                    uuid: packet.uuid,
+                    reason: format!("{:#}", e),
+                })
+            }
+        }
+    }
+    Ok(packet_count)
+}
+
+/// Validates chunks taken from the shared receiver until it is disconnected or
+/// nobody is left to take the results. A panic while validating is sent back
+/// in place of the chunk, for the receiving thread to resume.
+fn validation_worker(
+    server: &mut Server,
+    field: PrimeField,
+    ecies_key: &PrivateKey,
+    chunk_receiver: &Mutex<Receiver<PacketChunk>>,
+    validated_sender: Sender<std::thread::Result<ValidatedChunk>>,
+) {
+    loop {
+        // Release the lock before validating so other workers can take chunks
+        let chunk = match chunk_receiver.lock().unwrap().recv() {
+            Ok(chunk) => chunk,
+            Err(_) => return,
+        };
+        let validated = catch_unwind(AssertUnwindSafe(|| {
+            chunk.validate(server, field, ecies_key)
+        }));
+        if validated_sender.send(validated).is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns how many packet UUIDs to make room for up front when checking for
+/// duplicates in a batch whose header declares the provided packet count, if
+/// it declares one.
+fn preallocated_uuids(declared_packet_count: Option<u64>) -> usize {
+    declared_packet_count.map_or(0, |count| {
+        usize::try_from(count).map_or(MAX_PREALLOCATED_UUIDS, |count| {
+            count.min(MAX_PREALLOCATED_UUIDS)
+        })
+    })
+}
+
+/// Fails with Error::ReplayDetected if the batch was recorded as processed
+/// under a date other than its own.
+fn check_replay(batch: &BatchIdentity, recorded_date: Option<BatchDate>) -> Result<()> {
+    match recorded_date {
+        Some(date) if date != batch.date => {
+            Err(Error::ReplayDetected(batch.batch_id, date, batch.date).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the parameters the ingestion header declares are ones the
+/// libprio Server can validate packets under, and that the header describes
+/// the batch with the provided ID, which it was fetched as. Returns the field
+/// that the header's prime selects.
+fn check_ingestion_header(
+    header: &IngestionHeader,
+    batch_id: &Uuid,
+    expected_number_of_servers: i32,
+    expected_hamming_weight: Option<i32>,
+    min_epsilon: Option<f64>,
+    max_epsilon: f64,
+) -> Result<PrimeField> {
+    if header.bins <= 0 {
+        return Err(Error::MalformedHeaderError(format!(
+            "invalid bins/dimension value {}",
+            header.bins
+        ))
+        .into());
+    }
+    if let Some(hamming_weight) = header.hamming_weight {
+        if !(1..=header.bins).contains(&hamming_weight) {
+            return Err(Error::MalformedHeaderError(format!(
+                "hamming_weight is {} but must be between 1 and bins ({})",
+                hamming_weight, header.bins
+            ))
+            .into());
+        }
+    }
+    if let Some(expected_hamming_weight) = expected_hamming_weight {
+        if header.hamming_weight != Some(expected_hamming_weight) {
+            return Err(Error::MalformedHeaderError(format!(
+                "hamming_weight is {} but expected {}",
+                header
+                    .hamming_weight
+                    .map_or_else(|| "not set".to_owned(), |w| w.to_string()),
+                expected_hamming_weight
+            ))
+            .into());
+        }
+    }
+    let field = PrimeField::from_prime(header.prime)?;
+    if header.number_of_servers != expected_number_of_servers {
+        return Err(Error::MalformedHeaderError(format!(
+            "number_of_servers is {} but expected {}",
+            header.number_of_servers, expected_number_of_servers
+        ))
+        .into());
+    }
+    header.validate(min_epsilon, max_epsilon)?;
+    // The header is signed by the ingestor, but the key it was fetched
+    // from is not, so make sure the two agree on which batch this is.
+    if header.batch_uuid != *batch_id {
+        return Err(Error::BatchIdentityMismatch(*batch_id, header.batch_uuid).into());
+    }
+    Ok(field)
+}
+
+/// Computes the validation packet for a single ingestion packet, whose r_pit
+/// is an element of the provided field.
+fn validate_packet(
+    server: &mut Server,
+    field: PrimeField,
+    ecies_key: &PrivateKey,
+    packet: &IngestionDataSharePacket,
+) -> Result<ValidationPacket> {
+    let r_pit = field
+        .element(packet.r_pit)
+        .with_context(|| format!("illegal r_pit value {}", packet.r_pit))?;
+
+    // Whether a failure here aborts the batch is up to the caller's
+    // PacketFailurePolicy. libprio doesn't say why it failed, so decrypt the
+    // payload again to tell a payload we can't decrypt, perhaps because it
+    // was encrypted to another key, from a malformed proof. Only failed
+    // packets pay for the second decryption.
+    let validation_message =
+        match server.generate_verification_message(r_pit, &packet.encrypted_payload) {
+            Some(validation_message) => validation_message,
+            None => {
+                if let Err(e) = decrypt_share(&packet.encrypted_payload, ecies_key) {
+                    return Err(Error::PacketDecryptionError(e.to_string()).into());
+                }
+                return Err(anyhow!("failed to construct validation message"));
+            }
+        };
+
+    Ok(ValidationPacket::new(packet.uuid, &validation_message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        aggregation::compare_validation_batches,
+        batch::{
+            Batch, BatchDescriptor, BatchFileKind, BatchKind, BatchNaming,
+            DefaultBatchNamingScheme, PathLayout, MAX_KEY_LENGTH,
+        },
+        hash,
+        idl::{Header, SCHEMA_VERSION},
+        replay::MemoryProcessedBatchStore,
+        sample::{
+            generate_ingestion_sample, generate_ingestion_sample_from_parts,
+            generate_ingestion_sample_with_bad_packets, PacketCorruption,
+        },
+        test_utils::{
+            default_facilitator_signing_private_key, default_facilitator_signing_private_key_raw,
+            default_facilitator_signing_public_key, default_ingestor_private_key,
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            default_pha_signing_private_key, MockClock, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::{
+            is_transient, FanoutTransport, LocalFileTransport, MemoryTransport, MeteredTransport,
+            Stream, StreamTransport, TransportWriter,
+        },
+    };
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+    use ring::signature::{KeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use ring::test::rand::FixedByteRandom;
+    use std::io::{Read, Write};
+
+    /// The keys the PHA needs to validate a sample generated with the default
+    /// keys.
+    struct SampleKeys {
+        pha_ecies_key: PrivateKey,
+        pha_signing_key: EcdsaKeyPair,
+        ingestor_pub_key: UnparsedPublicKey<Vec<u8>>,
+    }
+
+    fn sample_keys() -> SampleKeys {
+        SampleKeys {
+            pha_ecies_key: PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            pha_signing_key: EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_FIXED_SIGNING,
+                &default_pha_signing_private_key(),
+            )
+            .unwrap(),
+            ingestor_pub_key: default_ingestor_public_key(),
+        }
+    }
+
+    /// Generates a sample of packet_count packets for the batch into the
+    /// provided ingestion transports, with the default keys.
+    fn generate_sample(
+        pha_ingest_transport: &mut dyn Transport,
+        facilitator_ingest_transport: &mut dyn Transport,
+        batch: &BatchIdentity,
+        packet_count: usize,
+    ) {
+        generate_ingestion_sample(
+            pha_ingest_transport,
+            facilitator_ingest_transport,
+            None,
+            batch,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            packet_count,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+    }
+
+    /// Generates a sample of packet_count packets for the batch into the
+    /// provided ingestion transports, with the default keys, corrupting the
+    /// packets listed in bad_packets. Returns the UUIDs of the corrupted
+    /// packets.
+    fn generate_sample_with_bad_packets(
+        pha_ingest_transport: &mut dyn Transport,
+        facilitator_ingest_transport: &mut dyn Transport,
+        batch: &BatchIdentity,
+        packet_count: usize,
+        bad_packets: &[(usize, PacketCorruption)],
+    ) -> Vec<Uuid> {
+        let (_, bad_packet_uuids) = generate_ingestion_sample_with_bad_packets(
+            pha_ingest_transport,
+            facilitator_ingest_transport,
+            None,
+            batch,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            packet_count,
+            0.11,
+            100,
+            100,
+            bad_packets,
+        )
+        .expect("failed to generate sample");
+        bad_packet_uuids
+    }
+
+    /// Generates a sample of packet_count packets for the batch with the
+    /// provided parts into the provided ingestion transports, with the default
+    /// keys.
+    fn generate_sample_from_parts(
+        pha_ingest_transport: &mut dyn Transport,
+        facilitator_ingest_transport: &mut dyn Transport,
+        batch_uuid: &Uuid,
+        aggregation_name: &str,
+        date: &BatchDate,
+        packet_count: usize,
+    ) {
+        generate_ingestion_sample_from_parts(
+            pha_ingest_transport,
+            facilitator_ingest_transport,
+            batch_uuid,
+            None,
+            aggregation_name,
+            date,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            packet_count,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+    }
+
+    #[test]
+    fn share_validator() {
+        run_share_validator(None)
+    }
+
+    #[test]
+    fn share_validator_with_instance_name() {
+        run_share_validator(Some("narnia"))
+    }
+
+    /// Returns the total size of all the values in the transport.
+    fn stored_bytes(transport: &dyn Transport) -> u64 {
+        transport
+            .list("")
+            .unwrap()
+            .iter()
+            .map(|key| {
+                let mut content = Vec::new();
+                transport
+                    .get(key)
+                    .unwrap()
+                    .read_to_end(&mut content)
+                    .unwrap();
+                content.len() as u64
+            })
+            .sum()
+    }
+
+    fn run_share_validator(instance_name: Option<&str>) {
+        let pha_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+        // Both share processors write validations into the same place so we
+        // can check that they don't clobber each other.
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch_uuid = Uuid::new_v4();
+        let mut pha_ingest_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+        let mut pha_validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+        let mut facilitator_validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let facilitator_ecies_key =
+            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+        let ingestor_pub_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            default_ingestor_private_key()
+                .public_key()
+                .as_ref()
+                .to_vec(),
+        );
+        let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
             &default_pha_signing_private_key(),
         )
         .unwrap();
         let facilitator_signing_key = default_facilitator_signing_private_key();
 
-        generate_ingestion_sample(
+        generate_ingestion_sample_from_parts(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch_uuid,
+            instance_name,
+            &aggregation_name,
+            &date,
+            &pha_ecies_key,
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+
+        let mut pha_ingestor = BatchIntaker::from_parts(
+            instance_name,
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut pha_validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+
+        let pha_stats = pha_ingestor
+            .generate_validation_share()
+            .expect("PHA failed to generate validation");
+        assert_eq!(pha_stats.packets, 10);
+        assert_eq!(pha_stats.epsilon, 0.11);
+        assert_eq!(pha_stats.bytes_read, stored_bytes(&pha_ingest_transport));
+        assert_eq!(
+            pha_stats.bytes_written,
+            stored_bytes(&pha_validate_transport)
+        );
+        assert!(!pha_stats.download_duration.is_zero());
+
+        let mut facilitator_ingestor = BatchIntaker::from_parts(
+            instance_name,
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut facilitator_ingest_transport,
+            &mut facilitator_validate_transport,
+            ServerIdentity::Facilitator,
+            &facilitator_ecies_key,
+            &facilitator_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+
+        let facilitator_stats = facilitator_ingestor
+            .generate_validation_share()
+            .expect("facilitator failed to generate validation");
+        assert_eq!(facilitator_stats.packets, 10);
+        assert_eq!(
+            facilitator_stats.bytes_read,
+            stored_bytes(&facilitator_ingest_transport)
+        );
+        assert_eq!(
+            facilitator_stats.bytes_written,
+            stored_bytes(&facilitator_validate_transport) - pha_stats.bytes_written
+        );
+
+        let mut batch_path = validation_tempdir.path().to_path_buf();
+        if let Some(instance_name) = instance_name {
+            batch_path.push(instance_name);
+        }
+        batch_path.push(format!(
+            "{}/{}/{}",
+            aggregation_name,
+            date,
+            batch_uuid.to_hyphenated()
+        ));
+        for label in &["validity_pha", "validity_facilitator"] {
+            for suffix in &["", ".avro", ".sig"] {
+                let path = batch_path.with_extension(format!("{}{}", label, suffix));
+                assert!(path.exists(), "missing validation file {}", path.display());
+            }
+        }
+
+        // The two validation batches can be aggregated together
+        let batch = BatchIdentity::new(
+            AggregationName::new(&aggregation_name).unwrap(),
+            date,
+            batch_uuid,
+        );
+        let instance_name = instance_name.map(|name| InstanceName::new(name).unwrap());
+        let validation_batch = |server_identity| {
+            batch
+                .own_validation_batch(
+                    &DEFAULT_NAMING_SCHEME,
+                    instance_name.as_ref(),
+                    server_identity,
+                )
+                .into_batch()
+        };
+        let pha_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                validation_batch(ServerIdentity::Pha),
+                &mut pha_validate_transport,
+            );
+        let facilitator_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                validation_batch(ServerIdentity::Facilitator),
+                &mut facilitator_validate_transport,
+            );
+        compare_validation_batches(
+            &pha_validation_batch,
+            &UnparsedPublicKey::new(
+                &ECDSA_P256_SHA256_FIXED,
+                pha_signing_key.public_key().as_ref().to_vec(),
+            ),
+            &facilitator_validation_batch,
+            &default_facilitator_signing_public_key(),
+        )
+        .unwrap();
+        assert_eq!(
+            facilitator_validation_batch
+                .header(&default_facilitator_signing_public_key())
+                .unwrap()
+                .signature_algorithm,
+            Some(SignatureAlgorithm::EcdsaP256Sha256Fixed)
+        );
+    }
+
+    #[test]
+    fn empty_batch() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch_uuid = Uuid::new_v4();
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample_from_parts(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch_uuid,
+            &aggregation_name,
+            &date,
+            0,
+        );
+
+        let mut pha_ingestor = BatchIntaker::from_parts(
+            None,
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+
+        // Empty batches are rejected by default, without writing anything.
+        let err = pha_ingestor
+            .generate_validation_share()
+            .expect_err("empty batch should be rejected");
+        match err.downcast_ref::<Error>() {
+            Some(Error::EmptyBatchError(_)) => (),
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(
+            std::fs::read_dir(validation_tempdir.path())
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // If allowed, an empty but well formed validation batch is written.
+        pha_ingestor.set_allow_empty_batches(true);
+        pha_ingestor
+            .generate_validation_share()
+            .expect("failed to generate empty validation");
+
+        let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                Batch::new_validation(
+                    &AggregationName::new(&aggregation_name).unwrap(),
+                    &batch_uuid,
+                    &date,
+                    ServerIdentity::Pha,
+                ),
+                &mut validate_transport,
+            );
+        let pha_signing_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+        let validation_header = validation_batch
+            .header(&pha_signing_public_key)
+            .expect("failed to read validation header");
+        let mut validation_packet_reader = validation_batch
+            .packet_file_reader(&validation_header)
+            .expect("failed to read validation packets");
+        match ValidationPacket::read(&mut validation_packet_reader) {
+            Err(Error::EofError) => (),
+            v => panic!("expected no validation packets, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn empty_batch_both_servers() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut facilitator_validate_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let facilitator_ecies_key =
+            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+        let pha_signing_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+        let facilitator_signing_key = default_facilitator_signing_private_key();
+
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &pha_ecies_key,
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            0,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+
+        for (server_identity, ingest_transport, validate_transport, ecies_key, signing_key) in [
+            (
+                ServerIdentity::Pha,
+                &mut pha_ingest_transport,
+                &mut pha_validate_transport,
+                &pha_ecies_key,
+                &pha_signing_key,
+            ),
+            (
+                ServerIdentity::Facilitator,
+                &mut facilitator_ingest_transport,
+                &mut facilitator_validate_transport,
+                &facilitator_ecies_key,
+                &facilitator_signing_key,
+            ),
+        ] {
+            let mut intaker = BatchIntaker::new(
+                None,
+                &batch,
+                ingest_transport,
+                validate_transport,
+                server_identity,
+                ecies_key,
+                signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            intaker.set_allow_empty_batches(true);
+            let stats = intaker.generate_validation_share().unwrap();
+            assert_eq!(stats.packets, 0);
+            assert!(stats.packet_failures.is_empty());
+        }
+
+        // Each validation batch has a signed header declaring no packets and
+        // a packet file that is an Avro container holding only the schema.
+        let validation_batch = |server_identity| {
+            batch
+                .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, server_identity)
+                .into_batch()
+        };
+        let pha_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                validation_batch(ServerIdentity::Pha),
+                &mut pha_validate_transport,
+            );
+        let facilitator_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                validation_batch(ServerIdentity::Facilitator),
+                &mut facilitator_validate_transport,
+            );
+        for (reader, public_key) in &[
+            (&pha_validation_batch, &pha_signing_public_key),
+            (
+                &facilitator_validation_batch,
+                &default_facilitator_signing_public_key(),
+            ),
+        ] {
+            let header = reader.header(public_key).unwrap();
+            assert_eq!(header.packet_count, Some(0));
+            let mut packet_reader = reader.sharded_packet_reader(&header).unwrap();
+            assert!(matches!(packet_reader.read_packet(), Err(Error::EofError)));
+            let packet_file_reader = reader.packet_file_reader(&header).unwrap();
+            assert_eq!(
+                packet_file_reader.writer_schema(),
+                &ValidationPacket::schema()
+            );
+        }
+        compare_validation_batches(
+            &pha_validation_batch,
+            &pha_signing_public_key,
+            &facilitator_validation_batch,
+            &default_facilitator_signing_public_key(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn wrong_number_of_servers() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch_uuid = Uuid::new_v4();
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample_from_parts(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch_uuid,
+            &aggregation_name,
+            &date,
+            10,
+        );
+
+        // Replace the ingestion header with a validly signed one that claims a
+        // different number of servers.
+        let ingestion_batch = Batch::new_ingestion(
+            &AggregationName::new(&aggregation_name).unwrap(),
+            &batch_uuid,
+            &date,
+        );
+        let mut header = BatchReader::<'_, IngestionHeader, IngestionDataSharePacket>::new(
+            Batch::new_ingestion(
+                &AggregationName::new(&aggregation_name).unwrap(),
+                &batch_uuid,
+                &date,
+            ),
+            &mut pha_ingest_transport,
+        )
+        .header(&ingestor_pub_key)
+        .unwrap();
+        header.number_of_servers = 3;
+        let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(ingestion_batch, &mut pha_ingest_transport);
+        let signature = ingestion_writer
+            .put_header(&header, &default_ingestor_private_key())
+            .unwrap();
+        ingestion_writer.put_signature(&signature).unwrap();
+
+        let mut pha_ingestor = BatchIntaker::from_parts(
+            None,
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+
+        let err = pha_ingestor
+            .generate_validation_share()
+            .expect_err("unexpected number of servers should be rejected");
+        match err.downcast_ref::<Error>() {
+            Some(Error::MalformedHeaderError(_)) => (),
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(
+            std::fs::read_dir(validation_tempdir.path())
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // The batch is accepted if that is the configured number of servers.
+        pha_ingestor.set_expected_number_of_servers(3);
+        pha_ingestor
+            .generate_validation_share()
+            .expect("failed to generate validation");
+    }
+
+    #[test]
+    fn malformed_header_parameters() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let pha_pub_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+        let original_header_bytes =
+            BatchReader::<'_, IngestionHeader, IngestionDataSharePacket>::new(
+                batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                &mut pha_ingest_transport,
+            )
+            .verified_header(&ingestor_pub_key)
+            .unwrap()
+            .header_bytes;
+
+        type Corruption = fn(&mut IngestionHeader);
+        let corruptions: &[(Corruption, Option<&str>)] = &[
+            (|header| header.bins = 0, Some("bins/dimension value 0")),
+            (|header| header.prime = 7, Some("prime is 7")),
+            // A prime, but not one libprio has a field for
+            (
+                |header| header.prime = (1 << 61) - 1,
+                Some("prime is 2305843009213693951 but libprio only supports [4293918721]"),
+            ),
+            (
+                |header| header.number_of_servers = 3,
+                Some("number_of_servers is 3"),
+            ),
+            (|header| header.epsilon = 0.0, Some("epsilon is 0")),
+            (|header| header.epsilon = -1.0, Some("epsilon is -1")),
+            (|header| header.epsilon = f64::NAN, Some("epsilon is NaN")),
+            (
+                |header| header.epsilon = f64::INFINITY,
+                Some("epsilon is inf but must be finite and greater than zero"),
+            ),
+            (
+                |header| header.epsilon = f64::NEG_INFINITY,
+                Some("epsilon is -inf"),
+            ),
+            (
+                |header| header.epsilon = DEFAULT_MAX_EPSILON * 2.0,
+                Some("epsilon is 200 but must be at most 100"),
+            ),
+            (|header| header.epsilon = DEFAULT_MAX_EPSILON, None),
+            (
+                |header| header.hamming_weight = Some(0),
+                Some("hamming_weight is 0 but must be between 1 and bins (10)"),
+            ),
+            (
+                |header| header.hamming_weight = Some(-1),
+                Some("hamming_weight is -1 but must be between 1 and bins (10)"),
+            ),
+            (
+                |header| header.hamming_weight = Some(11),
+                Some("hamming_weight is 11 but must be between 1 and bins (10)"),
+            ),
+            // Checked against the bins the header declares
+            (
+                |header| {
+                    header.bins = 4;
+                    header.hamming_weight = Some(5);
+                },
+                Some("hamming_weight is 5 but must be between 1 and bins (4)"),
+            ),
+            // The hamming weight may be as high as the number of bins
+            (|header| header.hamming_weight = Some(10), None),
+            (|header| header.hamming_weight = Some(1), None),
+            (|header| header.hamming_weight = None, None),
+        ];
+        for (corrupt, expected_error) in corruptions {
+            // Replace the ingestion header with a validly signed, corrupted one
+            let mut header =
+                <IngestionHeader as Header>::read(original_header_bytes.as_slice()).unwrap();
+            corrupt(&mut header);
+            let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(
+                    batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                    &mut pha_ingest_transport,
+                );
+            let signature = ingestion_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            ingestion_writer.put_signature(&signature).unwrap();
+
+            let mut validate_transport = MemoryTransport::new();
+            let result = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap()
+            .generate_validation_share();
+            let expected_error = match expected_error {
+                Some(expected_error) => expected_error,
+                None => {
+                    result.unwrap();
+                    // The hamming weight, or its absence, carries over
+                    let validation_header =
+                        BatchReader::<'_, ValidationHeader, ValidationPacket>::new(
+                            batch.validation_batch(
+                                &DEFAULT_NAMING_SCHEME,
+                                None,
+                                ServerIdentity::Pha,
+                            ),
+                            &mut validate_transport,
+                        )
+                        .header(&pha_pub_key)
+                        .unwrap();
+                    assert_eq!(validation_header.hamming_weight, header.hamming_weight);
+                    continue;
+                }
+            };
+            match result.unwrap_err().downcast_ref::<Error>() {
+                Some(Error::MalformedHeaderError(message)) => assert!(
+                    message.contains(expected_error),
+                    "error {:?} does not mention {:?}",
+                    message,
+                    expected_error
+                ),
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert!(validate_transport.list("").unwrap().is_empty());
+        }
+
+        // The last header in the list is valid, but its epsilon may still
+        // exceed a lower configured ceiling
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_max_epsilon(0.1);
+        match pha_ingestor
+            .generate_validation_share()
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::MalformedHeaderError(message)) => {
+                assert_eq!(message, "epsilon is 0.11 but must be at most 0.1")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        pha_ingestor.set_max_epsilon(0.11);
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.epsilon, 0.11);
+
+        // or fall short of a configured floor
+        pha_ingestor.set_overwrite(true);
+        pha_ingestor.set_min_epsilon(Some(0.12));
+        match pha_ingestor
+            .generate_validation_share()
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::MalformedHeaderError(message)) => {
+                assert_eq!(message, "epsilon is 0.11 but must be at least 0.12")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        pha_ingestor.set_min_epsilon(Some(0.11));
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.epsilon, 0.11);
+
+        // A configured hamming weight must match the one the header declares
+        let weights: &[(Option<i32>, Option<&str>)] = &[
+            (Some(3), None),
+            (Some(2), Some("hamming_weight is 2 but expected 3")),
+            (None, Some("hamming_weight is not set but expected 3")),
+            (
+                Some(11),
+                Some("hamming_weight is 11 but must be between 1 and bins (10)"),
+            ),
+        ];
+        for (hamming_weight, expected_error) in weights {
+            let mut header =
+                <IngestionHeader as Header>::read(original_header_bytes.as_slice()).unwrap();
+            header.hamming_weight = *hamming_weight;
+            let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(
+                    batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                    &mut pha_ingest_transport,
+                );
+            let signature = ingestion_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            ingestion_writer.put_signature(&signature).unwrap();
+
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_expected_hamming_weight(Some(3));
+            let result = pha_ingestor.generate_validation_share();
+            match expected_error {
+                None => {
+                    result.unwrap();
+                }
+                Some(expected_error) => match result.unwrap_err().downcast_ref::<Error>() {
+                    Some(Error::MalformedHeaderError(message)) => {
+                        assert_eq!(message, expected_error)
+                    }
+                    e => panic!("unexpected error {:?}", e),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut validate_transport = MemoryTransport::new();
+        let mut archive_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        type Misconfiguration = for<'a> fn(BatchIntakerBuilder<'a>) -> BatchIntakerBuilder<'a>;
+        let misconfigurations: &[(Misconfiguration, &str)] = &[
+            (|builder| builder, "no ingestion transport"),
+            (
+                |builder| builder.expected_number_of_servers(0),
+                "expected number of servers is 0",
+            ),
+            (
+                |builder| builder.expected_hamming_weight(Some(0)),
+                "expected hamming weight is 0",
+            ),
+            (|builder| builder.max_epsilon(0.0), "maximum epsilon is 0"),
+            (
+                |builder| builder.max_epsilon(f64::INFINITY),
+                "maximum epsilon is inf",
+            ),
+            (
+                |builder| builder.min_epsilon(Some(0.0)),
+                "minimum epsilon is 0",
+            ),
+            (
+                |builder| builder.max_epsilon(1.0).min_epsilon(Some(2.0)),
+                "minimum epsilon is 2 but must be greater than zero and at most the maximum \
+                epsilon 1",
+            ),
+            (
+                |builder| builder.read_buffer_size(0),
+                "read buffer size is 0",
+            ),
+            (
+                |builder| builder.copy_buffer_size(0),
+                "copy buffer size is 0",
+            ),
+            (
+                |builder| builder.progress_interval(0),
+                "progress interval is 0",
+            ),
+            (
+                |builder| {
+                    builder.packet_failure_policy(PacketFailurePolicy::Record {
+                        max_failure_fraction: 1.5,
+                    })
+                },
+                "max packet failure fraction 1.5",
+            ),
+            (
+                |builder| {
+                    builder.packet_failure_policy(PacketFailurePolicy::Record {
+                        max_failure_fraction: f64::NAN,
+                    })
+                },
+                "max packet failure fraction NaN",
+            ),
+            (
+                |builder| builder.archive_failures_fatal(false),
+                "without an archive transport",
+            ),
+        ];
+        for (misconfigure, expected_error) in misconfigurations {
+            let mut ingestion_transport = MemoryTransport::new();
+            let mut validation_transport = MemoryTransport::new();
+            let mut builder = misconfigure(BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            ));
+            // Provide the transports unless their absence is under test
+            if *expected_error != "no ingestion transport" {
+                builder = builder
+                    .ingestion_transport(&mut ingestion_transport)
+                    .validation_transport(&mut validation_transport);
+            }
+            match builder.build().err().unwrap().downcast_ref::<Error>() {
+                Some(Error::MalformedConfigError(message)) => assert!(
+                    message.contains(expected_error),
+                    "error {:?} does not mention {:?}",
+                    message,
+                    expected_error
+                ),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+
+        let mut ingestion_transport = MemoryTransport::new();
+        match BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut ingestion_transport)
+        .build()
+        .err()
+        .unwrap()
+        .downcast_ref::<Error>()
+        {
+            Some(Error::MalformedConfigError(message)) => {
+                assert_eq!(message, "no validation transport provided")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let mut ingestion_transport = MemoryTransport::new();
+        let mut validation_transport = MemoryTransport::new();
+        match BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut ingestion_transport)
+        .validation_transport(&mut validation_transport)
+        .instance_name("not/valid")
+        .build()
+        .err()
+        .unwrap()
+        .downcast_ref::<Error>()
+        {
+            Some(Error::IllegalNameError(_)) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let mut batch_intaker = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut validate_transport)
+        .archive_transport(&mut archive_transport)
+        .archive_failures_fatal(false)
+        .worker_threads(Some(1))
+        .write_manifest(true)
+        .validation_attempt(1)
+        .build()
+        .unwrap();
+        assert_eq!(
+            batch_intaker.generate_validation_share().unwrap().packets,
+            10
+        );
+        let summary = batch_intaker.self_verify_validation_batch().unwrap();
+        assert_eq!(summary.manifest.unwrap().packet_count, 10);
+        assert!(batch_intaker.archive_errors().is_empty());
+        drop(batch_intaker);
+        assert!(!archive_transport.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn declared_packet_count() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+        let original_header_bytes =
+            BatchReader::<'_, IngestionHeader, IngestionDataSharePacket>::new(
+                batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                &mut pha_ingest_transport,
+            )
+            .verified_header(&ingestor_pub_key)
+            .unwrap()
+            .header_bytes;
+
+        for declared_packet_count in &[Some(10), Some(11), Some(9), None] {
+            // Replace the ingestion header with a validly signed one declaring
+            // the packet count under test.
+            let mut header =
+                <IngestionHeader as Header>::read(original_header_bytes.as_slice()).unwrap();
+            assert_eq!(header.packet_count, Some(10));
+            header.packet_count = *declared_packet_count;
+            let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(
+                    batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                    &mut pha_ingest_transport,
+                );
+            let signature = ingestion_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            ingestion_writer.put_signature(&signature).unwrap();
+
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            let result = pha_ingestor.generate_validation_share();
+            match declared_packet_count {
+                Some(10) | None => {
+                    assert_eq!(result.unwrap().packets, 10);
+                    // Our own count is declared whether or not the ingestor's was
+                    let summary = pha_ingestor.self_verify_validation_batch().unwrap();
+                    assert_eq!(summary.header.packet_count, Some(10));
+                }
+                Some(declared_packet_count) => {
+                    match result.unwrap_err().downcast_ref::<Error>() {
+                        Some(Error::PacketCountMismatch(declared, decoded)) => {
+                            assert_eq!(declared, declared_packet_count);
+                            assert_eq!(*decoded, 10);
+                        }
+                        e => panic!("unexpected error {:?}", e),
+                    }
+                    assert!(validate_transport.list("").unwrap().is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn preallocated_uuid_capacity() {
+        assert_eq!(preallocated_uuids(None), 0);
+        assert_eq!(preallocated_uuids(Some(1000)), 1000);
+        assert_eq!(preallocated_uuids(Some(u64::MAX)), MAX_PREALLOCATED_UUIDS);
+
+        let seen_uuids: HashSet<Uuid> = HashSet::with_capacity(preallocated_uuids(Some(u64::MAX)));
+        assert!(seen_uuids.capacity() >= MAX_PREALLOCATED_UUIDS);
+    }
+
+    #[test]
+    fn prime_fields() {
+        let field = PrimeField::from_prime(MODULUS as i64).unwrap();
+        assert_eq!(field, PrimeField::Default);
+        assert_eq!(field.prime(), 4293918721);
+        assert_eq!(field.element(0).unwrap(), Field::from(0));
+        assert_eq!(
+            field.element(MODULUS as i64 - 1).unwrap(),
+            Field::from(MODULUS - 1)
+        );
+        // Values past the prime are reduced, as libprio does
+        assert_eq!(field.element(MODULUS as i64).unwrap(), Field::from(0));
+        assert_eq!(
+            field.element(u32::MAX as i64).unwrap(),
+            Field::from(u32::MAX - MODULUS)
+        );
+        for value in [-1, u32::MAX as i64 + 1] {
+            field.element(value).unwrap_err();
+        }
+
+        for prime in [0, 7, -(MODULUS as i64), (1 << 61) - 1] {
+            match PrimeField::from_prime(prime) {
+                Err(Error::MalformedHeaderError(message)) => assert_eq!(
+                    message,
+                    format!("prime is {} but libprio only supports [4293918721]", prime)
+                ),
+                v => panic!("unexpected result {:?} for prime {}", v, prime),
+            }
+        }
+    }
+
+    #[test]
+    fn batch_identity_mismatch() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let original_batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            date,
+            Uuid::new_v4(),
+        );
+        let renamed_batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            date,
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &original_batch,
+            10,
+        );
+
+        // Move the still validly signed batch under a different batch ID.
+        let original_keys = original_batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        let renamed_keys = renamed_batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        for (kind, original_key) in original_keys.keys() {
+            let mut content = Vec::new();
+            pha_ingest_transport
+                .get(original_key)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            let mut writer = pha_ingest_transport.put(renamed_keys.key(kind)).unwrap();
+            writer.write_all(&content).unwrap();
+            writer.complete_upload().unwrap();
+        }
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &renamed_batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+
+        let err = pha_ingestor
+            .generate_validation_share()
+            .expect_err("renamed batch should be rejected");
+        match err.downcast_ref::<Error>() {
+            Some(Error::BatchIdentityMismatch(path_uuid, header_uuid)) => {
+                assert_eq!(*path_uuid, renamed_batch.batch_id);
+                assert_eq!(*header_uuid, original_batch.batch_id);
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(
+            std::fs::read_dir(validation_tempdir.path())
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn swapped_packet_files() {
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batches = [
+            BatchIdentity::new(
+                AggregationName::new("fake-aggregation-1").unwrap(),
+                date,
+                Uuid::new_v4(),
+            ),
+            BatchIdentity::new(
+                AggregationName::new("fake-aggregation-1").unwrap(),
+                date,
+                Uuid::new_v4(),
+            ),
+        ];
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        let mut packet_files = Vec::new();
+        for batch in &batches {
+            generate_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                batch,
+                10,
+            );
+
+            let mut content = Vec::new();
+            pha_ingest_transport
+                .get(
+                    batch
+                        .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                        .key(BatchFileKind::Packets),
+                )
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            packet_files.push(content);
+        }
+
+        // Each batch's validly signed header now sits next to the other
+        // batch's equally valid packet file.
+        for (batch, packet_file) in batches.iter().zip(packet_files.iter().rev()) {
+            let mut writer = pha_ingest_transport
+                .put(
+                    batch
+                        .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                        .key(BatchFileKind::Packets),
+                )
+                .unwrap();
+            writer.write_all(packet_file).unwrap();
+            writer.complete_upload().unwrap();
+        }
+
+        for batch in &batches {
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            let err = pha_ingestor
+                .generate_validation_share()
+                .expect_err("swapped packet file should be rejected");
+            match err.downcast_ref::<Error>() {
+                Some(Error::PacketFileDigestMismatch(_)) => (),
+                _ => panic!("unexpected error {:?}", err),
+            }
+            assert!(validate_transport.list("").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn sharded_packet_file() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        // Read back the sample and rewrite it as two packet file shards.
+        let ingestion_reader = BatchReader::<'_, IngestionHeader, IngestionDataSharePacket>::new(
+            batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+            &mut pha_ingest_transport,
+        );
+        let mut header = ingestion_reader.header(&ingestor_pub_key).unwrap();
+        let mut packet_reader = ingestion_reader.packet_file_reader(&header).unwrap();
+        let mut packets = Vec::new();
+        loop {
+            match IngestionDataSharePacket::read(&mut packet_reader) {
+                Ok(packet) => packets.push(packet),
+                Err(Error::EofError) => break,
+                Err(e) => panic!("failed to read packet: {:?}", e),
+            }
+        }
+        assert_eq!(packets.len(), 10);
+
+        let sharded_batch = batch
+            .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+            .with_packet_file_shards(2);
+        assert_eq!(sharded_batch.packet_file_shard_keys().len(), 2);
+        let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(sharded_batch, &mut pha_ingest_transport);
+        let shard_digests = packets
+            .chunks(6)
+            .enumerate()
+            .map(|(index, shard)| {
+                ingestion_writer
+                    .packet_file_shard_writer(index, |writer| {
+                        for packet in shard {
+                            packet.write(writer)?;
+                        }
+                        Ok(())
+                    })
+                    .unwrap()
+                    .as_ref()
+                    .to_vec()
+            })
+            .collect();
+        header.packet_file_digest = vec![];
+        header.packet_file_shard_digests = shard_digests;
+        let signature = ingestion_writer
+            .put_header(&header, &default_ingestor_private_key())
+            .unwrap();
+        ingestion_writer.put_signature(&signature).unwrap();
+
+        // Clobber the original packet file to make sure it isn't used.
+        let mut writer = pha_ingest_transport
+            .put(
+                batch
+                    .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                    .key(BatchFileKind::Packets),
+            )
+            .unwrap();
+        writer.write_all(b"not a packet file").unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor
+            .generate_validation_share()
+            .expect("failed to generate validation");
+
+        // The validation batch is a single packet file covering both shards.
+        let validation_reader = BatchReader::<'_, ValidationHeader, ValidationPacket>::new(
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha),
+            &mut validate_transport,
+        );
+        let validation_header = validation_reader
+            .header(&UnparsedPublicKey::new(
+                &ECDSA_P256_SHA256_FIXED,
+                pha_signing_key.public_key().as_ref().to_vec(),
+            ))
+            .unwrap();
+        let mut validation_packet_reader = validation_reader
+            .packet_file_reader(&validation_header)
+            .unwrap();
+        for packet in &packets {
+            let validation_packet = ValidationPacket::read(&mut validation_packet_reader).unwrap();
+            assert_eq!(validation_packet.uuid, packet.uuid);
+        }
+        match ValidationPacket::read(&mut validation_packet_reader) {
+            Err(Error::EofError) => (),
+            v => panic!("unexpected result reading past the last packet: {:?}", v),
+        }
+
+        // Shards are verified against the header just like a single packet
+        // file.
+        let ingestion_reader = BatchReader::<'_, IngestionHeader, IngestionDataSharePacket>::new(
+            batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+            &mut pha_ingest_transport,
+        );
+        header.packet_file_shard_digests[1] = vec![0u8; 32];
+        let mut packet_reader = ingestion_reader.sharded_packet_reader(&header).unwrap();
+        for _ in 0..6 {
+            packet_reader.read_packet().unwrap();
+        }
+        match packet_reader.read_packet() {
+            Err(Error::AnyhowError(_)) => (),
+            v => panic!("unexpected result reading corrupt shard: {:?}", v),
+        }
+        assert!(
+            ingestion_reader.packet_file_reader(&header).is_err(),
+            "sharded header should be rejected by packet_file_reader"
+        );
+    }
+
+    #[test]
+    fn worker_threads() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let pha_pub_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+
+        // Enough packets that the batch is validated in several chunks.
+        let packet_count = PACKETS_PER_WORKER * 2 + 7;
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            packet_count,
+        );
+
+        let mut validate = |worker_threads| {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut validate_transport =
+                LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_worker_threads(worker_threads);
+            pha_ingestor
+                .generate_validation_share()
+                .expect("failed to generate validation");
+
+            let validation_batch =
+                batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+            let mut packet_file = Vec::new();
+            validate_transport
+                .get(validation_batch.key(BatchFileKind::Packets))
+                .unwrap()
+                .read_to_end(&mut packet_file)
+                .unwrap();
+            let validation_reader = BatchReader::<'_, ValidationHeader, ValidationPacket>::new(
+                validation_batch,
+                &mut validate_transport,
+            );
+            let validation_header = validation_reader.header(&pha_pub_key).unwrap();
+            let mut packet_reader = validation_reader
+                .packet_file_reader(&validation_header)
+                .unwrap();
+            let mut packets = Vec::new();
+            loop {
+                match ValidationPacket::read(&mut packet_reader) {
+                    Ok(packet) => packets.push(packet),
+                    Err(Error::EofError) => break,
+                    Err(e) => panic!("failed to read packet: {:?}", e),
+                }
+            }
+            (without_avro_sync_markers(&packet_file), packets)
+        };
+
+        let (serial_packet_file, serial_packets) = validate(Some(1));
+        assert_eq!(serial_packets.len(), packet_count);
+        // More workers than there are chunks leaves some of them idle.
+        for worker_threads in &[Some(2), Some(3), Some(8), None] {
+            let (packet_file, packets) = validate(*worker_threads);
+            assert_eq!(
+                packets, serial_packets,
+                "worker_threads = {:?}",
+                worker_threads
+            );
+            assert!(
+                packet_file == serial_packet_file,
+                "packet files differ with worker_threads = {:?}",
+                worker_threads
+            );
+        }
+    }
+
+    /// Returns the blocks of the provided Avro object container file with
+    /// every occurrence of its sync marker zeroed. avro-rs picks a random sync
+    /// marker for each file and writes the header's metadata in no particular
+    /// order, so this is what two files holding the same packets written the
+    /// same way have in common.
+    fn without_avro_sync_markers(file: &[u8]) -> Vec<u8> {
+        // The file ends with the sync marker that follows its last block, and
+        // the header ends with its first occurrence.
+        let sync_marker = &file[file.len() - 16..];
+        let header_length = file
+            .windows(sync_marker.len())
+            .position(|window| window == sync_marker)
+            .unwrap()
+            + sync_marker.len();
+        let mut blocks = file[header_length..].to_vec();
+        let mut index = 0;
+        while index + sync_marker.len() <= blocks.len() {
+            if &blocks[index..index + sync_marker.len()] == sync_marker {
+                for byte in &mut blocks[index..index + sync_marker.len()] {
+                    *byte = 0;
+                }
+                index += sync_marker.len();
+            } else {
+                index += 1;
+            }
+        }
+        blocks
+    }
+
+    /// A transport on which every operation fails.
+    struct FailingTransport;
+
+    impl Transport for FailingTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            Err(anyhow!("failed to get {}", key))
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            Err(anyhow!("failed to put {}", key))
+        }
+    }
+
+    /// A transport whose nth put fails, counting from one.
+    struct FailingNthPutTransport {
+        transport: MemoryTransport,
+        puts: usize,
+        failing_put: usize,
+    }
+
+    impl Transport for FailingNthPutTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            self.transport.get(key)
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            self.puts += 1;
+            if self.puts == self.failing_put {
+                return Err(anyhow!("failed to put {}", key));
+            }
+            self.transport.put(key)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.transport.list(prefix)
+        }
+
+        fn delete(&mut self, key: &str) -> Result<()> {
+            self.transport.delete(key)
+        }
+    }
+
+    #[test]
+    fn roll_back_partial_validation_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        // The packet file and header are written before the signature put
+        // fails.
+        let mut validate_transport = FailingNthPutTransport {
+            transport: MemoryTransport::new(),
+            puts: 0,
+            failing_put: 3,
+        };
+        let err = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap()
+        .generate_validation_share()
+        .unwrap_err();
+
+        let validation_batch =
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+        assert_eq!(
+            err.root_cause().to_string(),
+            format!(
+                "failed to put {}",
+                validation_batch.key(BatchFileKind::Signature)
+            )
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "deleted partially written {}, {}",
+                validation_batch.key(BatchFileKind::Header),
+                validation_batch.key(BatchFileKind::Packets)
+            )
+        );
+        assert_eq!(validate_transport.puts, 3);
+        assert!(validate_transport.transport.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn intake_timings() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            1000,
+        );
+
+        let mut validate_transport = MemoryTransport::new();
+        let mut batch_intaker = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        let stats = batch_intaker.generate_validation_share().unwrap();
+        let timings = stats.timings();
+        assert_eq!(timings, batch_intaker.last_timings());
+        assert!(!timings.download_duration.is_zero());
+        assert!(!timings.verification_duration.is_zero());
+        assert!(!timings.packet_loop_duration.is_zero());
+        assert!(!timings.sign_duration.is_zero());
+        assert!(!timings.upload_duration.is_zero());
+        // Validating a thousand packets takes longer than signing one header
+        assert!(timings.packet_loop_duration > timings.sign_duration);
+        assert!(timings
+            .to_string()
+            .starts_with("intake_timings download_ms="));
+
+        // A run that fails while writing the signature has timings for every
+        // phase up to and including signing.
+        let mut validate_transport = FailingNthPutTransport {
+            transport: MemoryTransport::new(),
+            puts: 0,
+            failing_put: 3,
+        };
+        let mut batch_intaker = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        batch_intaker.generate_validation_share().unwrap_err();
+        let timings = batch_intaker.last_timings();
+        assert!(!timings.download_duration.is_zero());
+        assert!(!timings.verification_duration.is_zero());
+        assert!(!timings.packet_loop_duration.is_zero());
+        assert!(!timings.sign_duration.is_zero());
+        assert!(!timings.upload_duration.is_zero());
+
+        // One that fails to fetch the ingestion header gets no further.
+        let mut ingest_transport = FailingTransport;
+        let mut validate_transport = MemoryTransport::new();
+        let mut batch_intaker = BatchIntaker::new(
+            None,
+            &batch,
+            &mut ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        batch_intaker.generate_validation_share().unwrap_err();
+        let timings = batch_intaker.last_timings();
+        assert!(timings.packet_loop_duration.is_zero());
+        assert!(timings.sign_duration.is_zero());
+        assert!(timings.upload_duration.is_zero());
+    }
+
+    #[test]
+    fn cancel_intake() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            5000,
+        );
+
+        for worker_threads in &[1, 2] {
+            // Another thread sets the flag once the first few hundred packets
+            // are validated, while the progress callback waits for it.
+            let cancelled = std::sync::Arc::new(AtomicBool::new(false));
+            let (request_sender, request_receiver) = mpsc::channel::<()>();
+            let (done_sender, done_receiver) = mpsc::channel();
+            let done_receiver = std::sync::Mutex::new(done_receiver);
+            let canceller = {
+                let cancelled = cancelled.clone();
+                std::thread::spawn(move || {
+                    if request_receiver.recv().is_ok() {
+                        cancelled.store(true, Ordering::Relaxed);
+                        done_sender.send(()).unwrap();
+                    }
+                })
+            };
+            let mut packets_validated = 0;
+            let mut progress_callback = |progress: &IntakeProgress| {
+                if progress.phase == IntakePhase::ValidatingPackets && packets_validated == 0 {
+                    packets_validated = progress.packets;
+                    request_sender.send(()).unwrap();
+                    done_receiver.lock().unwrap().recv().unwrap();
+                }
+            };
+
+            let mut validate_transport = MemoryTransport::new();
+            let mut batch_intaker = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            batch_intaker.set_worker_threads(Some(*worker_threads));
+            batch_intaker.set_progress_interval(256);
+            batch_intaker.set_progress_callback(&mut progress_callback);
+            batch_intaker.set_cancellation(&cancelled);
+            let err = batch_intaker.generate_validation_share().unwrap_err();
+            drop(batch_intaker);
+            drop(request_sender);
+            canceller.join().unwrap();
+
+            assert!(
+                matches!(err.downcast_ref(), Some(Error::Cancelled)),
+                "{:?}",
+                err
+            );
+            assert!(packets_validated > 0 && packets_validated < 5000);
+            assert!(validate_transport.list("").unwrap().is_empty());
+        }
+    }
+
+    /// A MemoryTransport that passes every value it gets through a hook, as if
+    /// the stored object had been altered after it was written.
+    struct HookedTransport {
+        transport: MemoryTransport,
+        hook: fn(&str, &mut Vec<u8>),
+    }
+
+    impl Transport for HookedTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            let mut value = Vec::new();
+            self.transport.get(key)?.read_to_end(&mut value)?;
+            (self.hook)(key, &mut value);
+            Ok(Box::new(std::io::Cursor::new(value)))
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            self.transport.put(key)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.transport.list(prefix)
+        }
+
+        fn delete(&mut self, key: &str) -> Result<()> {
+            self.transport.delete(key)
+        }
+    }
+
+    #[test]
+    fn verify_after_write() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        type Case<'c> = (fn(&str, &mut Vec<u8>), bool, Option<&'c str>);
+        let cases: &[Case] = &[
+            (|_, _| {}, true, None),
+            // Without the check, corruption goes unnoticed
+            (|_, value| value.clear(), false, None),
+            (
+                |key, value| {
+                    if !key.ends_with(".avro") && !key.ends_with(".sig") {
+                        *value.last_mut().unwrap() ^= 1;
+                    }
+                },
+                true,
+                Some("invalid signature on header"),
+            ),
+            (
+                |key, value| {
+                    if key.ends_with(".sig") {
+                        value.truncate(value.len() - 1);
+                    }
+                },
+                true,
+                Some("invalid signature on header"),
+            ),
+            (
+                |key, value| {
+                    if key.ends_with(".avro") {
+                        *value.last_mut().unwrap() ^= 1;
+                    }
+                },
+                true,
+                Some("does not match header"),
+            ),
+        ];
+        for (hook, verify_after_write, expected_error) in cases {
+            let mut validate_transport = HookedTransport {
+                transport: MemoryTransport::new(),
+                hook: *hook,
+            };
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_verify_after_write(*verify_after_write);
+            let result = pha_ingestor.generate_validation_share();
+            match expected_error {
+                None => assert_eq!(result.unwrap().packets, 10),
+                Some(expected_error) => match result.unwrap_err().downcast_ref::<Error>() {
+                    Some(Error::PostWriteVerificationFailed(message)) => assert!(
+                        message.contains(expected_error),
+                        "error {:?} does not mention {:?}",
+                        message,
+                        expected_error
+                    ),
+                    e => panic!("unexpected error {:?}", e),
+                },
+            }
+        }
+    }
+
+    /// A transport on which the first get or put of each key in flaky_keys
+    /// fails with a transient error partway through transferring the value.
+    /// Clones share their values and flaky keys.
+    #[derive(Clone, Default)]
+    struct FlakyTransport {
+        transport: MemoryTransport,
+        flaky_keys: std::sync::Arc<Mutex<HashSet<String>>>,
+    }
+
+    impl FlakyTransport {
+        fn new(transport: MemoryTransport) -> FlakyTransport {
+            FlakyTransport {
+                transport,
+                flaky_keys: Default::default(),
+            }
+        }
+
+        fn fail_once(&self, key: &str) {
+            self.flaky_keys.lock().unwrap().insert(key.to_owned());
+        }
+
+        /// Returns true if the key is flaky, which it no longer is afterward.
+        fn take_flaky(&self, key: &str) -> bool {
+            self.flaky_keys.lock().unwrap().remove(key)
+        }
+    }
+
+    fn connection_reset() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::ConnectionReset, "fake connection reset")
+    }
+
+    /// Yields half of a value and then fails.
+    struct FlakyReader(std::io::Cursor<Vec<u8>>);
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+            match self.0.read(buf)? {
+                0 => Err(connection_reset()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    /// Fails its second write, or its completion if there is no second write.
+    struct FlakyWriter {
+        writer: Box<dyn TransportWriter>,
+        flaky: bool,
+        writes: usize,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+            self.writes += 1;
+            if self.flaky && self.writes == 2 {
+                self.flaky = false;
+                return Err(connection_reset());
+            }
+            self.writer.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            self.writer.flush()
+        }
+    }
+
+    impl TransportWriter for FlakyWriter {
+        fn complete_upload(&mut self) -> Result<()> {
+            if self.flaky {
+                return Err(connection_reset()).context("failed to complete fake upload");
+            }
+            self.writer.complete_upload()
+        }
+
+        fn cancel_upload(&mut self) -> Result<()> {
+            self.writer.cancel_upload()
+        }
+    }
+
+    impl Transport for FlakyTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            let mut reader = self.transport.get(key)?;
+            if !self.take_flaky(key) {
+                return Ok(reader);
+            }
+            let mut value = Vec::new();
+            reader.read_to_end(&mut value)?;
+            value.truncate(value.len() / 2);
+            Ok(Box::new(FlakyReader(std::io::Cursor::new(value))))
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            Ok(Box::new(FlakyWriter {
+                writer: self.transport.put(key)?,
+                flaky: self.take_flaky(key),
+                writes: 0,
+            }))
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.transport.list(prefix)
+        }
+
+        fn delete(&mut self, key: &str) -> Result<()> {
+            self.transport.delete(key)
+        }
+
+        fn size(&self, key: &str) -> Result<Option<u64>> {
+            self.transport.size(key)
+        }
+    }
+
+    #[test]
+    fn transport_retries() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let ingestion_batch = batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        let validation_batch =
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+        let mut flaky_keys = Vec::new();
+        for batch in &[&ingestion_batch, &validation_batch] {
+            for kind in &[
+                BatchFileKind::Signature,
+                BatchFileKind::Header,
+                BatchFileKind::Packets,
+            ] {
+                flaky_keys.push(batch.key(*kind).to_owned());
+            }
+        }
+
+        let retry_once = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: std::time::Duration::from_secs(0),
+        };
+        for flaky_key in &flaky_keys {
+            for retry_policy in &[retry_once, RetryPolicy::NONE] {
+                let mut ingest_transport = FlakyTransport::new(pha_ingest_transport.clone());
+                let mut validate_transport = FlakyTransport::default();
+                ingest_transport.fail_once(flaky_key);
+                validate_transport.fail_once(flaky_key);
+
+                // The validation batch is checked after it is written, so a
+                // retried put that left behind anything from the attempt
+                // that failed would be caught.
+                let result = BatchIntakerBuilder::new(
+                    &batch,
+                    ServerIdentity::Pha,
+                    &pha_ecies_key,
+                    &pha_signing_key,
+                    &ingestor_pub_key,
+                )
+                .ingestion_transport(&mut ingest_transport)
+                .validation_transport(&mut validate_transport)
+                .verify_after_write(true)
+                .transport_retry_policy(*retry_policy)
+                .build()
+                .unwrap()
+                .generate_validation_share();
+                if retry_policy.retries() {
+                    assert_eq!(
+                        result
+                            .unwrap_or_else(|e| panic!("{}: {:?}", flaky_key, e))
+                            .packets,
+                        10
+                    );
+                    assert_eq!(
+                        validate_transport.transport.list("").unwrap().len(),
+                        3,
+                        "{}",
+                        flaky_key
+                    );
+                } else {
+                    let error = result.unwrap_err();
+                    assert!(is_transient(&error), "{}: {:?}", flaky_key, error);
+                    assert!(
+                        validate_transport.transport.list("").unwrap().is_empty(),
+                        "{}",
+                        flaky_key
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn archive_transport() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+        let mut archive_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        let clock = MockClock::new(NaiveDate::from_ymd(2020, 10, 14).and_hms(15, 30, 0));
+        pha_ingestor.set_clock(&clock);
+        pha_ingestor.set_archive_transport(&mut archive_transport);
+        pha_ingestor
+            .generate_validation_share()
+            .expect("failed to generate validation");
+        assert!(pha_ingestor.archive_errors().is_empty());
+
+        let ingestion_batch = batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        let archived_keys = archive_transport.list("").unwrap();
+        assert_eq!(archived_keys.len(), 3, "archived keys: {:?}", archived_keys);
+        for (kind, key) in ingestion_batch.keys() {
+            let archive_key = format!("20201014T153000Z/{}", key);
+            assert!(
+                archived_keys.contains(&archive_key),
+                "{:?} file {} not archived as {}",
+                kind,
+                key,
+                archive_key
+            );
+
+            let mut source = Vec::new();
+            pha_ingest_transport
+                .get(key)
+                .unwrap()
+                .read_to_end(&mut source)
+                .unwrap();
+            let mut archived = Vec::new();
+            archive_transport
+                .get(&archive_key)
+                .unwrap()
+                .read_to_end(&mut archived)
+                .unwrap();
+            assert_eq!(archived, source, "archived {:?} file differs", kind);
+        }
+    }
+
+    #[test]
+    fn archive_failures() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        for fatal in &[true, false] {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut validate_transport =
+                LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+            let mut archive_transport = FailingTransport;
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_archive_transport(&mut archive_transport);
+            pha_ingestor.set_archive_failures_fatal(*fatal);
+            let result = pha_ingestor.generate_validation_share();
+
+            if *fatal {
+                assert!(result.is_err(), "archive failure was ignored");
+                assert!(pha_ingestor.archive_errors().is_empty());
+                // Nothing should have been validated.
+                assert_eq!(
+                    std::fs::read_dir(validation_tempdir.path())
+                        .unwrap()
+                        .count(),
+                    0
+                );
+            } else {
+                assert!(result.is_ok(), "best-effort archive failed: {:?}", result);
+                assert_eq!(pha_ingestor.archive_errors().len(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn server_pool() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        let batches: Vec<BatchIdentity> = (0..3)
+            .map(|_| {
+                BatchIdentity::new(
+                    AggregationName::new("fake-aggregation-1").unwrap(),
+                    BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        for batch in &batches {
+            generate_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                batch,
+                10,
+            );
+        }
+
+        let server_pool = ServerPool::new(true, pha_ecies_key.clone());
+        let wrong_server_pool = ServerPool::new(false, pha_ecies_key.clone());
+        for batch in &batches {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut validate_transport =
+                LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_worker_threads(Some(2));
+
+            pha_ingestor.set_server_pool(&wrong_server_pool);
+            assert!(pha_ingestor.generate_validation_share().is_err());
+
+            pha_ingestor.set_server_pool(&server_pool);
+            pha_ingestor
+                .generate_validation_share()
+                .expect("failed to generate validation");
+            // The servers went back into the pool to be reused for the next
+            // batch.
+            assert_eq!(server_pool.idle_servers(10), 2);
+        }
+        assert_eq!(wrong_server_pool.idle_servers(10), 0);
+    }
+
+    #[test]
+    fn validation_attempts() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let pha_pub_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        for attempt in 0..2 {
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_validation_attempt(attempt);
+            pha_ingestor
+                .generate_validation_share()
+                .expect("failed to generate validation");
+        }
+
+        // Both attempts exist side by side, each complete and valid.
+        let descriptor = batch.descriptor(BatchKind::Validation(ServerIdentity::Pha));
+        assert_eq!(
+            DEFAULT_NAMING_SCHEME
+                .enumerate_attempts(&validate_transport, None, &descriptor)
+                .unwrap(),
+            vec![0, 1]
+        );
+        let attempts: Vec<Batch> = (0..2)
+            .map(|attempt| {
+                descriptor
+                    .clone()
+                    .with_attempt(attempt)
+                    .batch(&DEFAULT_NAMING_SCHEME, None)
+            })
+            .collect();
+        for ((_, key), (_, other_key)) in attempts[0].keys().zip(attempts[1].keys()) {
+            assert_ne!(key, other_key);
+        }
+        for (attempt, validation_batch) in attempts.into_iter().enumerate() {
+            assert_eq!(
+                Batch::from_header_key(validation_batch.key(BatchFileKind::Header))
+                    .unwrap()
+                    .attempt,
+                attempt as u32
+            );
+            let validation_reader = BatchReader::<'_, ValidationHeader, ValidationPacket>::new(
+                validation_batch,
+                &mut validate_transport,
+            );
+            let validation_header = validation_reader.header(&pha_pub_key).unwrap();
+            assert_eq!(validation_header.batch_uuid, batch.batch_id);
+            validation_reader
+                .packet_file_reader(&validation_header)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn batch_date_window() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+
+        let date = NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 5, 0);
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&date),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let window = BatchDateWindow::new(Duration::minutes(10), Duration::days(7));
+        // Time moves forward across the window.
+        let clock = MockClock::new(date - Duration::minutes(11));
+        for (advance, accepted) in &[
+            // The batch is dated further in the future than the window allows...
+            (Duration::zero(), false),
+            // ... or as far as it allows.
+            (Duration::minutes(1), true),
+            // The batch is as old as the window allows...
+            (Duration::days(7) + Duration::minutes(10), true),
+            // ... or older.
+            (Duration::minutes(1), false),
+        ] {
+            clock.advance(*advance);
+            let now = clock.now();
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut validate_transport =
+                LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_batch_date_window(Some(window));
+            pha_ingestor.set_clock(&clock);
+            let result = pha_ingestor.generate_validation_share();
+
+            if *accepted {
+                assert!(result.is_ok(), "now = {}: {:?}", now, result);
+            } else {
+                match result.unwrap_err().downcast_ref::<Error>() {
+                    Some(Error::BatchDateOutsideWindow(d, earliest, latest)) => {
+                        assert_eq!(*d, batch.date);
+                        assert_eq!(*earliest, now - Duration::days(7));
+                        assert_eq!(*latest, now + Duration::minutes(10));
+                    }
+                    e => panic!("now = {}: unexpected error {:?}", now, e),
+                }
+                assert_eq!(
+                    std::fs::read_dir(validation_tempdir.path())
+                        .unwrap()
+                        .count(),
+                    0
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_packet_file_size() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+        let ingestion_batch = batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        let packet_file_key = ingestion_batch.key(BatchFileKind::Packets).to_owned();
+        let packet_file_size = pha_ingest_transport
+            .size(&packet_file_key)
+            .unwrap()
+            .unwrap();
+
+        for (max_packet_file_size, ok) in &[
+            (None, true),
+            (Some(packet_file_size), true),
+            (Some(packet_file_size - 1), false),
+        ] {
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_max_packet_file_size(*max_packet_file_size);
+            let result = pha_ingestor.generate_validation_share();
+            if *ok {
+                result.unwrap();
+                continue;
+            }
+            match result.unwrap_err().downcast_ref::<Error>() {
+                Some(Error::PacketFileTooLarge(key, size, max)) => {
+                    assert_eq!(key, &packet_file_key);
+                    assert_eq!(*size, packet_file_size);
+                    assert_eq!(*max, packet_file_size - 1);
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            // Nothing was written for the refused batch
+            assert!(validate_transport.list("").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn probable_key_mismatch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let facilitator_ecies_key =
+            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+
+        // The PHA's shares are encrypted to the facilitator's key, as if the
+        // ingestor had been configured with the wrong one.
+        let packet_count = 3 * PACKETS_PER_WORKER;
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &facilitator_ecies_key,
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            packet_count,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+
+        for worker_threads in &[1, 2] {
+            // Even though every packet may fail, the batch is abandoned as
+            // soon as the limit has seen enough of them.
+            let mut validate_transport = MemoryTransport::new();
+            let mut packets_validated = 0;
+            let mut progress_callback = |progress: &IntakeProgress| {
+                if progress.phase == IntakePhase::ValidatingPackets {
+                    packets_validated = progress.packets;
+                }
+            };
+            let mut pha_ingestor = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut pha_ingest_transport)
+            .validation_transport(&mut validate_transport)
+            .worker_threads(Some(*worker_threads))
+            .packet_failure_policy(PacketFailurePolicy::Record {
+                max_failure_fraction: 1.0,
+            })
+            .progress_interval(1)
+            .progress_callback(&mut progress_callback)
+            .build()
+            .unwrap();
+            let err = pha_ingestor.generate_validation_share().unwrap_err();
+            drop(pha_ingestor);
+            match err.downcast_ref::<Error>() {
+                Some(Error::ProbableKeyMismatch(failures, packets, max_fraction)) => {
+                    assert_eq!(*failures, PACKETS_PER_WORKER as u64);
+                    assert_eq!(*packets, PACKETS_PER_WORKER as u64);
+                    assert_eq!(*max_fraction, DEFAULT_DECRYPTION_FAILURE_LIMIT.max_fraction);
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert!(packets_validated < packet_count as u64);
+            assert!(validate_transport.list("").unwrap().is_empty());
+        }
+
+        // Without the limit, the failures are recorded like any others.
+        let mut validate_transport = MemoryTransport::new();
+        let stats = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut validate_transport)
+        .packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 1.0,
+        })
+        .decryption_failure_limit(None)
+        .build()
+        .unwrap()
+        .generate_validation_share()
+        .unwrap();
+        assert_eq!(stats.packets, packet_count as u64);
+        assert_eq!(stats.decryption_failures, packet_count as u64);
+        assert_eq!(stats.structural_failures, 0);
+        assert!(stats.packet_failures[0]
+            .reason
+            .starts_with("failed to decrypt packet payload"));
+
+        // Batches too small for the limit are checked once they end.
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &facilitator_ecies_key,
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+        let err = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut MemoryTransport::new())
+        .packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 1.0,
+        })
+        .build()
+        .unwrap()
+        .generate_validation_share()
+        .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(Error::ProbableKeyMismatch(10, 10, _))
+            ),
+            "{:?}",
+            err
+        );
+
+        // The limit's fraction must make sense.
+        assert!(BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut MemoryTransport::new())
+        .decryption_failure_limit(Some(DecryptionFailureLimit {
+            min_packets: 100,
+            max_fraction: 1.5,
+        }))
+        .build()
+        .is_err());
+    }
+
+    #[test]
+    fn record_packet_failures() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        let bad_packet_uuids = generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+            &[
+                (2, PacketCorruption::IllegalRPit),
+                (7, PacketCorruption::TruncatedPayload),
+            ],
+        );
+        assert_eq!(bad_packet_uuids.len(), 2);
+
+        // By default, a single bad packet fails the whole batch.
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        let err = pha_ingestor.generate_validation_share().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(&bad_packet_uuids[0].to_string()),
+            "error does not name the bad packet: {:#}",
+            err
+        );
+        assert!(validate_transport.list("").unwrap().is_empty());
+
+        // Too many failures for the tolerated fraction
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 0.1,
+        });
+        match pha_ingestor
+            .generate_validation_share()
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::TooManyPacketFailures(2, 10, _)) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(validate_transport.list("").unwrap().is_empty());
+
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 0.2,
+        });
+        pha_ingestor.set_write_manifest(true);
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.packets, 10);
+        assert_eq!(
+            stats
+                .packet_failures
+                .iter()
+                .map(|failure| failure.uuid)
+                .collect::<Vec<_>>(),
+            bad_packet_uuids
+        );
+        assert_eq!(stats.decryption_failures, 1);
+        assert_eq!(stats.structural_failures, 1);
+        let summary = pha_ingestor.self_verify_validation_batch().unwrap();
+        assert_eq!(summary.packets, 8);
+
+        let validation_batch =
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+        let mut failures_json = Vec::new();
+        validate_transport
+            .get(&validation_batch.packet_failures_key())
+            .unwrap()
+            .read_to_end(&mut failures_json)
+            .unwrap();
+        let recorded_failures: Vec<PacketFailure> = serde_json::from_slice(&failures_json).unwrap();
+        assert_eq!(recorded_failures, stats.packet_failures);
+    }
+
+    #[test]
+    fn sequence_number_gap() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        // The header counts the packets that remain, so only the sequence
+        // numbers reveal the record dropped from the middle of the file.
+        let bad_packet_uuids = generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            20,
+            &[(6, PacketCorruption::DroppedRecord)],
+        );
+        assert_eq!(bad_packet_uuids.len(), 1);
+
+        let mut validate_transport = MemoryTransport::new();
+        let err = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap()
+        .generate_validation_share()
+        .unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::SequenceNumberMismatch(uuid, 6, 7)) if *uuid == bad_packet_uuids[0] => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(validate_transport.list("").unwrap().is_empty());
+
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 0.1,
+        });
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.packets, 19);
+        assert_eq!(
+            stats.packet_failures,
+            vec![PacketFailure {
+                uuid: bad_packet_uuids[0],
+                reason: "sequence number 7 where 6 was expected".to_owned(),
+            }]
+        );
+        assert_eq!(
+            pha_ingestor.self_verify_validation_batch().unwrap().packets,
+            18
+        );
+    }
+
+    #[test]
+    fn packet_admission() {
+        let packet = |sequence_number| IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![],
+            encryption_key_id: "fake-key-1".to_owned(),
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            sequence_number,
+        };
+        let policy = PacketFailurePolicy::Record {
+            max_failure_fraction: 1.0,
+        };
+
+        // Sequence numbers are optional, and count packets without them.
+        let mut admission = PacketAdmission::new(None);
+        let mut chunk = PacketChunk::new(0);
+        for sequence_number in &[
+            None,
+            Some(1),
+            None,
+            Some(3),
+            Some(3),
+            Some(4),
+            Some(6),
+            Some(7),
+        ] {
+            admission
+                .admit(packet(*sequence_number), policy, &mut chunk)
+                .unwrap();
+        }
+        assert_eq!(
+            chunk
+                .packets
+                .iter()
+                .map(|packet| packet.sequence_number)
+                .collect::<Vec<_>>(),
+            vec![None, Some(1), None, Some(3), Some(4), Some(7)]
+        );
+        assert_eq!(
+            chunk
+                .rejected
+                .iter()
+                .map(|failure| failure.reason.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "sequence number 3 where 4 was expected",
+                "sequence number 6 where 5 was expected",
+            ]
+        );
+
+        let mut admission = PacketAdmission::new(None);
+        let mut chunk = PacketChunk::new(0);
+        match admission.admit(packet(Some(1)), PacketFailurePolicy::Abort, &mut chunk) {
+            Err(e) => assert!(matches!(
+                e.downcast_ref::<Error>(),
+                Some(Error::SequenceNumberMismatch(_, 0, 1))
+            )),
+            Ok(()) => panic!("sequence number gap accepted"),
+        }
+    }
+
+    #[test]
+    fn spool_oversized_batches() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        let bad_packet_uuids = generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            40,
+            &[(13, PacketCorruption::IllegalRPit)],
+        );
+
+        // The packet file and the failures sidecar are each many times larger
+        // than a 64 byte threshold, so both spill to disk, and the outcome
+        // must be the same as when both stay in memory.
+        let mut results = Vec::new();
+        for spool_threshold in &[DEFAULT_SPOOL_THRESHOLD, 64] {
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut pha_ingest_transport)
+            .validation_transport(&mut validate_transport)
+            .packet_failure_policy(PacketFailurePolicy::Record {
+                max_failure_fraction: 0.1,
+            })
+            .write_manifest(true)
+            .spool_threshold(*spool_threshold)
+            .build()
+            .unwrap();
+            let stats = pha_ingestor.generate_validation_share().unwrap();
+            let summary = pha_ingestor.self_verify_validation_batch().unwrap();
+            assert_eq!(summary.packets, 39);
+            assert_eq!(
+                stats
+                    .packet_failures
+                    .iter()
+                    .map(|failure| failure.uuid)
+                    .collect::<Vec<_>>(),
+                bad_packet_uuids
+            );
+            results.push((stats.packets, stats.packet_failures));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn refuse_replayed_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        // Deliver the same signed batch again, an hour later.
+        let replayed = BatchIdentity::new(
+            batch.aggregation_name.clone(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890 + 3600, 0)),
+            batch.batch_id,
+        );
+        for kind in &[
+            BatchFileKind::Header,
+            BatchFileKind::Packets,
+            BatchFileKind::Signature,
+        ] {
+            let mut content = Vec::new();
+            pha_ingest_transport
+                .get(
+                    batch
+                        .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                        .key(*kind),
+                )
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            let mut writer = pha_ingest_transport
+                .put(
+                    replayed
+                        .ingestion_batch(&DEFAULT_NAMING_SCHEME, None)
+                        .key(*kind),
+                )
+                .unwrap();
+            writer.write_all(&content).unwrap();
+            writer.complete_upload().unwrap();
+        }
+
+        let store = MemoryProcessedBatchStore::new();
+        let mut validate_transport = MemoryTransport::new();
+        let intake_validate_transport = validate_transport.clone();
+        let mut intake =
+            |batch: &BatchIdentity, store: Option<&dyn ProcessedBatchStore>, overwrite: bool| {
+                let mut builder = BatchIntakerBuilder::new(
+                    batch,
+                    ServerIdentity::Pha,
+                    &pha_ecies_key,
+                    &pha_signing_key,
+                    &ingestor_pub_key,
+                )
+                .ingestion_transport(&mut pha_ingest_transport)
+                .owned_validation_transport(Box::new(intake_validate_transport.clone()))
+                .overwrite(overwrite);
+                if let Some(store) = store {
+                    builder = builder.processed_batch_store(store);
+                }
+                builder.build().unwrap().generate_validation_share()
+            };
+
+        // Without a store, nothing stops the replay from being validated.
+        assert_eq!(intake(&replayed, None, false).unwrap().packets, 10);
+        let written = validate_transport.list("").unwrap();
+        for key in written {
+            validate_transport.delete(&key).unwrap();
+        }
+
+        assert_eq!(intake(&batch, Some(&store), false).unwrap().packets, 10);
+        assert_eq!(
+            store
+                .lookup(&batch.aggregation_name, &batch.batch_id)
+                .unwrap(),
+            Some(batch.date)
+        );
+        let written = validate_transport.list("").unwrap();
+
+        match intake(&replayed, Some(&store), false)
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::ReplayDetected(batch_id, recorded_date, date)) => {
+                assert_eq!(*batch_id, batch.batch_id);
+                assert_eq!(*recorded_date, batch.date);
+                assert_eq!(*date, replayed.date);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(validate_transport.list("").unwrap(), written);
+
+        // Redoing the batch under its own date is not a replay.
+        assert_eq!(intake(&batch, Some(&store), true).unwrap().packets, 10);
+    }
+
+    #[test]
+    fn sort_validation_packets() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let pha_signing_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+        generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            300,
+            &[
+                (3, PacketCorruption::IllegalRPit),
+                (5, PacketCorruption::DuplicateUuid),
+            ],
+        );
+
+        let mut outputs = Vec::new();
+        for output_ordering in &[OutputOrdering::PreserveInput, OutputOrdering::SortByUuid] {
+            let mut validate_transport = MemoryTransport::new();
+            let stats = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut pha_ingest_transport)
+            .validation_transport(&mut validate_transport)
+            .worker_threads(Some(3))
+            .packet_failure_policy(PacketFailurePolicy::Record {
+                max_failure_fraction: 0.1,
+            })
+            .output_ordering(*output_ordering)
+            .verify_after_write(true)
+            .build()
+            .unwrap()
+            .generate_validation_share()
+            .unwrap();
+            assert_eq!(stats.packet_failures.len(), 2);
+
+            let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    batch
+                        .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+                        .into_batch(),
+                    &mut validate_transport,
+                );
+            let header = validation_batch.header(&pha_signing_public_key).unwrap();
+            let mut packet_reader = validation_batch.packet_file_reader(&header).unwrap();
+            let mut packets = Vec::new();
+            loop {
+                match ValidationPacket::read(&mut packet_reader) {
+                    Ok(packet) => packets.push(packet),
+                    Err(Error::EofError) => break,
+                    Err(e) => panic!("failed to read validation packet: {:?}", e),
+                }
+            }
+            assert_eq!(header.packet_count, Some(packets.len() as u64));
+            outputs.push((stats, packets));
+        }
+
+        let (sorted_stats, sorted) = outputs.pop().unwrap();
+        let (preserved_stats, mut preserved) = outputs.pop().unwrap();
+        assert_eq!(preserved_stats.sort_buffer_bytes, 0);
+        assert!(
+            sorted_stats.sort_buffer_bytes
+                >= (sorted.len() * std::mem::size_of::<ValidationPacket>()) as u64
+        );
+        assert_eq!(
+            preserved_stats.packet_failures,
+            sorted_stats.packet_failures
+        );
+        assert_eq!(preserved.len(), 298);
+        assert!(preserved.windows(2).any(|pair| pair[0].uuid > pair[1].uuid));
+        assert!(sorted.windows(2).all(|pair| pair[0].uuid < pair[1].uuid));
+        preserved.sort_by_key(|packet| packet.uuid);
+        assert_eq!(preserved, sorted);
+    }
+
+    #[test]
+    fn verify_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let validate_transport = MemoryTransport::new();
+        let archive_transport = MemoryTransport::new();
+        let mut intake_validate_transport = validate_transport.clone();
+        let mut intake_archive_transport = archive_transport.clone();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        let bad_packet_uuids = generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            12,
+            &[(4, PacketCorruption::IllegalRPit)],
+        );
+
+        let mut pha_ingestor = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut intake_validate_transport)
+        .archive_transport(&mut intake_archive_transport)
+        .write_manifest(true)
+        .packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 0.5,
+        })
+        .build()
+        .unwrap();
+        let verify_stats = pha_ingestor.verify_batch().unwrap();
+        assert_eq!(verify_stats.packets, 12);
+        assert_eq!(verify_stats.bytes_written, 0);
+        assert_eq!(
+            verify_stats
+                .packet_failures
+                .iter()
+                .map(|failure| failure.uuid)
+                .collect::<Vec<_>>(),
+            bad_packet_uuids
+        );
+        assert!(validate_transport.list("").unwrap().is_empty());
+        assert!(archive_transport.list("").unwrap().is_empty());
+
+        // A real run over the same batch finds the same packets
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.packets, verify_stats.packets);
+        assert_eq!(stats.packet_failures, verify_stats.packet_failures);
+        assert_ne!(stats.bytes_written, 0);
+        assert_eq!(
+            pha_ingestor.self_verify_validation_batch().unwrap().packets,
+            11
+        );
+    }
+
+    #[test]
+    fn already_processed() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut pha_validate_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let validation_batch = batch
+            .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+            .into_batch();
+        let read_validation_batch = |transport: &MemoryTransport| -> Vec<Vec<u8>> {
+            BatchFileKind::ALL
+                .iter()
+                .map(|kind| {
+                    let mut content = Vec::new();
+                    transport
+                        .get(validation_batch.key(*kind))
+                        .unwrap()
+                        .read_to_end(&mut content)
+                        .unwrap();
+                    content
+                })
+                .collect()
+        };
+
+        let mut ingestion_reads = Vec::new();
+        let mut validation_batches = Vec::new();
+        for overwrite in [false, false, true] {
+            let mut ingestion_transport = MeteredTransport::new(pha_ingest_transport.clone());
+            let ingestion_meter = ingestion_transport.meter();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut ingestion_transport,
+                &mut pha_validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_overwrite(overwrite);
+            pha_ingestor.set_rng(&FixedByteRandom { byte: 0 });
+            let result = pha_ingestor.generate_validation_share();
+            drop(pha_ingestor);
+            ingestion_reads.push((result, ingestion_meter.metrics().bytes_read));
+            validation_batches.push(read_validation_batch(&pha_validate_transport));
+        }
+
+        // The first run does the work, the second finds it done without
+        // fetching anything from the ingestion batch and the third is told to
+        // do it again regardless.
+        assert!(ingestion_reads[0].0.is_ok());
+        assert_ne!(ingestion_reads[0].1, 0);
+        match ingestion_reads[1].0.as_ref().unwrap_err().downcast_ref() {
+            Some(Error::AlreadyProcessed(_)) => (),
+            v => panic!("unexpected result {:?}", v),
+        }
+        assert_eq!(ingestion_reads[1].1, 0);
+        assert!(ingestion_reads[2].0.is_ok());
+        assert_eq!(ingestion_reads[2].1, ingestion_reads[0].1);
+        assert_eq!(validation_batches[0], validation_batches[1]);
+
+        // A validation batch whose signature doesn't verify was not finished
+        let mut writer = pha_validate_transport
+            .put(validation_batch.key(BatchFileKind::Signature))
+            .unwrap();
+        writer.write_all(b"not a signature").unwrap();
+        writer.complete_upload().unwrap();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut pha_validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.generate_validation_share().unwrap();
+        drop(pha_ingestor);
+        assert_ne!(
+            read_validation_batch(&pha_validate_transport)[2],
+            b"not a signature"
+        );
+    }
+
+    #[test]
+    fn done_marker() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut pha_validate_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let validation_batch = batch
+            .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+            .into_batch();
+        let read_all = |transport: &MemoryTransport| -> Vec<(String, Vec<u8>)> {
+            let mut keys = transport.list("").unwrap();
+            keys.sort();
+            keys.into_iter()
+                .map(|key| {
+                    let mut content = Vec::new();
+                    transport
+                        .get(&key)
+                        .unwrap()
+                        .read_to_end(&mut content)
+                        .unwrap();
+                    (key, content)
+                })
+                .collect()
+        };
+
+        let mut runs = Vec::new();
+        for overwrite in [false, false, true] {
+            let mut ingestion_transport = MeteredTransport::new(pha_ingest_transport.clone());
+            let ingestion_meter = ingestion_transport.meter();
+            let result = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut ingestion_transport)
+            .validation_transport(&mut pha_validate_transport)
+            .write_done_marker(true)
+            .overwrite(overwrite)
+            .rng(&FixedByteRandom { byte: 0 })
+            .build()
+            .unwrap()
+            .generate_validation_share();
+            runs.push((
+                result,
+                ingestion_meter.metrics().bytes_read,
+                read_all(&pha_validate_transport),
+            ));
+        }
+
+        // The first run writes the validation batch and then the marker, which
+        // is counted among the bytes written.
+        let first_stats = runs[0].0.as_ref().unwrap();
+        assert_eq!(
+            first_stats.bytes_written,
+            stored_bytes(&pha_validate_transport)
+        );
+        // The marker records the digest of the validation packet file, not
+        // the ingestion packet file it was computed from.
+        let marker = DoneMarker::get(&pha_validate_transport, &validation_batch).unwrap();
+        let mut packet_file = Vec::new();
+        pha_validate_transport
+            .get(validation_batch.key(BatchFileKind::Packets))
+            .unwrap()
+            .read_to_end(&mut packet_file)
+            .unwrap();
+        assert_eq!(
+            marker,
+            DoneMarker::new(batch.batch_id, 10, hash::sha256(&packet_file).as_ref())
+        );
+
+        // The second run finds the marker and does nothing at all, and the
+        // third is told to do the work again regardless.
+        match runs[1].0.as_ref().unwrap_err().downcast_ref() {
+            Some(Error::AlreadyProcessed(_)) => (),
+            v => panic!("unexpected result {:?}", v),
+        }
+        assert_eq!(runs[1].1, 0);
+        assert_eq!(runs[1].2, runs[0].2);
+        assert_eq!(runs[2].0.as_ref().unwrap().packets, 10);
+        assert_ne!(runs[2].1, 0);
+
+        // The marker alone is enough to skip the batch, even if the validation
+        // batch's signature no longer verifies with the share processor's key,
+        // e.g. because the key was rotated.
+        let mut writer = pha_validate_transport
+            .put(validation_batch.key(BatchFileKind::Signature))
+            .unwrap();
+        writer.write_all(b"not a signature").unwrap();
+        writer.complete_upload().unwrap();
+        let result = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut MemoryTransport::new())
+        .validation_transport(&mut pha_validate_transport)
+        .write_done_marker(true)
+        .build()
+        .unwrap()
+        .generate_validation_share();
+        match result.unwrap_err().downcast_ref() {
+            Some(Error::AlreadyProcessed(_)) => (),
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+
+    #[test]
+    fn progress_reporting() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10_000,
+        );
+
+        // With one worker thread, packets are validated 256 at a time, so
+        // every multiple of the interval is crossed by a separate group.
+        for (interval, expected_validating_reports) in [(1_000, 10), (DEFAULT_PROGRESS_INTERVAL, 1)]
+        {
+            let mut pha_validate_transport = MemoryTransport::new();
+            let mut reports = Vec::new();
+            let mut record_progress = |progress: &IntakeProgress| reports.push(progress.clone());
+            let stats = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .ingestion_transport(&mut pha_ingest_transport)
+            .validation_transport(&mut pha_validate_transport)
+            .worker_threads(Some(1))
+            .progress_interval(interval)
+            .progress_callback(&mut record_progress)
+            .build()
+            .unwrap()
+            .generate_validation_share()
+            .unwrap();
+            assert_eq!(stats.packets, 10_000);
+
+            let count = |phase| reports.iter().filter(|r| r.phase == phase).count();
+            assert_eq!(count(IntakePhase::HeaderVerified), 1);
+            assert_eq!(count(IntakePhase::PacketFilesDownloaded), 1);
+            assert_eq!(count(IntakePhase::Signed), 1);
+            assert_eq!(
+                count(IntakePhase::ValidatingPackets),
+                expected_validating_reports,
+                "interval {}",
+                interval
+            );
+            assert_eq!(reports.first().unwrap().phase, IntakePhase::HeaderVerified);
+            assert_eq!(reports.first().unwrap().packets, 0);
+            let last = reports.last().unwrap();
+            assert_eq!(last.phase, IntakePhase::Signed);
+            assert_eq!(last.packets, 10_000);
+            for pair in reports.windows(2) {
+                assert!(pair[0].packets <= pair[1].packets, "{:?}", pair);
+                assert!(pair[0].elapsed <= pair[1].elapsed, "{:?}", pair);
+            }
+            for report in &reports {
+                assert_eq!(report.declared_packets, Some(10_000));
+            }
+        }
+
+        // A callback that panics fails the batch without writing it
+        let mut pha_validate_transport = MemoryTransport::new();
+        let mut panic_on_packets = |progress: &IntakeProgress| {
+            if progress.phase == IntakePhase::ValidatingPackets {
+                panic!("progress callback failure");
+            }
+        };
+        let err = BatchIntakerBuilder::new(
+            &batch,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .ingestion_transport(&mut pha_ingest_transport)
+        .validation_transport(&mut pha_validate_transport)
+        .progress_interval(1)
+        .progress_callback(&mut panic_on_packets)
+        .build()
+        .unwrap()
+        .generate_validation_share()
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("progress callback panicked"),
+            "{:?}",
+            err
+        );
+        assert!(pha_validate_transport.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicate_packets() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        // Put the duplicate past the first worker's packets so that it is only
+        // caught across chunks.
+        let duplicate_index = PACKETS_PER_WORKER * 2 + 1;
+        let packet_count = duplicate_index + 5;
+        let duplicate_uuids = generate_sample_with_bad_packets(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            packet_count,
+            &[(duplicate_index, PacketCorruption::DuplicateUuid)],
+        );
+        assert_eq!(duplicate_uuids.len(), 1);
+
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        match pha_ingestor
+            .generate_validation_share()
+            .unwrap_err()
+            .downcast_ref::<Error>()
+        {
+            Some(Error::DuplicatePacketError(uuid)) => assert_eq!(*uuid, duplicate_uuids[0]),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(validate_transport.list("").unwrap().is_empty());
+
+        let mut validate_transport = MemoryTransport::new();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_packet_failure_policy(PacketFailurePolicy::Record {
+            max_failure_fraction: 0.1,
+        });
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.packets, packet_count as u64);
+        assert_eq!(
+            stats.packet_failures,
+            vec![PacketFailure {
+                uuid: duplicate_uuids[0],
+                reason: "duplicate packet UUID".to_owned(),
+            }]
+        );
+        assert!(
+            stats.duplicate_check_bytes >= (packet_count - 1) as u64 * 16,
+            "duplicate check bytes {} too small",
+            stats.duplicate_check_bytes
+        );
+        let summary = pha_ingestor.self_verify_validation_batch().unwrap();
+        assert_eq!(summary.packets, packet_count as u64 - 1);
+    }
+
+    #[test]
+    fn fanout_validation_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let peer_transport = MemoryTransport::new();
+        let audit_transport = MemoryTransport::new();
+        let mut validate_transport =
+            FanoutTransport::new(vec![peer_transport.clone(), audit_transport.clone()]).unwrap();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_write_manifest(true);
+        pha_ingestor.generate_validation_share().unwrap();
+        drop(pha_ingestor);
+
+        let keys = peer_transport.list("").unwrap();
+        assert_eq!(keys.len(), 5, "unexpected keys {:?}", keys);
+        assert_eq!(keys, audit_transport.list("").unwrap());
+        for key in &keys {
+            let mut peer_content = Vec::new();
+            peer_transport
+                .get(key)
+                .unwrap()
+                .read_to_end(&mut peer_content)
+                .unwrap();
+            let mut audit_content = Vec::new();
+            audit_transport
+                .get(key)
+                .unwrap()
+                .read_to_end(&mut audit_content)
+                .unwrap();
+            assert_eq!(peer_content, audit_content, "{} differs", key);
+        }
+
+        // The signature verifies over either copy of the header
+        let share_processor_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            Vec::from(pha_signing_key.public_key().as_ref()),
+        );
+        for mut destination in [peer_transport, audit_transport] {
+            let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    batch
+                        .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+                        .into_batch(),
+                    &mut destination,
+                );
+            let header = validation_batch
+                .header(&share_processor_public_key)
+                .unwrap();
+            assert_eq!(header.packet_count, Some(10));
+        }
+    }
+
+    #[test]
+    fn additional_ingestor_keys() {
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        // Stands in for a key the ingestor is rotating to
+        let rotated_ingestor_pub_key = default_facilitator_signing_public_key();
+
+        for (ingestor_key, expected_index) in [
+            (default_ingestor_private_key_raw(), 0),
+            (default_facilitator_signing_private_key_raw(), 1),
+        ] {
+            let batch = BatchIdentity::new(
+                AggregationName::new("fake-aggregation-1").unwrap(),
+                BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+                Uuid::new_v4(),
+            );
+            let mut pha_ingest_transport = MemoryTransport::new();
+            let mut facilitator_ingest_transport = MemoryTransport::new();
+            let mut pha_validate_transport = MemoryTransport::new();
+
+            generate_ingestion_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                None,
+                &batch,
+                &pha_ecies_key,
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                &ingestor_key,
+                10,
+                10,
+                0.11,
+                100,
+                100,
+            )
+            .expect("failed to generate sample");
+
+            // Only the first batch verifies against the primary key alone
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut pha_validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            let result = pha_ingestor.generate_validation_share();
+            assert_eq!(result.is_ok(), expected_index == 0, "{:?}", result);
+            drop(pha_ingestor);
+
+            let mut pha_ingestor = BatchIntakerBuilder::new(
+                &batch,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .additional_ingestor_key(&rotated_ingestor_pub_key)
+            .ingestion_transport(&mut pha_ingest_transport)
+            .validation_transport(&mut pha_validate_transport)
+            // The first batch was already validated above
+            .overwrite(true)
+            .build()
+            .unwrap();
+            let stats = pha_ingestor.generate_validation_share().unwrap();
+            assert_eq!(stats.ingestor_key_index, expected_index);
+        }
+    }
+
+    #[test]
+    fn validate_stream() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        // Lay the ingestion batch out as standalone files, as a pipeline
+        // would hand them over, and map the batch's keys onto them.
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let ingestion_batch = batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None);
+        let validation_batch = batch
+            .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+            .into_batch();
+        let mut ingestion_transport = StreamTransport::new();
+        let mut validation_transport = StreamTransport::new();
+        for (index, kind) in BatchFileKind::ALL.iter().enumerate() {
+            let input_path = tempdir.path().join(format!("input-{}", index));
+            let mut content = Vec::new();
+            pha_ingest_transport
+                .get(ingestion_batch.key(*kind))
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            std::fs::write(&input_path, content).unwrap();
+            ingestion_transport.map(ingestion_batch.key(*kind), Stream::Path(input_path));
+            validation_transport.map(
+                validation_batch.key(*kind),
+                Stream::Path(tempdir.path().join(format!("output-{}", index))),
+            );
+        }
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut ingestion_transport,
+            &mut validation_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        let stats = pha_ingestor.generate_validation_share().unwrap();
+        assert_eq!(stats.packets, 10);
+        let summary = pha_ingestor.self_verify_validation_batch().unwrap();
+        assert_eq!(summary.packets, 10);
+        for index in 0..BatchFileKind::ALL.len() {
+            assert!(tempdir.path().join(format!("output-{}", index)).exists());
+        }
+    }
+
+    #[test]
+    fn self_verify_validation_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut validate_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            17,
+        );
+
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor.set_write_manifest(true);
+        pha_ingestor
+            .generate_validation_share()
+            .expect("failed to generate validation");
+        let summary = pha_ingestor
+            .self_verify_validation_batch()
+            .expect("failed to self-verify validation");
+        assert_eq!(summary.packets, 17);
+        assert_eq!(summary.header.batch_uuid, batch.batch_id);
+        assert_eq!(summary.header.bins, 10);
+        assert_eq!(summary.manifest.unwrap().packet_count, 17);
+
+        // The batch was signed with the PHA's key, not the facilitator's.
+        let facilitator_signing_key = default_facilitator_signing_private_key();
+        let mut wrong_key_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut facilitator_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &facilitator_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        wrong_key_ingestor
+            .self_verify_validation_batch()
+            .expect_err("verified with wrong key");
+
+        // Corrupt the packet file.
+        let validation_batch =
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+        let mut writer = validate_transport
+            .put(validation_batch.key(BatchFileKind::Packets))
+            .unwrap();
+        writer.write_all(b"not a packet file").unwrap();
+        writer.complete_upload().unwrap();
+        let mut pha_ingestor = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
+            &ingestor_pub_key,
+        )
+        .unwrap();
+        pha_ingestor
+            .self_verify_validation_batch()
+            .expect_err("verified corrupt packet file");
+    }
+
+    #[test]
+    fn validation_manifest() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+        let pha_pub_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().as_ref().to_vec(),
+        );
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            17,
+        );
+
+        for write_manifest in &[false, true] {
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_write_manifest(*write_manifest);
+            pha_ingestor
+                .generate_validation_share()
+                .expect("failed to generate validation");
+
+            let validation_batch =
+                batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha);
+            if !*write_manifest {
+                assert!(validate_transport
+                    .get(&validation_batch.manifest_key())
+                    .is_err());
+                continue;
+            }
+
+            let written_keys: Vec<String> = validation_batch
+                .keys()
+                .map(|(_, key)| key.to_owned())
+                .collect();
+            let reader: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(validation_batch, &mut validate_transport);
+            assert!(reader.verified_manifest(&ingestor_pub_key).is_err());
+            let manifest = reader.verified_manifest(&pha_pub_key).unwrap();
+            assert_eq!(manifest.schema_version, SCHEMA_VERSION);
+            assert_eq!(manifest.packet_count, 17);
+
+            let mut manifest_keys: Vec<String> =
+                manifest.files.iter().map(|file| file.key.clone()).collect();
+            manifest_keys.sort();
+            let mut expected_keys = written_keys;
+            expected_keys.sort();
+            assert_eq!(manifest_keys, expected_keys);
+
+            for file in &manifest.files {
+                let mut content = Vec::new();
+                validate_transport
+                    .get(&file.key)
+                    .unwrap()
+                    .read_to_end(&mut content)
+                    .unwrap();
+                assert_eq!(file.size, content.len() as u64, "size of {}", file.key);
+                assert_eq!(file.sha256, hex_digest(&content), "digest of {}", file.key);
+            }
+        }
+    }
+
+    fn hex_digest(content: &[u8]) -> String {
+        ring::digest::digest(&ring::digest::SHA256, content)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn key_length_limit() {
+        let date = "2020/10/14/16/05".parse::<BatchDate>().unwrap();
+        let batch_id = Uuid::new_v4();
+        // Validation packet file keys are the longest in a batch, and are
+        // 72 bytes longer than the aggregation name.
+        let aggregation_name = AggregationName::new(&"a".repeat(MAX_KEY_LENGTH - 72 - 10)).unwrap();
+        let batch = BatchIdentity::new(aggregation_name, date, batch_id);
+        let longest_key = batch
+            .validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+            .key(BatchFileKind::Packets)
+            .to_owned();
+        assert_eq!(longest_key.len(), MAX_KEY_LENGTH - 10);
+
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            &batch,
+            10,
+        );
+
+        // With a 10 byte prefix, the keys are exactly as long as allowed, and
+        // with an 11 byte prefix, one byte too long.
+        for (reserved_prefix_length, accepted) in &[(10, true), (11, false)] {
+            let mut validate_transport = MemoryTransport::new();
+            let mut pha_ingestor = BatchIntaker::new(
+                None,
+                &batch,
+                &mut pha_ingest_transport,
+                &mut validate_transport,
+                ServerIdentity::Pha,
+                &pha_ecies_key,
+                &pha_signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap();
+            pha_ingestor.set_reserved_key_prefix_length(*reserved_prefix_length);
+            let result = pha_ingestor.generate_validation_share();
+
+            if *accepted {
+                assert!(result.is_ok(), "{:?}", result);
+                assert!(validate_transport.get(&longest_key).is_ok());
+            } else {
+                match result.unwrap_err().downcast_ref::<Error>() {
+                    Some(Error::KeyTooLong(key, length, limit)) => {
+                        assert_eq!(*key, longest_key);
+                        assert_eq!(*length, MAX_KEY_LENGTH + 1);
+                        assert_eq!(*limit, MAX_KEY_LENGTH);
+                    }
+                    e => panic!("unexpected error {:?}", e),
+                }
+                assert!(validate_transport.list("").unwrap().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn cross_layout() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch_uuid = Uuid::new_v4();
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        // The sample is written in the flat layout.
+        generate_sample_from_parts(
             &mut pha_ingest_transport,
             &mut facilitator_ingest_transport,
             &batch_uuid,
             &aggregation_name,
             &date,
-            &pha_ecies_key,
-            &facilitator_ecies_key,
-            &default_ingestor_private_key_raw(),
             10,
-            10,
-            0.11,
-            100,
-            100,
-        )
-        .expect("failed to generate sample");
+        );
 
-        let mut pha_ingestor = BatchIntaker::new(
+        let date_partitioned =
+            DefaultBatchNamingScheme::default().with_path_layout(PathLayout::DatePartitioned);
+        let mut pha_ingestor = BatchIntaker::from_parts(
+            None,
             &aggregation_name,
             &batch_uuid,
             &date,
             &mut pha_ingest_transport,
-            &mut pha_validate_transport,
-            true,
+            &mut validate_transport,
+            ServerIdentity::Pha,
             &pha_ecies_key,
             &pha_signing_key,
             &ingestor_pub_key,
         )
         .unwrap();
-
+        pha_ingestor.set_validation_naming_scheme(&date_partitioned);
         pha_ingestor
             .generate_validation_share()
             .expect("PHA failed to generate validation");
 
-        let mut facilitator_ingestor = BatchIntaker::new(
-            &aggregation_name,
+        let validation_batch = Batch::with_naming_scheme(
+            &date_partitioned,
+            None,
+            &AggregationName::new(&aggregation_name).unwrap(),
             &batch_uuid,
             &date,
+            BatchKind::Validation(ServerIdentity::Pha),
+        );
+        for (_, key) in validation_batch.keys() {
+            assert!(
+                validation_tempdir.path().join(key).exists(),
+                "missing validation file {}",
+                key
+            );
+        }
+        assert_eq!(
+            date_partitioned
+                .parse_header_key(None, validation_batch.key(BatchFileKind::Header))
+                .unwrap()
+                .batch_id,
+            batch_uuid
+        );
+
+        // Reading the ingestion batch in the wrong layout fails.
+        pha_ingestor.set_ingestion_naming_scheme(&date_partitioned);
+        assert!(pha_ingestor.generate_validation_share().is_err());
+    }
+
+    #[test]
+    fn custom_ingestion_naming() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch_uuid = Uuid::new_v4();
+        let mut pha_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingest_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validate_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        generate_sample_from_parts(
+            &mut pha_ingest_transport,
             &mut facilitator_ingest_transport,
-            &mut facilitator_validate_transport,
-            false,
-            &facilitator_ecies_key,
-            &facilitator_signing_key,
+            &batch_uuid,
+            &aggregation_name,
+            &date,
+            10,
+        );
+
+        // Move the batch to where an ingestion server that writes headers
+        // without an extension and packet files as ".data" would put it.
+        let partner_naming_scheme = DefaultBatchNamingScheme::default()
+            .with_ingestion_naming(BatchNaming::new("", ".data", ".sig").unwrap());
+        let aggregation_name_parsed = AggregationName::new(&aggregation_name).unwrap();
+        let default_batch = Batch::new_ingestion(&aggregation_name_parsed, &batch_uuid, &date);
+        let partner_batch = Batch::with_naming_scheme(
+            &partner_naming_scheme,
+            None,
+            &aggregation_name_parsed,
+            &batch_uuid,
+            &date,
+            BatchKind::Ingestion,
+        );
+        let pha_ingestion_path = ingestion_tempdir.path().join("pha");
+        for ((kind, from), (_, to)) in default_batch.keys().zip(partner_batch.keys()) {
+            match kind {
+                BatchFileKind::Header => assert!(!to.ends_with(".batch"), "{}", to),
+                BatchFileKind::Packets => assert!(to.ends_with(".data"), "{}", to),
+                BatchFileKind::Signature => assert!(to.ends_with(".sig"), "{}", to),
+            }
+            std::fs::rename(pha_ingestion_path.join(from), pha_ingestion_path.join(to)).unwrap();
+        }
+
+        let mut pha_ingestor = BatchIntaker::from_parts(
+            None,
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &pha_signing_key,
             &ingestor_pub_key,
         )
         .unwrap();
 
-        facilitator_ingestor
+        // The batch can't be found with the default naming.
+        assert!(pha_ingestor.generate_validation_share().is_err());
+
+        pha_ingestor.set_ingestion_naming_scheme(&partner_naming_scheme);
+        pha_ingestor
             .generate_validation_share()
-            .expect("facilitator failed to generate validation");
+            .expect("PHA failed to generate validation");
+
+        // Validation batches are still named the way we choose.
+        let validation_batch = Batch::new_validation(
+            &aggregation_name_parsed,
+            &batch_uuid,
+            &date,
+            ServerIdentity::Pha,
+        );
+        for (_, key) in validation_batch.keys() {
+            assert!(
+                validation_tempdir.path().join(key).exists(),
+                "missing validation file {}",
+                key
+            );
+        }
+
+        assert_eq!(
+            partner_naming_scheme
+                .parse_header_key(None, partner_batch.key(BatchFileKind::Header))
+                .unwrap(),
+            BatchDescriptor::new(
+                aggregation_name_parsed,
+                date,
+                batch_uuid,
+                BatchKind::Ingestion
+            )
+        );
+        assert!(partner_naming_scheme
+            .parse_header_key(None, partner_batch.key(BatchFileKind::Packets))
+            .is_err());
+    }
+
+    #[test]
+    fn owned_transports_on_worker_threads() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let batches: Vec<BatchIdentity> = (0..2)
+            .map(|_| {
+                BatchIdentity::new(
+                    AggregationName::new("fake-aggregation-1").unwrap(),
+                    BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+                    Uuid::new_v4(),
+                )
+            })
+            .collect();
+        // MemoryTransport clones share their contents, so each BatchIntaker can
+        // own a clone while the test inspects the results through the original.
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let validate_transport = MemoryTransport::new();
+        let SampleKeys {
+            pha_ecies_key,
+            pha_signing_key,
+            ingestor_pub_key,
+        } = sample_keys();
+
+        for (index, batch) in batches.iter().enumerate() {
+            generate_sample(
+                &mut pha_ingest_transport,
+                &mut facilitator_ingest_transport,
+                batch,
+                10 + index,
+            );
+        }
+
+        // Build every BatchIntaker before any of them runs, then hand each to
+        // its own thread.
+        let batch_intakers: Vec<BatchIntaker<'_>> = batches
+            .iter()
+            .map(|batch| {
+                BatchIntakerBuilder::new(
+                    batch,
+                    ServerIdentity::Pha,
+                    &pha_ecies_key,
+                    &pha_signing_key,
+                    &ingestor_pub_key,
+                )
+                .owned_ingestion_transport(Box::new(pha_ingest_transport.clone()))
+                .owned_validation_transport(Box::new(validate_transport.clone()))
+                .build()
+                .unwrap()
+            })
+            .collect();
+        assert_send(&batch_intakers[0]);
+
+        let packets: Vec<u64> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch_intakers
+                .into_iter()
+                .map(|mut batch_intaker| {
+                    scope.spawn(move || {
+                        let stats = batch_intaker.generate_validation_share().unwrap();
+                        let summary = batch_intaker.self_verify_validation_batch().unwrap();
+                        assert_eq!(summary.packets, stats.packets);
+                        stats.packets
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        assert_eq!(packets, vec![10, 11]);
+
+        for batch in &batches {
+            let validation_batch = batch
+                .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+                .into_batch();
+            for (_, key) in validation_batch.keys() {
+                assert!(
+                    validate_transport.get(key).is_ok(),
+                    "missing validation file {}",
+                    key
+                );
+            }
+        }
     }
 }
@@ -0,0 +1,364 @@
+use crate::{
+    batch::{Clock, SystemClock},
+    transport::basic_runtime,
+    Error,
+};
+use chrono::NaiveDateTime;
+use hyper::{body, Body, Client, Uri};
+use hyper_rustls::HttpsConnector;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+
+/// Length in bytes of each of the x and y coordinates of a P-256 point.
+const P256_COORDINATE_LENGTH: usize = 32;
+
+/// JwksKeySource provides the P-256 ECDSA public keys an ingestor publishes as
+/// a JSON Web Key Set (RFC 7517), indexed by their key ID. The key set is
+/// fetched on first use and cached for the configured TTL. Since ingestors
+/// publish new keys before signing with them, a lookup of a key ID that isn't
+/// in the cached set refetches it even if the TTL hasn't expired, so rotation
+/// is picked up without waiting. Batch headers don't identify the key that
+/// signed them, so callers look keys up by an ID they are configured with.
+///
+/// Keys of other types, on other curves or not meant for signatures are
+/// ignored, so that an ingestor may publish them in the same set.
+pub struct JwksKeySource<'a> {
+    url: String,
+    ttl: Duration,
+    clock: &'a dyn Clock,
+    cache: Option<CachedKeys>,
+}
+
+struct CachedKeys {
+    fetched_at: NaiveDateTime,
+    keys: HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKey {
+    kty: String,
+    crv: Option<String>,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl<'a> JwksKeySource<'a> {
+    /// Creates a JwksKeySource for the key set at the provided http or https
+    /// URL, which is cached for ttl. Nothing is fetched until keys are looked
+    /// up.
+    pub fn new(url: &str, ttl: Duration) -> JwksKeySource<'a> {
+        JwksKeySource {
+            url: url.to_owned(),
+            ttl,
+            clock: &SystemClock,
+            cache: None,
+        }
+    }
+
+    /// Sets the clock against which the age of the cached key set is
+    /// measured. Defaults to SystemClock.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    /// Returns all the keys in the key set, refetching it if the cached copy
+    /// is older than the TTL.
+    pub fn keys(&mut self) -> Result<&HashMap<String, UnparsedPublicKey<Vec<u8>>>, Error> {
+        if !self.is_fresh() {
+            self.refresh()?;
+        }
+        Ok(&self.cache.as_ref().unwrap().keys)
+    }
+
+    /// Returns the key with the provided key ID, refetching the key set if
+    /// the cached copy is older than the TTL or doesn't contain the key.
+    /// Returns Error::CryptographyError if the key set can't be fetched or
+    /// parsed, or if it doesn't contain the key once refetched.
+    pub fn key(&mut self, key_id: &str) -> Result<&UnparsedPublicKey<Vec<u8>>, Error> {
+        // A fresh key set lacking the key may predate the key's publication.
+        if !self.is_fresh() || !self.cache.as_ref().unwrap().keys.contains_key(key_id) {
+            self.refresh()?;
+        }
+        let url = &self.url;
+        self.cache
+            .as_ref()
+            .unwrap()
+            .keys
+            .get(key_id)
+            .ok_or_else(|| {
+                Error::CryptographyError(format!("no key with ID {:?} in JWKS at {}", key_id, url))
+            })
+    }
+
+    fn is_fresh(&self) -> bool {
+        matches!(&self.cache, Some(cache) if self.clock.is_within(&cache.fetched_at, self.ttl))
+    }
+
+    /// Fetches and parses the key set, replacing the cached copy.
+    fn refresh(&mut self) -> Result<(), Error> {
+        let fetched_at = self.clock.now();
+        let content = self.fetch()?;
+        let keys = parse_key_set(&content).map_err(|e| {
+            Error::CryptographyError(format!("malformed JWKS at {}: {}", self.url, e))
+        })?;
+        self.cache = Some(CachedKeys { fetched_at, keys });
+        Ok(())
+    }
+
+    fn fetch(&self) -> Result<Vec<u8>, Error> {
+        let failed = |e: String| {
+            Error::CryptographyError(format!("failed to fetch JWKS from {}: {}", self.url, e))
+        };
+        let uri: Uri = self.url.parse().map_err(|e| failed(format!("{}", e)))?;
+        let mut runtime = basic_runtime().map_err(|e| failed(format!("{}", e)))?;
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+        runtime.block_on(async {
+            let response = client
+                .get(uri)
+                .await
+                .map_err(|e| failed(format!("{}", e)))?;
+            if !response.status().is_success() {
+                return Err(failed(format!("HTTP status {}", response.status())));
+            }
+            let content = body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| failed(format!("{}", e)))?;
+            Ok(content.to_vec())
+        })
+    }
+}
+
+/// Parses a JSON Web Key Set, returning its P-256 signing keys as X9.62
+/// uncompressed points indexed by key ID.
+fn parse_key_set(content: &[u8]) -> Result<HashMap<String, UnparsedPublicKey<Vec<u8>>>, String> {
+    let key_set: JsonWebKeySet = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+    let mut keys = HashMap::new();
+    for key in key_set.keys {
+        if key.kty != "EC"
+            || key.crv.as_deref() != Some("P-256")
+            || key
+                .key_use
+                .as_deref()
+                .is_some_and(|key_use| key_use != "sig")
+        {
+            continue;
+        }
+        let key_id = key.kid.ok_or("P-256 key has no kid")?;
+        let mut point = vec![0x04];
+        for (name, coordinate) in &[("x", &key.x), ("y", &key.y)] {
+            let coordinate = coordinate
+                .as_ref()
+                .ok_or_else(|| format!("key {} has no {}", key_id, name))?;
+            let coordinate = base64::decode_config(coordinate, base64::URL_SAFE_NO_PAD)
+                .map_err(|e| format!("key {} has malformed {}: {}", key_id, name, e))?;
+            if coordinate.len() != P256_COORDINATE_LENGTH {
+                return Err(format!(
+                    "key {} has {} of length {} but should be {}",
+                    key_id,
+                    name,
+                    coordinate.len(),
+                    P256_COORDINATE_LENGTH
+                ));
+            }
+            point.extend_from_slice(&coordinate);
+        }
+        if keys.contains_key(&key_id) {
+            return Err(format!("key ID {} appears more than once", key_id));
+        }
+        keys.insert(
+            key_id,
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point),
+        );
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        default_facilitator_signing_private_key, default_ingestor_private_key, MockClock,
+    };
+    use chrono::{Duration as ChronoDuration, NaiveDate};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair},
+    };
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    /// Serves the provided responses over HTTP, one per request, repeating the
+    /// last one once they run out. Returns the URL to request and the number
+    /// of requests served so far.
+    fn serve(responses: Vec<(u16, String)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let index = served.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = &responses[index.min(responses.len() - 1)];
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Whatever\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn jwk(key_id: &str, key_pair: &EcdsaKeyPair) -> String {
+        let public_key = key_pair.public_key().as_ref();
+        format!(
+            r#"{{"kty":"EC","crv":"P-256","use":"sig","alg":"ES256","kid":"{}","x":"{}","y":"{}"}}"#,
+            key_id,
+            base64::encode_config(&public_key[1..33], base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&public_key[33..], base64::URL_SAFE_NO_PAD),
+        )
+    }
+
+    fn key_set(keys: &[String]) -> String {
+        format!(r#"{{"keys":[{}]}}"#, keys.join(","))
+    }
+
+    fn assert_verifies(key: &UnparsedPublicKey<Vec<u8>>, key_pair: &EcdsaKeyPair) {
+        let signature = key_pair.sign(&SystemRandom::new(), b"message").unwrap();
+        key.verify(b"message", signature.as_ref())
+            .expect("signature did not verify");
+    }
+
+    #[test]
+    fn jwks_key_source() {
+        let first_key = default_ingestor_private_key();
+        let second_key = default_facilitator_signing_private_key();
+        let keys = vec![
+            jwk("key-1", &first_key),
+            jwk("key-2", &second_key),
+            // Keys we can't use are ignored.
+            r#"{"kty":"RSA","kid":"rsa-key","n":"AQAB","e":"AQAB"}"#.to_owned(),
+            jwk("encryption-key", &first_key).replace(r#""use":"sig""#, r#""use":"enc""#),
+        ];
+        // After the first fetch, the ingestor rotates in a new key.
+        let rotated_keys = vec![jwk("key-2", &second_key), jwk("key-3", &first_key)];
+        let (url, requests) = serve(vec![(200, key_set(&keys)), (200, key_set(&rotated_keys))]);
+
+        let mut source = JwksKeySource::new(&url, Duration::from_secs(3600));
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        let mut key_ids: Vec<&String> = source.keys().unwrap().keys().collect();
+        key_ids.sort();
+        assert_eq!(key_ids, vec!["key-1", "key-2"]);
+        assert_verifies(source.key("key-1").unwrap(), &first_key);
+        assert_verifies(source.key("key-2").unwrap(), &second_key);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // An unknown key ID causes a refetch even though the TTL hasn't
+        // expired.
+        assert_verifies(source.key("key-3").unwrap(), &first_key);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(!source.keys().unwrap().contains_key("key-1"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        match source.key("encryption-key") {
+            Err(Error::CryptographyError(_)) => (),
+            v => panic!("unexpected result {:?}", v.map(|_| ())),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn jwks_key_source_ttl() {
+        let key_pair = default_ingestor_private_key();
+        let (url, requests) = serve(vec![(200, key_set(&[jwk("key-1", &key_pair)]))]);
+
+        let mut source = JwksKeySource::new(&url, Duration::from_secs(0));
+        for expected_requests in 1..=3 {
+            assert_verifies(source.key("key-1").unwrap(), &key_pair);
+            assert_eq!(requests.load(Ordering::SeqCst), expected_requests);
+        }
+
+        // The key set is refetched once the TTL is up, and not before.
+        let clock = MockClock::new(NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 5, 0));
+        let mut source = JwksKeySource::new(&url, Duration::from_secs(60));
+        source.set_clock(&clock);
+        source.keys().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        clock.advance(ChronoDuration::seconds(59));
+        assert_verifies(source.key("key-1").unwrap(), &key_pair);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        clock.advance(ChronoDuration::seconds(1));
+        assert_verifies(source.key("key-1").unwrap(), &key_pair);
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+        clock.advance(ChronoDuration::seconds(59));
+        source.keys().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn jwks_key_source_failures() {
+        let key_pair = default_ingestor_private_key();
+        let bad_coordinate = jwk("key-1", &key_pair).replace(r#""x":""#, r#""x":"AAAA"#);
+        for (status, body) in &[
+            (404, "not found".to_owned()),
+            (200, "not json".to_owned()),
+            (200, r#"{"keys":{}}"#.to_owned()),
+            (200, key_set(&[bad_coordinate])),
+            (
+                200,
+                key_set(&[jwk("key-1", &key_pair), jwk("key-1", &key_pair)]),
+            ),
+            (
+                200,
+                key_set(&[jwk("key-1", &key_pair).replace(r#""kid":"key-1","#, "")]),
+            ),
+        ] {
+            let (url, _) = serve(vec![(*status, body.clone())]);
+            let mut source = JwksKeySource::new(&url, Duration::from_secs(3600));
+            match source.keys() {
+                Err(Error::CryptographyError(_)) => (),
+                v => panic!("unexpected result for {}: {:?}", body, v.map(|_| ())),
+            }
+        }
+
+        // Nothing listening
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/jwks.json", listener.local_addr().unwrap())
+        };
+        match JwksKeySource::new(&url, Duration::from_secs(3600)).key("key-1") {
+            Err(Error::CryptographyError(_)) => (),
+            v => panic!("unexpected result {:?}", v.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    batch::{Clock, SystemClock},
+    jwks::JwksKeySource,
+    Error,
+};
+use chrono::NaiveDateTime;
+use ring::signature::UnparsedPublicKey;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// A source of public keys that are looked up by key ID, such as a
+/// JwksKeySource.
+pub trait KeySource: Send {
+    /// Returns the key with the provided key ID, failing if the source has no
+    /// such key or can't be reached.
+    fn public_key(&mut self, key_id: &str) -> Result<UnparsedPublicKey<Vec<u8>>, Error>;
+}
+
+impl KeySource for JwksKeySource<'_> {
+    fn public_key(&mut self, key_id: &str) -> Result<UnparsedPublicKey<Vec<u8>>, Error> {
+        self.key(key_id).cloned()
+    }
+}
+
+/// Running totals of the lookups through a KeyCache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyCacheMetrics {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups passed on to the underlying KeySource, whether or not it
+    /// returned a key
+    pub misses: u64,
+    /// Keys dropped from the cache to make room for others
+    pub evictions: u64,
+}
+
+/// KeyCache sits in front of a KeySource and remembers up to capacity of the
+/// keys it returns, each for at most ttl, so that a key looked up for every
+/// batch is only fetched once per TTL. When the cache is full, the least
+/// recently used key makes way for a new one. Failed lookups aren't cached.
+///
+/// KeyCache may be shared between threads. Lookups are serialized, including
+/// those that go to the underlying KeySource, so that threads looking up the
+/// same key at once fetch it once between them.
+pub struct KeyCache<'a, S> {
+    capacity: usize,
+    ttl: Duration,
+    clock: &'a dyn Clock,
+    state: Mutex<KeyCacheState<S>>,
+}
+
+struct KeyCacheState<S> {
+    source: S,
+    entries: HashMap<String, CacheEntry>,
+    /// Incremented on each lookup, to order entries by recency of use
+    lookups: u64,
+    metrics: KeyCacheMetrics,
+}
+
+struct CacheEntry {
+    key: UnparsedPublicKey<Vec<u8>>,
+    fetched_at: NaiveDateTime,
+    last_used: u64,
+}
+
+impl<'a, S: KeySource> KeyCache<'a, S> {
+    /// Creates an empty KeyCache in front of the provided source, holding at
+    /// most capacity keys for at most ttl each. A capacity of 0 caches nothing.
+    pub fn new(source: S, capacity: usize, ttl: Duration) -> KeyCache<'a, S> {
+        KeyCache {
+            capacity,
+            ttl,
+            clock: &SystemClock,
+            state: Mutex::new(KeyCacheState {
+                source,
+                entries: HashMap::new(),
+                lookups: 0,
+                metrics: KeyCacheMetrics::default(),
+            }),
+        }
+    }
+
+    /// Sets the clock against which the age of cached keys is measured.
+    /// Defaults to SystemClock.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    /// Returns the key with the provided key ID from the cache if it was
+    /// fetched less than the TTL ago, or else from the underlying KeySource.
+    pub fn key(&self, key_id: &str) -> Result<UnparsedPublicKey<Vec<u8>>, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.lookups += 1;
+        let lookups = state.lookups;
+        if let Some(entry) = state.entries.get_mut(key_id) {
+            if self.clock.is_within(&entry.fetched_at, self.ttl) {
+                entry.last_used = lookups;
+                let key = entry.key.clone();
+                state.metrics.hits += 1;
+                return Ok(key);
+            }
+        }
+
+        state.metrics.misses += 1;
+        state.entries.remove(key_id);
+        let fetched_at = self.clock.now();
+        let key = state.source.public_key(key_id)?;
+        if self.capacity == 0 {
+            return Ok(key);
+        }
+        if state.entries.len() >= self.capacity {
+            let least_recently_used = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key_id, _)| key_id.clone())
+                .unwrap();
+            state.entries.remove(&least_recently_used);
+            state.metrics.evictions += 1;
+        }
+        state.entries.insert(
+            key_id.to_owned(),
+            CacheEntry {
+                key: key.clone(),
+                fetched_at,
+                last_used: lookups,
+            },
+        );
+        Ok(key)
+    }
+
+    /// Returns the totals so far.
+    pub fn metrics(&self) -> KeyCacheMetrics {
+        self.state.lock().unwrap().metrics
+    }
+}
+
+impl<S: KeySource> KeySource for KeyCache<'_, S> {
+    fn public_key(&mut self, key_id: &str) -> Result<UnparsedPublicKey<Vec<u8>>, Error> {
+        self.key(key_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        default_facilitator_signing_public_key, default_ingestor_private_key,
+        default_ingestor_public_key, MockClock,
+    };
+    use chrono::{Duration as ChronoDuration, NaiveDate};
+    use ring::rand::SystemRandom;
+
+    /// A KeySource holding the default ingestor and facilitator keys, which
+    /// counts the lookups it serves.
+    #[derive(Default)]
+    struct CountingKeySource {
+        lookups: HashMap<String, u64>,
+    }
+
+    impl KeySource for CountingKeySource {
+        fn public_key(&mut self, key_id: &str) -> Result<UnparsedPublicKey<Vec<u8>>, Error> {
+            *self.lookups.entry(key_id.to_owned()).or_default() += 1;
+            match key_id {
+                "ingestor" => Ok(default_ingestor_public_key()),
+                "facilitator" => Ok(default_facilitator_signing_public_key()),
+                _ => Err(Error::CryptographyError(format!("no key {}", key_id))),
+            }
+        }
+    }
+
+    fn source_lookups(cache: &KeyCache<'_, CountingKeySource>, key_id: &str) -> u64 {
+        let state = cache.state.lock().unwrap();
+        state.source.lookups.get(key_id).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn repeated_lookups() {
+        let signature = default_ingestor_private_key()
+            .sign(&SystemRandom::new(), b"message")
+            .unwrap();
+        let cache = KeyCache::new(CountingKeySource::default(), 10, Duration::from_secs(3600));
+        for _ in 0..3 {
+            cache
+                .key("ingestor")
+                .unwrap()
+                .verify(b"message", signature.as_ref())
+                .unwrap();
+        }
+        assert_eq!(source_lookups(&cache, "ingestor"), 1);
+
+        // Failures are passed on every time.
+        for _ in 0..2 {
+            assert!(matches!(
+                cache.key("unknown"),
+                Err(Error::CryptographyError(_))
+            ));
+        }
+        assert_eq!(source_lookups(&cache, "unknown"), 2);
+        assert_eq!(
+            cache.metrics(),
+            KeyCacheMetrics {
+                hits: 2,
+                misses: 3,
+                evictions: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn expired_lookups() {
+        let cache = KeyCache::new(CountingKeySource::default(), 10, Duration::from_secs(0));
+        for _ in 0..3 {
+            cache.key("ingestor").unwrap();
+        }
+        assert_eq!(source_lookups(&cache, "ingestor"), 3);
+        assert_eq!(cache.metrics().hits, 0);
+
+        let clock = MockClock::new(NaiveDate::from_ymd(2020, 10, 14).and_hms(16, 5, 0));
+        let mut cache = KeyCache::new(CountingKeySource::default(), 10, Duration::from_secs(60));
+        cache.set_clock(&clock);
+        cache.key("ingestor").unwrap();
+        // A key is served from the cache until just before the TTL is up ...
+        clock.advance(ChronoDuration::seconds(59));
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 1);
+        // ... and fetched again once it is.
+        clock.advance(ChronoDuration::seconds(1));
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 2);
+        // The refetched key is fresh for another TTL.
+        clock.advance(ChronoDuration::seconds(59));
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 2);
+        // A clock set back doesn't expire keys.
+        clock.advance(ChronoDuration::seconds(-3600));
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 2);
+        assert_eq!(
+            cache.metrics(),
+            KeyCacheMetrics {
+                hits: 3,
+                misses: 2,
+                evictions: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = KeyCache::new(CountingKeySource::default(), 1, Duration::from_secs(3600));
+        cache.key("ingestor").unwrap();
+        cache.key("ingestor").unwrap();
+        cache.key("facilitator").unwrap();
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 2);
+        assert_eq!(source_lookups(&cache, "facilitator"), 1);
+        assert_eq!(
+            cache.metrics(),
+            KeyCacheMetrics {
+                hits: 1,
+                misses: 3,
+                evictions: 2,
+            }
+        );
+
+        let cache = KeyCache::new(CountingKeySource::default(), 0, Duration::from_secs(3600));
+        cache.key("ingestor").unwrap();
+        cache.key("ingestor").unwrap();
+        assert_eq!(source_lookups(&cache, "ingestor"), 2);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let cache = KeyCache::new(CountingKeySource::default(), 10, Duration::from_secs(3600));
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for key_id in &["ingestor", "facilitator"] {
+                        cache.key(key_id).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(source_lookups(&cache, "ingestor"), 1);
+        assert_eq!(source_lookups(&cache, "facilitator"), 1);
+        assert_eq!(cache.metrics().hits, 6);
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{anyhow, Context, Result};
+use prio::encrypt::{PrivateKey, PublicKey};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+/// Length of an X9.62 uncompressed NIST P-256 public key.
+const P256_PUBLIC_KEY_LENGTH: usize = 65;
+/// Length of a NIST P-256 secret scalar.
+const P256_SCALAR_LENGTH: usize = 32;
+
+/// ring serializes P-256 PKCS#8 documents using a fixed template: this prefix,
+/// the secret scalar, PKCS8_PUBLIC_KEY_PREFIX and then the X9.62 uncompressed
+/// public key. We rely on that layout to get at the raw scalar, which ring does
+/// not otherwise expose, but check it on every key we extract from.
+const PKCS8_PRIVATE_KEY_PREFIX: &[u8] = &[
+    0x30, 0x81, 0x87, 0x02, 0x01, 0x00, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+    0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x04, 0x6d, 0x30, 0x6b, 0x02,
+    0x01, 0x01, 0x04, 0x20,
+];
+const PKCS8_PUBLIC_KEY_PREFIX: &[u8] = &[0xa1, 0x44, 0x03, 0x42, 0x00];
+
+/// DER encoding of a SubjectPublicKeyInfo for a P-256 key, up to the X9.62
+/// uncompressed public key itself.
+const SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// A P-256 ECDSA key pair, as used to sign batch headers. The PKCS#8 document
+/// is wiped from memory when the SigningKeyPair is dropped.
+#[derive(Clone)]
+pub struct SigningKeyPair {
+    pkcs8: Zeroizing<Vec<u8>>,
+    public_key: Vec<u8>,
+}
+
+impl SigningKeyPair {
+    /// Returns the PKCS#8 document containing the private key.
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// Returns the X9.62 uncompressed public key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the base64 encoded PKCS#8 document, as accepted by the
+    /// facilitator's signing key arguments.
+    pub fn private_key_base64(&self) -> String {
+        base64::encode(&*self.pkcs8)
+    }
+
+    /// Returns the base64 encoded X9.62 uncompressed public key.
+    pub fn public_key_base64(&self) -> String {
+        base64::encode(&self.public_key)
+    }
+
+    /// Returns the PKCS#8 document in PEM.
+    pub fn private_key_pem(&self) -> String {
+        pem("PRIVATE KEY", &self.pkcs8)
+    }
+
+    /// Returns the public key as a SubjectPublicKeyInfo in PEM.
+    pub fn public_key_pem(&self) -> String {
+        let mut spki = SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(&self.public_key);
+        pem("PUBLIC KEY", &spki)
+    }
+
+    /// Constructs a ring key pair that can sign with this key.
+    pub fn key_pair(&self) -> Result<EcdsaKeyPair> {
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &self.pkcs8)
+            .context("failed to parse generated PKCS#8 document")
+    }
+}
+
+/// A libprio ECIES key pair, as used to encrypt data shares. The private key
+/// is wiped from memory when the EciesKeyPair is dropped.
+#[derive(Clone)]
+pub struct EciesKeyPair {
+    // X9.62 uncompressed public key concatenated with the secret scalar, which
+    // is the representation libprio expects.
+    private_key: Zeroizing<Vec<u8>>,
+}
+
+impl EciesKeyPair {
+    /// Returns the base64 encoded private key, as accepted by the facilitator's
+    /// ECIES key arguments and PrivateKey::from_base64.
+    pub fn private_key_base64(&self) -> String {
+        base64::encode(&*self.private_key)
+    }
+
+    /// Returns the base64 encoded X9.62 uncompressed public key, as accepted
+    /// by PublicKey::from_base64.
+    pub fn public_key_base64(&self) -> String {
+        base64::encode(&self.private_key[..P256_PUBLIC_KEY_LENGTH])
+    }
+
+    /// Returns the libprio private key. Note that libprio does not wipe its
+    /// copy of the key when the PrivateKey is dropped.
+    pub fn private_key(&self) -> PrivateKey {
+        // We produced the encoding ourselves, so it is OK to unwrap here.
+        PrivateKey::from_base64(&Zeroizing::new(self.private_key_base64())).unwrap()
+    }
+
+    /// Returns the libprio public key.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.private_key())
+    }
+}
+
+/// Generates a new P-256 ECDSA key pair for signing batches.
+pub fn generate_signing_key_pair() -> Result<SigningKeyPair> {
+    let pkcs8 =
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .map_err(|_| anyhow!("failed to generate ECDSA key pair"))?;
+    let public_key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+        .context("failed to parse generated PKCS#8 document")?
+        .public_key()
+        .as_ref()
+        .to_vec();
+
+    Ok(SigningKeyPair {
+        pkcs8: Zeroizing::new(pkcs8.as_ref().to_vec()),
+        public_key,
+    })
+}
+
+/// Generates a new libprio ECIES key pair for encrypting data shares.
+pub fn generate_ecies_key_pair() -> Result<EciesKeyPair> {
+    let signing_key_pair = generate_signing_key_pair()?;
+    let pkcs8 = signing_key_pair.pkcs8();
+
+    let scalar_end = PKCS8_PRIVATE_KEY_PREFIX.len() + P256_SCALAR_LENGTH;
+    let public_key_start = scalar_end + PKCS8_PUBLIC_KEY_PREFIX.len();
+    if pkcs8.len() != public_key_start + P256_PUBLIC_KEY_LENGTH
+        || &pkcs8[..PKCS8_PRIVATE_KEY_PREFIX.len()] != PKCS8_PRIVATE_KEY_PREFIX
+        || &pkcs8[scalar_end..public_key_start] != PKCS8_PUBLIC_KEY_PREFIX
+        || &pkcs8[public_key_start..] != signing_key_pair.public_key()
+    {
+        return Err(anyhow!("unexpected layout of generated PKCS#8 document"));
+    }
+
+    // Allocate the whole key up front, so that growing the vector can't leave
+    // a copy of the scalar behind.
+    let mut private_key = Zeroizing::new(Vec::with_capacity(
+        P256_PUBLIC_KEY_LENGTH + P256_SCALAR_LENGTH,
+    ));
+    private_key.extend_from_slice(signing_key_pair.public_key());
+    private_key.extend_from_slice(&pkcs8[PKCS8_PRIVATE_KEY_PREFIX.len()..scalar_end]);
+
+    Ok(EciesKeyPair { private_key })
+}
+
+/// Decodes a base64 encoded P-256 ECDSA public key, as accepted by the
+/// facilitator's public key arguments, and returns the X9.62 uncompressed
+/// public key. For convenience, a PKCS#8 private key is accepted too, in which
+/// case its public key is returned. The key is not otherwise validated.
+pub fn signing_public_key_from_base64(key: &str) -> Result<Vec<u8>> {
+    let key_bytes = Zeroizing::new(base64::decode(key).context("key is not valid base64")?);
+    match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_bytes) {
+        Ok(key_pair) => Ok(key_pair.public_key().as_ref().to_vec()),
+        Err(_) => Ok(key_bytes.to_vec()),
+    }
+}
+
+/// Decodes a base64 encoded PKCS#8 document containing a P-256 ECDSA private
+/// key, as accepted by the facilitator's signing key arguments, and returns a
+/// key pair that signs with it. The decoded document is wiped from memory
+/// before this returns, whether or not it parsed. ring keeps its own copy of
+/// the key in the EcdsaKeyPair, which is not wiped when it is dropped.
+pub fn signing_key_pair_from_base64(key: &str) -> Result<EcdsaKeyPair> {
+    let key_bytes = Zeroizing::new(base64::decode(key).context("key is not valid base64")?);
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_bytes)
+        .map_err(|e| anyhow!("key is not a PKCS#8 encoded P-256 ECDSA private key: {}", e))
+}
+
+/// Generates a signing key pair and an ECIES key pair and writes them into
+/// files in the provided directory, with names starting with prefix. Files
+/// containing private keys are only readable by their owner. Existing files
+/// are never overwritten. Returns the paths of the files that were written.
+pub fn write_key_files(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let signing_key_pair = generate_signing_key_pair()?;
+    let ecies_key_pair = generate_ecies_key_pair()?;
+
+    // The contents of private key files are wiped from memory once written.
+    let files = [
+        (
+            "signing-private-key",
+            Zeroizing::new(signing_key_pair.private_key_base64()),
+            true,
+        ),
+        (
+            "signing-public-key",
+            Zeroizing::new(signing_key_pair.public_key_base64()),
+            false,
+        ),
+        (
+            "signing-private-key.pem",
+            Zeroizing::new(signing_key_pair.private_key_pem()),
+            true,
+        ),
+        (
+            "signing-public-key.pem",
+            Zeroizing::new(signing_key_pair.public_key_pem()),
+            false,
+        ),
+        (
+            "ecies-private-key",
+            Zeroizing::new(ecies_key_pair.private_key_base64()),
+            true,
+        ),
+        (
+            "ecies-public-key",
+            Zeroizing::new(ecies_key_pair.public_key_base64()),
+            false,
+        ),
+    ];
+
+    let mut paths = Vec::new();
+    for (suffix, content, private) in files.iter() {
+        let path = directory.join(format!("{}-{}", prefix, suffix));
+        let mut file = create_key_file(&path, *private)
+            .with_context(|| format!("failed to create key file {}", path.display()))?;
+        writeln!(file, "{}", content.as_str())
+            .with_context(|| format!("failed to write key file {}", path.display()))?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+fn create_key_file(path: &Path, private: bool) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if private { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(path)
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    // PEM wraps base64 at 64 characters, and base64 output is ASCII, so we can
+    // split it at arbitrary byte offsets.
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----", label));
+    pem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{Batch, BatchDate, BatchIdentity, BatchReader, ServerIdentity},
+        idl::{
+            IngestionDataSharePacket, IngestionHeader, Packet, ValidationHeader, ValidationPacket,
+        },
+        intake::BatchIntaker,
+        sample::generate_ingestion_sample,
+        test_utils::DEFAULT_INGESTOR_PRIVATE_KEY,
+        transport::LocalFileTransport,
+        Error,
+    };
+    use chrono::NaiveDateTime;
+    use prio::encrypt::{decrypt_share, encrypt_share};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use std::io::Read;
+    use uuid::Uuid;
+
+    #[test]
+    fn signing_key_pair() {
+        let key_pair = generate_signing_key_pair().unwrap();
+        assert_eq!(key_pair.public_key().len(), P256_PUBLIC_KEY_LENGTH);
+        assert_eq!(
+            key_pair.key_pair().unwrap().public_key().as_ref(),
+            key_pair.public_key()
+        );
+        assert_eq!(
+            base64::decode(key_pair.private_key_base64()).unwrap(),
+            key_pair.pkcs8()
+        );
+
+        let pem = key_pair.public_key_pem();
+        assert!(pem.starts_with("-----BEGIN PUBLIC KEY-----\n"));
+        assert!(pem.ends_with("\n-----END PUBLIC KEY-----"));
+        let der = base64::decode(
+            pem.lines()
+                .filter(|l| !l.starts_with("-----"))
+                .collect::<String>(),
+        )
+        .unwrap();
+        assert_eq!(&der[SPKI_PREFIX.len()..], key_pair.public_key());
+
+        assert_ne!(
+            generate_signing_key_pair().unwrap().pkcs8(),
+            key_pair.pkcs8()
+        );
+
+        assert_eq!(
+            signing_key_pair_from_base64(&key_pair.private_key_base64())
+                .unwrap()
+                .public_key()
+                .as_ref(),
+            key_pair.public_key()
+        );
+        assert!(signing_key_pair_from_base64("not base64!").is_err());
+        assert!(signing_key_pair_from_base64(&key_pair.public_key_base64()).is_err());
+    }
+
+    #[test]
+    fn pkcs8_layout() {
+        // Keys generated elsewhere have the same layout we expect from ring.
+        let pkcs8 = base64::decode(DEFAULT_INGESTOR_PRIVATE_KEY).unwrap();
+        assert_eq!(
+            &pkcs8[..PKCS8_PRIVATE_KEY_PREFIX.len()],
+            PKCS8_PRIVATE_KEY_PREFIX
+        );
+        let public_key_start =
+            PKCS8_PRIVATE_KEY_PREFIX.len() + P256_SCALAR_LENGTH + PKCS8_PUBLIC_KEY_PREFIX.len();
+        assert_eq!(
+            &pkcs8[public_key_start - PKCS8_PUBLIC_KEY_PREFIX.len()..public_key_start],
+            PKCS8_PUBLIC_KEY_PREFIX
+        );
+        assert_eq!(pkcs8.len(), public_key_start + P256_PUBLIC_KEY_LENGTH);
+    }
+
+    #[test]
+    fn ecies_key_pair() {
+        let key_pair = generate_ecies_key_pair().unwrap();
+        assert_eq!(
+            base64::decode(key_pair.private_key_base64()).unwrap().len(),
+            P256_PUBLIC_KEY_LENGTH + P256_SCALAR_LENGTH
+        );
+
+        let message = b"a very secret data share";
+        let public_key = PublicKey::from_base64(&key_pair.public_key_base64()).unwrap();
+        let encrypted = encrypt_share(message, &public_key).unwrap();
+        let decrypted = decrypt_share(&encrypted, &key_pair.private_key()).unwrap();
+        assert_eq!(decrypted, message);
+
+        // Some other key must not be able to decrypt the share.
+        let other_key_pair = generate_ecies_key_pair().unwrap();
+        assert!(decrypt_share(&encrypted, &other_key_pair.private_key()).is_err());
+    }
+
+    #[test]
+    fn generated_keys_end_to_end() {
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+        let mut pha_ingestion_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("pha"));
+        let mut facilitator_ingestion_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().join("facilitator"));
+        let mut validation_transport =
+            LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+
+        let ingestor_signing_key = generate_signing_key_pair().unwrap();
+        let pha_signing_key = generate_signing_key_pair().unwrap();
+        let pha_ecies_key = generate_ecies_key_pair().unwrap();
+        let facilitator_ecies_key = generate_ecies_key_pair().unwrap();
+
+        let aggregation_name = "fake-aggregation";
+        let batch_uuid = Uuid::new_v4();
+        let date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+        let batch = BatchIdentity::new(aggregation_name.parse().unwrap(), date, batch_uuid);
+
+        generate_ingestion_sample(
+            &mut pha_ingestion_transport,
+            &mut facilitator_ingestion_transport,
+            None,
+            &batch,
+            &pha_ecies_key.private_key(),
+            &facilitator_ecies_key.private_key(),
+            ingestor_signing_key.pkcs8(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .unwrap();
+
+        let ingestor_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            ingestor_signing_key.public_key().to_vec(),
+        );
+
+        // The ingestion batch must only verify against the generated key.
+        let ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion(&aggregation_name.parse().unwrap(), &batch_uuid, &date),
+                &mut pha_ingestion_transport,
+            );
+        let other_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().to_vec(),
+        );
+        assert!(ingestion_batch.header(&other_public_key).is_err());
+        assert!(ingestion_batch.header(&ingestor_public_key).is_ok());
+
+        let pha_ecies_private_key = pha_ecies_key.private_key();
+        let pha_signing_key_pair = pha_signing_key.key_pair().unwrap();
+        let mut intaker = BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingestion_transport,
+            &mut validation_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_private_key,
+            &pha_signing_key_pair,
+            &ingestor_public_key,
+        )
+        .unwrap();
+        intaker.generate_validation_share().unwrap();
+
+        let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(
+                Batch::new_validation(
+                    &aggregation_name.parse().unwrap(),
+                    &batch_uuid,
+                    &date,
+                    ServerIdentity::Pha,
+                ),
+                &mut validation_transport,
+            );
+        let pha_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            pha_signing_key.public_key().to_vec(),
+        );
+        let header = validation_batch.header(&pha_public_key).unwrap();
+        assert_eq!(header.batch_uuid, batch_uuid);
+        let mut packet_reader = validation_batch.packet_file_reader(&header).unwrap();
+        let mut packet_count = 0;
+        loop {
+            match ValidationPacket::read(&mut packet_reader) {
+                Ok(_) => packet_count += 1,
+                Err(Error::EofError) => break,
+                Err(e) => panic!("failed to read validation packet: {:?}", e),
+            }
+        }
+        assert_eq!(packet_count, 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let paths = write_key_files(tempdir.path(), "test").unwrap();
+        assert_eq!(paths.len(), 6);
+
+        for path in &paths {
+            let mode = std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            if file_name.contains("private") {
+                assert_eq!(mode, 0o600, "{} is too permissive", file_name);
+            }
+        }
+
+        let mut ecies_private_key = String::new();
+        File::open(tempdir.path().join("test-ecies-private-key"))
+            .unwrap()
+            .read_to_string(&mut ecies_private_key)
+            .unwrap();
+        PrivateKey::from_base64(ecies_private_key.trim()).unwrap();
+
+        // Existing keys must not be clobbered.
+        assert!(write_key_files(tempdir.path(), "test").is_err());
+    }
+}
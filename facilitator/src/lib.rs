@@ -1,12 +1,28 @@
 use anyhow::Result;
-use ring::digest;
-use std::io::Write;
+use hash::{DefaultDigest, Digest, Sha256Digest};
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
+};
 
 pub mod aggregation;
+pub mod async_transport;
 pub mod batch;
+pub mod config;
+pub mod driver;
+pub mod export;
+pub mod hash;
 pub mod idl;
 pub mod intake;
+pub mod jwks;
+pub mod key_cache;
+pub mod keygen;
+pub mod preflight;
+pub mod replay;
+pub mod resign;
 pub mod sample;
+pub mod secrets;
+pub mod server_pool;
 pub mod test_utils;
 pub mod transport;
 
@@ -31,23 +47,83 @@ pub enum Error {
     MalformedDataPacketError(String),
     #[error("end of file")]
     EofError,
+    #[error("illegal name: {0}")]
+    IllegalNameError(String),
+    #[error("malformed date: {0}")]
+    MalformedDateError(String),
+    #[error("empty batch: {0}")]
+    EmptyBatchError(String),
+    #[error("malformed batch descriptor: {0}")]
+    MalformedBatchDescriptorError(String),
+    #[error("malformed configuration: {0}")]
+    MalformedConfigError(String),
+    #[error("malformed key: {0}")]
+    MalformedKeyError(String),
+    #[error("batch identity mismatch: object key has batch ID {0} but header has {1}")]
+    BatchIdentityMismatch(uuid::Uuid, uuid::Uuid),
+    #[error("key {0} differs only by case from existing key {1}")]
+    CaseCollision(String, String),
+    #[error("batch date {0} is outside the acceptance window from {1} to {2}")]
+    BatchDateOutsideWindow(
+        batch::BatchDate,
+        chrono::NaiveDateTime,
+        chrono::NaiveDateTime,
+    ),
+    #[error("key {0} would be {1} bytes long, including any reserved prefix, but keys are limited to {2} bytes")]
+    KeyTooLong(String, usize, usize),
+    #[error("cryptography error: {0}")]
+    CryptographyError(String),
+    #[error("digest of packet file {0} does not match header")]
+    PacketFileDigestMismatch(String),
+    #[error(
+        "packet file {0} is at least {1} bytes long, but packet files are limited to {2} bytes"
+    )]
+    PacketFileTooLarge(String, u64, u64),
+    #[error("{0} of {1} packets failed validation, more than the tolerated fraction {2}")]
+    TooManyPacketFailures(u64, u64, f64),
+    #[error("packet {0} appears more than once in the batch")]
+    DuplicatePacketError(uuid::Uuid),
+    #[error("packet {0} has sequence number {2} where {1} was expected")]
+    SequenceNumberMismatch(uuid::Uuid, i64, i64),
+    #[error("header declares {0} packets but {1} were decoded")]
+    PacketCountMismatch(u64, u64),
+    #[error("validation batch for {0} has already been written")]
+    AlreadyProcessed(String),
+    #[error("validation batches diverge: {0}")]
+    ValidationBatchMismatch(String),
+    #[error("post-write verification failed: {0}")]
+    PostWriteVerificationFailed(String),
+    #[error("transport error: {0}")]
+    TransportError(String),
+    #[error("cancelled")]
+    Cancelled,
+    #[error("libprio error: {0}")]
+    LibPrioError(String),
+    #[error("failed to decrypt packet payload: {0}")]
+    PacketDecryptionError(String),
+    #[error("probable key mismatch: {0} of the first {1} packets failed to decrypt, more than the tolerated fraction {2}")]
+    ProbableKeyMismatch(u64, u64, f64),
+    #[error("no packet decryption key is configured for aggregation {0}")]
+    MissingEciesKey(String),
+    #[error("batch {0} was already processed under date {1}, so refusing it under date {2}")]
+    ReplayDetected(uuid::Uuid, batch::BatchDate, batch::BatchDate),
 }
 
 /// An implementation of transport::TransportWriter that computes a SHA256
-/// digest over the content it is provided.
+/// digest over the content it is provided, with hash::DefaultDigest.
 pub struct DigestWriter {
-    context: digest::Context,
+    context: DefaultDigest,
 }
 
 impl DigestWriter {
     fn new() -> DigestWriter {
         DigestWriter {
-            context: digest::Context::new(&digest::SHA256),
+            context: DefaultDigest::new(),
         }
     }
 
     /// Consumes the DigestWriter and returns the computed SHA256 hash.
-    fn finish(self) -> digest::Digest {
+    fn finish(self) -> Sha256Digest {
         self.context.finish()
     }
 }
@@ -64,15 +140,28 @@ impl Write for DigestWriter {
 }
 
 /// SidecarWriter wraps an std::io::Write, but also writes any buffers passed to
-/// it into a second std::io::Write.
+/// it into a second std::io::Write. The sidecar may be anything implementing
+/// std::io::Write: a DigestWriter to hash content as it goes by, a Vec<u8> for
+/// small content or a SpooledBuffer to keep a copy of content that may be too
+/// big to comfortably hold in memory.
 pub struct SidecarWriter<T: Write, W: Write> {
     writer: T,
     sidecar: W,
+    bytes_written: u64,
 }
 
 impl<T: Write, W: Write> SidecarWriter<T, W> {
     fn new(writer: T, sidecar: W) -> SidecarWriter<T, W> {
-        SidecarWriter { writer, sidecar }
+        SidecarWriter {
+            writer,
+            sidecar,
+            bytes_written: 0,
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
     }
 }
 
@@ -82,6 +171,7 @@ impl<T: Write, W: Write> Write for SidecarWriter<T, W> {
         // sidecar writer doesn't get ahead in that case.
         let n = self.writer.write(buf)?;
         self.sidecar.write_all(&buf[..n])?;
+        self.bytes_written += n as u64;
         Ok(n)
     }
 
@@ -90,3 +180,250 @@ impl<T: Write, W: Write> Write for SidecarWriter<T, W> {
         self.sidecar.flush()
     }
 }
+
+/// Copies the entire content of reader into writer through a buffer of the
+/// provided size in bytes, returning the number of bytes copied. Like
+/// std::io::copy, no more is read from reader until the previous chunk has
+/// been accepted by writer, so a slow writer throttles a fast reader rather
+/// than content piling up in memory. Unlike std::io::copy, the size of each
+/// chunk is ours to choose: network transports move data faster in larger
+/// chunks, while memory-constrained environments may want smaller ones.
+pub fn copy_with_buffer<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+) -> Result<u64, std::io::Error> {
+    if buffer_size == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "copy buffer size must not be zero",
+        ));
+    }
+    let mut buffer = vec![0; buffer_size];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// SpooledBuffer is an std::io::Write that keeps content in memory until more
+/// than a threshold number of bytes have been written to it, at which point it
+/// moves the content into an anonymous temporary file and writes everything
+/// after that into the file. This bounds memory use when we need to keep a copy
+/// of content of unknown size, e.g. to sign over it once it is complete.
+pub struct SpooledBuffer {
+    threshold: usize,
+    spool: Spool,
+}
+
+enum Spool {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl SpooledBuffer {
+    /// Creates a SpooledBuffer that moves its content into a temporary file
+    /// once more than threshold bytes have been written.
+    pub fn new(threshold: usize) -> SpooledBuffer {
+        SpooledBuffer {
+            threshold,
+            spool: Spool::Memory(Vec::new()),
+        }
+    }
+
+    /// Creates a SpooledBuffer that never moves its content out of memory.
+    pub fn in_memory() -> SpooledBuffer {
+        SpooledBuffer::new(usize::MAX)
+    }
+
+    /// Returns true if the content has been moved into a temporary file.
+    pub fn is_spooled(&self) -> bool {
+        matches!(self.spool, Spool::File(_))
+    }
+
+    /// Consumes the SpooledBuffer and returns an std::io::Read positioned at
+    /// the start of the content written into it.
+    pub fn into_reader(self) -> Result<Box<dyn Read>, std::io::Error> {
+        match self.spool {
+            Spool::Memory(buf) => Ok(Box::new(Cursor::new(buf))),
+            Spool::File(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+        }
+    }
+
+    /// Like into_reader, but reads content that was spooled to a file through
+    /// a buffer of the provided capacity in bytes. Content still in memory is
+    /// read directly, since buffering it would only add a copy.
+    pub fn into_buffered_reader(self, capacity: usize) -> Result<Box<dyn Read>, std::io::Error> {
+        match self.spool {
+            Spool::Memory(buf) => Ok(Box::new(Cursor::new(buf))),
+            Spool::File(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(BufReader::with_capacity(capacity, file)))
+            }
+        }
+    }
+
+    /// Returns an std::io::Read positioned at the start of the content written
+    /// into the SpooledBuffer, without consuming it. Writing into the buffer
+    /// again after reading from it is not supported.
+    pub fn reader(&mut self) -> Result<Box<dyn Read + '_>, std::io::Error> {
+        match &mut self.spool {
+            Spool::Memory(buf) => Ok(Box::new(buf.as_slice())),
+            Spool::File(file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+        }
+    }
+
+    /// Returns the content written into the SpooledBuffer if it is still held
+    /// in memory, or None if it has been moved into a temporary file.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match &self.spool {
+            Spool::Memory(buf) => Some(buf.as_slice()),
+            Spool::File(_) => None,
+        }
+    }
+
+    /// Consumes the SpooledBuffer and returns its content.
+    pub fn into_vec(self) -> Result<Vec<u8>, std::io::Error> {
+        match self.spool {
+            Spool::Memory(buf) => Ok(buf),
+            spool => {
+                let mut buf = Vec::new();
+                SpooledBuffer {
+                    threshold: 0,
+                    spool,
+                }
+                .into_reader()?
+                .read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+impl Write for SpooledBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if let Spool::Memory(memory) = &self.spool {
+            if memory.len().saturating_add(buf.len()) > self.threshold {
+                let mut file = tempfile::tempfile()?;
+                file.write_all(memory)?;
+                self.spool = Spool::File(file);
+            }
+        }
+
+        match &mut self.spool {
+            Spool::Memory(memory) => memory.write(buf),
+            Spool::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match &mut self.spool {
+            Spool::Memory(_) => Ok(()),
+            Spool::File(file) => file.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spooled_buffer_in_memory() {
+        let mut buffer = SpooledBuffer::new(10);
+        buffer.write_all(&[1, 2, 3, 4, 5]).unwrap();
+        buffer.write_all(&[6, 7, 8, 9, 10]).unwrap();
+        assert!(!buffer.is_spooled());
+        assert_eq!(
+            buffer.as_slice(),
+            Some(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10][..])
+        );
+        assert_eq!(
+            buffer.into_vec().unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+        );
+    }
+
+    #[test]
+    fn spooled_buffer_spools_to_file() {
+        let content: Vec<u8> = (0..100).collect();
+        let mut buffer = SpooledBuffer::new(10);
+        buffer.write_all(&content[..5]).unwrap();
+        assert!(!buffer.is_spooled());
+        buffer.write_all(&content[5..]).unwrap();
+        assert!(buffer.is_spooled());
+        assert_eq!(buffer.as_slice(), None);
+
+        let mut content_again = Vec::new();
+        buffer
+            .reader()
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert_eq!(content_again, content);
+
+        let mut content_again = Vec::new();
+        buffer
+            .into_reader()
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert_eq!(content_again, content);
+    }
+
+    /// A writer that accepts at most one byte per call, like a congested
+    /// network connection.
+    struct TrickleWriter(Vec<u8>);
+
+    impl Write for TrickleWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+            self.0.extend(buf.iter().take(1));
+            Ok(buf.len().min(1))
+        }
+
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copy_with_buffer_sizes() {
+        let content: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        for buffer_size in [1, 7, 4096, 1 << 20] {
+            let mut writer = Vec::new();
+            let copied =
+                copy_with_buffer(&mut content.as_slice(), &mut writer, buffer_size).unwrap();
+            assert_eq!(copied, content.len() as u64);
+            assert_eq!(writer, content, "buffer size {}", buffer_size);
+        }
+
+        let mut writer = TrickleWriter(Vec::new());
+        copy_with_buffer(&mut content.as_slice(), &mut writer, 4096).unwrap();
+        assert_eq!(writer.0, content);
+
+        copy_with_buffer(&mut content.as_slice(), &mut Vec::new(), 0).unwrap_err();
+    }
+
+    #[test]
+    fn sidecar_into_spooled_buffer() {
+        let content: Vec<u8> = (0..100).collect();
+        let mut sidecar_writer = SidecarWriter::new(Vec::new(), SpooledBuffer::new(16));
+        sidecar_writer.write_all(&content).unwrap();
+        assert!(sidecar_writer.sidecar.is_spooled());
+        assert_eq!(sidecar_writer.writer, content);
+        assert_eq!(sidecar_writer.sidecar.into_vec().unwrap(), content);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{keygen::signing_public_key_from_base64, transport::Transport};
+use anyhow::{anyhow, Context, Result};
+use prio::encrypt::{decrypt_share, encrypt_share, PrivateKey, PublicKey};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Length of a libprio ECIES private key: an X9.62 uncompressed P-256 public
+/// key followed by the secret scalar.
+const ECIES_PRIVATE_KEY_LENGTH: usize = 65 + 32;
+
+/// Preflight checks that a share processor's configuration is usable before it
+/// starts processing batches: that keys parse and are consistent, and that
+/// transports can be written to, read from and cleaned up. Every check is run
+/// even if earlier ones fail, so that operators see all problems at once.
+#[derive(Default)]
+pub struct Preflight {
+    results: Vec<PreflightResult>,
+}
+
+/// The outcome of one preflight check.
+pub struct PreflightResult {
+    /// Describes what was checked, e.g. "ECIES private key ecies-private-key"
+    pub check: String,
+    /// Why the check failed, if it did
+    pub error: Option<anyhow::Error>,
+}
+
+impl Preflight {
+    pub fn new() -> Preflight {
+        Preflight::default()
+    }
+
+    /// Checks that the provided base64 encoded libprio ECIES private key
+    /// parses, and that data encrypted to the public key derived from it can
+    /// be decrypted with it.
+    pub fn check_ecies_private_key(&mut self, name: &str, key: &str) {
+        let result = (|| -> Result<()> {
+            let private_key = PrivateKey::from_base64(key).context(
+                "key is not valid base64. Check that the whole key was copied, without line breaks",
+            )?;
+            let length = Zeroizing::new(base64::decode(key)?).len();
+            if length != ECIES_PRIVATE_KEY_LENGTH {
+                return Err(anyhow!(
+                    "key is {} bytes long but should be {}. Check that this is an ECIES private \
+                    key and not a signing key or a public key",
+                    length,
+                    ECIES_PRIVATE_KEY_LENGTH
+                ));
+            }
+            let public_key = PublicKey::from(&private_key);
+            let ciphertext = encrypt_share(b"preflight", &public_key).map_err(|e| {
+                anyhow!(
+                    "public key derived from the key is not a valid P-256 point: {}",
+                    e
+                )
+            })?;
+            match decrypt_share(&ciphertext, &private_key) {
+                Ok(plaintext) if plaintext == b"preflight" => Ok(()),
+                _ => Err(anyhow!(
+                    "key's secret does not match its public key. Regenerate the key pair"
+                )),
+            }
+        })();
+        self.record(format!("ECIES private key {}", name), result);
+    }
+
+    /// Checks that the provided base64 encoded PKCS#8 document contains a
+    /// P-256 ECDSA private key from which a public key can be derived.
+    pub fn check_signing_private_key(&mut self, name: &str, key: &str) {
+        let result = (|| -> Result<()> {
+            let key_bytes = Zeroizing::new(base64::decode(key).context(
+                "key is not valid base64. Check that the whole key was copied, without line breaks",
+            )?);
+            let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_bytes)
+                .map_err(|e| {
+                anyhow!(
+                    "key is not a PKCS#8 encoded P-256 ECDSA private key ({}). Check that this \
+                    is a signing key and not an ECIES key or a public key",
+                    e
+                )
+            })?;
+            check_public_key_point(key_pair.public_key().as_ref())
+        })();
+        self.record(format!("signing private key {}", name), result);
+    }
+
+    /// Checks that the provided base64 encoded P-256 ECDSA public key (or
+    /// PKCS#8 private key, from which a public key is derived) is a valid
+    /// point on the curve.
+    pub fn check_signing_public_key(&mut self, name: &str, key: &str) {
+        let result = (|| -> Result<()> {
+            base64::decode(key).context(
+                "key is not valid base64. Check that the whole key was copied, without line breaks",
+            )?;
+            check_public_key_point(&signing_public_key_from_base64(key)?)
+        })();
+        self.record(format!("signing public key {}", name), result);
+    }
+
+    /// Writes a small value to a fresh key in the provided transport, reads it
+    /// back and deletes it.
+    pub fn check_transport(&mut self, name: &str, transport: &mut dyn Transport) {
+        let key = format!("preflight-{}", Uuid::new_v4());
+        let content = key.as_bytes();
+        let result = (|| -> Result<()> {
+            let mut writer = transport.put(&key).with_context(|| {
+                format!(
+                    "failed to start writing {}. Check the path or bucket name and that we may \
+                    write to it",
+                    key
+                )
+            })?;
+            writer
+                .write_all(content)
+                .with_context(|| format!("failed to write {}", key))?;
+            writer.complete_upload().with_context(|| {
+                format!(
+                    "failed to complete writing {}. Check that we may write to the path or bucket",
+                    key
+                )
+            })?;
+
+            let mut read_back = Vec::new();
+            transport
+                .get(&key)
+                .and_then(|mut reader| Ok(reader.read_to_end(&mut read_back)?))
+                .with_context(|| {
+                    format!(
+                        "failed to read back {}. Check that we may read from the path or bucket",
+                        key
+                    )
+                })?;
+            if read_back != content {
+                return Err(anyhow!(
+                    "read back different content from {} than was written",
+                    key
+                ));
+            }
+
+            transport.delete(&key).with_context(|| {
+                format!(
+                    "failed to delete {}. Check that we may delete from the path or bucket, and \
+                    remove the key by hand",
+                    key
+                )
+            })
+        })();
+        self.record(format!("transport {}", name), result);
+    }
+
+    /// Records the result of a check made elsewhere, e.g. constructing a
+    /// transport from its configuration.
+    pub fn record(&mut self, check: String, result: Result<()>) {
+        self.results.push(PreflightResult {
+            check,
+            error: result.err(),
+        });
+    }
+
+    /// Returns the results of the checks run so far, in the order they ran.
+    pub fn results(&self) -> &[PreflightResult] {
+        &self.results
+    }
+
+    /// Returns true if all the checks run so far passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.error.is_none())
+    }
+}
+
+impl fmt::Display for Preflight {
+    /// Writes one line per check, giving the full chain of causes for failed
+    /// checks.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            match &result.error {
+                None => writeln!(f, "ok: {}", result.check)?,
+                Some(error) => writeln!(f, "FAILED: {}: {:#}", result.check, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the provided X9.62 uncompressed public key is a valid P-256
+/// point. ring only validates ECDSA public keys while verifying a signature,
+/// which can't distinguish a bad key from a bad signature, but ECDSA and ECIES
+/// keys are points on the same curve and encrypting to a key validates it.
+fn check_public_key_point(public_key: &[u8]) -> Result<()> {
+    encrypt_share(
+        b"preflight",
+        &PublicKey::from_base64(&base64::encode(public_key))?,
+    )
+    .map(|_| ())
+    .map_err(|_| {
+        anyhow!(
+            "key is not a valid P-256 public key. Check that this is the public key and \
+                not some other value"
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{
+            DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY,
+            DEFAULT_INGESTOR_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::{MemoryTransport, TransportWriter},
+    };
+
+    /// A transport that can put and get but not delete.
+    struct UndeletableTransport(MemoryTransport);
+
+    impl Transport for UndeletableTransport {
+        fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+            self.0.get(key)
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            self.0.put(key)
+        }
+    }
+
+    #[test]
+    fn preflight_passes() {
+        let mut transport = MemoryTransport::new();
+        let mut other_transport = MemoryTransport::new();
+
+        let mut preflight = Preflight::new();
+        preflight.check_ecies_private_key("pha", DEFAULT_PHA_ECIES_PRIVATE_KEY);
+        preflight.check_ecies_private_key("facilitator", DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY);
+        preflight.check_signing_private_key("facilitator", DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY);
+        preflight.check_signing_public_key("ingestor", DEFAULT_INGESTOR_PRIVATE_KEY);
+        let public_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &base64::decode(DEFAULT_INGESTOR_PRIVATE_KEY).unwrap(),
+        )
+        .unwrap()
+        .public_key()
+        .as_ref()
+        .to_vec();
+        preflight.check_signing_public_key("ingestor-public", &base64::encode(&public_key));
+        preflight.check_transport("ingestion", &mut transport);
+        preflight.check_transport("validation", &mut other_transport);
+
+        assert!(preflight.passed(), "preflight failed:\n{}", preflight);
+        assert_eq!(preflight.results().len(), 7);
+        // Nothing is left behind in the transports.
+        assert!(transport.list("").unwrap().is_empty());
+        assert!(other_transport.list("").unwrap().is_empty());
+        assert!(preflight
+            .to_string()
+            .lines()
+            .all(|line| line.starts_with("ok: ")));
+    }
+
+    #[test]
+    fn preflight_failures() {
+        let mut preflight = Preflight::new();
+        // Signing keys and ECIES keys can't stand in for one another.
+        preflight.check_ecies_private_key("signing-key", DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY);
+        preflight.check_signing_private_key("ecies-key", DEFAULT_PHA_ECIES_PRIVATE_KEY);
+        preflight.check_ecies_private_key("not-base64", "not base64!");
+        preflight.check_signing_public_key("not-a-point", &base64::encode([4; 65]));
+        // An ECIES key whose secret doesn't belong to its public key
+        let mut mismatched = base64::decode(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        mismatched[65..]
+            .copy_from_slice(&base64::decode(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap()[65..]);
+        preflight.check_ecies_private_key("mismatched", &base64::encode(&mismatched));
+        let mut undeletable = UndeletableTransport(MemoryTransport::new());
+        preflight.check_transport("undeletable", &mut undeletable);
+        // A key that passes, among the failures
+        preflight.check_transport("memory", &mut MemoryTransport::new());
+
+        assert!(!preflight.passed());
+        let failed: Vec<&str> = preflight
+            .results()
+            .iter()
+            .filter(|result| result.error.is_some())
+            .map(|result| result.check.as_str())
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                "ECIES private key signing-key",
+                "signing private key ecies-key",
+                "ECIES private key not-base64",
+                "signing public key not-a-point",
+                "ECIES private key mismatched",
+                "transport undeletable",
+            ]
+        );
+        let report = preflight.to_string();
+        assert!(report.contains("FAILED: transport undeletable: failed to delete"));
+        assert!(report.contains("ok: transport memory"));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::batch::{AggregationName, BatchDate, BatchIdentity};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+/// A record of the batches a share processor has validated, by aggregation and
+/// batch ID, so that a batch delivered again under another date can be refused
+/// instead of being counted twice. See BatchIntaker::set_processed_batch_store.
+/// Implementations must be safe to share between threads and processes that
+/// record batches concurrently: of several attempts to record the same batch
+/// ID, exactly one succeeds.
+pub trait ProcessedBatchStore: Sync {
+    /// Returns the date under which the batch with the provided aggregation and
+    /// ID was recorded, if it was.
+    fn lookup(
+        &self,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+    ) -> Result<Option<BatchDate>>;
+
+    /// Records the batch under its date, unless a batch with the same
+    /// aggregation and ID was recorded already, in which case nothing is
+    /// recorded and the date of the earlier record is returned.
+    fn record_if_absent(&self, batch: &BatchIdentity) -> Result<Option<BatchDate>>;
+}
+
+/// A ProcessedBatchStore that keeps its records in memory, for tests and for
+/// processes that only need to refuse replays within their own lifetime.
+#[derive(Debug, Default)]
+pub struct MemoryProcessedBatchStore {
+    records: Mutex<HashMap<(AggregationName, Uuid), BatchDate>>,
+}
+
+impl MemoryProcessedBatchStore {
+    pub fn new() -> MemoryProcessedBatchStore {
+        MemoryProcessedBatchStore::default()
+    }
+}
+
+impl ProcessedBatchStore for MemoryProcessedBatchStore {
+    fn lookup(
+        &self,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+    ) -> Result<Option<BatchDate>> {
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .get(&(aggregation_name.clone(), *batch_id))
+            .copied())
+    }
+
+    fn record_if_absent(&self, batch: &BatchIdentity) -> Result<Option<BatchDate>> {
+        let mut records = self.records.lock().unwrap();
+        let key = (batch.aggregation_name.clone(), batch.batch_id);
+        if let Some(date) = records.get(&key) {
+            return Ok(Some(*date));
+        }
+        records.insert(key, batch.date);
+        Ok(None)
+    }
+}
+
+/// A ProcessedBatchStore that keeps each record in a file named for the batch
+/// ID, in a directory named for the aggregation, under the provided directory,
+/// in the manner of a LocalFileTransport. A record is written to a temporary
+/// file and then linked into place only if no record exists, so concurrent
+/// writers, including other processes sharing the directory, never overwrite
+/// each other's records or see a partial one.
+#[derive(Clone, Debug)]
+pub struct LocalFileProcessedBatchStore {
+    directory: PathBuf,
+}
+
+impl LocalFileProcessedBatchStore {
+    pub fn new(directory: PathBuf) -> LocalFileProcessedBatchStore {
+        LocalFileProcessedBatchStore { directory }
+    }
+
+    fn path(&self, aggregation_name: &AggregationName, batch_id: &Uuid) -> PathBuf {
+        self.directory
+            .join(aggregation_name.as_str())
+            .join(batch_id.to_hyphenated().to_string())
+    }
+}
+
+impl ProcessedBatchStore for LocalFileProcessedBatchStore {
+    fn lookup(
+        &self,
+        aggregation_name: &AggregationName,
+        batch_id: &Uuid,
+    ) -> Result<Option<BatchDate>> {
+        let path = self.path(aggregation_name, batch_id);
+        match fs::read_to_string(&path) {
+            Ok(date) => Ok(Some(date.parse().with_context(|| {
+                format!("malformed processed batch record {}", path.display())
+            })?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| {
+                format!("failed to read processed batch record {}", path.display())
+            }),
+        }
+    }
+
+    fn record_if_absent(&self, batch: &BatchIdentity) -> Result<Option<BatchDate>> {
+        let path = self.path(&batch.aggregation_name, &batch.batch_id);
+        let directory = path.parent().unwrap();
+        fs::create_dir_all(directory)
+            .with_context(|| format!("failed to create directory {}", directory.display()))?;
+        let mut record = NamedTempFile::new_in(directory)
+            .context("failed to create temporary processed batch record")?;
+        record
+            .write_all(batch.date.to_string().as_bytes())
+            .context("failed to write processed batch record")?;
+        match record.persist_noclobber(&path) {
+            Ok(_) => Ok(None),
+            Err(e) if e.error.kind() == ErrorKind::AlreadyExists => {
+                match self.lookup(&batch.aggregation_name, &batch.batch_id)? {
+                    Some(date) => Ok(Some(date)),
+                    None => Err(e.error).with_context(|| {
+                        format!("failed to write processed batch record {}", path.display())
+                    }),
+                }
+            }
+            Err(e) => Err(e.error).with_context(|| {
+                format!("failed to write processed batch record {}", path.display())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn check_store(store: &dyn ProcessedBatchStore) {
+        let aggregation_name = AggregationName::new("fake-aggregation-1").unwrap();
+        let batch = BatchIdentity::new(
+            aggregation_name.clone(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        assert_eq!(
+            store.lookup(&aggregation_name, &batch.batch_id).unwrap(),
+            None
+        );
+        assert_eq!(store.record_if_absent(&batch).unwrap(), None);
+        assert_eq!(
+            store.lookup(&aggregation_name, &batch.batch_id).unwrap(),
+            Some(batch.date)
+        );
+
+        // The same ID under another date keeps the first record.
+        let replayed = BatchIdentity::new(
+            aggregation_name.clone(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890 + 3600, 0)),
+            batch.batch_id,
+        );
+        assert_eq!(store.record_if_absent(&replayed).unwrap(), Some(batch.date));
+        assert_eq!(
+            store.lookup(&aggregation_name, &batch.batch_id).unwrap(),
+            Some(batch.date)
+        );
+
+        // The same ID in another aggregation is another batch.
+        let other_aggregation_name = AggregationName::new("fake-aggregation-2").unwrap();
+        assert_eq!(
+            store
+                .lookup(&other_aggregation_name, &batch.batch_id)
+                .unwrap(),
+            None
+        );
+
+        // Of several threads recording the same ID, exactly one succeeds.
+        let batch_id = Uuid::new_v4();
+        let results: Vec<Option<BatchDate>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|hour| {
+                    let batch = BatchIdentity::new(
+                        aggregation_name.clone(),
+                        BatchDate::new(&NaiveDateTime::from_timestamp(1234567890 + 3600 * hour, 0)),
+                        batch_id,
+                    );
+                    scope.spawn(move || store.record_if_absent(&batch).unwrap())
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|r| r.is_none()).count(), 1);
+        let recorded = store.lookup(&aggregation_name, &batch_id).unwrap().unwrap();
+        assert!(results.iter().flatten().all(|date| *date == recorded));
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&MemoryProcessedBatchStore::new());
+    }
+
+    #[test]
+    fn local_file_store() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        check_store(&LocalFileProcessedBatchStore::new(
+            tempdir.path().to_path_buf(),
+        ));
+
+        // Records outlive the store that wrote them.
+        let aggregation_name = AggregationName::new("fake-aggregation-1").unwrap();
+        let batch = BatchIdentity::new(
+            aggregation_name.clone(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        LocalFileProcessedBatchStore::new(tempdir.path().to_path_buf())
+            .record_if_absent(&batch)
+            .unwrap();
+        assert_eq!(
+            LocalFileProcessedBatchStore::new(tempdir.path().to_path_buf())
+                .lookup(&aggregation_name, &batch.batch_id)
+                .unwrap(),
+            Some(batch.date)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{
+    batch::{Batch, BatchFileKind, BatchReader},
+    idl::{ValidationHeader, ValidationPacket},
+    transport::Transport,
+};
+use anyhow::{Context, Result};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, UnparsedPublicKey},
+};
+use std::io::Write;
+
+/// Signs the header of an existing validation batch with new_key, e.g. while
+/// rotating the share processor's signing key, without recomputing anything.
+/// The header's signature must first verify with old_key and the packet
+/// file's digest must match the header. The header and packet file are never
+/// written, so their content stays byte for byte the same. The new signature
+/// covers the exact bytes of the header and replaces the batch's signature, or
+/// is written to output_signature_key if provided, leaving the old one in
+/// place. A manifest written with the batch is not re-signed. Returns the new
+/// signature.
+pub fn resign_validation_batch(
+    batch: Batch,
+    transport: &mut dyn Transport,
+    old_key: &UnparsedPublicKey<Vec<u8>>,
+    new_key: &EcdsaKeyPair,
+    output_signature_key: Option<&str>,
+) -> Result<Vec<u8>> {
+    let signature_key = output_signature_key
+        .unwrap_or_else(|| batch.key(BatchFileKind::Signature))
+        .to_owned();
+    let reader: BatchReader<'_, ValidationHeader, ValidationPacket> =
+        BatchReader::new(batch, transport);
+    let verified_header = reader
+        .verified_header(old_key)
+        .context("failed to verify header with old key")?;
+    reader
+        .packet_file_reader(&verified_header.header)
+        .context("failed to verify packet file")?;
+    drop(reader);
+
+    let signature = new_key
+        .sign(&SystemRandom::new(), &verified_header.header_bytes)
+        .context("failed to sign header")?;
+    let mut writer = transport.put(&signature_key)?;
+    writer
+        .write_all(signature.as_ref())
+        .context("failed to write signature")?;
+    writer
+        .complete_upload()
+        .context("failed to complete signature upload")?;
+    Ok(signature.as_ref().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{AggregationName, BatchDate, BatchIdentity, ServerIdentity, DEFAULT_NAMING_SCHEME},
+        intake::BatchIntaker,
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_facilitator_signing_private_key, default_facilitator_signing_public_key,
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            default_pha_signing_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::MemoryTransport,
+    };
+    use chrono::NaiveDateTime;
+    use prio::encrypt::PrivateKey;
+    use ring::signature::{KeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use std::io::Read;
+    use uuid::Uuid;
+
+    fn read_all(transport: &dyn Transport, key: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        transport
+            .get(key)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn resign_batch() {
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation-1").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            Uuid::new_v4(),
+        );
+        let mut pha_ingest_transport = MemoryTransport::new();
+        let mut facilitator_ingest_transport = MemoryTransport::new();
+        let mut validate_transport = MemoryTransport::new();
+        let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let old_signing_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &default_pha_signing_private_key(),
+        )
+        .unwrap();
+        let old_public_key = UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            old_signing_key.public_key().as_ref().to_vec(),
+        );
+        let new_signing_key = default_facilitator_signing_private_key();
+        let new_public_key = default_facilitator_signing_public_key();
+
+        generate_ingestion_sample(
+            &mut pha_ingest_transport,
+            &mut facilitator_ingest_transport,
+            None,
+            &batch,
+            &pha_ecies_key,
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+        BatchIntaker::new(
+            None,
+            &batch,
+            &mut pha_ingest_transport,
+            &mut validate_transport,
+            ServerIdentity::Pha,
+            &pha_ecies_key,
+            &old_signing_key,
+            &default_ingestor_public_key(),
+        )
+        .unwrap()
+        .generate_validation_share()
+        .unwrap();
+
+        let validation_batch = || {
+            batch
+                .own_validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha)
+                .into_batch()
+        };
+        let header_key = validation_batch().key(BatchFileKind::Header).to_owned();
+        let packets_key = validation_batch().key(BatchFileKind::Packets).to_owned();
+        let signature_key = validation_batch().key(BatchFileKind::Signature).to_owned();
+        let header = read_all(&validate_transport, &header_key);
+        let packets = read_all(&validate_transport, &packets_key);
+        let old_signature = read_all(&validate_transport, &signature_key);
+
+        // Verifying with the wrong old key fails without writing anything.
+        let err = resign_validation_batch(
+            validation_batch(),
+            &mut validate_transport,
+            &new_public_key,
+            &new_signing_key,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "failed to verify header with old key");
+        assert_eq!(read_all(&validate_transport, &signature_key), old_signature);
+
+        // Writing the signature elsewhere leaves the old one in place.
+        let new_signature = resign_validation_batch(
+            validation_batch(),
+            &mut validate_transport,
+            &old_public_key,
+            &new_signing_key,
+            Some("new-signature"),
+        )
+        .unwrap();
+        assert_eq!(
+            read_all(&validate_transport, "new-signature"),
+            new_signature
+        );
+        assert_eq!(read_all(&validate_transport, &signature_key), old_signature);
+        new_public_key.verify(&header, &new_signature).unwrap();
+
+        // Once re-signed in place, the batch verifies with the new key only,
+        // and its header and packet file are unchanged.
+        resign_validation_batch(
+            validation_batch(),
+            &mut validate_transport,
+            &old_public_key,
+            &new_signing_key,
+            None,
+        )
+        .unwrap();
+        assert_eq!(read_all(&validate_transport, &header_key), header);
+        assert_eq!(read_all(&validate_transport, &packets_key), packets);
+        let reader: BatchReader<'_, ValidationHeader, ValidationPacket> =
+            BatchReader::new(validation_batch(), &mut validate_transport);
+        assert!(reader.header(&old_public_key).is_err());
+        let header = reader.header(&new_public_key).unwrap();
+        assert_eq!(reader.packet_file_reader(&header).unwrap().count(), 10);
+    }
+}
@@ -1,10 +1,15 @@
 use crate::{
-    batch::{Batch, BatchWriter},
+    batch::{
+        AggregationName, BatchDate, BatchIdentity, BatchNaming, BatchWriter, InstanceName,
+        PacketPartNaming, DEFAULT_NAMING_SCHEME,
+    },
+    hash::Sha256Digest,
     idl::{IngestionDataSharePacket, IngestionHeader, Packet},
-    transport::Transport,
+    transport::{Transport, TransportWriter},
+    DigestWriter, SidecarWriter,
 };
 use anyhow::{anyhow, Context, Result};
-use chrono::NaiveDateTime;
+use avro_rs::Writer;
 use prio::{
     client::Client,
     encrypt::{PrivateKey, PublicKey},
@@ -15,13 +20,112 @@ use rand::{thread_rng, Rng};
 use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use uuid::Uuid;
 
+/// Generates an ingestion batch with the provided identity containing
+/// packet_count random data packets, writes the shares for the PHA and the
+/// facilitator into the respective transports, and returns the sum of the data
+/// packets. The packets are numbered in order, starting from zero.
 #[allow(clippy::too_many_arguments)] // Grandfathered in
 pub fn generate_ingestion_sample(
     pha_transport: &mut dyn Transport,
     facilitator_transport: &mut dyn Transport,
-    batch_uuid: &Uuid,
-    aggregation_name: &str,
-    date: &NaiveDateTime,
+    instance_name: Option<&str>,
+    batch: &BatchIdentity,
+    pha_key: &PrivateKey,
+    facilitator_key: &PrivateKey,
+    ingestor_key: &[u8],
+    dim: i32,
+    packet_count: usize,
+    epsilon: f64,
+    batch_start_time: i64,
+    batch_end_time: i64,
+) -> Result<Vec<Field>> {
+    let (reference_sum, _) = generate_ingestion_sample_with_bad_packets(
+        pha_transport,
+        facilitator_transport,
+        instance_name,
+        batch,
+        pha_key,
+        facilitator_key,
+        ingestor_key,
+        dim,
+        packet_count,
+        epsilon,
+        batch_start_time,
+        batch_end_time,
+        &[],
+    )?;
+    Ok(reference_sum)
+}
+
+/// Ways in which generate_ingestion_sample_with_bad_packets can corrupt a data
+/// packet so that share processors must reject it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketCorruption {
+    /// The packet's r_pit is negative and so not a valid field element.
+    IllegalRPit,
+    /// The packet's encrypted payload is cut short and cannot be decrypted.
+    TruncatedPayload,
+    /// The packet reuses the UUID of the packet before it, so share processors
+    /// should only validate the earlier one. May not be applied to the first
+    /// packet.
+    DuplicateUuid,
+    /// The packet is left out of the packet files, as though its record had
+    /// been lost from the middle of them, so the sequence number of the packet
+    /// after it skips one. That packet is the one share processors reject, and
+    /// the one whose UUID is returned. The headers' packet counts leave the
+    /// dropped packet out, so only the sequence numbers reveal the gap.
+    DroppedRecord,
+}
+
+/// Like generate_ingestion_sample, but corrupts both shares of the packets at
+/// the provided indices in the manner described by the paired
+/// PacketCorruption. Returns the sum of the data in the packets that were not
+/// corrupted, along with the UUIDs of the packets that were, in the order they
+/// appear in the batch.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_ingestion_sample_with_bad_packets(
+    pha_transport: &mut dyn Transport,
+    facilitator_transport: &mut dyn Transport,
+    instance_name: Option<&str>,
+    batch: &BatchIdentity,
+    pha_key: &PrivateKey,
+    facilitator_key: &PrivateKey,
+    ingestor_key: &[u8],
+    dim: i32,
+    packet_count: usize,
+    epsilon: f64,
+    batch_start_time: i64,
+    batch_end_time: i64,
+    bad_packets: &[(usize, PacketCorruption)],
+) -> Result<(Vec<Field>, Vec<Uuid>)> {
+    write_ingestion_sample(
+        pha_transport,
+        facilitator_transport,
+        instance_name,
+        batch,
+        pha_key,
+        facilitator_key,
+        ingestor_key,
+        dim,
+        packet_count,
+        epsilon,
+        batch_start_time,
+        batch_end_time,
+        bad_packets,
+        None,
+    )
+}
+
+/// Like generate_ingestion_sample, but splits each share's packets as evenly
+/// as possible, in order, across part_count packet file parts named according
+/// to part_naming (see Batch::packet_file_shard_key). The signed headers list
+/// the digest of each part, in order, instead of a packet file digest.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_multipart_ingestion_sample(
+    pha_transport: &mut dyn Transport,
+    facilitator_transport: &mut dyn Transport,
+    instance_name: Option<&str>,
+    batch: &BatchIdentity,
     pha_key: &PrivateKey,
     facilitator_key: &PrivateKey,
     ingestor_key: &[u8],
@@ -30,18 +134,71 @@ pub fn generate_ingestion_sample(
     epsilon: f64,
     batch_start_time: i64,
     batch_end_time: i64,
+    part_count: usize,
+    part_naming: PacketPartNaming,
 ) -> Result<Vec<Field>> {
+    if part_count == 0 {
+        return Err(anyhow!("part count must be an integer greater than zero"));
+    }
+    let (reference_sum, _) = write_ingestion_sample(
+        pha_transport,
+        facilitator_transport,
+        instance_name,
+        batch,
+        pha_key,
+        facilitator_key,
+        ingestor_key,
+        dim,
+        packet_count,
+        epsilon,
+        batch_start_time,
+        batch_end_time,
+        &[],
+        Some((part_count, part_naming)),
+    )?;
+    Ok(reference_sum)
+}
+
+/// Implements generate_ingestion_sample_with_bad_packets, writing the packets
+/// to a single packet file per share unless a part count and naming are
+/// provided, as for generate_multipart_ingestion_sample.
+#[allow(clippy::too_many_arguments)]
+fn write_ingestion_sample(
+    pha_transport: &mut dyn Transport,
+    facilitator_transport: &mut dyn Transport,
+    instance_name: Option<&str>,
+    batch: &BatchIdentity,
+    pha_key: &PrivateKey,
+    facilitator_key: &PrivateKey,
+    ingestor_key: &[u8],
+    dim: i32,
+    packet_count: usize,
+    epsilon: f64,
+    batch_start_time: i64,
+    batch_end_time: i64,
+    bad_packets: &[(usize, PacketCorruption)],
+    parts: Option<(usize, PacketPartNaming)>,
+) -> Result<(Vec<Field>, Vec<Uuid>)> {
     if dim <= 0 {
         return Err(anyhow!("dimension must be an integer greater than zero"));
     }
+    let instance_name = instance_name.map(InstanceName::new).transpose()?;
+    let batch_uuid = &batch.batch_id;
+    let aggregation_name = &batch.aggregation_name;
 
     let ingestor_key_pair =
         EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, ingestor_key)
             .context("failed to parse ingestor key pair")?;
 
+    let naming_scheme = match parts {
+        Some((_, part_naming)) => DEFAULT_NAMING_SCHEME
+            .clone()
+            .with_ingestion_naming(BatchNaming::default().with_packet_part_naming(part_naming)),
+        None => DEFAULT_NAMING_SCHEME.clone(),
+    };
     let mut pha_ingestion_batch: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
         BatchWriter::new(
-            Batch::new_ingestion(aggregation_name, batch_uuid, date),
+            batch.ingestion_batch(&naming_scheme, instance_name.as_ref()),
             pha_transport,
         );
     let mut facilitator_ingestion_batch: BatchWriter<
@@ -49,7 +206,7 @@ pub fn generate_ingestion_sample(
         IngestionHeader,
         IngestionDataSharePacket,
     > = BatchWriter::new(
-        Batch::new_ingestion(aggregation_name, batch_uuid, date),
+        batch.ingestion_batch(&naming_scheme, instance_name.as_ref()),
         facilitator_transport,
     );
 
@@ -65,95 +222,193 @@ pub fn generate_ingestion_sample(
     )
     .context("failed to create client (bad dimension parameter?)")?;
 
+    // We need an instance of a libprio server to pick an r_pit.
+    let fake_server = Server::new(dim as usize, true, pha_key.clone());
     let mut reference_sum = vec![Field::from(0); dim as usize];
+    let mut bad_packet_uuids = Vec::new();
+    let mut dropped_records = 0;
+    let mut previous_uuid = None;
+    let mut after_dropped_record = false;
+    let mut pha_packet_file_digests = Vec::new();
+    let mut facilitator_packet_file_digests = Vec::new();
+
+    let part_count = parts.map_or(1, |(part_count, _)| part_count);
+    for part in 0..part_count {
+        let part_index = parts.map(|_| part);
+        let indices = part * packet_count / part_count..(part + 1) * packet_count / part_count;
+        // We nest the closures here to get both packet writers in one scope
+        let pha_packet_file_digest =
+            write_packet_file(&mut pha_ingestion_batch, part_index, |pha_packet_writer| {
+                let facilitator_packet_file_digest = write_packet_file(
+                    &mut facilitator_ingestion_batch,
+                    part_index,
+                    |facilitator_packet_writer| {
+                        for index in indices {
+                            // Generate random bit vector
+                            let data = (0..dim)
+                                .map(|_| Field::from(thread_rng.gen_range(0, 2)))
+                                .collect::<Vec<Field>>();
+
+                            let (mut pha_share, mut facilitator_share) = client
+                                .encode_simple(&data)
+                                .context("failed to encode data")?;
+
+                            let mut r_pit = u32::from(fake_server.choose_eval_at()) as i64;
+                            let mut packet_uuid = Uuid::new_v4();
+
+                            let corruption = bad_packets
+                                .iter()
+                                .find(|(bad_index, _)| *bad_index == index)
+                                .map(|(_, corruption)| corruption);
+                            let rejected = corruption.is_some() || after_dropped_record;
+                            after_dropped_record = false;
+                            match corruption {
+                                None if rejected => (),
+                                None => {
+                                    for (r, d) in reference_sum.iter_mut().zip(data.iter()) {
+                                        *r += *d
+                                    }
+                                }
+                                Some(PacketCorruption::IllegalRPit) => r_pit = -1,
+                                Some(PacketCorruption::TruncatedPayload) => {
+                                    pha_share.truncate(pha_share.len() / 2);
+                                    facilitator_share.truncate(facilitator_share.len() / 2);
+                                }
+                                Some(PacketCorruption::DuplicateUuid) => {
+                                    packet_uuid = previous_uuid.ok_or_else(|| {
+                                        anyhow!("the first packet cannot duplicate a UUID")
+                                    })?
+                                }
+                                Some(PacketCorruption::DroppedRecord) => {
+                                    after_dropped_record = true;
+                                    dropped_records += 1;
+                                    continue;
+                                }
+                            }
+                            previous_uuid = Some(packet_uuid);
+                            if rejected {
+                                bad_packet_uuids.push(packet_uuid);
+                            }
+
+                            let pha_packet = IngestionDataSharePacket {
+                                uuid: packet_uuid,
+                                encrypted_payload: pha_share,
+                                encryption_key_id: "pha-fake-key-1".to_owned(),
+                                r_pit,
+                                version_configuration: Some("config-1".to_owned()),
+                                device_nonce: None,
+                                sequence_number: Some(index as i64),
+                            };
+
+                            pha_packet.write(pha_packet_writer)?;
 
-    // We nest the closures here to get both packet writers in one scope
-    let pha_packet_file_digest =
-        pha_ingestion_batch.packet_file_writer(|mut pha_packet_writer| {
-            let facilitator_packet_file_digest = facilitator_ingestion_batch.packet_file_writer(
-                |mut facilitator_packet_writer| {
-                    // We need an instance of a libprio server to pick an r_pit.
-                    let fake_server = Server::new(dim as usize, true, pha_key.clone());
-
-                    for _ in 0..packet_count {
-                        // Generate random bit vector
-                        let data = (0..dim)
-                            .map(|_| Field::from(thread_rng.gen_range(0, 2)))
-                            .collect::<Vec<Field>>();
-
-                        for (r, d) in reference_sum.iter_mut().zip(data.iter()) {
-                            *r += *d
+                            let facilitator_packet = IngestionDataSharePacket {
+                                uuid: packet_uuid,
+                                encrypted_payload: facilitator_share,
+                                encryption_key_id: "facilitator-fake-key-1".to_owned(),
+                                r_pit,
+                                version_configuration: Some("config-1".to_owned()),
+                                device_nonce: None,
+                                sequence_number: Some(index as i64),
+                            };
+
+                            facilitator_packet.write(facilitator_packet_writer)?;
                         }
+                        Ok(())
+                    },
+                )?;
+                facilitator_packet_file_digests.push(facilitator_packet_file_digest);
+                Ok(())
+            })?;
+        pha_packet_file_digests.push(pha_packet_file_digest);
+    }
 
-                        let (pha_share, facilitator_share) = client
-                            .encode_simple(&data)
-                            .context("failed to encode data")?;
-
-                        let r_pit = fake_server.choose_eval_at();
-                        let packet_uuid = Uuid::new_v4();
-
-                        let pha_packet = IngestionDataSharePacket {
-                            uuid: packet_uuid,
-                            encrypted_payload: pha_share,
-                            encryption_key_id: "pha-fake-key-1".to_owned(),
-                            r_pit: u32::from(r_pit) as i64,
-                            version_configuration: Some("config-1".to_owned()),
-                            device_nonce: None,
-                        };
-
-                        pha_packet.write(&mut pha_packet_writer)?;
-
-                        let facilitator_packet = IngestionDataSharePacket {
-                            uuid: packet_uuid,
-                            encrypted_payload: facilitator_share,
-                            encryption_key_id: "facilitator-fake-key-1".to_owned(),
-                            r_pit: u32::from(r_pit) as i64,
-                            version_configuration: Some("config-1".to_owned()),
-                            device_nonce: None,
-                        };
-
-                        facilitator_packet.write(&mut facilitator_packet_writer)?;
-                    }
-                    Ok(())
-                },
-            )?;
-
-            let facilitator_header_signature = facilitator_ingestion_batch.put_header(
-                &IngestionHeader {
-                    batch_uuid: *batch_uuid,
-                    name: aggregation_name.to_owned(),
-                    bins: dim,
-                    epsilon,
-                    prime: MODULUS as i64,
-                    number_of_servers: 2,
-                    hamming_weight: None,
-                    batch_start_time,
-                    batch_end_time,
-                    packet_file_digest: facilitator_packet_file_digest.as_ref().to_vec(),
-                },
-                &ingestor_key_pair,
-            )?;
-
-            Ok(facilitator_ingestion_batch.put_signature(&facilitator_header_signature)?)
-        })?;
-
-    let pha_header_signature = pha_ingestion_batch.put_header(
-        &IngestionHeader {
-            batch_uuid: *batch_uuid,
-            name: aggregation_name.to_owned(),
-            bins: dim,
-            epsilon,
-            prime: MODULUS as i64,
-            number_of_servers: 2,
-            hamming_weight: None,
-            batch_start_time,
-            batch_end_time,
-            packet_file_digest: pha_packet_file_digest.as_ref().to_vec(),
-        },
-        &ingestor_key_pair,
-    )?;
-    pha_ingestion_batch.put_signature(&pha_header_signature)?;
-    Ok(reference_sum)
+    for (ingestion_batch, packet_file_digests) in [
+        (&mut pha_ingestion_batch, pha_packet_file_digests),
+        (
+            &mut facilitator_ingestion_batch,
+            facilitator_packet_file_digests,
+        ),
+    ] {
+        let mut packet_file_digests = packet_file_digests
+            .iter()
+            .map(|digest| digest.as_ref().to_vec())
+            .collect::<Vec<_>>();
+        let (packet_file_digest, packet_file_shard_digests) = match parts {
+            Some(_) => (vec![], packet_file_digests),
+            None => (packet_file_digests.remove(0), vec![]),
+        };
+        let header_signature = ingestion_batch.put_header(
+            &IngestionHeader {
+                batch_uuid: *batch_uuid,
+                name: aggregation_name.to_string(),
+                bins: dim,
+                epsilon,
+                prime: MODULUS as i64,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time,
+                batch_end_time,
+                packet_file_digest,
+                packet_file_shard_digests,
+                packet_count: Some((packet_count - dropped_records) as u64),
+            },
+            &ingestor_key_pair,
+        )?;
+        ingestion_batch.put_signature(&header_signature)?;
+    }
+    Ok((reference_sum, bad_packet_uuids))
+}
+
+/// Writes the packet file of the batch, or the part of it with the provided
+/// index if there is one, with the provided operation.
+fn write_packet_file<F>(
+    ingestion_batch: &mut BatchWriter<'_, IngestionHeader, IngestionDataSharePacket>,
+    part_index: Option<usize>,
+    operation: F,
+) -> Result<Sha256Digest>
+where
+    F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
+{
+    match part_index {
+        Some(index) => ingestion_batch.packet_file_shard_writer(index, operation),
+        None => ingestion_batch.packet_file_writer(operation),
+    }
+}
+
+/// Generates an ingestion batch identified by the provided batch ID,
+/// aggregation name and date. See generate_ingestion_sample.
+#[allow(clippy::too_many_arguments)] // Grandfathered in
+pub fn generate_ingestion_sample_from_parts(
+    pha_transport: &mut dyn Transport,
+    facilitator_transport: &mut dyn Transport,
+    batch_uuid: &Uuid,
+    instance_name: Option<&str>,
+    aggregation_name: &str,
+    date: &BatchDate,
+    pha_key: &PrivateKey,
+    facilitator_key: &PrivateKey,
+    ingestor_key: &[u8],
+    dim: i32,
+    packet_count: usize,
+    epsilon: f64,
+    batch_start_time: i64,
+    batch_end_time: i64,
+) -> Result<Vec<Field>> {
+    generate_ingestion_sample(
+        pha_transport,
+        facilitator_transport,
+        instance_name,
+        &BatchIdentity::new(AggregationName::new(aggregation_name)?, *date, *batch_uuid),
+        pha_key,
+        facilitator_key,
+        ingestor_key,
+        dim,
+        packet_count,
+        epsilon,
+        batch_start_time,
+        batch_end_time,
+    )
 }
 
 #[cfg(test)]
@@ -167,6 +422,35 @@ mod tests {
         },
         transport::LocalFileTransport,
     };
+    use chrono::NaiveDateTime;
+
+    #[test]
+    fn path_traversal_rejected() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut pha_transport = LocalFileTransport::new(tempdir.path().join("pha"));
+        let mut facilitator_transport = LocalFileTransport::new(tempdir.path().join("facilitator"));
+
+        let res = generate_ingestion_sample_from_parts(
+            &mut pha_transport,
+            &mut facilitator_transport,
+            &Uuid::new_v4(),
+            None,
+            "../escaped",
+            &BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        );
+        assert!(res.is_err(), "traversal in aggregation name was accepted");
+        // Nothing should have been written anywhere, inside or outside of the
+        // transports' directories.
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 0);
+    }
 
     #[test]
     #[ignore]
@@ -177,12 +461,13 @@ mod tests {
         let mut facilitator_transport =
             LocalFileTransport::new(tempdir.path().to_path_buf().join("pha"));
 
-        let res = generate_ingestion_sample(
+        let res = generate_ingestion_sample_from_parts(
             &mut pha_transport,
             &mut facilitator_transport,
             &batch_uuid,
+            None,
             "fake-aggregation",
-            &NaiveDateTime::from_timestamp(1234567890, 654321),
+            &BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321)),
             &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
             &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
             &default_ingestor_private_key_raw(),
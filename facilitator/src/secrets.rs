@@ -0,0 +1,446 @@
+use crate::{transport::basic_runtime, Error};
+use anyhow::{anyhow, Context, Result};
+use hyper::{body, Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use rusoto_core::{signature::SignedRequest, Region};
+use serde::Deserialize;
+use std::{fmt, path::PathBuf, str::FromStr};
+use zeroize::Zeroizing;
+
+/// The metadata server from which GcpSecretManagerSource gets an access token
+/// for the instance's service account, unless GCE_METADATA_HOST names another.
+const GCE_METADATA_HOST: &str = "metadata.google.internal";
+
+/// A secret, such as a private key, kept outside the configuration that refers
+/// to it. Nothing is read until secret is called, and each call reads the
+/// secret afresh, so that a rotated secret is picked up.
+pub trait SecretSource: fmt::Debug {
+    /// Returns the raw content of the secret.
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+/// Parses a secret reference into the SecretSource it names. References are
+/// one of:
+///
+///   - "env:{variable}", the value of an environment variable
+///   - "file:{path}", the content of a file
+///   - "aws-secretsmanager://{region}/{secret-id}", the current version of a
+///     secret in AWS Secrets Manager, identified by name or ARN
+///   - "gcp-secretmanager://projects/{project}/secrets/{secret}", the latest
+///     version of a secret in GCP Secret Manager, or, if followed by
+///     "/versions/{version}", that version
+///
+/// Fails with Error::MalformedConfigError if the reference is none of these.
+pub fn secret_source(reference: &str) -> Result<Box<dyn SecretSource>, Error> {
+    let malformed = |reason: &str| {
+        Error::MalformedConfigError(format!("secret reference {:?} {}", reference, reason))
+    };
+    if let Some(variable) = reference.strip_prefix("env:") {
+        if variable.is_empty() {
+            return Err(malformed("names no variable"));
+        }
+        Ok(Box::new(EnvSecretSource::new(variable)))
+    } else if let Some(path) = reference.strip_prefix("file:") {
+        if path.is_empty() {
+            return Err(malformed("names no file"));
+        }
+        Ok(Box::new(FileSecretSource::new(path)))
+    } else if let Some(rest) = reference.strip_prefix("aws-secretsmanager://") {
+        match rest.split_once('/') {
+            Some((region, secret_id)) if !secret_id.is_empty() => {
+                let region = Region::from_str(region)
+                    .map_err(|e| malformed(&format!("has invalid region: {}", e)))?;
+                Ok(Box::new(AwsSecretsManagerSource::new(region, secret_id)))
+            }
+            _ => Err(malformed(
+                "must be like \"aws-secretsmanager://{region}/{secret-id}\"",
+            )),
+        }
+    } else if let Some(name) = reference.strip_prefix("gcp-secretmanager://") {
+        GcpSecretManagerSource::new(name)
+            .map(|source| Box::new(source) as Box<dyn SecretSource>)
+            .map_err(|e| malformed(&e))
+    } else {
+        Err(malformed(
+            "must begin with env:, file:, aws-secretsmanager:// or gcp-secretmanager://",
+        ))
+    }
+}
+
+/// A secret held in an environment variable.
+#[derive(Clone, Debug)]
+pub struct EnvSecretSource {
+    variable: String,
+}
+
+impl EnvSecretSource {
+    pub fn new(variable: &str) -> EnvSecretSource {
+        EnvSecretSource {
+            variable: variable.to_owned(),
+        }
+    }
+}
+
+impl SecretSource for EnvSecretSource {
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let value = std::env::var_os(&self.variable)
+            .ok_or_else(|| anyhow!("environment variable {} is not set", self.variable))?
+            .into_string()
+            .map_err(|_| anyhow!("environment variable {} is not UTF-8", self.variable))?;
+        Ok(Zeroizing::new(value.into_bytes()))
+    }
+}
+
+/// A secret held in a file, e.g. one mounted from a Kubernetes secret.
+#[derive(Clone, Debug)]
+pub struct FileSecretSource {
+    path: PathBuf,
+}
+
+impl FileSecretSource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileSecretSource {
+        FileSecretSource { path: path.into() }
+    }
+}
+
+impl SecretSource for FileSecretSource {
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(std::fs::read(&self.path).with_context(
+            || format!("failed to read secret from {}", self.path.display()),
+        )?))
+    }
+}
+
+/// The current version of a secret in AWS Secrets Manager. Credentials are
+/// found the same way as for S3Transport, from the environment,
+/// ~/.aws/credentials or the instance's role.
+#[derive(Clone, Debug)]
+pub struct AwsSecretsManagerSource {
+    region: Region,
+    secret_id: String,
+}
+
+#[derive(Deserialize)]
+struct GetSecretValueResponse {
+    #[serde(rename = "SecretString")]
+    secret_string: Option<String>,
+    #[serde(rename = "SecretBinary")]
+    secret_binary: Option<String>,
+}
+
+impl AwsSecretsManagerSource {
+    /// Creates a source for the secret with the provided name or ARN in the
+    /// region.
+    pub fn new(region: Region, secret_id: &str) -> AwsSecretsManagerSource {
+        AwsSecretsManagerSource {
+            region,
+            secret_id: secret_id.to_owned(),
+        }
+    }
+}
+
+impl SecretSource for AwsSecretsManagerSource {
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let failed = |e: String| {
+            anyhow!(
+                "failed to get secret {} from AWS Secrets Manager in {}: {}",
+                self.secret_id,
+                self.region.name(),
+                e
+            )
+        };
+        // There is no rusoto crate for Secrets Manager in our dependencies,
+        // but its API is a single signed JSON request.
+        // https://docs.aws.amazon.com/secretsmanager/latest/apireference/API_GetSecretValue.html
+        let mut request = SignedRequest::new("POST", "secretsmanager", &self.region, "/");
+        request.set_content_type("application/x-amz-json-1.1".to_owned());
+        request.add_header("x-amz-target", "secretsmanager.GetSecretValue");
+        request.set_payload(Some(serde_json::to_vec(
+            &serde_json::json!({ "SecretId": self.secret_id }),
+        )?));
+
+        let mut runtime = basic_runtime()?;
+        let response = runtime
+            .block_on(async {
+                let mut response = rusoto_core::Client::shared()
+                    .sign_and_dispatch(request)
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                response.buffer().await.map_err(|e| e.to_string())
+            })
+            .map_err(failed)?;
+        let body = Zeroizing::new(response.body.to_vec());
+        if !response.status.is_success() {
+            return Err(failed(format!(
+                "HTTP status {}: {}",
+                response.status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        parse_aws_secret_value(&body).map_err(failed)
+    }
+}
+
+/// Returns the secret in a GetSecretValue response, which holds either a
+/// string or base64 encoded binary.
+fn parse_aws_secret_value(body: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    let response: GetSecretValueResponse =
+        serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    match (response.secret_string, response.secret_binary) {
+        (Some(string), _) => Ok(Zeroizing::new(string.into_bytes())),
+        (None, Some(binary)) => Ok(Zeroizing::new(
+            base64::decode(Zeroizing::new(binary).as_bytes()).map_err(|e| e.to_string())?,
+        )),
+        (None, None) => Err("response holds no secret".to_owned()),
+    }
+}
+
+/// A version of a secret in GCP Secret Manager, accessed with a token for the
+/// service account of the GCE instance or GKE workload we run as.
+#[derive(Clone, Debug)]
+pub struct GcpSecretManagerSource {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct AccessSecretVersionResponse {
+    payload: SecretPayload,
+}
+
+#[derive(Deserialize)]
+struct SecretPayload {
+    data: String,
+}
+
+impl GcpSecretManagerSource {
+    /// Creates a source for the secret version with the provided resource
+    /// name, "projects/{project}/secrets/{secret}/versions/{version}". The
+    /// "/versions/{version}" suffix may be left off to use the latest version.
+    pub fn new(name: &str) -> Result<GcpSecretManagerSource, String> {
+        let segments: Vec<&str> = name.split('/').collect();
+        let name = match segments[..] {
+            ["projects", project, "secrets", secret]
+                if !project.is_empty() && !secret.is_empty() =>
+            {
+                format!("{}/versions/latest", name)
+            }
+            ["projects", project, "secrets", secret, "versions", version]
+                if !project.is_empty() && !secret.is_empty() && !version.is_empty() =>
+            {
+                name.to_owned()
+            }
+            _ => {
+                return Err(
+                    "must be like \"gcp-secretmanager://projects/{project}/secrets/{secret}\""
+                        .to_owned(),
+                )
+            }
+        };
+        Ok(GcpSecretManagerSource { name })
+    }
+}
+
+impl SecretSource for GcpSecretManagerSource {
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let failed = |e: String| {
+            anyhow!(
+                "failed to get secret {} from GCP Secret Manager: {}",
+                self.name,
+                e
+            )
+        };
+        let mut runtime = basic_runtime()?;
+        let metadata_host =
+            std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| GCE_METADATA_HOST.to_owned());
+        let token = Zeroizing::new(
+            runtime
+                .block_on(http_get(
+                    &format!(
+                        "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+                        metadata_host
+                    ),
+                    ("Metadata-Flavor", "Google"),
+                ))
+                .map_err(|e| failed(format!("failed to get access token: {}", e)))?,
+        );
+        let token: AccessTokenResponse = serde_json::from_slice(&token)
+            .map_err(|e| failed(format!("malformed access token: {}", e)))?;
+        let token = Zeroizing::new(token.access_token);
+
+        let body = Zeroizing::new(
+            runtime
+                .block_on(http_get(
+                    &format!(
+                        "https://secretmanager.googleapis.com/v1/{}:access",
+                        self.name
+                    ),
+                    ("Authorization", &format!("Bearer {}", *token)),
+                ))
+                .map_err(failed)?,
+        );
+        parse_gcp_secret_payload(&body).map_err(failed)
+    }
+}
+
+/// Returns the secret in an AccessSecretVersion response, which holds it
+/// base64 encoded.
+fn parse_gcp_secret_payload(body: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    let response: AccessSecretVersionResponse =
+        serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let data = Zeroizing::new(response.payload.data);
+    Ok(Zeroizing::new(
+        base64::decode(data.as_bytes()).map_err(|e| e.to_string())?,
+    ))
+}
+
+/// Fetches url with the provided header, returning the body if the response
+/// status is 2xx.
+async fn http_get(url: &str, header: (&str, &str)) -> Result<Vec<u8>, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("{}", e))?;
+    let request = Request::get(uri)
+        .header(header.0, header.1)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let content = body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("HTTP status {}", status));
+    }
+    Ok(content.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_secret() {
+        let variable = "FACILITATOR_TEST_ENV_SECRET";
+        std::env::set_var(variable, "c2VjcmV0");
+        let source = secret_source(&format!("env:{}", variable)).unwrap();
+        assert_eq!(*source.secret().unwrap(), b"c2VjcmV0");
+
+        // The variable is read whenever the secret is, not when the source is
+        // created.
+        std::env::set_var(variable, "cm90YXRlZA==");
+        assert_eq!(*source.secret().unwrap(), b"cm90YXRlZA==");
+        std::env::remove_var(variable);
+        assert_eq!(
+            source.secret().unwrap_err().to_string(),
+            "environment variable FACILITATOR_TEST_ENV_SECRET is not set"
+        );
+    }
+
+    #[test]
+    fn file_secret() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("secret");
+        let source = FileSecretSource::new(&path);
+        assert!(source.secret().is_err());
+
+        std::fs::write(&path, b"\x00binary\xff").unwrap();
+        assert_eq!(*source.secret().unwrap(), b"\x00binary\xff");
+        let source = secret_source(&format!("file:{}", path.display())).unwrap();
+        assert_eq!(*source.secret().unwrap(), b"\x00binary\xff");
+    }
+
+    #[test]
+    fn secret_references() {
+        for reference in &[
+            "env:SOME_VARIABLE",
+            "file:/secrets/key",
+            "aws-secretsmanager://us-west-2/prod/ecies-key",
+            "aws-secretsmanager://us-west-2/arn:aws:secretsmanager:us-west-2:123:secret:key",
+            "gcp-secretmanager://projects/prio/secrets/ecies-key",
+            "gcp-secretmanager://projects/prio/secrets/ecies-key/versions/3",
+        ] {
+            secret_source(reference).unwrap();
+        }
+        for reference in &[
+            "",
+            "SOME_VARIABLE",
+            "env:",
+            "file:",
+            "s3://us-west-2/bucket",
+            "aws-secretsmanager://us-west-2",
+            "aws-secretsmanager://us-west-2/",
+            "aws-secretsmanager://not-a-region/key",
+            "gcp-secretmanager://projects/prio",
+            "gcp-secretmanager://projects//secrets/ecies-key",
+            "gcp-secretmanager://projects/prio/secrets/ecies-key/versions/",
+            "gcp-secretmanager://projects/prio/secrets/ecies-key/versions/3/extra",
+        ] {
+            match secret_source(reference) {
+                Err(Error::MalformedConfigError(_)) => (),
+                v => panic!("unexpected result for {:?}: {:?}", reference, v),
+            }
+        }
+
+        assert_eq!(
+            GcpSecretManagerSource::new("projects/prio/secrets/ecies-key")
+                .unwrap()
+                .name,
+            "projects/prio/secrets/ecies-key/versions/latest"
+        );
+    }
+
+    #[test]
+    fn parse_cloud_responses() {
+        assert_eq!(
+            *parse_aws_secret_value(br#"{"Name": "key", "SecretString": "c2VjcmV0"}"#).unwrap(),
+            b"c2VjcmV0"
+        );
+        assert_eq!(
+            *parse_aws_secret_value(br#"{"Name": "key", "SecretBinary": "AP8="}"#).unwrap(),
+            b"\x00\xff"
+        );
+        assert!(parse_aws_secret_value(br#"{"Name": "key"}"#).is_err());
+        assert!(parse_aws_secret_value(br#"{"SecretBinary": "not base64!"}"#).is_err());
+
+        assert_eq!(
+            *parse_gcp_secret_payload(
+                br#"{"name": "projects/1/secrets/key/versions/1", "payload": {"data": "AP8="}}"#
+            )
+            .unwrap(),
+            b"\x00\xff"
+        );
+        assert!(parse_gcp_secret_payload(br#"{"name": "x"}"#).is_err());
+    }
+
+    /// Reads the secret referenced by the environment variable and compares
+    /// it to the value in another, both of which must be set when the
+    /// cloud-tests feature is enabled, e.g.:
+    ///
+    /// FACILITATOR_TEST_AWS_SECRET=aws-secretsmanager://us-west-2/test-secret
+    /// FACILITATOR_TEST_AWS_SECRET_VALUE=expected-value
+    #[cfg(feature = "cloud-tests")]
+    fn check_cloud_secret(variable: &str) {
+        let reference = std::env::var(variable)
+            .unwrap_or_else(|_| panic!("{} must be set for cloud tests", variable));
+        let expected = std::env::var(format!("{}_VALUE", variable))
+            .unwrap_or_else(|_| panic!("{}_VALUE must be set for cloud tests", variable));
+        let secret = secret_source(&reference).unwrap().secret().unwrap();
+        assert_eq!(*secret, expected.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "cloud-tests")]
+    fn aws_secrets_manager_secret() {
+        check_cloud_secret("FACILITATOR_TEST_AWS_SECRET");
+    }
+
+    #[test]
+    #[cfg(feature = "cloud-tests")]
+    fn gcp_secret_manager_secret() {
+        check_cloud_secret("FACILITATOR_TEST_GCP_SECRET");
+    }
+}
@@ -0,0 +1,183 @@
+use prio::{encrypt::PrivateKey, finite_field::Field, server::Server};
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// ServerPool hands out libprio Servers for one share processor, reusing them
+/// across batches and worker threads so that the tables a Server allocates
+/// for its dimension are only allocated once per concurrent user rather than
+/// once per batch. Servers are returned to the pool when the PooledServer
+/// guard is dropped, so a ServerPool may be shared between threads.
+pub struct ServerPool {
+    is_first: bool,
+    key_id: Option<String>,
+    private_key: PrivateKey,
+    servers: Mutex<HashMap<usize, Vec<Server>>>,
+}
+
+impl ServerPool {
+    /// Creates an empty ServerPool whose Servers decrypt shares with the
+    /// provided key and act as the first server if is_first is true.
+    pub fn new(is_first: bool, private_key: PrivateKey) -> ServerPool {
+        ServerPool {
+            is_first,
+            key_id: None,
+            private_key,
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates an empty ServerPool like new, for a key selected from
+    /// intake::EciesKeys under the provided identifier. A BatchIntaker only
+    /// uses a pool whose identifier matches that of the key it selected.
+    pub fn with_key_id(is_first: bool, key_id: &str, private_key: PrivateKey) -> ServerPool {
+        ServerPool {
+            key_id: Some(key_id.to_owned()),
+            ..ServerPool::new(is_first, private_key)
+        }
+    }
+
+    /// Returns the identifier of the key with which this pool's Servers
+    /// decrypt shares, if it was created with with_key_id.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Returns the key with which this pool's Servers decrypt shares.
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    /// Returns true if this pool's Servers act as the first server.
+    pub fn is_first(&self) -> bool {
+        self.is_first
+    }
+
+    /// Returns a Server for the provided dimension, reusing one returned to
+    /// the pool earlier if there is one. The Server's accumulator is zeroed.
+    pub fn acquire(&self, dimension: usize) -> PooledServer<'_> {
+        let server = self
+            .servers
+            .lock()
+            .unwrap()
+            .get_mut(&dimension)
+            .and_then(Vec::pop)
+            .unwrap_or_else(|| Server::new(dimension, self.is_first, self.private_key.clone()));
+        PooledServer {
+            pool: self,
+            dimension,
+            server: Some(server),
+        }
+    }
+
+    /// Returns the number of Servers of the provided dimension waiting in the
+    /// pool to be reused.
+    pub fn idle_servers(&self, dimension: usize) -> usize {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(&dimension)
+            .map_or(0, Vec::len)
+    }
+
+    fn release(&self, dimension: usize, mut server: Server) {
+        // libprio has no way to reset a Server, but its only state that
+        // outlives a call is the accumulator, which we can cancel out. The
+        // validation memory is scratch space that is overwritten on each use.
+        let negated_total_shares: Vec<Field> = server
+            .total_shares()
+            .iter()
+            .map(|share| Field::from(0) - *share)
+            .collect();
+        server.merge_total_shares(&negated_total_shares);
+
+        // Don't let a panic elsewhere while the lock was held keep us from
+        // returning the server.
+        let mut servers = match self.servers.lock() {
+            Ok(servers) => servers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        servers.entry(dimension).or_default().push(server);
+    }
+}
+
+/// A Server acquired from a ServerPool, which is returned to the pool when
+/// this is dropped.
+pub struct PooledServer<'p> {
+    pool: &'p ServerPool,
+    dimension: usize,
+    server: Option<Server>,
+}
+
+impl Deref for PooledServer<'_> {
+    type Target = Server;
+
+    fn deref(&self) -> &Server {
+        self.server.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledServer<'_> {
+    fn deref_mut(&mut self) -> &mut Server {
+        self.server.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledServer<'_> {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            self.pool.release(self.dimension, server);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::DEFAULT_PHA_ECIES_PRIVATE_KEY;
+
+    fn pool() -> ServerPool {
+        ServerPool::new(
+            true,
+            PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+        )
+    }
+
+    #[test]
+    fn reuses_servers() {
+        let pool = pool();
+        assert_eq!(pool.idle_servers(10), 0);
+
+        let first = pool.acquire(10);
+        let second = pool.acquire(10);
+        let other_dimension = pool.acquire(20);
+        drop(first);
+        drop(second);
+        drop(other_dimension);
+        assert_eq!(pool.idle_servers(10), 2);
+        assert_eq!(pool.idle_servers(20), 1);
+
+        let _reused = pool.acquire(10);
+        assert_eq!(pool.idle_servers(10), 1);
+        let _reused_again = pool.acquire(10);
+        assert_eq!(pool.idle_servers(10), 0);
+        let _new = pool.acquire(10);
+        assert_eq!(pool.idle_servers(10), 0);
+    }
+
+    #[test]
+    fn resets_accumulator() {
+        let pool = pool();
+        {
+            let mut server = pool.acquire(4);
+            let shares: Vec<Field> = [1, 0, 7, 1].iter().map(|s| Field::from(*s)).collect();
+            server.merge_total_shares(&shares);
+            assert_eq!(server.total_shares(), shares.as_slice());
+        }
+
+        let server = pool.acquire(4);
+        assert!(server.total_shares().iter().all(|s| *s == 0));
+    }
+}
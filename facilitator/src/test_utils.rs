@@ -1,7 +1,10 @@
+use crate::batch::Clock;
+use chrono::{Duration, NaiveDateTime};
 use ring::signature::{
     EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
     ECDSA_P256_SHA256_FIXED_SIGNING,
 };
+use std::sync::Mutex;
 
 /// Default keys used in testing and for sample data generation. These are
 /// stored in base64 to make it convenient to copy/paste them into other tools
@@ -76,3 +79,35 @@ pub fn default_facilitator_signing_public_key() -> UnparsedPublicKey<Vec<u8>> {
 pub fn default_pha_signing_private_key() -> Vec<u8> {
     base64::decode(DEFAULT_PHA_SIGNING_PRIVATE_KEY).unwrap()
 }
+
+/// A Clock whose time only changes when a test says so, so that time-dependent
+/// behavior can be tested deterministically.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<NaiveDateTime>,
+}
+
+impl MockClock {
+    pub fn new(now: NaiveDateTime) -> MockClock {
+        MockClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Sets the time the clock returns.
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Moves the time the clock returns forward by the provided duration,
+    /// which may be negative.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock().unwrap()
+    }
+}
@@ -1,20 +1,43 @@
 use crate::Error;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use derivative::Derivative;
+use hyper::{
+    body::{Bytes, HttpBody},
+    client::HttpConnector,
+    header::LOCATION,
+    Body, Client, Method, Request, Response, StatusCode, Uri,
+};
 use hyper_rustls::HttpsConnector;
-use rusoto_core::{credential::DefaultCredentialsProvider, ByteStream, Region};
+use ring::{
+    aead,
+    rand::{SecureRandom, SystemRandom},
+};
+use rusoto_core::{
+    credential::DefaultCredentialsProvider, request::HttpDispatchError, ByteStream, Region,
+    RusotoError,
+};
 use rusoto_s3::{
-    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, S3Client, UploadPartRequest, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadError, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CreateMultipartUploadError,
+    CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError, GetObjectRequest,
+    HeadObjectError, HeadObjectRequest, ListObjectsV2Error, ListObjectsV2Request, S3Client,
+    UploadPartError, UploadPartRequest, S3,
 };
 use std::{
     boxed::Box,
-    fs::{create_dir_all, File},
-    io::{Read, Write},
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::{create_dir_all, metadata, read_dir, remove_file, File},
+    io::{stdin, stdout, Cursor, ErrorKind, Read, Stdout, Write},
     mem,
     path::{PathBuf, MAIN_SEPARATOR},
     pin::Pin,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt},
@@ -47,13 +70,82 @@ impl<T: TransportWriter + ?Sized> TransportWriter for Box<T> {
 
 /// A transport moves object in and out of some data store, such as a cloud
 /// object store like Amazon S3, or local files, or buffers in memory.
-pub trait Transport {
+pub trait Transport: Send {
     /// Returns an std::io::Read instance from which the contents of the value
     /// of the provided key may be read.
     fn get(&self, key: &str) -> Result<Box<dyn Read>>;
     /// Returns an std::io::Write instance into which the contents of the value
     /// may be written.
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>>;
+    /// Returns the keys of all the values whose keys begin with the provided
+    /// prefix, which is matched as a string and need not end in "/". The
+    /// default implementation fails, for transports that cannot list.
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Err(anyhow!(
+            "listing keys with prefix {} is not supported by this transport",
+            prefix
+        ))
+    }
+    /// Deletes the value of the provided key. Deleting a key that has no value
+    /// is not an error. The default implementation fails, for transports that
+    /// cannot delete.
+    fn delete(&mut self, key: &str) -> Result<()> {
+        Err(anyhow!(
+            "deleting key {} is not supported by this transport",
+            key
+        ))
+    }
+    /// Returns the size in bytes of the value of the provided key, if the
+    /// transport can tell without fetching the value. This is only a hint: the
+    /// value may change before it is fetched. The default implementation
+    /// returns None.
+    fn size(&self, _key: &str) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        (**self).get(key)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        (**self).put(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        (**self).list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        (**self).delete(key)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        (**self).size(key)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        (**self).get(key)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        (**self).put(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        (**self).list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        (**self).delete(key)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        (**self).size(key)
+    }
 }
 
 /// A transport implementation backed by the local filesystem.
@@ -74,18 +166,60 @@ impl LocalFileTransport {
     fn relative_path(key: &str) -> PathBuf {
         PathBuf::from(key.replace("/", &MAIN_SEPARATOR.to_string()))
     }
+
+    /// Returns the path of the file in which the value of the key is stored.
+    pub(crate) fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(LocalFileTransport::relative_path(key))
+    }
+
+    /// Object stores treat keys that differ only by case as distinct, but on
+    /// case-insensitive filesystems (the default on macOS and Windows) they
+    /// would refer to the same file. To behave the same everywhere, this
+    /// returns Error::CaseCollision if any component of the key differs only
+    /// by case from an existing file or directory.
+    pub(crate) fn check_case_collisions(&self, key: &str) -> Result<()> {
+        let mut directory = self.directory.clone();
+        let mut existing_key = Vec::new();
+        for component in key.split('/') {
+            let entries = match read_dir(&directory) {
+                Ok(entries) => entries,
+                // Nothing can collide with the remaining components
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("listing {}", directory.display()))
+                }
+            };
+            let lowercase_component = component.to_lowercase();
+            for entry in entries {
+                let entry = entry.with_context(|| format!("listing {}", directory.display()))?;
+                let name = entry.file_name();
+                let name = match name.to_str() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if name != component && name.to_lowercase() == lowercase_component {
+                    existing_key.push(name.to_owned());
+                    return Err(Error::CaseCollision(key.to_owned(), existing_key.join("/")).into());
+                }
+            }
+            existing_key.push(component.to_owned());
+            directory.push(component);
+        }
+        Ok(())
+    }
 }
 
 impl Transport for LocalFileTransport {
     fn get(&self, key: &str) -> Result<Box<dyn Read>> {
-        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        let path = self.path(key);
         let f =
             File::open(path.as_path()).with_context(|| format!("opening {}", path.display()))?;
         Ok(Box::new(f))
     }
 
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
-        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        self.check_case_collisions(key)?;
+        let path = self.path(key);
         if let Some(parent) = path.parent() {
             create_dir_all(parent)
                 .with_context(|| format!("creating parent directories {}", parent.display()))?;
@@ -94,6 +228,68 @@ impl Transport for LocalFileTransport {
             File::create(path.as_path()).with_context(|| format!("creating {}", path.display()))?;
         Ok(Box::new(f))
     }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Only the directory the prefix ends in needs to be walked.
+        let mut pending_directories = vec![match prefix.rfind('/') {
+            Some(index) => prefix[..index].to_owned(),
+            None => String::new(),
+        }];
+        let mut keys = Vec::new();
+        while let Some(directory_key) = pending_directories.pop() {
+            let directory = self.path(&directory_key);
+            let entries = match read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("listing {}", directory.display()))
+                }
+            };
+            for entry in entries {
+                let entry = entry.with_context(|| format!("listing {}", directory.display()))?;
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                let key = if directory_key.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", directory_key, name)
+                };
+                if !key.starts_with(prefix) {
+                    continue;
+                }
+                if entry
+                    .file_type()
+                    .with_context(|| format!("listing {}", directory.display()))?
+                    .is_dir()
+                {
+                    pending_directories.push(key);
+                } else {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        match remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        let path = self.path(key);
+        let metadata =
+            metadata(&path).with_context(|| format!("getting metadata of {}", path.display()))?;
+        Ok(Some(metadata.len()))
+    }
 }
 
 impl TransportWriter for File {
@@ -108,11 +304,151 @@ impl TransportWriter for File {
     }
 }
 
+/// Where a StreamTransport reads or writes the value of a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// The process's standard input, which can only be read once.
+    Stdin,
+    /// The process's standard output.
+    Stdout,
+    /// A file, which is created or truncated when written.
+    Path(PathBuf),
+}
+
+impl Stream {
+    /// Interprets a command line argument naming an input: "-" is standard
+    /// input and anything else a path.
+    pub fn input(arg: &str) -> Stream {
+        match arg {
+            "-" => Stream::Stdin,
+            path => Stream::Path(PathBuf::from(path)),
+        }
+    }
+
+    /// Interprets a command line argument naming an output: "-" is standard
+    /// output and anything else a path.
+    pub fn output(arg: &str) -> Stream {
+        match arg {
+            "-" => Stream::Stdout,
+            path => Stream::Path(PathBuf::from(path)),
+        }
+    }
+}
+
+/// A degenerate transport that maps a fixed set of keys onto standard input,
+/// standard output or particular files, so that a single batch can be piped
+/// through the facilitator without a bucket or directory to hold it. Getting
+/// or putting any key that was not mapped fails.
+///
+/// Bytes written to standard output cannot be taken back, so cancelling an
+/// upload to it does not retract what was already written. Consumers must rely
+/// on the process's exit status to tell whether the output is complete.
+#[derive(Debug, Default)]
+pub struct StreamTransport {
+    streams: BTreeMap<String, Stream>,
+    stdin_read: AtomicBool,
+}
+
+impl StreamTransport {
+    pub fn new() -> StreamTransport {
+        StreamTransport::default()
+    }
+
+    /// Maps the key onto the stream, replacing any earlier mapping of the key.
+    pub fn map(&mut self, key: &str, stream: Stream) {
+        self.streams.insert(key.to_owned(), stream);
+    }
+
+    fn stream(&self, key: &str) -> Result<&Stream> {
+        self.streams
+            .get(key)
+            .with_context(|| format!("no stream is mapped to key {}", key))
+    }
+}
+
+impl Transport for StreamTransport {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        match self.stream(key)? {
+            Stream::Stdin => {
+                if self.stdin_read.swap(true, Ordering::SeqCst) {
+                    return Err(anyhow!(
+                        "standard input was already read, so key {} cannot be",
+                        key
+                    ));
+                }
+                Ok(Box::new(stdin()))
+            }
+            Stream::Stdout => Err(anyhow!(
+                "key {} is mapped to standard output and cannot be read",
+                key
+            )),
+            Stream::Path(path) => {
+                let f = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+                Ok(Box::new(f))
+            }
+        }
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        match self.stream(key)? {
+            Stream::Stdin => Err(anyhow!(
+                "key {} is mapped to standard input and cannot be written",
+                key
+            )),
+            Stream::Stdout => Ok(Box::new(StdoutWriter(stdout()))),
+            Stream::Path(path) => {
+                let f =
+                    File::create(path).with_context(|| format!("creating {}", path.display()))?;
+                Ok(Box::new(f))
+            }
+        }
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        match self.stream(key)? {
+            Stream::Path(path) => {
+                let metadata = metadata(path)
+                    .with_context(|| format!("getting metadata of {}", path.display()))?;
+                Ok(Some(metadata.len()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+struct StdoutWriter(Stdout);
+
+impl Write for StdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.0.flush()
+    }
+}
+
+impl TransportWriter for StdoutWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.0.flush().context("failed to flush standard output")
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        // What was written cannot be retracted; see StreamTransport.
+        Ok(())
+    }
+}
+
 /// Constructs a basic runtime suitable for use in our single threaded context
-fn basic_runtime() -> Result<Runtime> {
+pub(crate) fn basic_runtime() -> Result<Runtime> {
     Ok(Builder::new().basic_scheduler().enable_all().build()?)
 }
 
+/// The smallest part that may be uploaded to S3 in a multipart upload, other
+/// than the last one. 5 MB is the minimum required by Amazon.
+/// https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
+pub(crate) const MINIMUM_UPLOAD_PART_SIZE: usize = 5_242_880;
+
 /// Implementation of Transport that reads and writes objects from Amazon S3.
 pub struct S3Transport {
     region: Region,
@@ -154,7 +490,7 @@ impl S3Transport {
         })
     }
 
-    fn new_with_client(
+    pub(crate) fn new_with_client(
         region: Region,
         bucket: String,
         client_provider: fn(&Region) -> S3Client,
@@ -165,6 +501,14 @@ impl S3Transport {
             client_provider,
         }
     }
+
+    pub(crate) fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub(crate) fn client(&self) -> S3Client {
+        (self.client_provider)(&self.region)
+    }
 }
 
 impl Transport for S3Transport {
@@ -189,12 +533,71 @@ impl Transport for S3Transport {
             self.region.clone(),
             self.bucket.to_owned(),
             key.to_string(),
-            // Set buffer size to 5 MB, which is the minimum required by Amazon
-            // https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
-            5_242_880,
+            MINIMUM_UPLOAD_PART_SIZE,
             self.client_provider,
         )?))
     }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut runtime = basic_runtime()?;
+        let client = (self.client_provider)(&self.region);
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        // ListObjectsV2 returns at most 1000 keys at a time, so follow the
+        // continuation tokens until we have them all.
+        loop {
+            let list_output = runtime
+                .block_on(client.list_objects_v2(ListObjectsV2Request {
+                    bucket: self.bucket.to_owned(),
+                    prefix: Some(prefix.to_owned()),
+                    continuation_token,
+                    ..Default::default()
+                }))
+                .context("error listing S3 objects")?;
+            keys.extend(
+                list_output
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key),
+            );
+            match list_output.next_continuation_token {
+                Some(token) if list_output.is_truncated == Some(true) => {
+                    continuation_token = Some(token)
+                }
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        let mut runtime = basic_runtime()?;
+        let client = (self.client_provider)(&self.region);
+        runtime
+            .block_on(client.delete_object(DeleteObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key: key.to_string(),
+                ..Default::default()
+            }))
+            .context("error deleting S3 object")?;
+        Ok(())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        let mut runtime = basic_runtime()?;
+        let client = (self.client_provider)(&self.region);
+        let head_output = runtime
+            .block_on(client.head_object(HeadObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key: key.to_string(),
+                ..Default::default()
+            }))
+            .context("error getting S3 object metadata")?;
+        // S3 reports sizes as signed integers, which are never negative.
+        Ok(head_output
+            .content_length
+            .and_then(|length| u64::try_from(length).ok()))
+    }
 }
 
 /// StreamingBodyReader is an std::io::Read implementation which reads from the
@@ -401,128 +804,1940 @@ impl TransportWriter for MultipartUploadWriter {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rusoto_core::signature::SignedRequest;
-    use rusoto_mock::{
-        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
-    };
-    use rusoto_s3::CreateMultipartUploadError;
-    use std::io::Read;
-
-    #[test]
-    fn roundtrip_file_transport() {
-        let tempdir = tempfile::TempDir::new().unwrap();
-        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
-        let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
+/// The most redirects HttpTransport follows for a single request.
+const MAX_HTTP_REDIRECTS: usize = 10;
+
+/// A Transport that fetches values over HTTP or HTTPS, each key being a path
+/// under a base URL, e.g. to test against batches served by a partner's
+/// staging environment. Redirects are followed and responses with a status
+/// other than 2xx fail with Error::TransportError. Values are streamed as they
+/// are read. Since this is intended for debugging, values can only be written
+/// once enabled with set_allow_put, in which case each is held in memory until
+/// its upload is completed and then sent with a PUT request. HttpTransport
+/// can't list or delete values.
+pub struct HttpTransport {
+    base_url: String,
+    allow_put: bool,
+}
 
-        {
-            let ret = file_transport.get("path2");
-            assert!(ret.is_err(), "unexpected return value {:?}", ret.err());
+impl HttpTransport {
+    /// Creates an HttpTransport for keys under base_url, e.g.
+    /// "https://example.com/batches".
+    pub fn new(base_url: &str) -> HttpTransport {
+        HttpTransport {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            allow_put: false,
         }
+    }
 
-        for path in &["path", "path3/with/separators"] {
-            let writer = file_transport.put(path);
-            assert!(writer.is_ok(), "unexpected error {:?}", writer.err());
+    /// Sets whether values may be written with PUT requests. Defaults to false.
+    pub fn set_allow_put(&mut self, allow_put: bool) {
+        self.allow_put = allow_put;
+    }
 
-            writer
-                .unwrap()
-                .write_all(&content)
-                .expect("failed to write");
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+}
 
-            let reader = file_transport.get(path);
-            assert!(reader.is_ok(), "create reader failed: {:?}", reader.err());
+impl Transport for HttpTransport {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        let mut runtime = basic_runtime()?;
+        let response = send_http_request(&mut runtime, Method::GET, &self.url(key), Vec::new())?;
+        Ok(Box::new(HttpBodyReader {
+            runtime,
+            body: response.into_body(),
+            chunk: Bytes::new(),
+        }))
+    }
 
-            let mut content_again = Vec::new();
-            reader
-                .unwrap()
-                .read_to_end(&mut content_again)
-                .expect("failed to read");
-            assert_eq!(content_again, content);
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        if !self.allow_put {
+            return Err(Error::TransportError(format!(
+                "cannot put {}: writing is not enabled for HTTP transport",
+                self.url(key)
+            ))
+            .into());
         }
+        Ok(Box::new(HttpPutWriter {
+            url: self.url(key),
+            buffer: Vec::new(),
+        }))
     }
+}
 
-    // Rusoto provides us the ability to create mock clients and play canned
-    // responses to API requests. Besides that, we want to verify that we get
-    // the expected sequence of API requests, for instance to verify that we
-    // call AbortMultipartUpload if an UploadPart call fails. The mock client
-    // will crash if it runs out of canned responses to give the client, which
-    // will catch excess API calls. To make sure we issue the correct API calls,
-    // we use with_request_checker to examine the outgoing requests. We only get
-    // to examine a rusoto::signature::SignedRequest, which does not contain an
-    // explicit indication of which API call the HTTP request represents. So we
-    // have the `is_*_request` functions below, which compare HTTP method and
-    // URL parameters against the Amazon API specification to figure out what
-    // API call we are looking at.
-
-    const TEST_BUCKET: &str = "fake-bucket";
-    const TEST_KEY: &str = "fake-key";
+/// Sends a request with the provided method and body to url, following
+/// redirects, and returns the response if its status is 2xx. GET requests
+/// follow any redirect, but other requests only those that preserve the method
+/// and body, 307 and 308.
+fn send_http_request(
+    runtime: &mut Runtime,
+    method: Method,
+    url: &str,
+    body: Vec<u8>,
+) -> Result<Response<Body>> {
+    let failed = |url: &Uri, e: String| Error::TransportError(format!("{} {}: {}", method, url, e));
+    let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+    let mut uri: Uri = url
+        .parse()
+        .map_err(|e| Error::TransportError(format!("invalid URL {}: {}", url, e)))?;
+    for _ in 0..=MAX_HTTP_REDIRECTS {
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(uri.clone())
+            .body(Body::from(body.clone()))
+            .map_err(|e| failed(&uri, e.to_string()))?;
+        let response = runtime
+            .block_on(client.request(request))
+            .map_err(|e| failed(&uri, e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let follow = status.is_redirection()
+            && (method == Method::GET
+                || status == StatusCode::TEMPORARY_REDIRECT
+                || status == StatusCode::PERMANENT_REDIRECT);
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok());
+        match location {
+            Some(location) if follow => {
+                uri = resolve_http_redirect(&uri, location)
+                    .ok_or_else(|| failed(&uri, format!("bad redirect to {}", location)))?;
+            }
+            _ => return Err(failed(&uri, format!("HTTP status {}", status)).into()),
+        }
+    }
+    Err(Error::TransportError(format!(
+        "{} {}: more than {} redirects",
+        method, url, MAX_HTTP_REDIRECTS
+    ))
+    .into())
+}
 
-    fn is_create_multipart_upload_request(request: &SignedRequest) {
-        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CreateMultipartUpload.html
-        assert_eq!(
-            request.method, "POST",
-            "expected CreateMultipartUpload request, found {:?}",
-            request
-        );
-        assert!(
-            request.params.contains_key("uploads"),
-            "expected CreateMultipartUpload request, found {:?}",
-            request
-        );
+/// Resolves the Location of a redirect from uri, which may be an absolute URL
+/// or an absolute path on the same host. Other relative references are not
+/// supported and yield None.
+fn resolve_http_redirect(uri: &Uri, location: &str) -> Option<Uri> {
+    if location.starts_with('/') {
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(location.parse().ok()?);
+        return Uri::from_parts(parts).ok();
     }
+    let location: Uri = location.parse().ok()?;
+    location.scheme()?;
+    Some(location)
+}
 
-    fn is_upload_part_request(request: &SignedRequest) {
-        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_UploadPart.html
-        assert_eq!(
-            request.method, "PUT",
-            "expected UploadPart request, found {:?}",
-            request
-        );
-        assert!(
-            request.params.contains_key("partNumber"),
-            "expected UploadPart request, found {:?}",
-            request
-        );
-        assert!(
-            request.params.contains_key("uploadId"),
-            "expected UploadPart request, found {:?}",
-            request
-        );
+/// Reads the body of an HTTP response, one chunk at a time.
+struct HttpBodyReader {
+    runtime: Runtime,
+    body: Body,
+    chunk: Bytes,
+}
+
+impl Read for HttpBodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.body.data()) {
+                Some(chunk) => self.chunk = chunk.map_err(std::io::Error::other)?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
     }
+}
 
-    fn is_abort_multipart_upload_request(request: &SignedRequest) {
-        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_AbortMultipartUpload.html
-        assert_eq!(
-            request.method, "DELETE",
-            "expected AbortMultipartUpload request, found {:?}",
-            request
-        );
-        assert!(
-            request.params.contains_key("uploadId"),
-            "expected AbortMultipartUpload request, found {:?}",
-            request
-        );
+/// Buffers a value written through HttpTransport and sends it with a PUT
+/// request once the upload is completed.
+struct HttpPutWriter {
+    url: String,
+    buffer: Vec<u8>,
+}
+
+impl Write for HttpPutWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.buffer.write(buf)
     }
 
-    fn is_complete_multipart_upload_request(request: &SignedRequest) {
-        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CompleteMultipartUpload.html
-        assert_eq!(
-            request.method, "POST",
-            "expected CompleteMultipartUpload request, found {:?}",
-            request
-        );
-        assert!(
-            request.params.contains_key("uploadId"),
-            "expected CompleteMultipartUpload request, found {:?}",
-            request
-        );
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
     }
+}
 
-    fn is_get_object_request(request: &SignedRequest) {
-        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html
-        assert_eq!(
+impl TransportWriter for HttpPutWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let mut runtime = basic_runtime()?;
+        send_http_request(
+            &mut runtime,
+            Method::PUT,
+            &self.url,
+            mem::take(&mut self.buffer),
+        )?;
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        // Nothing has been sent yet
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// How transport operations that fail with a transient error (see
+/// is_transient) are retried. Other errors are returned right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times an operation is attempted, including the first. 1
+    /// disables retries.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. Each later retry waits twice
+    /// as long as the one before, up to MAX_RETRY_BACKOFF.
+    pub initial_backoff: Duration,
+}
+
+/// The longest RetryPolicy waits between two attempts.
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The RetryPolicy BatchIntaker applies to its transport operations by default.
+pub const DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    initial_backoff: Duration::from_millis(500),
+};
+
+impl RetryPolicy {
+    /// A RetryPolicy that attempts every operation once.
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        initial_backoff: Duration::from_secs(0),
+    };
+
+    /// Returns true if operations may be attempted more than once.
+    pub fn retries(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Calls operation until it succeeds, fails with an error that is not
+    /// transient, or has been attempted max_attempts times, and returns its
+    /// last result. An error after more than one attempt says how many there
+    /// were.
+    pub fn retry<T>(&self, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(MAX_RETRY_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) if attempt > 1 => {
+                    return Err(e.context(format!("failed after {} attempts", attempt)))
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns true if the error was caused by a failure that might not recur were
+/// the operation that failed tried again: an I/O error such as a timeout or a
+/// reset connection, or an S3 request that could not be sent or that failed
+/// with a server error or was throttled. Errors from HttpTransport are never
+/// transient.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        // Both Error::AnyhowError and std::io::Error report the source of the
+        // error they wrap, rather than the error itself, as their source, so
+        // look inside them.
+        if let Some(Error::AnyhowError(inner)) = cause.downcast_ref::<Error>() {
+            return is_transient(inner);
+        }
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            return match io_error.kind() {
+                ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe => true,
+                _ => match io_error
+                    .get_ref()
+                    .map(|inner| inner.downcast_ref::<Error>())
+                {
+                    Some(Some(Error::AnyhowError(inner))) => is_transient(inner),
+                    _ => false,
+                },
+            };
+        }
+        cause.is::<HttpDispatchError>()
+            || is_transient_s3_response::<GetObjectError>(cause)
+            || is_transient_s3_response::<HeadObjectError>(cause)
+            || is_transient_s3_response::<ListObjectsV2Error>(cause)
+            || is_transient_s3_response::<CreateMultipartUploadError>(cause)
+            || is_transient_s3_response::<UploadPartError>(cause)
+            || is_transient_s3_response::<CompleteMultipartUploadError>(cause)
+    })
+}
+
+/// Returns true if the error is a response to an S3 request of the kind that
+/// fails with E, whose status indicates a server error or throttling.
+fn is_transient_s3_response<E: std::error::Error + 'static>(
+    error: &(dyn std::error::Error + 'static),
+) -> bool {
+    match error.downcast_ref::<RusotoError<E>>() {
+        Some(RusotoError::Unknown(response)) => {
+            response.status.is_server_error() || response.status.as_u16() == 429
+        }
+        _ => false,
+    }
+}
+
+/// A token bucket limiting the rate of some operation. RateLimiter may be
+/// cloned, in which case the clones share the same budget, so that one limit
+/// can be applied across several transports or threads.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    operations_per_second: f64,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    // May be negative if callers have reserved tokens they are waiting for.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a RateLimiter allowing operations_per_second operations every
+    /// second on average, and bursts of up to operations_per_second (but at
+    /// least one) operations.
+    pub fn new(operations_per_second: f64) -> Result<RateLimiter> {
+        if !(operations_per_second.is_finite() && operations_per_second > 0.0) {
+            return Err(anyhow!(
+                "invalid rate limit {} operations per second",
+                operations_per_second
+            ));
+        }
+        Ok(RateLimiter {
+            operations_per_second,
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                tokens: RateLimiter::capacity(operations_per_second),
+                last_refill: Instant::now(),
+            })),
+        })
+    }
+
+    fn capacity(operations_per_second: f64) -> f64 {
+        operations_per_second.max(1.0)
+    }
+
+    /// Blocks until the budget allows another operation.
+    pub fn acquire(&self) {
+        let wait = {
+            // A panic while holding the lock can't leave the bucket in an
+            // inconsistent state, so ignore poisoning.
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.operations_per_second)
+                .min(RateLimiter::capacity(self.operations_per_second));
+            bucket.last_refill = now;
+
+            // Take our token now, even if that puts the bucket into debt, so
+            // that callers are served in the order they arrive.
+            bucket.tokens -= 1.0;
+            if bucket.tokens < 0.0 {
+                Some(Duration::from_secs_f64(
+                    -bucket.tokens / self.operations_per_second,
+                ))
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// A Transport that wraps another Transport and limits the rate at which get
+/// and put are called on it, to stay within an object store's request quota.
+/// Callers block while the budget is exhausted. Only calls to get and put are
+/// counted, so e.g. the individual part uploads of an S3 multipart upload are
+/// not.
+pub struct RateLimitedTransport<T> {
+    transport: T,
+    limiter: RateLimiter,
+}
+
+impl<T: Transport> RateLimitedTransport<T> {
+    /// Creates a RateLimitedTransport allowing operations_per_second calls to
+    /// get or put every second.
+    pub fn new(transport: T, operations_per_second: f64) -> Result<RateLimitedTransport<T>> {
+        Ok(RateLimitedTransport::with_limiter(
+            transport,
+            RateLimiter::new(operations_per_second)?,
+        ))
+    }
+
+    /// Creates a RateLimitedTransport whose calls to get and put draw from the
+    /// budget of the provided RateLimiter.
+    pub fn with_limiter(transport: T, limiter: RateLimiter) -> RateLimitedTransport<T> {
+        RateLimitedTransport { transport, limiter }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: Transport> Transport for RateLimitedTransport<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        self.limiter.acquire();
+        self.transport.get(key)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        self.limiter.acquire();
+        self.transport.put(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.limiter.acquire();
+        self.transport.list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.limiter.acquire();
+        self.transport.delete(key)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.limiter.acquire();
+        self.transport.size(key)
+    }
+}
+
+/// Running totals of the traffic through a MeteredTransport.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransportMetrics {
+    /// Bytes read from values fetched with get
+    pub bytes_read: u64,
+    /// Bytes written into values stored with put, whether or not the upload
+    /// was completed
+    pub bytes_written: u64,
+    /// Wall-clock time spent in get and in reading from the values it returned
+    pub read_duration: Duration,
+    /// Wall-clock time spent in put and in writing, completing or cancelling
+    /// the uploads it returned
+    pub write_duration: Duration,
+}
+
+/// A handle on the running totals of a MeteredTransport, which may be read
+/// while the transport itself is borrowed elsewhere.
+#[derive(Clone, Default)]
+pub struct TransportMeter {
+    metrics: Arc<Mutex<TransportMetrics>>,
+}
+
+impl TransportMeter {
+    /// Returns the totals so far, including traffic through readers and
+    /// writers that are still open.
+    pub fn metrics(&self) -> TransportMetrics {
+        *self.metrics.lock().unwrap()
+    }
+}
+
+/// A Transport that wraps another Transport and keeps count of the bytes read
+/// from and written to it and of the time spent reading and writing, so that
+/// callers can report on the cost of processing a batch.
+pub struct MeteredTransport<T> {
+    transport: T,
+    meter: TransportMeter,
+}
+
+impl<T: Transport> MeteredTransport<T> {
+    pub fn new(transport: T) -> MeteredTransport<T> {
+        MeteredTransport {
+            transport,
+            meter: TransportMeter::default(),
+        }
+    }
+
+    /// Returns a MeteredTransport that adds to the running totals of the
+    /// provided meter, e.g. to count further traffic to a transport that an
+    /// earlier MeteredTransport wrapped.
+    pub fn with_meter(transport: T, meter: TransportMeter) -> MeteredTransport<T> {
+        MeteredTransport { transport, meter }
+    }
+
+    /// Returns a handle on this transport's running totals.
+    pub fn meter(&self) -> TransportMeter {
+        self.meter.clone()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: Transport> Transport for MeteredTransport<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        let start = Instant::now();
+        let result = self.transport.get(key);
+        self.meter.metrics.lock().unwrap().read_duration += start.elapsed();
+        Ok(Box::new(MeteredReader {
+            reader: result?,
+            metrics: self.meter.metrics.clone(),
+        }))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let start = Instant::now();
+        let result = self.transport.put(key);
+        self.meter.metrics.lock().unwrap().write_duration += start.elapsed();
+        Ok(Box::new(MeteredWriter {
+            writer: result?,
+            metrics: self.meter.metrics.clone(),
+        }))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.transport.list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.transport.delete(key)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.transport.size(key)
+    }
+}
+
+struct MeteredReader {
+    reader: Box<dyn Read>,
+    metrics: Arc<Mutex<TransportMetrics>>,
+}
+
+impl Read for MeteredReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let start = Instant::now();
+        let result = self.reader.read(buf);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.read_duration += start.elapsed();
+        if let Ok(n) = result {
+            metrics.bytes_read += n as u64;
+        }
+        result
+    }
+}
+
+struct MeteredWriter {
+    writer: Box<dyn TransportWriter>,
+    metrics: Arc<Mutex<TransportMetrics>>,
+}
+
+impl MeteredWriter {
+    /// Runs f, counting the time it takes as time spent writing.
+    fn timed<R>(&mut self, f: impl FnOnce(&mut dyn TransportWriter) -> R) -> R {
+        let start = Instant::now();
+        let result = f(&mut *self.writer);
+        self.metrics.lock().unwrap().write_duration += start.elapsed();
+        result
+    }
+}
+
+impl Write for MeteredWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let n = self.timed(|writer| writer.write(buf))?;
+        self.metrics.lock().unwrap().bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.timed(|writer| writer.flush())
+    }
+}
+
+impl TransportWriter for MeteredWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.timed(|writer| writer.complete_upload())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.timed(|writer| writer.cancel_upload())
+    }
+}
+
+/// The number of bytes by which a value stored through an EncryptingTransport
+/// is longer than the plaintext: a nonce ahead of the ciphertext and an
+/// authentication tag after it.
+pub const ENCRYPTION_OVERHEAD: usize = aead::NONCE_LEN + 16;
+
+/// A Transport that wraps another Transport and encrypts every value put into
+/// it with AES-256-GCM under a configured key, and decrypts values as they are
+/// got, so that they are encrypted at rest whatever the underlying store does.
+/// Each value is stored as a random nonce followed by the ciphertext and tag.
+/// The key of the value is authenticated along with it, so a value copied to
+/// another key fails to decrypt. Callers see only plaintext, so the digests and
+/// signatures BatchWriter computes are over the logical content.
+///
+/// Values are encrypted once their upload is completed, and decrypted in full
+/// before get returns, so each value is held in memory. size reports the size
+/// of the stored value, ENCRYPTION_OVERHEAD bytes more than the plaintext.
+pub struct EncryptingTransport<T> {
+    transport: T,
+    key: Arc<aead::LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl<T: Transport> EncryptingTransport<T> {
+    /// Creates an EncryptingTransport using the provided 32 byte AES-256 key.
+    /// Returns Error::CryptographyError if the key is any other length.
+    pub fn new(transport: T, key: &[u8]) -> Result<EncryptingTransport<T>, Error> {
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, key).map_err(|_| {
+            Error::CryptographyError(format!(
+                "encryption key must be {} bytes but is {}",
+                aead::AES_256_GCM.key_len(),
+                key.len()
+            ))
+        })?;
+        Ok(EncryptingTransport {
+            transport,
+            key: Arc::new(aead::LessSafeKey::new(key)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: Transport> Transport for EncryptingTransport<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        let mut value = Vec::new();
+        self.transport
+            .get(key)?
+            .read_to_end(&mut value)
+            .with_context(|| format!("failed to read {}", key))?;
+        if value.len() < ENCRYPTION_OVERHEAD {
+            return Err(Error::CryptographyError(format!(
+                "{} is {} bytes long, too short to be encrypted",
+                key,
+                value.len()
+            ))
+            .into());
+        }
+        let mut ciphertext = value.split_off(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(&value).unwrap();
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(key.as_bytes()), &mut ciphertext)
+            .map_err(|_| Error::CryptographyError(format!("failed to decrypt {}", key)))?
+            .len();
+        ciphertext.truncate(plaintext_len);
+        Ok(Box::new(Cursor::new(ciphertext)))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(EncryptingWriter {
+            writer: self.transport.put(key)?,
+            key: self.key.clone(),
+            rng: self.rng.clone(),
+            value_key: key.to_owned(),
+            plaintext: Vec::new(),
+        }))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.transport.list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.transport.delete(key)
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.transport.size(key)
+    }
+}
+
+/// Buffers the plaintext of a value until its upload is completed, and then
+/// writes the encrypted value to the underlying writer.
+struct EncryptingWriter {
+    writer: Box<dyn TransportWriter>,
+    key: Arc<aead::LessSafeKey>,
+    rng: SystemRandom,
+    value_key: String,
+    plaintext: Vec<u8>,
+}
+
+impl Write for EncryptingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.plaintext.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for EncryptingWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| Error::CryptographyError("failed to generate nonce".to_owned()))?;
+        let mut value = mem::take(&mut self.plaintext);
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(self.value_key.as_bytes()),
+                &mut value,
+            )
+            .map_err(|_| {
+                Error::CryptographyError(format!("failed to encrypt {}", self.value_key))
+            })?;
+        self.writer
+            .write_all(&nonce)
+            .and_then(|_| self.writer.write_all(&value))
+            .with_context(|| format!("failed to write {}", self.value_key))?;
+        self.writer.complete_upload()
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.plaintext.clear();
+        self.writer.cancel_upload()
+    }
+}
+
+/// A Transport that writes every value put into it to each of several
+/// underlying transports, e.g. to deliver a validation batch to the peer share
+/// processor and to an audit bucket in one pass. A single TransportWriter is
+/// returned from put, so callers that digest or sign what they write still see
+/// one copy of the bytes. Values are read, listed and sized through the first
+/// transport only, and deleted from all of them.
+pub struct FanoutTransport<T> {
+    transports: Vec<T>,
+    failures_fatal: bool,
+    errors: Arc<Mutex<Vec<anyhow::Error>>>,
+}
+
+impl<T: Transport> FanoutTransport<T> {
+    /// Creates a FanoutTransport writing to all of the provided transports,
+    /// of which there must be at least one.
+    pub fn new(transports: Vec<T>) -> Result<FanoutTransport<T>> {
+        if transports.is_empty() {
+            return Err(anyhow!("fanout transport needs at least one transport"));
+        }
+        Ok(FanoutTransport {
+            transports,
+            failures_fatal: true,
+            errors: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Sets whether a failure to put, write or complete an upload into any one
+    /// of the transports fails the whole operation. If so, uploads to the
+    /// other transports are cancelled where they have not yet completed. If
+    /// not, the failed transport is dropped from that upload, the error is
+    /// collected for take_errors and the operation fails only if it failed
+    /// for every transport. Defaults to true.
+    pub fn set_failures_fatal(&mut self, failures_fatal: bool) {
+        self.failures_fatal = failures_fatal;
+    }
+
+    /// Returns and forgets the errors tolerated so far because failures are
+    /// not fatal. See set_failures_fatal.
+    pub fn take_errors(&self) -> Vec<anyhow::Error> {
+        mem::take(&mut *self.errors.lock().unwrap())
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.transports
+    }
+}
+
+impl<T: Transport> Transport for FanoutTransport<T> {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        self.transports[0].get(key)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let mut writers = Vec::new();
+        for (index, transport) in self.transports.iter_mut().enumerate() {
+            match transport.put(key) {
+                Ok(writer) => writers.push((index, writer)),
+                Err(e) => {
+                    let e = e.context(format!("failed to put {} into transport {}", key, index));
+                    if self.failures_fatal {
+                        for (_, mut writer) in writers {
+                            let _ = writer.cancel_upload();
+                        }
+                        return Err(e);
+                    }
+                    self.errors.lock().unwrap().push(e);
+                }
+            }
+        }
+        if writers.is_empty() {
+            return Err(anyhow!("failed to put {} into any transport", key));
+        }
+        Ok(Box::new(FanoutWriter {
+            key: key.to_owned(),
+            writers,
+            failures_fatal: self.failures_fatal,
+            errors: self.errors.clone(),
+        }))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.transports[0].list(prefix)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        for transport in &mut self.transports {
+            transport.delete(key)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.transports[0].size(key)
+    }
+}
+
+struct FanoutWriter {
+    key: String,
+    // Each writer is paired with the index of its transport, for errors
+    writers: Vec<(usize, Box<dyn TransportWriter>)>,
+    failures_fatal: bool,
+    errors: Arc<Mutex<Vec<anyhow::Error>>>,
+}
+
+impl FanoutWriter {
+    /// Applies the provided operation to each remaining writer. If failures
+    /// are fatal, the first failure is returned, leaving every writer in place
+    /// for cancel_upload. Otherwise writers for which the operation fails are
+    /// cancelled and dropped, and this only fails once none remain.
+    fn for_each_writer<F>(&mut self, mut operation: F) -> Result<()>
+    where
+        F: FnMut(&mut dyn TransportWriter) -> Result<()>,
+    {
+        let mut position = 0;
+        while position < self.writers.len() {
+            let (index, writer) = &mut self.writers[position];
+            match operation(&mut **writer) {
+                Ok(()) => position += 1,
+                Err(e) => {
+                    let e = e.context(format!(
+                        "failed to upload {} to transport {}",
+                        self.key, index
+                    ));
+                    if self.failures_fatal {
+                        return Err(e);
+                    }
+                    let (_, mut writer) = self.writers.remove(position);
+                    let _ = writer.cancel_upload();
+                    self.errors.lock().unwrap().push(e);
+                }
+            }
+        }
+        if self.writers.is_empty() {
+            return Err(anyhow!("failed to upload {} to any transport", self.key));
+        }
+        Ok(())
+    }
+}
+
+impl Write for FanoutWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        // Every destination must get all of buf, since a short write into one
+        // can't be reported without the others getting ahead of it.
+        self.for_each_writer(|writer| Ok(writer.write_all(buf)?))
+            .map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.for_each_writer(|writer| Ok(writer.flush()?))
+            .map_err(std::io::Error::other)
+    }
+}
+
+impl TransportWriter for FanoutWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        if !self.failures_fatal {
+            return self.for_each_writer(|writer| writer.complete_upload());
+        }
+        // Uploads that have already completed can't be undone, so a failure
+        // may still leave the value in the transports before the one that
+        // failed. The others are cancelled.
+        let mut writers = mem::take(&mut self.writers).into_iter();
+        while let Some((index, mut writer)) = writers.next() {
+            if let Err(e) = writer.complete_upload() {
+                let _ = writer.cancel_upload();
+                for (_, mut writer) in writers {
+                    let _ = writer.cancel_upload();
+                }
+                return Err(e.context(format!(
+                    "failed to upload {} to transport {}",
+                    self.key, index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for (index, writer) in &mut self.writers {
+            if let Err(e) = writer.cancel_upload() {
+                if result.is_ok() {
+                    result = Err(e.context(format!(
+                        "failed to cancel upload of {} to transport {}",
+                        self.key, index
+                    )));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A transport that discards everything written to it and holds no values, for
+/// running code that writes through a Transport without producing anything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullTransport;
+
+impl Transport for NullTransport {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        Err(anyhow!("no value for key {}", key))
+    }
+
+    fn put(&mut self, _key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(NullTransportWriter))
+    }
+
+    fn list(&self, _prefix: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn delete(&mut self, _key: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct NullTransportWriter;
+
+impl Write for NullTransportWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for NullTransportWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A transport implementation that keeps values in memory, intended for tests.
+/// Clones of a MemoryTransport share the same values. As with an object store,
+/// a value only becomes visible once its upload is completed.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    values: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn get(&self, key: &str) -> Result<Box<dyn Read>> {
+        let values = self.values.lock().unwrap();
+        let value = values
+            .get(key)
+            .with_context(|| format!("no value for key {}", key))?;
+        Ok(Box::new(Cursor::new(value.clone())))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(MemoryTransportWriter {
+            values: self.values.clone(),
+            key: key.to_owned(),
+            buffer: Vec::new(),
+        }))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let values = self.values.lock().unwrap();
+        Ok(values
+            .range(prefix.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        let values = self.values.lock().unwrap();
+        let value = values
+            .get(key)
+            .with_context(|| format!("no value for key {}", key))?;
+        Ok(Some(value.len() as u64))
+    }
+}
+
+struct MemoryTransportWriter {
+    values: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    key: String,
+    buffer: Vec<u8>,
+}
+
+impl Write for MemoryTransportWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for MemoryTransportWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(self.key.clone(), mem::take(&mut self.buffer));
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{AggregationName, BatchDate, BatchIdentity, BatchReader, DEFAULT_NAMING_SCHEME},
+        idl::{IngestionDataSharePacket, IngestionHeader},
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_ingestor_private_key_raw, default_ingestor_public_key,
+            DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+    };
+    use chrono::NaiveDateTime;
+    use prio::encrypt::PrivateKey;
+    use rusoto_core::signature::SignedRequest;
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+    use rusoto_s3::CreateMultipartUploadError;
+    use std::io::Read;
+    use uuid::Uuid;
+
+    #[test]
+    fn roundtrip_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+        {
+            let ret = file_transport.get("path2");
+            assert!(ret.is_err(), "unexpected return value {:?}", ret.err());
+        }
+
+        for path in &["path", "path3/with/separators"] {
+            let writer = file_transport.put(path);
+            assert!(writer.is_ok(), "unexpected error {:?}", writer.err());
+
+            writer
+                .unwrap()
+                .write_all(&content)
+                .expect("failed to write");
+
+            let reader = file_transport.get(path);
+            assert!(reader.is_ok(), "create reader failed: {:?}", reader.err());
+
+            let mut content_again = Vec::new();
+            reader
+                .unwrap()
+                .read_to_end(&mut content_again)
+                .expect("failed to read");
+            assert_eq!(content_again, content);
+        }
+    }
+
+    fn put_values(transport: &mut dyn Transport, keys: &[&str]) {
+        for key in keys {
+            let mut writer = transport.put(key).unwrap();
+            writer.write_all(key.as_bytes()).unwrap();
+            writer.complete_upload().unwrap();
+        }
+    }
+
+    fn check_list(transport: &mut dyn Transport) {
+        put_values(
+            transport,
+            &["a/b/c", "a/b/d", "a/bc", "a/e/f", "a.sig", "b"],
+        );
+        for (prefix, expected) in &[
+            ("a/b/", vec!["a/b/c", "a/b/d"]),
+            ("a/b", vec!["a/b/c", "a/b/d", "a/bc"]),
+            ("a", vec!["a.sig", "a/b/c", "a/b/d", "a/bc", "a/e/f"]),
+            ("", vec!["a.sig", "a/b/c", "a/b/d", "a/bc", "a/e/f", "b"]),
+            ("a/e/f", vec!["a/e/f"]),
+            ("a/x/", vec![]),
+            ("c", vec![]),
+        ] {
+            assert_eq!(
+                transport.list(prefix).unwrap(),
+                *expected,
+                "prefix {:?}",
+                prefix
+            );
+        }
+    }
+
+    #[test]
+    fn file_transport_list() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        check_list(&mut LocalFileTransport::new(tempdir.path().to_path_buf()));
+    }
+
+    /// Checks that the provided transport, which must have had check_list run
+    /// on it, reports the sizes of values.
+    fn check_size(transport: &dyn Transport) {
+        assert_eq!(transport.size("a/b/c").unwrap(), Some(5));
+        assert_eq!(transport.size("a.sig").unwrap(), Some(5));
+        assert!(transport.size("a/b/x").is_err());
+    }
+
+    #[test]
+    fn file_transport_size() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        check_list(&mut transport);
+        check_size(&transport);
+    }
+
+    #[test]
+    fn stream_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("value");
+        let mut transport = StreamTransport::new();
+        transport.map("some/key", Stream::output(path.to_str().unwrap()));
+        transport.map("stdin/key", Stream::input("-"));
+        transport.map("stdout/key", Stream::output("-"));
+        assert_eq!(Stream::input("-"), Stream::Stdin);
+        assert_eq!(Stream::output("-"), Stream::Stdout);
+
+        let mut writer = transport.put("some/key").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"content");
+
+        let mut content = Vec::new();
+        transport
+            .get("some/key")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"content");
+        assert_eq!(transport.size("some/key").unwrap(), Some(7));
+        assert_eq!(transport.size("stdin/key").unwrap(), None);
+
+        assert!(transport.get("unmapped/key").is_err());
+        assert!(transport.put("unmapped/key").is_err());
+        assert!(transport.get("stdout/key").is_err());
+        assert!(transport.put("stdin/key").is_err());
+        assert!(transport.list("").is_err());
+    }
+
+    /// Checks that the provided transport deletes values and tolerates
+    /// deleting keys with no value.
+    fn check_delete(transport: &mut dyn Transport) {
+        let mut writer = transport.put("delete/me").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+        assert!(transport.get("delete/me").is_ok());
+
+        transport.delete("delete/me").unwrap();
+        assert!(transport.get("delete/me").is_err());
+        transport.delete("delete/me").unwrap();
+        transport.delete("never/existed").unwrap();
+    }
+
+    #[test]
+    fn file_transport_delete() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        check_delete(&mut LocalFileTransport::new(tempdir.path().to_path_buf()));
+    }
+
+    #[test]
+    fn memory_transport() {
+        let mut transport = MemoryTransport::new();
+        check_list(&mut transport);
+        check_size(&transport);
+
+        let mut content = Vec::new();
+        transport
+            .get("a/b/c")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"a/b/c");
+        assert!(transport.get("a/b").is_err());
+
+        // Values are visible to clones, but only once their upload completes.
+        let clone = transport.clone();
+        let mut writer = transport.put("new").unwrap();
+        writer.write_all(b"new content").unwrap();
+        assert!(clone.get("new").is_err());
+        writer.complete_upload().unwrap();
+        let mut content = Vec::new();
+        clone.get("new").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"new content");
+
+        let mut writer = transport.put("cancelled").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+        assert!(clone.get("cancelled").is_err());
+
+        check_delete(&mut transport);
+    }
+
+    fn is_list_objects_request(request: &SignedRequest, continuation_token: Option<&str>) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_ListObjectsV2.html
+        assert_eq!(request.method, "GET", "unexpected request {:?}", request);
+        assert_eq!(
+            request.path, "/fake-bucket",
+            "unexpected request {:?}",
+            request
+        );
+        assert_eq!(
+            request.params.get("list-type"),
+            Some(&Some("2".to_owned())),
+            "unexpected request {:?}",
+            request
+        );
+        assert_eq!(
+            request.params.get("prefix"),
+            Some(&Some("fake-prefix/".to_owned())),
+            "unexpected request {:?}",
+            request
+        );
+        assert_eq!(
+            request.params.get("continuation-token"),
+            continuation_token.map(|t| Some(t.to_owned())).as_ref(),
+            "unexpected request {:?}",
+            request
+        );
+    }
+
+    #[test]
+    fn fanout_transport() {
+        assert!(FanoutTransport::<MemoryTransport>::new(vec![]).is_err());
+
+        let first = MemoryTransport::new();
+        let second = MemoryTransport::new();
+        let mut transport = FanoutTransport::new(vec![first.clone(), second.clone()]).unwrap();
+
+        let mut writer = transport.put("key").unwrap();
+        writer.write_all(b"content").unwrap();
+        assert!(first.get("key").is_err());
+        writer.complete_upload().unwrap();
+        for destination in &[&first, &second] {
+            let mut content = Vec::new();
+            destination
+                .get("key")
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, b"content");
+        }
+        assert_eq!(transport.list("").unwrap(), vec!["key".to_owned()]);
+        assert_eq!(transport.size("key").unwrap(), Some(7));
+
+        let mut writer = transport.put("cancelled").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+        assert!(first.get("cancelled").is_err());
+        assert!(second.get("cancelled").is_err());
+
+        transport.delete("key").unwrap();
+        assert!(first.list("").unwrap().is_empty());
+        assert!(second.list("").unwrap().is_empty());
+
+        // Nothing can be put into a LocalFileTransport rooted under a file.
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let file_path = tempdir.path().join("file");
+        std::fs::write(&file_path, b"").unwrap();
+        let transports: Vec<Box<dyn Transport>> = vec![
+            Box::new(first.clone()),
+            Box::new(LocalFileTransport::new(file_path)),
+        ];
+        let mut transport = FanoutTransport::new(transports).unwrap();
+        assert!(transport.put("key").is_err());
+        assert!(first.list("").unwrap().is_empty());
+
+        transport.set_failures_fatal(false);
+        let mut writer = transport.put("key").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+        let mut content = Vec::new();
+        first.get("key").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"content");
+        assert_eq!(transport.take_errors().len(), 1);
+        assert!(transport.take_errors().is_empty());
+    }
+
+    #[test]
+    fn s3_transport_list() {
+        let transport =
+            S3Transport::new_with_client(Region::UsWest2, TEST_BUCKET.to_string(), |region| {
+                let requests = vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+   <IsTruncated>true</IsTruncated>
+   <Contents><Key>fake-prefix/a</Key></Contents>
+   <Contents><Key>fake-prefix/b</Key></Contents>
+   <Name>fake-bucket</Name>
+   <Prefix>fake-prefix/</Prefix>
+   <KeyCount>2</KeyCount>
+   <NextContinuationToken>fake-token</NextContinuationToken>
+</ListBucketResult>"#,
+                        )
+                        .with_request_checker(|request| is_list_objects_request(request, None)),
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+   <IsTruncated>false</IsTruncated>
+   <Contents><Key>fake-prefix/c</Key></Contents>
+   <Name>fake-bucket</Name>
+   <Prefix>fake-prefix/</Prefix>
+   <KeyCount>1</KeyCount>
+   <ContinuationToken>fake-token</ContinuationToken>
+</ListBucketResult>"#,
+                        )
+                        .with_request_checker(|request| {
+                            is_list_objects_request(request, Some("fake-token"))
+                        }),
+                ];
+                S3Client::new_with(
+                    MultipleMockRequestDispatcher::new(requests),
+                    MockCredentialsProvider,
+                    region.clone(),
+                )
+            });
+
+        assert_eq!(
+            transport.list("fake-prefix/").unwrap(),
+            vec!["fake-prefix/a", "fake-prefix/b", "fake-prefix/c"]
+        );
+    }
+
+    #[test]
+    fn s3_transport_delete() {
+        let mut transport =
+            S3Transport::new_with_client(Region::UsWest2, TEST_BUCKET.to_string(), |region| {
+                S3Client::new_with(
+                    // https://docs.aws.amazon.com/AmazonS3/latest/API/API_DeleteObject.html
+                    MockRequestDispatcher::with_status(204).with_request_checker(|request| {
+                        assert_eq!(request.method, "DELETE");
+                        assert_eq!(request.path, "/fake-bucket/fake-key");
+                    }),
+                    MockCredentialsProvider,
+                    region.clone(),
+                )
+            });
+        transport.delete("fake-key").unwrap();
+    }
+
+    #[test]
+    fn file_transport_case_collision() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        for key in &[
+            "Aggregation/batch",
+            "Aggregation/other-batch",
+            "Aggregation/batch",
+        ] {
+            file_transport
+                .put(key)
+                .unwrap_or_else(|e| panic!("failed to put {}: {:?}", key, e))
+                .complete_upload()
+                .unwrap();
+        }
+
+        for (key, existing) in &[
+            ("aggregation/batch", "Aggregation"),
+            ("aggregation/new-batch", "Aggregation"),
+            ("Aggregation/Batch", "Aggregation/batch"),
+        ] {
+            let err = file_transport
+                .put(key)
+                .err()
+                .unwrap_or_else(|| panic!("case collision on {} was not detected", key));
+            match err.downcast_ref::<Error>() {
+                Some(Error::CaseCollision(k, e)) => {
+                    assert_eq!(k, key);
+                    assert_eq!(e, existing);
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+
+        // Nothing was created for the colliding keys.
+        let mut entries: Vec<_> = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec!["Aggregation"]);
+    }
+
+    #[test]
+    fn rate_limited_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = RateLimitedTransport::new(
+            Box::new(LocalFileTransport::new(tempdir.path().to_path_buf())) as Box<dyn Transport>,
+            20.0,
+        )
+        .unwrap();
+
+        // The first 20 operations are allowed as a burst, and the remaining 20
+        // must be spread out over at least a second.
+        let start = Instant::now();
+        for i in 0..20 {
+            let mut writer = transport.put(&format!("key-{}", i)).unwrap();
+            writer.write_all(&[1, 2, 3]).unwrap();
+            writer.complete_upload().unwrap();
+            let mut content = Vec::new();
+            transport
+                .get(&format!("key-{}", i))
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, vec![1, 2, 3]);
+        }
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(950),
+            "40 operations at 20/s took only {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn rate_limiter_shared_between_clones() {
+        let limiter = RateLimiter::new(10.0).unwrap();
+        let start = Instant::now();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        limiter.acquire();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // After the initial burst of 10, the other 10 take a second.
+        assert!(start.elapsed() >= Duration::from_millis(950));
+    }
+
+    #[test]
+    fn rate_limiter_invalid_rate() {
+        for rate in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(*rate).is_err(), "rate {} accepted", rate);
+        }
+    }
+
+    #[test]
+    fn transient_errors() {
+        let reset = || std::io::Error::new(ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient(&anyhow::Error::new(reset())));
+        assert!(is_transient(
+            &Err::<(), _>(reset())
+                .context("failed to read header")
+                .unwrap_err()
+        ));
+        // Wrapped, as MultipartUploadWriter reports them, in an I/O error of
+        // another kind
+        assert!(is_transient(&anyhow::Error::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            Error::AnyhowError(anyhow::Error::new(reset()).context("failed to upload part")),
+        ))));
+        assert!(!is_transient(&anyhow::Error::new(std::io::Error::new(
+            ErrorKind::NotFound,
+            "not found"
+        ))));
+        assert!(!is_transient(&anyhow!("no value for key")));
+
+        // Client errors are permanent, but server errors and throttling are
+        // not.
+        fn client(status: u16, region: &Region) -> S3Client {
+            S3Client::new_with(
+                MockRequestDispatcher::with_status(status)
+                    .with_request_checker(is_create_multipart_upload_request),
+                MockCredentialsProvider,
+                region.clone(),
+            )
+        }
+        type Case = (fn(&Region) -> S3Client, bool);
+        let cases: &[Case] = &[
+            (|region| client(401, region), false),
+            (|region| client(404, region), false),
+            (|region| client(429, region), true),
+            (|region| client(503, region), true),
+        ];
+        for (client_provider, transient) in cases {
+            let err = MultipartUploadWriter::new(
+                Region::UsWest2,
+                String::from(TEST_BUCKET),
+                String::from(TEST_KEY),
+                50,
+                *client_provider,
+            )
+            .expect_err("expected error");
+            assert_eq!(is_transient(&err), *transient, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+        };
+        let transient = || anyhow::Error::new(std::io::Error::new(ErrorKind::TimedOut, "timeout"));
+
+        let mut attempts = 0;
+        assert_eq!(
+            policy
+                .retry(|| {
+                    attempts += 1;
+                    if attempts < 3 {
+                        Err(transient())
+                    } else {
+                        Ok(attempts)
+                    }
+                })
+                .unwrap(),
+            3
+        );
+
+        attempts = 0;
+        let err = policy
+            .retry(|| -> Result<()> {
+                attempts += 1;
+                Err(transient())
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert_eq!(err.to_string(), "failed after 3 attempts");
+        assert!(is_transient(&err));
+
+        // Errors that are not transient are not retried.
+        attempts = 0;
+        let err = policy
+            .retry(|| -> Result<()> {
+                attempts += 1;
+                Err(anyhow!("malformed"))
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert_eq!(err.to_string(), "malformed");
+
+        attempts = 0;
+        RetryPolicy::NONE
+            .retry(|| -> Result<()> {
+                attempts += 1;
+                Err(transient())
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
+
+    /// Serves the values in transport over HTTP under /batches/, redirecting
+    /// requests under /moved/ there and storing the bodies of PUT requests.
+    /// Returns the base URL of the server.
+    fn serve_over_http(transport: MemoryTransport) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let header_end = loop {
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                    let n = stream.read(&mut buf).unwrap();
+                    assert_ne!(n, 0);
+                    request.extend_from_slice(&buf[..n]);
+                };
+                let head = String::from_utf8(request[..header_end].to_vec()).unwrap();
+                let content_length = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_owned)
+                    })
+                    .map_or(0, |length| length.parse().unwrap());
+                while request.len() < header_end + content_length {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let body = &request[header_end..];
+                let mut request_line = head.split(' ');
+                let method = request_line.next().unwrap();
+                let path = request_line.next().unwrap();
+
+                let (status, headers, content) = match (method, path.strip_prefix("/batches/")) {
+                    ("GET", Some(key)) => match transport.get(key) {
+                        Ok(mut reader) => {
+                            let mut content = Vec::new();
+                            reader.read_to_end(&mut content).unwrap();
+                            ("200 OK", String::new(), content)
+                        }
+                        Err(_) => ("404 Not Found", String::new(), Vec::new()),
+                    },
+                    ("PUT", Some(key)) => {
+                        let mut writer = transport.clone().put(key).unwrap();
+                        writer.write_all(body).unwrap();
+                        writer.complete_upload().unwrap();
+                        ("201 Created", String::new(), Vec::new())
+                    }
+                    (_, None) if path.starts_with("/moved/") => (
+                        "302 Found",
+                        format!("Location: /batches/{}\r\n", &path["/moved/".len()..]),
+                        Vec::new(),
+                    ),
+                    _ => ("405 Method Not Allowed", String::new(), Vec::new()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    content.len()
+                )
+                .unwrap();
+                stream.write_all(&content).unwrap();
+            }
+        });
+        base_url
+    }
+
+    #[test]
+    fn http_transport() {
+        let memory_transport = MemoryTransport::new();
+        let batch = BatchIdentity::new(
+            AggregationName::new("fake-aggregation").unwrap(),
+            BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 0)),
+            Uuid::new_v4(),
+        );
+        generate_ingestion_sample(
+            &mut memory_transport.clone(),
+            &mut MemoryTransport::new(),
+            None,
+            &batch,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .unwrap();
+        let base_url = serve_over_http(memory_transport.clone());
+
+        // The sample batch can be read and verified over HTTP, including
+        // through a redirect.
+        for prefix in &["batches", "moved"] {
+            let mut transport = HttpTransport::new(&format!("{}/{}/", base_url, prefix));
+            let reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+                    &mut transport,
+                );
+            let header = reader.header(&default_ingestor_public_key()).unwrap();
+            assert_eq!(header.batch_uuid, batch.batch_id);
+            assert_eq!(reader.packet_file_reader(&header).unwrap().count(), 10);
+        }
+
+        let mut transport = HttpTransport::new(&format!("{}/batches", base_url));
+        let err = transport.get("missing").err().unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::TransportError(message)) if message.ends_with("HTTP status 404 Not Found")),
+            "unexpected error {:?}",
+            err
+        );
+
+        // Writing is refused until enabled.
+        let err = transport.put("key").err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(Error::TransportError(_))));
+        transport.set_allow_put(true);
+        let mut writer = transport.put("key").unwrap();
+        writer.write_all(b"some content").unwrap();
+        writer.complete_upload().unwrap();
+        let mut content = Vec::new();
+        memory_transport
+            .get("key")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"some content");
+        let mut writer = transport.put("not-written").unwrap();
+        writer.write_all(b"some content").unwrap();
+        writer.cancel_upload().unwrap();
+        assert!(memory_transport.get("not-written").is_err());
+    }
+
+    #[test]
+    fn metered_transport() {
+        let mut memory_transport = MemoryTransport::new();
+        let mut transport = MeteredTransport::new(&mut memory_transport);
+        let meter = transport.meter();
+
+        let mut writer = transport.put("key").unwrap();
+        writer.write_all(b"some content").unwrap();
+        writer.complete_upload().unwrap();
+        let mut cancelled = transport.put("cancelled").unwrap();
+        cancelled.write_all(b"abc").unwrap();
+        cancelled.cancel_upload().unwrap();
+
+        let mut content = Vec::new();
+        transport
+            .get("key")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"some content");
+        assert!(transport.get("missing").is_err());
+
+        let metrics = meter.metrics();
+        assert_eq!(metrics, transport.meter().metrics());
+        assert_eq!(metrics.bytes_read, 12);
+        assert_eq!(metrics.bytes_written, 15);
+        assert!(metrics.read_duration > Duration::from_secs(0));
+        assert!(metrics.write_duration > Duration::from_secs(0));
+        assert_eq!(memory_transport.list("").unwrap(), vec!["key".to_owned()]);
+    }
+
+    #[test]
+    fn encrypting_transport() {
+        let memory_transport = MemoryTransport::new();
+        let mut transport = EncryptingTransport::new(memory_transport.clone(), &[7; 32]).unwrap();
+        let read = |transport: &dyn Transport, key| -> Result<Vec<u8>> {
+            let mut content = Vec::new();
+            transport.get(key)?.read_to_end(&mut content)?;
+            Ok(content)
+        };
+
+        for content in &[&b"some content"[..], b""] {
+            let mut writer = transport.put("key").unwrap();
+            writer.write_all(content).unwrap();
+            writer.complete_upload().unwrap();
+            assert_eq!(&read(&transport, "key").unwrap(), content);
+
+            let stored = read(&memory_transport, "key").unwrap();
+            assert_eq!(stored.len(), content.len() + ENCRYPTION_OVERHEAD);
+            assert_eq!(transport.size("key").unwrap(), Some(stored.len() as u64));
+            if !content.is_empty() {
+                assert!(!stored
+                    .windows(content.len())
+                    .any(|window| window == *content));
+            }
+        }
+
+        // Each value is encrypted under a fresh nonce
+        let mut writer = transport.put("other key").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.complete_upload().unwrap();
+        assert_ne!(
+            read(&memory_transport, "key").unwrap(),
+            read(&memory_transport, "other key").unwrap()
+        );
+
+        let mut cancelled = transport.put("cancelled").unwrap();
+        cancelled.write_all(b"abc").unwrap();
+        cancelled.cancel_upload().unwrap();
+        assert!(transport.get("cancelled").is_err());
+        assert_eq!(
+            transport.list("").unwrap(),
+            vec!["key".to_owned(), "other key".to_owned()]
+        );
+
+        assert!(EncryptingTransport::new(MemoryTransport::new(), &[7; 16]).is_err());
+    }
+
+    #[test]
+    fn encrypting_transport_tampering() {
+        let mut memory_transport = MemoryTransport::new();
+        let mut transport = EncryptingTransport::new(memory_transport.clone(), &[7; 32]).unwrap();
+        let mut writer = transport.put("key").unwrap();
+        writer.write_all(b"some content").unwrap();
+        writer.complete_upload().unwrap();
+        let mut stored = Vec::new();
+        memory_transport
+            .get("key")
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+
+        let mut put = |key: &str, value: &[u8]| {
+            let mut writer = memory_transport.put(key).unwrap();
+            writer.write_all(value).unwrap();
+            writer.complete_upload().unwrap();
+        };
+        let mut flipped = stored.clone();
+        flipped[aead::NONCE_LEN] ^= 1;
+        put("flipped", &flipped);
+        put("copied", &stored);
+        put("truncated", &stored[..ENCRYPTION_OVERHEAD - 1]);
+
+        let other_key = EncryptingTransport::new(memory_transport.clone(), &[8; 32]).unwrap();
+        for (transport, key) in &[
+            (&transport, "flipped"),
+            (&transport, "copied"),
+            (&transport, "truncated"),
+            (&other_key, "key"),
+        ] {
+            let error = transport.get(key).err().unwrap();
+            match error.downcast_ref::<Error>() {
+                Some(Error::CryptographyError(_)) => (),
+                _ => panic!("unexpected error for {}: {:?}", key, error),
+            }
+        }
+    }
+
+    // Rusoto provides us the ability to create mock clients and play canned
+    // responses to API requests. Besides that, we want to verify that we get
+    // the expected sequence of API requests, for instance to verify that we
+    // call AbortMultipartUpload if an UploadPart call fails. The mock client
+    // will crash if it runs out of canned responses to give the client, which
+    // will catch excess API calls. To make sure we issue the correct API calls,
+    // we use with_request_checker to examine the outgoing requests. We only get
+    // to examine a rusoto::signature::SignedRequest, which does not contain an
+    // explicit indication of which API call the HTTP request represents. So we
+    // have the `is_*_request` functions below, which compare HTTP method and
+    // URL parameters against the Amazon API specification to figure out what
+    // API call we are looking at.
+
+    const TEST_BUCKET: &str = "fake-bucket";
+    const TEST_KEY: &str = "fake-key";
+
+    fn is_create_multipart_upload_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CreateMultipartUpload.html
+        assert_eq!(
+            request.method, "POST",
+            "expected CreateMultipartUpload request, found {:?}",
+            request
+        );
+        assert!(
+            request.params.contains_key("uploads"),
+            "expected CreateMultipartUpload request, found {:?}",
+            request
+        );
+    }
+
+    fn is_upload_part_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_UploadPart.html
+        assert_eq!(
+            request.method, "PUT",
+            "expected UploadPart request, found {:?}",
+            request
+        );
+        assert!(
+            request.params.contains_key("partNumber"),
+            "expected UploadPart request, found {:?}",
+            request
+        );
+        assert!(
+            request.params.contains_key("uploadId"),
+            "expected UploadPart request, found {:?}",
+            request
+        );
+    }
+
+    fn is_abort_multipart_upload_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_AbortMultipartUpload.html
+        assert_eq!(
+            request.method, "DELETE",
+            "expected AbortMultipartUpload request, found {:?}",
+            request
+        );
+        assert!(
+            request.params.contains_key("uploadId"),
+            "expected AbortMultipartUpload request, found {:?}",
+            request
+        );
+    }
+
+    fn is_complete_multipart_upload_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CompleteMultipartUpload.html
+        assert_eq!(
+            request.method, "POST",
+            "expected CompleteMultipartUpload request, found {:?}",
+            request
+        );
+        assert!(
+            request.params.contains_key("uploadId"),
+            "expected CompleteMultipartUpload request, found {:?}",
+            request
+        );
+    }
+
+    fn is_get_object_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html
+        assert_eq!(
             request.method, "GET",
             "expected GetObject request, found {:?}",
             request
@@ -542,6 +2757,20 @@ mod tests {
         );
     }
 
+    fn is_head_object_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_HeadObject.html
+        assert_eq!(
+            request.method, "HEAD",
+            "expected HeadObject request, found {:?}",
+            request
+        );
+        assert_eq!(
+            request.path, "/fake-bucket/fake-key",
+            "expected HeadObject request, found {:?}",
+            request
+        );
+    }
+
     #[test]
     fn multipart_upload_create_fails() {
         let err = MultipartUploadWriter::new(
@@ -694,6 +2923,32 @@ mod tests {
         writer.complete_upload().unwrap_err();
     }
 
+    #[test]
+    fn s3_transport_size() {
+        let transport =
+            S3Transport::new_with_client(Region::UsWest2, TEST_BUCKET.to_string(), |region| {
+                S3Client::new_with(
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_head_object_request)
+                        .with_header("Content-Length", "1234"),
+                    MockCredentialsProvider,
+                    region.clone(),
+                )
+            });
+        assert_eq!(transport.size(TEST_KEY).unwrap(), Some(1234));
+
+        let transport =
+            S3Transport::new_with_client(Region::UsWest2, TEST_BUCKET.to_string(), |region| {
+                S3Client::new_with(
+                    MockRequestDispatcher::with_status(404)
+                        .with_request_checker(is_head_object_request),
+                    MockCredentialsProvider,
+                    region.clone(),
+                )
+            });
+        assert!(transport.size(TEST_KEY).is_err());
+    }
+
     #[test]
     fn roundtrip_s3_transport() {
         let transport =
@@ -1,36 +1,73 @@
 use chrono::NaiveDateTime;
 use facilitator::{
-    aggregation::BatchAggregator,
-    batch::{Batch, BatchReader},
-    idl::{IngestionDataSharePacket, SumPart},
-    intake::BatchIntaker,
-    sample::generate_ingestion_sample,
+    aggregation::{Accumulator, BatchAggregator, ValidationBatchReader},
+    batch::{
+        AggregationName, Batch, BatchDate, BatchIdentity, BatchNaming, BatchReader, InstanceName,
+        PacketPartNaming, ServerIdentity, DEFAULT_NAMING_SCHEME,
+    },
+    idl::{
+        IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
+        ValidationHeader, ValidationPacket,
+    },
+    intake::{BatchIntaker, PacketFailurePolicy},
+    sample::{
+        generate_ingestion_sample, generate_ingestion_sample_with_bad_packets,
+        generate_multipart_ingestion_sample, PacketCorruption,
+    },
     test_utils::{
         default_facilitator_signing_private_key, default_ingestor_private_key,
         default_ingestor_private_key_raw, default_pha_signing_private_key,
         DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
     },
-    transport::LocalFileTransport,
+    transport::{LocalFileTransport, Transport, TransportWriter},
+    Error,
 };
-use prio::{encrypt::PrivateKey, util::reconstruct_shares};
+use prio::{encrypt::PrivateKey, finite_field::Field, util::reconstruct_shares};
 use ring::signature::{
     EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
     ECDSA_P256_SHA256_FIXED_SIGNING,
 };
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration as StdDuration,
+};
 use uuid::Uuid;
 
 #[test]
 fn end_to_end() {
+    run_end_to_end(None)
+}
+
+#[test]
+fn end_to_end_with_instance_name() {
+    run_end_to_end(Some("narnia"))
+}
+
+fn run_end_to_end(instance_name: Option<&str>) {
     let pha_tempdir = tempfile::TempDir::new().unwrap();
     let facilitator_tempdir = tempfile::TempDir::new().unwrap();
 
     let aggregation_name = "fake-aggregation-1".to_owned();
-    let date = NaiveDateTime::from_timestamp(2234567890, 654321);
-    let start_date = NaiveDateTime::from_timestamp(1234567890, 654321);
-    let end_date = NaiveDateTime::from_timestamp(3234567890, 654321);
+    let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+    let start_date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+    let end_date = BatchDate::new(&NaiveDateTime::from_timestamp(3234567890, 654321));
 
     let batch_1_uuid = Uuid::new_v4();
     let batch_2_uuid = Uuid::new_v4();
+    let batch_1 = BatchIdentity::new(
+        AggregationName::new(&aggregation_name).unwrap(),
+        date,
+        batch_1_uuid,
+    );
+    let batch_2 = BatchIdentity::new(
+        AggregationName::new(&aggregation_name).unwrap(),
+        date,
+        batch_2_uuid,
+    );
 
     let mut pha_ingest_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
     let mut facilitator_ingest_transport =
@@ -68,9 +105,8 @@ fn end_to_end() {
     let batch_1_reference_sum = generate_ingestion_sample(
         &mut pha_ingest_transport,
         &mut facilitator_ingest_transport,
-        &batch_1_uuid,
-        &aggregation_name,
-        &date,
+        instance_name,
+        &batch_1,
         &pha_ecies_key,
         &facilitator_ecies_key,
         &default_ingestor_private_key_raw(),
@@ -89,9 +125,8 @@ fn end_to_end() {
     let batch_2_reference_sum = generate_ingestion_sample(
         &mut pha_ingest_transport,
         &mut facilitator_ingest_transport,
-        &batch_2_uuid,
-        &aggregation_name,
-        &date,
+        instance_name,
+        &batch_2,
         &pha_ecies_key,
         &facilitator_ecies_key,
         &default_ingestor_private_key_raw(),
@@ -108,12 +143,11 @@ fn end_to_end() {
     );
 
     let res = BatchIntaker::new(
-        &aggregation_name,
-        &batch_1_uuid,
-        &date,
+        instance_name,
+        &batch_1,
         &mut pha_ingest_transport,
         &mut pha_validate_transport,
-        true,
+        ServerIdentity::Pha,
         &pha_ecies_key,
         &pha_signing_key,
         &ingestor_pub_key,
@@ -127,12 +161,11 @@ fn end_to_end() {
     );
 
     let res = BatchIntaker::new(
-        &aggregation_name,
-        &batch_2_uuid,
-        &date,
+        instance_name,
+        &batch_2,
         &mut pha_ingest_transport,
         &mut pha_validate_transport,
-        true,
+        ServerIdentity::Pha,
         &pha_ecies_key,
         &pha_signing_key,
         &ingestor_pub_key,
@@ -146,12 +179,11 @@ fn end_to_end() {
     );
 
     let res = BatchIntaker::new(
-        &aggregation_name,
-        &batch_1_uuid,
-        &date,
+        instance_name,
+        &batch_1,
         &mut facilitator_ingest_transport,
         &mut facilitator_validate_transport,
-        false,
+        ServerIdentity::Facilitator,
         &facilitator_ecies_key,
         &facilitator_signing_key,
         &ingestor_pub_key,
@@ -165,12 +197,11 @@ fn end_to_end() {
     );
 
     let res = BatchIntaker::new(
-        &aggregation_name,
-        &batch_2_uuid,
-        &date,
+        instance_name,
+        &batch_2,
         &mut facilitator_ingest_transport,
         &mut facilitator_validate_transport,
-        false,
+        ServerIdentity::Facilitator,
         &facilitator_ecies_key,
         &facilitator_signing_key,
         &ingestor_pub_key,
@@ -186,10 +217,11 @@ fn end_to_end() {
     let batch_ids_and_dates = vec![(batch_1_uuid, date), (batch_2_uuid, date)];
 
     let res = BatchAggregator::new(
+        instance_name,
         &aggregation_name,
         &start_date,
         &end_date,
-        true,
+        ServerIdentity::Pha,
         &mut pha_ingest_transport,
         &mut pha_validate_transport,
         &mut facilitator_validate_transport,
@@ -208,10 +240,11 @@ fn end_to_end() {
     );
 
     let res = BatchAggregator::new(
+        instance_name,
         &aggregation_name,
         &start_date,
         &end_date,
-        false,
+        ServerIdentity::Facilitator,
         &mut facilitator_ingest_transport,
         &mut facilitator_validate_transport,
         &mut pha_validate_transport,
@@ -229,11 +262,17 @@ fn end_to_end() {
         res.err()
     );
 
-    let pha_aggregation_batch_reader: BatchReader<'_, SumPart, IngestionDataSharePacket> =
-        BatchReader::new(
-            Batch::new_sum(&aggregation_name, &start_date, &end_date, true),
-            &mut aggregation_transport,
-        );
+    let parsed_instance_name = instance_name.map(|name| InstanceName::new(name).unwrap());
+    let pha_aggregation_batch_reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+        Batch::new_sum(
+            parsed_instance_name.as_ref(),
+            &AggregationName::new(&aggregation_name).unwrap(),
+            &start_date,
+            &end_date,
+            ServerIdentity::Pha,
+        ),
+        &mut aggregation_transport,
+    );
     let pha_sum_part = pha_aggregation_batch_reader.header(&pha_pub_signing_key);
     assert!(
         pha_sum_part.is_ok(),
@@ -243,15 +282,26 @@ fn end_to_end() {
     let pha_sum_part = pha_sum_part.unwrap();
     let pha_sum_fields = pha_sum_part.sum().unwrap();
 
-    let pha_invalid_packet_reader = pha_aggregation_batch_reader.packet_file_reader(&pha_sum_part);
-    assert!(
-        pha_invalid_packet_reader.is_err(),
-        "should get no invalid packet reader when all packets were OK"
-    );
+    let mut pha_invalid_packet_reader = pha_aggregation_batch_reader
+        .packet_file_reader(&pha_sum_part)
+        .expect("failed to read PHA invalid packets");
+    match InvalidPacket::read(&mut pha_invalid_packet_reader) {
+        Err(Error::EofError) => (),
+        v => panic!(
+            "should get no invalid packets when all packets were OK: {:?}",
+            v
+        ),
+    }
 
-    let facilitator_aggregation_batch_reader: BatchReader<'_, SumPart, IngestionDataSharePacket> =
+    let facilitator_aggregation_batch_reader: BatchReader<'_, SumPart, InvalidPacket> =
         BatchReader::new(
-            Batch::new_sum(&aggregation_name, &start_date, &end_date, false),
+            Batch::new_sum(
+                parsed_instance_name.as_ref(),
+                &AggregationName::new(&aggregation_name).unwrap(),
+                &start_date,
+                &end_date,
+                ServerIdentity::Facilitator,
+            ),
             &mut aggregation_transport,
         );
     let facilitator_sum_part =
@@ -264,12 +314,29 @@ fn end_to_end() {
     let facilitator_sum_part = facilitator_sum_part.unwrap();
     let facilitator_sum_fields = facilitator_sum_part.sum().unwrap();
 
-    let facilitator_invalid_packet_reader =
-        facilitator_aggregation_batch_reader.packet_file_reader(&facilitator_sum_part);
-    assert!(
-        facilitator_invalid_packet_reader.is_err(),
-        "should get no invalid packet reader when all packets were OK"
-    );
+    let mut facilitator_invalid_packet_reader = facilitator_aggregation_batch_reader
+        .packet_file_reader(&facilitator_sum_part)
+        .expect("failed to read facilitator invalid packets");
+    match InvalidPacket::read(&mut facilitator_invalid_packet_reader) {
+        Err(Error::EofError) => (),
+        v => panic!(
+            "should get no invalid packets when all packets were OK: {:?}",
+            v
+        ),
+    }
+
+    // Everything should have been written under the instance's prefix, if
+    // any.
+    for tempdir in &[&pha_tempdir, &facilitator_tempdir] {
+        let entries: Vec<_> = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![instance_name.unwrap_or(&aggregation_name).to_owned()]
+        );
+    }
 
     let reconstructed = reconstruct_shares(&facilitator_sum_fields, &pha_sum_fields).unwrap();
 
@@ -285,3 +352,717 @@ fn end_to_end() {
         pha_sum_fields, facilitator_sum_fields, reconstructed, reference_sum
     );
 }
+
+#[test]
+fn end_to_end_with_packet_failures() {
+    let pha_tempdir = tempfile::TempDir::new().unwrap();
+    let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+    let start_date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+    let end_date = BatchDate::new(&NaiveDateTime::from_timestamp(3234567890, 654321));
+
+    let batch_uuid = Uuid::new_v4();
+    let batch = BatchIdentity::new(
+        AggregationName::new(&aggregation_name).unwrap(),
+        date,
+        batch_uuid,
+    );
+
+    let mut pha_ingest_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut facilitator_ingest_transport =
+        LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+    let mut pha_validate_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut facilitator_validate_transport =
+        LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+    let mut aggregation_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+
+    let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+    let facilitator_ecies_key =
+        PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+    let ingestor_pub_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        default_ingestor_private_key()
+            .public_key()
+            .as_ref()
+            .to_vec(),
+    );
+    let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        &default_pha_signing_private_key(),
+    )
+    .unwrap();
+    let pha_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        pha_signing_key.public_key().as_ref().to_vec(),
+    );
+    let facilitator_signing_key = default_facilitator_signing_private_key();
+    let facilitator_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        facilitator_signing_key.public_key().as_ref().to_vec(),
+    );
+
+    let (reference_sum, bad_packet_uuids) = generate_ingestion_sample_with_bad_packets(
+        &mut pha_ingest_transport,
+        &mut facilitator_ingest_transport,
+        None,
+        &batch,
+        &pha_ecies_key,
+        &facilitator_ecies_key,
+        &default_ingestor_private_key_raw(),
+        10,
+        10,
+        0.11,
+        100,
+        100,
+        &[
+            (0, PacketCorruption::TruncatedPayload),
+            (5, PacketCorruption::IllegalRPit),
+        ],
+    )
+    .expect("failed to generate sample");
+
+    let policy = PacketFailurePolicy::Record {
+        max_failure_fraction: 0.5,
+    };
+    let mut pha_intaker = BatchIntaker::new(
+        None,
+        &batch,
+        &mut pha_ingest_transport,
+        &mut pha_validate_transport,
+        ServerIdentity::Pha,
+        &pha_ecies_key,
+        &pha_signing_key,
+        &ingestor_pub_key,
+    )
+    .unwrap();
+    pha_intaker.set_packet_failure_policy(policy);
+    let stats = pha_intaker
+        .generate_validation_share()
+        .expect("PHA failed to generate validation");
+    assert_eq!(stats.packet_failures.len(), 2);
+
+    let mut facilitator_intaker = BatchIntaker::new(
+        None,
+        &batch,
+        &mut facilitator_ingest_transport,
+        &mut facilitator_validate_transport,
+        ServerIdentity::Facilitator,
+        &facilitator_ecies_key,
+        &facilitator_signing_key,
+        &ingestor_pub_key,
+    )
+    .unwrap();
+    facilitator_intaker.set_packet_failure_policy(policy);
+    let stats = facilitator_intaker
+        .generate_validation_share()
+        .expect("facilitator failed to generate validation");
+    assert_eq!(stats.packet_failures.len(), 2);
+
+    let batch_ids_and_dates = vec![(batch_uuid, date)];
+    BatchAggregator::new(
+        None,
+        &aggregation_name,
+        &start_date,
+        &end_date,
+        ServerIdentity::Pha,
+        &mut pha_ingest_transport,
+        &mut pha_validate_transport,
+        &mut facilitator_validate_transport,
+        &mut aggregation_transport,
+        &ingestor_pub_key,
+        &pha_signing_key,
+        &facilitator_pub_signing_key,
+        &pha_ecies_key,
+    )
+    .unwrap()
+    .generate_sum_part(&batch_ids_and_dates)
+    .expect("PHA failed to generate sum part");
+    BatchAggregator::new(
+        None,
+        &aggregation_name,
+        &start_date,
+        &end_date,
+        ServerIdentity::Facilitator,
+        &mut facilitator_ingest_transport,
+        &mut facilitator_validate_transport,
+        &mut pha_validate_transport,
+        &mut aggregation_transport,
+        &ingestor_pub_key,
+        &facilitator_signing_key,
+        &pha_pub_signing_key,
+        &facilitator_ecies_key,
+    )
+    .unwrap()
+    .generate_sum_part(&batch_ids_and_dates)
+    .expect("facilitator failed to generate sum part");
+
+    let mut sums = Vec::new();
+    for (server_identity, public_key) in &[
+        (ServerIdentity::Pha, &pha_pub_signing_key),
+        (ServerIdentity::Facilitator, &facilitator_pub_signing_key),
+    ] {
+        let reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+            Batch::new_sum(
+                None,
+                &AggregationName::new(&aggregation_name).unwrap(),
+                &start_date,
+                &end_date,
+                *server_identity,
+            ),
+            &mut aggregation_transport,
+        );
+        let sum_part = reader.header(public_key).unwrap();
+        let mut invalid_packet_reader = reader.packet_file_reader(&sum_part).unwrap();
+        let mut invalid_uuids = Vec::new();
+        loop {
+            match InvalidPacket::read(&mut invalid_packet_reader) {
+                Ok(packet) => invalid_uuids.push(packet.uuid),
+                Err(Error::EofError) => break,
+                Err(e) => panic!("failed to read invalid packet: {:?}", e),
+            }
+        }
+        // The packets neither share processor could validate are reported as
+        // invalid and left out of the sum.
+        assert_eq!(invalid_uuids, bad_packet_uuids);
+        sums.push(sum_part.sum().unwrap());
+    }
+
+    assert_eq!(
+        reconstruct_shares(&sums[0], &sums[1]).unwrap(),
+        reference_sum
+    );
+
+    // Streaming the same packets through an Accumulator yields the same sum
+    // part and invalid packets as BatchAggregator.
+    let pha_sum_part: SumPart = BatchReader::<'_, SumPart, InvalidPacket>::new(
+        Batch::new_sum(
+            None,
+            &AggregationName::new(&aggregation_name).unwrap(),
+            &start_date,
+            &end_date,
+            ServerIdentity::Pha,
+        ),
+        &mut aggregation_transport,
+    )
+    .header(&pha_pub_signing_key)
+    .unwrap();
+    let ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+        BatchReader::new(
+            batch.ingestion_batch(&DEFAULT_NAMING_SCHEME, None),
+            &mut pha_ingest_transport,
+        );
+    let ingestion_header = ingestion_batch.header(&ingestor_pub_key).unwrap();
+    let own_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+        BatchReader::new(
+            batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha),
+            &mut pha_validate_transport,
+        );
+    let own_validation_header = own_validation_batch.header(&pha_pub_signing_key).unwrap();
+    let peer_validation_batch = ValidationBatchReader::new(
+        batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Facilitator),
+        &mut facilitator_validate_transport,
+        &facilitator_pub_signing_key,
+    );
+    let peer_validation_header = peer_validation_batch.header(&ingestion_header).unwrap();
+
+    let mut ingestion_packets = ingestion_batch
+        .sharded_packet_reader(&ingestion_header)
+        .unwrap();
+    let mut own_validation_packets = own_validation_batch
+        .packet_file_reader(&own_validation_header)
+        .unwrap();
+    let mut accumulator = Accumulator::new(&ingestion_header, ServerIdentity::Pha, &pha_ecies_key);
+    let invalid_uuids = accumulator
+        .accumulate_batch(
+            std::iter::from_fn(|| match ingestion_packets.read_packet() {
+                Ok(packet) => Some(Ok(packet)),
+                Err(Error::EofError) => None,
+                Err(e) => Some(Err(e.into())),
+            }),
+            peer_validation_batch
+                .packets(&peer_validation_header)
+                .unwrap(),
+            std::iter::from_fn(
+                || match ValidationPacket::read(&mut own_validation_packets) {
+                    Ok(packet) => Some(Ok(packet)),
+                    Err(Error::EofError) => None,
+                    Err(e) => Some(Err(e.into())),
+                },
+            ),
+        )
+        .unwrap();
+    assert_eq!(invalid_uuids, bad_packet_uuids);
+    assert_eq!(
+        accumulator.finish(
+            vec![batch_uuid],
+            &start_date,
+            &end_date,
+            pha_sum_part.packet_file_digest.clone()
+        ),
+        pha_sum_part
+    );
+}
+
+#[test]
+fn end_to_end_with_multipart_packet_files() {
+    let pha_tempdir = tempfile::TempDir::new().unwrap();
+    let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+    let start_date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+    let end_date = BatchDate::new(&NaiveDateTime::from_timestamp(3234567890, 654321));
+
+    let batch_uuid = Uuid::new_v4();
+    let batch = BatchIdentity::new(
+        AggregationName::new(&aggregation_name).unwrap(),
+        date,
+        batch_uuid,
+    );
+
+    let mut pha_ingest_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut facilitator_ingest_transport =
+        LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+    let mut pha_validate_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut facilitator_validate_transport =
+        LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+    let mut aggregation_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+
+    let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+    let facilitator_ecies_key =
+        PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+    let ingestor_pub_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        default_ingestor_private_key()
+            .public_key()
+            .as_ref()
+            .to_vec(),
+    );
+    let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        &default_pha_signing_private_key(),
+    )
+    .unwrap();
+    let pha_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        pha_signing_key.public_key().as_ref().to_vec(),
+    );
+    let facilitator_signing_key = default_facilitator_signing_private_key();
+    let facilitator_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        facilitator_signing_key.public_key().as_ref().to_vec(),
+    );
+
+    let reference_sum = generate_multipart_ingestion_sample(
+        &mut pha_ingest_transport,
+        &mut facilitator_ingest_transport,
+        None,
+        &batch,
+        &pha_ecies_key,
+        &facilitator_ecies_key,
+        &default_ingestor_private_key_raw(),
+        10,
+        10,
+        0.11,
+        100,
+        100,
+        3,
+        PacketPartNaming::Numbered,
+    )
+    .expect("failed to generate sample");
+
+    let ingestion_naming_scheme = DEFAULT_NAMING_SCHEME.clone().with_ingestion_naming(
+        BatchNaming::default().with_packet_part_naming(PacketPartNaming::Numbered),
+    );
+    let ingestion_batch = batch.ingestion_batch(&ingestion_naming_scheme, None);
+    for part in 0..3 {
+        let key = ingestion_batch.packet_file_shard_key(part);
+        assert!(pha_tempdir.path().join(&key).exists(), "missing {}", key);
+        assert!(
+            facilitator_tempdir.path().join(&key).exists(),
+            "missing {}",
+            key
+        );
+    }
+
+    let mut pha_intaker = BatchIntaker::new(
+        None,
+        &batch,
+        &mut pha_ingest_transport,
+        &mut pha_validate_transport,
+        ServerIdentity::Pha,
+        &pha_ecies_key,
+        &pha_signing_key,
+        &ingestor_pub_key,
+    )
+    .unwrap();
+    pha_intaker.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+    pha_intaker
+        .generate_validation_share()
+        .expect("PHA failed to generate validation");
+
+    let mut facilitator_intaker = BatchIntaker::new(
+        None,
+        &batch,
+        &mut facilitator_ingest_transport,
+        &mut facilitator_validate_transport,
+        ServerIdentity::Facilitator,
+        &facilitator_ecies_key,
+        &facilitator_signing_key,
+        &ingestor_pub_key,
+    )
+    .unwrap();
+    facilitator_intaker.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+    facilitator_intaker
+        .generate_validation_share()
+        .expect("facilitator failed to generate validation");
+
+    // Each server emits a single validation packet file covering the packets
+    // from every part.
+    let validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> = BatchReader::new(
+        batch.validation_batch(&DEFAULT_NAMING_SCHEME, None, ServerIdentity::Pha),
+        &mut pha_validate_transport,
+    );
+    let validation_header = validation_batch.header(&pha_pub_signing_key).unwrap();
+    let mut validation_packets = validation_batch
+        .packet_file_reader(&validation_header)
+        .unwrap();
+    let mut validation_packet_count = 0;
+    loop {
+        match ValidationPacket::read(&mut validation_packets) {
+            Ok(_) => validation_packet_count += 1,
+            Err(Error::EofError) => break,
+            Err(e) => panic!("failed to read validation packet: {:?}", e),
+        }
+    }
+    assert_eq!(validation_packet_count, 10);
+
+    let batch_ids_and_dates = vec![(batch_uuid, date)];
+    let mut pha_aggregator = BatchAggregator::new(
+        None,
+        &aggregation_name,
+        &start_date,
+        &end_date,
+        ServerIdentity::Pha,
+        &mut pha_ingest_transport,
+        &mut pha_validate_transport,
+        &mut facilitator_validate_transport,
+        &mut aggregation_transport,
+        &ingestor_pub_key,
+        &pha_signing_key,
+        &facilitator_pub_signing_key,
+        &pha_ecies_key,
+    )
+    .unwrap();
+    pha_aggregator.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+    pha_aggregator
+        .generate_sum_part(&batch_ids_and_dates)
+        .expect("PHA failed to generate sum part");
+
+    let mut facilitator_aggregator = BatchAggregator::new(
+        None,
+        &aggregation_name,
+        &start_date,
+        &end_date,
+        ServerIdentity::Facilitator,
+        &mut facilitator_ingest_transport,
+        &mut facilitator_validate_transport,
+        &mut pha_validate_transport,
+        &mut aggregation_transport,
+        &ingestor_pub_key,
+        &facilitator_signing_key,
+        &pha_pub_signing_key,
+        &facilitator_ecies_key,
+    )
+    .unwrap();
+    facilitator_aggregator.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+    facilitator_aggregator
+        .generate_sum_part(&batch_ids_and_dates)
+        .expect("facilitator failed to generate sum part");
+
+    let mut sums = Vec::new();
+    for (server_identity, public_key) in &[
+        (ServerIdentity::Pha, &pha_pub_signing_key),
+        (ServerIdentity::Facilitator, &facilitator_pub_signing_key),
+    ] {
+        let reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+            Batch::new_sum(
+                None,
+                &AggregationName::new(&aggregation_name).unwrap(),
+                &start_date,
+                &end_date,
+                *server_identity,
+            ),
+            &mut aggregation_transport,
+        );
+        let sum_part = reader.header(public_key).unwrap();
+        sums.push(sum_part.sum().unwrap());
+    }
+
+    assert_eq!(
+        reconstruct_shares(&sums[0], &sums[1]).unwrap(),
+        reference_sum
+    );
+
+    // A part that no longer matches the digest the ingestor signed is
+    // rejected.
+    let tampered_key = ingestion_batch.packet_file_shard_key(1);
+    std::fs::write(pha_tempdir.path().join(&tampered_key), b"tampered").unwrap();
+    let mut pha_intaker = BatchIntaker::new(
+        None,
+        &batch,
+        &mut pha_ingest_transport,
+        &mut pha_validate_transport,
+        ServerIdentity::Pha,
+        &pha_ecies_key,
+        &pha_signing_key,
+        &ingestor_pub_key,
+    )
+    .unwrap();
+    pha_intaker.set_ingestion_naming_scheme(&ingestion_naming_scheme);
+    pha_intaker.set_overwrite(true);
+    assert!(pha_intaker.generate_validation_share().is_err());
+}
+
+/// A transport that counts how many of the readers it hands out are open at
+/// once, and makes each of them slow to start so that readers opened by
+/// concurrent fetches overlap.
+struct CountingTransport {
+    transport: LocalFileTransport,
+    counts: Arc<ReaderCounts>,
+}
+
+#[derive(Default)]
+struct ReaderCounts {
+    open: AtomicUsize,
+    max_open: AtomicUsize,
+}
+
+struct CountingReader {
+    reader: Box<dyn Read>,
+    counts: Arc<ReaderCounts>,
+    started: bool,
+}
+
+impl Transport for CountingTransport {
+    fn get(&self, key: &str) -> anyhow::Result<Box<dyn Read>> {
+        let reader = self.transport.get(key)?;
+        let open = self.counts.open.fetch_add(1, Ordering::SeqCst) + 1;
+        self.counts.max_open.fetch_max(open, Ordering::SeqCst);
+        Ok(Box::new(CountingReader {
+            reader,
+            counts: self.counts.clone(),
+            started: false,
+        }))
+    }
+
+    fn put(&mut self, key: &str) -> anyhow::Result<Box<dyn TransportWriter>> {
+        self.transport.put(key)
+    }
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.started {
+            self.started = true;
+            std::thread::sleep(StdDuration::from_millis(5));
+        }
+        self.reader.read(buf)
+    }
+}
+
+impl Drop for CountingReader {
+    fn drop(&mut self) {
+        self.counts.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn aggregation_concurrency_limit() {
+    let pha_tempdir = tempfile::TempDir::new().unwrap();
+    let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = BatchDate::new(&NaiveDateTime::from_timestamp(2234567890, 654321));
+    let start_date = BatchDate::new(&NaiveDateTime::from_timestamp(1234567890, 654321));
+    let end_date = BatchDate::new(&NaiveDateTime::from_timestamp(3234567890, 654321));
+
+    let mut pha_transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut facilitator_transport =
+        LocalFileTransport::new(facilitator_tempdir.path().to_path_buf());
+
+    let pha_ecies_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+    let facilitator_ecies_key =
+        PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+    let ingestor_pub_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        default_ingestor_private_key()
+            .public_key()
+            .as_ref()
+            .to_vec(),
+    );
+    let pha_signing_key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        &default_pha_signing_private_key(),
+    )
+    .unwrap();
+    let pha_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        pha_signing_key.public_key().as_ref().to_vec(),
+    );
+    let facilitator_signing_key = default_facilitator_signing_private_key();
+    let facilitator_pub_signing_key = UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        facilitator_signing_key.public_key().as_ref().to_vec(),
+    );
+
+    let mut batch_ids_and_dates = Vec::new();
+    let mut reference_sum: Option<Vec<Field>> = None;
+    for _ in 0..6 {
+        let batch = BatchIdentity::new(
+            AggregationName::new(&aggregation_name).unwrap(),
+            date,
+            Uuid::new_v4(),
+        );
+        let batch_reference_sum = generate_ingestion_sample(
+            &mut pha_transport,
+            &mut facilitator_transport,
+            None,
+            &batch,
+            &pha_ecies_key,
+            &facilitator_ecies_key,
+            &default_ingestor_private_key_raw(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+        )
+        .expect("failed to generate sample");
+        reference_sum = Some(match reference_sum {
+            Some(sum) => reconstruct_shares(&sum, &batch_reference_sum).unwrap(),
+            None => batch_reference_sum,
+        });
+
+        for (server_identity, tempdir, ecies_key, signing_key) in [
+            (
+                ServerIdentity::Pha,
+                &pha_tempdir,
+                &pha_ecies_key,
+                &pha_signing_key,
+            ),
+            (
+                ServerIdentity::Facilitator,
+                &facilitator_tempdir,
+                &facilitator_ecies_key,
+                &facilitator_signing_key,
+            ),
+        ] {
+            let mut ingestion_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            let mut validation_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            BatchIntaker::new(
+                None,
+                &batch,
+                &mut ingestion_transport,
+                &mut validation_transport,
+                server_identity,
+                ecies_key,
+                signing_key,
+                &ingestor_pub_key,
+            )
+            .unwrap()
+            .generate_validation_share()
+            .expect("failed to generate validation");
+        }
+        batch_ids_and_dates.push((batch.batch_id, date));
+    }
+
+    let mut sums = Vec::new();
+    for (server_identity, max_concurrent_batches, tempdir, peer_tempdir) in [
+        (ServerIdentity::Pha, 2, &pha_tempdir, &facilitator_tempdir),
+        (
+            ServerIdentity::Facilitator,
+            1,
+            &facilitator_tempdir,
+            &pha_tempdir,
+        ),
+    ] {
+        let counts = Arc::new(ReaderCounts::default());
+        let counting_transport = |path: &std::path::Path| CountingTransport {
+            transport: LocalFileTransport::new(path.to_path_buf()),
+            counts: counts.clone(),
+        };
+        let mut ingestion_transport = counting_transport(tempdir.path());
+        let mut own_validation_transport = counting_transport(tempdir.path());
+        let mut peer_validation_transport = counting_transport(peer_tempdir.path());
+        let mut aggregation_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let (ecies_key, signing_key, peer_pub_signing_key) = match server_identity {
+            ServerIdentity::Pha => (
+                &pha_ecies_key,
+                &pha_signing_key,
+                &facilitator_pub_signing_key,
+            ),
+            ServerIdentity::Facilitator => (
+                &facilitator_ecies_key,
+                &facilitator_signing_key,
+                &pha_pub_signing_key,
+            ),
+        };
+        let mut aggregator = BatchAggregator::new(
+            None,
+            &aggregation_name,
+            &start_date,
+            &end_date,
+            server_identity,
+            &mut ingestion_transport,
+            &mut own_validation_transport,
+            &mut peer_validation_transport,
+            &mut aggregation_transport,
+            &ingestor_pub_key,
+            signing_key,
+            peer_pub_signing_key,
+            ecies_key,
+        )
+        .unwrap();
+        aggregator.set_max_concurrent_batches(max_concurrent_batches);
+        aggregator.set_batch_start_jitter(StdDuration::from_millis(2));
+        aggregator
+            .generate_sum_part(&batch_ids_and_dates)
+            .expect("failed to generate sum part");
+        drop(aggregator);
+
+        // Each batch reads its files one after another, so the number of
+        // readers open at once is the number of batches in flight.
+        let max_open = counts.max_open.load(Ordering::SeqCst);
+        assert!(
+            max_open <= max_concurrent_batches,
+            "{} readers open at once but at most {} batches may be in flight",
+            max_open,
+            max_concurrent_batches
+        );
+        assert_eq!(counts.open.load(Ordering::SeqCst), 0);
+
+        let reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+            Batch::new_sum(
+                None,
+                &AggregationName::new(&aggregation_name).unwrap(),
+                &start_date,
+                &end_date,
+                server_identity,
+            ),
+            &mut aggregation_transport,
+        );
+        let public_key = match server_identity {
+            ServerIdentity::Pha => &pha_pub_signing_key,
+            ServerIdentity::Facilitator => &facilitator_pub_signing_key,
+        };
+        sums.push(reader.header(public_key).unwrap().sum().unwrap());
+    }
+
+    // Summing concurrently gives the same sum as summing one batch at a time.
+    assert_eq!(
+        reconstruct_shares(&sums[0], &sums[1]).unwrap(),
+        reference_sum.unwrap()
+    );
+}